@@ -0,0 +1,35 @@
+use std::process::Command;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Generates the messages of the proto, with the gRPC service of
+    // src/grpc.rs when the server is built; the client only needs the messages
+    let server = std::env::var_os("CARGO_FEATURE_SERVER").is_some();
+    tonic_build::configure()
+        .build_server(server)
+        .build_client(false)
+        .compile_protos(&["proto/owlfacerec.proto"], &["proto"])?;
+    // Migrations are embedded by sqlx::migrate!
+    println!("cargo:rerun-if-changed=migrations");
+
+    // Commit reported by GET /version; GIT_COMMIT covers builds without .git
+    // (e.g. Docker build contexts)
+    let commit = std::env::var("GIT_COMMIT")
+        .ok()
+        .filter(|commit| !commit.is_empty())
+        .or_else(|| {
+            Command::new("git")
+                .args(["rev-parse", "HEAD"])
+                .output()
+                .ok()
+                .filter(|output| output.status.success())
+                .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        })
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=GIT_COMMIT={}", commit);
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    if std::path::Path::new(".git").exists() {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        println!("cargo:rerun-if-changed=.git/refs/heads");
+    }
+    Ok(())
+}
@@ -0,0 +1,47 @@
+-- Schema as created before migrations were tracked. Every statement is
+-- idempotent, so deployments that predate this file are brought up to date
+-- (columns added over time) instead of failing on existing tables.
+
+CREATE TABLE IF NOT EXISTS targets (
+    uuid UUID NOT NULL,
+    origin VARCHAR(64) NOT NULL DEFAULT 'unknown',
+    embeddings REAL[] NOT NULL,
+    metadata JSONB NOT NULL DEFAULT '{}'::jsonb
+);
+-- Deployments created before metadata support lack the column
+ALTER TABLE targets ADD COLUMN IF NOT EXISTS metadata JSONB NOT NULL DEFAULT '{}'::jsonb;
+-- Targets registered before collections existed belong to the default one
+ALTER TABLE targets ADD COLUMN IF NOT EXISTS collection VARCHAR(64) NOT NULL DEFAULT 'default';
+-- Targets registered before tenancy existed belong to the default tenant
+ALTER TABLE targets ADD COLUMN IF NOT EXISTS tenant VARCHAR(64) NOT NULL DEFAULT 'default';
+-- Object key of the enrollment crop, when crop storage is configured
+ALTER TABLE targets ADD COLUMN IF NOT EXISTS image_key TEXT;
+-- Insertion order of the rows, so startup only replays the rows newer than
+-- the snapshot; existing rows are numbered when the column is added
+ALTER TABLE targets ADD COLUMN IF NOT EXISTS id BIGSERIAL;
+CREATE UNIQUE INDEX IF NOT EXISTS targets_id_idx ON targets (id);
+CREATE INDEX IF NOT EXISTS targets_tenant_collection_idx ON targets (tenant, collection);
+
+CREATE TABLE IF NOT EXISTS collections (
+    tenant VARCHAR(64) NOT NULL DEFAULT 'default',
+    name VARCHAR(64) NOT NULL,
+    threshold REAL,
+    template_mode VARCHAR(8),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+-- Per-collection settings were added after the table itself
+ALTER TABLE collections ADD COLUMN IF NOT EXISTS threshold REAL, ADD COLUMN IF NOT EXISTS template_mode VARCHAR(8);
+-- Collection names used to be global; they are now unique per tenant
+ALTER TABLE collections ADD COLUMN IF NOT EXISTS tenant VARCHAR(64) NOT NULL DEFAULT 'default';
+ALTER TABLE collections DROP CONSTRAINT IF EXISTS collections_pkey;
+CREATE UNIQUE INDEX IF NOT EXISTS collections_tenant_name_idx ON collections (tenant, name);
+INSERT INTO collections (name) VALUES ('default') ON CONFLICT DO NOTHING;
+
+CREATE TABLE IF NOT EXISTS api_keys (
+    id UUID PRIMARY KEY,
+    key_hash VARCHAR(64) NOT NULL UNIQUE,
+    tenant VARCHAR(64) NOT NULL DEFAULT 'default',
+    name VARCHAR(128) NOT NULL DEFAULT '',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    revoked_at TIMESTAMPTZ
+);
@@ -0,0 +1,16 @@
+-- The surrogate id becomes the primary key, replacing its unique index
+ALTER TABLE targets ALTER COLUMN id SET NOT NULL;
+ALTER TABLE targets ADD CONSTRAINT targets_pkey PRIMARY KEY USING INDEX targets_id_idx;
+
+-- Lookups by target (deletion, replace, images) and per-origin counts
+CREATE INDEX targets_tenant_collection_uuid_idx ON targets (tenant, collection, uuid);
+CREATE INDEX targets_tenant_origin_idx ON targets (tenant, origin);
+-- Superseded by the index above, whose prefix it is
+DROP INDEX targets_tenant_collection_idx;
+
+-- A single non-empty vector per row; the width itself depends on the model
+-- and is checked by the application. NOT VALID: enforced on new rows without
+-- failing the migration on rows written before
+ALTER TABLE targets ADD CONSTRAINT targets_embeddings_check
+    CHECK (array_ndims(embeddings) = 1 AND cardinality(embeddings) > 0) NOT VALID;
+ALTER TABLE targets ADD CONSTRAINT targets_origin_check CHECK (origin <> '') NOT VALID;
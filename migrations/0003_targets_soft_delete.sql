@@ -0,0 +1,5 @@
+-- Deleted registrations are kept as tombstones, so deletions can be audited
+-- and reversed; every read of the gallery skips them
+ALTER TABLE targets ADD COLUMN deleted_at TIMESTAMPTZ;
+
+CREATE INDEX targets_deleted_at_idx ON targets (deleted_at) WHERE deleted_at IS NOT NULL;
@@ -0,0 +1,17 @@
+-- Searches and their matches, for "when was this person last seen"
+CREATE TABLE search_events (
+    id BIGSERIAL PRIMARY KEY,
+    created_at TIMESTAMPTZ NOT NULL,
+    tenant VARCHAR(64) NOT NULL,
+    collection VARCHAR(64) NOT NULL,
+    source VARCHAR(128),
+    query_hash CHAR(64) NOT NULL,
+    threshold REAL NOT NULL,
+    latency_ms INTEGER NOT NULL,
+    faces INTEGER NOT NULL,
+    matches JSONB NOT NULL,
+    matched_uuids UUID[] NOT NULL
+);
+
+CREATE INDEX search_events_tenant_created_at_idx ON search_events (tenant, created_at);
+CREATE INDEX search_events_matched_uuids_idx ON search_events USING GIN (matched_uuids);
@@ -0,0 +1,8 @@
+-- Registration time, for the server retention policy; rows registered before
+-- this migration count from the time it ran
+ALTER TABLE targets ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT now();
+-- Optional expiry of a registration, set at enrollment
+ALTER TABLE targets ADD COLUMN expires_at TIMESTAMPTZ;
+
+CREATE INDEX targets_created_at_idx ON targets (created_at);
+CREATE INDEX targets_expires_at_idx ON targets (expires_at) WHERE expires_at IS NOT NULL;
@@ -0,0 +1,13 @@
+-- Outcomes of registrations sent with an Idempotency-Key header, replayed to
+-- retries of the same key until they expire
+CREATE TABLE idempotency_keys (
+    tenant VARCHAR(64) NOT NULL,
+    key VARCHAR(255) NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    -- NULL while the first request is in flight
+    status SMALLINT,
+    body BYTEA,
+    PRIMARY KEY (tenant, key)
+);
+
+CREATE INDEX idempotency_keys_created_at_idx ON idempotency_keys (created_at);
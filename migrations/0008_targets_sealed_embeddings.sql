@@ -0,0 +1,6 @@
+-- Embeddings encrypted at rest with EMBEDDING_ENCRYPTION_KEY: a row holds
+-- either the plain vector or the sealed one (nonce, AES-256-GCM ciphertext)
+ALTER TABLE targets ADD COLUMN embeddings_sealed BYTEA;
+ALTER TABLE targets ALTER COLUMN embeddings DROP NOT NULL;
+ALTER TABLE targets ADD CONSTRAINT targets_embeddings_sealed_check
+    CHECK ((embeddings IS NULL) <> (embeddings_sealed IS NULL)) NOT VALID;
@@ -0,0 +1,3 @@
+-- Model version each vector was computed with, so a model upgrade can tell
+-- the rows still to re-embed; NULL for rows written before this migration
+ALTER TABLE targets ADD COLUMN model VARCHAR(64);
@@ -0,0 +1,15 @@
+-- Optional quotas of a key per UTC day and calendar month; NULL is unlimited
+ALTER TABLE api_keys
+    ADD COLUMN daily_requests BIGINT,
+    ADD COLUMN monthly_requests BIGINT,
+    ADD COLUMN daily_inference_seconds DOUBLE PRECISION,
+    ADD COLUMN monthly_inference_seconds DOUBLE PRECISION;
+
+-- Requests and model time per key and UTC day, added to by every instance
+CREATE TABLE api_key_usage (
+    key_id UUID NOT NULL REFERENCES api_keys (id),
+    day DATE NOT NULL,
+    requests BIGINT NOT NULL DEFAULT 0,
+    inference_seconds DOUBLE PRECISION NOT NULL DEFAULT 0,
+    PRIMARY KEY (key_id, day)
+);
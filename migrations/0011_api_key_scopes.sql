@@ -0,0 +1,3 @@
+-- Routes a key may call: 'search', 'register' and/or 'admin' (everything).
+-- Existing keys keep full access.
+ALTER TABLE api_keys ADD COLUMN scopes TEXT[] NOT NULL DEFAULT '{admin}';
@@ -0,0 +1,3 @@
+-- SHA-256 of the body of the first request with a key; a retry with another
+-- body is refused instead of replayed. NULL for keys claimed before this column.
+ALTER TABLE idempotency_keys ADD COLUMN request_hash BYTEA;
@@ -0,0 +1,3 @@
+-- Content-Type of the response replayed to retries, JSON or protobuf. NULL
+-- for outcomes stored before this column, which were all JSON.
+ALTER TABLE idempotency_keys ADD COLUMN content_type VARCHAR(255);
@@ -0,0 +1,32 @@
+use axum::{body::HttpBody, extract::Request, http::header, middleware::Next, response::Response};
+use std::time::Instant;
+
+// Layer of the whole router, inside the request span: one line per request
+// with its method, path, status, latency and body sizes. Bodies themselves
+// are never logged; they hold images and embeddings.
+pub async fn log_requests(request: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    // Declared size; chunked uploads have none
+    let request_bytes = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+
+    let response = next.run(request).await;
+
+    // Unknown for streamed responses (exports, WebSockets)
+    let response_bytes = response.body().size_hint().exact();
+    tracing::info!(
+        %method,
+        %path,
+        status = response.status().as_u16(),
+        latency_ms = start.elapsed().as_secs_f64() * 1000.0,
+        request_bytes,
+        response_bytes,
+        "Request completed"
+    );
+    response
+}
@@ -0,0 +1,45 @@
+use axum::{
+    extract::Path,
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+
+// Assets are compiled into the binary, so the dashboard needs no files on disk
+const INDEX_HTML: &str = include_str!("../ui/index.html");
+const APP_JS: &str = include_str!("../ui/app.js");
+const STYLE_CSS: &str = include_str!("../ui/style.css");
+
+// The pages hold no data: they call the tenant API with the credential the
+// operator enters, so they are served without authentication. Scripts and
+// styles only load from here.
+const CONTENT_SECURITY_POLICY: &str =
+    "default-src 'none'; script-src 'self'; style-src 'self'; img-src 'self' blob:; connect-src 'self'; form-action 'none'; frame-ancestors 'none'";
+
+fn asset(content_type: &'static str, body: &'static str) -> Response {
+    (
+        [
+            (header::CONTENT_TYPE, HeaderValue::from_static(content_type)),
+            (header::CACHE_CONTROL, HeaderValue::from_static("no-cache")),
+            (
+                header::CONTENT_SECURITY_POLICY,
+                HeaderValue::from_static(CONTENT_SECURITY_POLICY),
+            ),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+// Handler for GET /admin/ui
+pub async fn index() -> Response {
+    asset("text/html; charset=utf-8", INDEX_HTML)
+}
+
+// Handler for GET /admin/ui/:file
+pub async fn file(Path(file): Path<String>) -> Response {
+    match file.as_str() {
+        "app.js" => asset("text/javascript; charset=utf-8", APP_JS),
+        "style.css" => asset("text/css; charset=utf-8", STYLE_CSS),
+        _ => StatusCode::NOT_FOUND.into_response(),
+    }
+}
@@ -0,0 +1,241 @@
+// Payloads of the HTTP API shared by the server and the Rust client, so both
+// (de)serialize the same types. Compiled into the binary (main.rs) and the
+// library (lib.rs) alike, hence serde, serde_json and uuid only.
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+// Free-form JSON attributes attached to a target (name, external ids, tags...)
+pub type Metadata = serde_json::Map<String, serde_json::Value>;
+
+// Define the request payload for /register/
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RegisterPayload {
+    // Omitted with register_all_faces
+    #[serde(default)]
+    pub target_uuid: Uuid,
+    // Omitted with a precomputed embedding
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub image_base64: String,
+    // Embedding computed by the client with the same model, instead of an
+    // image; normalized before it is stored
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub embedding: Option<Vec<f32>>,
+    pub origin: String,
+    // Arbitrary attributes of the target (name, external ids, tags...)
+    #[serde(default, skip_serializing_if = "Metadata::is_empty")]
+    pub metadata: Metadata,
+    // Enroll every detected face under a new uuid (needs a face detector)
+    #[serde(default)]
+    pub register_all_faces: bool,
+    // Enroll this detected face, largest first, instead of the largest one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub face_index: Option<usize>,
+    // Include the aligned crop fed to the model
+    #[serde(default)]
+    pub return_crops: bool,
+    // Include the stored embedding
+    #[serde(default)]
+    pub return_embedding: bool,
+    // Unix time (seconds) after which the registration is deleted; the server
+    // retention policy (RETENTION_SECS) may remove it earlier
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+    // What happens when the uuid is already registered
+    #[serde(default)]
+    pub mode: RegisterMode,
+    // Answer 409 Conflict when another uuid already has a face at least this
+    // similar, e.g. the same person enrolled twice by mistake
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reject_if_similar_above: Option<f32>,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RegisterMode {
+    // Add the embedding next to the existing ones
+    #[default]
+    Append,
+    // Drop the existing embeddings of the uuid first
+    Replace,
+    // Answer 409 Conflict
+    RejectIfExists,
+}
+
+impl std::str::FromStr for RegisterMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "append" => Ok(RegisterMode::Append),
+            "replace" => Ok(RegisterMode::Replace),
+            "reject_if_exists" => Ok(RegisterMode::RejectIfExists),
+            other => Err(format!("invalid registration mode '{}'", other)),
+        }
+    }
+}
+
+// Define the request payload for /search/
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SearchPayload {
+    // Omitted with a precomputed embedding
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub image_base64: String,
+    // Query embedding computed by the client with the same model, instead of
+    // an image; decoding, detection and inference are skipped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub embedding: Option<Vec<f32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub threshold: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub enhance: EnhanceOptions,
+    // Return each uuid at most once, with its best similarity
+    #[serde(default)]
+    pub group_by_uuid: bool,
+    // Include the number of matching entries per uuid (only with group_by_uuid)
+    #[serde(default)]
+    pub hit_counts: bool,
+    // Only consider targets enrolled from one of these origins
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub origins: Option<Vec<String>>,
+    // Only consider targets whose metadata contains all of these key/value pairs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Metadata>,
+    // Skip these targets, e.g. staff when looking for unknown visitors
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exclude_uuids: Option<Vec<Uuid>>,
+    // Skip targets enrolled from these origins
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exclude_origins: Option<Vec<String>>,
+    // Time budget for the whole request; the scan stops when it runs out
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time_budget_ms: Option<u64>,
+    // IVF lists to scan (see IVF_NPROBE): more finds more of the weaker
+    // matches, fewer answers faster
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nprobe: Option<usize>,
+    // Estimate age and gender of the query face (needs an attributes model)
+    #[serde(default)]
+    pub attributes: bool,
+    // Estimate the expression of the query face (needs an emotion model)
+    #[serde(default)]
+    pub emotion: bool,
+    // Include the aligned crop of each query face fed to the model
+    #[serde(default)]
+    pub return_crops: bool,
+    // Include the embedding of each query face
+    #[serde(default)]
+    pub return_embedding: bool,
+    // Camera the query frame comes from, reported in match events
+    #[serde(skip)]
+    pub source: Option<String>,
+    // Several frames of the same person searched as one query, instead of
+    // `image_base64`; the largest face of each is used
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images_base64: Vec<String>,
+    // How the frames of `images_base64` are combined
+    #[serde(default)]
+    pub fusion: Fusion,
+    // Also search the archived targets, read from the database (see
+    // ARCHIVE_AFTER_DAYS)
+    #[serde(default)]
+    pub include_archived: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Fusion {
+    // One search with the mean of the frame embeddings
+    #[default]
+    Mean,
+    // One search per frame, each uuid keeping its best similarity
+    Max,
+}
+
+// Per-request switches for the query-side enhancement stage
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug)]
+pub struct EnhanceOptions {
+    // Equalize the luminance histogram (helps with dark or washed-out frames)
+    #[serde(default)]
+    pub equalize: bool,
+    // Upscale the image with the configured super-resolution model
+    #[serde(default)]
+    pub super_resolution: bool,
+}
+
+// A face found in an image, in pixel coordinates of that image
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct DetectedFace {
+    // [x1, y1, x2, y2]
+    pub bbox: [f32; 4],
+    pub score: f32,
+    // Left eye, right eye, nose tip, left and right mouth corners
+    pub landmarks: [[f32; 2]; 5],
+}
+
+// Head orientation in degrees; 0 everywhere is a frontal face
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct Pose {
+    pub yaw: f32,
+    pub pitch: f32,
+    pub roll: f32,
+}
+
+// Outcome of the mask check of a face
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct MaskCheck {
+    pub masked: bool,
+    // Probability that the face wears a mask, between 0 and 1
+    pub score: f32,
+}
+
+// Image quality measures of an enrollment
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct QualityScores {
+    // Variance of the Laplacian; low values mean a blurry image
+    pub sharpness: f32,
+    // Mean luma, 0-255
+    pub brightness: f32,
+    // Standard deviation of the luma
+    pub contrast: f32,
+    // Smaller side of the face box in pixels; without a face detector images
+    // are taken to be face crops and this is the smaller side of the image
+    pub face_size: u32,
+}
+
+// Estimated demographic attributes of a face
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct Attributes {
+    pub age: u32,
+    // Bracket holding the estimate, e.g. [30, 39]
+    pub age_range: [u32; 2],
+    pub gender: Gender,
+    // Probability of the estimated gender, between 0.5 and 1
+    pub gender_confidence: f32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Gender {
+    Female,
+    Male,
+}
+
+// Estimated facial expression of a face
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Emotion {
+    // Most probable class
+    pub emotion: String,
+    // Its probability, between 0 and 1
+    pub confidence: f32,
+    // Probability of every class, in the model's order
+    pub scores: Vec<EmotionScore>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EmotionScore {
+    pub emotion: String,
+    pub probability: f32,
+}
@@ -0,0 +1,29 @@
+use axum::{
+    extract::Request,
+    http::{header, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+
+// Prefix of the current API. A breaking change ships as a /v2 router nested
+// next to it, reusing the /v1 routes that did not change, while /v1 keeps
+// answering as before.
+pub const V1: &str = "/v1";
+
+// Middleware of the unprefixed aliases of the /v1 routes: they answer the
+// same, flagged as deprecated with a link to their /v1 path (RFC 9745)
+pub async fn deprecated(request: Request, next: Next) -> Response {
+    let successor = format!(
+        "<{}{}>; rel=\"successor-version\"",
+        V1,
+        request.uri().path()
+    );
+    tracing::debug!(path = %request.uri().path(), "Request to a deprecated unversioned path");
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    headers.insert("deprecation", HeaderValue::from_static("true"));
+    if let Ok(link) = HeaderValue::from_str(&successor) {
+        headers.append(header::LINK, link);
+    }
+    response
+}
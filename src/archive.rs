@@ -0,0 +1,202 @@
+use futures_util::TryStreamExt;
+use sqlx::{PgPool, Row};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::collections::{Collection, Collections};
+use crate::encryption;
+use crate::resync::TargetKey;
+use crate::store::{self, Metadata, NewEmbedding, SearchOptions, SearchResults};
+use crate::AppState;
+
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// Archived rows held in memory at once by an on-demand search
+const SEARCH_BATCH_ROWS: usize = 50_000;
+
+// Targets kept out of memory: those not registered again for `after_secs`,
+// and those registered only from `origins`. They stay in the database,
+// accounted for like evicted ones, and are searched on request.
+#[derive(Clone, Debug, Default)]
+pub struct ArchivePolicy {
+    pub after_secs: Option<i64>,
+    pub origins: Vec<String>,
+}
+
+// Per-origin live rows of the archived targets
+async fn archived_targets(
+    pool: &PgPool,
+    policy: &ArchivePolicy,
+) -> Result<HashMap<TargetKey, Vec<(String, u32)>>, sqlx::Error> {
+    let mut targets: HashMap<TargetKey, Vec<(String, u32)>> = HashMap::new();
+    let mut rows = sqlx::query(
+        r#"
+        SELECT tenant, collection, uuid, origin, COUNT(*) AS rows FROM targets
+        WHERE deleted_at IS NULL AND (tenant, collection, uuid) IN (
+            SELECT tenant, collection, uuid FROM targets WHERE deleted_at IS NULL
+            GROUP BY tenant, collection, uuid
+            HAVING ($1::BIGINT IS NOT NULL AND MAX(created_at) < now() - $1::BIGINT * interval '1 second')
+                OR bool_and(origin = ANY($2))
+        )
+        GROUP BY tenant, collection, uuid, origin
+        "#,
+    )
+    .bind(policy.after_secs)
+    .bind(&policy.origins)
+    .fetch(pool);
+    while let Some(row) = rows.try_next().await? {
+        let key = (
+            row.try_get("tenant")?,
+            row.try_get("collection")?,
+            row.try_get("uuid")?,
+        );
+        let registrations: i64 = row.try_get("rows")?;
+        targets
+            .entry(key)
+            .or_default()
+            .push((row.try_get("origin")?, registrations as u32));
+    }
+    Ok(targets)
+}
+
+// Moves the archived targets out of memory: those loaded are evicted, those
+// the loader skipped are accounted for. A target paged back in since, e.g. to
+// be verified, goes out again.
+pub async fn sweep(
+    pool: &PgPool,
+    collections: &Collections,
+    policy: &ArchivePolicy,
+) -> Result<(), sqlx::Error> {
+    let start = Instant::now();
+    let targets = archived_targets(pool, policy).await?;
+    let (mut evicted, mut held) = (0, 0);
+    for ((tenant, name, uuid), registrations) in targets {
+        let store = &collections.get_or_create(&tenant, &name).store;
+        if store.evict(&uuid).await > 0 {
+            evicted += 1;
+        } else if store.hold(uuid, registrations).await {
+            held += 1;
+        }
+    }
+    if evicted > 0 || held > 0 {
+        tracing::info!(evicted, held, duration = ?start.elapsed(), "Stale targets archived");
+    }
+    Ok(())
+}
+
+pub async fn run(
+    pool: PgPool,
+    collections: Arc<Collections>,
+    policy: Arc<ArchivePolicy>,
+    interval: Duration,
+) {
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = sweep(&pool, &collections, &policy).await {
+            tracing::error!(error = %e, "Failed to archive stale targets");
+        }
+    }
+}
+
+// Searches the archived targets of a collection, streamed from the database
+// in batches of whole targets scanned like the collection's store; targets
+// in memory again are skipped, the store search covers them
+pub async fn search(
+    state: &AppState,
+    policy: &ArchivePolicy,
+    tenant: &str,
+    name: &str,
+    collection: &Arc<Collection>,
+    query: &[f32],
+    options: &SearchOptions,
+) -> Result<SearchResults, sqlx::Error> {
+    let start = Instant::now();
+    let mut found = SearchResults::default();
+    let mut rows = sqlx::query(
+        r#"
+        SELECT uuid, embeddings, embeddings_sealed, origin, metadata, model FROM targets
+        WHERE tenant = $1 AND collection = $2 AND deleted_at IS NULL AND uuid IN (
+            SELECT uuid FROM targets WHERE tenant = $1 AND collection = $2 AND deleted_at IS NULL
+            GROUP BY uuid
+            HAVING ($3::BIGINT IS NOT NULL AND MAX(created_at) < now() - $3::BIGINT * interval '1 second')
+                OR bool_and(origin = ANY($4))
+        )
+        ORDER BY uuid, id
+        "#,
+    )
+    .bind(tenant)
+    .bind(name)
+    .bind(policy.after_secs)
+    .bind(&policy.origins)
+    .fetch(&state.read_pool);
+
+    let mut batch: Vec<NewEmbedding> = Vec::new();
+    let mut scanned = 0;
+    // Uuid of the previous row and whether it is in memory
+    let mut current: Option<(Uuid, bool)> = None;
+    loop {
+        let row = rows.try_next().await?;
+        let next = match &row {
+            Some(row) => Some(row.try_get::<Uuid, _>("uuid")?),
+            None => None,
+        };
+        // Batches are cut between targets, so templates are folded whole
+        let boundary = batch.last().is_some_and(|last| Some(last.uuid) != next);
+        if boundary && (next.is_none() || batch.len() >= SEARCH_BATCH_ROWS) {
+            scanned += batch.len();
+            let embeddings = std::mem::take(&mut batch);
+            let results = search_batch(state, collection, embeddings, query, options).await;
+            found.matches.extend(results.matches);
+            found.partial |= results.partial;
+        }
+        let (Some(row), Some(uuid)) = (row, next) else {
+            break;
+        };
+        if options
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            found.partial = true;
+            break;
+        }
+        let in_memory = match current {
+            Some((previous, in_memory)) if previous == uuid => in_memory,
+            _ => collection.store.contains(&uuid).await,
+        };
+        current = Some((uuid, in_memory));
+        if in_memory {
+            continue;
+        }
+        let metadata: sqlx::types::Json<Metadata> = row.try_get("metadata")?;
+        batch.push(NewEmbedding {
+            uuid,
+            origin: row.try_get("origin")?,
+            metadata: metadata.0,
+            embedding: encryption::from_row(&row)?,
+            model_version: row.try_get("model")?,
+            expires_at: None,
+        });
+    }
+    // Both sides are grouped already when asked, and hold different targets
+    store::top_k(&mut found.matches, options.limit);
+    tracing::info!(%tenant, collection = %name, rows = scanned, matches = found.matches.len(), duration = ?start.elapsed(), "Archived targets searched");
+    Ok(found)
+}
+
+async fn search_batch(
+    state: &AppState,
+    collection: &Arc<Collection>,
+    embeddings: Vec<NewEmbedding>,
+    query: &[f32],
+    options: &SearchOptions,
+) -> SearchResults {
+    let archived = state.collections.detached(collection.settings);
+    archived.store.add_batch(embeddings).await;
+    let (query, options) = (query.to_vec(), options.clone());
+    tokio::task::spawn_blocking(move || archived.store.find_similar(&query, &options))
+        .await
+        .unwrap_or_default()
+}
@@ -0,0 +1,74 @@
+use image::DynamicImage;
+use ndarray::Array;
+use std::path::Path;
+
+pub use crate::api::{Attributes, Gender};
+use crate::embedder::SessionThreads;
+use crate::onnx::{self, ImageModel};
+
+// Width of the reported age ranges, in years
+const AGE_BRACKET: u32 = 10;
+
+// Age and gender model in the layout of InsightFace's genderage.onnx: a
+// [1, 3, H, W] RGB input with raw 0-255 values, and [female, male, age / 100]
+// as output
+pub struct AttributeModel {
+    model: ImageModel,
+    input_width: u32,
+    input_height: u32,
+}
+
+impl AttributeModel {
+    pub fn load(
+        model_path: &Path,
+        threads: SessionThreads,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let model = ImageModel::load(model_path, threads)?;
+
+        // Dynamic dimensions fall back to 96x96, the genderage input size
+
+        Ok(Self {
+            input_height: model.dim(2, 96),
+            input_width: model.dim(3, 96),
+            model,
+        })
+    }
+
+    pub fn estimate(&self, img: &DynamicImage) -> Result<Attributes, Box<dyn std::error::Error>> {
+        let resized = img
+            .resize_exact(
+                self.input_width,
+                self.input_height,
+                image::imageops::FilterType::Triangle,
+            )
+            .to_rgb8();
+
+        let mut input = Array::zeros((1, 3, self.input_height as usize, self.input_width as usize));
+        for (x, y, pixel) in resized.enumerate_pixels() {
+            for channel in 0..3 {
+                input[[0, channel, y as usize, x as usize]] = pixel[channel] as f32;
+            }
+        }
+
+        let output = self.model.run_first(input)?;
+
+        let [female, male, age] = output[..] else {
+            return Err(format!("expected 3 attribute outputs, got {}", output.len()).into());
+        };
+        let male_probability = onnx::softmax(&[female, male])[1];
+        let (gender, gender_confidence) = if male_probability >= 0.5 {
+            (Gender::Male, male_probability)
+        } else {
+            (Gender::Female, 1.0 - male_probability)
+        };
+        let age = (age * 100.0).round().max(0.0) as u32;
+        let bracket_start = age / AGE_BRACKET * AGE_BRACKET;
+
+        Ok(Attributes {
+            age,
+            age_range: [bracket_start, bracket_start + AGE_BRACKET - 1],
+            gender,
+            gender_confidence,
+        })
+    }
+}
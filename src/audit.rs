@@ -0,0 +1,436 @@
+use axum::{
+    extract::{ConnectInfo, MatchedPath, Query, Request, State},
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::Response,
+    Json,
+};
+use futures_util::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::{PgPool, Row};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+
+use crate::auth::{AdminKey, ApiKey};
+use crate::jwt::Claims;
+use crate::request_id::RequestId;
+use crate::tenant::Tenant;
+use crate::util;
+use crate::AppState;
+
+// Entries waiting for the writer; requests wait for room once it is full
+const QUEUE_SIZE: usize = 10_000;
+// Entries chained and inserted per transaction
+const BATCH_ENTRIES: usize = 500;
+// Pause before writing a failed batch again
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+// Hash the chain starts from
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+// Serializes the writers of every instance on the tail of the chain
+const CHAIN_LOCK: i64 = 0x6f776c5f61756474;
+const DEFAULT_QUERY_LIMIT: i64 = 100;
+const MAX_QUERY_LIMIT: i64 = 1000;
+
+// Result summary of a request, attached by handlers as a response extension
+// and recorded with the request
+#[derive(Clone)]
+pub struct Summary(pub serde_json::Value);
+
+// One audited operation
+pub struct AuditEntry {
+    // None for admin operations
+    pub tenant: Option<String>,
+    pub actor: String,
+    // "METHOD /route" for HTTP, "grpc Method" for gRPC
+    pub action: String,
+    pub parameters: serde_json::Value,
+    // HTTP status of the outcome, also for gRPC calls
+    pub status: u16,
+    pub result: Option<serde_json::Value>,
+}
+
+// Fields covered by the hash of a row, in a fixed order
+#[derive(Serialize)]
+struct Chained<'a> {
+    prev_hash: &'a str,
+    created_at: i64,
+    tenant: Option<&'a str>,
+    actor: &'a str,
+    action: &'a str,
+    parameters: &'a serde_json::Value,
+    status: i16,
+    result: Option<&'a serde_json::Value>,
+}
+
+impl Chained<'_> {
+    fn hash(&self) -> String {
+        let encoded = serde_json::to_vec(self).unwrap_or_default();
+        hex::encode(Sha256::digest(&encoded))
+    }
+}
+
+// Append-only log of every mutation and search, each row hashed together with
+// the hash of the previous one so edits and removals break the chain
+pub struct AuditLog {
+    sender: mpsc::Sender<(i64, AuditEntry)>,
+    closing: Arc<Notify>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl AuditLog {
+    // Starts the writer task; entries are written in the background, in order
+    pub fn start(pool: PgPool) -> Self {
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        let closing = Arc::new(Notify::new());
+        let writer = tokio::spawn(write_entries(pool, receiver, closing.clone()));
+        Self {
+            sender,
+            closing,
+            writer: Mutex::new(Some(writer)),
+        }
+    }
+
+    // Stops taking entries and waits for the queued ones to be written, at
+    // shutdown
+    pub async fn close(&self) {
+        self.closing.notify_one();
+        let writer = self.writer.lock().unwrap_or_else(|e| e.into_inner()).take();
+        if let Some(writer) = writer {
+            writer.await.ok();
+        }
+    }
+
+    pub async fn record(&self, entry: AuditEntry) {
+        if self.sender.send((util::unix_now(), entry)).await.is_err() {
+            tracing::error!("Audit writer stopped, entry lost");
+        }
+    }
+}
+
+async fn write_entries(
+    pool: PgPool,
+    mut receiver: mpsc::Receiver<(i64, AuditEntry)>,
+    closing: Arc<Notify>,
+) {
+    let mut batch = Vec::with_capacity(BATCH_ENTRIES);
+    loop {
+        let entry = tokio::select! {
+            entry = receiver.recv() => entry,
+            _ = closing.notified() => {
+                // Queued entries are still received, then `recv` ends
+                receiver.close();
+                continue;
+            }
+        };
+        let Some(entry) = entry else {
+            break;
+        };
+        batch.push(entry);
+        while batch.len() < BATCH_ENTRIES {
+            match receiver.try_recv() {
+                Ok(entry) => batch.push(entry),
+                Err(_) => break,
+            }
+        }
+        // Entries are never dropped: a failing database holds the queue, and
+        // with it the audited requests
+        while let Err(e) = write_batch(&pool, &batch).await {
+            tracing::error!(entries = batch.len(), error = %e, "Failed to write audit entries, retrying");
+            tokio::time::sleep(RETRY_DELAY).await;
+        }
+        batch.clear();
+    }
+}
+
+async fn write_batch(pool: &PgPool, batch: &[(i64, AuditEntry)]) -> Result<(), sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    sqlx::query("SELECT pg_advisory_xact_lock($1)")
+        .bind(CHAIN_LOCK)
+        .execute(&mut *transaction)
+        .await?;
+    let mut prev_hash: String =
+        sqlx::query_scalar("SELECT hash FROM audit_log ORDER BY id DESC LIMIT 1")
+            .fetch_optional(&mut *transaction)
+            .await?
+            .unwrap_or_else(|| GENESIS_HASH.to_string());
+    for (created_at, entry) in batch {
+        let chained = Chained {
+            prev_hash: &prev_hash,
+            created_at: *created_at,
+            tenant: entry.tenant.as_deref(),
+            actor: &entry.actor,
+            action: &entry.action,
+            parameters: &entry.parameters,
+            status: entry.status as i16,
+            result: entry.result.as_ref(),
+        };
+        let hash = chained.hash();
+        sqlx::query(
+            "INSERT INTO audit_log (created_at, tenant, actor, action, parameters, status, result, prev_hash, hash) VALUES (to_timestamp($1), $2, $3, $4, $5, $6, $7, $8, $9)",
+        )
+        .bind(*created_at as f64)
+        .bind(&entry.tenant)
+        .bind(&entry.actor)
+        .bind(&entry.action)
+        .bind(&entry.parameters)
+        .bind(entry.status as i16)
+        .bind(&entry.result)
+        .bind(&prev_hash)
+        .bind(&hash)
+        .execute(&mut *transaction)
+        .await?;
+        prev_hash = hash;
+    }
+    transaction.commit().await
+}
+
+// Who made a request, as authenticated: the subject of the verified JWT or
+// the id of the API key, else the client address. Credentials the request
+// merely carries are not trusted.
+pub fn actor(claims: Option<&Claims>, key: Option<&ApiKey>, peer: Option<SocketAddr>) -> String {
+    if let Some(subject) = claims.and_then(|claims| claims.get_str("sub")) {
+        return format!("sub:{}", subject);
+    }
+    if let Some(key) = key {
+        return format!("key:{}", key.id);
+    }
+    match peer {
+        Some(address) => format!("ip:{}", address.ip()),
+        None => "ip:unknown".to_string(),
+    }
+}
+
+// Route layer recording every request other than reads; images and other
+// bodies are not recorded, only the path, query and result summary
+pub async fn record(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let Some(audit) = state.audit.clone() else {
+        return next.run(request).await;
+    };
+    if matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    ) {
+        return next.run(request).await;
+    }
+
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| request.uri().path().to_string());
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(address)| *address);
+    let actor = if request.extensions().get::<AdminKey>().is_some() {
+        "admin".to_string()
+    } else {
+        actor(
+            request.extensions().get::<Claims>(),
+            request.extensions().get::<ApiKey>(),
+            peer,
+        )
+    };
+    let tenant = request
+        .extensions()
+        .get::<Tenant>()
+        .map(|tenant| tenant.id().to_string());
+    let parameters = json!({
+        "path": request.uri().path(),
+        "query": request.uri().query(),
+        "request_id": request.extensions().get::<RequestId>().map(RequestId::as_str),
+    });
+    let action = format!("{} {}", request.method(), route);
+
+    let response = next.run(request).await;
+    audit
+        .record(AuditEntry {
+            tenant,
+            actor,
+            action,
+            parameters,
+            status: response.status().as_u16(),
+            result: response
+                .extensions()
+                .get::<Summary>()
+                .map(|summary| summary.0.clone()),
+        })
+        .await;
+    response
+}
+
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    tenant: Option<String>,
+    actor: Option<String>,
+    action: Option<String>,
+    // Unix seconds, inclusive
+    since: Option<i64>,
+    until: Option<i64>,
+    // Entries older than this id, for paging back from `next_before_id`
+    before_id: Option<i64>,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct AuditRecord {
+    id: i64,
+    created_at: String,
+    tenant: Option<String>,
+    actor: String,
+    action: String,
+    parameters: serde_json::Value,
+    status: i16,
+    result: Option<serde_json::Value>,
+    hash: String,
+}
+
+// Define the response for /admin/audit
+#[derive(Serialize)]
+pub struct AuditResponse {
+    entries: Vec<AuditRecord>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_before_id: Option<i64>,
+}
+
+// Handler for GET /admin/audit - newest entries first, filtered by tenant,
+// actor, action and time range
+pub async fn query_audit(
+    State(state): State<AppState>,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<AuditResponse>, StatusCode> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_QUERY_LIMIT)
+        .clamp(1, MAX_QUERY_LIMIT);
+    let rows = sqlx::query(
+        r#"
+        SELECT id, EXTRACT(EPOCH FROM created_at)::BIGINT AS created_at, tenant, actor,
+               action, parameters, status, result, hash
+        FROM audit_log
+        WHERE ($1::TEXT IS NULL OR tenant = $1)
+          AND ($2::TEXT IS NULL OR actor = $2)
+          AND ($3::TEXT IS NULL OR action = $3)
+          AND ($4::BIGINT IS NULL OR created_at >= to_timestamp($4))
+          AND ($5::BIGINT IS NULL OR created_at <= to_timestamp($5))
+          AND ($6::BIGINT IS NULL OR id < $6)
+        ORDER BY id DESC
+        LIMIT $7
+        "#,
+    )
+    .bind(&query.tenant)
+    .bind(&query.actor)
+    .bind(&query.action)
+    .bind(query.since)
+    .bind(query.until)
+    .bind(query.before_id)
+    .bind(limit)
+    .fetch_all(&state.read_pool)
+    .await
+    .map_err(|e| {
+        tracing::error!(error = %e, "Failed to query audit log");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let entries = rows
+        .iter()
+        .map(|row| {
+            Ok(AuditRecord {
+                id: row.try_get("id")?,
+                created_at: util::format_rfc3339(row.try_get("created_at")?),
+                tenant: row.try_get("tenant")?,
+                actor: row.try_get("actor")?,
+                action: row.try_get("action")?,
+                parameters: row.try_get("parameters")?,
+                status: row.try_get("status")?,
+                result: row.try_get("result")?,
+                hash: row.try_get("hash")?,
+            })
+        })
+        .collect::<Result<Vec<AuditRecord>, sqlx::Error>>()
+        .map_err(|e| {
+            tracing::error!(error = %e, "Failed to decode audit log");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let next_before_id = (entries.len() as i64 == limit)
+        .then(|| entries.last().map(|entry| entry.id))
+        .flatten();
+    Ok(Json(AuditResponse {
+        entries,
+        next_before_id,
+    }))
+}
+
+// Define the response for /admin/audit/verify
+#[derive(Serialize)]
+pub struct VerifyResponse {
+    valid: bool,
+    entries: u64,
+    // Hash of the last entry; kept outside the database, it also reveals
+    // removals at the end of the chain
+    head_hash: String,
+    // First entry whose hash or link does not match
+    #[serde(skip_serializing_if = "Option::is_none")]
+    broken_at: Option<i64>,
+}
+
+// Handler for GET /admin/audit/verify - recomputes the whole hash chain
+pub async fn verify_audit(
+    State(state): State<AppState>,
+) -> Result<Json<VerifyResponse>, StatusCode> {
+    let verified = async {
+        let mut rows = sqlx::query(
+            "SELECT id, EXTRACT(EPOCH FROM created_at)::BIGINT AS created_at, tenant, actor, action, parameters, status, result, prev_hash, hash FROM audit_log ORDER BY id",
+        )
+        .fetch(&state.read_pool);
+        let mut head_hash = GENESIS_HASH.to_string();
+        let mut entries = 0;
+        while let Some(row) = rows.try_next().await? {
+            let id: i64 = row.try_get("id")?;
+            let prev_hash: String = row.try_get("prev_hash")?;
+            let hash: String = row.try_get("hash")?;
+            let tenant: Option<String> = row.try_get("tenant")?;
+            let actor: String = row.try_get("actor")?;
+            let action: String = row.try_get("action")?;
+            let parameters: serde_json::Value = row.try_get("parameters")?;
+            let result: Option<serde_json::Value> = row.try_get("result")?;
+            let chained = Chained {
+                prev_hash: &prev_hash,
+                created_at: row.try_get("created_at")?,
+                tenant: tenant.as_deref(),
+                actor: &actor,
+                action: &action,
+                parameters: &parameters,
+                status: row.try_get("status")?,
+                result: result.as_ref(),
+            };
+            if prev_hash != head_hash || chained.hash() != hash {
+                return Ok::<_, sqlx::Error>((false, entries, head_hash, Some(id)));
+            }
+            head_hash = hash;
+            entries += 1;
+        }
+        Ok((true, entries, head_hash, None))
+    }
+    .await;
+    let (valid, entries, head_hash, broken_at) = verified.map_err(|e| {
+        tracing::error!(error = %e, "Failed to verify audit log");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    if valid {
+        tracing::info!(entries, "Audit log verified");
+    } else {
+        tracing::error!(?broken_at, "Audit log chain is broken");
+    }
+    Ok(Json(VerifyResponse {
+        valid,
+        entries,
+        head_hash,
+        broken_at,
+    }))
+}
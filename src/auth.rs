@@ -0,0 +1,691 @@
+use axum::{
+    extract::{MatchedPath, Path, Request, State},
+    http::{HeaderMap, StatusCode},
+    middleware::Next,
+    response::Response,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{PgPool, Row};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::api_version;
+use crate::backpressure::{self, Priority};
+use crate::collections;
+use crate::jwt::{Claims, JwtError, JwtVerifier};
+use crate::key_usage::Quotas;
+use crate::tenant::{Tenant, DEFAULT_TENANT};
+use crate::util;
+use crate::AppState;
+
+// How long a key lookup is trusted before asking the database again, which
+// bounds how long a key revoked on another instance keeps working here
+const KEY_CACHE_TTL: Duration = Duration::from_secs(30);
+// Cached lookups are dropped wholesale past this size (e.g. a flood of bogus keys)
+const KEY_CACHE_CAPACITY: usize = 10_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthMode {
+    // Endpoints are open; tenants come from TENANT_API_KEYS / TENANT_HEADER
+    None,
+    // Every request needs an X-API-Key stored in the 'api_keys' table
+    ApiKey,
+    // Every request needs an `Authorization: Bearer` JWT of the configured issuer
+    Jwt,
+}
+
+impl FromStr for AuthMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "none" | "off" => Ok(AuthMode::None),
+            "api-key" | "apikey" => Ok(AuthMode::ApiKey),
+            "jwt" | "oidc" => Ok(AuthMode::Jwt),
+            other => Err(format!("invalid auth mode '{}'", other)),
+        }
+    }
+}
+
+// What an API key may call. Devices deployed in the open get `search` or
+// `register` alone, so a stolen one cannot list, export or delete targets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    // Searches, verification and image analysis
+    Search,
+    // Registrations
+    Register,
+    // Requests sent with `X-Priority: high`, ahead of everyone else's
+    Priority,
+    // Every tenant route, including the two above
+    Admin,
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "search" => Ok(Scope::Search),
+            "register" => Ok(Scope::Register),
+            "priority" => Ok(Scope::Priority),
+            "admin" => Ok(Scope::Admin),
+            other => Err(format!("invalid scope '{}'", other)),
+        }
+    }
+}
+
+impl Scope {
+    fn as_str(self) -> &'static str {
+        match self {
+            Scope::Search => "search",
+            Scope::Register => "register",
+            Scope::Priority => "priority",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+// Scope needed by a tenant route, given as its pattern with or without the
+// version prefix; routes not listed here need `admin`
+fn route_scope(route: &str) -> Scope {
+    match route.strip_prefix(api_version::V1).unwrap_or(route) {
+        "/search/"
+        | "/collections/:name/search/"
+        | "/search/video/"
+        | "/ws/search"
+        | "/verify/"
+        | "/analyze/"
+        | "/detect/"
+        | "/landmarks/"
+        | "/compare/matrix" => Scope::Search,
+        "/register/"
+        | "/register/stream"
+        | "/collections/:name/register/"
+        | "/enrollments"
+        | "/enrollments/:id"
+        | "/enrollments/:id/photos"
+        | "/enrollments/:id/commit" => Scope::Register,
+        _ => Scope::Admin,
+    }
+}
+
+// Keys are random, so a plain SHA-256 is enough to keep them out of the database
+pub fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+fn generate_key() -> String {
+    let bytes: [u8; 32] = rand::random();
+    format!("owl_{}", hex::encode(bytes))
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+}
+
+fn header_key(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get("x-api-key")
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+}
+
+pub struct Auth {
+    mode: AuthMode,
+    pool: PgPool,
+    // Hash of ADMIN_API_KEY; admin routes are disabled without it
+    admin_key_hash: Option<String>,
+    // key hash -> (the key, None if unknown or revoked; lookup time)
+    cache: Mutex<HashMap<String, (Option<ApiKey>, Instant)>>,
+    // Token validation of the JWT mode
+    jwt: Option<JwtVerifier>,
+    // Claim holding the tenant of a JWT; without it tenants are resolved as usual
+    tenant_claim: Option<String>,
+}
+
+impl Auth {
+    pub fn new(mode: AuthMode, pool: PgPool, admin_key: Option<&str>) -> Self {
+        Self {
+            mode,
+            pool,
+            admin_key_hash: admin_key.map(hash_key),
+            cache: Mutex::new(HashMap::new()),
+            jwt: None,
+            tenant_claim: None,
+        }
+    }
+
+    pub fn with_jwt(mut self, verifier: JwtVerifier, tenant_claim: Option<String>) -> Self {
+        self.jwt = Some(verifier);
+        self.tenant_claim = tenant_claim;
+        self
+    }
+
+    pub fn mode(&self) -> AuthMode {
+        self.mode
+    }
+
+    pub fn has_admin(&self) -> bool {
+        self.admin_key_hash.is_some()
+    }
+
+    // Active key, with its tenant and quotas
+    async fn lookup(&self, key: &str) -> Result<Option<ApiKey>, sqlx::Error> {
+        let key_hash = hash_key(key);
+        if let Some((api_key, at)) = self
+            .cache
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&key_hash)
+        {
+            if at.elapsed() < KEY_CACHE_TTL {
+                return Ok(api_key.clone());
+            }
+        }
+
+        let api_key = sqlx::query(
+            r#"
+            SELECT id, tenant, scopes, daily_requests, monthly_requests,
+                   daily_inference_seconds, monthly_inference_seconds
+            FROM api_keys
+            WHERE key_hash = $1 AND revoked_at IS NULL
+            "#,
+        )
+        .bind(&key_hash)
+        .fetch_optional(&self.pool)
+        .await?
+        .map(|record| {
+            Ok::<_, sqlx::Error>(ApiKey {
+                id: record.try_get("id")?,
+                tenant: record.try_get("tenant")?,
+                scopes: scopes(&record)?,
+                quotas: quotas(&record)?,
+            })
+        })
+        .transpose()?;
+
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        if cache.len() >= KEY_CACHE_CAPACITY {
+            cache.clear();
+        }
+        cache.insert(key_hash, (api_key.clone(), Instant::now()));
+        Ok(api_key)
+    }
+
+    fn forget(&self, key_hash: &str) {
+        self.cache
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(key_hash);
+    }
+}
+
+// Unknown scopes, written by a newer version, grant nothing
+fn scopes(record: &sqlx::postgres::PgRow) -> Result<Vec<Scope>, sqlx::Error> {
+    let scopes: Vec<String> = record.try_get("scopes")?;
+    Ok(scopes
+        .iter()
+        .filter_map(|scope| scope.parse().ok())
+        .collect())
+}
+
+fn quotas(record: &sqlx::postgres::PgRow) -> Result<Quotas, sqlx::Error> {
+    Ok(Quotas {
+        daily_requests: record.try_get("daily_requests")?,
+        monthly_requests: record.try_get("monthly_requests")?,
+        daily_inference_seconds: record.try_get("daily_inference_seconds")?,
+        monthly_inference_seconds: record.try_get("monthly_inference_seconds")?,
+    })
+}
+
+// API key a request was made with, added as an extension in the API key mode
+#[derive(Clone, Debug)]
+pub struct ApiKey {
+    pub id: Uuid,
+    pub tenant: String,
+    pub scopes: Vec<Scope>,
+    pub quotas: Quotas,
+}
+
+impl ApiKey {
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&Scope::Admin) || self.scopes.contains(&scope)
+    }
+}
+
+// Caller established from the credentials of a request
+#[derive(Default)]
+pub struct Identity {
+    // Tenant of the API key or JWT tenant claim; None leaves the choice to
+    // `tenant::resolve_tenant`
+    pub tenant: Option<Tenant>,
+    pub claims: Option<Claims>,
+    pub key: Option<ApiKey>,
+}
+
+// Rejects with 403 an API key without `scope`; other credentials grant
+// every scope
+pub fn check_scope(identity: &Identity, scope: Scope) -> Result<(), StatusCode> {
+    match &identity.key {
+        Some(key) if !key.allows(scope) => {
+            tracing::warn!(
+                key_id = %key.id,
+                scope = scope.as_str(),
+                "Rejected API key without the required scope"
+            );
+            Err(StatusCode::FORBIDDEN)
+        }
+        _ => Ok(()),
+    }
+}
+
+// Checks the credentials in `headers` according to the auth mode. Shared by
+// the HTTP middleware and the gRPC service (whose metadata maps to headers).
+pub async fn identify(state: &AppState, headers: &HeaderMap) -> Result<Identity, StatusCode> {
+    match state.auth.mode() {
+        AuthMode::None => Ok(Identity::default()),
+        AuthMode::Jwt => identify_jwt(state, headers).await,
+        AuthMode::ApiKey => {
+            let Some(key) = header_key(headers) else {
+                tracing::warn!("Rejected request without X-API-Key");
+                return Err(StatusCode::UNAUTHORIZED);
+            };
+            let api_key = state.auth.lookup(key).await.map_err(|e| {
+                tracing::error!(error = %e, "Failed to look up API key");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+            let Some(api_key) = api_key else {
+                tracing::warn!("Rejected request with unknown or revoked API key");
+                return Err(StatusCode::UNAUTHORIZED);
+            };
+            Ok(Identity {
+                tenant: Some(Tenant(api_key.tenant.clone())),
+                claims: None,
+                key: Some(api_key),
+            })
+        }
+    }
+}
+
+async fn identify_jwt(state: &AppState, headers: &HeaderMap) -> Result<Identity, StatusCode> {
+    let Some(verifier) = &state.auth.jwt else {
+        tracing::error!("JWT auth mode without a configured verifier");
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+    let Some(token) = bearer_token(headers) else {
+        tracing::warn!("Rejected request without bearer token");
+        return Err(StatusCode::UNAUTHORIZED);
+    };
+    let claims = verifier.verify(token).await.map_err(|e| match e {
+        JwtError::Invalid(_) => {
+            tracing::warn!(error = %e, "Rejected request with invalid bearer token");
+            StatusCode::UNAUTHORIZED
+        }
+        JwtError::Jwks(_) => {
+            tracing::error!(error = %e, "Could not validate bearer token");
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+    })?;
+
+    let tenant = match &state.auth.tenant_claim {
+        Some(tenant_claim) => {
+            let tenant = claims
+                .get_str(tenant_claim)
+                .filter(|tenant| collections::is_valid_name(tenant))
+                .ok_or_else(|| {
+                    tracing::warn!(claim = %tenant_claim, "Rejected token without a valid tenant claim");
+                    StatusCode::UNAUTHORIZED
+                })?;
+            Some(Tenant(tenant.to_string()))
+        }
+        None => None,
+    };
+    Ok(Identity {
+        tenant,
+        claims: Some(claims),
+        key: None,
+    })
+}
+
+// Middleware that authenticates the request. The key's tenant (or the JWT's
+// tenant claim) becomes the request tenant, taking precedence over
+// `tenant::resolve_tenant`; JWT claims are added as a `Claims` extension and
+// the key as an `ApiKey` one. Keys without the scope of the route get 403.
+pub async fn authenticate(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let identity = identify(&state, request.headers()).await?;
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str())
+        .unwrap_or_else(|| request.uri().path());
+    check_scope(&identity, route_scope(route))?;
+    // Jumping the inference queue is granted, not self-declared
+    if backpressure::current_priority() == Priority::High {
+        check_scope(&identity, Scope::Priority)?;
+    }
+    if let Some(tenant) = identity.tenant {
+        request.extensions_mut().insert(tenant);
+    }
+    if let Some(claims) = identity.claims {
+        request.extensions_mut().insert(claims);
+    }
+    if let Some(key) = identity.key {
+        request.extensions_mut().insert(key);
+    }
+    Ok(next.run(request).await)
+}
+
+// Marks a request authenticated with the admin key
+#[derive(Clone)]
+pub struct AdminKey;
+
+// Middleware of the /admin/ routes
+pub async fn require_admin(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let authorized = match (&state.auth.admin_key_hash, header_key(request.headers())) {
+        (Some(admin_key_hash), Some(key)) => hash_key(key) == *admin_key_hash,
+        _ => false,
+    };
+    if !authorized {
+        tracing::warn!("Rejected admin request with missing or invalid admin key");
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    request.extensions_mut().insert(AdminKey);
+    Ok(next.run(request).await)
+}
+
+// Define the request payload for POST /admin/api-keys/
+#[derive(Deserialize)]
+pub struct CreateApiKeyPayload {
+    #[serde(default)]
+    tenant: Option<String>,
+    // Free-form label, e.g. the integrator the key is issued to
+    #[serde(default)]
+    name: String,
+    // Full access by default
+    #[serde(default = "default_scopes")]
+    scopes: Vec<Scope>,
+    #[serde(default)]
+    quotas: Quotas,
+}
+
+fn default_scopes() -> Vec<Scope> {
+    vec![Scope::Admin]
+}
+
+fn scope_names(scopes: &[Scope]) -> Vec<&'static str> {
+    scopes.iter().map(|scope| scope.as_str()).collect()
+}
+
+#[derive(Serialize)]
+pub struct CreatedApiKey {
+    id: Uuid,
+    tenant: String,
+    name: String,
+    scopes: Vec<Scope>,
+    quotas: Quotas,
+    // Only returned once; the database keeps the hash
+    key: String,
+}
+
+#[derive(Serialize)]
+pub struct ApiKeyInfo {
+    id: Uuid,
+    tenant: String,
+    name: String,
+    scopes: Vec<Scope>,
+    quotas: Quotas,
+    created_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    revoked_at: Option<String>,
+}
+
+fn valid_quotas(quotas: &Quotas) -> bool {
+    quotas
+        .daily_requests
+        .into_iter()
+        .chain(quotas.monthly_requests)
+        .all(|quota| quota >= 0)
+        && quotas
+            .daily_inference_seconds
+            .into_iter()
+            .chain(quotas.monthly_inference_seconds)
+            .all(|quota| quota.is_finite() && quota >= 0.0)
+}
+
+// Handler for POST /admin/api-keys/
+pub async fn create_api_key(
+    State(state): State<AppState>,
+    Json(payload): Json<CreateApiKeyPayload>,
+) -> Result<(StatusCode, Json<CreatedApiKey>), StatusCode> {
+    let tenant = payload.tenant.unwrap_or_else(|| DEFAULT_TENANT.to_string());
+    if !collections::is_valid_name(&tenant) {
+        tracing::warn!(%tenant, "Received API key creation with invalid tenant");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if payload.name.len() > 128 {
+        tracing::warn!(%tenant, "Received API key creation with a name over 128 bytes");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if payload.scopes.is_empty() {
+        tracing::warn!(%tenant, "Received API key creation without scopes");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if !valid_quotas(&payload.quotas) {
+        tracing::warn!(%tenant, "Received API key creation with negative quotas");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let id = Uuid::new_v4();
+    let key = generate_key();
+    sqlx::query(
+        r#"
+        INSERT INTO api_keys
+            (id, key_hash, tenant, name, scopes, daily_requests, monthly_requests,
+             daily_inference_seconds, monthly_inference_seconds)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        "#,
+    )
+    .bind(id)
+    .bind(hash_key(&key))
+    .bind(&tenant)
+    .bind(&payload.name)
+    .bind(scope_names(&payload.scopes))
+    .bind(payload.quotas.daily_requests)
+    .bind(payload.quotas.monthly_requests)
+    .bind(payload.quotas.daily_inference_seconds)
+    .bind(payload.quotas.monthly_inference_seconds)
+    .execute(&state.db_pool)
+    .await
+    .map_err(|e| {
+        tracing::error!(error = %e, "Failed to store API key");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    tracing::info!(%id, %tenant, scopes = ?payload.scopes, "API key created");
+
+    Ok((
+        StatusCode::CREATED,
+        Json(CreatedApiKey {
+            id,
+            tenant,
+            name: payload.name,
+            scopes: payload.scopes,
+            quotas: payload.quotas,
+            key,
+        }),
+    ))
+}
+
+// Handler for GET /admin/api-keys/
+pub async fn list_api_keys(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<ApiKeyInfo>>, StatusCode> {
+    let records = sqlx::query(
+        r#"
+        SELECT id, tenant, name, scopes, daily_requests, monthly_requests,
+               daily_inference_seconds, monthly_inference_seconds,
+               EXTRACT(EPOCH FROM created_at)::BIGINT AS created_at,
+               EXTRACT(EPOCH FROM revoked_at)::BIGINT AS revoked_at
+        FROM api_keys
+        ORDER BY created_at
+        "#,
+    )
+    .fetch_all(&state.read_pool)
+    .await
+    .map_err(|e| {
+        tracing::error!(error = %e, "Failed to list API keys");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    records
+        .iter()
+        .map(|record| {
+            let created_at: i64 = record.try_get("created_at")?;
+            let revoked_at: Option<i64> = record.try_get("revoked_at")?;
+            Ok(ApiKeyInfo {
+                id: record.try_get("id")?,
+                tenant: record.try_get("tenant")?,
+                name: record.try_get("name")?,
+                scopes: scopes(record)?,
+                quotas: quotas(record)?,
+                created_at: util::format_rfc3339(created_at),
+                revoked_at: revoked_at.map(util::format_rfc3339),
+            })
+        })
+        .collect::<Result<Vec<ApiKeyInfo>, sqlx::Error>>()
+        .map(Json)
+        .map_err(|e| {
+            tracing::error!(error = %e, "Failed to decode API keys");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+}
+
+// Handler for DELETE /admin/api-keys/:id - revokes the key (kept for auditing)
+pub async fn revoke_api_key(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode, StatusCode> {
+    let revoked = sqlx::query(
+        "UPDATE api_keys SET revoked_at = now() WHERE id = $1 AND revoked_at IS NULL RETURNING key_hash",
+    )
+    .bind(id)
+    .fetch_optional(&state.db_pool)
+    .await
+    .map_err(|e| {
+        tracing::error!(%id, error = %e, "Failed to revoke API key");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let Some(record) = revoked else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let key_hash: String = record.try_get("key_hash").map_err(|e| {
+        tracing::error!(%id, error = %e, "Failed to decode revoked API key");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    state.auth.forget(&key_hash);
+    tracing::info!(%id, "API key revoked");
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Handler for PUT /admin/api-keys/:id/quotas - replaces the quotas of a key;
+// omitted ones become unlimited
+pub async fn set_api_key_quotas(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Json(quotas): Json<Quotas>,
+) -> Result<Json<Quotas>, StatusCode> {
+    if !valid_quotas(&quotas) {
+        tracing::warn!(%id, "Received negative API key quotas");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let updated = sqlx::query(
+        r#"
+        UPDATE api_keys
+        SET daily_requests = $2, monthly_requests = $3,
+            daily_inference_seconds = $4, monthly_inference_seconds = $5
+        WHERE id = $1 AND revoked_at IS NULL
+        RETURNING key_hash
+        "#,
+    )
+    .bind(id)
+    .bind(quotas.daily_requests)
+    .bind(quotas.monthly_requests)
+    .bind(quotas.daily_inference_seconds)
+    .bind(quotas.monthly_inference_seconds)
+    .fetch_optional(&state.db_pool)
+    .await
+    .map_err(|e| {
+        tracing::error!(%id, error = %e, "Failed to update API key quotas");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let Some(record) = updated else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let key_hash: String = record.try_get("key_hash").map_err(|e| {
+        tracing::error!(%id, error = %e, "Failed to decode updated API key");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    // Other instances pick the quotas up when their cached lookup expires
+    state.auth.forget(&key_hash);
+    tracing::info!(%id, "API key quotas updated");
+
+    Ok(Json(quotas))
+}
+
+// Handler for PUT /admin/api-keys/:id/scopes - replaces the scopes of a key
+pub async fn set_api_key_scopes(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Json(scopes): Json<Vec<Scope>>,
+) -> Result<Json<Vec<Scope>>, StatusCode> {
+    if scopes.is_empty() {
+        tracing::warn!(%id, "Received empty API key scopes");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let updated = sqlx::query(
+        "UPDATE api_keys SET scopes = $2 WHERE id = $1 AND revoked_at IS NULL RETURNING key_hash",
+    )
+    .bind(id)
+    .bind(scope_names(&scopes))
+    .fetch_optional(&state.db_pool)
+    .await
+    .map_err(|e| {
+        tracing::error!(%id, error = %e, "Failed to update API key scopes");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let Some(record) = updated else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let key_hash: String = record.try_get("key_hash").map_err(|e| {
+        tracing::error!(%id, error = %e, "Failed to decode updated API key");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    state.auth.forget(&key_hash);
+    tracing::info!(%id, ?scopes, "API key scopes updated");
+
+    Ok(Json(scopes))
+}
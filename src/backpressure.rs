@@ -0,0 +1,186 @@
+use axum::{
+    extract::Request,
+    http::{HeaderName, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+use crate::telemetry;
+
+pub const DEFAULT_MAX_QUEUE: usize = 64;
+
+pub static X_PRIORITY: HeaderName = HeaderName::from_static("x-priority");
+
+// Scheduling class of a request: queued high priority pipelines get the next
+// free slot before normal ones, normal ones before low ones
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Priority {
+    // e.g. live access control
+    High,
+    #[default]
+    Normal,
+    // e.g. bulk backfills and background jobs
+    Low,
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "high" => Ok(Priority::High),
+            "normal" => Ok(Priority::Normal),
+            "low" => Ok(Priority::Low),
+            other => Err(format!("invalid priority '{}'", other)),
+        }
+    }
+}
+
+tokio::task_local! {
+    // Priority of the request (or job) the current task works for
+    static PRIORITY: Priority;
+}
+
+// Runs a future with the given priority for its pipelines
+pub async fn with_priority<F: Future>(priority: Priority, future: F) -> F::Output {
+    PRIORITY.scope(priority, future).await
+}
+
+pub fn current_priority() -> Priority {
+    PRIORITY.try_with(|priority| *priority).unwrap_or_default()
+}
+
+// Layer reading the X-Priority header (high, normal or low) of a request;
+// requests without one are normal
+pub async fn prioritize(request: Request, next: Next) -> Response {
+    let priority = match request.headers().get(&X_PRIORITY) {
+        Some(value) => match value.to_str().ok().map(Priority::from_str) {
+            Some(Ok(priority)) => priority,
+            _ => {
+                tracing::warn!(priority = ?value, "Received request with an invalid priority");
+                return StatusCode::BAD_REQUEST.into_response();
+            }
+        },
+        None => Priority::Normal,
+    };
+    with_priority(priority, next.run(request)).await
+}
+
+struct Slots {
+    free: usize,
+    // Waiting requests per priority, high first; entries of cancelled
+    // requests are skipped when a slot is handed over
+    waiting: [VecDeque<oneshot::Sender<()>>; 3],
+    queued: usize,
+}
+
+// Bounds the image pipelines (decoding, detection, inference) running at
+// once; requests past the concurrency wait in a queue of bounded depth, and
+// past that are turned away right away rather than slowing everyone down. A
+// freed slot goes to the oldest waiting request of the highest priority.
+pub struct InferenceQueue {
+    slots: Mutex<Slots>,
+    max_queue: usize,
+}
+
+impl InferenceQueue {
+    pub fn new(max_concurrency: usize, max_queue: usize) -> Self {
+        Self {
+            slots: Mutex::new(Slots {
+                free: max_concurrency.max(1),
+                waiting: Default::default(),
+                queued: 0,
+            }),
+            max_queue,
+        }
+    }
+
+    fn slots(&self) -> std::sync::MutexGuard<'_, Slots> {
+        self.slots.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // A slot for one pipeline, held until it is dropped; 503 when the queue
+    // is full. Owned, so it can go with the pipeline to its blocking thread.
+    pub async fn acquire(self: &Arc<Self>) -> Result<Slot, StatusCode> {
+        let priority = current_priority();
+        let receiver = {
+            let mut slots = self.slots();
+            if slots.free > 0 {
+                slots.free -= 1;
+                return Ok(Slot(self.clone()));
+            }
+            if slots.queued >= self.max_queue {
+                let queued = slots.queued;
+                drop(slots);
+                telemetry::inference_rejected();
+                tracing::warn!(queued, ?priority, "Inference queue full, rejecting request");
+                return Err(StatusCode::SERVICE_UNAVAILABLE);
+            }
+            slots.queued += 1;
+            telemetry::set_inference_queued(slots.queued);
+            let (sender, receiver) = oneshot::channel();
+            slots.waiting[priority as usize].push_back(sender);
+            receiver
+        };
+        let mut queued = Queued {
+            queue: self,
+            receiver,
+        };
+        // Senders are only dropped once their slot is sent
+        (&mut queued.receiver)
+            .await
+            .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+        Ok(Slot(self.clone()))
+    }
+
+    // Hands a freed slot to the first live waiter, or puts it back
+    fn release(&self) {
+        let mut slots = self.slots();
+        for waiting in slots.waiting.iter_mut() {
+            while let Some(sender) = waiting.pop_front() {
+                if sender.send(()).is_ok() {
+                    return;
+                }
+            }
+        }
+        slots.free += 1;
+    }
+}
+
+// A running pipeline, until dropped
+pub struct Slot(Arc<InferenceQueue>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+// A request waiting for a slot, until it gets one or is cancelled
+struct Queued<'a> {
+    queue: &'a InferenceQueue,
+    receiver: oneshot::Receiver<()>,
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        // `release` sends under the lock, so once the receiver is closed here
+        // a slot was either handed over already or goes to another waiter
+        let handed_over = {
+            let mut slots = self.queue.slots();
+            self.receiver.close();
+            slots.queued -= 1;
+            telemetry::set_inference_queued(slots.queued);
+            self.receiver.try_recv().is_ok()
+        };
+        // Cancelled right after being handed a slot: pass it on
+        if handed_over {
+            self.queue.release();
+        }
+    }
+}
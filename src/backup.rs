@@ -0,0 +1,594 @@
+use axum::{
+    body::Bytes,
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures_util::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{PgPool, Row};
+use std::collections::{BTreeSet, HashSet};
+use std::time::Instant;
+use uuid::Uuid;
+
+use crate::chunked::{self, ChunkWriter};
+use crate::collections::{self, CollectionSettings, Collections, DEFAULT_COLLECTION};
+use crate::db;
+use crate::encryption;
+use crate::notify::Change;
+use crate::snapshot::{self, Reader};
+use crate::storage::Storage;
+use crate::store::{Metadata, NewEmbedding, TemplateMode};
+use crate::util;
+use crate::AppState;
+
+const MAGIC: &[u8; 8] = b"OWLBKUP\0";
+// Bumped on any change of the layout; other versions are refused
+const VERSION: u32 = 1;
+// Rows per database transaction on restore
+const BATCH_ROWS: usize = 1000;
+
+type BackupError = Box<dyn std::error::Error + Send + Sync>;
+
+// Archive layout, little-endian: magic, version (u32), the manifest as
+// u32-length-prefixed JSON, then per row the fields of a snapshot row
+// (tenant, collection, origin, metadata, model, uuid, vector) followed by a
+// u8 expiry flag and the expiry in unix seconds (i64), and last the SHA-256
+// of everything before it.
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    // Newest migration of the instance that wrote the archive
+    schema_version: i64,
+    // Model of the instance that wrote the archive; rows keep their own tag
+    model_version: String,
+    created_at: i64,
+    // Vectors sealed with EMBEDDING_ENCRYPTION_KEY
+    sealed: bool,
+    // Collections other than the default ones, with their settings
+    collections: Vec<CollectionRecord>,
+    rows: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CollectionRecord {
+    tenant: String,
+    name: String,
+    threshold: Option<f32>,
+    template_mode: Option<String>,
+}
+
+struct BackupRow {
+    tenant: String,
+    collection: String,
+    uuid: Uuid,
+    origin: String,
+    metadata: Metadata,
+    embeddings: Vec<f32>,
+    model_version: Option<String>,
+    expires_at: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct BackupQuery {
+    // Only this tenant, every tenant when unset
+    tenant: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct RestoreResponse {
+    restored: usize,
+    collections: usize,
+    schema_version: i64,
+    model_version: String,
+}
+
+// Streams the archive to the client, hashing it on the way
+struct ArchiveWriter {
+    chunks: ChunkWriter,
+    hasher: Sha256,
+}
+
+impl ArchiveWriter {
+    async fn write(&mut self, bytes: &[u8]) -> Result<(), BackupError> {
+        self.hasher.update(bytes);
+        Ok(self.chunks.send(bytes).await?)
+    }
+
+    async fn finish(mut self) -> Result<(), BackupError> {
+        let digest = std::mem::take(&mut self.hasher).finalize();
+        self.chunks.send(&digest).await?;
+        Ok(self.chunks.finish().await?)
+    }
+
+    async fn manifest(&mut self, manifest: &Manifest) -> Result<(), BackupError> {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(MAGIC);
+        buffer.extend_from_slice(&VERSION.to_le_bytes());
+        snapshot::put_string(&mut buffer, &serde_json::to_string(manifest)?);
+        self.write(&buffer).await
+    }
+
+    async fn row(&mut self, row: &BackupRow) -> Result<(), BackupError> {
+        let mut buffer = Vec::new();
+        snapshot::put_row(
+            &mut buffer,
+            &row.tenant,
+            &row.collection,
+            &row.origin,
+            &row.metadata,
+            row.model_version.as_deref(),
+            &row.uuid,
+            &row.embeddings,
+        )?;
+        snapshot::put_expiry(&mut buffer, row.expires_at);
+        self.write(&buffer).await
+    }
+}
+
+// Collections to list in the manifest; the default ones exist everywhere
+fn collection_records(collections: &Collections, tenant: Option<&str>) -> Vec<CollectionRecord> {
+    collections
+        .all()
+        .into_iter()
+        .filter(|(owner, name, _)| {
+            name != DEFAULT_COLLECTION && tenant.map_or(true, |tenant| tenant == owner)
+        })
+        .map(|(tenant, name, collection)| CollectionRecord {
+            tenant,
+            name,
+            threshold: collection.settings.threshold,
+            template_mode: collection
+                .settings
+                .template_mode
+                .map(|mode| mode.as_str().to_string()),
+        })
+        .collect()
+}
+
+// Every live row of the targets table, in one repeatable read transaction so
+// the count of the manifest matches the rows
+async fn write_database(
+    pool: &PgPool,
+    mut manifest: Manifest,
+    tenant: Option<&str>,
+    writer: &mut ArchiveWriter,
+) -> Result<u64, BackupError> {
+    let mut transaction = pool.begin().await?;
+    sqlx::query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ READ ONLY")
+        .execute(&mut *transaction)
+        .await?;
+    let count: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM targets WHERE deleted_at IS NULL AND ($1::text IS NULL OR tenant = $1)",
+    )
+    .bind(tenant)
+    .fetch_one(&mut *transaction)
+    .await?;
+    manifest.rows = count as u64;
+    writer.manifest(&manifest).await?;
+
+    let mut rows = sqlx::query(
+        "SELECT uuid, embeddings, embeddings_sealed, origin, metadata, collection, tenant, model, EXTRACT(EPOCH FROM expires_at)::BIGINT AS expires_at FROM targets WHERE deleted_at IS NULL AND ($1::text IS NULL OR tenant = $1) ORDER BY id",
+    )
+    .bind(tenant)
+    .fetch(&mut *transaction);
+    while let Some(row) = rows.try_next().await? {
+        let metadata: sqlx::types::Json<Metadata> = row.try_get("metadata")?;
+        writer
+            .row(&BackupRow {
+                tenant: row.try_get("tenant")?,
+                collection: row.try_get("collection")?,
+                uuid: row.try_get("uuid")?,
+                origin: row.try_get("origin")?,
+                metadata: metadata.0,
+                embeddings: encryption::from_row(&row)?,
+                model_version: row.try_get("model")?,
+                expires_at: row.try_get("expires_at")?,
+            })
+            .await?;
+    }
+    drop(rows);
+    transaction.commit().await?;
+    Ok(count as u64)
+}
+
+// The stores themselves in memory-only mode, one row per vector as in the
+// memory snapshots
+async fn write_memory(
+    collections: &Collections,
+    mut manifest: Manifest,
+    tenant: Option<&str>,
+    writer: &mut ArchiveWriter,
+) -> Result<u64, BackupError> {
+    let mut stores = Vec::new();
+    for (owner, name, collection) in collections.all() {
+        if tenant.map_or(true, |tenant| tenant == owner) {
+            stores.push((owner, name, collection.store.snapshot().await));
+        }
+    }
+    let count: usize = stores
+        .iter()
+        .flat_map(|(_, _, entries)| entries)
+        .map(|entry| entry.embeddings.len())
+        .sum();
+    manifest.rows = count as u64;
+    writer.manifest(&manifest).await?;
+    for (owner, name, entries) in stores {
+        for entry in entries {
+            for vector in &entry.embeddings {
+                writer
+                    .row(&BackupRow {
+                        tenant: owner.clone(),
+                        collection: name.clone(),
+                        uuid: entry.uuid,
+                        origin: entry.origin.clone(),
+                        metadata: entry.metadata.clone(),
+                        embeddings: vector.to_f32(),
+                        model_version: entry.model_version.as_deref().map(str::to_string),
+                        expires_at: entry.expires_at,
+                    })
+                    .await?;
+            }
+        }
+    }
+    Ok(count as u64)
+}
+
+// Handler for POST /admin/backup - every registration and collection (of
+// ?tenant= when set) as a self-contained archive for POST /admin/restore
+pub async fn backup(
+    State(state): State<AppState>,
+    Query(query): Query<BackupQuery>,
+) -> Result<Response, StatusCode> {
+    if state.storage == Storage::MySql {
+        tracing::warn!("Rejected backup of a MySQL targets table");
+        return Err(StatusCode::NOT_IMPLEMENTED);
+    }
+    let manifest = Manifest {
+        schema_version: db::schema_version(),
+        model_version: state.version.model_version().to_string(),
+        created_at: util::unix_now(),
+        sealed: encryption::enabled(),
+        collections: collection_records(&state.collections, query.tenant.as_deref()),
+        rows: 0,
+    };
+    let filename = format!("owlfacerec-{}.backup", manifest.created_at);
+
+    let (chunks, body) = chunked::body();
+    tokio::spawn(async move {
+        let start = Instant::now();
+        let mut writer = ArchiveWriter {
+            chunks,
+            hasher: Sha256::new(),
+        };
+        let tenant = query.tenant.as_deref();
+        let written = match state.storage {
+            Storage::Memory => {
+                write_memory(&state.collections, manifest, tenant, &mut writer).await
+            }
+            _ => write_database(&state.read_pool, manifest, tenant, &mut writer).await,
+        };
+        let written = match written {
+            Ok(rows) => writer.finish().await.map(|()| rows),
+            Err(e) => {
+                writer.chunks.abort(&e).await;
+                Err(e)
+            }
+        };
+        match written {
+            Ok(rows) => tracing::info!(rows, duration = ?start.elapsed(), "Backup written"),
+            Err(e) => tracing::error!(error = %e, "Failed to write backup"),
+        }
+    });
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", filename),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+// Checks the checksum and decodes the whole archive, opening sealed vectors
+fn read(bytes: &[u8]) -> Result<(Manifest, Vec<BackupRow>), BackupError> {
+    if bytes.len() < MAGIC.len() + 4 + 32 {
+        return Err("truncated archive".into());
+    }
+    let (content, digest) = bytes.split_at(bytes.len() - 32);
+    if Sha256::digest(content)[..] != digest[..] {
+        return Err("checksum mismatch, the archive is corrupted or truncated".into());
+    }
+    let mut reader = Reader::new(content);
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err("not a backup archive".into());
+    }
+    let version = reader.u32()?;
+    if version != VERSION {
+        return Err(format!("archive version {} instead of {}", version, VERSION).into());
+    }
+    let manifest: Manifest = serde_json::from_str(&reader.string()?)?;
+    if manifest.sealed && !encryption::enabled() {
+        return Err("the archive is sealed and EMBEDDING_ENCRYPTION_KEY is not set".into());
+    }
+    let mut rows = Vec::with_capacity(manifest.rows.min(1 << 20) as usize);
+    for _ in 0..manifest.rows {
+        let tenant = reader.string()?;
+        let collection = reader.string()?;
+        let origin = reader.string()?;
+        let metadata = serde_json::from_str(&reader.string()?)?;
+        let model_version = Some(reader.string()?).filter(|model| !model.is_empty());
+        let uuid = Uuid::from_slice(reader.take(16)?)?;
+        let embeddings = reader.vector(manifest.sealed)?;
+        let expires_at = reader.expiry()?;
+        rows.push(BackupRow {
+            tenant,
+            collection,
+            uuid,
+            origin,
+            metadata,
+            embeddings,
+            model_version,
+            expires_at,
+        });
+    }
+    if reader.take(1).is_ok() {
+        return Err("trailing bytes after the last row".into());
+    }
+    Ok((manifest, rows))
+}
+
+fn validate(state: &AppState, manifest: &Manifest, rows: &[BackupRow]) -> Result<(), String> {
+    if manifest.schema_version > db::schema_version() {
+        return Err(format!(
+            "archive of schema {}, newer than {}",
+            manifest.schema_version,
+            db::schema_version()
+        ));
+    }
+    for record in &manifest.collections {
+        if !collections::is_valid_name(&record.name) {
+            return Err(format!("invalid collection name '{}'", record.name));
+        }
+        if let Some(Err(e)) = record
+            .template_mode
+            .as_deref()
+            .map(str::parse::<TemplateMode>)
+        {
+            return Err(e.to_string());
+        }
+    }
+    let model_version = state.version.model_version();
+    for row in rows {
+        if row.uuid.is_nil() {
+            return Err("nil uuid".to_string());
+        }
+        if row.embeddings.iter().any(|value| !value.is_finite()) {
+            return Err(format!("target {} has non-finite values", row.uuid));
+        }
+        // Rows of another model are kept for re-embedding, whatever their length
+        let current = row
+            .model_version
+            .as_deref()
+            .map_or(true, |model| model == model_version);
+        if let (true, Some(dimension)) = (current, state.embedding_dimension) {
+            if row.embeddings.len() != dimension {
+                return Err(format!(
+                    "target {} has {} values, expected {}",
+                    row.uuid,
+                    row.embeddings.len(),
+                    dimension
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Whether the collection holds no registration yet
+async fn is_empty(state: &AppState, tenant: &str, name: &str) -> Result<bool, sqlx::Error> {
+    if state.storage == Storage::Memory {
+        return Ok(state
+            .collections
+            .get(tenant, name)
+            .map_or(true, |collection| collection.store.len() == 0));
+    }
+    let exists: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM targets WHERE tenant = $1 AND collection = $2 AND deleted_at IS NULL)",
+    )
+    .bind(tenant)
+    .bind(name)
+    .fetch_one(&state.db_pool)
+    .await?;
+    Ok(!exists)
+}
+
+async fn create_collection(state: &AppState, record: &CollectionRecord) -> Result<(), sqlx::Error> {
+    let settings = CollectionSettings {
+        threshold: record.threshold,
+        template_mode: record
+            .template_mode
+            .as_deref()
+            .and_then(|mode| mode.parse().ok()),
+    };
+    if state.storage.is_postgres() {
+        sqlx::query(
+            "INSERT INTO collections (tenant, name, threshold, template_mode) VALUES ($1, $2, $3, $4) ON CONFLICT DO NOTHING",
+        )
+        .bind(&record.tenant)
+        .bind(&record.name)
+        .bind(settings.threshold)
+        .bind(settings.template_mode.map(|mode| mode.as_str()))
+        .execute(&state.db_pool)
+        .await?;
+        if let Some(notifier) = &state.notifier {
+            let change = Change::CollectionCreated {
+                tenant: record.tenant.clone(),
+                collection: record.name.clone(),
+                threshold: settings.threshold,
+                template_mode: settings.template_mode.map(|mode| mode.as_str().to_string()),
+            };
+            if let Err(e) = notifier.publish(&state.db_pool, change).await {
+                tracing::warn!(collection = %record.name, error = %e, "Failed to notify collection creation");
+            }
+        }
+    }
+    if state
+        .collections
+        .get(&record.tenant, &record.name)
+        .is_none()
+    {
+        state
+            .collections
+            .insert(&record.tenant, &record.name, settings);
+    }
+    Ok(())
+}
+
+async fn insert_rows(state: &AppState, batch: &[BackupRow]) -> Result<(), sqlx::Error> {
+    let mut transaction = state.db_pool.begin().await?;
+    for row in batch {
+        let (plain, sealed) = encryption::columns(&row.embeddings)?;
+        sqlx::query(
+            "INSERT INTO targets (uuid, embeddings, embeddings_sealed, origin, metadata, collection, tenant, expires_at, model) VALUES ($1, $2, $3, $4, $5, $6, $7, to_timestamp($8), $9)",
+        )
+        .bind(row.uuid)
+        .bind(plain)
+        .bind(sealed)
+        .bind(&row.origin)
+        .bind(sqlx::types::Json(&row.metadata))
+        .bind(&row.collection)
+        .bind(&row.tenant)
+        .bind(row.expires_at.map(|expires_at| expires_at as f64))
+        .bind(&row.model_version)
+        .execute(&mut *transaction)
+        .await?;
+    }
+    if let Some(notifier) = &state.notifier {
+        let targets: HashSet<(&str, &str, Uuid)> = batch
+            .iter()
+            .map(|row| (row.tenant.as_str(), row.collection.as_str(), row.uuid))
+            .collect();
+        for (tenant, collection, uuid) in targets {
+            let change = Change::Target {
+                tenant: tenant.to_string(),
+                collection: collection.to_string(),
+                uuid,
+            };
+            notifier.publish(&mut *transaction, change).await?;
+        }
+    }
+    transaction.commit().await
+}
+
+// Handler for POST /admin/restore - loads an archive of POST /admin/backup
+// into collections that hold no registration yet
+pub async fn restore(
+    State(state): State<AppState>,
+    body: Bytes,
+) -> Result<Json<RestoreResponse>, StatusCode> {
+    let start = Instant::now();
+    if state.storage == Storage::MySql {
+        tracing::warn!("Rejected restore into a MySQL targets table");
+        return Err(StatusCode::NOT_IMPLEMENTED);
+    }
+    let (manifest, rows) = match tokio::task::spawn_blocking(move || read(&body)).await {
+        Ok(Ok(archive)) => archive,
+        Ok(Err(e)) => {
+            tracing::warn!(error = %e, "Received unreadable backup archive");
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "Backup read task failed");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    // Vectors of another model cannot be compared with the ones computed here
+    if manifest.model_version != state.version.model_version() {
+        tracing::warn!(archive = %manifest.model_version, model = %state.version.model_version(), "Rejected backup of another model");
+        return Err(StatusCode::CONFLICT);
+    }
+    if let Err(e) = validate(&state, &manifest, &rows) {
+        tracing::warn!(error = %e, "Rejected invalid backup archive");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    // Restoring twice would store every registration twice
+    let destinations: BTreeSet<(&str, &str)> = rows
+        .iter()
+        .map(|row| (row.tenant.as_str(), row.collection.as_str()))
+        .chain(
+            manifest
+                .collections
+                .iter()
+                .map(|record| (record.tenant.as_str(), record.name.as_str())),
+        )
+        .collect();
+    for (tenant, name) in &destinations {
+        match is_empty(&state, tenant, name).await {
+            Ok(true) => {}
+            Ok(false) => {
+                tracing::warn!(%tenant, collection = %name, "Rejected restore into a collection with registrations");
+                return Err(StatusCode::CONFLICT);
+            }
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to check the restored collections");
+                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        }
+    }
+
+    for record in &manifest.collections {
+        if let Err(e) = create_collection(&state, record).await {
+            tracing::error!(tenant = %record.tenant, collection = %record.name, error = %e, "Failed to create restored collection");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+    // Each batch is committed, then made searchable; a failed batch stops the
+    // restore with the earlier ones kept
+    let mut restored = 0;
+    for batch in rows.chunks(BATCH_ROWS) {
+        if state.storage.is_postgres() {
+            if let Err(e) = insert_rows(&state, batch).await {
+                tracing::error!(restored, error = %e, "Failed to store restored rows");
+                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        }
+        for row in batch {
+            state
+                .collections
+                .get_or_create(&row.tenant, &row.collection)
+                .store
+                .add_embedding(NewEmbedding {
+                    uuid: row.uuid,
+                    origin: row.origin.clone(),
+                    metadata: row.metadata.clone(),
+                    embedding: row.embeddings.clone(),
+                    model_version: row.model_version.clone(),
+                    expires_at: row.expires_at,
+                })
+                .await;
+        }
+        state.collections.enforce_memory_limits().await;
+        restored += batch.len();
+    }
+    tracing::info!(
+        restored,
+        collections = manifest.collections.len(),
+        schema_version = manifest.schema_version,
+        duration = ?start.elapsed(),
+        "Backup restored"
+    );
+
+    Ok(Json(RestoreResponse {
+        restored,
+        collections: manifest.collections.len(),
+        schema_version: manifest.schema_version,
+        model_version: manifest.model_version,
+    }))
+}
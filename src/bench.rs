@@ -0,0 +1,318 @@
+use futures_util::{stream, StreamExt};
+use image::{DynamicImage, RgbImage};
+use ndarray::{Array, Ix4};
+use rand::Rng;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::collections::CollectionSettings;
+use crate::detect::FaceCrop;
+use crate::embedder;
+use crate::enhance::EnhanceOptions;
+use crate::handlers::{self, ImageInput};
+use crate::store::{NewEmbedding, SearchOptions};
+use crate::AppState;
+
+// Gallery vectors added per batch while building the search gallery
+const GALLERY_BATCH: usize = 10_000;
+// Matches returned per benchmark search, as a typical /search/ request
+const SEARCH_LIMIT: usize = 10;
+
+type BenchError = Box<dyn std::error::Error>;
+
+pub struct BenchConfig {
+    // Encoded images; synthetic noise images when empty
+    pub images: Vec<PathBuf>,
+    // Operations timed per stage
+    pub requests: usize,
+    // Operations in flight at once
+    pub concurrency: usize,
+    // Random vectors searched by the search stage
+    pub gallery: usize,
+}
+
+// Printed by `bench` as JSON; compare reports of the same command line across
+// hardware or tuning changes
+#[derive(Serialize)]
+pub struct BenchReport {
+    model: String,
+    // Dimension of the embeddings and gallery vectors
+    dimension: usize,
+    requests: usize,
+    concurrency: usize,
+    // Files given on the command line, 0 for synthetic images
+    images: usize,
+    // Resize of the face crop and conversion to the model input
+    preprocess: Stage,
+    // Model run of a preprocessed input
+    inference: Stage,
+    // Decoding, detection, checks, preprocessing and inference of an image,
+    // as a registration or search does; absent for synthetic images with a
+    // face detector, which finds no face in noise
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pipeline: Option<Stage>,
+    // Search of a random query among `gallery` random vectors
+    search: Stage,
+    gallery: usize,
+}
+
+#[derive(Serialize)]
+pub struct Stage {
+    completed: usize,
+    failed: usize,
+    // Completed operations per second of wall time
+    throughput: f64,
+    latency_ms: Latency,
+}
+
+#[derive(Serialize, Default)]
+struct Latency {
+    mean: f64,
+    p50: f64,
+    p90: f64,
+    p99: f64,
+    max: f64,
+}
+
+impl Latency {
+    fn of(mut durations: Vec<Duration>) -> Self {
+        if durations.is_empty() {
+            return Latency::default();
+        }
+        durations.sort();
+        let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        let percentile = |p: f64| {
+            let rank = ((durations.len() as f64 * p).ceil() as usize).clamp(1, durations.len());
+            ms(durations[rank - 1])
+        };
+        Latency {
+            mean: durations.iter().copied().map(ms).sum::<f64>() / durations.len() as f64,
+            p50: percentile(0.5),
+            p90: percentile(0.9),
+            p99: percentile(0.99),
+            max: ms(durations[durations.len() - 1]),
+        }
+    }
+}
+
+// Runs `operation` `requests` times, `concurrency` at once, timing each run
+async fn measure<F, Fut, T, E>(requests: usize, concurrency: usize, operation: F) -> Stage
+where
+    F: Fn(usize) -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: std::fmt::Display,
+{
+    let start = Instant::now();
+    let outcomes: Vec<Result<Duration, E>> = stream::iter(0..requests)
+        .map(|i| {
+            let run = operation(i);
+            async move {
+                let started = Instant::now();
+                run.await.map(|_| started.elapsed())
+            }
+        })
+        .buffer_unordered(concurrency.max(1))
+        .collect()
+        .await;
+    let elapsed = start.elapsed();
+
+    let mut durations = Vec::with_capacity(requests);
+    let mut failed = 0;
+    for outcome in outcomes {
+        match outcome {
+            Ok(duration) => durations.push(duration),
+            Err(e) => {
+                failed += 1;
+                tracing::debug!(error = %e, "Benchmark operation failed");
+            }
+        }
+    }
+    Stage {
+        completed: durations.len(),
+        failed,
+        throughput: durations.len() as f64 / elapsed.as_secs_f64().max(f64::EPSILON),
+        latency_ms: Latency::of(durations),
+    }
+}
+
+// Preprocessing, inference, whole-image and search benchmarks with the models
+// and search settings of the environment, one stage after the other
+pub async fn run(state: &AppState, config: BenchConfig) -> Result<BenchReport, BenchError> {
+    let (width, height) = state.embedder.input_size();
+    let encoded = read_images(&config.images).await?;
+    let faces: Vec<DynamicImage> = if encoded.is_empty() {
+        (0..config.concurrency.max(1))
+            .map(|_| noise_image(width, height))
+            .collect()
+    } else {
+        encoded
+            .iter()
+            .map(|bytes| image::load_from_memory(bytes))
+            .collect::<Result<_, _>>()?
+    };
+    let faces = Arc::new(faces);
+    tracing::info!(
+        images = faces.len(),
+        requests = config.requests,
+        concurrency = config.concurrency,
+        "Benchmarking preprocessing..."
+    );
+
+    let preprocessing = measure(config.requests, config.concurrency, |i| {
+        let (state, faces) = (state.clone(), faces.clone());
+        async move {
+            tokio::task::spawn_blocking(move || preprocess(&state, &faces[i % faces.len()]))
+                .await
+                .map_err(|e| e.to_string())?
+        }
+    })
+    .await;
+
+    tracing::info!("Benchmarking inference...");
+    let inputs: Arc<Vec<Array<f32, Ix4>>> = Arc::new(
+        faces
+            .iter()
+            .map(|face| preprocess(state, face))
+            .collect::<Result<_, _>>()?,
+    );
+    let dimension = state
+        .embedder
+        .infer(inputs[0].clone())
+        .map_err(|e| format!("inference failed: {}", e))?
+        .len();
+    let inference = measure(config.requests, config.concurrency, |i| {
+        let (state, inputs) = (state.clone(), inputs.clone());
+        async move {
+            tokio::task::spawn_blocking(move || {
+                state.embedder.infer(inputs[i % inputs.len()].clone())
+            })
+            .await
+            .map_err(|e| e.to_string())?
+        }
+    })
+    .await;
+
+    let pipeline = if encoded.is_empty() && state.detector.is_some() {
+        None
+    } else {
+        tracing::info!("Benchmarking the whole pipeline...");
+        let images: Arc<Vec<Vec<u8>>> = Arc::new(if encoded.is_empty() {
+            faces
+                .iter()
+                .map(|face| FaceCrop::encode(face).map(|png| png.0))
+                .collect::<Result<_, _>>()?
+        } else {
+            encoded
+        });
+        Some(
+            measure(config.requests, config.concurrency, |i| {
+                let images = images.clone();
+                async move {
+                    let image = ImageInput::Bytes(&images[i % images.len()]);
+                    handlers::get_embedding(image, state, EnhanceOptions::default()).await
+                }
+            })
+            .await,
+        )
+    };
+
+    tracing::info!(gallery = config.gallery, "Building the search gallery...");
+    let collection = state.collections.detached(CollectionSettings::default());
+    let mut remaining = config.gallery;
+    while remaining > 0 {
+        let batch = remaining.min(GALLERY_BATCH);
+        let rows = (0..batch)
+            .map(|_| NewEmbedding {
+                uuid: Uuid::new_v4(),
+                origin: "bench".to_string(),
+                metadata: Default::default(),
+                embedding: random_unit_vector(dimension),
+                model_version: None,
+                expires_at: None,
+            })
+            .collect();
+        collection.store.add_batch(rows).await;
+        remaining -= batch;
+    }
+    tracing::info!("Benchmarking search...");
+    let options = Arc::new(SearchOptions {
+        threshold: state.tunables.default_threshold(),
+        origin_thresholds: Arc::new(HashMap::new()),
+        limit: SEARCH_LIMIT,
+        group_by_uuid: true,
+        origins: None,
+        metadata_filter: None,
+        exclude_uuids: None,
+        exclude_origins: None,
+        deadline: None,
+        similarity_weight: 1.0,
+        nprobe: None,
+    });
+    let search = measure(config.requests, config.concurrency, |_| {
+        let (collection, options) = (collection.clone(), options.clone());
+        async move {
+            tokio::task::spawn_blocking(move || {
+                let query = random_unit_vector(dimension);
+                collection.store.find_similar(&query, &options)
+            })
+            .await
+        }
+    })
+    .await;
+
+    Ok(BenchReport {
+        model: state.version.model_version().to_string(),
+        dimension,
+        requests: config.requests,
+        concurrency: config.concurrency,
+        images: config.images.len(),
+        preprocess: preprocessing,
+        inference,
+        pipeline,
+        search,
+        gallery: config.gallery,
+    })
+}
+
+async fn read_images(paths: &[PathBuf]) -> Result<Vec<Vec<u8>>, BenchError> {
+    let mut images = Vec::with_capacity(paths.len());
+    for path in paths {
+        images.push(
+            tokio::fs::read(path)
+                .await
+                .map_err(|e| format!("failed to read {}: {}", path.display(), e))?,
+        );
+    }
+    Ok(images)
+}
+
+// The face crop as `embed_image` feeds it to the model
+fn preprocess(state: &AppState, face: &DynamicImage) -> Result<Array<f32, Ix4>, String> {
+    let (width, height) = state.embedder.input_size();
+    let img = embedder::resize(face, width, height, state.embedder.resize_mode());
+    state.embedder.preprocess(&img)
+}
+
+fn noise_image(width: u32, height: u32) -> DynamicImage {
+    let mut rng = rand::thread_rng();
+    DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |_, _| {
+        image::Rgb(rng.gen())
+    }))
+}
+
+fn random_unit_vector(dimension: usize) -> Vec<f32> {
+    let mut rng = rand::thread_rng();
+    let vector: Vec<f32> = (0..dimension).map(|_| rng.gen_range(-1.0..1.0)).collect();
+    let norm = vector
+        .iter()
+        .map(|x| x * x)
+        .sum::<f32>()
+        .sqrt()
+        .max(f32::EPSILON);
+    vector.into_iter().map(|x| x / norm).collect()
+}
@@ -0,0 +1,195 @@
+use axum::http::StatusCode;
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_RETRIES: u32 = 3;
+pub const DEFAULT_THRESHOLD: u32 = 5;
+pub const DEFAULT_COOLDOWN: Duration = Duration::from_secs(30);
+// First backoff, doubled on every retry
+const BASE_DELAY: Duration = Duration::from_millis(100);
+
+// Failure of a database operation run through the breaker
+#[derive(Debug)]
+pub enum DbError {
+    // The circuit is open, the database was not tried
+    CircuitOpen,
+    // Still failing after the retries, or failing with the circuit now open
+    Unavailable(sqlx::Error),
+    // Not worth retrying: constraint violations, bad queries...
+    Failed(sqlx::Error),
+    // The connection was lost during the commit, which may have gone through
+    CommitUnknown(sqlx::Error),
+}
+
+impl DbError {
+    // 503 while the database is down, so clients back off and retry later
+    pub fn status(&self) -> StatusCode {
+        match self {
+            DbError::CircuitOpen | DbError::Unavailable(_) | DbError::CommitUnknown(_) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            DbError::Failed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::CircuitOpen => write!(f, "database circuit open"),
+            DbError::Unavailable(e) => write!(f, "database unavailable: {}", e),
+            DbError::Failed(e) => write!(f, "{}", e),
+            DbError::CommitUnknown(e) => write!(f, "commit outcome unknown: {}", e),
+        }
+    }
+}
+
+// Failure of one attempt of a write
+#[derive(Debug)]
+pub enum WriteError {
+    // Nothing was written, or it was rolled back
+    Query(sqlx::Error),
+    // Failure of the COMMIT itself
+    Commit(sqlx::Error),
+}
+
+impl From<sqlx::Error> for WriteError {
+    fn from(e: sqlx::Error) -> Self {
+        WriteError::Query(e)
+    }
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WriteError::Query(e) => write!(f, "{}", e),
+            WriteError::Commit(e) => write!(f, "commit failed: {}", e),
+        }
+    }
+}
+
+// Errors of a lost or overloaded database, which a later attempt may not hit
+fn is_transient(e: &sqlx::Error) -> bool {
+    match e {
+        sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut => true,
+        sqlx::Error::Database(e) => e.code().is_some_and(|code| {
+            // Connection exceptions, then serialization failure, deadlock,
+            // shutdowns and too many connections
+            code.starts_with("08")
+                || matches!(
+                    code.as_ref(),
+                    "40001" | "40P01" | "57P01" | "57P02" | "57P03" | "53300"
+                )
+        }),
+        _ => false,
+    }
+}
+
+// Errors of a commit that do not tell whether it went through: the
+// connection was lost before the answer. Serialization failures and
+// deadlocks at commit did roll the transaction back.
+fn outcome_unknown(e: &sqlx::Error) -> bool {
+    let rolled_back = matches!(e, sqlx::Error::Database(e)
+        if e.code().is_some_and(|code| matches!(code.as_ref(), "40001" | "40P01")));
+    is_transient(e) && !rolled_back
+}
+
+// Retries transient database errors with exponential backoff, and after
+// `threshold` transient failures in a row opens for `cooldown`: calls then
+// fail fast instead of piling up on a database that is down. The first call
+// after the cooldown goes through and closes the circuit if it succeeds.
+pub struct DbBreaker {
+    retries: u32,
+    threshold: u32,
+    cooldown: Duration,
+    failures: AtomicU32,
+    opened_at: Mutex<Option<Instant>>,
+}
+
+impl DbBreaker {
+    pub fn new(retries: u32, threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            retries,
+            threshold: threshold.max(1),
+            cooldown,
+            failures: AtomicU32::new(0),
+            opened_at: Mutex::new(None),
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.opened_at
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_some_and(|opened_at| opened_at.elapsed() < self.cooldown)
+    }
+
+    fn succeeded(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+        let mut opened_at = self.opened_at.lock().unwrap_or_else(|e| e.into_inner());
+        if opened_at.take().is_some() {
+            tracing::info!("Database reachable again, circuit closed");
+        }
+    }
+
+    fn failed(&self) {
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= self.threshold {
+            let mut opened_at = self.opened_at.lock().unwrap_or_else(|e| e.into_inner());
+            if !opened_at.is_some_and(|opened_at| opened_at.elapsed() < self.cooldown) {
+                tracing::error!(failures, cooldown = ?self.cooldown, "Database failing, circuit opened");
+            }
+            *opened_at = Some(Instant::now());
+        }
+    }
+
+    // Runs `operation`, again on transient errors. A transaction that failed
+    // was rolled back and is run again, except when the connection dropped
+    // during its commit (WriteError::Commit), which may have gone through:
+    // that one fails with DbError::CommitUnknown instead of being repeated.
+    pub async fn run<T, E, F, Fut>(&self, mut operation: F) -> Result<T, DbError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        E: Into<WriteError>,
+    {
+        if self.is_open() {
+            return Err(DbError::CircuitOpen);
+        }
+        let mut delay = BASE_DELAY;
+        let mut attempt = 0;
+        loop {
+            let e = match operation().await.map_err(Into::into) {
+                Ok(value) => {
+                    self.succeeded();
+                    return Ok(value);
+                }
+                Err(WriteError::Commit(e)) if outcome_unknown(&e) => {
+                    self.failed();
+                    return Err(DbError::CommitUnknown(e));
+                }
+                Err(WriteError::Query(e) | WriteError::Commit(e)) => e,
+            };
+            match e {
+                e if is_transient(&e) => {
+                    self.failed();
+                    if attempt == self.retries || self.is_open() {
+                        return Err(DbError::Unavailable(e));
+                    }
+                    attempt += 1;
+                    tracing::warn!(attempt, ?delay, error = %e, "Transient database error, retrying");
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                e => {
+                    // The database answered, it is up
+                    self.succeeded();
+                    return Err(DbError::Failed(e));
+                }
+            }
+        }
+    }
+}
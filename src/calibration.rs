@@ -0,0 +1,179 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+// Newton steps of the sigmoid fit, which converges in a handful
+const SIGMOID_FIT_STEPS: usize = 100;
+
+// Maps the cosine similarity of a match to the probability that both faces
+// are the same person, for consumers that cannot interpret raw similarities.
+// Loaded from the JSON file of CALIBRATION_PATH, as written by `owlfacerec
+// calibrate` from a labeled set of the deployment's own cameras and model.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Calibration {
+    // Platt scaling: 1 / (1 + exp(-(a * similarity + b)))
+    Sigmoid { a: f32, b: f32 },
+    // Piecewise linear through (similarity, probability) points, sorted by
+    // similarity, and flat beyond the first and last
+    Isotonic { points: Vec<(f32, f32)> },
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum CalibrationMethod {
+    Sigmoid,
+    Isotonic,
+}
+
+// A comparison of the labeled set: similarity of two faces and whether they
+// are the same person
+#[derive(Deserialize)]
+pub struct LabeledPair {
+    pub similarity: f32,
+    #[serde(rename = "match")]
+    pub is_match: bool,
+}
+
+impl Calibration {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        let calibration: Calibration = serde_json::from_str(&text)
+            .map_err(|e| format!("invalid calibration {}: {}", path.display(), e))?;
+        calibration
+            .validate()
+            .map_err(|e| format!("invalid calibration {}: {}", path.display(), e))?;
+        Ok(calibration)
+    }
+
+    // Higher similarities never get lower probabilities
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            Calibration::Sigmoid { a, b } => {
+                if !(a.is_finite() && b.is_finite()) || *a <= 0.0 {
+                    return Err("a must be positive and b finite".to_string());
+                }
+            }
+            Calibration::Isotonic { points } => {
+                if points.is_empty() {
+                    return Err("points must not be empty".to_string());
+                }
+                if points
+                    .iter()
+                    .any(|(_, probability)| !(0.0..=1.0).contains(probability))
+                {
+                    return Err("probabilities must be between 0 and 1".to_string());
+                }
+                if points
+                    .windows(2)
+                    .any(|pair| pair[0].0 > pair[1].0 || pair[0].1 > pair[1].1)
+                {
+                    return Err("points must be increasing".to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn probability(&self, similarity: f32) -> f32 {
+        match self {
+            Calibration::Sigmoid { a, b } => 1.0 / (1.0 + (-(a * similarity + b)).exp()),
+            Calibration::Isotonic { points } => {
+                let upper = points.partition_point(|(x, _)| *x < similarity);
+                let lower = upper.checked_sub(1).map(|i| points[i]);
+                match (lower, points.get(upper).copied()) {
+                    (Some((x0, p0)), Some((x1, p1))) if x1 > x0 => {
+                        p0 + (p1 - p0) * (similarity - x0) / (x1 - x0)
+                    }
+                    (_, Some((_, probability))) | (Some((_, probability)), None) => probability,
+                    (None, None) => 0.0,
+                }
+            }
+        }
+    }
+
+    pub fn fit(method: CalibrationMethod, pairs: &[LabeledPair]) -> Result<Self, String> {
+        let matches = pairs.iter().filter(|pair| pair.is_match).count();
+        if matches == 0 || matches == pairs.len() {
+            return Err("the labeled set needs both matching and non-matching pairs".to_string());
+        }
+        let calibration = match method {
+            CalibrationMethod::Sigmoid => fit_sigmoid(pairs, matches),
+            CalibrationMethod::Isotonic => fit_isotonic(pairs),
+        };
+        calibration.validate()?;
+        Ok(calibration)
+    }
+}
+
+// Platt's fit: logistic regression of the labels on the similarity by
+// Newton's method, with his smoothed targets against overfitting
+fn fit_sigmoid(pairs: &[LabeledPair], matches: usize) -> Calibration {
+    let positive = (matches as f64 + 1.0) / (matches as f64 + 2.0);
+    let negative = 1.0 / ((pairs.len() - matches) as f64 + 2.0);
+    let (mut a, mut b) = (1.0f64, 0.0f64);
+    for _ in 0..SIGMOID_FIT_STEPS {
+        let (mut ga, mut gb, mut haa, mut hab, mut hbb) = (0.0, 0.0, 1e-9, 0.0, 1e-9);
+        for pair in pairs {
+            let x = pair.similarity as f64;
+            let target = if pair.is_match { positive } else { negative };
+            let p = 1.0 / (1.0 + (-(a * x + b)).exp());
+            let weight = p * (1.0 - p);
+            ga += (p - target) * x;
+            gb += p - target;
+            haa += weight * x * x;
+            hab += weight * x;
+            hbb += weight;
+        }
+        let determinant = haa * hbb - hab * hab;
+        if determinant.abs() < 1e-12 {
+            break;
+        }
+        let da = (hbb * ga - hab * gb) / determinant;
+        let db = (haa * gb - hab * ga) / determinant;
+        a -= da;
+        b -= db;
+        if da.abs() < 1e-9 && db.abs() < 1e-9 {
+            break;
+        }
+    }
+    Calibration::Sigmoid {
+        a: a as f32,
+        b: b as f32,
+    }
+}
+
+// Pool adjacent violators over the pairs sorted by similarity; each pooled
+// block becomes a point at its mean similarity
+fn fit_isotonic(pairs: &[LabeledPair]) -> Calibration {
+    let mut sorted: Vec<(f32, f32)> = pairs
+        .iter()
+        .map(|pair| (pair.similarity, if pair.is_match { 1.0 } else { 0.0 }))
+        .collect();
+    sorted.sort_by(|a, b| a.0.total_cmp(&b.0));
+    // (similarity sum, label sum, count) per block
+    let mut blocks: Vec<(f64, f64, f64)> = Vec::new();
+    for (similarity, label) in sorted {
+        blocks.push((similarity as f64, label as f64, 1.0));
+        while blocks.len() > 1 {
+            let last = blocks[blocks.len() - 1];
+            let previous = blocks[blocks.len() - 2];
+            if previous.1 / previous.2 < last.1 / last.2 {
+                break;
+            }
+            blocks.pop();
+            let merged = blocks.last_mut().expect("two blocks");
+            merged.0 += last.0;
+            merged.1 += last.1;
+            merged.2 += last.2;
+        }
+    }
+    Calibration::Isotonic {
+        points: blocks
+            .into_iter()
+            .map(|(similarities, labels, count)| {
+                ((similarities / count) as f32, (labels / count) as f32)
+            })
+            .collect(),
+    }
+}
@@ -0,0 +1,194 @@
+use candle_core::{DType, Device, Module, ModuleT, Tensor};
+use candle_nn::{
+    batch_norm, conv2d_no_bias, linear, prelu, BatchNorm, Conv2d, Conv2dConfig, Linear, PReLU,
+    VarBuilder,
+};
+use image::DynamicImage;
+use ndarray::{Array, Ix4};
+use std::path::Path;
+
+use crate::embedder::{self, EmbeddingModel, ResizeMode};
+
+const BN_EPS: f64 = 1e-5;
+
+// Output channels of the four stages of the network
+const STAGE_CHANNELS: [usize; 4] = [64, 128, 256, 512];
+
+// ArcFace IResNet in pure Rust, loaded from the safetensors of an InsightFace
+// arcface_torch backbone (iresnet18 to iresnet100, told apart by the blocks
+// present in the file) and run on the CPU without ONNX Runtime
+pub struct CandleModel {
+    conv1: Conv2d,
+    bn1: BatchNorm,
+    prelu: PReLU,
+    blocks: Vec<Block>,
+    bn2: BatchNorm,
+    fc: Linear,
+    features: BatchNorm,
+    dimension: usize,
+    resize: ResizeMode,
+    device: Device,
+}
+
+// IBasicBlock of arcface_torch, with a projection shortcut where the stride
+// or the channels change
+struct Block {
+    bn1: BatchNorm,
+    conv1: Conv2d,
+    bn2: BatchNorm,
+    prelu: PReLU,
+    conv2: Conv2d,
+    bn3: BatchNorm,
+    downsample: Option<(Conv2d, BatchNorm)>,
+}
+
+fn conv3x3(
+    inplanes: usize,
+    planes: usize,
+    stride: usize,
+    vb: VarBuilder,
+) -> candle_core::Result<Conv2d> {
+    let config = Conv2dConfig {
+        padding: 1,
+        stride,
+        ..Default::default()
+    };
+    conv2d_no_bias(inplanes, planes, 3, config, vb)
+}
+
+impl Block {
+    fn load(
+        inplanes: usize,
+        planes: usize,
+        stride: usize,
+        vb: VarBuilder,
+    ) -> candle_core::Result<Self> {
+        let downsample = if stride != 1 || inplanes != planes {
+            let config = Conv2dConfig {
+                stride,
+                ..Default::default()
+            };
+            Some((
+                conv2d_no_bias(inplanes, planes, 1, config, vb.pp("downsample.0"))?,
+                batch_norm(planes, BN_EPS, vb.pp("downsample.1"))?,
+            ))
+        } else {
+            None
+        };
+        Ok(Self {
+            bn1: batch_norm(inplanes, BN_EPS, vb.pp("bn1"))?,
+            conv1: conv3x3(inplanes, planes, 1, vb.pp("conv1"))?,
+            bn2: batch_norm(planes, BN_EPS, vb.pp("bn2"))?,
+            prelu: prelu(Some(planes), vb.pp("prelu"))?,
+            conv2: conv3x3(planes, planes, stride, vb.pp("conv2"))?,
+            bn3: batch_norm(planes, BN_EPS, vb.pp("bn3"))?,
+            downsample,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor) -> candle_core::Result<Tensor> {
+        let out = self.bn1.forward_t(xs, false)?;
+        let out = self.conv1.forward(&out)?;
+        let out = self.bn2.forward_t(&out, false)?;
+        let out = self.prelu.forward(&out)?;
+        let out = self.conv2.forward(&out)?;
+        let out = self.bn3.forward_t(&out, false)?;
+        let identity = match &self.downsample {
+            Some((conv, bn)) => bn.forward_t(&conv.forward(xs)?, false)?,
+            None => xs.clone(),
+        };
+        out + identity
+    }
+}
+
+impl CandleModel {
+    pub fn load(model_path: &Path, resize: ResizeMode) -> Result<Self, Box<dyn std::error::Error>> {
+        let device = Device::Cpu;
+        let tensors = candle_core::safetensors::load(model_path, &device)?;
+        let dimension = tensors
+            .get("fc.weight")
+            .ok_or("fc.weight missing from the model weights")?
+            .dim(0)?;
+        let depths: Vec<usize> = (1..=STAGE_CHANNELS.len())
+            .map(|stage| {
+                (0..)
+                    .take_while(|block| {
+                        tensors.contains_key(&format!("layer{}.{}.conv1.weight", stage, block))
+                    })
+                    .count()
+            })
+            .collect();
+        if depths.contains(&0) {
+            return Err(format!("not an IResNet, blocks per stage: {:?}", depths).into());
+        }
+        tracing::info!(?depths, dimension, "IResNet weights found");
+        let vb = VarBuilder::from_tensors(tensors, DType::F32, &device);
+
+        let mut blocks = Vec::new();
+        let mut inplanes = STAGE_CHANNELS[0];
+        for (stage, (&planes, &depth)) in STAGE_CHANNELS.iter().zip(&depths).enumerate() {
+            for block in 0..depth {
+                let stride = if block == 0 { 2 } else { 1 };
+                let vb = vb.pp(format!("layer{}.{}", stage + 1, block));
+                blocks.push(Block::load(inplanes, planes, stride, vb)?);
+                inplanes = planes;
+            }
+        }
+        Ok(Self {
+            conv1: conv3x3(3, STAGE_CHANNELS[0], 1, vb.pp("conv1"))?,
+            bn1: batch_norm(STAGE_CHANNELS[0], BN_EPS, vb.pp("bn1"))?,
+            prelu: prelu(Some(STAGE_CHANNELS[0]), vb.pp("prelu"))?,
+            blocks,
+            bn2: batch_norm(inplanes, BN_EPS, vb.pp("bn2"))?,
+            // Four stride-2 stages take 112x112 down to 7x7
+            fc: linear(inplanes * 7 * 7, dimension, vb.pp("fc"))?,
+            features: batch_norm(dimension, BN_EPS, vb.pp("features"))?,
+            dimension,
+            resize,
+            device,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor) -> candle_core::Result<Tensor> {
+        let mut xs = self.conv1.forward(xs)?;
+        xs = self.bn1.forward_t(&xs, false)?;
+        xs = self.prelu.forward(&xs)?;
+        for block in &self.blocks {
+            xs = block.forward(&xs)?;
+        }
+        xs = self.bn2.forward_t(&xs, false)?;
+        xs = self.fc.forward(&xs.flatten_from(1)?)?;
+        self.features.forward_t(&xs, false)
+    }
+}
+
+impl EmbeddingModel for CandleModel {
+    // arcface_torch input: 112x112, RGB channels scaled to [-1, 1]
+    fn preprocess(&self, img: &DynamicImage) -> Result<Array<f32, Ix4>, String> {
+        let rgb_img = embedder::resize(img, 112, 112, self.resize).to_rgb8();
+        let mut input_tensor = Array::zeros((1, 3, 112, 112));
+        for (x, y, pixel) in rgb_img.enumerate_pixels() {
+            for (channel, &value) in pixel.0.iter().enumerate() {
+                input_tensor[[0, channel, y as usize, x as usize]] = (value as f32 - 127.5) / 127.5;
+            }
+        }
+        Ok(input_tensor)
+    }
+
+    fn infer(&self, input: Array<f32, Ix4>) -> Result<Vec<f32>, String> {
+        let shape = input.dim();
+        let input = Tensor::from_vec(input.into_raw_vec(), shape, &self.device)
+            .map_err(|e| format!("failed to create input tensor: {}", e))?;
+        self.forward(&input)
+            .and_then(|embedding| embedding.flatten_all()?.to_vec1::<f32>())
+            .map_err(|e| format!("candle inference failed: {}", e))
+    }
+
+    fn dimension(&self) -> Option<usize> {
+        Some(self.dimension)
+    }
+
+    fn resize_mode(&self) -> ResizeMode {
+        self.resize
+    }
+}
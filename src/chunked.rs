@@ -0,0 +1,90 @@
+use axum::body::{Body, Bytes};
+use std::io::{self, Write};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+// Bytes buffered before a chunk goes out to the client
+const CHUNK_BYTES: usize = 1 << 20;
+// Chunks in flight between the writer and the response body
+const CHANNEL_CHUNKS: usize = 4;
+
+// Writes into a response body as a download is produced, so large files are
+// never held whole in memory. Async writers use `send`/`finish`/`abort`,
+// blocking ones (run under spawn_blocking) the `io::Write` implementation.
+pub struct ChunkWriter {
+    buffer: Vec<u8>,
+    sender: mpsc::Sender<io::Result<Bytes>>,
+}
+
+// The writer and the body it streams to
+pub fn body() -> (ChunkWriter, Body) {
+    let (sender, receiver) = mpsc::channel(CHANNEL_CHUNKS);
+    let writer = ChunkWriter {
+        buffer: Vec::with_capacity(CHUNK_BYTES),
+        sender,
+    };
+    (writer, Body::from_stream(ReceiverStream::new(receiver)))
+}
+
+impl ChunkWriter {
+    fn take(&mut self) -> Bytes {
+        Bytes::from(std::mem::replace(
+            &mut self.buffer,
+            Vec::with_capacity(CHUNK_BYTES),
+        ))
+    }
+
+    // The client went away
+    fn closed<T>(_: T) -> io::Error {
+        io::Error::from(io::ErrorKind::BrokenPipe)
+    }
+
+    pub async fn send(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.buffer.extend_from_slice(bytes);
+        if self.buffer.len() >= CHUNK_BYTES {
+            let chunk = self.take();
+            self.sender.send(Ok(chunk)).await.map_err(Self::closed)?;
+        }
+        Ok(())
+    }
+
+    pub async fn finish(mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = self.take();
+        self.sender.send(Ok(chunk)).await.map_err(Self::closed)
+    }
+
+    // Aborts the response instead of ending a truncated file cleanly
+    pub async fn abort(self, error: impl ToString) {
+        let _ = self
+            .sender
+            .send(Err(io::Error::other(error.to_string())))
+            .await;
+    }
+
+    pub fn blocking_abort(self, error: impl ToString) {
+        let _ = self
+            .sender
+            .blocking_send(Err(io::Error::other(error.to_string())));
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(bytes);
+        if self.buffer.len() >= CHUNK_BYTES {
+            self.flush()?;
+        }
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = self.take();
+        self.sender.blocking_send(Ok(chunk)).map_err(Self::closed)
+    }
+}
@@ -0,0 +1,440 @@
+use clap::{Parser, Subcommand};
+use futures_util::TryStreamExt;
+use serde::Serialize;
+use sqlx::Row;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::bench::{self, BenchConfig};
+use crate::calibration::{Calibration, CalibrationMethod, LabeledPair};
+use crate::collections::DEFAULT_COLLECTION;
+use crate::dataset::{self, DatasetFormat};
+use crate::encryption;
+use crate::enhance::EnhanceOptions;
+use crate::handlers::{self, ImageInput};
+use crate::import::{self, ImportFormat};
+use crate::jobs::JobProgress;
+use crate::notify::Change;
+use crate::partition::{self, PartitionScheme};
+use crate::resync::{self, TargetKey};
+use crate::snapshot;
+use crate::store::cosine_similarity;
+use crate::tenant::DEFAULT_TENANT;
+use crate::AppState;
+
+type CliError = Box<dyn std::error::Error>;
+
+// Rows sealed per transaction by `encrypt`
+const ENCRYPT_BATCH_ROWS: i64 = 1000;
+
+#[derive(Parser)]
+#[command(name = "owlfacerec", version, about = "Face recognition service")]
+pub struct Cli {
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        help = "TOML config file, overridden by environment variables"
+    )]
+    pub config: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+// Every command runs the same startup (environment, models, database), the
+// offline ones then do their work and exit without binding the API port
+#[derive(Subcommand)]
+pub enum Command {
+    #[command(about = "Serve the HTTP and gRPC APIs (the default)")]
+    Serve,
+    #[command(about = "Register precomputed embeddings from a JSONL or CSV file")]
+    Import {
+        file: PathBuf,
+        #[arg(long, value_enum, default_value = "jsonl")]
+        format: ImportFormat,
+        #[arg(long, default_value = DEFAULT_TENANT)]
+        tenant: String,
+        #[arg(long, default_value = DEFAULT_COLLECTION)]
+        collection: String,
+    },
+    #[command(about = "Write the gallery of a collection as a Parquet or Arrow IPC file")]
+    Export {
+        output: PathBuf,
+        #[arg(long, value_enum, default_value = "parquet")]
+        format: DatasetFormat,
+        #[arg(long, default_value = DEFAULT_TENANT)]
+        tenant: String,
+        #[arg(long, default_value = DEFAULT_COLLECTION)]
+        collection: String,
+    },
+    #[command(about = "Re-embed the stored enrollment crops with the current model")]
+    Reindex {
+        #[arg(long, help = "Only this tenant")]
+        tenant: Option<String>,
+        #[arg(long, help = "Only this collection")]
+        collection: Option<String>,
+        #[arg(long, help = "Also the rows already embedded with the current model")]
+        all: bool,
+    },
+    #[command(about = "Compare the largest faces of two images")]
+    Verify { image1: PathBuf, image2: PathBuf },
+    #[command(about = "Encrypt the embeddings stored in the clear with EMBEDDING_ENCRYPTION_KEY")]
+    Encrypt,
+    #[command(about = "Fit a match probability calibration to labeled similarities")]
+    Calibrate {
+        file: PathBuf,
+        #[arg(long, value_enum, default_value = "sigmoid")]
+        method: CalibrationMethod,
+    },
+    #[command(about = "Measure preprocessing, inference and search throughput and latency")]
+    Bench {
+        #[arg(help = "Images to process, synthetic noise images if none are given")]
+        images: Vec<PathBuf>,
+        #[arg(long, default_value_t = 200, help = "Operations timed per stage")]
+        requests: usize,
+        #[arg(long, default_value_t = 4, help = "Operations in flight at once")]
+        concurrency: usize,
+        #[arg(
+            long,
+            default_value_t = 100_000,
+            help = "Random vectors of the search gallery"
+        )]
+        gallery: usize,
+    },
+    #[command(
+        about = "Convert the targets table into one partitioned by registration month or origin"
+    )]
+    Partition {
+        #[arg(long, value_enum)]
+        by: PartitionScheme,
+    },
+}
+
+impl Command {
+    // Export reads the in-memory gallery, the other offline commands go
+    // straight to the database or the model
+    pub fn loads_gallery(&self) -> bool {
+        matches!(self, Command::Serve | Command::Export { .. })
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct ReindexSummary {
+    // Version the rows are re-embedded with
+    model: String,
+    reindexed: usize,
+    // Rows without a stored crop, which keep their embedding; they need
+    // re-enrollment, as do the failed ones
+    skipped: usize,
+    failed: usize,
+}
+
+#[derive(Serialize)]
+struct EncryptSummary {
+    encrypted: u64,
+}
+
+#[derive(Serialize)]
+struct Verification {
+    similarity: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    match_probability: Option<f32>,
+    threshold: f32,
+    is_match: bool,
+}
+
+// Runs an offline command; results go to stdout as JSON, logs to stderr
+pub async fn run(command: Command, state: AppState) -> Result<(), CliError> {
+    match command {
+        Command::Serve => Ok(()),
+        Command::Import {
+            file,
+            format,
+            tenant,
+            collection,
+        } => {
+            if !state.storage.is_postgres() {
+                return Err("import requires STORAGE=postgres".into());
+            }
+            let text = tokio::fs::read_to_string(&file).await?;
+            let response = import::import_into(
+                &state,
+                &tenant,
+                &collection,
+                format,
+                &text,
+                &JobProgress::default(),
+            )
+            .await
+            .map_err(|status| format!("import failed: {}", status))?;
+            print_json(&response)
+        }
+        Command::Export {
+            output,
+            format,
+            tenant,
+            collection,
+        } => {
+            let Some(found) = state.collections.get(&tenant, &collection) else {
+                return Err(format!("unknown collection {}/{}", tenant, collection).into());
+            };
+            let entries = found.store.snapshot().await;
+            let dimension = found.store.dimension().unwrap_or(0);
+            let targets = entries.len();
+            let path = output.clone();
+            tokio::task::spawn_blocking(move || {
+                let file = std::io::BufWriter::new(std::fs::File::create(&path)?);
+                dataset::write_dataset(&entries, dimension, format, file)
+            })
+            .await?
+            .map_err(|e| format!("export failed: {}", e))?;
+            tracing::info!(path = %output.display(), targets, "Gallery dataset exported");
+            Ok(())
+        }
+        Command::Reindex {
+            tenant,
+            collection,
+            all,
+        } => {
+            let summary = reindex(
+                &state,
+                tenant.as_deref(),
+                collection.as_deref(),
+                all,
+                &JobProgress::default(),
+            )
+            .await?;
+            // A snapshot holds the old vectors of rows it already covers
+            if let Ok(path) = std::env::var("SNAPSHOT_PATH") {
+                let source = snapshot::Source::Database(state.bulk_pool.clone());
+                snapshot::write_now(&source, &PathBuf::from(path)).await;
+            }
+            print_json(&summary)
+        }
+        Command::Verify { image1, image2 } => {
+            let first = embed_file(&image1, &state).await?;
+            let second = embed_file(&image2, &state).await?;
+            let similarity = cosine_similarity(&first, &second);
+            let threshold = state.tunables.default_threshold();
+            print_json(&Verification {
+                similarity,
+                match_probability: state
+                    .calibration
+                    .as_deref()
+                    .map(|calibration| calibration.probability(similarity)),
+                threshold,
+                is_match: similarity >= threshold,
+            })
+        }
+        Command::Encrypt => {
+            let encrypted = encrypt(&state).await?;
+            // A snapshot written before holds the vectors in the clear
+            if let Ok(path) = std::env::var("SNAPSHOT_PATH") {
+                let source = snapshot::Source::Database(state.bulk_pool.clone());
+                snapshot::write_now(&source, &PathBuf::from(path)).await;
+            }
+            print_json(&EncryptSummary { encrypted })
+        }
+        Command::Calibrate { file, method } => {
+            let text = tokio::fs::read_to_string(&file).await?;
+            let mut pairs = Vec::new();
+            for (line, record) in text.lines().enumerate() {
+                if record.trim().is_empty() {
+                    continue;
+                }
+                let pair: LabeledPair = serde_json::from_str(record)
+                    .map_err(|e| format!("{} line {}: {}", file.display(), line + 1, e))?;
+                pairs.push(pair);
+            }
+            let calibration = Calibration::fit(method, &pairs)?;
+            tracing::info!(pairs = pairs.len(), ?calibration, "Calibration fitted");
+            print_json(&calibration)
+        }
+        Command::Bench {
+            images,
+            requests,
+            concurrency,
+            gallery,
+        } => {
+            let config = BenchConfig {
+                images,
+                requests,
+                concurrency,
+                gallery,
+            };
+            print_json(&bench::run(&state, config).await?)
+        }
+        Command::Partition { by } => {
+            if !state.storage.is_postgres() {
+                return Err("partition requires STORAGE=postgres".into());
+            }
+            let summary = partition::partition_targets(&state.bulk_pool, by)
+                .await
+                .map_err(|e| format!("partition failed: {}", e))?;
+            print_json(&summary)
+        }
+    }
+}
+
+// Seals the plain vectors of the targets table, tombstones included, in
+// batches; rows keep their id, so snapshots stay valid
+async fn encrypt(state: &AppState) -> Result<u64, CliError> {
+    if !state.storage.is_postgres() {
+        return Err("encrypt requires STORAGE=postgres".into());
+    }
+    if !encryption::enabled() {
+        return Err("encrypt needs EMBEDDING_ENCRYPTION_KEY".into());
+    }
+    let start = Instant::now();
+    let mut encrypted = 0;
+    loop {
+        let rows: Vec<(i64, Vec<f32>)> = sqlx::query_as(
+            "SELECT id, embeddings FROM targets WHERE embeddings IS NOT NULL ORDER BY id LIMIT $1",
+        )
+        .bind(ENCRYPT_BATCH_ROWS)
+        .fetch_all(&state.bulk_pool)
+        .await?;
+        if rows.is_empty() {
+            break;
+        }
+        let mut transaction = state.bulk_pool.begin().await?;
+        for (id, embedding) in &rows {
+            let (_, sealed) = encryption::columns(embedding)?;
+            sqlx::query(
+                "UPDATE targets SET embeddings = NULL, embeddings_sealed = $1 WHERE id = $2",
+            )
+            .bind(sealed)
+            .bind(id)
+            .execute(&mut *transaction)
+            .await?;
+        }
+        transaction.commit().await?;
+        encrypted += rows.len() as u64;
+        tracing::info!(rows = encrypted, "Encrypting embeddings...");
+    }
+    tracing::info!(encrypted, duration = ?start.elapsed(), "Embeddings encrypted");
+    Ok(encrypted)
+}
+
+// Replaces the embedding of the live rows not computed with the current model
+// (every live row with `all`) from their crops, e.g. after a model upgrade.
+// Each rewritten target is reloaded into the local stores and, with
+// NOTIFY_CHANGES, announced to the running instances.
+// Rows re-embedded are tagged with the model, so a run picks up where an
+// interrupted one stopped. Also run as a background job, whose progress
+// counts the rows done.
+pub(crate) async fn reindex(
+    state: &AppState,
+    tenant: Option<&str>,
+    collection: Option<&str>,
+    all: bool,
+    progress: &JobProgress,
+) -> Result<ReindexSummary, CliError> {
+    if !state.storage.is_postgres() {
+        return Err("reindex requires STORAGE=postgres".into());
+    }
+    let Some(crops) = &state.crops else {
+        return Err("reindex needs the enrollment crops, set S3_BUCKET".into());
+    };
+    let model = state.version.model_version();
+    let start = Instant::now();
+    let rows: Vec<(i64, Option<String>, TargetKey)> = sqlx::query(
+        "SELECT id, image_key, tenant, collection, uuid FROM targets WHERE deleted_at IS NULL AND ($1::text IS NULL OR tenant = $1) AND ($2::text IS NULL OR collection = $2) AND ($3 OR model IS DISTINCT FROM $4) ORDER BY id",
+    )
+    .bind(tenant)
+    .bind(collection)
+    .bind(all)
+    .bind(model)
+    .fetch(&state.bulk_pool)
+    .map_ok(|record| {
+        (
+            record.get("id"),
+            record.get("image_key"),
+            (
+                record.get("tenant"),
+                record.get("collection"),
+                record.get("uuid"),
+            ),
+        )
+    })
+    .try_collect()
+    .await?;
+    tracing::info!(rows = rows.len(), model, "Reindexing targets...");
+    let total = rows.len();
+
+    let mut summary = ReindexSummary {
+        model: model.to_string(),
+        reindexed: 0,
+        skipped: 0,
+        failed: 0,
+    };
+    for (done, (id, image_key, key)) in rows.into_iter().enumerate() {
+        progress.set(done, Some(total));
+        let Some(key) = image_key else {
+            summary.skipped += 1;
+            continue;
+        };
+        let embedding = match crops.get(&key).await {
+            Ok(png) => handlers::embed_crop(&png, state).await.ok(),
+            Err(e) => {
+                tracing::warn!(id, key = %key, error = %e, "Failed to read enrollment crop");
+                None
+            }
+        };
+        let Some(embedding) = embedding else {
+            summary.failed += 1;
+            continue;
+        };
+        let (plain, sealed) = encryption::columns(&embedding)?;
+        let mut transaction = state.bulk_pool.begin().await?;
+        // A new id, so a snapshot holding the old vector is recognized as
+        // stale
+        sqlx::query(
+            "UPDATE targets SET embeddings = $1, embeddings_sealed = $2, model = $3, id = nextval(pg_get_serial_sequence('targets', 'id')) WHERE id = $4",
+        )
+        .bind(plain)
+        .bind(sealed)
+        .bind(model)
+        .bind(id)
+        .execute(&mut *transaction)
+        .await?;
+        if let Some(notifier) = &state.notifier {
+            let (tenant, collection, uuid) = key.clone();
+            notifier
+                .publish(
+                    &mut *transaction,
+                    Change::Target {
+                        tenant,
+                        collection,
+                        uuid,
+                    },
+                )
+                .await?;
+        }
+        transaction.commit().await?;
+        resync::reload(state, &key).await?;
+        summary.reindexed += 1;
+    }
+    progress.set(total, Some(total));
+    tracing::info!(
+        reindexed = summary.reindexed,
+        skipped = summary.skipped,
+        failed = summary.failed,
+        duration = ?start.elapsed(),
+        "Targets reindexed"
+    );
+    Ok(summary)
+}
+
+// Embedding of the largest face of an image file
+async fn embed_file(path: &Path, state: &AppState) -> Result<Vec<f32>, CliError> {
+    let bytes = tokio::fs::read(path).await?;
+    handlers::get_embedding(ImageInput::Bytes(&bytes), state, EnhanceOptions::default())
+        .await
+        .map_err(|status| format!("failed to embed {}: {}", path.display(), status).into())
+}
+
+fn print_json<T: Serialize>(value: &T) -> Result<(), CliError> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
@@ -0,0 +1,280 @@
+use prost::Message;
+use reqwest::{header::CONTENT_TYPE, RequestBuilder, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize};
+use std::fmt;
+use uuid::Uuid;
+
+// The messages of proto/owlfacerec.proto, for /verify/
+mod proto {
+    include!(concat!(env!("OUT_DIR"), "/owlfacerec.v1.rs"));
+}
+
+const API_PREFIX: &str = "/v1";
+const PROTOBUF: &str = "application/x-protobuf";
+
+// The request bodies and the checks of a face are the server's own types;
+// the responses are read into views of the server's, which testing.rs checks
+// against the handlers
+pub use crate::api::{
+    Attributes, DetectedFace, Emotion, EmotionScore, EnhanceOptions, Fusion, Gender, MaskCheck,
+    Metadata, Pose, QualityScores, RegisterMode, RegisterPayload as RegisterRequest,
+    SearchPayload as SearchRequest,
+};
+
+// Typed client of the HTTP API
+#[derive(Clone)]
+pub struct OwlClient {
+    // e.g. http://localhost:8080, without the /v1 prefix
+    base_url: String,
+    http: reqwest::Client,
+    api_key: Option<String>,
+    bearer_token: Option<String>,
+    // Name and value of the trusted tenant header (TENANT_HEADER)
+    tenant: Option<(String, String)>,
+    collection: Option<String>,
+}
+
+impl OwlClient {
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self {
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            http: reqwest::Client::new(),
+            api_key: None,
+            bearer_token: None,
+            tenant: None,
+            collection: None,
+        }
+    }
+
+    // Sent as X-API-Key, for AUTH_MODE=api-key
+    pub fn with_api_key(mut self, key: impl Into<String>) -> Self {
+        self.api_key = Some(key.into());
+        self
+    }
+
+    // Sent as Authorization: Bearer, for JWT authentication
+    pub fn with_bearer_token(mut self, token: impl Into<String>) -> Self {
+        self.bearer_token = Some(token.into());
+        self
+    }
+
+    pub fn with_tenant(mut self, header: impl Into<String>, tenant: impl Into<String>) -> Self {
+        self.tenant = Some((header.into(), tenant.into()));
+        self
+    }
+
+    // Collection every call works on, instead of the default one
+    pub fn with_collection(mut self, collection: impl Into<String>) -> Self {
+        self.collection = Some(collection.into());
+        self
+    }
+
+    // Own HTTP client, e.g. with timeouts or a proxy
+    pub fn with_http_client(mut self, http: reqwest::Client) -> Self {
+        self.http = http;
+        self
+    }
+
+    pub async fn register(
+        &self,
+        request: &RegisterRequest,
+    ) -> Result<RegisterResponse, ClientError> {
+        let response = self
+            .request(reqwest::Method::POST, "/register/")
+            .json(request)
+            .send()
+            .await?;
+        json(response).await
+    }
+
+    pub async fn search(&self, request: &SearchRequest) -> Result<SearchResponse, ClientError> {
+        let response = self
+            .request(reqwest::Method::POST, "/search/")
+            .json(request)
+            .send()
+            .await?;
+        json(response).await
+    }
+
+    // 1:1 check of an image against the faces of a uuid, over protobuf
+    pub async fn verify(
+        &self,
+        target_uuid: Uuid,
+        image: Vec<u8>,
+        threshold: Option<f32>,
+    ) -> Result<VerifyResponse, ClientError> {
+        let message = proto::VerifyRequest {
+            target_uuid: target_uuid.to_string(),
+            image,
+            threshold,
+            collection: self.collection.clone().unwrap_or_default(),
+        };
+        let response = self
+            .request(reqwest::Method::POST, "/verify/")
+            .header(CONTENT_TYPE, PROTOBUF)
+            .body(message.encode_to_vec())
+            .send()
+            .await?;
+        let body = checked(response).await?.bytes().await?;
+        let decoded = proto::VerifyResponse::decode(body)?;
+        Ok(VerifyResponse {
+            is_match: decoded.is_match,
+            similarity: decoded.similarity,
+            match_probability: decoded.match_probability,
+        })
+    }
+
+    // Deletes every face of a uuid
+    pub async fn delete(&self, target_uuid: Uuid) -> Result<(), ClientError> {
+        let response = self
+            .request(
+                reqwest::Method::DELETE,
+                &format!("/targets/{}", target_uuid),
+            )
+            .send()
+            .await?;
+        checked(response).await?;
+        Ok(())
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> RequestBuilder {
+        let mut request = self
+            .http
+            .request(method, format!("{}{}{}", self.base_url, API_PREFIX, path));
+        if let Some(collection) = &self.collection {
+            request = request.query(&[("collection", collection)]);
+        }
+        if let Some(key) = &self.api_key {
+            request = request.header("x-api-key", key);
+        }
+        if let Some(token) = &self.bearer_token {
+            request = request.bearer_auth(token);
+        }
+        if let Some((header, tenant)) = &self.tenant {
+            request = request.header(header.as_str(), tenant);
+        }
+        request
+    }
+}
+
+// The response when its status is a success, the status and body otherwise
+async fn checked(response: reqwest::Response) -> Result<reqwest::Response, ClientError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().await.unwrap_or_default();
+    Err(ClientError::Status { status, body })
+}
+
+async fn json<T: DeserializeOwned>(response: reqwest::Response) -> Result<T, ClientError> {
+    Ok(checked(response).await?.json().await?)
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    Http(reqwest::Error),
+    // The server answered with an error status; quality and duplicate
+    // rejections of /register/ explain themselves in the body
+    Status { status: StatusCode, body: String },
+    Protobuf(prost::DecodeError),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Http(e) => write!(f, "request failed: {}", e),
+            ClientError::Status { status, body } if body.is_empty() => {
+                write!(f, "server answered {}", status)
+            }
+            ClientError::Status { status, body } => {
+                write!(f, "server answered {}: {}", status, body)
+            }
+            ClientError::Protobuf(e) => write!(f, "invalid protobuf response: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(e: reqwest::Error) -> Self {
+        ClientError::Http(e)
+    }
+}
+
+impl From<prost::DecodeError> for ClientError {
+    fn from(e: prost::DecodeError) -> Self {
+        ClientError::Protobuf(e)
+    }
+}
+
+// --- Responses, as read from handlers.rs ---
+
+// Checks of a face, as reported by /register/ and /search/; fields are only
+// set when the matching model is configured or the estimate was requested
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Analysis {
+    pub face: Option<DetectedFace>,
+    pub liveness: Option<f32>,
+    pub pose: Option<Pose>,
+    pub mask: Option<MaskCheck>,
+    pub quality: Option<QualityScores>,
+    pub attributes: Option<Attributes>,
+    pub emotion: Option<Emotion>,
+    // Base64 PNG
+    pub crop: Option<String>,
+    pub embedding: Option<Vec<f32>>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RegisterResponse {
+    #[serde(flatten)]
+    pub analysis: Analysis,
+    // With register_all_faces
+    #[serde(default)]
+    pub faces: Vec<RegisteredFace>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RegisteredFace {
+    pub target_uuid: Uuid,
+    #[serde(flatten)]
+    pub analysis: Analysis,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SearchResponse {
+    pub results: Vec<SearchResult>,
+    pub partial: bool,
+    pub liveness: Option<f32>,
+    pub pose: Option<Pose>,
+    pub mask: Option<MaskCheck>,
+    pub attributes: Option<Attributes>,
+    pub emotion: Option<Emotion>,
+    pub face: Option<DetectedFace>,
+    // Base64 PNG
+    pub crop: Option<String>,
+    pub embedding: Option<Vec<f32>>,
+    // Per detected face, when the image holds several
+    #[serde(default)]
+    pub faces: Vec<SearchResponse>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SearchResult {
+    pub target_uuid: Uuid,
+    pub similarity: f32,
+    pub match_probability: Option<f32>,
+    pub origin: String,
+    #[serde(default)]
+    pub metadata: Metadata,
+    pub hits: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct VerifyResponse {
+    pub is_match: bool,
+    pub similarity: f32,
+    pub match_probability: Option<f32>,
+}
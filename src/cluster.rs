@@ -0,0 +1,237 @@
+use axum::{
+    extract::{Extension, State},
+    http::StatusCode,
+    Json,
+};
+use ndarray::{s, Array2};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::time::Instant;
+use uuid::Uuid;
+
+use crate::enhance::EnhanceOptions;
+use crate::matrix::{self, MatrixItem};
+use crate::store::Vector;
+use crate::tenant::Tenant;
+use crate::AppState;
+
+// Upper bound on the images/embeddings of a single request
+const MAX_ITEMS: usize = 1000;
+// Block of the self-similarity matrix computed per matrix product
+const BLOCK_ROWS: usize = 256;
+const BLOCK_COLUMNS: usize = 4096;
+// Label of faces that belong to no cluster
+const NOISE: i64 = -1;
+
+// Define the request payload for /cluster
+#[derive(Deserialize)]
+pub struct ClusterPayload {
+    // Images or raw embeddings to group; exclusive with `collection`
+    items: Option<Vec<MatrixItem>>,
+    // Collection whose targets are grouped instead
+    collection: Option<String>,
+    // Two faces are neighbours from this similarity on
+    threshold: Option<f32>,
+    // Neighbours (the face included) that make a face a cluster core; 1 puts
+    // every face in a cluster (single-linkage clustering)
+    min_samples: Option<usize>,
+    #[serde(default)]
+    enhance: EnhanceOptions,
+}
+
+// Cluster of one target of the collection
+#[derive(Serialize)]
+pub struct TargetCluster {
+    target_uuid: Uuid,
+    cluster: i64,
+}
+
+// Define the response for /cluster
+#[derive(Serialize)]
+pub struct ClusterResponse {
+    clusters: usize,
+    // Cluster of each item, in request order; -1 for noise
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    labels: Vec<i64>,
+    // Cluster of each target, with `collection`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    targets: Vec<TargetCluster>,
+}
+
+// Faces above the threshold of each face, itself excluded
+fn neighbours(embeddings: &[Vec<f32>], threshold: f32) -> Vec<Vec<usize>> {
+    let dimension = embeddings.first().map_or(0, Vec::len);
+    let matrix: Array2<f32> = matrix::normalized_rows(embeddings, dimension);
+    let count = embeddings.len();
+    (0..count)
+        .step_by(BLOCK_ROWS)
+        .collect::<Vec<_>>()
+        .into_par_iter()
+        .flat_map_iter(|start| {
+            let rows = matrix.slice(s![start..(start + BLOCK_ROWS).min(count), ..]);
+            let mut block = vec![Vec::new(); rows.nrows()];
+            for column_start in (0..count).step_by(BLOCK_COLUMNS) {
+                let column_end = (column_start + BLOCK_COLUMNS).min(count);
+                let similarities = rows.dot(&matrix.slice(s![column_start..column_end, ..]).t());
+                for ((i, j), &similarity) in similarities.indexed_iter() {
+                    if similarity >= threshold && start + i != column_start + j {
+                        block[i].push(column_start + j);
+                    }
+                }
+            }
+            block
+        })
+        .collect()
+}
+
+// DBSCAN over the neighbour lists; clusters are numbered in order of their
+// first face
+fn dbscan(neighbours: &[Vec<usize>], min_samples: usize) -> (Vec<i64>, usize) {
+    let is_core = |face: usize| neighbours[face].len() + 1 >= min_samples;
+    let mut labels: Vec<Option<i64>> = vec![None; neighbours.len()];
+    let mut clusters = 0;
+    for face in 0..neighbours.len() {
+        if labels[face].is_some() || !is_core(face) {
+            continue;
+        }
+        let cluster = clusters as i64;
+        clusters += 1;
+        labels[face] = Some(cluster);
+        let mut queue = VecDeque::from([face]);
+        while let Some(current) = queue.pop_front() {
+            // Border faces join the cluster but do not extend it
+            if !is_core(current) {
+                continue;
+            }
+            for &neighbour in &neighbours[current] {
+                if labels[neighbour].is_none() {
+                    labels[neighbour] = Some(cluster);
+                    queue.push_back(neighbour);
+                }
+            }
+        }
+    }
+    let labels = labels
+        .into_iter()
+        .map(|label| label.unwrap_or(NOISE))
+        .collect();
+    (labels, clusters)
+}
+
+// Handler for POST /cluster - groups unlabeled faces into identities
+pub async fn cluster(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Json(payload): Json<ClusterPayload>,
+) -> Result<Json<ClusterResponse>, StatusCode> {
+    run_cluster(&state, &tenant, payload).await.map(Json)
+}
+
+// Clustering of a request, also run as a background job
+pub(crate) async fn run_cluster(
+    state: &AppState,
+    tenant: &Tenant,
+    payload: ClusterPayload,
+) -> Result<ClusterResponse, StatusCode> {
+    let start = Instant::now();
+    let threshold = payload
+        .threshold
+        .unwrap_or(state.tunables.default_threshold());
+    let min_samples = payload.min_samples.unwrap_or(1).max(1);
+
+    // Either the request items or one vector per target of the collection
+    let (embeddings, uuids) = match (payload.items, &payload.collection) {
+        (Some(items), None) => {
+            if items.is_empty() {
+                tracing::warn!("Received cluster request with no items");
+                return Err(StatusCode::BAD_REQUEST);
+            }
+            if items.len() > MAX_ITEMS {
+                tracing::warn!(
+                    items = items.len(),
+                    "Received cluster request over {} items",
+                    MAX_ITEMS
+                );
+                return Err(StatusCode::PAYLOAD_TOO_LARGE);
+            }
+            (
+                matrix::embed_set(items, state, payload.enhance).await?,
+                None,
+            )
+        }
+        (None, Some(name)) => {
+            let Some(collection) = state.collections.get(tenant.id(), name) else {
+                return Err(StatusCode::NOT_FOUND);
+            };
+            // Registrations of a uuid are averaged into a single vector
+            let mut targets: HashMap<Uuid, Vec<f32>> = HashMap::new();
+            for entry in collection.store.snapshot().await {
+                for embedding in entry.embeddings.into_iter().map(Vector::into_f32) {
+                    let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+                    let sum = targets
+                        .entry(entry.uuid)
+                        .or_insert_with(|| vec![0.0; embedding.len()]);
+                    if norm > 0.0 && sum.len() == embedding.len() {
+                        sum.iter_mut()
+                            .zip(&embedding)
+                            .for_each(|(total, x)| *total += x / norm);
+                    }
+                }
+            }
+            let (uuids, embeddings): (Vec<Uuid>, Vec<Vec<f32>>) = targets.into_iter().unzip();
+            (embeddings, Some(uuids))
+        }
+        _ => {
+            tracing::warn!("Cluster request needs either items or a collection");
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    };
+
+    if let Some(first) = embeddings.first() {
+        if first.is_empty()
+            || embeddings
+                .iter()
+                .any(|embedding| embedding.len() != first.len())
+        {
+            tracing::warn!("Received cluster request with mismatched embedding dimensions");
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    }
+
+    // Quadratic in the number of faces, keep it off the async workers
+    let count = embeddings.len();
+    let (labels, clusters) = tokio::task::spawn_blocking(move || {
+        dbscan(&neighbours(&embeddings, threshold), min_samples)
+    })
+    .await
+    .map_err(|e| {
+        tracing::error!(error = %e, "Clustering task failed");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    tracing::info!(faces = count, clusters, duration = ?start.elapsed(), "Faces clustered");
+
+    Ok(match uuids {
+        Some(uuids) => {
+            let mut targets: Vec<TargetCluster> = uuids
+                .into_iter()
+                .zip(labels)
+                .map(|(target_uuid, cluster)| TargetCluster {
+                    target_uuid,
+                    cluster,
+                })
+                .collect();
+            targets.sort_by_key(|target| (target.cluster, target.target_uuid));
+            ClusterResponse {
+                clusters,
+                labels: Vec::new(),
+                targets,
+            }
+        }
+        None => ClusterResponse {
+            clusters,
+            labels,
+            targets: Vec::new(),
+        },
+    })
+}
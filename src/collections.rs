@@ -0,0 +1,608 @@
+use axum::{
+    extract::{Extension, Path, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+#[cfg(feature = "gpu-search")]
+use crate::gpu::GpuContext;
+use crate::handlers;
+use crate::ivf::IvfConfig;
+use crate::notify::Change;
+use crate::pca::PcaConfig;
+use crate::sharding::GalleryShard;
+use crate::store::{self, EmbeddingsStore, Precision, TemplateMode, DEFAULT_COMPACTION_RATIO};
+use crate::tenant::Tenant;
+use crate::AppState;
+
+// Collection used by routes called without a collection
+pub const DEFAULT_COLLECTION: &str = "default";
+
+// Per-collection configuration, stored in the 'collections' table
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CollectionSettings {
+    // Search threshold used when a request does not set one
+    pub threshold: Option<f32>,
+    // None uses the server-wide TEMPLATE_MODE
+    pub template_mode: Option<TemplateMode>,
+}
+
+// A named gallery: its settings and its own in-memory store
+pub struct Collection {
+    pub settings: CollectionSettings,
+    pub store: EmbeddingsStore,
+}
+
+// Caps on the entries held in memory across every store; past them the
+// least recently matched targets are evicted, staying in the database
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MemoryLimits {
+    pub max_entries: Option<usize>,
+    pub max_bytes: Option<usize>,
+}
+
+impl MemoryLimits {
+    fn exceeded(&self, entries: usize, bytes: usize) -> bool {
+        self.max_entries.is_some_and(|max| entries > max)
+            || self.max_bytes.is_some_and(|max| bytes > max)
+    }
+
+    // Eviction goes below the caps so it does not run on every registration
+    fn low_water(&self) -> Self {
+        let low = |max: usize| max / 10 * 9;
+        Self {
+            max_entries: self.max_entries.map(low),
+            max_bytes: self.max_bytes.map(low),
+        }
+    }
+}
+
+// Named galleries of every tenant, each with its own in-memory store
+//
+// Collections are keyed by tenant first, so one tenant can never reach the
+// stores of another. The map lock is only held to look up, add or remove a
+// collection; searches and registrations then work on the store's own shard locks.
+pub struct Collections {
+    tenants: RwLock<HashMap<String, HashMap<String, Arc<Collection>>>>,
+    // Shard count for new stores; None uses the store default (CPU cores)
+    shards: Option<usize>,
+    // Template mode of collections that do not set their own
+    template_mode: TemplateMode,
+    compaction_ratio: f32,
+    // Bits of the search prefilter sketches, 0 without
+    prefilter_bits: usize,
+    // Precision of the vectors held by the stores
+    precision: Precision,
+    // Inverted file index of the stores, off when None
+    ivf: Option<IvfConfig>,
+    // Reduced scan of the stores, off when None
+    pca: Option<PcaConfig>,
+    // Adapter the searches score on, CPU when None
+    #[cfg(feature = "gpu-search")]
+    gpu: Option<Arc<GpuContext>>,
+    memory_limits: MemoryLimits,
+    // Model of the running instance, whose entries the searches compare
+    model_version: Option<Arc<str>>,
+    // Model of the untagged entries, the running one when None
+    legacy_model_version: Option<Arc<str>>,
+    // Output length of the model, when it declares one
+    dimension: Option<usize>,
+    // Slice of the gallery held by this instance, all of it when None
+    gallery_shard: Option<GalleryShard>,
+    // Held while evicting, so concurrent callers do not evict twice
+    evicting: tokio::sync::Mutex<()>,
+}
+
+impl Collections {
+    pub fn new(shards: Option<usize>, template_mode: TemplateMode) -> Self {
+        Self {
+            tenants: RwLock::new(HashMap::new()),
+            shards,
+            template_mode,
+            compaction_ratio: DEFAULT_COMPACTION_RATIO,
+            prefilter_bits: 0,
+            precision: Precision::F32,
+            ivf: None,
+            pca: None,
+            #[cfg(feature = "gpu-search")]
+            gpu: None,
+            memory_limits: MemoryLimits::default(),
+            model_version: None,
+            legacy_model_version: None,
+            dimension: None,
+            gallery_shard: None,
+            evicting: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    pub fn with_compaction_ratio(mut self, compaction_ratio: f32) -> Self {
+        self.compaction_ratio = compaction_ratio;
+        self
+    }
+
+    pub fn with_prefilter_bits(mut self, prefilter_bits: usize) -> Self {
+        self.prefilter_bits = prefilter_bits;
+        self
+    }
+
+    pub fn with_precision(mut self, precision: Precision) -> Self {
+        self.precision = precision;
+        self
+    }
+
+    pub fn with_ivf(mut self, ivf: Option<IvfConfig>) -> Self {
+        self.ivf = ivf;
+        self
+    }
+
+    pub fn with_pca(mut self, pca: Option<PcaConfig>) -> Self {
+        self.pca = pca;
+        self
+    }
+
+    #[cfg(feature = "gpu-search")]
+    pub fn with_gpu(mut self, gpu: Option<Arc<GpuContext>>) -> Self {
+        self.gpu = gpu;
+        self
+    }
+
+    pub fn with_memory_limits(mut self, memory_limits: MemoryLimits) -> Self {
+        self.memory_limits = memory_limits;
+        self
+    }
+
+    pub fn with_model_version(mut self, model_version: &str) -> Self {
+        self.model_version = Some(Arc::from(model_version));
+        self
+    }
+
+    pub fn with_legacy_model_version(mut self, legacy_model_version: Option<&str>) -> Self {
+        self.legacy_model_version = legacy_model_version.map(Arc::from);
+        self
+    }
+
+    pub fn legacy_model_version(&self) -> Option<&str> {
+        self.legacy_model_version.as_deref()
+    }
+
+    pub fn with_dimension(mut self, dimension: Option<usize>) -> Self {
+        self.dimension = dimension;
+        self
+    }
+
+    pub fn with_gallery_shard(mut self, gallery_shard: Option<GalleryShard>) -> Self {
+        self.gallery_shard = gallery_shard;
+        self
+    }
+
+    fn new_collection(&self, settings: CollectionSettings) -> Arc<Collection> {
+        let store = match self.shards {
+            Some(shards) => EmbeddingsStore::with_shards(shards),
+            None => EmbeddingsStore::new(),
+        }
+        .with_template_mode(settings.template_mode.unwrap_or(self.template_mode))
+        .with_compaction_ratio(self.compaction_ratio)
+        .with_prefilter_bits(self.prefilter_bits)
+        .with_precision(self.precision)
+        .with_ivf(self.ivf)
+        .with_pca(self.pca)
+        .with_model_version(self.model_version.clone())
+        .with_legacy_model_version(self.legacy_model_version.clone())
+        .with_dimension(self.dimension)
+        .with_gallery_shard(self.gallery_shard);
+        #[cfg(feature = "gpu-search")]
+        let store = store.with_gpu(self.gpu.clone());
+        Arc::new(Collection { settings, store })
+    }
+
+    // No collections, with the same store configuration; filled aside and then
+    // swapped in with `replace`
+    pub fn empty_like(&self) -> Self {
+        Self {
+            tenants: RwLock::new(HashMap::new()),
+            shards: self.shards,
+            template_mode: self.template_mode,
+            compaction_ratio: self.compaction_ratio,
+            prefilter_bits: self.prefilter_bits,
+            precision: self.precision,
+            ivf: self.ivf,
+            pca: self.pca,
+            #[cfg(feature = "gpu-search")]
+            gpu: self.gpu.clone(),
+            memory_limits: self.memory_limits,
+            model_version: self.model_version.clone(),
+            legacy_model_version: self.legacy_model_version.clone(),
+            dimension: self.dimension,
+            gallery_shard: self.gallery_shard,
+            evicting: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    // Swaps every collection for those of `other` at once; requests already
+    // holding a collection finish on the old one
+    pub fn replace(&self, other: Collections) {
+        *self.tenants.write().unwrap_or_else(|e| e.into_inner()) = other
+            .tenants
+            .into_inner()
+            .unwrap_or_else(|e| e.into_inner());
+    }
+
+    // Every tenant implicitly owns a default collection, created on first use
+    pub fn get(&self, tenant: &str, name: &str) -> Option<Arc<Collection>> {
+        let found = self
+            .tenants
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(tenant)
+            .and_then(|collections| collections.get(name))
+            .cloned();
+        match found {
+            None if name == DEFAULT_COLLECTION => Some(self.get_or_create(tenant, name)),
+            found => found,
+        }
+    }
+
+    // Collection set up like the others but outside every tenant, e.g. the
+    // gallery of `bench`
+    pub fn detached(&self, settings: CollectionSettings) -> Arc<Collection> {
+        self.new_collection(settings)
+    }
+
+    // Adds a collection, or replaces an existing one together with its store
+    pub fn insert(
+        &self,
+        tenant: &str,
+        name: &str,
+        settings: CollectionSettings,
+    ) -> Arc<Collection> {
+        let collection = self.new_collection(settings);
+        self.tenants
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(tenant.to_string())
+            .or_default()
+            .insert(name.to_string(), collection.clone());
+        collection
+    }
+
+    // Collections missing from the 'collections' table get default settings
+    pub fn get_or_create(&self, tenant: &str, name: &str) -> Arc<Collection> {
+        let mut tenants = self.tenants.write().unwrap_or_else(|e| e.into_inner());
+        tenants
+            .entry(tenant.to_string())
+            .or_default()
+            .entry(name.to_string())
+            .or_insert_with(|| self.new_collection(CollectionSettings::default()))
+            .clone()
+    }
+
+    pub fn remove(&self, tenant: &str, name: &str) -> Option<Arc<Collection>> {
+        self.tenants
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_mut(tenant)
+            .and_then(|collections| collections.remove(name))
+    }
+
+    // Collections of a tenant sorted by name, including its default collection
+    pub fn list(&self, tenant: &str) -> Vec<(String, Arc<Collection>)> {
+        self.get(tenant, DEFAULT_COLLECTION);
+        let mut collections: Vec<(String, Arc<Collection>)> = self
+            .tenants
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(tenant)
+            .into_iter()
+            .flatten()
+            .map(|(name, collection)| (name.clone(), collection.clone()))
+            .collect();
+        collections.sort_by(|a, b| a.0.cmp(&b.0));
+        collections
+    }
+
+    // Every collection of every tenant, as (tenant, name, collection)
+    pub fn all(&self) -> Vec<(String, String, Arc<Collection>)> {
+        self.tenants
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .flat_map(|(tenant, collections)| {
+                collections
+                    .iter()
+                    .map(|(name, collection)| (tenant.clone(), name.clone(), collection.clone()))
+            })
+            .collect()
+    }
+
+    // Registrations stored for an origin across every collection of a tenant
+    pub fn origin_count(&self, tenant: &str, origin: &str) -> usize {
+        self.list(tenant)
+            .iter()
+            .map(|(_, collection)| collection.store.origin_count(origin))
+            .sum()
+    }
+
+    pub fn origin_counts(&self, tenant: &str) -> HashMap<String, usize> {
+        let mut counts = HashMap::new();
+        for (_, collection) in self.list(tenant) {
+            for (origin, count) in collection.store.origin_counts() {
+                *counts.entry(origin).or_insert(0) += count;
+            }
+        }
+        counts
+    }
+
+    // Entries across all tenants
+    pub fn len(&self) -> usize {
+        self.tenants
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .values()
+            .flat_map(HashMap::values)
+            .map(|collection| collection.store.len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Estimated memory of the entries across all tenants
+    pub fn memory_bytes(&self) -> usize {
+        self.all()
+            .iter()
+            .map(|(_, _, collection)| collection.store.memory_bytes())
+            .sum()
+    }
+
+    // Evicts the least recently matched targets of every store until the
+    // memory limits are met again; returns the targets evicted. Call it after
+    // adding entries, evicted targets are paged back in by `resync::reload`.
+    pub async fn enforce_memory_limits(&self) -> usize {
+        let limits = self.memory_limits;
+        if !limits.exceeded(self.len(), self.memory_bytes()) {
+            return 0;
+        }
+        let Ok(_evicting) = self.evicting.try_lock() else {
+            return 0;
+        };
+
+        let collections = self.all();
+        let mut usage = Vec::new();
+        for (index, (_, _, collection)) in collections.iter().enumerate() {
+            let vector_bytes = collection.store.vector_bytes();
+            for (last_used, uuid, entries, vectors) in collection.store.usage().await {
+                usage.push((last_used, index, uuid, entries, vectors, vector_bytes));
+            }
+        }
+        usage.sort_unstable_by_key(|&(last_used, ..)| last_used);
+
+        let target = limits.low_water();
+        let mut entries = self.len();
+        let mut bytes = self.memory_bytes();
+        let mut evicted = 0;
+        for (_, index, uuid, held, vectors, vector_bytes) in usage {
+            if !target.exceeded(entries, bytes) {
+                break;
+            }
+            collections[index].2.store.evict(&uuid).await;
+            entries = entries.saturating_sub(held);
+            bytes = bytes.saturating_sub(store::entries_bytes(held, vectors, vector_bytes));
+            evicted += 1;
+        }
+        tracing::info!(
+            evicted,
+            entries,
+            bytes,
+            "Evicted least recently matched targets from memory"
+        );
+        evicted
+    }
+}
+
+// Selects the collection of the top-level routes, e.g. /search/?collection=vip
+#[derive(Deserialize, Default)]
+pub struct CollectionQuery {
+    collection: Option<String>,
+}
+
+impl CollectionQuery {
+    pub fn name(&self) -> &str {
+        self.requested().unwrap_or(DEFAULT_COLLECTION)
+    }
+
+    // The collection named in the query, if any
+    pub fn requested(&self) -> Option<&str> {
+        self.collection.as_deref()
+    }
+}
+
+// Names end up in URLs and log lines, keep them simple
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// Define the request payload for POST /collections/
+#[derive(Deserialize)]
+pub struct CreateCollectionPayload {
+    name: String,
+    threshold: Option<f32>,
+    // off | mean | max; defaults to the server-wide TEMPLATE_MODE
+    template_mode: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct CollectionInfo {
+    name: String,
+    entries: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    threshold: Option<f32>,
+    template_mode: &'static str,
+}
+
+// Handler for GET /collections/
+pub async fn list_collections(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+) -> Json<Vec<CollectionInfo>> {
+    Json(
+        state
+            .collections
+            .list(tenant.id())
+            .into_iter()
+            .map(|(name, collection)| CollectionInfo {
+                name,
+                entries: collection.store.len(),
+                threshold: collection.settings.threshold,
+                template_mode: collection.store.template_mode().as_str(),
+            })
+            .collect(),
+    )
+}
+
+// Handler for POST /collections/
+pub async fn create_collection(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Json(payload): Json<CreateCollectionPayload>,
+) -> Result<StatusCode, StatusCode> {
+    let name = payload.name;
+    if !is_valid_name(&name) {
+        tracing::warn!(collection = %name, "Received collection creation with invalid name");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if name == DEFAULT_COLLECTION {
+        // Every tenant already has it
+        return Err(StatusCode::CONFLICT);
+    }
+    if payload
+        .threshold
+        .is_some_and(|threshold| !(-1.0..=1.0).contains(&threshold))
+    {
+        tracing::warn!(collection = %name, "Received collection creation with invalid threshold");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let template_mode = match payload
+        .template_mode
+        .as_deref()
+        .map(str::parse::<TemplateMode>)
+    {
+        Some(Ok(template_mode)) => Some(template_mode),
+        Some(Err(e)) => {
+            tracing::warn!(collection = %name, error = %e, "Received collection creation with invalid template mode");
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        None => None,
+    };
+    let settings = CollectionSettings {
+        threshold: payload.threshold,
+        template_mode,
+    };
+
+    match sqlx::query(
+        "INSERT INTO collections (tenant, name, threshold, template_mode) VALUES ($1, $2, $3, $4)",
+    )
+    .bind(tenant.id())
+    .bind(&name)
+    .bind(settings.threshold)
+    .bind(settings.template_mode.map(|mode| mode.as_str()))
+    .execute(&state.db_pool)
+    .await
+    {
+        Ok(_) => {
+            state.collections.insert(tenant.id(), &name, settings);
+            if let Some(notifier) = &state.notifier {
+                let change = Change::CollectionCreated {
+                    tenant: tenant.id().to_string(),
+                    collection: name.clone(),
+                    threshold: settings.threshold,
+                    template_mode: settings.template_mode.map(|mode| mode.as_str().to_string()),
+                };
+                if let Err(e) = notifier.publish(&state.db_pool, change).await {
+                    tracing::warn!(collection = %name, error = %e, "Failed to notify collection creation");
+                }
+            }
+            tracing::info!(tenant = %tenant.id(), collection = %name, "Collection created");
+            Ok(StatusCode::CREATED)
+        }
+        Err(e) => {
+            // PostgreSQL error code '23505' (unique violation)
+            if e.as_database_error()
+                .and_then(|db_err| db_err.code())
+                .is_some_and(|code| code == "23505")
+            {
+                tracing::warn!(collection = %name, "Collection already exists");
+                return Err(StatusCode::CONFLICT);
+            }
+            tracing::error!(collection = %name, error = %e, "Failed to create collection");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn delete_collection_rows(
+    state: &AppState,
+    tenant: &str,
+    name: &str,
+) -> Result<(), sqlx::Error> {
+    // Rows still queued for the database would land after the delete
+    if let Some(write_behind) = &state.write_behind {
+        write_behind.settle_all().await;
+    }
+    let mut tx = state.db_pool.begin().await?;
+    sqlx::query("DELETE FROM targets WHERE tenant = $1 AND collection = $2")
+        .bind(tenant)
+        .bind(name)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM collections WHERE tenant = $1 AND name = $2")
+        .bind(tenant)
+        .bind(name)
+        .execute(&mut *tx)
+        .await?;
+    if let Some(notifier) = &state.notifier {
+        let change = Change::CollectionDeleted {
+            tenant: tenant.to_string(),
+            collection: name.to_string(),
+        };
+        notifier.publish(&mut *tx, change).await?;
+    }
+    tx.commit().await
+}
+
+// Handler for DELETE /collections/:name - drops the collection and its targets
+pub async fn delete_collection(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    if name == DEFAULT_COLLECTION {
+        tracing::warn!("Refusing to delete the default collection");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if state.collections.get(tenant.id(), &name).is_none() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    match delete_collection_rows(&state, tenant.id(), &name).await {
+        Ok(()) => {
+            state.collections.remove(tenant.id(), &name);
+            // A collection created again under the name must not be answered
+            // with the targets of this one
+            handlers::invalidate_cached_searches(&state, tenant.id(), &name).await;
+            tracing::info!(tenant = %tenant.id(), collection = %name, "Collection deleted");
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Err(e) => {
+            tracing::error!(collection = %name, error = %e, "Failed to delete collection");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
@@ -0,0 +1,513 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use crate::auth::AuthMode;
+use crate::log_format::LogFormat;
+use crate::profiles;
+use crate::run_mode::RunMode;
+use crate::storage::Storage;
+use crate::store::TemplateMode;
+use crate::tunables::SettingsUpdate;
+
+// Optional config.toml. Every setting has an environment variable, which
+// wins when both are set, so a file can carry the defaults of a deployment
+// and the environment override them per instance. The file is resolved
+// into Settings, read in place of the environment; nothing is exported.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub model: ModelConfig,
+    pub thresholds: ThresholdsConfig,
+    pub limits: LimitsConfig,
+    pub auth: AuthConfig,
+    // Per embedding model, by name (the file stem of its path)
+    pub profiles: BTreeMap<String, ProfileConfig>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub grpc_port: Option<u16>,
+    pub log_level: Option<String>,
+    pub log_format: Option<String>,
+    pub tls_cert_path: Option<PathBuf>,
+    pub tls_key_path: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub storage: Option<String>,
+    pub url: Option<String>,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub name: Option<String>,
+    pub run_mode: Option<String>,
+    pub retries: Option<u32>,
+    pub breaker_threshold: Option<u32>,
+    pub breaker_cooldown_secs: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelConfig {
+    pub path: Option<PathBuf>,
+    // Input of the embedding model
+    pub channel_order: Option<String>,
+    pub input_mean: Option<f32>,
+    pub input_std: Option<f32>,
+    pub input_layout: Option<String>,
+    pub resize_mode: Option<String>,
+    pub template_mode: Option<String>,
+    pub flip_tta: Option<bool>,
+    pub detector_path: Option<PathBuf>,
+    pub liveness_path: Option<PathBuf>,
+    pub mask_path: Option<PathBuf>,
+    pub pose_path: Option<PathBuf>,
+    pub attributes_path: Option<PathBuf>,
+    pub emotion_path: Option<PathBuf>,
+    pub super_resolution_path: Option<PathBuf>,
+    pub calibration_path: Option<PathBuf>,
+    pub intra_op_threads: Option<usize>,
+    pub inter_op_threads: Option<usize>,
+    pub parallel_execution: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThresholdsConfig {
+    pub search: Option<f32>,
+    pub alert: Option<f32>,
+    pub detector: Option<f32>,
+    pub liveness: Option<f32>,
+    pub mask: Option<f32>,
+    // Search threshold per origin
+    pub origins: BTreeMap<String, f32>,
+}
+
+// Thresholds and calibration of one embedding model. Those of the running
+// model replace the [thresholds] and [model] settings, so one file serves
+// instances running different models.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProfileConfig {
+    pub search: Option<f32>,
+    pub alert: Option<f32>,
+    pub calibration_path: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub search_limit: Option<usize>,
+    pub body_max_bytes: Option<usize>,
+    pub video_max_bytes: Option<usize>,
+    pub import_max_bytes: Option<usize>,
+    pub image_max_dimension: Option<u32>,
+    pub image_max_megapixels: Option<f64>,
+    pub inference_timeout_ms: Option<u64>,
+    pub inference_max_concurrency: Option<usize>,
+    pub inference_max_queue: Option<usize>,
+    pub rate_limit_rps: Option<f64>,
+    pub rate_limit_burst: Option<f64>,
+    pub store_max_entries: Option<usize>,
+    pub store_max_bytes: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub mode: Option<String>,
+    pub admin_api_key: Option<String>,
+    pub tenant_header: Option<String>,
+    // API key to tenant
+    pub tenant_api_keys: BTreeMap<String, String>,
+    pub jwt_issuer: Option<String>,
+    pub jwt_audience: Option<String>,
+    pub jwt_jwks_url: Option<String>,
+    pub jwt_tenant_claim: Option<String>,
+}
+
+// Path of the config file: `--config` on the command line, then CONFIG_FILE
+pub fn path(argument: Option<PathBuf>) -> Option<PathBuf> {
+    argument.or_else(|| env::var("CONFIG_FILE").ok().map(PathBuf::from))
+}
+
+impl Config {
+    // Reads and validates the file
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        let config: Config = toml::from_str(&contents)
+            .map_err(|e| format!("invalid config file {}: {}", path.display(), e))?;
+        config
+            .validate()
+            .map_err(|e| format!("invalid config file {}: {}", path.display(), e))?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if let Some(log_format) = &self.server.log_format {
+            log_format.parse::<LogFormat>()?;
+        }
+        if let Some(storage) = &self.database.storage {
+            storage.parse::<Storage>()?;
+        }
+        if let Some(run_mode) = &self.database.run_mode {
+            run_mode.parse::<RunMode>()?;
+        }
+        if let Some(template_mode) = &self.model.template_mode {
+            template_mode.parse::<TemplateMode>()?;
+        }
+        if let Some(mode) = &self.auth.mode {
+            // The issuer may come from the environment instead, as may
+            // another mode; the key set is discovered from the issuer
+            if mode.parse::<AuthMode>()? == AuthMode::Jwt
+                && env::var_os("AUTH_MODE").is_none()
+                && self.auth.jwt_issuer.is_none()
+                && env::var_os("JWT_ISSUER").is_none()
+            {
+                return Err("auth mode jwt needs jwt_issuer or JWT_ISSUER".to_string());
+            }
+        }
+        for (name, port) in [
+            ("server.port", self.server.port),
+            ("server.grpc_port", self.server.grpc_port),
+            ("database.port", self.database.port),
+        ] {
+            if port == Some(0) {
+                return Err(format!("{} must not be 0", name));
+            }
+        }
+
+        // Cosine similarities, or scores of the optional models
+        let thresholds = [
+            ("thresholds.search", self.thresholds.search),
+            ("thresholds.alert", self.thresholds.alert),
+            ("thresholds.detector", self.thresholds.detector),
+            ("thresholds.liveness", self.thresholds.liveness),
+            ("thresholds.mask", self.thresholds.mask),
+        ];
+        let origins = self
+            .thresholds
+            .origins
+            .values()
+            .map(|threshold| ("thresholds.origins", Some(*threshold)));
+        for (name, threshold) in thresholds.into_iter().chain(origins) {
+            if threshold.is_some_and(|threshold| !(-1.0..=1.0).contains(&threshold)) {
+                return Err(format!("{} must be between -1 and 1", name));
+            }
+        }
+        for (model, profile) in &self.profiles {
+            for (key, threshold) in [("search", profile.search), ("alert", profile.alert)] {
+                if threshold.is_some_and(|threshold| !(-1.0..=1.0).contains(&threshold)) {
+                    return Err(format!(
+                        "profiles.{}.{} must be between -1 and 1",
+                        model, key
+                    ));
+                }
+            }
+        }
+
+        let limits = &self.limits;
+        for (name, zero) in [
+            ("limits.search_limit", limits.search_limit == Some(0)),
+            ("limits.body_max_bytes", limits.body_max_bytes == Some(0)),
+            ("limits.video_max_bytes", limits.video_max_bytes == Some(0)),
+            (
+                "limits.import_max_bytes",
+                limits.import_max_bytes == Some(0),
+            ),
+            (
+                "limits.image_max_dimension",
+                limits.image_max_dimension == Some(0),
+            ),
+            (
+                "limits.inference_timeout_ms",
+                limits.inference_timeout_ms == Some(0),
+            ),
+            (
+                "limits.inference_max_concurrency",
+                limits.inference_max_concurrency == Some(0),
+            ),
+            (
+                "limits.store_max_entries",
+                limits.store_max_entries == Some(0),
+            ),
+            ("limits.store_max_bytes", limits.store_max_bytes == Some(0)),
+            (
+                "model.intra_op_threads",
+                self.model.intra_op_threads == Some(0),
+            ),
+            (
+                "model.inter_op_threads",
+                self.model.inter_op_threads == Some(0),
+            ),
+        ] {
+            if zero {
+                return Err(format!("{} must be positive", name));
+            }
+        }
+        for (name, value) in [
+            ("limits.image_max_megapixels", limits.image_max_megapixels),
+            ("limits.rate_limit_rps", limits.rate_limit_rps),
+            ("limits.rate_limit_burst", limits.rate_limit_burst),
+        ] {
+            if value.is_some_and(|value| value.is_nan() || value <= 0.0) {
+                return Err(format!("{} must be positive", name));
+            }
+        }
+        Ok(())
+    }
+
+    // Profile of the model that runs: that of MODEL_PATH from the
+    // environment, else from the file, else of the default model
+    fn active_profile(&self) -> Option<&ProfileConfig> {
+        let model_path = env::var_os("MODEL_PATH")
+            .map(PathBuf::from)
+            .or_else(|| self.model.path.clone())
+            .unwrap_or_else(|| PathBuf::from(crate::DEFAULT_MODEL_FILE));
+        self.profiles.get(&profiles::model_name(&model_path))
+    }
+
+    // Environment variables of the settings present in the file
+    fn variables(&self) -> Vec<(&'static str, String)> {
+        let mut variables = Vec::new();
+        let mut set = |name: &'static str, value: Option<String>| {
+            if let Some(value) = value {
+                variables.push((name, value));
+            }
+        };
+        fn text<T: Display>(value: &Option<T>) -> Option<String> {
+            value.as_ref().map(ToString::to_string)
+        }
+        fn path(value: &Option<PathBuf>) -> Option<String> {
+            value.as_ref().map(|path| path.display().to_string())
+        }
+        fn pairs<T: Display>(values: &BTreeMap<String, T>) -> Option<String> {
+            (!values.is_empty()).then(|| {
+                values
+                    .iter()
+                    .map(|(key, value)| format!("{}={}", key, value))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            })
+        }
+
+        let server = &self.server;
+        set("HOST", text(&server.host));
+        set("PORT", text(&server.port));
+        set("GRPC_PORT", text(&server.grpc_port));
+        set("LOG_LEVEL", text(&server.log_level));
+        set("LOG_FORMAT", text(&server.log_format));
+        set("TLS_CERT_PATH", path(&server.tls_cert_path));
+        set("TLS_KEY_PATH", path(&server.tls_key_path));
+
+        let database = &self.database;
+        set("STORAGE", text(&database.storage));
+        set("DATABASE_URL", text(&database.url));
+        set("POSTGRES_HOST", text(&database.host));
+        set("POSTGRES_PORT", text(&database.port));
+        set("POSTGRES_USER", text(&database.user));
+        set("POSTGRES_PASSWORD", text(&database.password));
+        set("POSTGRES_DB", text(&database.name));
+        set("RUN_MODE", text(&database.run_mode));
+        set("DB_RETRIES", text(&database.retries));
+        set("DB_BREAKER_THRESHOLD", text(&database.breaker_threshold));
+        set(
+            "DB_BREAKER_COOLDOWN_SECS",
+            text(&database.breaker_cooldown_secs),
+        );
+
+        let profile = self.active_profile();
+        let model = &self.model;
+        set("MODEL_PATH", path(&model.path));
+        set("MODEL_CHANNEL_ORDER", text(&model.channel_order));
+        set("MODEL_INPUT_MEAN", text(&model.input_mean));
+        set("MODEL_INPUT_STD", text(&model.input_std));
+        set("MODEL_INPUT_LAYOUT", text(&model.input_layout));
+        set("MODEL_RESIZE_MODE", text(&model.resize_mode));
+        set("TEMPLATE_MODE", text(&model.template_mode));
+        set("FLIP_TTA", text(&model.flip_tta));
+        set("DETECTOR_MODEL_PATH", path(&model.detector_path));
+        set("LIVENESS_MODEL_PATH", path(&model.liveness_path));
+        set("MASK_MODEL_PATH", path(&model.mask_path));
+        set("POSE_MODEL_PATH", path(&model.pose_path));
+        set("ATTRIBUTES_MODEL_PATH", path(&model.attributes_path));
+        set("EMOTION_MODEL_PATH", path(&model.emotion_path));
+        set("SR_MODEL_PATH", path(&model.super_resolution_path));
+        set(
+            "CALIBRATION_PATH",
+            path(
+                &profile
+                    .and_then(|profile| profile.calibration_path.clone())
+                    .or_else(|| model.calibration_path.clone()),
+            ),
+        );
+        set("ORT_INTRA_OP_THREADS", text(&model.intra_op_threads));
+        set("ORT_INTER_OP_THREADS", text(&model.inter_op_threads));
+        set("ORT_PARALLEL_EXECUTION", text(&model.parallel_execution));
+
+        let thresholds = &self.thresholds;
+        set(
+            "SEARCH_THRESHOLD",
+            text(
+                &profile
+                    .and_then(|profile| profile.search)
+                    .or(thresholds.search),
+            ),
+        );
+        set(
+            "ALERT_THRESHOLD",
+            text(
+                &profile
+                    .and_then(|profile| profile.alert)
+                    .or(thresholds.alert),
+            ),
+        );
+        set("DETECTOR_THRESHOLD", text(&thresholds.detector));
+        set("LIVENESS_THRESHOLD", text(&thresholds.liveness));
+        set("MASK_THRESHOLD", text(&thresholds.mask));
+        set("ORIGIN_THRESHOLDS", pairs(&thresholds.origins));
+
+        let limits = &self.limits;
+        set("SEARCH_LIMIT", text(&limits.search_limit));
+        set("BODY_MAX_BYTES", text(&limits.body_max_bytes));
+        set("VIDEO_MAX_BYTES", text(&limits.video_max_bytes));
+        set("IMPORT_MAX_BYTES", text(&limits.import_max_bytes));
+        set("IMAGE_MAX_DIMENSION", text(&limits.image_max_dimension));
+        set("IMAGE_MAX_MEGAPIXELS", text(&limits.image_max_megapixels));
+        set("INFERENCE_TIMEOUT_MS", text(&limits.inference_timeout_ms));
+        set(
+            "INFERENCE_MAX_CONCURRENCY",
+            text(&limits.inference_max_concurrency),
+        );
+        set("INFERENCE_MAX_QUEUE", text(&limits.inference_max_queue));
+        set("RATE_LIMIT_RPS", text(&limits.rate_limit_rps));
+        set("RATE_LIMIT_BURST", text(&limits.rate_limit_burst));
+        set("STORE_MAX_ENTRIES", text(&limits.store_max_entries));
+        set("STORE_MAX_BYTES", text(&limits.store_max_bytes));
+
+        let auth = &self.auth;
+        set("AUTH_MODE", text(&auth.mode));
+        set("ADMIN_API_KEY", text(&auth.admin_api_key));
+        set("TENANT_HEADER", text(&auth.tenant_header));
+        set("TENANT_API_KEYS", pairs(&auth.tenant_api_keys));
+        set("JWT_ISSUER", text(&auth.jwt_issuer));
+        set("JWT_AUDIENCE", text(&auth.jwt_audience));
+        set("JWT_JWKS_URL", text(&auth.jwt_jwks_url));
+        set("JWT_TENANT_CLAIM", text(&auth.jwt_tenant_claim));
+        variables
+    }
+
+    // The file's settings under the environment
+    pub fn resolve(&self) -> Settings {
+        Settings {
+            file: self.variables().into_iter().collect(),
+            environment: None,
+        }
+    }
+
+    // Runtime settings of the file, for a reload. Those whose variable was
+    // set by the environment rather than taken from the file are left out,
+    // so the environment keeps overriding the file.
+    pub fn settings_update(&self, file_variables: &[&str]) -> SettingsUpdate {
+        let from_file = |name: &str| file_variables.contains(&name) || env::var_os(name).is_none();
+        let profile = self.active_profile();
+        SettingsUpdate {
+            default_threshold: profile
+                .and_then(|profile| profile.search)
+                .or(self.thresholds.search)
+                .filter(|_| from_file("SEARCH_THRESHOLD")),
+            default_limit: self
+                .limits
+                .search_limit
+                .filter(|_| from_file("SEARCH_LIMIT")),
+            alert_threshold: profile
+                .and_then(|profile| profile.alert)
+                .or(self.thresholds.alert)
+                .filter(|_| from_file("ALERT_THRESHOLD")),
+            rate_limit_rps: self
+                .limits
+                .rate_limit_rps
+                .filter(|_| from_file("RATE_LIMIT_RPS"))
+                .map(Some),
+            rate_limit_burst: self
+                .limits
+                .rate_limit_burst
+                .filter(|_| from_file("RATE_LIMIT_BURST"))
+                .map(Some),
+        }
+    }
+}
+
+// Settings of the server by variable name: the environment, then the config
+// file. Read instead of the environment, which stays as the process got it;
+// setting variables once threads run is not sound.
+#[derive(Debug, Default)]
+pub struct Settings {
+    file: BTreeMap<&'static str, String>,
+    // Read in place of the process environment when set, by tests
+    environment: Option<BTreeMap<String, String>>,
+}
+
+impl Settings {
+    // The environment alone, without a config file
+    pub fn from_env() -> Self {
+        Self::default()
+    }
+
+    #[cfg(test)]
+    pub fn with_environment(mut self, environment: BTreeMap<String, String>) -> Self {
+        self.environment = Some(environment);
+        self
+    }
+
+    fn env_var(&self, name: &str) -> Result<String, env::VarError> {
+        match &self.environment {
+            Some(environment) => environment
+                .get(name)
+                .cloned()
+                .ok_or(env::VarError::NotPresent),
+            None => env::var(name),
+        }
+    }
+
+    fn in_env(&self, name: &str) -> bool {
+        !matches!(self.env_var(name), Err(env::VarError::NotPresent))
+    }
+
+    // Like env::var, falling back to the file
+    pub fn var(&self, name: &str) -> Result<String, env::VarError> {
+        match self.env_var(name) {
+            Err(env::VarError::NotPresent) => self
+                .file
+                .get(name)
+                .cloned()
+                .ok_or(env::VarError::NotPresent),
+            value => value,
+        }
+    }
+
+    pub fn is_set(&self, name: &str) -> bool {
+        self.in_env(name) || self.file.contains_key(name)
+    }
+
+    // Variables taken from the file, the environment not setting them
+    pub fn file_variables(&self) -> Vec<&'static str> {
+        self.file
+            .keys()
+            .copied()
+            .filter(|name| !self.in_env(name))
+            .collect()
+    }
+}
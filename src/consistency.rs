@@ -0,0 +1,226 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::Row;
+use std::collections::BTreeMap;
+use std::time::Instant;
+use uuid::Uuid;
+
+use crate::db;
+use crate::resync;
+use crate::AppState;
+
+const DEFAULT_MAX_LISTED: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct ConsistencyQuery {
+    // Reload the divergent targets from the database
+    #[serde(default)]
+    repair: bool,
+    // Most targets listed per category
+    limit: Option<usize>,
+}
+
+// Registrations of an origin in one collection, on both sides
+#[derive(Serialize)]
+pub struct OriginCounts {
+    tenant: String,
+    collection: String,
+    origin: String,
+    memory: usize,
+    database: i64,
+}
+
+#[derive(Serialize)]
+pub struct DivergentTarget {
+    tenant: String,
+    collection: String,
+    target_uuid: Uuid,
+    // Rows in Postgres and registrations in memory
+    database_rows: i64,
+    memory_registrations: i64,
+}
+
+// Define the response for /admin/consistency
+#[derive(Serialize)]
+pub struct ConsistencyResponse {
+    consistent: bool,
+    origins: Vec<OriginCounts>,
+    // Targets only in memory, only in the database, or in both with different
+    // registration counts
+    memory_only: Vec<DivergentTarget>,
+    database_only: Vec<DivergentTarget>,
+    mismatched: Vec<DivergentTarget>,
+    // More divergent targets than `limit` in some category
+    truncated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    repaired: Option<usize>,
+}
+
+// Handler for GET /admin/consistency - compares the in-memory stores with
+// the targets table, optionally reloading what diverged
+pub async fn consistency(
+    State(state): State<AppState>,
+    Query(query): Query<ConsistencyQuery>,
+) -> Result<Json<ConsistencyResponse>, StatusCode> {
+    let start = Instant::now();
+    let limit = query.limit.unwrap_or(DEFAULT_MAX_LISTED);
+    let db_error = |e: sqlx::Error| {
+        tracing::error!(error = %e, "Consistency check failed");
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+
+    // Keyed by (tenant, collection, origin), sorted for a stable report
+    let mut counts: BTreeMap<(String, String, String), (usize, i64)> = BTreeMap::new();
+    // A gallery shard only holds its own targets, counted per target
+    let rows = match &state.sharding {
+        None => sqlx::query(
+            "SELECT tenant, collection, origin, COUNT(*) AS rows FROM targets WHERE deleted_at IS NULL GROUP BY tenant, collection, origin",
+        ),
+        Some(_) => sqlx::query(
+            "SELECT tenant, collection, origin, uuid, COUNT(*) AS rows FROM targets WHERE deleted_at IS NULL GROUP BY tenant, collection, origin, uuid",
+        ),
+    }
+    .fetch_all(&state.bulk_pool)
+    .await
+    .map_err(db_error)?;
+    for row in rows {
+        if let Some(sharding) = &state.sharding {
+            let uuid: Uuid = row.try_get("uuid").map_err(db_error)?;
+            if !sharding.owns(&uuid) {
+                continue;
+            }
+        }
+        let key = (
+            row.try_get("tenant").map_err(db_error)?,
+            row.try_get("collection").map_err(db_error)?,
+            row.try_get("origin").map_err(db_error)?,
+        );
+        let rows: i64 = row.try_get("rows").map_err(db_error)?;
+        counts.entry(key).or_insert((0, 0)).1 += rows;
+    }
+    for (tenant, name, collection) in state.collections.all() {
+        for (origin, memory) in collection.store.origin_counts() {
+            // Origins whose targets were all deleted linger at zero
+            if memory > 0 {
+                counts
+                    .entry((tenant.clone(), name.clone(), origin))
+                    .or_insert((0, 0))
+                    .0 = memory;
+            }
+        }
+    }
+    let origins: Vec<OriginCounts> = counts
+        .into_iter()
+        .map(
+            |((tenant, collection, origin), (memory, database))| OriginCounts {
+                tenant,
+                collection,
+                origin,
+                memory,
+                database,
+            },
+        )
+        .collect();
+
+    let drifted = resync::drifted_targets(&state).await.map_err(db_error)?;
+    let mut sorted: Vec<_> = drifted.iter().collect();
+    sorted.sort_by(|a, b| a.0.cmp(b.0));
+    let (mut memory_only, mut database_only, mut mismatched) = (Vec::new(), Vec::new(), Vec::new());
+    let mut truncated = false;
+    for ((tenant, collection, uuid), &(database_rows, memory_registrations)) in sorted {
+        let list = match (database_rows, memory_registrations) {
+            (0, _) => &mut memory_only,
+            (_, 0) => &mut database_only,
+            _ => &mut mismatched,
+        };
+        if list.len() == limit {
+            truncated = true;
+            continue;
+        }
+        list.push(DivergentTarget {
+            tenant: tenant.clone(),
+            collection: collection.clone(),
+            target_uuid: *uuid,
+            database_rows,
+            memory_registrations,
+        });
+    }
+    let consistent = drifted.is_empty()
+        && origins
+            .iter()
+            .all(|counts| counts.memory as i64 == counts.database);
+
+    // The database is the source of truth; every divergent target is reloaded,
+    // listed or not
+    let repaired = if query.repair {
+        for key in drifted.keys() {
+            resync::reload(&state, key).await.map_err(db_error)?;
+        }
+        tracing::warn!(
+            repaired = drifted.len(),
+            "Divergent targets reloaded from the database"
+        );
+        Some(drifted.len())
+    } else {
+        None
+    };
+    tracing::info!(
+        consistent,
+        divergent = drifted.len(),
+        duration = ?start.elapsed(),
+        "Consistency check done"
+    );
+
+    Ok(Json(ConsistencyResponse {
+        consistent,
+        origins,
+        memory_only,
+        database_only,
+        mismatched,
+        truncated,
+        repaired,
+    }))
+}
+
+// Define the response for /admin/reload
+#[derive(Serialize)]
+pub struct ReloadResponse {
+    collections: usize,
+    embeddings: usize,
+    duration_ms: u64,
+}
+
+// Handler for POST /admin/reload - rebuilds every store from the database and
+// swaps them all in at once, e.g. after bulk SQL imports
+pub async fn reload(State(state): State<AppState>) -> Result<Json<ReloadResponse>, StatusCode> {
+    let start = Instant::now();
+    let fresh = state.collections.empty_like();
+    let loaded = async {
+        db::load_collections(&state.db_pool, &fresh).await?;
+        state.targets.load_all(&fresh, 0).await
+    }
+    .await;
+    let embeddings = loaded.map_err(|e| {
+        tracing::error!(error = %e, "Failed to reload the stores, keeping the current ones");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let collections = fresh.all().len();
+    state.collections.replace(fresh);
+
+    let duration = start.elapsed();
+    tracing::info!(
+        collections,
+        embeddings,
+        ?duration,
+        "Stores reloaded from the database"
+    );
+    Ok(Json(ReloadResponse {
+        collections,
+        embeddings,
+        duration_ms: duration.as_millis() as u64,
+    }))
+}
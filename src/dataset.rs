@@ -0,0 +1,185 @@
+use arrow::array::{ArrayRef, FixedSizeListBuilder, Float32Builder, StringBuilder};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use arrow::ipc::writer::FileWriter;
+use arrow::record_batch::RecordBatch;
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::io::Write;
+use std::sync::Arc;
+
+use crate::chunked;
+use crate::collections::DEFAULT_COLLECTION;
+use crate::store::EmbeddingEntry;
+use crate::tenant::DEFAULT_TENANT;
+use crate::AppState;
+
+// Rows per record batch (and Parquet row group)
+const BATCH_ROWS: usize = 4096;
+
+type DatasetError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Deserialize, Default, Clone, Copy, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DatasetFormat {
+    #[default]
+    Parquet,
+    Arrow,
+}
+
+impl DatasetFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            DatasetFormat::Parquet => "application/vnd.apache.parquet",
+            DatasetFormat::Arrow => "application/vnd.apache.arrow.file",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            DatasetFormat::Parquet => "parquet",
+            DatasetFormat::Arrow => "arrow",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DatasetQuery {
+    #[serde(default)]
+    format: DatasetFormat,
+    tenant: Option<String>,
+    collection: Option<String>,
+}
+
+fn schema(dimension: usize) -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("uuid", DataType::Utf8, false),
+        Field::new("origin", DataType::Utf8, false),
+        // JSON object, as stored
+        Field::new("metadata", DataType::Utf8, false),
+        Field::new(
+            "embedding",
+            DataType::FixedSizeList(
+                Arc::new(Field::new("item", DataType::Float32, false)),
+                dimension as i32,
+            ),
+            false,
+        ),
+    ]))
+}
+
+fn record_batch(
+    schema: &SchemaRef,
+    rows: &[(&EmbeddingEntry, Cow<'_, [f32]>)],
+    dimension: usize,
+) -> Result<RecordBatch, DatasetError> {
+    let mut uuids = StringBuilder::new();
+    let mut origins = StringBuilder::new();
+    let mut metadata = StringBuilder::new();
+    let mut embeddings = FixedSizeListBuilder::new(Float32Builder::new(), dimension as i32)
+        .with_field(Arc::new(Field::new("item", DataType::Float32, false)));
+    for (entry, embedding) in rows {
+        uuids.append_value(entry.uuid.to_string());
+        origins.append_value(&entry.origin);
+        metadata.append_value(serde_json::to_string(&entry.metadata)?);
+        embeddings.values().append_slice(embedding);
+        embeddings.append(true);
+    }
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(uuids.finish()),
+        Arc::new(origins.finish()),
+        Arc::new(metadata.finish()),
+        Arc::new(embeddings.finish()),
+    ];
+    Ok(RecordBatch::try_new(schema.clone(), columns)?)
+}
+
+// One row per stored vector: a registration, the running mean of a uuid in
+// `mean` template mode or each enrolled vector in `max` mode
+pub(crate) fn write_dataset<W: Write + Send>(
+    entries: &[EmbeddingEntry],
+    dimension: usize,
+    format: DatasetFormat,
+    writer: W,
+) -> Result<(), DatasetError> {
+    let schema = schema(dimension);
+    let rows: Vec<(&EmbeddingEntry, Cow<'_, [f32]>)> = entries
+        .iter()
+        .flat_map(|entry| {
+            entry
+                .embeddings
+                .iter()
+                .map(move |embedding| (entry, embedding.to_f32()))
+        })
+        .collect();
+
+    match format {
+        DatasetFormat::Parquet => {
+            let properties = WriterProperties::builder()
+                .set_compression(Compression::SNAPPY)
+                .set_max_row_group_size(BATCH_ROWS)
+                .build();
+            let mut file = ArrowWriter::try_new(writer, schema.clone(), Some(properties))?;
+            for chunk in rows.chunks(BATCH_ROWS) {
+                file.write(&record_batch(&schema, chunk, dimension)?)?;
+            }
+            file.into_inner()?.flush()?;
+        }
+        DatasetFormat::Arrow => {
+            let mut file = FileWriter::try_new(writer, &schema)?;
+            for chunk in rows.chunks(BATCH_ROWS) {
+                file.write(&record_batch(&schema, chunk, dimension)?)?;
+            }
+            file.finish()?;
+            file.into_inner()?.flush()?;
+        }
+    }
+    Ok(())
+}
+
+// Handler for GET /admin/export - the gallery of a collection as a Parquet or
+// Arrow IPC file for offline analysis
+pub async fn export_dataset(
+    State(state): State<AppState>,
+    Query(query): Query<DatasetQuery>,
+) -> Result<Response, StatusCode> {
+    let tenant = query.tenant.as_deref().unwrap_or(DEFAULT_TENANT);
+    let name = query.collection.as_deref().unwrap_or(DEFAULT_COLLECTION);
+    let Some(collection) = state.collections.get(tenant, name) else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let entries = collection.store.snapshot().await;
+    let dimension = collection.store.dimension().unwrap_or(0);
+    tracing::info!(tenant, collection = %name, targets = entries.len(), "Exporting gallery dataset");
+
+    // Written into the response body as the file is encoded, so the gallery
+    // is never held twice in memory
+    let (mut writer, body) = chunked::body();
+    let format = query.format;
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = write_dataset(&entries, dimension, format, &mut writer) {
+            tracing::error!(error = %e, "Failed to write gallery dataset");
+            writer.blocking_abort(e);
+        }
+    });
+
+    let filename = format!("{}-{}.{}", tenant, name, format.extension());
+    Ok((
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", filename),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
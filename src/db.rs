@@ -0,0 +1,160 @@
+use sqlx::mysql::MySqlPoolOptions;
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+use sqlx::{Connection, PgPool, Row};
+use std::time::Duration;
+
+use crate::collections::{CollectionSettings, Collections};
+use crate::store::TemplateMode;
+
+pub const DEFAULT_MAX_CONNECTIONS: u32 = 5;
+pub const DEFAULT_ACQUIRE_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+// Sizing and timeouts of the database pool
+#[derive(Clone, Copy, Debug)]
+pub struct PoolConfig {
+    pub max_connections: u32,
+    // Opened at startup and kept open even when idle
+    pub min_connections: u32,
+    // Longest a query waits for a free connection before failing
+    pub acquire_timeout: Duration,
+    // Idle connections above `min_connections` are closed after it; never
+    // when None
+    pub idle_timeout: Option<Duration>,
+    // Statements running longer are cancelled by the server; Postgres only
+    pub statement_timeout: Option<Duration>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            min_connections: 0,
+            acquire_timeout: DEFAULT_ACQUIRE_TIMEOUT,
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+            statement_timeout: None,
+        }
+    }
+}
+
+impl PoolConfig {
+    pub fn postgres(&self) -> PgPoolOptions {
+        let options = PgPoolOptions::new()
+            .max_connections(self.max_connections)
+            .min_connections(self.min_connections)
+            .acquire_timeout(self.acquire_timeout)
+            .idle_timeout(self.idle_timeout);
+        let Some(statement_timeout) = self.statement_timeout else {
+            return options;
+        };
+        // Set on every new connection, so it holds for the whole session
+        options.after_connect(move |connection, _| {
+            Box::pin(async move {
+                sqlx::query(&format!(
+                    "SET statement_timeout = {}",
+                    statement_timeout.as_millis()
+                ))
+                .execute(connection)
+                .await?;
+                Ok(())
+            })
+        })
+    }
+
+    // Settings of the pools of bulk work and of the replica: statements there
+    // legitimately read or write whole tables, so DB_STATEMENT_TIMEOUT_MS does
+    // not apply to them
+    pub fn bulk(&self) -> PoolConfig {
+        PoolConfig {
+            min_connections: 0,
+            statement_timeout: None,
+            ..*self
+        }
+    }
+
+    pub fn mysql(&self) -> MySqlPoolOptions {
+        MySqlPoolOptions::new()
+            .max_connections(self.max_connections)
+            .min_connections(self.min_connections)
+            .acquire_timeout(self.acquire_timeout)
+            .idle_timeout(self.idle_timeout)
+    }
+}
+
+// Creates the application database if it does not exist yet
+pub async fn ensure_database(
+    pg_options: &PgConnectOptions,
+    postgres_db: &str,
+) -> Result<(), sqlx::Error> {
+    // 1. Connect to the default 'postgres' database
+    tracing::info!("Connecting to default 'postgres' database to ensure target database exists...");
+    let mut conn =
+        sqlx::PgConnection::connect_with(&pg_options.clone().database("postgres")).await?;
+
+    // 2. Try to create the target database
+    tracing::info!(target_db = %postgres_db, "Attempting to create database if it doesn't exist...");
+    match sqlx::query(&format!("CREATE DATABASE \"{}\"", postgres_db))
+        .execute(&mut conn)
+        .await
+    {
+        Ok(_) => {
+            tracing::info!(target_db = %postgres_db, "Database created successfully or already existed.")
+        }
+        Err(e) => {
+            if let Some(db_err) = e.as_database_error() {
+                // Check for PostgreSQL error code '42P04' (database already exists)
+                if db_err.code().is_some_and(|code| code == "42P04") {
+                    tracing::info!(target_db = %postgres_db, "Database already exists.");
+                } else {
+                    tracing::error!(error = %e, target_db = %postgres_db, "Failed to create database");
+                    return Err(e); // Return the original error
+                }
+            } else {
+                tracing::error!(error = %e, target_db = %postgres_db, "Non-database error occurred during creation check");
+                return Err(e); // Return the original error
+            }
+        }
+    }
+    // Connection to 'postgres' DB is implicitly closed when 'conn' goes out of scope here.
+    Ok(())
+}
+
+// Applies the pending migrations of `migrations/`, embedded at build time;
+// sqlx records them in `_sqlx_migrations` and serializes concurrent instances
+pub async fn migrate(pool: &PgPool) -> Result<(), sqlx::migrate::MigrateError> {
+    tracing::info!("Applying database migrations...");
+    sqlx::migrate!().run(pool).await?;
+    tracing::info!("Database schema is up to date.");
+    Ok(())
+}
+
+// Version of the newest migration this binary carries
+pub fn schema_version() -> i64 {
+    sqlx::migrate!()
+        .iter()
+        .map(|migration| migration.version)
+        .max()
+        .unwrap_or(0)
+}
+
+// Adds every collection of the 'collections' table with its settings
+pub async fn load_collections(pool: &PgPool, collections: &Collections) -> Result<(), sqlx::Error> {
+    for record in sqlx::query("SELECT tenant, name, threshold, template_mode FROM collections")
+        .fetch_all(pool)
+        .await?
+    {
+        let tenant: String = record.try_get("tenant")?;
+        let name: String = record.try_get("name")?;
+        let template_mode: Option<String> = record.try_get("template_mode")?;
+        let settings = CollectionSettings {
+            threshold: record.try_get("threshold")?,
+            template_mode: template_mode
+                .map(|mode| mode.parse::<TemplateMode>())
+                .transpose()
+                .map_err(|e| sqlx::Error::Decode(e.into()))?,
+        };
+        collections.insert(&tenant, &name, settings);
+        tracing::info!(%tenant, collection = %name, settings = ?settings, "Collection loaded");
+    }
+    Ok(())
+}
@@ -0,0 +1,355 @@
+use axum::{extract::State, http::StatusCode, Json};
+use ndarray::{s, Array2};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sqlx::Row;
+use std::collections::HashMap;
+use std::time::Instant;
+use uuid::Uuid;
+
+use crate::collections::DEFAULT_COLLECTION;
+use crate::encryption;
+use crate::handlers;
+use crate::notify::Change;
+use crate::store::{EmbeddingEntry, EmbeddingsStore, Metadata};
+use crate::tenant::DEFAULT_TENANT;
+use crate::AppState;
+
+const DEFAULT_DEDUPE_THRESHOLD: f32 = 0.8;
+const DEFAULT_MAX_CANDIDATES: usize = 1000;
+// Block of the gallery self-similarity matrix computed per matrix product,
+// small enough to stay in cache on large galleries
+const BLOCK_ROWS: usize = 256;
+const BLOCK_COLUMNS: usize = 4096;
+
+// Define the request payload for /admin/dedupe
+#[derive(Deserialize)]
+pub struct DedupePayload {
+    #[serde(default)]
+    tenant: Option<String>,
+    #[serde(default)]
+    collection: Option<String>,
+    threshold: Option<f32>,
+    // Most candidate pairs returned, best first
+    limit: Option<usize>,
+    // Fold every group of duplicates into its most enrolled uuid
+    #[serde(default)]
+    merge: bool,
+}
+
+// Two uuids whose best pair of registrations exceeds the threshold
+#[derive(Serialize)]
+pub struct DuplicateCandidate {
+    uuids: [Uuid; 2],
+    similarity: f32,
+}
+
+// Uuids folded into `target_uuid` by a merge
+#[derive(Serialize)]
+pub struct MergedGroup {
+    target_uuid: Uuid,
+    merged: Vec<Uuid>,
+}
+
+// Define the response for /admin/dedupe
+#[derive(Serialize)]
+pub struct DedupeResponse {
+    candidates: Vec<DuplicateCandidate>,
+    // More pairs exceeded the threshold than `limit`
+    truncated: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    merged: Vec<MergedGroup>,
+}
+
+// Every stored vector with the uuid it belongs to
+fn gallery(entries: Vec<EmbeddingEntry>) -> (Vec<Uuid>, Vec<Vec<f32>>) {
+    let mut owners = Vec::new();
+    let mut vectors = Vec::new();
+    for entry in entries {
+        for embedding in entry.embeddings {
+            owners.push(entry.uuid);
+            vectors.push(embedding.into_f32());
+        }
+    }
+    (owners, vectors)
+}
+
+// Best similarity of every pair of distinct uuids above the threshold; the
+// upper triangle of the gallery self-similarity matrix, block by block
+fn duplicate_pairs(
+    owners: &[Uuid],
+    vectors: &[Vec<f32>],
+    threshold: f32,
+) -> HashMap<(Uuid, Uuid), f32> {
+    let Some(dimension) = vectors.first().map(Vec::len) else {
+        return HashMap::new();
+    };
+    let mut matrix = Array2::<f32>::zeros((vectors.len(), dimension));
+    for (mut row, vector) in matrix.rows_mut().into_iter().zip(vectors) {
+        let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            row.iter_mut()
+                .zip(vector)
+                .for_each(|(cell, x)| *cell = x / norm);
+        }
+    }
+
+    (0..vectors.len())
+        .step_by(BLOCK_ROWS)
+        .collect::<Vec<_>>()
+        .into_par_iter()
+        .map(|start| {
+            let rows = matrix.slice(s![start..(start + BLOCK_ROWS).min(vectors.len()), ..]);
+            let mut pairs = HashMap::new();
+            for column_start in (start..vectors.len()).step_by(BLOCK_COLUMNS) {
+                let column_end = (column_start + BLOCK_COLUMNS).min(vectors.len());
+                let similarities = rows.dot(&matrix.slice(s![column_start..column_end, ..]).t());
+                for ((i, j), &similarity) in similarities.indexed_iter() {
+                    let (row, column) = (start + i, column_start + j);
+                    // Only the upper triangle, without the diagonal
+                    if column <= row || similarity < threshold {
+                        continue;
+                    }
+                    let (a, b) = (owners[row], owners[column]);
+                    if a == b {
+                        continue;
+                    }
+                    let key = if a < b { (a, b) } else { (b, a) };
+                    let best = pairs.entry(key).or_insert(similarity);
+                    *best = best.max(similarity);
+                }
+            }
+            pairs
+        })
+        .reduce(HashMap::new, |mut merged, pairs| {
+            for (key, similarity) in pairs {
+                let best = merged.entry(key).or_insert(similarity);
+                *best = best.max(similarity);
+            }
+            merged
+        })
+}
+
+// Union-find root of a uuid, registering it on first sight
+fn root(parent: &mut HashMap<Uuid, Uuid>, uuid: Uuid) -> Uuid {
+    let mut current = uuid;
+    while let Some(&next) = parent.get(&current) {
+        if next == current {
+            break;
+        }
+        current = next;
+    }
+    parent.insert(uuid, current);
+    current
+}
+
+// Connected components of the candidate pairs, each with the uuid that
+// absorbs the others: the one with the most registrations
+fn merge_groups(
+    candidates: &[DuplicateCandidate],
+    registrations: &HashMap<Uuid, u32>,
+) -> Vec<MergedGroup> {
+    let mut parent: HashMap<Uuid, Uuid> = HashMap::new();
+    for candidate in candidates {
+        let [a, b] = candidate.uuids;
+        let (a, b) = (root(&mut parent, a), root(&mut parent, b));
+        if a != b {
+            parent.insert(b, a);
+        }
+    }
+
+    let uuids: Vec<Uuid> = parent.keys().copied().collect();
+    let mut components: HashMap<Uuid, Vec<Uuid>> = HashMap::new();
+    for uuid in uuids {
+        let group = root(&mut parent, uuid);
+        components.entry(group).or_default().push(uuid);
+    }
+    let mut groups: Vec<MergedGroup> = components
+        .into_values()
+        .map(|mut members| {
+            // Most registrations first, ties broken by uuid for a stable choice
+            members.sort_by_key(|uuid| {
+                (
+                    std::cmp::Reverse(registrations.get(uuid).copied().unwrap_or(0)),
+                    *uuid,
+                )
+            });
+            let target_uuid = members.remove(0);
+            MergedGroup {
+                target_uuid,
+                merged: members,
+            }
+        })
+        .collect();
+    groups.sort_by_key(|group| group.target_uuid);
+    groups
+}
+
+// Relabels the registrations of every merged uuid in the database, then
+// reloads the affected targets into the store
+async fn merge(
+    state: &AppState,
+    store: &EmbeddingsStore,
+    tenant: &str,
+    collection: &str,
+    groups: &[MergedGroup],
+) -> Result<(), sqlx::Error> {
+    // Rows still queued for the database would keep their old uuid
+    if let Some(write_behind) = &state.write_behind {
+        write_behind.settle_all().await;
+    }
+    let mut transaction = state.db_pool.begin().await?;
+    for group in groups {
+        // A new id, so a snapshot holding the rows under their old uuid is
+        // recognized as stale
+        sqlx::query(
+            "UPDATE targets SET uuid = $1, id = nextval(pg_get_serial_sequence('targets', 'id')) WHERE tenant = $2 AND collection = $3 AND uuid = ANY($4)",
+        )
+        .bind(group.target_uuid)
+        .bind(tenant)
+        .bind(collection)
+        .bind(&group.merged)
+        .execute(&mut *transaction)
+        .await?;
+        if let Some(notifier) = &state.notifier {
+            for uuid in std::iter::once(&group.target_uuid).chain(&group.merged) {
+                notifier
+                    .publish(
+                        &mut *transaction,
+                        Change::Target {
+                            tenant: tenant.to_string(),
+                            collection: collection.to_string(),
+                            uuid: *uuid,
+                        },
+                    )
+                    .await?;
+            }
+        }
+    }
+    transaction.commit().await?;
+
+    for group in groups {
+        let rows = sqlx::query(
+            "SELECT embeddings, embeddings_sealed, origin, metadata, model FROM targets WHERE tenant = $1 AND collection = $2 AND uuid = $3 AND deleted_at IS NULL",
+        )
+        .bind(tenant)
+        .bind(collection)
+        .bind(group.target_uuid)
+        .fetch_all(&state.db_pool)
+        .await?;
+        for uuid in std::iter::once(&group.target_uuid).chain(&group.merged) {
+            store.remove(uuid).await;
+        }
+        for row in rows {
+            let embeddings = encryption::from_row(&row)?;
+            let origin: String = row.try_get("origin")?;
+            let metadata: sqlx::types::Json<Metadata> = row.try_get("metadata")?;
+            let added = store
+                .add(
+                    group.target_uuid,
+                    origin,
+                    metadata.0,
+                    embeddings,
+                    row.try_get("model")?,
+                )
+                .await;
+            if !added {
+                tracing::warn!(uuid = %group.target_uuid, expected = ?store.dimension(), "Skipped a stored embedding of the wrong dimension");
+            }
+        }
+    }
+    state.collections.enforce_memory_limits().await;
+    Ok(())
+}
+
+// Handler for POST /admin/dedupe - pairs of different uuids that look like
+// the same person, optionally merged into one uuid each
+pub async fn dedupe(
+    State(state): State<AppState>,
+    Json(payload): Json<DedupePayload>,
+) -> Result<Json<DedupeResponse>, StatusCode> {
+    let start = Instant::now();
+    let tenant = payload.tenant.as_deref().unwrap_or(DEFAULT_TENANT);
+    let name = payload.collection.as_deref().unwrap_or(DEFAULT_COLLECTION);
+    let threshold = payload.threshold.unwrap_or(DEFAULT_DEDUPE_THRESHOLD);
+    let limit = payload.limit.unwrap_or(DEFAULT_MAX_CANDIDATES);
+    if !(-1.0..=1.0).contains(&threshold) {
+        tracing::warn!(threshold, "Received dedupe request with invalid threshold");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if payload.merge && !state.run_mode.is_writable() {
+        tracing::warn!("Rejected dedupe merge on read-only instance");
+        return Err(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    let Some(collection) = state.collections.get(tenant, name) else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+
+    let entries = collection.store.snapshot().await;
+    let mut registrations: HashMap<Uuid, u32> = HashMap::new();
+    for entry in &entries {
+        *registrations.entry(entry.uuid).or_insert(0) += entry.samples;
+    }
+    let (owners, vectors) = gallery(entries);
+    if vectors
+        .iter()
+        .any(|vector| vector.len() != vectors[0].len())
+    {
+        tracing::error!("Gallery holds embeddings of different dimensions");
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    // Quadratic in the gallery size, keep it off the async workers
+    let vector_count = vectors.len();
+    let pairs = tokio::task::spawn_blocking(move || duplicate_pairs(&owners, &vectors, threshold))
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, "Dedupe task failed");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let mut candidates: Vec<DuplicateCandidate> = pairs
+        .into_iter()
+        .map(|((a, b), similarity)| DuplicateCandidate {
+            uuids: [a, b],
+            similarity,
+        })
+        .collect();
+    candidates.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+    let truncated = candidates.len() > limit;
+    candidates.truncate(limit);
+    tracing::info!(
+        tenant,
+        collection = %name,
+        vectors = vector_count,
+        candidates = candidates.len(),
+        truncated,
+        duration = ?start.elapsed(),
+        "Gallery deduplication scan done"
+    );
+
+    // Only the returned pairs are merged, so a truncated scan merges the most
+    // confident ones first
+    let merged = if payload.merge {
+        let groups = merge_groups(&candidates, &registrations);
+        let merging = merge(&state, &collection.store, tenant, name, &groups).await;
+        // Even a failed merge may have relabelled some targets already
+        handlers::invalidate_cached_searches(&state, tenant, name).await;
+        merging.map_err(|e| {
+            tracing::error!(error = %e, "Failed to merge duplicate targets");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        for group in &groups {
+            tracing::info!(uuid = %group.target_uuid, merged = ?group.merged, "Duplicate targets merged");
+        }
+        groups
+    } else {
+        Vec::new()
+    };
+
+    Ok(Json(DedupeResponse {
+        candidates,
+        truncated,
+        merged,
+    }))
+}
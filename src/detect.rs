@@ -0,0 +1,258 @@
+use base64::{engine::general_purpose, Engine as _};
+use image::{DynamicImage, GenericImageView, ImageFormat, Rgb, RgbImage};
+use ndarray::Array;
+use serde::{Serialize, Serializer};
+use std::io::Cursor;
+use std::path::Path;
+
+pub use crate::api::DetectedFace;
+use crate::embedder::SessionThreads;
+use crate::onnx::ImageModel;
+
+pub const DEFAULT_DETECTION_THRESHOLD: f32 = 0.5;
+const NMS_THRESHOLD: f32 = 0.4;
+const STRIDES: [usize; 3] = [8, 16, 32];
+// Anchors per feature map location in the SCRFD models
+const ANCHORS: usize = 2;
+// Side of the aligned crop, the embedding model input
+const ALIGNED_SIZE: u32 = 112;
+// Landmark positions (eyes, nose tip, mouth corners) of the ArcFace 112x112
+// template
+const ARCFACE_TEMPLATE: [[f32; 2]; 5] = [
+    [38.2946, 51.6963],
+    [73.5318, 51.5014],
+    [56.0252, 71.7366],
+    [41.5493, 92.3655],
+    [70.7299, 92.2041],
+];
+
+impl DetectedFace {
+    pub fn area(&self) -> f32 {
+        (self.bbox[2] - self.bbox[0]).max(0.0) * (self.bbox[3] - self.bbox[1]).max(0.0)
+    }
+
+    fn iou(&self, other: &DetectedFace) -> f32 {
+        let width = (self.bbox[2].min(other.bbox[2]) - self.bbox[0].max(other.bbox[0])).max(0.0);
+        let height = (self.bbox[3].min(other.bbox[3]) - self.bbox[1].max(other.bbox[1])).max(0.0);
+        let intersection = width * height;
+        intersection / (self.area() + other.area() - intersection).max(f32::EPSILON)
+    }
+
+    // The same face in the image `factor` times larger
+    pub fn scaled(mut self, factor: f32) -> Self {
+        self.bbox.iter_mut().for_each(|value| *value *= factor);
+        for point in &mut self.landmarks {
+            point.iter_mut().for_each(|value| *value *= factor);
+        }
+        self
+    }
+
+    // Region of the box within the image, as (x, y, width, height)
+    pub fn region(&self, img: &DynamicImage) -> (u32, u32, u32, u32) {
+        let (width, height) = img.dimensions();
+        let x1 = (self.bbox[0].max(0.0) as u32).min(width.saturating_sub(1));
+        let y1 = (self.bbox[1].max(0.0) as u32).min(height.saturating_sub(1));
+        let x2 = (self.bbox[2].max(0.0).ceil() as u32).clamp(x1 + 1, width.max(x1 + 1));
+        let y2 = (self.bbox[3].max(0.0).ceil() as u32).clamp(y1 + 1, height.max(y1 + 1));
+        (x1, y1, x2 - x1, y2 - y1)
+    }
+}
+
+// PNG of a face as fed to the embedding model, base64 in JSON
+#[derive(Clone, Debug)]
+pub struct FaceCrop(pub Vec<u8>);
+
+impl FaceCrop {
+    pub fn encode(img: &DynamicImage) -> Result<Self, image::ImageError> {
+        let mut png = Vec::new();
+        img.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+        Ok(Self(png))
+    }
+}
+
+impl Serialize for FaceCrop {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&general_purpose::STANDARD.encode(&self.0))
+    }
+}
+
+// SCRFD face detector exported to ONNX with keypoints (e.g. InsightFace's
+// det_10g.onnx): a [1, 3, H, W] RGB input normalized as (x - 127.5) / 128,
+// and score, box and keypoint outputs for strides 8, 16 and 32 in that order
+pub struct FaceDetector {
+    model: ImageModel,
+    input_size: u32,
+    threshold: f32,
+}
+
+impl FaceDetector {
+    pub fn load(
+        model_path: &Path,
+        threads: SessionThreads,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let model = ImageModel::load(model_path, threads)?;
+        if model.output_count() != 9 {
+            return Err(format!(
+                "expected a detector with keypoints (9 outputs), got {} outputs",
+                model.output_count()
+            )
+            .into());
+        }
+
+        // Dynamic dimensions fall back to 640x640; SCRFD inputs are square
+        let input_size = model.dim(2, 640);
+
+        Ok(Self {
+            model,
+            input_size,
+            threshold: DEFAULT_DETECTION_THRESHOLD,
+        })
+    }
+
+    pub fn with_threshold(mut self, threshold: f32) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    // Faces above the detection threshold, largest first
+    pub fn detect(
+        &self,
+        img: &DynamicImage,
+    ) -> Result<Vec<DetectedFace>, Box<dyn std::error::Error>> {
+        // Letterboxed: scaled to fit the square input, padded right and bottom
+        let (width, height) = img.dimensions();
+        let scale = self.input_size as f32 / width.max(height) as f32;
+        let resized = img
+            .resize_exact(
+                ((width as f32 * scale).round() as u32).max(1),
+                ((height as f32 * scale).round() as u32).max(1),
+                image::imageops::FilterType::Triangle,
+            )
+            .to_rgb8();
+
+        let size = self.input_size as usize;
+        let mut input = Array::zeros((1, 3, size, size));
+        for (x, y, pixel) in resized.enumerate_pixels() {
+            for channel in 0..3 {
+                input[[0, channel, y as usize, x as usize]] =
+                    (pixel[channel] as f32 - 127.5) / 128.0;
+            }
+        }
+
+        let outputs = self.model.run(input)?;
+
+        let mut candidates = Vec::new();
+        for (level, &stride) in STRIDES.iter().enumerate() {
+            let scores = &outputs[level].values;
+            let boxes = &outputs[level + 3].values;
+            let keypoints = &outputs[level + 6].values;
+
+            let cells = size / stride;
+            if scores.len() != cells * cells * ANCHORS
+                || boxes.len() != scores.len() * 4
+                || keypoints.len() != scores.len() * 10
+            {
+                return Err(
+                    format!("unexpected detector output sizes at stride {}", stride).into(),
+                );
+            }
+            for (anchor, &score) in scores.iter().enumerate() {
+                if score < self.threshold {
+                    continue;
+                }
+                let cell = anchor / ANCHORS;
+                let cx = ((cell % cells) * stride) as f32;
+                let cy = ((cell / cells) * stride) as f32;
+                let stride = stride as f32;
+                let distance = &boxes[anchor * 4..anchor * 4 + 4];
+                let points = &keypoints[anchor * 10..anchor * 10 + 10];
+
+                let mut landmarks = [[0.0; 2]; 5];
+                for (point, landmark) in landmarks.iter_mut().enumerate() {
+                    *landmark = [
+                        (cx + points[point * 2] * stride) / scale,
+                        (cy + points[point * 2 + 1] * stride) / scale,
+                    ];
+                }
+                candidates.push(DetectedFace {
+                    bbox: [
+                        (cx - distance[0] * stride) / scale,
+                        (cy - distance[1] * stride) / scale,
+                        (cx + distance[2] * stride) / scale,
+                        (cy + distance[3] * stride) / scale,
+                    ],
+                    score,
+                    landmarks,
+                });
+            }
+        }
+
+        // Non-maximum suppression, best score first
+        candidates.sort_by(|a, b| b.score.total_cmp(&a.score));
+        let mut faces: Vec<DetectedFace> = Vec::new();
+        for candidate in candidates {
+            if faces
+                .iter()
+                .all(|face| face.iou(&candidate) <= NMS_THRESHOLD)
+            {
+                faces.push(candidate);
+            }
+        }
+        faces.sort_by(|a, b| b.area().total_cmp(&a.area()));
+        Ok(faces)
+    }
+}
+
+// Crop of `face` rotated, scaled and shifted so its landmarks land on the
+// ArcFace template, as the recognition model was trained on
+pub fn align(img: &DynamicImage, face: &DetectedFace) -> DynamicImage {
+    // Least-squares similarity transform from the landmarks to the template:
+    // u = a*x - b*y + tx, v = b*x + a*y + ty
+    let count = face.landmarks.len() as f32;
+    let mean =
+        |points: &[[f32; 2]], axis: usize| points.iter().map(|p| p[axis]).sum::<f32>() / count;
+    let (src_x, src_y) = (mean(&face.landmarks, 0), mean(&face.landmarks, 1));
+    let (dst_x, dst_y) = (mean(&ARCFACE_TEMPLATE, 0), mean(&ARCFACE_TEMPLATE, 1));
+    let (mut dot, mut cross, mut norm) = (0.0, 0.0, 0.0);
+    for (src, dst) in face.landmarks.iter().zip(ARCFACE_TEMPLATE.iter()) {
+        let (x, y) = (src[0] - src_x, src[1] - src_y);
+        let (u, v) = (dst[0] - dst_x, dst[1] - dst_y);
+        dot += x * u + y * v;
+        cross += x * v - y * u;
+        norm += x * x + y * y;
+    }
+    let norm = norm.max(f32::EPSILON);
+    let (a, b) = (dot / norm, cross / norm);
+    let tx = dst_x - (a * src_x - b * src_y);
+    let ty = dst_y - (b * src_x + a * src_y);
+
+    // Every crop pixel is sampled from its preimage in the source image
+    let scale = (a * a + b * b).max(f32::EPSILON);
+    let source = img.to_rgb8();
+    RgbImage::from_fn(ALIGNED_SIZE, ALIGNED_SIZE, |u, v| {
+        let (u, v) = (u as f32 - tx, v as f32 - ty);
+        let x = (a * u + b * v) / scale;
+        let y = (a * v - b * u) / scale;
+        sample_bilinear(&source, x, y)
+    })
+    .into()
+}
+
+// Black outside the image
+fn sample_bilinear(img: &RgbImage, x: f32, y: f32) -> Rgb<u8> {
+    let (width, height) = img.dimensions();
+    if x < 0.0 || y < 0.0 || x > (width - 1) as f32 || y > (height - 1) as f32 {
+        return Rgb([0, 0, 0]);
+    }
+    let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+    let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+    let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+    let mut pixel = [0u8; 3];
+    for (channel, value) in pixel.iter_mut().enumerate() {
+        let at = |x: u32, y: u32| img.get_pixel(x, y)[channel] as f32;
+        let top = at(x0, y0) * (1.0 - fx) + at(x1, y0) * fx;
+        let bottom = at(x0, y1) * (1.0 - fx) + at(x1, y1) * fx;
+        *value = (top * (1.0 - fy) + bottom * fy).round() as u8;
+    }
+    Rgb(pixel)
+}
@@ -0,0 +1,564 @@
+#[cfg(feature = "onnx")]
+use half::f16;
+use image::{DynamicImage, ImageBuffer, Rgb, RgbImage};
+#[cfg(feature = "onnx")]
+use ndarray::Axis;
+use ndarray::{Array, Ix4};
+#[cfg(feature = "coreml")]
+use ort::execution_providers::CoreMLExecutionProvider;
+#[cfg(feature = "directml")]
+use ort::execution_providers::DirectMLExecutionProvider;
+#[cfg(feature = "onnx")]
+use ort::{
+    execution_providers::ExecutionProviderDispatch,
+    inputs,
+    session::{
+        builder::{GraphOptimizationLevel, SessionBuilder},
+        Session, SessionOutputs,
+    },
+    tensor::TensorElementType,
+    value::Value,
+};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+// Face recognition runtime turning face crops into embeddings. Handlers only
+// go through this trait, so another runtime (tract, candle, a remote
+// inference service) needs an implementation of its own and a change to the
+// model construction in main, nothing else.
+pub trait EmbeddingModel: Send + Sync {
+    // Model input of a face crop, a batch of one
+    fn preprocess(&self, img: &DynamicImage) -> Result<Array<f32, Ix4>, String>;
+
+    // Embedding of a preprocessed face
+    fn infer(&self, input: Array<f32, Ix4>) -> Result<Vec<f32>, String>;
+
+    // Embeddings of several preprocessed faces, in order; runtimes that take
+    // a batch run them at once, the others one at a time
+    fn infer_batch(&self, inputs: Vec<Array<f32, Ix4>>) -> Result<Vec<Vec<f32>>, String> {
+        inputs.into_iter().map(|input| self.infer(input)).collect()
+    }
+
+    // Length of the embeddings, when the model declares it
+    fn dimension(&self) -> Option<usize>;
+
+    // How face crops of another shape are brought to the model input
+    fn resize_mode(&self) -> ResizeMode {
+        ResizeMode::Stretch
+    }
+
+    // Width and height of the model input
+    fn input_size(&self) -> (u32, u32) {
+        (112, 112)
+    }
+}
+
+// Thread pools of the ONNX Runtime sessions; None leaves the ort default of
+// one thread per core, which oversubscribes the cores shared with rayon and
+// tokio on large machines
+#[derive(Clone, Copy, Debug, Default)]
+#[cfg_attr(not(feature = "onnx"), allow(dead_code))]
+pub struct SessionThreads {
+    // Threads parallelizing a single operator
+    pub intra_op: Option<usize>,
+    // Threads running independent operators at once, with parallel execution
+    pub inter_op: Option<usize>,
+    pub parallel_execution: bool,
+}
+
+#[cfg(feature = "onnx")]
+impl SessionThreads {
+    // Session builder with these threads, the execution providers of the
+    // build and full graph optimization
+    pub fn builder(&self) -> ort::Result<SessionBuilder> {
+        let mut builder = Session::builder()?
+            .with_optimization_level(GraphOptimizationLevel::Level3)?
+            .with_execution_providers(execution_providers())?;
+        // DirectML supports neither memory patterns nor parallel execution
+        #[cfg(feature = "directml")]
+        {
+            builder = builder.with_memory_pattern(false)?;
+        }
+        if let Some(threads) = self.intra_op {
+            builder = builder.with_intra_threads(threads)?;
+        }
+        if let Some(threads) = self.inter_op {
+            builder = builder.with_inter_threads(threads)?;
+        }
+        if self.parallel_execution && !cfg!(feature = "directml") {
+            builder = builder.with_parallel_execution(true)?;
+        }
+        Ok(builder)
+    }
+}
+
+// Hardware providers compiled in, in order of preference; ONNX Runtime falls
+// back to the CPU for the machines and operators they cannot run
+#[cfg(feature = "onnx")]
+fn execution_providers() -> Vec<ExecutionProviderDispatch> {
+    vec![
+        #[cfg(feature = "coreml")]
+        CoreMLExecutionProvider::default().build(),
+        #[cfg(feature = "directml")]
+        DirectMLExecutionProvider::default().build(),
+    ]
+}
+
+// Runtime of the model file: safetensors weights run with candle (with the
+// `candle` feature), anything else is taken to be ONNX (with the `onnx`
+// feature, on by default)
+pub fn load(
+    model_path: &Path,
+    threads: SessionThreads,
+    preprocessing: Preprocessing,
+) -> Result<Arc<dyn EmbeddingModel>, Box<dyn std::error::Error>> {
+    if model_path
+        .extension()
+        .is_some_and(|extension| extension == "safetensors")
+    {
+        // arcface_torch models have a fixed input, only the resize applies
+        #[cfg(feature = "candle")]
+        return Ok(Arc::new(crate::candle_model::CandleModel::load(
+            model_path,
+            preprocessing.resize,
+        )?));
+        #[cfg(not(feature = "candle"))]
+        return Err("safetensors models need the candle feature".into());
+    }
+    #[cfg(feature = "onnx")]
+    return Ok(Arc::new(OnnxModel::load(
+        model_path,
+        threads,
+        preprocessing,
+    )?));
+    #[cfg(not(feature = "onnx"))]
+    {
+        let _ = (threads, preprocessing);
+        Err("ONNX models need the onnx feature".into())
+    }
+}
+
+// Channel order of an ONNX model input: BGR for the model zoo ArcFace, RGB for
+// the InsightFace recognition models (e.g. w600k_mbf of buffalo_s)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChannelOrder {
+    Bgr,
+    Rgb,
+}
+
+impl FromStr for ChannelOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bgr" => Ok(ChannelOrder::Bgr),
+            "rgb" => Ok(ChannelOrder::Rgb),
+            _ => Err(format!(
+                "unknown channel order '{}', expected bgr or rgb",
+                s
+            )),
+        }
+    }
+}
+
+// How an image of another aspect ratio than the model input is resized to it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResizeMode {
+    // Both sides scaled independently, distorting the face
+    Stretch,
+    // Scaled to fit, the rest padded with black
+    Letterbox,
+    // Scaled to cover, the overflow of the longer side cut off
+    CenterCrop,
+}
+
+impl FromStr for ResizeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stretch" => Ok(ResizeMode::Stretch),
+            "letterbox" => Ok(ResizeMode::Letterbox),
+            "center_crop" => Ok(ResizeMode::CenterCrop),
+            _ => Err(format!(
+                "unknown resize mode '{}', expected stretch, letterbox or center_crop",
+                s
+            )),
+        }
+    }
+}
+
+// Memory layout of an ONNX model input: channels first (PyTorch exports) or
+// last (TensorFlow and Keras exports)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Layout {
+    Nchw,
+    Nhwc,
+}
+
+impl FromStr for Layout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nchw" => Ok(Layout::Nchw),
+            "nhwc" => Ok(Layout::Nhwc),
+            _ => Err(format!("unknown layout '{}', expected nchw or nhwc", s)),
+        }
+    }
+}
+
+// How face crops become the input of an ONNX model: each channel value v is
+// fed as (v - mean) / std. The defaults are those of the ArcFace exports,
+// which scale the channels to [-1, 1].
+#[derive(Clone, Copy, Debug)]
+pub struct Preprocessing {
+    pub channels: ChannelOrder,
+    pub mean: f32,
+    pub std: f32,
+    // None reads it from the input shape: the dimension of size 3
+    pub layout: Option<Layout>,
+    pub resize: ResizeMode,
+}
+
+impl Preprocessing {
+    pub fn arcface(channels: ChannelOrder) -> Self {
+        Self {
+            channels,
+            mean: 127.5,
+            std: 128.0,
+            layout: None,
+            resize: ResizeMode::Stretch,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.mean.is_finite() || !self.std.is_finite() || self.std == 0.0 {
+            return Err("MODEL_INPUT_MEAN must be finite and MODEL_INPUT_STD non-zero".to_string());
+        }
+        Ok(())
+    }
+}
+
+pub fn resize(img: &DynamicImage, width: u32, height: u32, mode: ResizeMode) -> DynamicImage {
+    let filter = image::imageops::FilterType::Triangle;
+    match mode {
+        ResizeMode::Stretch => img.resize_exact(width, height, filter),
+        ResizeMode::Letterbox => {
+            let fitted = img.resize(width, height, filter).to_rgb8();
+            let mut padded = RgbImage::new(width, height);
+            image::imageops::overlay(
+                &mut padded,
+                &fitted,
+                ((width - fitted.width()) / 2) as i64,
+                ((height - fitted.height()) / 2) as i64,
+            );
+            DynamicImage::ImageRgb8(padded)
+        }
+        ResizeMode::CenterCrop => img.resize_to_fill(width, height, filter),
+    }
+}
+
+// Model input of a face crop, 112x112 for the stock ArcFace models
+pub fn model_input(
+    img: &DynamicImage,
+    width: u32,
+    height: u32,
+    preprocessing: &Preprocessing,
+) -> Array<f32, Ix4> {
+    let resized_img = resize(img, width, height, preprocessing.resize);
+    let rgb_img: ImageBuffer<Rgb<u8>, Vec<u8>> = resized_img.to_rgb8();
+
+    let (height, width) = (height as usize, width as usize);
+    let layout = preprocessing.layout.unwrap_or(Layout::Nchw);
+    let mut input_tensor = match layout {
+        Layout::Nchw => Array::zeros((1, 3, height, width)),
+        Layout::Nhwc => Array::zeros((1, height, width, 3)),
+    };
+
+    for (x, y, pixel) in rgb_img.enumerate_pixels() {
+        let r = pixel[0] as f32;
+        let g = pixel[1] as f32;
+        let b = pixel[2] as f32;
+
+        let (first, last) = match preprocessing.channels {
+            ChannelOrder::Bgr => (b, r),
+            ChannelOrder::Rgb => (r, b),
+        };
+        let (x, y) = (x as usize, y as usize);
+        for (channel, value) in [first, g, last].into_iter().enumerate() {
+            let index = match layout {
+                Layout::Nchw => [0, channel, y, x],
+                Layout::Nhwc => [0, y, x, channel],
+            };
+            input_tensor[index] = (value - preprocessing.mean) / preprocessing.std;
+        }
+    }
+
+    input_tensor
+}
+
+// ArcFace ONNX model run with ort, the default runtime. Quantized variants
+// load as well: int8 models usually keep float32 inputs and outputs, fp16
+// models get their tensors converted from and to float32 here.
+#[cfg(feature = "onnx")]
+pub struct OnnxModel {
+    session: Session,
+    input_width: u32,
+    input_height: u32,
+    preprocessing: Preprocessing,
+    half_input: bool,
+    half_output: bool,
+    // The batch dimension of the input is dynamic, so faces can be stacked
+    dynamic_batch: bool,
+}
+
+// Whether a tensor of the model is float16 (true) or float32 (false)
+#[cfg(feature = "onnx")]
+fn is_half(name: &str, element: Option<TensorElementType>) -> Result<bool, String> {
+    match element {
+        Some(TensorElementType::Float32) => Ok(false),
+        Some(TensorElementType::Float16) => Ok(true),
+        other => Err(format!(
+            "unsupported {} type {:?}, expected float32 or float16",
+            name, other
+        )),
+    }
+}
+
+#[cfg(feature = "onnx")]
+impl OnnxModel {
+    pub fn load(
+        model_path: &Path,
+        threads: SessionThreads,
+        mut preprocessing: Preprocessing,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let session = threads.builder()?.commit_from_file(model_path)?;
+
+        let input = session.inputs.first().ok_or("model has no input")?;
+        let output = session.outputs.first().ok_or("model has no output")?;
+        let input_type = input.input_type.tensor_type();
+        let output_type = output.output_type.tensor_type();
+        // Dynamic dimensions fall back to 112x112, the ArcFace input size
+        let dims = input
+            .input_type
+            .tensor_dimensions()
+            .cloned()
+            .unwrap_or_default();
+        let dim = |i: usize| {
+            dims.get(i)
+                .copied()
+                .filter(|&d| d > 0)
+                .map_or(112, |d| d as u32)
+        };
+        let layout = *preprocessing.layout.get_or_insert(match dims.as_slice() {
+            [_, _, _, 3] => Layout::Nhwc,
+            _ => Layout::Nchw,
+        });
+        check_input(&dims, layout)?;
+        check_output(output.output_type.tensor_dimensions().map(Vec::as_slice))?;
+        tracing::info!(
+            input = %input.name,
+            ?input_type,
+            input_dims = ?dims,
+            ?output_type,
+            ?preprocessing,
+            "Embedding model input and output"
+        );
+
+        let (input_width, input_height) = match layout {
+            Layout::Nchw => (dim(3), dim(2)),
+            Layout::Nhwc => (dim(2), dim(1)),
+        };
+        Ok(Self {
+            input_width,
+            input_height,
+            preprocessing,
+            half_input: is_half("input", input_type)?,
+            half_output: is_half("output", output_type)?,
+            dynamic_batch: dims[0] <= 0,
+            session,
+        })
+    }
+}
+
+#[cfg(feature = "onnx")]
+impl EmbeddingModel for OnnxModel {
+    fn preprocess(&self, img: &DynamicImage) -> Result<Array<f32, Ix4>, String> {
+        Ok(model_input(
+            img,
+            self.input_width,
+            self.input_height,
+            &self.preprocessing,
+        ))
+    }
+
+    fn infer(&self, input: Array<f32, Ix4>) -> Result<Vec<f32>, String> {
+        let shape: Vec<usize> = input.shape().to_vec();
+        let input_value = if self.half_input {
+            let raw_vec: Vec<f16> = input.iter().map(|&value| f16::from_f32(value)).collect();
+            Value::from_array((shape, raw_vec)).map(|value| value.into_dyn())
+        } else {
+            Value::from_array((shape, input.into_raw_vec())).map(|value| value.into_dyn())
+        }
+        .map_err(|e| format!("failed to create input value from array: {}", e))?;
+        let session_inputs =
+            inputs![input_value].map_err(|e| format!("failed to create session inputs: {}", e))?;
+        let outputs: SessionOutputs = self
+            .session
+            .run(session_inputs)
+            .map_err(|e| format!("ONNX inference failed: {}", e))?;
+
+        if outputs.len() == 0 {
+            return Err("ONNX output is empty".to_string());
+        }
+        let extraction_failed =
+            |e: ort::Error| format!("failed to extract tensor from ONNX output: {}", e);
+        if self.half_output {
+            let embedding_tensor = outputs[0]
+                .try_extract_tensor::<f16>()
+                .map_err(extraction_failed)?;
+            return Ok(embedding_tensor
+                .view()
+                .iter()
+                .map(|value| value.to_f32())
+                .collect());
+        }
+        let embedding_tensor = outputs[0]
+            .try_extract_tensor::<f32>()
+            .map_err(extraction_failed)?;
+        Ok(embedding_tensor.view().iter().cloned().collect())
+    }
+
+    fn infer_batch(&self, inputs: Vec<Array<f32, Ix4>>) -> Result<Vec<Vec<f32>>, String> {
+        if !self.dynamic_batch || inputs.len() < 2 {
+            return inputs.into_iter().map(|input| self.infer(input)).collect();
+        }
+        let count = inputs.len();
+        let views: Vec<_> = inputs.iter().map(|input| input.view()).collect();
+        let batch = ndarray::concatenate(Axis(0), &views)
+            .map_err(|e| format!("failed to stack the batch: {}", e))?;
+        let values = self.infer(batch)?;
+        if values.is_empty() || values.len() % count != 0 {
+            return Err(format!(
+                "ONNX output of {} values for a batch of {}",
+                values.len(),
+                count
+            ));
+        }
+        Ok(values
+            .chunks_exact(values.len() / count)
+            .map(<[f32]>::to_vec)
+            .collect())
+    }
+
+    fn dimension(&self) -> Option<usize> {
+        self.session
+            .outputs
+            .first()
+            .and_then(|output| output.output_type.tensor_dimensions())
+            .and_then(|dims| dims.last().copied())
+            .filter(|&d| d > 0)
+            .map(|d| d as usize)
+    }
+
+    fn resize_mode(&self) -> ResizeMode {
+        self.preprocessing.resize
+    }
+
+    fn input_size(&self) -> (u32, u32) {
+        (self.input_width, self.input_height)
+    }
+}
+
+// The input must be a batch of one RGB image in the configured layout;
+// dynamic (negative) dimensions accept anything
+#[cfg(feature = "onnx")]
+fn check_input(dims: &[i64], layout: Layout) -> Result<(), String> {
+    if dims.len() != 4 {
+        return Err(format!(
+            "model input has shape {:?}, expected 4 dimensions (NCHW or NHWC)",
+            dims
+        ));
+    }
+    let fixed = |i: usize, expected: i64| dims[i] <= 0 || dims[i] == expected;
+    if !fixed(0, 1) {
+        return Err(format!(
+            "model input has shape {:?}, expected a batch size of 1 or dynamic",
+            dims
+        ));
+    }
+    let channel = match layout {
+        Layout::Nchw => 1,
+        Layout::Nhwc => 3,
+    };
+    if !fixed(channel, 3) {
+        return Err(format!(
+            "model input has shape {:?}, without 3 channels at position {} as MODEL_INPUT_LAYOUT={:?} expects",
+            dims, channel, layout
+        ));
+    }
+    Ok(())
+}
+
+// The output must be one vector per image, e.g. [1, 512], not a feature map
+#[cfg(feature = "onnx")]
+fn check_output(dims: Option<&[i64]>) -> Result<(), String> {
+    let Some(dims) = dims else {
+        return Err("model output is not a tensor".to_string());
+    };
+    let spread = dims.iter().skip(1).filter(|&&d| d != 1).count();
+    if dims.is_empty() || spread > 1 {
+        return Err(format!(
+            "model output has shape {:?}, expected an embedding such as [1, 512]",
+            dims
+        ));
+    }
+    Ok(())
+}
+
+// Stands in for the model in tests, which then run without the 250MB ONNX
+// file
+#[cfg(feature = "mock-inference")]
+pub struct MockModel {
+    pub dimension: usize,
+}
+
+#[cfg(feature = "mock-inference")]
+impl EmbeddingModel for MockModel {
+    fn preprocess(&self, img: &DynamicImage) -> Result<Array<f32, Ix4>, String> {
+        Ok(model_input(
+            img,
+            112,
+            112,
+            &Preprocessing::arcface(ChannelOrder::Bgr),
+        ))
+    }
+
+    // Unit vector seeded by a hash of the model input: the same image always
+    // embeds the same, two different images almost orthogonally
+    fn infer(&self, input: Array<f32, Ix4>) -> Result<Vec<f32>, String> {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        use sha2::{Digest, Sha256};
+
+        let mut hasher = Sha256::new();
+        for value in input.iter() {
+            hasher.update(value.to_le_bytes());
+        }
+        let mut rng = StdRng::from_seed(hasher.finalize().into());
+        let embedding: Vec<f32> = (0..self.dimension)
+            .map(|_| rng.gen_range(-1.0f32..1.0))
+            .collect();
+        let norm = embedding
+            .iter()
+            .map(|value| value * value)
+            .sum::<f32>()
+            .sqrt();
+        Ok(embedding.into_iter().map(|value| value / norm).collect())
+    }
+
+    fn dimension(&self) -> Option<usize> {
+        Some(self.dimension)
+    }
+}
@@ -0,0 +1,106 @@
+use image::DynamicImage;
+use ndarray::Array;
+use std::path::Path;
+
+pub use crate::api::{Emotion, EmotionScore};
+use crate::embedder::SessionThreads;
+use crate::onnx::{self, ImageModel};
+
+// Classes of the FER+ emotion model, in its output order
+pub const DEFAULT_LABELS: [&str; 8] = [
+    "neutral",
+    "happiness",
+    "surprise",
+    "sadness",
+    "anger",
+    "disgust",
+    "fear",
+    "contempt",
+];
+
+// Emotion classifier exported to ONNX in the layout of the FER+ model: a
+// [1, C, H, W] input with raw 0-255 values, grayscale when C is 1 and RGB
+// when it is 3, and per-class logits as output
+pub struct EmotionModel {
+    model: ImageModel,
+    input_width: u32,
+    input_height: u32,
+    grayscale: bool,
+    labels: Vec<String>,
+}
+
+impl EmotionModel {
+    pub fn load(
+        model_path: &Path,
+        labels: Vec<String>,
+        threads: SessionThreads,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let model = ImageModel::load(model_path, threads)?;
+
+        // Dynamic dimensions fall back to 64x64 grayscale, the FER+ input
+        Ok(Self {
+            grayscale: model.dim(1, 1) == 1,
+            input_height: model.dim(2, 64),
+            input_width: model.dim(3, 64),
+            labels,
+            model,
+        })
+    }
+
+    pub fn estimate(&self, img: &DynamicImage) -> Result<Emotion, Box<dyn std::error::Error>> {
+        let resized = img.resize_exact(
+            self.input_width,
+            self.input_height,
+            image::imageops::FilterType::Triangle,
+        );
+
+        let channels = if self.grayscale { 1 } else { 3 };
+        let mut input = Array::zeros((
+            1,
+            channels,
+            self.input_height as usize,
+            self.input_width as usize,
+        ));
+        if self.grayscale {
+            for (x, y, pixel) in resized.to_luma8().enumerate_pixels() {
+                input[[0, 0, y as usize, x as usize]] = pixel[0] as f32;
+            }
+        } else {
+            for (x, y, pixel) in resized.to_rgb8().enumerate_pixels() {
+                for channel in 0..3 {
+                    input[[0, channel, y as usize, x as usize]] = pixel[channel] as f32;
+                }
+            }
+        }
+
+        let logits = self.model.run_first(input)?;
+        if logits.len() != self.labels.len() {
+            return Err(format!(
+                "expected {} emotion classes, got {}",
+                self.labels.len(),
+                logits.len()
+            )
+            .into());
+        }
+
+        let scores: Vec<EmotionScore> = self
+            .labels
+            .iter()
+            .zip(onnx::softmax(&logits))
+            .map(|(label, probability)| EmotionScore {
+                emotion: label.clone(),
+                probability,
+            })
+            .collect();
+        let best = scores
+            .iter()
+            .max_by(|a, b| a.probability.total_cmp(&b.probability))
+            .ok_or("emotion model without classes")?;
+
+        Ok(Emotion {
+            emotion: best.emotion.clone(),
+            confidence: best.probability,
+            scores,
+        })
+    }
+}
@@ -0,0 +1,92 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Nonce,
+};
+use base64::{engine::general_purpose, Engine as _};
+use sqlx::{postgres::PgRow, Row};
+use std::sync::OnceLock;
+
+// Layout of a sealed vector: version, 12-byte nonce, then the AES-256-GCM
+// ciphertext and tag of the little-endian f32 values
+const SEALED_VERSION: u8 = 1;
+const NONCE_LEN: usize = 12;
+
+// Key of EMBEDDING_ENCRYPTION_KEY, set once at startup; without one vectors
+// are stored in the clear
+static CIPHER: OnceLock<Aes256Gcm> = OnceLock::new();
+
+// Takes the base64 of a 32-byte key
+pub fn init(key: &str) -> Result<(), String> {
+    let key = general_purpose::STANDARD
+        .decode(key.trim())
+        .map_err(|e| format!("invalid EMBEDDING_ENCRYPTION_KEY: {}", e))?;
+    let cipher = Aes256Gcm::new_from_slice(&key)
+        .map_err(|_| "EMBEDDING_ENCRYPTION_KEY must be 32 bytes".to_string())?;
+    CIPHER
+        .set(cipher)
+        .map_err(|_| "embedding encryption already initialized".to_string())
+}
+
+pub fn enabled() -> bool {
+    CIPHER.get().is_some()
+}
+
+// Sealed vector, None without a key
+pub fn seal(embedding: &[f32]) -> Option<Result<Vec<u8>, String>> {
+    let cipher = CIPHER.get()?;
+    let plaintext: Vec<u8> = embedding.iter().flat_map(|v| v.to_le_bytes()).collect();
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    Some(
+        cipher
+            .encrypt(&nonce, plaintext.as_slice())
+            .map(|ciphertext| {
+                let mut sealed = Vec::with_capacity(1 + NONCE_LEN + ciphertext.len());
+                sealed.push(SEALED_VERSION);
+                sealed.extend_from_slice(&nonce);
+                sealed.extend_from_slice(&ciphertext);
+                sealed
+            })
+            .map_err(|_| "failed to seal embedding".to_string()),
+    )
+}
+
+// Vector of a sealed one; fails without the key it was sealed with
+pub fn open(sealed: &[u8]) -> Result<Vec<f32>, String> {
+    let cipher = CIPHER
+        .get()
+        .ok_or("embedding is encrypted, set EMBEDDING_ENCRYPTION_KEY")?;
+    match sealed.split_first() {
+        Some((&SEALED_VERSION, rest)) if rest.len() >= NONCE_LEN => {
+            let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+            let plaintext = cipher
+                .decrypt(Nonce::from_slice(nonce), ciphertext)
+                .map_err(|_| "failed to decrypt embedding, wrong key or corrupted value")?;
+            Ok(plaintext
+                .chunks_exact(4)
+                .map(|value| f32::from_le_bytes([value[0], value[1], value[2], value[3]]))
+                .collect())
+        }
+        _ => Err("unsupported sealed embedding".to_string()),
+    }
+}
+
+// Values of the `embeddings` and `embeddings_sealed` columns of the targets
+// table for a vector: one of them is set, depending on the key
+pub fn columns(embedding: &[f32]) -> Result<(Option<&[f32]>, Option<Vec<u8>>), sqlx::Error> {
+    match seal(embedding) {
+        Some(sealed) => Ok((
+            None,
+            Some(sealed.map_err(|e| sqlx::Error::Encode(e.into()))?),
+        )),
+        None => Ok((Some(embedding), None)),
+    }
+}
+
+// Vector of a targets row selected with both columns; rows written before
+// the key was set stay readable until `owlfacerec encrypt` seals them
+pub fn from_row(row: &PgRow) -> Result<Vec<f32>, sqlx::Error> {
+    match row.try_get::<Option<Vec<u8>>, _>("embeddings_sealed")? {
+        Some(sealed) => open(&sealed).map_err(|e| sqlx::Error::Decode(e.into())),
+        None => row.try_get("embeddings"),
+    }
+}
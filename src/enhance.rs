@@ -0,0 +1,140 @@
+use image::{DynamicImage, ImageBuffer, Rgb, RgbImage};
+use ndarray::Array;
+use std::path::Path;
+
+pub use crate::api::EnhanceOptions;
+use crate::embedder::SessionThreads;
+use crate::onnx::ImageModel;
+
+impl EnhanceOptions {
+    pub fn is_enabled(&self) -> bool {
+        self.equalize || self.super_resolution
+    }
+}
+
+fn rgb_to_ycbcr(pixel: &Rgb<u8>) -> (f32, f32, f32) {
+    let (r, g, b) = (pixel[0] as f32, pixel[1] as f32, pixel[2] as f32);
+    let y = 0.299 * r + 0.587 * g + 0.114 * b;
+    let cb = 128.0 - 0.168736 * r - 0.331264 * g + 0.5 * b;
+    let cr = 128.0 + 0.5 * r - 0.418688 * g - 0.081312 * b;
+    (y, cb, cr)
+}
+
+fn ycbcr_to_rgb(y: f32, cb: f32, cr: f32) -> Rgb<u8> {
+    let r = y + 1.402 * (cr - 128.0);
+    let g = y - 0.344136 * (cb - 128.0) - 0.714136 * (cr - 128.0);
+    let b = y + 1.772 * (cb - 128.0);
+    Rgb([
+        r.round().clamp(0.0, 255.0) as u8,
+        g.round().clamp(0.0, 255.0) as u8,
+        b.round().clamp(0.0, 255.0) as u8,
+    ])
+}
+
+// Histogram equalization of the luma channel, leaving chroma untouched so
+// colors are not shifted
+pub fn equalize_histogram(img: &DynamicImage) -> DynamicImage {
+    let rgb = img.to_rgb8();
+    let ycbcr: Vec<(f32, f32, f32)> = rgb.pixels().map(rgb_to_ycbcr).collect();
+    if ycbcr.is_empty() {
+        return img.clone();
+    }
+
+    let mut histogram = [0u32; 256];
+    for &(y, _, _) in &ycbcr {
+        histogram[y.round().clamp(0.0, 255.0) as usize] += 1;
+    }
+
+    let mut cdf = [0u32; 256];
+    let mut total = 0;
+    for (value, count) in histogram.iter().enumerate() {
+        total += count;
+        cdf[value] = total;
+    }
+
+    let cdf_min = cdf.iter().copied().find(|&c| c > 0).unwrap_or(0);
+    let range = (total - cdf_min).max(1) as f32;
+    let lut: Vec<f32> = cdf
+        .iter()
+        .map(|&c| (c.saturating_sub(cdf_min) as f32 / range) * 255.0)
+        .collect();
+
+    let (width, height) = rgb.dimensions();
+    let mut equalized: RgbImage = ImageBuffer::new(width, height);
+    for (pixel, &(y, cb, cr)) in equalized.pixels_mut().zip(&ycbcr) {
+        *pixel = ycbcr_to_rgb(lut[y.round().clamp(0.0, 255.0) as usize], cb, cr);
+    }
+
+    DynamicImage::ImageRgb8(equalized)
+}
+
+// Single-channel super-resolution model (e.g. the ONNX model zoo
+// `super-resolution-10.onnx`): the luma channel is upscaled by the model and
+// the chroma channels are upscaled with a regular bicubic filter
+pub struct SuperResolution {
+    model: ImageModel,
+    input_width: u32,
+    input_height: u32,
+}
+
+impl SuperResolution {
+    pub fn load(
+        model_path: &Path,
+        threads: SessionThreads,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let model = ImageModel::load(model_path, threads)?;
+
+        // Expected input layout is [1, 1, H, W]; dynamic dimensions fall back to 224
+        Ok(Self {
+            input_height: model.dim(2, 224),
+            input_width: model.dim(3, 224),
+            model,
+        })
+    }
+
+    pub fn upscale(&self, img: &DynamicImage) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+        let resized = img
+            .resize_exact(
+                self.input_width,
+                self.input_height,
+                image::imageops::FilterType::CatmullRom,
+            )
+            .to_rgb8();
+
+        let mut luma = Array::zeros((1, 1, self.input_height as usize, self.input_width as usize));
+        for (x, y, pixel) in resized.enumerate_pixels() {
+            luma[[0, 0, y as usize, x as usize]] = rgb_to_ycbcr(pixel).0 / 255.0;
+        }
+
+        let output = self
+            .model
+            .run(luma)?
+            .into_iter()
+            .next()
+            .ok_or("super-resolution model without outputs")?;
+        if output.shape.len() != 4 {
+            return Err(format!(
+                "unexpected super-resolution output shape {:?}",
+                output.shape
+            )
+            .into());
+        }
+        let (out_height, out_width) = (output.shape[2] as u32, output.shape[3] as u32);
+
+        // Chroma is upscaled conventionally and merged with the model's luma
+        let chroma = image::imageops::resize(
+            &resized,
+            out_width,
+            out_height,
+            image::imageops::FilterType::CatmullRom,
+        );
+        let mut upscaled: RgbImage = ImageBuffer::new(out_width, out_height);
+        for (x, y, pixel) in upscaled.enumerate_pixels_mut() {
+            let (_, cb, cr) = rgb_to_ycbcr(chroma.get_pixel(x, y));
+            let y_value = output.values[(y * out_width + x) as usize] * 255.0;
+            *pixel = ycbcr_to_rgb(y_value, cb, cr);
+        }
+
+        Ok(DynamicImage::ImageRgb8(upscaled))
+    }
+}
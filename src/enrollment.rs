@@ -0,0 +1,456 @@
+use axum::{
+    extract::{Extension, Path, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+use uuid::Uuid;
+
+use crate::audit;
+use crate::collections::DEFAULT_COLLECTION;
+use crate::detect::FaceCrop;
+use crate::enhance::EnhanceOptions;
+use crate::handlers::{
+    self, Analysis, Estimates, ImageInput, RegisterError, RegisterMode, RegisterPayload,
+};
+use crate::store::{cosine_similarity, Metadata};
+use crate::tenant::Tenant;
+use crate::util;
+use crate::AppState;
+
+// Embeddings stored at commit unless the session asks otherwise
+const DEFAULT_KEEP: usize = 3;
+const MAX_KEEP: usize = 10;
+// Photos submitted to one session, accepted or not
+const MAX_PHOTOS: usize = 20;
+// Sessions idle this long are forgotten
+const IDLE_SECS: i64 = 900;
+// Open sessions of this instance
+const MAX_SESSIONS: usize = 1024;
+// Photos at least this similar to a kept one add nothing, e.g. the same photo
+// sent twice
+const NEAR_DUPLICATE: f32 = 0.98;
+
+// Define the request payload for POST /enrollments
+#[derive(Deserialize)]
+pub struct EnrollmentPayload {
+    pub target_uuid: Uuid,
+    pub origin: String,
+    #[serde(default)]
+    pub metadata: Metadata,
+    // Default collection if unset
+    pub collection: Option<String>,
+    // Best photos stored at commit
+    pub keep: Option<usize>,
+    // As for /register/, applied at commit
+    pub expires_at: Option<i64>,
+    #[serde(default)]
+    pub mode: RegisterMode,
+    pub reject_if_similar_above: Option<f32>,
+}
+
+// Define the request payload for POST /enrollments/:id/photos
+#[derive(Deserialize)]
+pub struct PhotoPayload {
+    pub image_base64: String,
+}
+
+// Checks of a submitted photo; only accepted photos can be stored
+#[derive(Serialize, Clone)]
+pub struct PhotoFeedback {
+    index: usize,
+    accepted: bool,
+    // Gates the photo failed: quality checks, "liveness" or "pose"
+    failed: Vec<&'static str>,
+    // Higher is better; ranks the accepted photos at commit
+    score: f32,
+    #[serde(flatten)]
+    analysis: Analysis,
+}
+
+// Define the response for /enrollments and /enrollments/:id
+#[derive(Serialize)]
+pub struct SessionView {
+    id: Uuid,
+    target_uuid: Uuid,
+    collection: String,
+    keep: usize,
+    photos: Vec<PhotoFeedback>,
+    // Unix time (seconds) the session is forgotten unless used again
+    expires_at: i64,
+}
+
+// Define the response for POST /enrollments/:id/commit
+#[derive(Serialize)]
+pub struct CommitResponse {
+    target_uuid: Uuid,
+    collection: String,
+    // Indexes of the stored photos, best first
+    stored: Vec<usize>,
+}
+
+struct Photo {
+    feedback: PhotoFeedback,
+    embedding: Vec<f32>,
+    // Kept for crop storage; stripped from the feedback
+    crop: Option<FaceCrop>,
+}
+
+struct Session {
+    tenant: String,
+    collection: String,
+    payload: EnrollmentPayload,
+    keep: usize,
+    photos: Vec<Photo>,
+    touched_at: i64,
+}
+
+impl Session {
+    fn view(&self, id: Uuid) -> SessionView {
+        SessionView {
+            id,
+            target_uuid: self.payload.target_uuid,
+            collection: self.collection.clone(),
+            keep: self.keep,
+            photos: self
+                .photos
+                .iter()
+                .map(|photo| photo.feedback.clone())
+                .collect(),
+            expires_at: self.touched_at + IDLE_SECS,
+        }
+    }
+
+    // Accepted photos, best first, without near duplicates of a better one
+    fn best(&self) -> Vec<&Photo> {
+        let mut accepted: Vec<&Photo> = self
+            .photos
+            .iter()
+            .filter(|photo| photo.feedback.accepted)
+            .collect();
+        accepted.sort_by(|a, b| b.feedback.score.total_cmp(&a.feedback.score));
+        let mut kept: Vec<&Photo> = Vec::with_capacity(self.keep);
+        for photo in accepted {
+            if kept.len() == self.keep {
+                break;
+            }
+            if kept
+                .iter()
+                .all(|other| cosine_similarity(&other.embedding, &photo.embedding) < NEAR_DUPLICATE)
+            {
+                kept.push(photo);
+            }
+        }
+        kept
+    }
+}
+
+// Multi-photo enrollments in progress. Sessions live in the memory of the
+// instance that opened them, like jobs, and do not survive a restart.
+#[derive(Default)]
+pub struct Enrollments {
+    sessions: Mutex<HashMap<Uuid, Session>>,
+}
+
+impl Enrollments {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<Uuid, Session>> {
+        self.sessions.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Runs `f` on a live session of the tenant
+    fn with<T>(&self, tenant: &str, id: &Uuid, f: impl FnOnce(&mut Session) -> T) -> Option<T> {
+        let mut sessions = self.lock();
+        let now = util::unix_now();
+        sessions
+            .get_mut(id)
+            .filter(|session| session.tenant == tenant && now - session.touched_at < IDLE_SECS)
+            .map(f)
+    }
+
+    fn take(&self, tenant: &str, id: &Uuid) -> Option<Session> {
+        let mut sessions = self.lock();
+        let now = util::unix_now();
+        match sessions.get(id) {
+            Some(session) if session.tenant == tenant && now - session.touched_at < IDLE_SECS => {
+                sessions.remove(id)
+            }
+            _ => None,
+        }
+    }
+}
+
+// Handler for POST /enrollments - opens a session collecting photos of one
+// target; nothing is stored until it is committed
+pub async fn open_session(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Json(payload): Json<EnrollmentPayload>,
+) -> Result<(StatusCode, Json<SessionView>), StatusCode> {
+    let keep = payload.keep.unwrap_or(DEFAULT_KEEP);
+    if payload.target_uuid.is_nil()
+        || payload.origin.trim().is_empty()
+        || !(1..=MAX_KEEP).contains(&keep)
+        || payload
+            .reject_if_similar_above
+            .is_some_and(|threshold| !(-1.0..=1.0).contains(&threshold))
+        || payload
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= util::unix_now())
+    {
+        tracing::warn!(uuid = %payload.target_uuid, keep, "Received invalid enrollment session request");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let collection = payload
+        .collection
+        .clone()
+        .unwrap_or_else(|| DEFAULT_COLLECTION.to_string());
+    if state.collections.get(tenant.id(), &collection).is_none() {
+        tracing::warn!(%collection, "Received enrollment session for unknown collection");
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let id = Uuid::new_v4();
+    let now = util::unix_now();
+    let session = Session {
+        tenant: tenant.id().to_string(),
+        collection,
+        payload,
+        keep,
+        photos: Vec::new(),
+        touched_at: now,
+    };
+    let view = session.view(id);
+    let mut sessions = state.enrollments.lock();
+    sessions.retain(|_, session| now - session.touched_at < IDLE_SECS);
+    if sessions.len() >= MAX_SESSIONS {
+        tracing::warn!(open = sessions.len(), "Too many open enrollment sessions");
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    }
+    sessions.insert(id, session);
+    tracing::info!(session = %id, uuid = %view.target_uuid, "Enrollment session opened");
+    Ok((StatusCode::CREATED, Json(view)))
+}
+
+// Handler for GET /enrollments/:id - the session with the feedback of its photos
+pub async fn get_session(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<SessionView>, StatusCode> {
+    state
+        .enrollments
+        .with(tenant.id(), &id, |session| Json(session.view(id)))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+// Handler for POST /enrollments/:id/photos - embeds the largest face of a
+// photo and answers with its quality, liveness and pose checks, so the client
+// can ask for another one right away
+pub async fn add_photo(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(id): Path<Uuid>,
+    Json(payload): Json<PhotoPayload>,
+) -> Result<Json<PhotoFeedback>, StatusCode> {
+    let image = ImageInput::Base64(&payload.image_base64);
+    if image.is_empty() {
+        tracing::warn!(session = %id, "Received enrollment photo with empty image");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    // Checked before running inference, and again when adding the photo
+    let full = state
+        .enrollments
+        .with(tenant.id(), &id, |session| {
+            session.photos.len() >= MAX_PHOTOS
+        })
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if full {
+        tracing::warn!(session = %id, "Enrollment session has too many photos");
+        return Err(StatusCode::CONFLICT);
+    }
+
+    let estimates = Estimates {
+        quality: true,
+        // Stored at commit when crop storage is configured
+        crop: state.crops.is_some(),
+        ..Estimates::default()
+    };
+    let (embedding, mut analysis) =
+        handlers::get_embedding_analyzed(image, &state, EnhanceOptions::default(), estimates)
+            .await?;
+    let failed = failures(&state, &analysis);
+    let score = score(&analysis);
+    let crop = analysis.crop.take();
+
+    state
+        .enrollments
+        .with(tenant.id(), &id, |session| {
+            if session.photos.len() >= MAX_PHOTOS {
+                return Err(StatusCode::CONFLICT);
+            }
+            let feedback = PhotoFeedback {
+                index: session.photos.len(),
+                accepted: failed.is_empty(),
+                failed,
+                score,
+                analysis,
+            };
+            session.photos.push(Photo {
+                feedback: feedback.clone(),
+                embedding,
+                crop,
+            });
+            session.touched_at = util::unix_now();
+            tracing::info!(session = %id, index = feedback.index, accepted = feedback.accepted, failed = ?feedback.failed, "Enrollment photo checked");
+            Ok(Json(feedback))
+        })
+        .ok_or(StatusCode::NOT_FOUND)?
+}
+
+// Handler for POST /enrollments/:id/commit - stores the best accepted photos
+// under the target and closes the session. A failed commit leaves the
+// session open, so it can be retried or completed with more photos.
+pub async fn commit_session(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(id): Path<Uuid>,
+) -> Result<(StatusCode, Extension<audit::Summary>, Json<CommitResponse>), RegisterError> {
+    let start = Instant::now();
+    let session = state
+        .enrollments
+        .take(tenant.id(), &id)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let outcome = commit(&state, &tenant, &session, start).await;
+    match outcome {
+        Ok((stored, summary)) => {
+            tracing::info!(session = %id, uuid = %session.payload.target_uuid, stored = ?stored, "Enrollment session committed");
+            Ok((
+                StatusCode::CREATED,
+                Extension(audit::Summary(summary)),
+                Json(CommitResponse {
+                    target_uuid: session.payload.target_uuid,
+                    collection: session.collection,
+                    stored,
+                }),
+            ))
+        }
+        Err(e) => {
+            state.enrollments.lock().insert(id, session);
+            Err(e)
+        }
+    }
+}
+
+async fn commit(
+    state: &AppState,
+    tenant: &Tenant,
+    session: &Session,
+    start: Instant,
+) -> Result<(Vec<usize>, serde_json::Value), RegisterError> {
+    let best = session.best();
+    if best.is_empty() {
+        tracing::warn!(uuid = %session.payload.target_uuid, photos = session.photos.len(), "No accepted photo to commit");
+        return Err(StatusCode::UNPROCESSABLE_ENTITY.into());
+    }
+    let Some(collection) = state.collections.get(tenant.id(), &session.collection) else {
+        tracing::warn!(collection = %session.collection, "Enrollment session collection no longer exists");
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+    let used = state
+        .collections
+        .origin_count(tenant.id(), &session.payload.origin);
+    if state
+        .quotas
+        .would_exceed(&session.payload.origin, used, best.len())
+    {
+        tracing::warn!(uuid = %session.payload.target_uuid, origin = %session.payload.origin, used, photos = best.len(), "Origin quota exceeded by the photos, rejecting enrollment");
+        return Err(StatusCode::INSUFFICIENT_STORAGE.into());
+    }
+
+    let payload = RegisterPayload {
+        target_uuid: session.payload.target_uuid,
+        image_base64: String::new(),
+        embedding: None,
+        origin: session.payload.origin.clone(),
+        metadata: session.payload.metadata.clone(),
+        register_all_faces: false,
+        face_index: None,
+        return_crops: false,
+        return_embedding: false,
+        expires_at: session.payload.expires_at,
+        mode: session.payload.mode,
+        reject_if_similar_above: session.payload.reject_if_similar_above,
+    };
+    let faces = best
+        .iter()
+        .map(|photo| {
+            let mut analysis = photo.feedback.analysis.clone();
+            analysis.crop = photo.crop.clone();
+            (payload.target_uuid, photo.embedding.clone(), analysis)
+        })
+        .collect();
+    let response = handlers::register_faces(
+        state,
+        tenant,
+        &session.collection,
+        &collection,
+        &payload,
+        faces,
+        start,
+    )
+    .await?;
+    let stored = best.iter().map(|photo| photo.feedback.index).collect();
+    Ok((
+        stored,
+        handlers::register_summary(&session.collection, &payload, &response),
+    ))
+}
+
+// Handler for DELETE /enrollments/:id - abandons a session
+pub async fn delete_session(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(id): Path<Uuid>,
+) -> StatusCode {
+    match state.enrollments.take(tenant.id(), &id) {
+        Some(_) => StatusCode::NO_CONTENT,
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+// Gates of /register/ the photo fails, none when it could be stored
+fn failures(state: &AppState, analysis: &Analysis) -> Vec<&'static str> {
+    let mut failed = analysis
+        .quality
+        .map(|scores| state.quality_gate.failures(&scores))
+        .unwrap_or_default();
+    if let (Some(score), Some(threshold)) = (
+        analysis.liveness,
+        state.liveness.as_ref().and_then(|model| model.threshold()),
+    ) {
+        if score < threshold {
+            failed.push("liveness");
+        }
+    }
+    if let (Some(angles), Some(estimator)) = (analysis.pose, &state.pose) {
+        if estimator.limits().is_exceeded(&angles) {
+            failed.push("pose");
+        }
+    }
+    failed
+}
+
+// Sharper, larger and more frontal faces rank first
+fn score(analysis: &Analysis) -> f32 {
+    let quality = analysis.quality.map_or(0.0, |scores| {
+        (1.0 + scores.sharpness).ln() + (1.0 + scores.face_size as f32).ln()
+    });
+    // About one point per 30 degrees away from the camera
+    let turned = analysis
+        .pose
+        .map_or(0.0, |angles| (angles.yaw.abs() + angles.pitch.abs()) / 30.0);
+    quality - turned
+}
@@ -0,0 +1,122 @@
+use axum::{
+    extract::{MatchedPath, Request},
+    http::header,
+    middleware::Next,
+    response::Response,
+};
+use sentry::integrations::tracing::{EventFilter, SentryLayer};
+use sentry::{protocol, ClientInitGuard, Hub, SentryFutureExt};
+use std::borrow::Cow;
+use std::sync::Arc;
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::redact;
+use crate::request_id::RequestId;
+
+// Headers copied to reports; credentials, cookies and bodies never leave
+const REPORTED_HEADERS: [header::HeaderName; 3] = [
+    header::CONTENT_TYPE,
+    header::CONTENT_LENGTH,
+    header::USER_AGENT,
+];
+
+// Sends panics and `error!` events to the Sentry-compatible backend of `dsn`,
+// with images and embeddings scrubbed like in the logs.
+// The guard flushes pending reports when dropped, so it must live as long as
+// the process.
+pub fn init(
+    dsn: &str,
+    environment: Option<String>,
+) -> Result<ClientInitGuard, Box<dyn std::error::Error>> {
+    let dsn: sentry::types::Dsn = dsn.parse()?;
+    Ok(sentry::init(sentry::ClientOptions {
+        dsn: Some(dsn),
+        release: Some(Cow::Borrowed(env!("CARGO_PKG_VERSION"))),
+        environment: environment.map(Cow::Owned),
+        // Peer addresses and user identities stay out of the reports
+        send_default_pii: false,
+        before_send: Some(Arc::new(|mut event| {
+            if let Some(message) = &mut event.message {
+                *message = redact::scrub(message).into_owned();
+            }
+            for value in event.extra.values_mut() {
+                if let protocol::Value::String(text) = value {
+                    *text = redact::scrub(text).into_owned();
+                }
+            }
+            for exception in event.exception.values.iter_mut() {
+                if let Some(value) = &mut exception.value {
+                    *value = redact::scrub(value).into_owned();
+                }
+            }
+            Some(event)
+        })),
+        ..Default::default()
+    }))
+}
+
+// Errors become reports and warnings their breadcrumbs; the rest is only logged
+pub fn layer<S>() -> SentryLayer<S>
+where
+    S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+{
+    sentry::integrations::tracing::layer().event_filter(|metadata| match *metadata.level() {
+        tracing::Level::ERROR => EventFilter::Event,
+        tracing::Level::WARN => EventFilter::Breadcrumb,
+        _ => EventFilter::Ignore,
+    })
+}
+
+// Layer of the whole router: reports raised while serving a request
+// (including panics of its handler) carry its method, route, path and
+// request id, but never its body
+pub async fn report_requests(request: Request, next: Next) -> Response {
+    if Hub::current().client().is_none() {
+        return next.run(request).await;
+    }
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| request.uri().path().to_string());
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .map(|id| id.as_str().to_string());
+    let headers = REPORTED_HEADERS
+        .iter()
+        .filter_map(|name| {
+            let value = request.headers().get(name)?.to_str().ok()?;
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect();
+    let host = request
+        .headers()
+        .get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("localhost");
+    let context = protocol::Request {
+        method: Some(request.method().to_string()),
+        // The path alone: query strings can hold identifiers
+        url: format!("http://{}{}", host, request.uri().path())
+            .parse()
+            .ok(),
+        headers,
+        ..Default::default()
+    };
+
+    let hub = Arc::new(Hub::new_from_top(Hub::current()));
+    hub.configure_scope(|scope| {
+        scope.set_tag("route", &route);
+        if let Some(request_id) = &request_id {
+            scope.set_tag("request_id", request_id);
+        }
+        scope.add_event_processor(move |mut event| {
+            if event.request.is_none() {
+                event.request = Some(context.clone());
+            }
+            Some(event)
+        });
+    });
+    next.run(request).bind_hub(hub).await
+}
@@ -0,0 +1,140 @@
+use axum::{
+    extract::{Extension, Query, State},
+    response::sse::{Event, KeepAlive, Sse},
+};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::sync::RwLock;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tokio_stream::{Stream, StreamExt};
+use uuid::Uuid;
+
+use crate::store::SearchMatch;
+use crate::tenant::Tenant;
+use crate::util;
+use crate::AppState;
+
+// Similarity from which a search hit is reported as a match event
+pub const DEFAULT_ALERT_THRESHOLD: f32 = 0.9;
+// Events buffered per subscriber; a subscriber further behind skips the oldest
+const EVENT_BUFFER: usize = 1024;
+
+// A search hit above the alert threshold
+#[derive(Clone, Debug, Serialize)]
+pub struct MatchEvent {
+    #[serde(skip)]
+    pub tenant: String,
+    pub collection: String,
+    // Camera that captured the query, for searches of the RTSP workers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    pub target_uuid: Uuid,
+    pub origin: String,
+    pub similarity: f32,
+    pub timestamp: String,
+}
+
+// Fan-out of match events to the live subscribers of /events
+pub struct Events {
+    // Changes at runtime, see `tunables`
+    alert_threshold: RwLock<f32>,
+    sender: broadcast::Sender<MatchEvent>,
+}
+
+impl Events {
+    pub fn new(alert_threshold: f32) -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER);
+        Self {
+            alert_threshold: RwLock::new(alert_threshold),
+            sender,
+        }
+    }
+
+    pub fn alert_threshold(&self) -> f32 {
+        *self
+            .alert_threshold
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn set_alert_threshold(&self, alert_threshold: f32) {
+        *self
+            .alert_threshold
+            .write()
+            .unwrap_or_else(|e| e.into_inner()) = alert_threshold;
+    }
+
+    // Called with the results of every search; a no-op without subscribers
+    pub fn publish_matches(
+        &self,
+        tenant: &str,
+        collection: &str,
+        source: Option<&str>,
+        matches: &[SearchMatch],
+    ) {
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        let timestamp = util::format_rfc3339(util::unix_now());
+        let alert_threshold = self.alert_threshold();
+        for found in matches
+            .iter()
+            .filter(|found| found.similarity >= alert_threshold)
+        {
+            // Only fails when every subscriber left in the meantime
+            let _ = self.sender.send(MatchEvent {
+                tenant: tenant.to_string(),
+                collection: collection.to_string(),
+                source: source.map(str::to_string),
+                target_uuid: found.uuid,
+                origin: found.origin.clone(),
+                similarity: found.similarity,
+                timestamp: timestamp.clone(),
+            });
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<MatchEvent> {
+        self.sender.subscribe()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct EventsQuery {
+    // Only events of this collection; all collections of the tenant if unset
+    collection: Option<String>,
+}
+
+// Handler for GET /events - Server-Sent Events stream of the tenant's match events
+pub async fn match_events(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Query(query): Query<EventsQuery>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    tracing::info!(tenant = %tenant.id(), collection = ?query.collection, "Match event subscriber connected");
+    let stream = BroadcastStream::new(state.events.subscribe()).filter_map(move |received| {
+        match received {
+            Ok(event) => {
+                if event.tenant != tenant.id()
+                    || query
+                        .collection
+                        .as_ref()
+                        .is_some_and(|collection| *collection != event.collection)
+                {
+                    return None;
+                }
+                Event::default()
+                    .event("match")
+                    .json_data(&event)
+                    .ok()
+                    .map(Ok)
+            }
+            // The subscriber fell behind; tell it how many events it missed
+            Err(BroadcastStreamRecvError::Lagged(missed)) => Some(Ok(Event::default()
+                .event("lagged")
+                .data(missed.to_string()))),
+        }
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
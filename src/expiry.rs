@@ -0,0 +1,140 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::handlers;
+use crate::notify::Change;
+use crate::resync::{self, TargetKey};
+use crate::target_store::ExpiredRow;
+use crate::util;
+use crate::AppState;
+
+pub const DEFAULT_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+// Rows deleted per transaction
+const SWEEP_BATCH_ROWS: i64 = 10_000;
+
+// Deletes one batch of expired Postgres rows, notifying the other instances
+// of their targets in the same transaction
+async fn delete_expired(
+    state: &AppState,
+    retention_secs: Option<i64>,
+) -> Result<Vec<ExpiredRow>, sqlx::Error> {
+    let mut transaction = state.db_pool.begin().await?;
+    // Soft-deleted rows still hold embeddings, so they expire as well
+    let rows: Vec<ExpiredRow> = sqlx::query_as(
+        r#"
+        DELETE FROM targets WHERE id IN (
+            SELECT id FROM targets
+            WHERE expires_at <= now()
+               OR ($1::BIGINT IS NOT NULL AND created_at <= now() - $1::BIGINT * interval '1 second')
+            LIMIT $2
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING tenant, collection, uuid, image_key
+        "#,
+    )
+    .bind(retention_secs)
+    .bind(SWEEP_BATCH_ROWS)
+    .fetch_all(&mut *transaction)
+    .await?;
+    if let Some(notifier) = &state.notifier {
+        let targets: HashSet<TargetKey> = rows
+            .iter()
+            .map(|(tenant, collection, uuid, _)| (tenant.clone(), collection.clone(), *uuid))
+            .collect();
+        for (tenant, collection, uuid) in &targets {
+            let change = Change::Target {
+                tenant: tenant.clone(),
+                collection: collection.clone(),
+                uuid: *uuid,
+            };
+            notifier.publish(&mut *transaction, change).await?;
+        }
+    }
+    transaction.commit().await?;
+    Ok(rows)
+}
+
+// Deletes one batch of expired rows and drops them from the stores; returns
+// the number of rows deleted
+async fn sweep_batch(state: &AppState, retention_secs: Option<i64>) -> Result<usize, sqlx::Error> {
+    let rows = if state.storage.is_postgres() {
+        delete_expired(state, retention_secs).await?
+    } else {
+        state
+            .targets
+            .delete_expired(retention_secs, SWEEP_BATCH_ROWS)
+            .await?
+    };
+    let targets: HashSet<TargetKey> = rows
+        .iter()
+        .map(|(tenant, collection, uuid, _)| (tenant.clone(), collection.clone(), *uuid))
+        .collect();
+
+    // Registrations of a target that have not expired stay searchable
+    for key in &targets {
+        if state.collections.get(&key.0, &key.1).is_some() {
+            resync::reload(state, key).await?;
+        }
+    }
+    let mut crops: HashMap<Uuid, Vec<Option<String>>> = HashMap::new();
+    for (_, _, uuid, image_key) in &rows {
+        crops.entry(*uuid).or_default().push(image_key.clone());
+    }
+    for (uuid, image_keys) in crops {
+        handlers::delete_crops(state, uuid, image_keys);
+    }
+    if !targets.is_empty() {
+        tracing::info!(
+            rows = rows.len(),
+            targets = targets.len(),
+            "Expired registrations deleted"
+        );
+    }
+    Ok(rows.len())
+}
+
+// Deletes, every `interval`, the registrations past their `expires_at` or
+// older than `retention` from the database, the stores and the crop bucket
+pub async fn run(state: AppState, interval: Duration, retention: Option<Duration>) {
+    let retention_secs = retention.map(|retention| retention.as_secs() as i64);
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let start = Instant::now();
+        let mut deleted = 0;
+        loop {
+            match sweep_batch(&state, retention_secs).await {
+                Ok(rows) => {
+                    deleted += rows;
+                    if (rows as i64) < SWEEP_BATCH_ROWS {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    tracing::error!(error = %e, "Expiry sweep failed");
+                    break;
+                }
+            }
+        }
+        tracing::debug!(deleted, duration = ?start.elapsed(), "Expiry sweep done");
+    }
+}
+
+// Drops, every `interval`, the memory-only registrations past their
+// `expires_at`; retention needs the creation time kept by a database
+pub async fn run_memory(state: AppState, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let now = util::unix_now();
+        for (tenant, name, collection) in state.collections.all() {
+            let removed = collection.store.remove_expired(now).await;
+            if !removed.is_empty() {
+                tracing::info!(%tenant, collection = %name, targets = removed.len(), "Expired registrations dropped from memory");
+            }
+        }
+    }
+}
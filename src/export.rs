@@ -0,0 +1,311 @@
+use axum::{
+    extract::{Extension, Query, State},
+    http::StatusCode,
+    Json,
+};
+use base64::{engine::general_purpose, Engine as _};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::collections::DEFAULT_COLLECTION;
+use crate::handlers::{self, SearchPayload};
+use crate::store::{EmbeddingsStore, Metadata, SearchResults, Vector};
+use crate::tenant::Tenant;
+use crate::util;
+use crate::AppState;
+
+// Export documents follow the schema described in the README ("Exports"). The
+// "bias" format wraps the same data in ISO/IEC 30108-1 (BIAS) style
+// identity/BIR records for systems that expect that layout.
+
+pub const GALLERY_SCHEMA: &str = "owlfacerec.gallery/v1";
+pub const MATCH_SCHEMA: &str = "owlfacerec.match/v1";
+const VECTOR_ENCODING: &str = "f32le+base64";
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Json,
+    Bias,
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    format: ExportFormat,
+    // Defaults to the default collection
+    collection: Option<String>,
+}
+
+impl ExportQuery {
+    fn collection(&self) -> &str {
+        self.collection.as_deref().unwrap_or(DEFAULT_COLLECTION)
+    }
+}
+
+#[derive(Serialize)]
+pub struct ModelInfo {
+    name: String,
+    embedding_dimension: usize,
+}
+
+// --- Native JSON format ---
+
+#[derive(Serialize)]
+pub struct GalleryExport {
+    schema: &'static str,
+    generated_at: String,
+    collection: String,
+    model: ModelInfo,
+    template_mode: &'static str,
+    templates: Vec<TemplateRecord>,
+}
+
+#[derive(Serialize)]
+pub struct TemplateRecord {
+    subject_id: Uuid,
+    origin: String,
+    metadata: Metadata,
+    samples: u32,
+    vectors: Vec<Vec<f32>>,
+}
+
+#[derive(Serialize)]
+pub struct MatchTransaction {
+    schema: &'static str,
+    transaction_id: Uuid,
+    timestamp: String,
+    collection: String,
+    model: ModelInfo,
+    threshold: f32,
+    limit: usize,
+    // The search ran out of its time budget before scanning the whole gallery
+    partial: bool,
+    candidates: Vec<MatchCandidate>,
+}
+
+#[derive(Serialize)]
+pub struct MatchCandidate {
+    rank: usize,
+    subject_id: Uuid,
+    origin: String,
+    score: f32,
+}
+
+// --- ISO/IEC 30108-1 (BIAS) style wrapping ---
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct BiasIdentityList {
+    schema: &'static str,
+    #[serde(rename = "BIASIdentity")]
+    identities: Vec<BiasIdentity>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct BiasIdentity {
+    #[serde(rename = "SubjectID")]
+    subject_id: Uuid,
+    identity_data: BiasIdentityData,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct BiasIdentityData {
+    #[serde(rename = "BIRList")]
+    bir_list: Vec<Bir>,
+}
+
+// CBEFF biometric information record: header plus opaque biometric data block
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Bir {
+    #[serde(rename = "BIRHeader")]
+    header: BirHeader,
+    #[serde(rename = "BDB")]
+    bdb: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct BirHeader {
+    format_owner: &'static str,
+    format_type: String,
+    biometric_type: &'static str,
+    purpose: &'static str,
+    creation_date: String,
+    // Non-standard extension carrying the enrollment origin
+    origin: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct BiasIdentifySubjectResponse {
+    schema: &'static str,
+    #[serde(rename = "TransactionID")]
+    transaction_id: Uuid,
+    response_status: BiasResponseStatus,
+    candidate_list: Vec<BiasCandidate>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct BiasResponseStatus {
+    #[serde(rename = "Return")]
+    return_code: i32,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct BiasCandidate {
+    rank: usize,
+    #[serde(rename = "SubjectID")]
+    subject_id: Uuid,
+    score: f32,
+}
+
+// Little-endian f32 bytes, base64-encoded
+pub fn encode_vector(vector: &[f32]) -> String {
+    let bytes: Vec<u8> = vector.iter().flat_map(|v| v.to_le_bytes()).collect();
+    general_purpose::STANDARD.encode(bytes)
+}
+
+fn model_info(state: &AppState, store: &EmbeddingsStore) -> ModelInfo {
+    ModelInfo {
+        name: state.model_name.clone(),
+        embedding_dimension: store.dimension().unwrap_or(0),
+    }
+}
+
+// Handler for GET /export/templates/
+pub async fn export_templates(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Query(query): Query<ExportQuery>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let Some(collection) = state.collections.get(tenant.id(), query.collection()) else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let store = &collection.store;
+    let entries = store.snapshot().await;
+    let generated_at = util::format_rfc3339(util::unix_now());
+    tracing::info!(templates = entries.len(), "Exporting gallery templates");
+
+    let document = match query.format {
+        ExportFormat::Json => serde_json::to_value(GalleryExport {
+            schema: GALLERY_SCHEMA,
+            generated_at,
+            collection: query.collection().to_string(),
+            model: model_info(&state, store),
+            template_mode: store.template_mode().as_str(),
+            templates: entries
+                .into_iter()
+                .map(|entry| TemplateRecord {
+                    subject_id: entry.uuid,
+                    origin: entry.origin,
+                    metadata: entry.metadata,
+                    samples: entry.samples,
+                    vectors: entry.embeddings.into_iter().map(Vector::into_f32).collect(),
+                })
+                .collect(),
+        }),
+        ExportFormat::Bias => {
+            let format_type = format!("{}:{}", state.model_name, VECTOR_ENCODING);
+            serde_json::to_value(BiasIdentityList {
+                schema: GALLERY_SCHEMA,
+                identities: entries
+                    .into_iter()
+                    .map(|entry| BiasIdentity {
+                        subject_id: entry.uuid,
+                        identity_data: BiasIdentityData {
+                            bir_list: entry
+                                .embeddings
+                                .iter()
+                                .map(|vector| Bir {
+                                    header: BirHeader {
+                                        format_owner: "owlfacerec",
+                                        format_type: format_type.clone(),
+                                        biometric_type: "Face",
+                                        purpose: "Enroll",
+                                        creation_date: generated_at.clone(),
+                                        origin: entry.origin.clone(),
+                                    },
+                                    bdb: encode_vector(&vector.to_f32()),
+                                })
+                                .collect(),
+                        },
+                    })
+                    .collect(),
+            })
+        }
+    };
+
+    document.map(Json).map_err(|e| {
+        tracing::error!(error = %e, "Failed to serialize gallery export");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+// Handler for POST /export/search/ - runs a search and returns it as a match transaction
+pub async fn export_search(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Query(query): Query<ExportQuery>,
+    Json(payload): Json<SearchPayload>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let Some(collection) = state.collections.get(tenant.id(), query.collection()) else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let SearchResults {
+        matches, partial, ..
+    } = handlers::run_search(&state, &tenant, query.collection(), &payload).await?;
+    let transaction_id = Uuid::new_v4();
+    tracing::info!(%transaction_id, candidates = matches.len(), "Exporting match transaction");
+
+    let document = match query.format {
+        ExportFormat::Json => serde_json::to_value(MatchTransaction {
+            schema: MATCH_SCHEMA,
+            transaction_id,
+            timestamp: util::format_rfc3339(util::unix_now()),
+            collection: query.collection().to_string(),
+            model: model_info(&state, &collection.store),
+            threshold: payload
+                .threshold
+                .or(collection.settings.threshold)
+                .unwrap_or(state.tunables.default_threshold()),
+            limit: payload.limit.unwrap_or(state.tunables.default_limit()),
+            partial,
+            candidates: matches
+                .into_iter()
+                .enumerate()
+                .map(|(i, found)| MatchCandidate {
+                    rank: i + 1,
+                    subject_id: found.uuid,
+                    origin: found.origin,
+                    score: found.similarity,
+                })
+                .collect(),
+        }),
+        ExportFormat::Bias => serde_json::to_value(BiasIdentifySubjectResponse {
+            schema: MATCH_SCHEMA,
+            transaction_id,
+            response_status: BiasResponseStatus { return_code: 0 },
+            candidate_list: matches
+                .into_iter()
+                .enumerate()
+                .map(|(i, found)| BiasCandidate {
+                    rank: i + 1,
+                    subject_id: found.uuid,
+                    score: found.similarity,
+                })
+                .collect(),
+        }),
+    };
+
+    document.map(Json).map_err(|e| {
+        tracing::error!(error = %e, "Failed to serialize match transaction");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
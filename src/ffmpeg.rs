@@ -0,0 +1,78 @@
+use std::process::Stdio;
+use tokio::io::AsyncReadExt;
+use tokio::process::{Child, ChildStdout, Command};
+
+const READ_CHUNK: usize = 64 * 1024;
+// A buffer growing past this without a complete JPEG is garbage
+const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+// Decoding is left to an ffmpeg child process, which samples `fps` frames per
+// second of `input` (a file, or a stream URL) and writes them as a JPEG
+// sequence on its stdout. The child is killed when dropped.
+pub fn sample_frames(input: &str, fps: f32) -> std::io::Result<(Child, JpegFrames)> {
+    let mut command = Command::new("ffmpeg");
+    command.args(["-nostdin", "-loglevel", "error"]);
+    if input.starts_with("rtsp") {
+        // UDP drops packets on busy networks, which corrupts frames
+        command.args(["-rtsp_transport", "tcp"]);
+    }
+    let mut child = command
+        .args(["-i", input, "-vf"])
+        .arg(format!("fps={}", fps))
+        .args(["-f", "image2pipe", "-c:v", "mjpeg", "-q:v", "3", "-"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+    let Some(stdout) = child.stdout.take() else {
+        return Err(std::io::Error::other("ffmpeg stdout not captured"));
+    };
+    let frames = JpegFrames {
+        stdout,
+        buffer: Vec::new(),
+        chunk: vec![0u8; READ_CHUNK],
+    };
+    Ok((child, frames))
+}
+
+// Frames of an ffmpeg JPEG sequence, in order
+pub struct JpegFrames {
+    stdout: ChildStdout,
+    buffer: Vec<u8>,
+    chunk: Vec<u8>,
+}
+
+impl JpegFrames {
+    // None once ffmpeg closed its output
+    pub async fn next(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(frame) = take_jpeg(&mut self.buffer) {
+                return Ok(Some(frame));
+            }
+            if self.buffer.len() > MAX_FRAME_SIZE {
+                self.buffer.clear();
+            }
+            let read = self.stdout.read(&mut self.chunk).await?;
+            if read == 0 {
+                return Ok(None);
+            }
+            self.buffer.extend_from_slice(&self.chunk[..read]);
+        }
+    }
+}
+
+// Splits the first complete JPEG (SOI to EOI marker) off the front of
+// `buffer`. 0xFF bytes inside the compressed data are always followed by 0x00
+// or a restart marker, so the first EOI after the SOI ends the image.
+fn take_jpeg(buffer: &mut Vec<u8>) -> Option<Vec<u8>> {
+    let start = buffer.windows(2).position(|bytes| bytes == [0xFF, 0xD8])?;
+    let end = buffer[start + 2..]
+        .windows(2)
+        .position(|bytes| bytes == [0xFF, 0xD9])?
+        + start
+        + 4;
+    let frame = buffer[start..end].to_vec();
+    buffer.drain(..end);
+    Some(frame)
+}
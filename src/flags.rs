@@ -0,0 +1,148 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use std::sync::RwLock;
+
+use crate::collections;
+use crate::AppState;
+
+// Flags of the subsystems gated today and whether they are on by default
+pub const SEARCH_SHADOWING: &str = "search_shadowing";
+pub const COMPACTION: &str = "compaction";
+const DEFAULTS: &[(&str, bool)] = &[(SEARCH_SHADOWING, true), (COMPACTION, true)];
+
+// When a flag is on
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct FlagRule {
+    pub enabled: bool,
+    // Only for these tenants; None is every tenant
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenants: Option<Vec<String>>,
+    // Only for this share of calls (0-100); None is all of them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub percentage: Option<u8>,
+}
+
+impl FlagRule {
+    fn on(enabled: bool) -> Self {
+        Self {
+            enabled,
+            tenants: None,
+            percentage: None,
+        }
+    }
+}
+
+// on | off | 25% | tenants:acme|globex
+impl FromStr for FlagRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match s.to_ascii_lowercase().as_str() {
+            "on" | "true" => return Ok(FlagRule::on(true)),
+            "off" | "false" => return Ok(FlagRule::on(false)),
+            _ => {}
+        }
+        if let Some(percentage) = s.strip_suffix('%') {
+            let percentage = percentage
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|percentage| *percentage <= 100)
+                .ok_or_else(|| format!("invalid flag percentage '{}'", s))?;
+            return Ok(FlagRule {
+                percentage: Some(percentage),
+                ..FlagRule::on(true)
+            });
+        }
+        if let Some(tenants) = s.strip_prefix("tenants:") {
+            return Ok(FlagRule {
+                tenants: Some(tenants.split('|').map(|t| t.trim().to_string()).collect()),
+                ..FlagRule::on(true)
+            });
+        }
+        Err(format!("invalid flag rule '{}'", s))
+    }
+}
+
+// Runtime feature flags, seeded from FEATURE_FLAGS and changed through the
+// admin API. Changes are kept in memory: they apply to this instance only and
+// are lost on restart.
+pub struct FeatureFlags {
+    flags: RwLock<HashMap<String, FlagRule>>,
+}
+
+impl FeatureFlags {
+    pub fn new(configured: HashMap<String, FlagRule>) -> Self {
+        let mut flags: HashMap<String, FlagRule> = DEFAULTS
+            .iter()
+            .map(|(name, enabled)| (name.to_string(), FlagRule::on(*enabled)))
+            .collect();
+        flags.extend(configured);
+        Self {
+            flags: RwLock::new(flags),
+        }
+    }
+
+    // Unknown flags are off
+    pub fn is_enabled(&self, name: &str, tenant: Option<&str>) -> bool {
+        let flags = self.flags.read().unwrap_or_else(|e| e.into_inner());
+        let Some(rule) = flags.get(name) else {
+            return false;
+        };
+        if !rule.enabled {
+            return false;
+        }
+        if let Some(tenants) = &rule.tenants {
+            if !tenant.is_some_and(|tenant| tenants.iter().any(|t| t == tenant)) {
+                return false;
+            }
+        }
+        match rule.percentage {
+            Some(percentage) => rand::random::<f64>() * 100.0 < f64::from(percentage),
+            None => true,
+        }
+    }
+
+    pub fn list(&self) -> BTreeMap<String, FlagRule> {
+        self.flags
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|(name, rule)| (name.clone(), rule.clone()))
+            .collect()
+    }
+
+    pub fn set(&self, name: &str, rule: FlagRule) {
+        self.flags
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(name.to_string(), rule);
+    }
+}
+
+// Handler for GET /admin/flags/
+pub async fn list_flags(State(state): State<AppState>) -> Json<BTreeMap<String, FlagRule>> {
+    Json(state.flags.list())
+}
+
+// Handler for PUT /admin/flags/:name
+pub async fn set_flag(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Json(rule): Json<FlagRule>,
+) -> Result<Json<FlagRule>, StatusCode> {
+    if !collections::is_valid_name(&name) || rule.percentage.is_some_and(|p| p > 100) {
+        tracing::warn!(flag = %name, "Received invalid feature flag update");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    tracing::info!(flag = %name, rule = ?rule, "Feature flag updated");
+    state.flags.set(&name, rule.clone());
+    Ok(Json(rule))
+}
@@ -0,0 +1,288 @@
+use image::codecs::{gif::GifDecoder, png::PngDecoder, webp::WebPDecoder};
+use image::{
+    AnimationDecoder, DynamicImage, GenericImageView, ImageDecoder, ImageFormat, ImageReader,
+};
+use std::fmt;
+use std::io::Cursor;
+
+pub const DEFAULT_MAX_DIMENSION: u32 = 10_000;
+pub const DEFAULT_MAX_MEGAPIXELS: f32 = 50.0;
+pub const DEFAULT_MAX_FRAMES: usize = 8;
+pub const DEFAULT_WORKING_DIMENSION: u32 = 2048;
+// Frames of an animation decoded at most, whatever their size; later ones
+// are ignored
+const MAX_DECODED_FRAMES: usize = 1_000;
+
+// Caps on the resolution of decoded images, checked against the image header
+// before any pixel is decoded
+#[derive(Clone, Copy, Debug)]
+pub struct ImageLimits {
+    // Longest side, in pixels
+    pub max_dimension: u32,
+    pub max_megapixels: f32,
+    // Frames of an animation kept to pick the face from, 1 for the first
+    // frame alone
+    pub max_frames: usize,
+    // Longest side images are downscaled to as they are decoded, so detection
+    // and the checks never run over the full resolution of large photos; None
+    // keeps them as sent
+    pub working_dimension: Option<u32>,
+}
+
+impl Default for ImageLimits {
+    fn default() -> Self {
+        Self {
+            max_dimension: DEFAULT_MAX_DIMENSION,
+            max_megapixels: DEFAULT_MAX_MEGAPIXELS,
+            max_frames: DEFAULT_MAX_FRAMES,
+            working_dimension: Some(DEFAULT_WORKING_DIMENSION),
+        }
+    }
+}
+
+impl ImageLimits {
+    fn check(&self, width: u32, height: u32) -> Result<(), TooLarge> {
+        let megapixels = width as f32 * height as f32 / 1_000_000.0;
+        if width.max(height) > self.max_dimension || megapixels > self.max_megapixels {
+            return Err(TooLarge {
+                width,
+                height,
+                limits: *self,
+            });
+        }
+        Ok(())
+    }
+
+    // Checks the canvas of an animation and returns how many of its frames
+    // may be decoded: as many pixels as `max_frames` images at the megapixel
+    // limit, so a large canvas is not decoded a thousand times over
+    fn check_decoder(&self, decoder: &impl ImageDecoder) -> Result<usize, TooLarge> {
+        let (width, height) = decoder.dimensions();
+        self.check(width, height)?;
+        let megapixels = (width as f32 * height as f32 / 1_000_000.0).max(f32::EPSILON);
+        let frames = self.max_megapixels * self.max_frames as f32 / megapixels;
+        Ok((frames as usize).clamp(1, MAX_DECODED_FRAMES))
+    }
+}
+
+// An image over the limits, rejected without being decoded
+#[derive(Debug)]
+pub struct TooLarge {
+    pub width: u32,
+    pub height: u32,
+    pub limits: ImageLimits,
+}
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "image of {}x{} exceeds the limits of {} pixels per side and {} megapixels",
+            self.width, self.height, self.limits.max_dimension, self.limits.max_megapixels
+        )
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+// HEIF brands of still images and sequences; AVIF shares the container but
+// has its own brands and is decoded by the image crate
+const HEIF_BRANDS: [&[u8; 4]; 6] = [b"heic", b"heix", b"hevc", b"hevx", b"heim", b"heis"];
+const GENERIC_BRANDS: [&[u8; 4]; 2] = [b"mif1", b"msf1"];
+
+// Decodes any supported format: JPEG, PNG, GIF, WebP (lossy, lossless, alpha
+// and the first frame of animations), AVIF with the `avif` feature and HEIC with
+// the `heic` feature. Images over `limits` fail with `TooLarge`.
+pub fn load(
+    bytes: &[u8],
+    limits: &ImageLimits,
+) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+    if is_heif(bytes) {
+        return load_heif(bytes, limits);
+    }
+    let (width, height) = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()?
+        .into_dimensions()
+        .map_err(|e| -> Box<dyn std::error::Error> {
+            match e {
+                image::ImageError::Unsupported(_) if is_avif(bytes) => {
+                    "AVIF decoding is not enabled in this build (feature `avif`)".into()
+                }
+                e => e.into(),
+            }
+        })?;
+    limits.check(width, height)?;
+    image::load_from_memory(bytes).map_err(|e| match e {
+        image::ImageError::Unsupported(_) if is_avif(bytes) => {
+            "AVIF decoding is not enabled in this build (feature `avif`)".into()
+        }
+        e => e.into(),
+    })
+}
+
+// Frames of an animated GIF, WebP or PNG, at most `limits.max_frames` of
+// them spread evenly over the animation; any other image is a single frame.
+// Each frame is the whole canvas with the earlier frames drawn under it,
+// downscaled to the working dimension as soon as it is decoded; with the
+// factor back to the pixels of the image as sent.
+pub fn load_frames(
+    bytes: &[u8],
+    limits: &ImageLimits,
+) -> Result<(Vec<DynamicImage>, f32), Box<dyn std::error::Error>> {
+    let still = || {
+        let (img, scale) = downscale(load(bytes, limits)?, limits.working_dimension);
+        Ok::<_, Box<dyn std::error::Error>>((vec![img], scale))
+    };
+    if limits.max_frames < 2 {
+        return still();
+    }
+    let cursor = Cursor::new(bytes);
+    let (frames, decoded) = match image::guess_format(bytes) {
+        Ok(ImageFormat::Gif) => {
+            let decoder = GifDecoder::new(cursor)?;
+            let decoded = limits.check_decoder(&decoder)?;
+            (decoder.into_frames(), decoded)
+        }
+        Ok(ImageFormat::WebP) => {
+            let decoder = WebPDecoder::new(cursor)?;
+            if !decoder.has_animation() {
+                return still();
+            }
+            let decoded = limits.check_decoder(&decoder)?;
+            (decoder.into_frames(), decoded)
+        }
+        Ok(ImageFormat::Png) => {
+            let decoder = PngDecoder::new(cursor)?;
+            if !decoder.is_apng()? {
+                return still();
+            }
+            let decoded = limits.check_decoder(&decoder)?;
+            (decoder.apng()?.into_frames(), decoded)
+        }
+        _ => return still(),
+    };
+    sample_frames(frames, decoded, limits)
+}
+
+// Keeps every `stride`-th of the first `decoded` frames; once more than
+// `max_frames` are kept the stride doubles and every other kept frame goes,
+// so the frames stay evenly spread without knowing their count upfront. A
+// truncated or corrupt frame ends the animation there, the frames before it
+// are kept. Kept frames are downscaled right away, so at most one of them is
+// held at full resolution.
+fn sample_frames(
+    frames: image::Frames<'_>,
+    decoded: usize,
+    limits: &ImageLimits,
+) -> Result<(Vec<DynamicImage>, f32), Box<dyn std::error::Error>> {
+    let mut stride = 1;
+    let mut scale = 1.0;
+    let mut kept = Vec::new();
+    for (index, frame) in frames.take(decoded).enumerate() {
+        // Skipped frames are decoded all the same, later ones are drawn over them
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(e) if index == 0 => return Err(e.into()),
+            Err(e) => {
+                tracing::warn!(frame = index, error = %e, "Animation cut short at an undecodable frame");
+                break;
+            }
+        };
+        if index % stride != 0 {
+            continue;
+        }
+        let (frame, factor) = downscale(
+            DynamicImage::ImageRgba8(frame.into_buffer()),
+            limits.working_dimension,
+        );
+        scale = factor;
+        kept.push(frame);
+        if kept.len() > limits.max_frames {
+            stride *= 2;
+            kept = kept.into_iter().step_by(2).collect();
+        }
+    }
+    if kept.is_empty() {
+        return Err("animation without frames".into());
+    }
+    Ok((kept, scale))
+}
+
+// Downscales an image whose longest side is over `max_side`, in a single
+// area-averaging pass; None (or 0) leaves it as it is. Returns the factor
+// from the coordinates of the result to the original ones.
+pub fn downscale(img: DynamicImage, max_side: Option<u32>) -> (DynamicImage, f32) {
+    let (width, height) = img.dimensions();
+    let longest = width.max(height);
+    let Some(max_side) = max_side.filter(|&max_side| max_side > 0 && longest > max_side) else {
+        return (img, 1.0);
+    };
+    let scale = longest as f32 / max_side as f32;
+    let side = |side: u32| ((side as f32 / scale).round() as u32).max(1);
+    (img.thumbnail_exact(side(width), side(height)), scale)
+}
+
+// Brands of the leading ISO BMFF `ftyp` box: the major brand, then the
+// compatible ones
+fn brands(bytes: &[u8]) -> Option<impl Iterator<Item = &[u8]>> {
+    if bytes.len() < 16 || &bytes[4..8] != b"ftyp" {
+        return None;
+    }
+    let size = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+    let end = size.clamp(16, bytes.len());
+    // The minor version sits between the major and the compatible brands
+    let compatible = bytes[16..end].chunks_exact(4);
+    Some(std::iter::once(&bytes[8..12]).chain(compatible))
+}
+
+fn is_avif(bytes: &[u8]) -> bool {
+    brands(bytes).is_some_and(|mut brands| brands.any(|brand| brand == b"avif" || brand == b"avis"))
+}
+
+fn is_heif(bytes: &[u8]) -> bool {
+    let Some(brands) = brands(bytes) else {
+        return false;
+    };
+    let brands: Vec<&[u8]> = brands.collect();
+    let heif = brands
+        .iter()
+        .any(|brand| HEIF_BRANDS.iter().any(|heif| brand == heif));
+    // A bare mif1/msf1 file is HEIF unless it declares itself AVIF
+    let generic = GENERIC_BRANDS.iter().any(|generic| brands[0] == *generic) && !is_avif(bytes);
+    heif || generic
+}
+
+#[cfg(feature = "heic")]
+fn load_heif(
+    bytes: &[u8],
+    limits: &ImageLimits,
+) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+    use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+    let context = HeifContext::read_from_bytes(bytes)?;
+    let handle = context.primary_image_handle()?;
+    limits.check(handle.width(), handle.height())?;
+    let decoded = LibHeif::new().decode(&handle, ColorSpace::Rgb(RgbChroma::Rgb), None)?;
+    let plane = decoded
+        .planes()
+        .interleaved
+        .ok_or("HEIC image without an interleaved RGB plane")?;
+
+    // Rows may be padded past width * 3 bytes
+    let row = plane.width as usize * 3;
+    let mut pixels = Vec::with_capacity(row * plane.height as usize);
+    for line in plane.data.chunks(plane.stride).take(plane.height as usize) {
+        pixels.extend_from_slice(&line[..row]);
+    }
+    let rgb = image::RgbImage::from_raw(plane.width, plane.height, pixels)
+        .ok_or("HEIC plane smaller than its dimensions")?;
+    Ok(DynamicImage::ImageRgb8(rgb))
+}
+
+#[cfg(not(feature = "heic"))]
+fn load_heif(
+    _bytes: &[u8],
+    _limits: &ImageLimits,
+) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+    Err("HEIC decoding is not enabled in this build (feature `heic`)".into())
+}
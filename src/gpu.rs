@@ -0,0 +1,330 @@
+use std::num::NonZeroU64;
+use std::sync::{mpsc, Arc};
+use wgpu::util::DeviceExt;
+
+// Invocations per workgroup, one row each; must match the shader
+const WORKGROUP_SIZE: u32 = 64;
+// Workgroups per dispatch dimension guaranteed by every backend
+const MAX_GROUPS_PER_DIMENSION: u32 = 65_535;
+// Rows the vector buffer of a shard starts with
+const MIN_CAPACITY_ROWS: usize = 1024;
+// The GPU sums in another order than the CPU, so its similarities may be off
+// in the last bits; rows this far below the lowest threshold are still
+// scored exactly
+const SCORE_MARGIN: f32 = 1e-3;
+
+// Dot product of the query with every row; the rows and the query are unit
+// length, so that is their cosine similarity
+const SHADER: &str = r#"
+struct Params {
+    rows: u32,
+    dimension: u32,
+    // Invocations per row of workgroups, to index past the dispatch limit
+    stride: u32,
+    padding: u32,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+@group(0) @binding(1) var<storage, read> vectors: array<f32>;
+@group(0) @binding(2) var<storage, read> query: array<f32>;
+@group(0) @binding(3) var<storage, read_write> scores: array<f32>;
+
+@compute @workgroup_size(64)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let row = id.y * params.stride + id.x;
+    if (row >= params.rows) {
+        return;
+    }
+    let base = row * params.dimension;
+    var dot = 0.0;
+    for (var i = 0u; i < params.dimension; i = i + 1u) {
+        dot = dot + vectors[base + i] * query[i];
+    }
+    scores[row] = dot;
+}
+"#;
+
+// The adapter and compiled shader shared by every store
+pub struct GpuContext {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    pipeline: wgpu::ComputePipeline,
+    // Largest buffer a shard's vectors may take
+    max_buffer_bytes: u64,
+    // Name of the adapter, for the logs
+    pub adapter: String,
+}
+
+impl GpuContext {
+    // Err when there is no hardware adapter; software ones would be slower
+    // than the CPU scan they replace
+    pub async fn request() -> Result<Arc<Self>, String> {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+        let adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::HighPerformance,
+                force_fallback_adapter: false,
+                compatible_surface: None,
+            })
+            .await
+            .ok_or("no GPU adapter")?;
+        let info = adapter.get_info();
+        if info.device_type == wgpu::DeviceType::Cpu {
+            return Err(format!("{} is a software adapter", info.name));
+        }
+        let limits = adapter.limits();
+        let (device, queue) = adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    label: Some("search"),
+                    required_features: wgpu::Features::empty(),
+                    required_limits: limits.clone(),
+                    memory_hints: wgpu::MemoryHints::Performance,
+                },
+                None,
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("similarities"),
+            source: wgpu::ShaderSource::Wgsl(SHADER.into()),
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("similarities"),
+            layout: None,
+            module: &module,
+            entry_point: "main",
+            compilation_options: Default::default(),
+            cache: None,
+        });
+        Ok(Arc::new(Self {
+            device,
+            queue,
+            pipeline,
+            max_buffer_bytes: u64::from(limits.max_storage_buffer_binding_size)
+                .min(limits.max_buffer_size),
+            adapter: info.name,
+        }))
+    }
+}
+
+fn bytes(values: &[f32]) -> Vec<u8> {
+    values
+        .iter()
+        .flat_map(|value| value.to_le_bytes())
+        .collect()
+}
+
+fn normalized(vector: &[f32]) -> Vec<f32> {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm == 0.0 {
+        return vector.to_vec();
+    }
+    vector.iter().map(|x| x / norm).collect()
+}
+
+// Copy of a shard's vectors in GPU memory, one row per vector, appended as
+// they are registered. Rows are never rewritten: a `Mean` template that
+// moves gets a new row and its old one lingers, and the rows of deleted
+// entries stay until compaction uploads the live vectors to a new copy.
+// Both only add candidates that the exact scoring on the CPU then discards.
+pub struct GpuVectors {
+    context: Arc<GpuContext>,
+    // Unit-length vectors, row by row; None until the first vector
+    buffer: Option<wgpu::Buffer>,
+    dimension: usize,
+    // Rows the buffer can hold
+    capacity: usize,
+    // Entry position of each row
+    rows: Vec<u32>,
+    // The shard outgrew the largest buffer the adapter can bind, so its
+    // searches run on the CPU
+    overflowed: bool,
+}
+
+impl GpuVectors {
+    pub fn new(context: Arc<GpuContext>) -> Self {
+        Self {
+            context,
+            buffer: None,
+            dimension: 0,
+            capacity: 0,
+            rows: Vec::new(),
+            overflowed: false,
+        }
+    }
+
+    // Uploads the vector of the entry at `position`
+    pub fn add(&mut self, position: usize, vector: &[f32]) {
+        if self.overflowed {
+            return;
+        }
+        if self.buffer.is_none() {
+            self.dimension = vector.len();
+        }
+        if self.rows.len() == self.capacity && !self.grow() {
+            tracing::warn!(
+                rows = self.rows.len(),
+                max_bytes = self.context.max_buffer_bytes,
+                "Shard too large for the GPU, searching it on the CPU"
+            );
+            self.overflowed = true;
+            self.buffer = None;
+            self.rows = Vec::new();
+            return;
+        }
+        let Some(buffer) = &self.buffer else {
+            return;
+        };
+        let offset = (self.rows.len() * self.dimension * 4) as u64;
+        self.context
+            .queue
+            .write_buffer(buffer, offset, &bytes(&normalized(vector)));
+        self.rows.push(position as u32);
+    }
+
+    // Doubles the buffer, or fills what the adapter allows; false when not
+    // even one more row fits
+    fn grow(&mut self) -> bool {
+        let row_bytes = (self.dimension * 4) as u64;
+        let fitting = (self.context.max_buffer_bytes / row_bytes) as usize;
+        let capacity = (self.capacity * 2).max(MIN_CAPACITY_ROWS).min(fitting);
+        if capacity <= self.rows.len() {
+            return false;
+        }
+        let device = &self.context.device;
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("vectors"),
+            size: capacity as u64 * row_bytes,
+            usage: wgpu::BufferUsages::STORAGE
+                | wgpu::BufferUsages::COPY_DST
+                | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        if let Some(old) = &self.buffer {
+            let mut encoder = device.create_command_encoder(&Default::default());
+            encoder.copy_buffer_to_buffer(old, 0, &buffer, 0, self.rows.len() as u64 * row_bytes);
+            self.context.queue.submit([encoder.finish()]);
+        }
+        self.buffer = Some(buffer);
+        self.capacity = capacity;
+        true
+    }
+
+    // Empty copy on the same adapter, filled by compaction with the live
+    // vectors of the shard; dropping the old one frees its stale rows
+    pub fn cleared(&self) -> Self {
+        Self::new(self.context.clone())
+    }
+
+    // Positions of the entries with a vector at least `lowest` similar to
+    // the query, in storage order and once each. None when the search has
+    // to run on the CPU: nothing uploaded, overflowed, or a GPU failure.
+    //
+    // Blocking: waits for the GPU
+    pub fn candidates(&self, query: &[f32], lowest: f32) -> Option<Vec<u32>> {
+        let buffer = self.buffer.as_ref()?;
+        if self.rows.is_empty() || query.len() != self.dimension {
+            return None;
+        }
+        let device = &self.context.device;
+        let rows = self.rows.len() as u32;
+        let groups = rows.div_ceil(WORKGROUP_SIZE);
+        let groups_x = groups.min(MAX_GROUPS_PER_DIMENSION);
+        let groups_y = groups.div_ceil(groups_x);
+        let params: Vec<u8> = [rows, self.dimension as u32, groups_x * WORKGROUP_SIZE, 0]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+
+        let params = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("params"),
+            contents: &params,
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        let query = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("query"),
+            contents: &bytes(&normalized(query)),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let scores_size = u64::from(rows) * 4;
+        let scores = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("scores"),
+            size: scores_size,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let readback = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("readback"),
+            size: scores_size,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let vectors_size = NonZeroU64::new(u64::from(rows) * self.dimension as u64 * 4)?;
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("similarities"),
+            layout: &self.context.pipeline.get_bind_group_layout(0),
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: params.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                        buffer,
+                        offset: 0,
+                        size: Some(vectors_size),
+                    }),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: query.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: scores.as_entire_binding(),
+                },
+            ],
+        });
+
+        let mut encoder = device.create_command_encoder(&Default::default());
+        {
+            let mut pass = encoder.begin_compute_pass(&Default::default());
+            pass.set_pipeline(&self.context.pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.dispatch_workgroups(groups_x, groups_y, 1);
+        }
+        encoder.copy_buffer_to_buffer(&scores, 0, &readback, 0, scores_size);
+        self.context.queue.submit([encoder.finish()]);
+
+        let slice = readback.slice(..);
+        let (sender, receiver) = mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |mapped| {
+            let _ = sender.send(mapped);
+        });
+        device.poll(wgpu::Maintain::Wait);
+        if let Err(e) = receiver
+            .recv()
+            .map_err(|e| e.to_string())
+            .and_then(|mapped| mapped.map_err(|e| e.to_string()))
+        {
+            tracing::warn!(error = %e, "GPU search failed, searching on the CPU");
+            return None;
+        }
+
+        let cutoff = lowest - SCORE_MARGIN;
+        let mut positions: Vec<u32> = slice
+            .get_mapped_range()
+            .chunks_exact(4)
+            .map(|score| f32::from_le_bytes([score[0], score[1], score[2], score[3]]))
+            .zip(&self.rows)
+            .filter(|(score, _)| *score >= cutoff)
+            .map(|(_, position)| *position)
+            .collect();
+        readback.unmap();
+        positions.sort_unstable();
+        positions.dedup();
+        Some(positions)
+    }
+}
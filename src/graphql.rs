@@ -0,0 +1,356 @@
+use async_graphql::{
+    connection::{Connection, Edge},
+    ComplexObject, Context, EmptyMutation, EmptySubscription, Json, Object, Schema, SimpleObject,
+};
+use async_graphql_axum::{GraphQLRequest, GraphQLResponse};
+use axum::extract::{Extension, State};
+use sqlx::Row;
+use std::sync::LazyLock;
+use uuid::Uuid;
+
+use crate::collections::DEFAULT_COLLECTION;
+use crate::handlers::{self, OriginStats};
+use crate::history::{self, RecordedSearch, SearchesQuery};
+use crate::tenant::Tenant;
+use crate::util;
+use crate::AppState;
+
+const DEFAULT_PAGE_SIZE: i64 = 100;
+const MAX_PAGE_SIZE: i64 = 1000;
+// Nesting allowed in a query, so one request cannot fan out into a search
+// history query per target of a large page many times over
+const MAX_DEPTH: usize = 6;
+// Budget of a query: every field returned costs 1, once per item of the
+// pages holding it, and the search history of a target costs a query of its
+// own. A page of 1000 targets fits, as do the searches of about a hundred of
+// them; their searches for the whole page do not.
+const MAX_COMPLEXITY: usize = 20_000;
+const SEARCHES_QUERY_COMPLEXITY: usize = 100;
+
+type GraphSchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+
+// Resolvers get the state and tenant of each request as context data
+static SCHEMA: LazyLock<GraphSchema> = LazyLock::new(|| {
+    Schema::build(QueryRoot, EmptyMutation, EmptySubscription)
+        .limit_depth(MAX_DEPTH)
+        .limit_complexity(MAX_COMPLEXITY)
+        .finish()
+});
+
+// Handler for POST /graphql - read-only queries over the targets, origins,
+// stats and search history of the tenant
+pub async fn graphql(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    request: GraphQLRequest,
+) -> GraphQLResponse {
+    SCHEMA
+        .execute(request.into_inner().data(state).data(tenant))
+        .await
+        .into()
+}
+
+// Database errors are logged, clients only learn the query failed
+fn database_error(e: sqlx::Error) -> async_graphql::Error {
+    tracing::error!(error = %e, "GraphQL query failed");
+    async_graphql::Error::new("database query failed")
+}
+
+fn page_size(first: Option<i32>) -> i64 {
+    first
+        .map(i64::from)
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE)
+}
+
+// A registered uuid of a collection, aggregated over its registrations
+#[derive(SimpleObject)]
+#[graphql(complex)]
+pub struct Target {
+    uuid: Uuid,
+    collection: String,
+    // Origins of its registrations, sorted
+    origins: Vec<String>,
+    // Metadata of the latest registration
+    metadata: Json<serde_json::Value>,
+    registrations: i64,
+    first_registered: String,
+    last_registered: String,
+}
+
+#[ComplexObject]
+impl Target {
+    // Past searches that matched this target, newest first
+    #[graphql(
+        complexity = "SEARCHES_QUERY_COMPLEXITY + page_size(first) as usize * child_complexity"
+    )]
+    async fn searches(
+        &self,
+        ctx: &Context<'_>,
+        first: Option<i32>,
+        after: Option<String>,
+    ) -> async_graphql::Result<Connection<String, RecordedSearch>> {
+        let query = SearchesQuery {
+            target_uuid: Some(self.uuid),
+            collection: Some(self.collection.clone()),
+            ..Default::default()
+        };
+        searches(ctx, query, first, after).await
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct CollectionStats {
+    name: String,
+    // Entries of the in-memory store
+    entries: usize,
+    // Targets evicted from memory, still in the database
+    evicted: usize,
+    memory_bytes: usize,
+}
+
+#[derive(SimpleObject)]
+pub struct Stats {
+    entries: usize,
+    collections: Vec<CollectionStats>,
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    // Targets ordered by collection and uuid; `origin` and `metadata` keep
+    // those with at least one matching registration
+    #[graphql(complexity = "page_size(first) as usize * child_complexity")]
+    async fn targets(
+        &self,
+        ctx: &Context<'_>,
+        collection: Option<String>,
+        origin: Option<String>,
+        metadata: Option<Json<serde_json::Value>>,
+        first: Option<i32>,
+        after: Option<String>,
+    ) -> async_graphql::Result<Connection<String, Target>> {
+        let after = match after.as_deref() {
+            Some(cursor) => Some(parse_target_cursor(cursor)?),
+            None => None,
+        };
+        let has_previous_page = after.is_some();
+        let limit = page_size(first);
+        let mut targets = fetch_targets(
+            ctx,
+            TargetFilter {
+                collection: collection.as_deref(),
+                origin: origin.as_deref(),
+                metadata: metadata.map(|metadata| metadata.0),
+                uuid: None,
+                after,
+            },
+            limit + 1,
+        )
+        .await?;
+        let has_next_page = targets.len() as i64 > limit;
+        targets.truncate(limit as usize);
+
+        let mut connection = Connection::new(has_previous_page, has_next_page);
+        connection.edges = targets
+            .into_iter()
+            .map(|target| Edge::new(format!("{}/{}", target.collection, target.uuid), target))
+            .collect();
+        Ok(connection)
+    }
+
+    // One target of a collection, the default one if unset
+    async fn target(
+        &self,
+        ctx: &Context<'_>,
+        uuid: Uuid,
+        collection: Option<String>,
+    ) -> async_graphql::Result<Option<Target>> {
+        let collection = collection.unwrap_or_else(|| DEFAULT_COLLECTION.to_string());
+        let targets = fetch_targets(
+            ctx,
+            TargetFilter {
+                collection: Some(&collection),
+                origin: None,
+                metadata: None,
+                uuid: Some(uuid),
+                after: None,
+            },
+            1,
+        )
+        .await?;
+        Ok(targets.into_iter().next())
+    }
+
+    // Registrations per origin, as on /stats/origins
+    async fn origins(
+        &self,
+        ctx: &Context<'_>,
+        collection: Option<String>,
+    ) -> async_graphql::Result<Vec<OriginStats>> {
+        let state = ctx.data::<AppState>()?;
+        let tenant = ctx.data::<Tenant>()?;
+        handlers::fetch_origin_stats(&state.read_pool, tenant.id(), collection.as_deref())
+            .await
+            .map_err(database_error)
+    }
+
+    // In-memory stores of the tenant, as on /metrics/
+    async fn stats(&self, ctx: &Context<'_>) -> async_graphql::Result<Stats> {
+        let state = ctx.data::<AppState>()?;
+        let tenant = ctx.data::<Tenant>()?;
+        let mut collections = Vec::new();
+        for (name, collection) in state.collections.list(tenant.id()) {
+            collections.push(CollectionStats {
+                name,
+                entries: collection.store.len(),
+                evicted: collection.store.evicted_count().await,
+                memory_bytes: collection.store.memory_bytes(),
+            });
+        }
+        Ok(Stats {
+            entries: collections
+                .iter()
+                .map(|collection| collection.entries)
+                .sum(),
+            collections,
+        })
+    }
+
+    // Past searches, newest first, as on /searches
+    #[allow(clippy::too_many_arguments)]
+    #[graphql(complexity = "page_size(first) as usize * child_complexity")]
+    async fn searches(
+        &self,
+        ctx: &Context<'_>,
+        target_uuid: Option<Uuid>,
+        collection: Option<String>,
+        source: Option<String>,
+        // Unix seconds, inclusive
+        since: Option<i64>,
+        until: Option<i64>,
+        first: Option<i32>,
+        after: Option<String>,
+    ) -> async_graphql::Result<Connection<String, RecordedSearch>> {
+        let query = SearchesQuery {
+            target_uuid,
+            collection,
+            source,
+            since,
+            until,
+            ..Default::default()
+        };
+        searches(ctx, query, first, after).await
+    }
+}
+
+struct TargetFilter<'a> {
+    collection: Option<&'a str>,
+    origin: Option<&'a str>,
+    metadata: Option<serde_json::Value>,
+    uuid: Option<Uuid>,
+    // Targets after this collection and uuid
+    after: Option<(String, Uuid)>,
+}
+
+// Cursors of targets are "collection/uuid"; names never contain a slash
+fn parse_target_cursor(cursor: &str) -> async_graphql::Result<(String, Uuid)> {
+    cursor
+        .split_once('/')
+        .and_then(|(collection, uuid)| Some((collection.to_string(), Uuid::parse_str(uuid).ok()?)))
+        .ok_or_else(|| async_graphql::Error::new("invalid cursor"))
+}
+
+// Read from the database, so targets evicted from memory are included
+async fn fetch_targets(
+    ctx: &Context<'_>,
+    filter: TargetFilter<'_>,
+    limit: i64,
+) -> async_graphql::Result<Vec<Target>> {
+    let state = ctx.data::<AppState>()?;
+    let tenant = ctx.data::<Tenant>()?;
+    let (after_collection, after_uuid) = filter.after.unzip();
+    let rows = sqlx::query(
+        r#"
+        SELECT collection, uuid,
+               array_agg(DISTINCT origin ORDER BY origin) AS origins,
+               (array_agg(metadata ORDER BY id DESC))[1] AS metadata,
+               COUNT(*) AS registrations,
+               EXTRACT(EPOCH FROM MIN(created_at))::BIGINT AS first_registered,
+               EXTRACT(EPOCH FROM MAX(created_at))::BIGINT AS last_registered
+        FROM targets
+        WHERE tenant = $1 AND deleted_at IS NULL
+          AND ($2::TEXT IS NULL OR collection = $2)
+          AND ($3::UUID IS NULL OR uuid = $3)
+          AND ($4::TEXT IS NULL OR (collection, uuid) > ($4, $5::UUID))
+        GROUP BY collection, uuid
+        HAVING ($6::TEXT IS NULL OR bool_or(origin = $6))
+           AND ($7::JSONB IS NULL OR bool_or(metadata @> $7))
+        ORDER BY collection, uuid
+        LIMIT $8
+        "#,
+    )
+    .bind(tenant.id())
+    .bind(filter.collection)
+    .bind(filter.uuid)
+    .bind(after_collection)
+    .bind(after_uuid)
+    .bind(filter.origin)
+    .bind(filter.metadata.map(sqlx::types::Json))
+    .bind(limit)
+    .fetch_all(&state.read_pool)
+    .await
+    .map_err(database_error)?;
+
+    rows.iter()
+        .map(|row| {
+            let metadata: sqlx::types::Json<serde_json::Value> = row.try_get("metadata")?;
+            Ok(Target {
+                uuid: row.try_get("uuid")?,
+                collection: row.try_get("collection")?,
+                origins: row.try_get("origins")?,
+                metadata: Json(metadata.0),
+                registrations: row.try_get("registrations")?,
+                first_registered: util::format_rfc3339(row.try_get("first_registered")?),
+                last_registered: util::format_rfc3339(row.try_get("last_registered")?),
+            })
+        })
+        .collect::<Result<Vec<Target>, sqlx::Error>>()
+        .map_err(database_error)
+}
+
+// A page of the search history; cursors are search ids
+async fn searches(
+    ctx: &Context<'_>,
+    mut query: SearchesQuery,
+    first: Option<i32>,
+    after: Option<String>,
+) -> async_graphql::Result<Connection<String, RecordedSearch>> {
+    let state = ctx.data::<AppState>()?;
+    let tenant = ctx.data::<Tenant>()?;
+    if state.search_history.is_none() {
+        return Err(async_graphql::Error::new("search history is disabled"));
+    }
+    query.before_id = match after.as_deref() {
+        Some(cursor) => Some(
+            cursor
+                .parse()
+                .map_err(|_| async_graphql::Error::new("invalid cursor"))?,
+        ),
+        None => None,
+    };
+    let limit = page_size(first);
+    let mut found = history::fetch_searches(&state.read_pool, tenant.id(), &query, limit + 1)
+        .await
+        .map_err(database_error)?;
+    let has_next_page = found.len() as i64 > limit;
+    found.truncate(limit as usize);
+
+    let mut connection = Connection::new(after.is_some(), has_next_page);
+    connection.edges = found
+        .into_iter()
+        .map(|search| Edge::new(search.id.to_string(), search))
+        .collect();
+    Ok(connection)
+}
@@ -0,0 +1,636 @@
+use axum::http::{HeaderMap, StatusCode};
+use std::net::SocketAddr;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status, Streaming};
+use uuid::Uuid;
+
+use crate::attributes::Gender;
+use crate::audit::{self, AuditEntry};
+use crate::auth::{self, ApiKey, Scope};
+use crate::calibration::Calibration;
+use crate::collections::DEFAULT_COLLECTION;
+use crate::enhance::EnhanceOptions;
+use crate::handlers::{self, Fusion, ImageInput, RegisterMode, RegisterPayload, SearchPayload};
+use crate::jwt::Claims;
+use crate::resync;
+use crate::sharding::ScoreQuery;
+use crate::store::{Metadata, SearchResults};
+use crate::tenant::Tenant;
+use crate::AppState;
+
+pub mod proto {
+    tonic::include_proto!("owlfacerec.v1");
+}
+
+use proto::face_recognition_server::{FaceRecognition, FaceRecognitionServer};
+use proto::{
+    Attributes, Emotion, Face, Mask, Match, Pose, QualityScores, RegisterRequest, RegisterResponse,
+    RegisteredFace, SearchRequest, SearchResponse, VerifyRequest, VerifyResponse,
+};
+
+// Searches of one stream waiting to be sent back to a slow client
+const STREAM_BUFFER: usize = 16;
+
+// gRPC counterpart of the REST API, sharing its state and pipeline
+pub struct GrpcService {
+    state: AppState,
+}
+
+impl GrpcService {
+    // Images travel as raw bytes, so messages are capped like HTTP bodies
+    // rather than at tonic's 4MB default
+    pub fn server(state: AppState, max_message_size: usize) -> FaceRecognitionServer<Self> {
+        FaceRecognitionServer::new(Self { state })
+            .max_decoding_message_size(max_message_size)
+            .max_encoding_message_size(max_message_size)
+    }
+}
+
+// Authenticated caller of an RPC; the metadata is checked like HTTP headers
+struct Caller {
+    tenant: Tenant,
+    // Rate limiting key, see `RateLimiter::client_id`
+    client: String,
+    // Caller recorded in the audit log, see `audit::actor`
+    actor: String,
+}
+
+async fn authorize<T>(
+    state: &AppState,
+    request: &Request<T>,
+    scope: Scope,
+) -> Result<Caller, Status> {
+    let headers: HeaderMap = request.metadata().clone().into_headers();
+    let identity = auth::identify(state, &headers).await.map_err(status)?;
+    auth::check_scope(&identity, scope).map_err(status)?;
+    let tenant = match identity.tenant {
+        Some(tenant) => tenant,
+        None => state.tenants.resolve(&headers).map_err(status)?,
+    };
+    Ok(Caller {
+        client: client_id(
+            state,
+            &headers,
+            identity.claims.as_ref(),
+            identity.key.as_ref(),
+            request.remote_addr(),
+        ),
+        actor: audit::actor(
+            identity.claims.as_ref(),
+            identity.key.as_ref(),
+            request.remote_addr(),
+        ),
+        tenant,
+    })
+}
+
+// Records an RPC in the audit log, when enabled; failures are recorded with
+// the HTTP status of the error
+async fn record(
+    state: &AppState,
+    caller: &Caller,
+    method: &str,
+    parameters: serde_json::Value,
+    outcome: Result<serde_json::Value, StatusCode>,
+) {
+    let Some(audit) = &state.audit else {
+        return;
+    };
+    let (status, result) = match outcome {
+        Ok(summary) => (StatusCode::OK, Some(summary)),
+        Err(status) => (status, None),
+    };
+    audit
+        .record(AuditEntry {
+            tenant: Some(caller.tenant.id().to_string()),
+            actor: caller.actor.clone(),
+            action: format!("grpc {}", method),
+            parameters,
+            status: status.as_u16(),
+            result,
+        })
+        .await;
+}
+
+fn client_id(
+    state: &AppState,
+    headers: &HeaderMap,
+    claims: Option<&Claims>,
+    key: Option<&ApiKey>,
+    peer: Option<SocketAddr>,
+) -> String {
+    state.rate_limiter.client_id(headers, claims, key, peer)
+}
+
+// Same per-client buckets as the REST inference endpoints
+fn limit(state: &AppState, caller: &Caller) -> Result<(), Status> {
+    state
+        .rate_limiter
+        .acquire(caller.client.clone())
+        .map_err(|retry_after| {
+            tracing::warn!(client = %caller.client, retry_after, "gRPC rate limit exceeded");
+            Status::resource_exhausted(format!("rate limited, retry after {}s", retry_after))
+        })
+}
+
+// Handler errors are HTTP status codes; map them to their gRPC equivalent
+fn status(code: StatusCode) -> Status {
+    let message = code.canonical_reason().unwrap_or("request failed");
+    match code {
+        StatusCode::BAD_REQUEST | StatusCode::PAYLOAD_TOO_LARGE => {
+            Status::invalid_argument(message)
+        }
+        // Failed liveness, pose or quality gate
+        StatusCode::UNPROCESSABLE_ENTITY => Status::failed_precondition(message),
+        StatusCode::UNAUTHORIZED => Status::unauthenticated(message),
+        StatusCode::FORBIDDEN => Status::permission_denied(message),
+        StatusCode::NOT_FOUND => Status::not_found(message),
+        StatusCode::CONFLICT => Status::already_exists(message),
+        StatusCode::METHOD_NOT_ALLOWED => Status::failed_precondition(message),
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::INSUFFICIENT_STORAGE => {
+            Status::resource_exhausted(message)
+        }
+        StatusCode::SERVICE_UNAVAILABLE => Status::unavailable(message),
+        // Inference over INFERENCE_TIMEOUT_MS
+        StatusCode::GATEWAY_TIMEOUT => Status::deadline_exceeded(message),
+        _ => Status::internal(message),
+    }
+}
+
+fn face(detected: crate::detect::DetectedFace) -> Face {
+    let [x1, y1, x2, y2] = detected.bbox;
+    Face {
+        x1,
+        y1,
+        x2,
+        y2,
+        score: detected.score,
+    }
+}
+
+fn quality(scores: crate::quality::QualityScores) -> QualityScores {
+    QualityScores {
+        sharpness: scores.sharpness,
+        brightness: scores.brightness,
+        contrast: scores.contrast,
+        face_size: scores.face_size,
+    }
+}
+
+fn pose(angles: crate::pose::Pose) -> Pose {
+    Pose {
+        yaw: angles.yaw,
+        pitch: angles.pitch,
+        roll: angles.roll,
+    }
+}
+
+fn mask(check: crate::mask::MaskCheck) -> Mask {
+    Mask {
+        masked: check.masked,
+        score: check.score,
+    }
+}
+
+fn collection_name(collection: &str) -> &str {
+    if collection.is_empty() {
+        DEFAULT_COLLECTION
+    } else {
+        collection
+    }
+}
+
+fn parse_uuid(value: &str) -> Result<Uuid, Status> {
+    Uuid::parse_str(value).map_err(|_| Status::invalid_argument("invalid target_uuid"))
+}
+
+// Metadata is a JSON object in a string field; empty means none
+fn parse_metadata(value: &str) -> Result<Option<Metadata>, Status> {
+    if value.trim().is_empty() {
+        return Ok(None);
+    }
+    serde_json::from_str(value)
+        .map(Some)
+        .map_err(|_| Status::invalid_argument("metadata_json must be a JSON object"))
+}
+
+// Search options of a message; the image stays in `request.image`
+pub(crate) fn search_payload(request: &SearchRequest) -> Result<SearchPayload, Status> {
+    Ok(SearchPayload {
+        image_base64: String::new(),
+        embedding: None,
+        threshold: request.threshold,
+        limit: request.limit.map(|limit| limit as usize),
+        enhance: EnhanceOptions::default(),
+        group_by_uuid: request.group_by_uuid,
+        hit_counts: request.hit_counts,
+        origins: Some(request.origins.clone()),
+        metadata: parse_metadata(&request.metadata_json)?,
+        exclude_uuids: Some(
+            request
+                .exclude_uuids
+                .iter()
+                .map(|uuid| {
+                    Uuid::parse_str(uuid)
+                        .map_err(|_| Status::invalid_argument("invalid uuid in exclude_uuids"))
+                })
+                .collect::<Result<_, _>>()?,
+        ),
+        exclude_origins: Some(request.exclude_origins.clone()),
+        time_budget_ms: request.time_budget_ms,
+        nprobe: None,
+        attributes: request.attributes,
+        emotion: request.emotion,
+        return_crops: request.return_crops,
+        return_embedding: false,
+        source: None,
+        images_base64: Vec::new(),
+        fusion: Fusion::default(),
+        include_archived: false,
+    })
+}
+
+async fn run_search(
+    state: &AppState,
+    caller: &Caller,
+    method: &str,
+    request: SearchRequest,
+) -> Result<SearchResponse, Status> {
+    let name = collection_name(&request.collection);
+    let payload = search_payload(&request)?;
+    let found = handlers::run_search_image(
+        state,
+        &caller.tenant,
+        name,
+        &payload,
+        ImageInput::Bytes(&request.image),
+    )
+    .await;
+    record(
+        state,
+        caller,
+        method,
+        serde_json::json!({ "collection": name }),
+        found
+            .as_ref()
+            .map(|found| handlers::search_summary(name, found))
+            .map_err(|e| *e),
+    )
+    .await;
+    let found = found.map_err(status)?;
+    Ok(search_response(
+        &found,
+        &payload,
+        state.calibration.as_deref(),
+    ))
+}
+
+pub(crate) fn search_response(
+    found: &SearchResults,
+    payload: &SearchPayload,
+    calibration: Option<&Calibration>,
+) -> SearchResponse {
+    let mut response = face_response(found, payload, calibration);
+    // With several faces each gets its own entry, the largest one included
+    if !found.other_faces.is_empty() {
+        response.faces = std::iter::once(found)
+            .chain(&found.other_faces)
+            .map(|face| face_response(face, payload, calibration))
+            .collect();
+    }
+    response
+}
+
+fn face_response(
+    found: &SearchResults,
+    payload: &SearchPayload,
+    calibration: Option<&Calibration>,
+) -> SearchResponse {
+    let results = found
+        .matches
+        .iter()
+        .map(|found| Match {
+            target_uuid: found.uuid.to_string(),
+            similarity: found.similarity,
+            match_probability: calibration
+                .map(|calibration| calibration.probability(found.similarity)),
+            origin: found.origin.clone(),
+            metadata_json: if found.metadata.is_empty() {
+                String::new()
+            } else {
+                serde_json::Value::Object(found.metadata.clone()).to_string()
+            },
+            hits: (payload.group_by_uuid && payload.hit_counts).then_some(found.hits),
+        })
+        .collect();
+    SearchResponse {
+        results,
+        partial: found.partial,
+        face: found.face.map(face),
+        liveness: found.liveness,
+        pose: found.pose.map(pose),
+        mask: found.mask.map(mask),
+        attributes: found.attributes.map(|attributes| Attributes {
+            age: attributes.age,
+            age_min: attributes.age_range[0],
+            age_max: attributes.age_range[1],
+            gender: match attributes.gender {
+                Gender::Female => "female",
+                Gender::Male => "male",
+            }
+            .to_string(),
+            gender_confidence: attributes.gender_confidence,
+        }),
+        emotion: found.emotion.as_ref().map(|emotion| Emotion {
+            emotion: emotion.emotion.clone(),
+            confidence: emotion.confidence,
+            scores: emotion
+                .scores
+                .iter()
+                .map(|score| (score.emotion.clone(), score.probability))
+                .collect(),
+        }),
+        crop: found
+            .crop
+            .as_ref()
+            .map(|crop| crop.0.clone())
+            .unwrap_or_default(),
+        faces: Vec::new(),
+    }
+}
+
+// Registration options of a message; the image stays in `request.image`
+pub(crate) fn register_payload(request: &RegisterRequest) -> Result<RegisterPayload, Status> {
+    Ok(RegisterPayload {
+        // Left empty with register_all_faces
+        target_uuid: if request.target_uuid.is_empty() {
+            Uuid::nil()
+        } else {
+            parse_uuid(&request.target_uuid)?
+        },
+        image_base64: String::new(),
+        embedding: None,
+        origin: request.origin.clone(),
+        metadata: parse_metadata(&request.metadata_json)?.unwrap_or_default(),
+        register_all_faces: request.register_all_faces,
+        face_index: request.face_index.map(|index| index as usize),
+        return_crops: request.return_crops,
+        return_embedding: false,
+        expires_at: request.expires_at,
+        mode: if request.mode.is_empty() {
+            RegisterMode::Append
+        } else {
+            request
+                .mode
+                .parse()
+                .map_err(|e: String| Status::invalid_argument(e))?
+        },
+        reject_if_similar_above: None,
+    })
+}
+
+pub(crate) fn register_response(registered: handlers::RegisterResponse) -> RegisterResponse {
+    let analysis = registered.analysis;
+    RegisterResponse {
+        liveness: analysis.liveness,
+        pose: analysis.pose.map(pose),
+        mask: analysis.mask.map(mask),
+        quality: analysis.quality.map(quality),
+        face: analysis.face.map(face),
+        crop: analysis.crop.map(|crop| crop.0).unwrap_or_default(),
+        faces: registered
+            .faces
+            .into_iter()
+            .map(|registered| RegisteredFace {
+                target_uuid: registered.target_uuid.to_string(),
+                face: registered.analysis.face.map(face),
+                liveness: registered.analysis.liveness,
+                quality: registered.analysis.quality.map(quality),
+                pose: registered.analysis.pose.map(pose),
+                mask: registered.analysis.mask.map(mask),
+                crop: registered
+                    .analysis
+                    .crop
+                    .map(|crop| crop.0)
+                    .unwrap_or_default(),
+            })
+            .collect(),
+    }
+}
+
+// 1:1 check of a message's image against its target, with the threshold it
+// was held to
+pub(crate) async fn verify_target(
+    state: &AppState,
+    tenant: &Tenant,
+    request: &VerifyRequest,
+) -> Result<(VerifyResponse, f32), StatusCode> {
+    let Ok(target_uuid) = Uuid::parse_str(&request.target_uuid) else {
+        tracing::warn!("Received verification with an invalid target_uuid");
+        return Err(StatusCode::BAD_REQUEST);
+    };
+    if request.image.is_empty() {
+        tracing::warn!("Received verification with empty image");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let name = collection_name(&request.collection);
+    let Some(collection) = state.collections.get(tenant.id(), name) else {
+        tracing::warn!(collection = %name, "Received verification for unknown collection");
+        return Err(StatusCode::NOT_FOUND);
+    };
+
+    // A target of another gallery shard is scored by that shard
+    let sharding = state
+        .sharding
+        .as_ref()
+        .filter(|sharding| !sharding.owns(&target_uuid));
+    // An evicted target is paged back in from the database to be scored
+    if sharding.is_none() && collection.store.is_evicted(&target_uuid).await {
+        let key = (tenant.id().to_string(), name.to_string(), target_uuid);
+        if let Err(e) = resync::reload(state, &key).await {
+            tracing::error!(%target_uuid, error = %e, "Failed to page in evicted target");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+    let embedding_vec = handlers::get_embedding(
+        ImageInput::Bytes(&request.image),
+        state,
+        EnhanceOptions::default(),
+    )
+    .await?;
+    let similarity = match sharding {
+        Some(sharding) => {
+            let query = ScoreQuery::new(tenant.id(), name, target_uuid, &embedding_vec);
+            sharding.score_peer(&query).await.map_err(|e| {
+                tracing::error!(%target_uuid, error = %e, "Failed to verify against the owning shard");
+                StatusCode::BAD_GATEWAY
+            })?
+        }
+        None => {
+            collection
+                .store
+                .score_uuid(&target_uuid, &embedding_vec)
+                .await
+        }
+    };
+    let Some(similarity) = similarity else {
+        tracing::warn!(%target_uuid, "Received verification for unknown target");
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let threshold = request
+        .threshold
+        .or(collection.settings.threshold)
+        .unwrap_or(state.tunables.default_threshold());
+    let response = VerifyResponse {
+        is_match: similarity >= threshold,
+        similarity,
+        match_probability: state
+            .calibration
+            .as_deref()
+            .map(|calibration| calibration.probability(similarity)),
+    };
+    Ok((response, threshold))
+}
+
+// Result summary of a verification, for the audit log
+pub(crate) fn verify_summary(response: &VerifyResponse, threshold: f32) -> serde_json::Value {
+    serde_json::json!({
+        "similarity": response.similarity,
+        "threshold": threshold,
+        "is_match": response.is_match,
+    })
+}
+
+#[tonic::async_trait]
+impl FaceRecognition for GrpcService {
+    async fn register(
+        &self,
+        request: Request<RegisterRequest>,
+    ) -> Result<Response<RegisterResponse>, Status> {
+        let caller = authorize(&self.state, &request, Scope::Register).await?;
+        if !self.state.run_mode.is_writable() {
+            tracing::warn!("Rejected gRPC registration on read-only instance");
+            return Err(Status::failed_precondition("read-only instance"));
+        }
+        limit(&self.state, &caller)?;
+
+        let request = request.into_inner();
+        let payload = register_payload(&request)?;
+        let name = collection_name(&request.collection);
+        let registered = handlers::register_into(
+            &self.state,
+            &caller.tenant,
+            name,
+            &payload,
+            ImageInput::Bytes(&request.image),
+        )
+        .await;
+        record(
+            &self.state,
+            &caller,
+            "Register",
+            serde_json::json!({ "collection": name }),
+            registered
+                .as_ref()
+                .map(|registered| handlers::register_summary(name, &payload, registered))
+                .map_err(|e| e.status),
+        )
+        .await;
+        let registered = registered.map_err(|e| match e.quality {
+            // The failed checks tell the client what to fix
+            Some(report) => Status::failed_precondition(format!(
+                "quality check failed: {}",
+                report.failed.join(", ")
+            )),
+            None => status(e.status),
+        })?;
+        Ok(Response::new(register_response(registered)))
+    }
+
+    async fn search(
+        &self,
+        request: Request<SearchRequest>,
+    ) -> Result<Response<SearchResponse>, Status> {
+        let caller = authorize(&self.state, &request, Scope::Search).await?;
+        limit(&self.state, &caller)?;
+        let response = run_search(&self.state, &caller, "Search", request.into_inner()).await?;
+        tracing::info!(
+            results_count = response.results.len(),
+            partial = response.partial,
+            "gRPC search successful"
+        );
+        Ok(Response::new(response))
+    }
+
+    async fn verify(
+        &self,
+        request: Request<VerifyRequest>,
+    ) -> Result<Response<VerifyResponse>, Status> {
+        let caller = authorize(&self.state, &request, Scope::Search).await?;
+        limit(&self.state, &caller)?;
+
+        let request = request.into_inner();
+        let name = collection_name(&request.collection);
+        let parameters =
+            serde_json::json!({ "collection": name, "target_uuid": request.target_uuid });
+        let verified = verify_target(&self.state, &caller.tenant, &request).await;
+        record(
+            &self.state,
+            &caller,
+            "Verify",
+            parameters,
+            verified
+                .as_ref()
+                .map(|(response, threshold)| verify_summary(response, *threshold))
+                .map_err(|e| *e),
+        )
+        .await;
+        let (response, threshold) = verified.map_err(status)?;
+
+        tracing::info!(
+            uuid = %request.target_uuid,
+            similarity = response.similarity,
+            threshold,
+            "gRPC verification completed"
+        );
+        Ok(Response::new(response))
+    }
+
+    type SearchStreamStream = ReceiverStream<Result<SearchResponse, Status>>;
+
+    // Authenticated once when the stream opens; every search still takes a
+    // rate limit token, and the first failed search ends the stream with its status
+    async fn search_stream(
+        &self,
+        request: Request<Streaming<SearchRequest>>,
+    ) -> Result<Response<Self::SearchStreamStream>, Status> {
+        let caller = authorize(&self.state, &request, Scope::Search).await?;
+        let mut inbound = request.into_inner();
+        let state = self.state.clone();
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+
+        tokio::spawn(async move {
+            loop {
+                let request = match inbound.message().await {
+                    Ok(Some(request)) => request,
+                    Ok(None) => break,
+                    Err(e) => {
+                        tracing::debug!(error = %e, "gRPC search stream closed by client");
+                        break;
+                    }
+                };
+                let response = match limit(&state, &caller) {
+                    Ok(()) => run_search(&state, &caller, "SearchStream", request).await,
+                    Err(e) => Err(e),
+                };
+                let failed = response.is_err();
+                if sender.send(response).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+}
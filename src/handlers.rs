@@ -1,228 +1,2709 @@
-use axum::{extract::State, http::StatusCode, Json};
+use async_graphql::SimpleObject;
+use axum::{
+    extract::{Extension, Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
 use base64::{engine::general_purpose, Engine as _};
-use image::{DynamicImage, GenericImageView, ImageBuffer, Rgb};
-use ndarray::{Array, Ix4};
-use ort::{inputs, session::Session, session::SessionOutputs, value::Value};
+use image::{DynamicImage, GenericImageView};
+use ndarray::{s, Array, Ix4};
 use serde::{Deserialize, Serialize};
-use sqlx;
-use std::sync::Arc;
-use std::time::Instant;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+use tracing::Instrument;
 use uuid::Uuid;
 
+pub use crate::api::{Fusion, RegisterMode, RegisterPayload, SearchPayload};
+use crate::archive;
+use crate::attributes::{AttributeModel, Attributes};
+use crate::audit;
+use crate::backpressure::Slot;
+use crate::calibration::Calibration;
+use crate::collections::{Collection, CollectionQuery};
+use crate::detect::{self, DetectedFace, FaceCrop, FaceDetector};
+use crate::embedder;
+use crate::emotion::{Emotion, EmotionModel};
+use crate::enhance::{self, EnhanceOptions};
+use crate::flags;
+use crate::formats::{self, ImageLimits, TooLarge};
+use crate::history::{self, SearchEvent};
+use crate::key_usage;
+use crate::liveness::Liveness;
+use crate::mask::{MaskCheck, MaskDetector};
+use crate::notify::Change;
+use crate::objects::ObjectStore;
+use crate::pose::{Pose, PoseEstimator, PoseMode};
+use crate::quality::{self, QualityReport, QualityScores};
+use crate::resync;
+use crate::sharding::ShardQuery;
+use crate::store::{
+    self, LockStats, Metadata, NewEmbedding, SearchMatch, SearchOptions, SearchResults,
+};
+use crate::target_store::NewTargets;
+use crate::telemetry;
+use crate::tenant::Tenant;
+use crate::util;
+use crate::write_behind::QueuedTargets;
 use crate::AppState; // Import AppState from main.rs
 
 // --- Helper function for Image Processing and Embedding Extraction ---
 
-async fn get_embedding_from_base64(
+// Most faces handled per image when all of them are selected
+const MAX_FACES: usize = 32;
+
+// Largest JSON body accepted, base64 images included; also the largest gRPC
+// message, where images travel as raw bytes
+pub const DEFAULT_MAX_BODY_BYTES: usize = 16 * 1024 * 1024;
+
+// Encoded image as received: base64 from the JSON API, raw bytes from gRPC
+#[derive(Clone, Copy)]
+pub(crate) enum ImageInput<'a> {
+    Base64(&'a str),
+    Bytes(&'a [u8]),
+}
+
+impl ImageInput<'_> {
+    pub(crate) fn is_empty(&self) -> bool {
+        match self {
+            ImageInput::Base64(image_base64) => image_base64.trim().is_empty(),
+            ImageInput::Bytes(bytes) => bytes.is_empty(),
+        }
+    }
+
+    // Hex SHA-256 of the image bytes, the same whichever way they arrived
+    pub(crate) fn sha256(&self) -> String {
+        let digest = match self {
+            ImageInput::Base64(image_base64) => {
+                match general_purpose::STANDARD.decode(image_base64) {
+                    Ok(bytes) => Sha256::digest(bytes),
+                    Err(_) => Sha256::digest(image_base64.as_bytes()),
+                }
+            }
+            ImageInput::Bytes(bytes) => Sha256::digest(bytes),
+        };
+        hex::encode(digest)
+    }
+
+    // Copy that can move to the blocking thread of the pipeline
+    fn to_owned_image(self) -> OwnedImage {
+        match self {
+            ImageInput::Base64(image_base64) => OwnedImage::Base64(image_base64.to_string()),
+            ImageInput::Bytes(bytes) => OwnedImage::Bytes(bytes.to_vec()),
+        }
+    }
+}
+
+enum OwnedImage {
+    Base64(String),
+    Bytes(Vec<u8>),
+}
+
+impl OwnedImage {
+    fn as_input(&self) -> ImageInput<'_> {
+        match self {
+            OwnedImage::Base64(image_base64) => ImageInput::Base64(image_base64),
+            OwnedImage::Bytes(bytes) => ImageInput::Bytes(bytes),
+        }
+    }
+}
+
+// Hex SHA-256 of the little-endian bytes of an embedding
+fn embedding_sha256(embedding: &[f32]) -> String {
+    let mut hasher = Sha256::new();
+    for value in embedding {
+        hasher.update(value.to_le_bytes());
+    }
+    hex::encode(hasher.finalize())
+}
+
+pub(crate) async fn get_embedding_from_base64(
     image_base64: &str,
-    onnx_session: &Arc<Session>,
+    state: &AppState,
+    enhance: EnhanceOptions,
+) -> Result<Vec<f32>, StatusCode> {
+    get_embedding(ImageInput::Base64(image_base64), state, enhance).await
+}
+
+pub(crate) async fn get_embedding(
+    image: ImageInput<'_>,
+    state: &AppState,
+    enhance: EnhanceOptions,
 ) -> Result<Vec<f32>, StatusCode> {
+    let (embedding, _) =
+        get_embedding_analyzed(image, state, enhance, Estimates::default()).await?;
+    Ok(embedding)
+}
+
+// Faces of an image to embed, when a face detector is configured
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum FaceSelection {
+    Largest,
+    // Position among the detected faces, largest first
+    Index(usize),
+    All,
+}
+
+// Estimates that cost extra work per image, computed only when asked for
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Estimates {
+    pub quality: bool,
+    pub attributes: bool,
+    pub emotion: bool,
+    // PNG of the model input, for debugging matches
+    pub crop: bool,
+}
+
+// Checks run on a face as received, before enhancement (which would hide
+// replay artifacts and blur)
+#[derive(Clone, Default, Serialize)]
+pub struct Analysis {
+    // Set when a face detector is configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub face: Option<DetectedFace>,
+    // Set when a liveness model is configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub liveness: Option<f32>,
+    // Set when a pose model is configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pose: Option<Pose>,
+    // Set when a mask model is configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mask: Option<MaskCheck>,
+    // Set when quality assessment was requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quality: Option<QualityScores>,
+    // Set when attribute estimation was requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attributes: Option<Attributes>,
+    // Set when emotion estimation was requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub emotion: Option<Emotion>,
+    // Set when the crop was requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crop: Option<FaceCrop>,
+    // Set when the embedding was requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub embedding: Option<Vec<f32>>,
+}
+
+impl Analysis {
+    // Back to the pixels of the image as sent, from those of its downscaled copy
+    pub(crate) fn rescale(&mut self, factor: f32) {
+        if factor == 1.0 {
+            return;
+        }
+        self.face = self.face.map(|face| face.scaled(factor));
+        if let Some(quality) = &mut self.quality {
+            quality.face_size = (quality.face_size as f32 * factor).round() as u32;
+        }
+    }
+}
+
+// Embedding and analysis of the largest face (or of the whole image without a detector)
+pub(crate) async fn get_embedding_analyzed(
+    image: ImageInput<'_>,
+    state: &AppState,
+    enhance: EnhanceOptions,
+    estimates: Estimates,
+) -> Result<(Vec<f32>, Analysis), StatusCode> {
+    let mut faces =
+        get_face_embeddings(image, state, enhance, estimates, FaceSelection::Largest).await?;
+    faces.pop().ok_or(StatusCode::BAD_REQUEST)
+}
+
+pub(crate) async fn get_face_embeddings(
+    image: ImageInput<'_>,
+    state: &AppState,
+    enhance: EnhanceOptions,
+    estimates: Estimates,
+    selection: FaceSelection,
+) -> Result<Vec<(Vec<f32>, Analysis)>, StatusCode> {
+    let slot = match &state.inference_queue {
+        Some(queue) => Some(queue.acquire().await?),
+        None => None,
+    };
+    let image = image.to_owned_image();
+    run_bounded(state, slot, move |state| {
+        let (frames, scale) = decode_frames(image.as_input(), &state.image_limits)?;
+        let img = select_frame(frames, state)?;
+        let detections = locate_faces(&img, state, selection)?;
+        let mut faces = Vec::with_capacity(detections.len());
+        for detection in detections {
+            let Some(face) = detection else {
+                // Without a detector the whole image is taken to be the face
+                let mut analysis = analyze_image(&img, None, state, estimates)?;
+                analysis.rescale(scale);
+                let (embedding, crop) = embed_image(img, state, enhance, estimates.crop)?;
+                analysis.crop = crop;
+                return Ok(vec![(embedding, analysis)]);
+            };
+            let mut analysis = analyze_image(&img, Some(face), state, estimates)?;
+            analysis.rescale(scale);
+            let (embedding, crop) =
+                embed_image(detect::align(&img, &face), state, enhance, estimates.crop)?;
+            analysis.crop = crop;
+            faces.push((embedding, analysis));
+        }
+        Ok(faces)
+    })
+    .await
+}
+
+// Embeddings of the largest faces of several images, run through the model as
+// one batch under a single inference slot. An image that cannot be embedded
+// (undecodable, no face...) fails on its own; server errors fail them all.
+pub(crate) async fn get_embeddings_batch(
+    images_base64: &[String],
+    state: &AppState,
+    enhance: EnhanceOptions,
+) -> Result<Vec<Result<Vec<f32>, StatusCode>>, StatusCode> {
+    let slot = match &state.inference_queue {
+        Some(queue) => Some(queue.acquire().await?),
+        None => None,
+    };
+    let images_base64 = images_base64.to_vec();
+    run_bounded(state, slot, move |state| {
+        let mut faces = Vec::with_capacity(images_base64.len());
+        // Position of each image's face in `faces`
+        let mut located = Vec::with_capacity(images_base64.len());
+        for image_base64 in &images_base64 {
+            match largest_face(ImageInput::Base64(image_base64), state) {
+                Ok(face) => {
+                    located.push(Ok(faces.len()));
+                    faces.push(face);
+                }
+                Err(status) if status.is_server_error() => return Err(status),
+                Err(status) => located.push(Err(status)),
+            }
+        }
+        let mut embeddings = if faces.is_empty() {
+            Vec::new()
+        } else {
+            embed_images(faces, state, enhance)?
+        };
+        Ok(located
+            .into_iter()
+            .map(|position| position.map(|index| std::mem::take(&mut embeddings[index])))
+            .collect())
+    })
+    .await
+}
+
+// The largest face of an image, aligned, or the whole image without a detector
+fn largest_face(image: ImageInput<'_>, state: &AppState) -> Result<DynamicImage, StatusCode> {
+    let (frames, _) = decode_frames(image, &state.image_limits)?;
+    let img = select_frame(frames, state)?;
+    match locate_faces(&img, state, FaceSelection::Largest)?.pop() {
+        Some(Some(face)) => Ok(detect::align(&img, &face)),
+        Some(None) => Ok(img),
+        None => Err(StatusCode::BAD_REQUEST),
+    }
+}
+
+// Embedding of a stored enrollment crop, which is already the aligned model
+// input, so the detector is skipped
+pub(crate) async fn embed_crop(png: &[u8], state: &AppState) -> Result<Vec<f32>, StatusCode> {
+    let png = png.to_vec();
+    run_bounded(state, None, move |state| {
+        let img = decode_image(ImageInput::Bytes(&png), &state.image_limits)?;
+        let (embedding, _) = embed_image(img, state, EnhanceOptions::default(), false)?;
+        Ok(embedding)
+    })
+    .await
+}
+
+// The selected faces, or a single None (the whole image) without a detector
+fn locate_faces(
+    img: &DynamicImage,
+    state: &AppState,
+    selection: FaceSelection,
+) -> Result<Vec<Option<DetectedFace>>, StatusCode> {
+    let Some(detector) = &state.detector else {
+        if !matches!(selection, FaceSelection::Largest) {
+            tracing::warn!(selection = ?selection, "Face selection requested but no DETECTOR_MODEL_PATH is configured");
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        return Ok(vec![None]);
+    };
+
+    let detect_start = Instant::now();
+    let mut faces = tracing::info_span!("detect")
+        .in_scope(|| detector.detect(img))
+        .map_err(|e| {
+            tracing::error!(error = %e, "Face detection failed");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    telemetry::observe_stage(telemetry::STAGE_DETECT, detect_start.elapsed());
+    tracing::debug!(faces = faces.len(), "Faces detected");
+
+    match selection {
+        FaceSelection::Largest => faces.truncate(1),
+        FaceSelection::Index(index) => {
+            if index >= faces.len() {
+                tracing::warn!(
+                    index,
+                    faces = faces.len(),
+                    "Selected face index out of range"
+                );
+                return Err(StatusCode::BAD_REQUEST);
+            }
+            faces = vec![faces[index]];
+        }
+        // Crowd shots are capped, every face costs a full pipeline run
+        FaceSelection::All => faces.truncate(MAX_FACES),
+    }
+    if faces.is_empty() {
+        tracing::warn!("No face detected in image");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    Ok(faces.into_iter().map(Some).collect())
+}
+
+// Checks of the box of `face` within `img`, or of the whole image without a detection
+fn analyze_image(
+    img: &DynamicImage,
+    face: Option<DetectedFace>,
+    state: &AppState,
+    estimates: Estimates,
+) -> Result<Analysis, StatusCode> {
+    let region;
+    let img = match face {
+        Some(face) => {
+            let (x, y, width, height) = face.region(img);
+            region = img.crop_imm(x, y, width, height);
+            &region
+        }
+        None => img,
+    };
+    let liveness = match &state.liveness {
+        Some(liveness) => Some(score_liveness(liveness, img)?),
+        None => None,
+    };
+    let pose = match &state.pose {
+        Some(pose) => Some(estimate_pose(pose, img)?),
+        None => None,
+    };
+    let mask = match &state.mask {
+        Some(mask) => Some(check_mask(mask, img)?),
+        None => None,
+    };
+    let quality = estimates.quality.then(|| {
+        let scores = quality::assess(img);
+        tracing::debug!(scores = ?scores, "Image quality assessed");
+        scores
+    });
+    let attributes = if estimates.attributes {
+        let model = state.attributes.as_ref().ok_or_else(|| {
+            tracing::warn!("Attributes requested but no ATTRIBUTES_MODEL_PATH is configured");
+            StatusCode::BAD_REQUEST
+        })?;
+        Some(estimate_attributes(model, img)?)
+    } else {
+        None
+    };
+    let emotion = if estimates.emotion {
+        let model = state.emotion.as_ref().ok_or_else(|| {
+            tracing::warn!("Emotion requested but no EMOTION_MODEL_PATH is configured");
+            StatusCode::BAD_REQUEST
+        })?;
+        Some(estimate_emotion(model, img)?)
+    } else {
+        None
+    };
+    Ok(Analysis {
+        face,
+        liveness,
+        pose,
+        mask,
+        quality,
+        attributes,
+        emotion,
+        // Set once the face is embedded
+        crop: None,
+        embedding: None,
+    })
+}
+
+fn decode_image(image: ImageInput<'_>, limits: &ImageLimits) -> Result<DynamicImage, StatusCode> {
+    let first_frame = ImageLimits {
+        max_frames: 1,
+        ..*limits
+    };
+    let (mut frames, _) = decode_frames(image, &first_frame)?;
+    Ok(frames.swap_remove(0))
+}
+
+// The frames of an animation, or the image alone, downscaled to the working
+// dimension; with the factor back to the pixels of the image as sent
+fn decode_frames(
+    image: ImageInput<'_>,
+    limits: &ImageLimits,
+) -> Result<(Vec<DynamicImage>, f32), StatusCode> {
     // 1. Decode Base64
-    let image_bytes = general_purpose::STANDARD
-        .decode(image_base64)
+    let decode_start = Instant::now();
+    let decoded = tracing::info_span!("decode").in_scope(|| {
+        let decoded;
+        let image_bytes: &[u8] = match image {
+            ImageInput::Base64(image_base64) => {
+                decoded = general_purpose::STANDARD
+                    .decode(image_base64)
+                    .map_err(|e| {
+                        tracing::error!(error = %e, "Failed to decode base64 image");
+                        StatusCode::BAD_REQUEST
+                    })?;
+                tracing::debug!(image_size = decoded.len(), "Base64 decoded");
+                &decoded
+            }
+            ImageInput::Bytes(bytes) => bytes,
+        };
+
+        // 2. Load Image from bytes; large photos are brought down to the
+        // working dimension as they are decoded, before detection
+        let (frames, scale) = formats::load_frames(image_bytes, limits).map_err(|e| {
+            if e.is::<TooLarge>() {
+                tracing::warn!(error = %e, "Rejecting oversized image");
+                return StatusCode::UNPROCESSABLE_ENTITY;
+            }
+            tracing::error!(error = %e, "Failed to load image from bytes");
+            StatusCode::BAD_REQUEST
+        })?;
+        tracing::debug!(dims = ?frames[0].dimensions(), frames = frames.len(), scale, "Image loaded");
+        Ok::<_, StatusCode>((frames, scale))
+    })?;
+    telemetry::observe_stage(telemetry::STAGE_DECODE, decode_start.elapsed());
+    Ok(decoded)
+}
+
+// The frame of an animation whose largest face is the sharpest (the sharpest
+// frame without a detector); the first frame when none has a face, so the
+// request fails detection like a still image would
+fn select_frame(
+    mut frames: Vec<DynamicImage>,
+    state: &AppState,
+) -> Result<DynamicImage, StatusCode> {
+    if frames.len() == 1 {
+        return Ok(frames.swap_remove(0));
+    }
+    let mut best: Option<(usize, f32)> = None;
+    tracing::info_span!("select_frame").in_scope(|| {
+        for (index, frame) in frames.iter().enumerate() {
+            let sharpness = match &state.detector {
+                Some(detector) => {
+                    let faces = detector.detect(frame).map_err(|e| {
+                        tracing::error!(error = %e, "Face detection failed");
+                        StatusCode::INTERNAL_SERVER_ERROR
+                    })?;
+                    let Some(face) = faces.first() else {
+                        continue;
+                    };
+                    let (x, y, width, height) = face.region(frame);
+                    quality::assess(&frame.crop_imm(x, y, width, height)).sharpness
+                }
+                None => quality::assess(frame).sharpness,
+            };
+            if best.map_or(true, |(_, best)| sharpness > best) {
+                best = Some((index, sharpness));
+            }
+        }
+        Ok::<_, StatusCode>(())
+    })?;
+    let index = best.map_or(0, |(index, _)| index);
+    tracing::debug!(
+        frame = index,
+        frames = frames.len(),
+        "Animation frame selected"
+    );
+    Ok(frames.swap_remove(index))
+}
+
+fn score_liveness(liveness: &Liveness, img: &DynamicImage) -> Result<f32, StatusCode> {
+    let liveness_start = Instant::now();
+    let score = tracing::info_span!("liveness")
+        .in_scope(|| liveness.score(img))
+        .map_err(|e| {
+            tracing::error!(error = %e, "Liveness scoring failed");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    telemetry::observe_stage(telemetry::STAGE_LIVENESS, liveness_start.elapsed());
+    tracing::debug!(score, "Liveness scored");
+    Ok(score)
+}
+
+fn estimate_pose(estimator: &PoseEstimator, img: &DynamicImage) -> Result<Pose, StatusCode> {
+    let pose_start = Instant::now();
+    let pose = tracing::info_span!("pose")
+        .in_scope(|| estimator.estimate(img))
         .map_err(|e| {
-            tracing::error!(error = %e, "Failed to decode base64 image");
+            tracing::error!(error = %e, "Pose estimation failed");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    telemetry::observe_stage(telemetry::STAGE_POSE, pose_start.elapsed());
+    tracing::debug!(pose = ?pose, "Pose estimated");
+    Ok(pose)
+}
+
+fn check_mask(detector: &MaskDetector, img: &DynamicImage) -> Result<MaskCheck, StatusCode> {
+    let mask_start = Instant::now();
+    let check = tracing::info_span!("mask")
+        .in_scope(|| detector.check(img))
+        .map_err(|e| {
+            tracing::error!(error = %e, "Mask check failed");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    telemetry::observe_stage(telemetry::STAGE_MASK, mask_start.elapsed());
+    tracing::debug!(check = ?check, "Mask checked");
+    Ok(check)
+}
+
+fn estimate_attributes(
+    model: &AttributeModel,
+    img: &DynamicImage,
+) -> Result<Attributes, StatusCode> {
+    let attributes_start = Instant::now();
+    let attributes = tracing::info_span!("attributes")
+        .in_scope(|| model.estimate(img))
+        .map_err(|e| {
+            tracing::error!(error = %e, "Attribute estimation failed");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    telemetry::observe_stage(telemetry::STAGE_ATTRIBUTES, attributes_start.elapsed());
+    tracing::debug!(attributes = ?attributes, "Attributes estimated");
+    Ok(attributes)
+}
+
+fn estimate_emotion(model: &EmotionModel, img: &DynamicImage) -> Result<Emotion, StatusCode> {
+    let emotion_start = Instant::now();
+    let emotion = tracing::info_span!("emotion")
+        .in_scope(|| model.estimate(img))
+        .map_err(|e| {
+            tracing::error!(error = %e, "Emotion estimation failed");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    telemetry::observe_stage(telemetry::STAGE_EMOTION, emotion_start.elapsed());
+    tracing::debug!(emotion = %emotion.emotion, confidence = emotion.confidence, "Emotion estimated");
+    Ok(emotion)
+}
+
+// The image pipeline of a request (decoding, detection, face checks,
+// preprocessing and inference) on a blocking thread, bounded by
+// INFERENCE_TIMEOUT_MS when set: a run over the timeout answers 504 and its
+// thread finishes on its own, holding the inference slot until then
+pub(crate) async fn run_bounded<T, F>(
+    state: &AppState,
+    slot: Option<Slot>,
+    run: F,
+) -> Result<T, StatusCode>
+where
+    T: Send + 'static,
+    F: FnOnce(&AppState) -> Result<T, StatusCode> + Send + 'static,
+{
+    let task_state = state.clone();
+    let span = tracing::Span::current();
+    let task = tokio::task::spawn_blocking(move || {
+        let _slot = slot;
+        span.in_scope(|| {
+            let start = Instant::now();
+            let embedded = run(&task_state);
+            (embedded, start.elapsed())
+        })
+    });
+    let joined = match state.inference_timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, task).await {
+            Ok(joined) => joined,
+            Err(_) => {
+                key_usage::charge_inference(timeout);
+                telemetry::inference_timed_out();
+                tracing::error!(?timeout, "Image pipeline timed out");
+                return Err(StatusCode::GATEWAY_TIMEOUT);
+            }
+        },
+        None => task.await,
+    };
+    let (embedded, duration) = joined.map_err(|e| {
+        tracing::error!(error = %e, "Inference task failed");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    key_usage::charge_inference(duration);
+    embedded
+}
+
+// Embedding of a face, with the PNG of the model input when `crop` is set
+fn embed_image(
+    img: DynamicImage,
+    state: &AppState,
+    enhance: EnhanceOptions,
+    crop: bool,
+) -> Result<(Vec<f32>, Option<FaceCrop>), StatusCode> {
+    let (input_array, crop) = preprocess_face(img, state, enhance, crop)?;
+    let embedding = infer_faces(state, vec![input_array])?
+        .pop()
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok((embedding, crop))
+}
+
+// Embeddings of several faces, run through the model as one batch
+fn embed_images(
+    faces: Vec<DynamicImage>,
+    state: &AppState,
+    enhance: EnhanceOptions,
+) -> Result<Vec<Vec<f32>>, StatusCode> {
+    let inputs = faces
+        .into_iter()
+        .map(|img| preprocess_face(img, state, enhance, false).map(|(input, _)| input))
+        .collect::<Result<Vec<_>, _>>()?;
+    infer_faces(state, inputs)
+}
+
+// Model input of a face, with the PNG of it when `crop` is set
+fn preprocess_face(
+    img: DynamicImage,
+    state: &AppState,
+    enhance: EnhanceOptions,
+    crop: bool,
+) -> Result<(Array<f32, Ix4>, Option<FaceCrop>), StatusCode> {
+    let preprocess_start = Instant::now();
+    let (input_array, crop): (Array<f32, Ix4>, _) =
+        tracing::info_span!("preprocess").in_scope(|| {
+            // 2.1 Optional enhancement of low-quality images
+            let img = if enhance.is_enabled() {
+                enhance_image(img, state, enhance)?
+            } else {
+                img
+            };
+
+            // 3. Preprocess Image
+            let (width, height) = state.embedder.input_size();
+            let img = embedder::resize(&img, width, height, state.embedder.resize_mode());
+            let crop = if crop {
+                Some(FaceCrop::encode(&img).map_err(|e| {
+                    tracing::error!(error = %e, "Failed to encode face crop");
+                    StatusCode::INTERNAL_SERVER_ERROR
+                })?)
+            } else {
+                None
+            };
+            let input_array = state.embedder.preprocess(&img).map_err(|e| {
+                tracing::error!(error = %e, "Failed to preprocess image");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+            tracing::debug!(shape = ?input_array.shape(), "Image preprocessed");
+            Ok::<_, StatusCode>((input_array, crop))
+        })?;
+    telemetry::observe_stage(telemetry::STAGE_PREPROCESS, preprocess_start.elapsed());
+    Ok((input_array, crop))
+}
+
+// Embeddings of preprocessed faces, in order
+fn infer_faces(
+    state: &AppState,
+    inputs: Vec<Array<f32, Ix4>>,
+) -> Result<Vec<Vec<f32>>, StatusCode> {
+    let inference_start = Instant::now();
+    let embeddings = tracing::info_span!("inference").in_scope(|| {
+        if !state.flip_tta {
+            return run_model(state, inputs);
+        }
+        // Test-time augmentation: the mirrored face embeds slightly differently,
+        // the mean of both normalized embeddings is more robust than either.
+        // The mirrored faces ride in the same batch, after the originals.
+        let count = inputs.len();
+        let mirrored: Vec<_> = inputs
+            .iter()
+            .map(|input| input.slice(s![.., .., .., ..;-1]).to_owned())
+            .collect();
+        let mut embeddings = run_model(state, inputs.into_iter().chain(mirrored).collect())?;
+        let mirrored = embeddings.split_off(count);
+        Ok(embeddings
+            .into_iter()
+            .zip(mirrored)
+            .map(|(embedding, mirrored)| {
+                let (embedding, mirrored) = (normalized(embedding), normalized(mirrored));
+                normalized(
+                    embedding
+                        .iter()
+                        .zip(&mirrored)
+                        .map(|(a, b)| a + b)
+                        .collect(),
+                )
+            })
+            .collect())
+    })?;
+    telemetry::observe_stage(telemetry::STAGE_INFERENCE, inference_start.elapsed());
+    Ok(embeddings)
+}
+
+// Unit-length copy of an embedding; all-zero embeddings are left as they are
+fn normalized(mut embedding: Vec<f32>) -> Vec<f32> {
+    let norm = embedding
+        .iter()
+        .map(|value| value * value)
+        .sum::<f32>()
+        .sqrt();
+    if norm > 0.0 {
+        embedding.iter_mut().for_each(|value| *value /= norm);
+    }
+    embedding
+}
+
+fn run_model(state: &AppState, inputs: Vec<Array<f32, Ix4>>) -> Result<Vec<Vec<f32>>, StatusCode> {
+    let count = inputs.len();
+    let embeddings = state.embedder.infer_batch(inputs).map_err(|e| {
+        tracing::error!(error = %e, "Inference failed");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    if embeddings.len() != count {
+        tracing::error!(
+            inputs = count,
+            embeddings = embeddings.len(),
+            "Inference returned the wrong number of embeddings"
+        );
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    Ok(embeddings)
+}
+
+fn enhance_image(
+    img: DynamicImage,
+    state: &AppState,
+    enhance: EnhanceOptions,
+) -> Result<DynamicImage, StatusCode> {
+    let mut img = img;
+
+    if enhance.super_resolution {
+        let super_resolution = state.super_resolution.as_ref().ok_or_else(|| {
+            tracing::warn!("Super-resolution requested but no SR_MODEL_PATH is configured");
             StatusCode::BAD_REQUEST
         })?;
-    tracing::debug!(image_size = image_bytes.len(), "Base64 decoded");
-
-    // 2. Load Image from bytes
-    let img: DynamicImage = image::load_from_memory(&image_bytes).map_err(|e| {
-        tracing::error!(error = %e, "Failed to load image from bytes");
-        StatusCode::BAD_REQUEST
-    })?;
-    tracing::debug!(dims = ?img.dimensions(), "Image loaded");
+        img = super_resolution.upscale(&img).map_err(|e| {
+            tracing::error!(error = %e, "Super-resolution failed");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        tracing::debug!(dims = ?img.dimensions(), "Image upscaled");
+    }
+
+    if enhance.equalize {
+        img = enhance::equalize_histogram(&img);
+        tracing::debug!("Image histogram equalized");
+    }
+
+    Ok(img)
+}
+
+// --- Struct Definitions ---
+
+// Defaults of SEARCH_THRESHOLD and SEARCH_LIMIT
+pub const DEFAULT_THRESHOLD: f32 = 0.7;
+pub const DEFAULT_LIMIT: usize = 10;
+// Best matches per face kept in the audit log
+const AUDITED_MATCHES: usize = 5;
+
+// Define the response for /register/
+#[derive(Serialize)]
+pub struct RegisterResponse {
+    // Checks of the enrolled face; empty with register_all_faces
+    #[serde(flatten)]
+    pub analysis: Analysis,
+    // One entry per enrolled face, with register_all_faces
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub faces: Vec<RegisteredFace>,
+}
+
+#[derive(Serialize)]
+pub struct RegisteredFace {
+    pub target_uuid: Uuid,
+    #[serde(flatten)]
+    pub analysis: Analysis,
+}
+
+// Failed registration; quality gate rejections carry the scores so clients
+// can ask for a better photo, duplicate rejections the enrolled look-alike
+pub(crate) struct RegisterError {
+    pub status: StatusCode,
+    pub quality: Option<QualityReport>,
+    pub duplicate: Option<Duplicate>,
+}
+
+// Registered face of another uuid too similar to the one being enrolled
+#[derive(Serialize)]
+pub(crate) struct Duplicate {
+    pub target_uuid: Uuid,
+    pub similarity: f32,
+}
+
+impl From<StatusCode> for RegisterError {
+    fn from(status: StatusCode) -> Self {
+        RegisterError {
+            status,
+            quality: None,
+            duplicate: None,
+        }
+    }
+}
+
+impl IntoResponse for RegisterError {
+    fn into_response(self) -> Response {
+        match (self.quality, self.duplicate) {
+            (Some(quality), _) => {
+                (self.status, Json(serde_json::json!({ "quality": quality }))).into_response()
+            }
+            (None, Some(duplicate)) => (
+                self.status,
+                Json(serde_json::json!({ "conflict": duplicate })),
+            )
+                .into_response(),
+            (None, None) => self.status.into_response(),
+        }
+    }
+}
+
+// Most frames of a composite query
+const MAX_QUERY_IMAGES: usize = 10;
+
+// Define the response for /search/
+#[derive(Serialize)]
+pub struct SearchResponse {
+    results: Vec<SearchResult>,
+    // The time budget ran out before the whole gallery was scanned
+    partial: bool,
+    // Liveness score of the query image, when a liveness model is configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    liveness: Option<f32>,
+    // Head pose of the query face, when a pose model is configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pose: Option<Pose>,
+    // Mask check of the query face, when a mask model is configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mask: Option<MaskCheck>,
+    // Estimated age and gender of the query face, when requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    attributes: Option<Attributes>,
+    // Estimated expression of the query face, when requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    emotion: Option<Emotion>,
+    // Box of the query face, when a face detector is configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    face: Option<DetectedFace>,
+    // Aligned crop of the query face fed to the model, when requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    crop: Option<FaceCrop>,
+    // Embedding of the query face, when requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    embedding: Option<Vec<f32>>,
+    // Results per detected face, largest first, when the image holds several
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    faces: Vec<SearchResponse>,
+}
+
+#[derive(Serialize)]
+pub struct SearchResult {
+    target_uuid: String,
+    similarity: f32,
+    // Calibrated probability that the faces are the same person, with
+    // CALIBRATION_PATH
+    #[serde(skip_serializing_if = "Option::is_none")]
+    match_probability: Option<f32>,
+    origin: String,
+    #[serde(skip_serializing_if = "Metadata::is_empty")]
+    metadata: Metadata,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hits: Option<u32>,
+}
+
+// Define the response for /usage/
+#[derive(Serialize)]
+pub struct UsageResponse {
+    total: usize,
+    origins: Vec<OriginUsage>,
+}
+
+#[derive(Serialize)]
+pub struct OriginUsage {
+    origin: String,
+    used: usize,
+    // None when the origin is unlimited
+    limit: Option<usize>,
+}
+
+// Define the response for /stats/origins
+#[derive(Serialize)]
+pub struct OriginStatsResponse {
+    origins: Vec<OriginStats>,
+}
+
+#[derive(Serialize, SimpleObject)]
+pub struct OriginStats {
+    origin: String,
+    // Distinct target uuids enrolled from the origin
+    identities: i64,
+    embeddings: i64,
+    oldest_registration: String,
+    newest_registration: String,
+}
+
+// Define the response for /health/deep
+#[derive(Serialize)]
+pub struct DeepHealthResponse {
+    healthy: bool,
+    database: DependencyHealth,
+    // Registrations fail fast until the database recovers
+    database_circuit_open: bool,
+    model: DependencyHealth,
+}
+
+#[derive(Serialize)]
+pub struct DependencyHealth {
+    healthy: bool,
+    latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl DependencyHealth {
+    fn of(start: Instant, outcome: Result<(), String>) -> Self {
+        Self {
+            healthy: outcome.is_ok(),
+            latency_ms: start.elapsed().as_millis() as u64,
+            error: outcome.err(),
+        }
+    }
+}
+
+// Define the response for /metrics/
+#[derive(Serialize)]
+pub struct MetricsResponse {
+    entries: usize,
+    collections: Vec<CollectionMetrics>,
+}
+
+#[derive(Serialize)]
+pub struct CollectionMetrics {
+    name: String,
+    entries: usize,
+    shards: usize,
+    // Deleted entries awaiting compaction
+    holes: usize,
+    // Targets evicted from memory by the memory limits
+    evicted: usize,
+    memory_bytes: usize,
+    store_lock: LockStats,
+}
+
+// --- Handlers ---
+
+// Handler for GET / route, returns 200 OK
+pub async fn health_check() -> axum::http::StatusCode {
+    axum::http::StatusCode::OK
+}
+
+// Longest a dependency of /health/deep may take before it counts as down
+const DEEP_HEALTH_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Blank face run through the recognition model by /health/deep
+static PROBE_FACE: LazyLock<DynamicImage> = LazyLock::new(|| DynamicImage::new_rgb8(112, 112));
+
+// Handler for GET /health/deep - pings the database and runs an inference,
+// 503 when either fails or takes longer than DEEP_HEALTH_TIMEOUT
+pub async fn deep_health_check(
+    State(state): State<AppState>,
+) -> (StatusCode, Json<DeepHealthResponse>) {
+    let start = Instant::now();
+    let outcome = if state.storage.persists_targets() {
+        match tokio::time::timeout(DEEP_HEALTH_TIMEOUT, state.targets.ping()).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err("timed out".to_string()),
+        }
+    } else {
+        // Nothing to ping in memory-only mode
+        Ok(())
+    };
+    let database = DependencyHealth::of(start, outcome);
+
+    let start = Instant::now();
+    let probe_state = state.clone();
+    let inference = tokio::task::spawn_blocking(move || {
+        let input = probe_state.embedder.preprocess(&PROBE_FACE).map_err(|e| {
+            tracing::error!(error = %e, "Failed to preprocess image");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        run_model(&probe_state, input)
+    });
+    let outcome = match tokio::time::timeout(DEEP_HEALTH_TIMEOUT, inference).await {
+        Ok(Ok(Ok(embedding))) if !embedding.is_empty() => Ok(()),
+        Ok(Ok(Ok(_))) => Err("empty embedding".to_string()),
+        Ok(Ok(Err(status))) => Err(format!("inference failed ({})", status)),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("timed out".to_string()),
+    };
+    let model = DependencyHealth::of(start, outcome);
+
+    let database_circuit_open = state.db_breaker.is_open();
+    let healthy = database.healthy && !database_circuit_open && model.healthy;
+    if !healthy {
+        tracing::warn!(database = ?database.error, model = ?model.error, "Deep health check failed");
+    }
+    let status = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(DeepHealthResponse {
+            healthy,
+            database,
+            database_circuit_open,
+            model,
+        }),
+    )
+}
+
+// Handler for GET /metrics/ - store size and shard lock wait times
+pub async fn metrics(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+) -> Json<MetricsResponse> {
+    let mut collections = Vec::new();
+    for (name, collection) in state.collections.list(tenant.id()) {
+        collections.push(CollectionMetrics {
+            name,
+            entries: collection.store.len(),
+            shards: collection.store.shard_count(),
+            holes: collection.store.hole_count(),
+            evicted: collection.store.evicted_count().await,
+            memory_bytes: collection.store.memory_bytes(),
+            store_lock: collection.store.lock_stats(),
+        });
+    }
+
+    Json(MetricsResponse {
+        entries: collections
+            .iter()
+            .map(|collection| collection.entries)
+            .sum(),
+        collections,
+    })
+}
+
+// Handler for GET /usage/ - stored embeddings per origin against their quota,
+// for all collections or only ?collection=
+pub async fn usage(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Query(query): Query<CollectionQuery>,
+) -> Result<Json<UsageResponse>, StatusCode> {
+    let mut counts = match query.requested() {
+        Some(name) => state
+            .collections
+            .get(tenant.id(), name)
+            .ok_or(StatusCode::NOT_FOUND)?
+            .store
+            .origin_counts(),
+        None => state.collections.origin_counts(tenant.id()),
+    };
+    for origin in state.quotas.origins() {
+        counts.entry(origin.to_string()).or_insert(0);
+    }
+
+    let mut origins: Vec<OriginUsage> = counts
+        .into_iter()
+        .map(|(origin, used)| OriginUsage {
+            limit: state.quotas.limit_for(&origin),
+            origin,
+            used,
+        })
+        .collect();
+    origins.sort_by(|a, b| a.origin.cmp(&b.origin));
+
+    Ok(Json(UsageResponse {
+        total: origins.iter().map(|usage| usage.used).sum(),
+        origins,
+    }))
+}
+
+// Handler for GET /stats/origins - identities, embeddings and registration
+// times per origin, for all collections or only ?collection=. Read from the
+// database, so targets evicted from memory are included.
+pub async fn origin_stats(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Query(query): Query<CollectionQuery>,
+) -> Result<Json<OriginStatsResponse>, StatusCode> {
+    if let Some(name) = query.requested() {
+        state
+            .collections
+            .get(tenant.id(), name)
+            .ok_or(StatusCode::NOT_FOUND)?;
+    }
+    let origins = fetch_origin_stats(&state.read_pool, tenant.id(), query.requested())
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, "Failed to query origin statistics");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(Json(OriginStatsResponse { origins }))
+}
+
+// Registrations per origin of a tenant, in all collections or one
+pub(crate) async fn fetch_origin_stats(
+    pool: &sqlx::PgPool,
+    tenant: &str,
+    collection: Option<&str>,
+) -> Result<Vec<OriginStats>, sqlx::Error> {
+    let rows: Vec<(String, i64, i64, i64, i64)> = sqlx::query_as(
+        r#"
+        SELECT origin, COUNT(DISTINCT uuid), COUNT(*),
+               EXTRACT(EPOCH FROM MIN(created_at))::BIGINT,
+               EXTRACT(EPOCH FROM MAX(created_at))::BIGINT
+        FROM targets
+        WHERE tenant = $1 AND ($2::TEXT IS NULL OR collection = $2) AND deleted_at IS NULL
+        GROUP BY origin
+        ORDER BY origin
+        "#,
+    )
+    .bind(tenant)
+    .bind(collection)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(
+            |(origin, identities, embeddings, oldest, newest)| OriginStats {
+                origin,
+                identities,
+                embeddings,
+                oldest_registration: util::format_rfc3339(oldest),
+                newest_registration: util::format_rfc3339(newest),
+            },
+        )
+        .collect())
+}
+
+// Result summary of a registration, for the audit log
+pub(crate) fn register_summary(
+    collection: &str,
+    payload: &RegisterPayload,
+    response: &RegisterResponse,
+) -> serde_json::Value {
+    let target_uuids: Vec<Uuid> = if payload.register_all_faces {
+        response.faces.iter().map(|face| face.target_uuid).collect()
+    } else {
+        vec![payload.target_uuid]
+    };
+    serde_json::json!({
+        "collection": collection,
+        "origin": payload.origin,
+        "mode": payload.mode,
+        "target_uuids": target_uuids,
+    })
+}
+
+// Result summary of a search, for the audit log: the best matches of each face
+pub(crate) fn search_summary(collection: &str, found: &SearchResults) -> serde_json::Value {
+    let faces: Vec<serde_json::Value> = std::iter::once(found)
+        .chain(&found.other_faces)
+        .map(|face| {
+            serde_json::json!({
+                "matches": face.matches.len(),
+                "best": face.matches.iter().take(AUDITED_MATCHES).map(|found| {
+                    serde_json::json!({"target_uuid": found.uuid, "similarity": found.similarity})
+                }).collect::<Vec<_>>(),
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "collection": collection,
+        "partial": found.partial,
+        "faces": faces,
+    })
+}
+
+// Handler for POST /register/ - registers into ?collection= (default collection if unset)
+pub async fn register(
+    State(state): State<AppState>, // Extract state
+    Extension(tenant): Extension<Tenant>,
+    Query(query): Query<CollectionQuery>,
+    Json(payload): Json<RegisterPayload>,
+) -> Result<
+    (
+        StatusCode,
+        Extension<audit::Summary>,
+        Json<RegisterResponse>,
+    ),
+    RegisterError,
+> {
+    register_into(
+        &state,
+        &tenant,
+        query.name(),
+        &payload,
+        ImageInput::Base64(&payload.image_base64),
+    )
+    .await
+    .map(|response| {
+        let summary = register_summary(query.name(), &payload, &response);
+        (
+            StatusCode::CREATED,
+            Extension(audit::Summary(summary)),
+            Json(response),
+        )
+    })
+}
+
+// Handler for POST /collections/:name/register/
+pub async fn register_in_collection(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(collection): Path<String>,
+    Json(payload): Json<RegisterPayload>,
+) -> Result<
+    (
+        StatusCode,
+        Extension<audit::Summary>,
+        Json<RegisterResponse>,
+    ),
+    RegisterError,
+> {
+    register_into(
+        &state,
+        &tenant,
+        &collection,
+        &payload,
+        ImageInput::Base64(&payload.image_base64),
+    )
+    .await
+    .map(|response| {
+        let summary = register_summary(&collection, &payload, &response);
+        (
+            StatusCode::CREATED,
+            Extension(audit::Summary(summary)),
+            Json(response),
+        )
+    })
+}
+
+// Shared registration pipeline; `image` replaces `payload.image_base64`, which
+// is left empty by callers that receive raw bytes
+pub(crate) async fn register_into(
+    state: &AppState,
+    tenant: &Tenant,
+    name: &str,
+    payload: &RegisterPayload,
+    image: ImageInput<'_>,
+) -> Result<RegisterResponse, RegisterError> {
+    let start = Instant::now(); // Record start time
+
+    let Some(collection) = state.collections.get(tenant.id(), name) else {
+        tracing::warn!(collection = %name, "Received registration for unknown collection");
+        return Err(StatusCode::NOT_FOUND.into());
+    };
+
+    // --- Payload Validation ---
+    if payload.register_all_faces {
+        // Every face gets a uuid of its own
+        if payload.target_uuid != Uuid::nil() || payload.face_index.is_some() {
+            tracing::warn!("Received register_all_faces request with target_uuid or face_index");
+            return Err(StatusCode::BAD_REQUEST.into());
+        }
+    } else if payload.target_uuid == Uuid::nil() {
+        // Check if UUID is nil (optional, but good practice)
+        tracing::warn!("Received registration request with nil UUID");
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    if payload.register_all_faces && payload.mode != RegisterMode::Append {
+        // New uuids never exist yet
+        tracing::warn!(mode = ?payload.mode, "Received register_all_faces request with a registration mode");
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    if payload.origin.trim().is_empty() {
+        tracing::warn!("Received registration request with empty origin");
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    match &payload.embedding {
+        None if image.is_empty() => {
+            tracing::warn!("Received registration request with empty image");
+            return Err(StatusCode::BAD_REQUEST.into());
+        }
+        // Face selection and crops need an image
+        Some(_)
+            if !image.is_empty()
+                || payload.register_all_faces
+                || payload.face_index.is_some()
+                || payload.return_crops =>
+        {
+            tracing::warn!("Received registration request with an embedding and image options");
+            return Err(StatusCode::BAD_REQUEST.into());
+        }
+        // The enrollment gates need the face; a vector would go around them
+        Some(_) if enrollment_gated(state) => {
+            tracing::warn!("Received registration request with an embedding while enrollment gates are configured");
+            return Err(StatusCode::UNPROCESSABLE_ENTITY.into());
+        }
+        _ => {}
+    }
+    if payload
+        .reject_if_similar_above
+        .is_some_and(|threshold| !(-1.0..=1.0).contains(&threshold))
+    {
+        tracing::warn!(threshold = ?payload.reject_if_similar_above, "Received registration request with invalid duplicate threshold");
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    if payload
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= util::unix_now())
+    {
+        tracing::warn!(expires_at = ?payload.expires_at, "Received registration request that is already expired");
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    // --- End Validation ---
+
+    let origin = payload.origin.clone();
+    tracing::debug!(uuid = %payload.target_uuid, %origin, collection = %name, "Received registration request");
+
+    // Refuse before running inference if the origin has used up its quota
+    let used = state.collections.origin_count(tenant.id(), &origin);
+    if state.quotas.is_exhausted(&origin, used) {
+        tracing::warn!(uuid = %payload.target_uuid, %origin, used, "Origin quota exhausted, rejecting registration");
+        return Err(StatusCode::INSUFFICIENT_STORAGE.into());
+    }
+    // Refuse duplicates before running inference; checked again when storing
+    if payload.mode == RegisterMode::RejectIfExists {
+        let exists = if state.storage.persists_targets() {
+            settle_writes(state, tenant.id(), name, payload.target_uuid).await;
+            state
+                .db_breaker
+                .run(|| state.targets.exists(tenant.id(), name, payload.target_uuid))
+                .await
+                .map_err(|e| {
+                    tracing::error!(uuid = %payload.target_uuid, error = %e, "Failed to look up target");
+                    e.status()
+                })?
+        } else {
+            collection.store.contains(&payload.target_uuid).await
+        };
+        if exists {
+            tracing::warn!(uuid = %payload.target_uuid, "Target already registered, rejecting registration");
+            return Err(StatusCode::CONFLICT.into());
+        }
+    }
+
+    // Get embeddings using the helper function
+    let estimates = Estimates {
+        quality: true,
+        // Also kept for review when crop storage is configured
+        crop: payload.return_crops || state.crops.is_some(),
+        ..Estimates::default()
+    };
+    let selection = if payload.register_all_faces {
+        FaceSelection::All
+    } else {
+        payload
+            .face_index
+            .map_or(FaceSelection::Largest, FaceSelection::Index)
+    };
+    let faces: Vec<(Uuid, Vec<f32>, Analysis)> = match &payload.embedding {
+        Some(embedding) => {
+            let embedding = precomputed_embedding(state, &collection, embedding)?;
+            vec![(payload.target_uuid, embedding, Analysis::default())]
+        }
+        None => get_face_embeddings(
+            image,
+            state,
+            EnhanceOptions::default(),
+            estimates,
+            selection,
+        )
+        .await?
+        .into_iter()
+        .map(|(embedding, analysis)| {
+            let target_uuid = if payload.register_all_faces {
+                Uuid::new_v4()
+            } else {
+                payload.target_uuid
+            };
+            (target_uuid, embedding, analysis)
+        })
+        .collect(),
+    };
+    register_faces(state, tenant, name, &collection, payload, faces, start).await
+}
+
+// Gates, duplicate check and storage of faces already embedded, all under the
+// target of `payload` or, with register_all_faces, under uuids of their own
+pub(crate) async fn register_faces(
+    state: &AppState,
+    tenant: &Tenant,
+    name: &str,
+    collection: &Arc<Collection>,
+    payload: &RegisterPayload,
+    faces: Vec<(Uuid, Vec<f32>, Analysis)>,
+    start: Instant,
+) -> Result<RegisterResponse, RegisterError> {
+    let origin = payload.origin.clone();
+    // A group photo is enrolled whole or not at all
+    for (target_uuid, embedding_vec, analysis) in &faces {
+        check_enrollment(state, *target_uuid, analysis)?;
+        tracing::info!(%target_uuid, dimension = embedding_vec.len(), "Embedding calculated");
+    }
+    // Every face counts against the quota, not only the first one
+    let used = state.collections.origin_count(tenant.id(), &origin);
+    if state.quotas.would_exceed(&origin, used, faces.len()) {
+        tracing::warn!(%origin, used, faces = faces.len(), "Origin quota exceeded by the faces, rejecting registration");
+        return Err(StatusCode::INSUFFICIENT_STORAGE.into());
+    }
+    if let Some(threshold) = payload.reject_if_similar_above {
+        for (target_uuid, embedding_vec, _) in &faces {
+            check_duplicate(
+                state,
+                tenant.id(),
+                name,
+                collection,
+                *target_uuid,
+                embedding_vec,
+                threshold,
+            )
+            .await?;
+        }
+    }
+
+    // An evicted or archived target is paged back in before it is registered
+    // again, or the new face would only be counted and never searched
+    if payload.mode != RegisterMode::Replace {
+        for (target_uuid, _, _) in &faces {
+            page_in(state, tenant.id(), name, collection, *target_uuid).await?;
+        }
+    }
+
+    // Upload the crops first, so every stored key points to an object
+    let mut image_keys = Vec::with_capacity(faces.len());
+    for (target_uuid, _, analysis) in &faces {
+        let (Some(crops), Some(crop)) = (&state.crops, &analysis.crop) else {
+            image_keys.push(None);
+            continue;
+        };
+        let key = ObjectStore::crop_key(tenant.id(), name, *target_uuid);
+        crops
+            .put(&key, crop.0.clone())
+            .instrument(tracing::info_span!("crop_upload"))
+            .await
+            .map_err(|e| {
+                tracing::error!(%target_uuid, error = %e, "Failed to upload enrollment crop");
+                StatusCode::SERVICE_UNAVAILABLE
+            })?;
+        image_keys.push(Some(key));
+    }
+
+    // Appended registrations are written behind when the queue has room:
+    // acknowledged once in memory, written to the database right after
+    let queued = match &state.write_behind {
+        Some(write_behind) if payload.mode == RegisterMode::Append => {
+            write_behind.reserve().map(|permit| {
+                let targets = QueuedTargets {
+                    tenant: tenant.id().to_string(),
+                    collection: name.to_string(),
+                    origin: payload.origin.clone(),
+                    metadata: payload.metadata.clone(),
+                    expires_at: payload.expires_at,
+                    model: state.version.model_version().to_string(),
+                    faces: faces
+                        .iter()
+                        .zip(&image_keys)
+                        .map(|((target_uuid, embedding, _), image_key)| {
+                            (*target_uuid, embedding.clone(), image_key.clone())
+                        })
+                        .collect(),
+                };
+                write_behind.queue(permit, targets);
+            })
+        }
+        _ => None,
+    };
+
+    let new_embedding = |target_uuid: Uuid, embedding: Vec<f32>| NewEmbedding {
+        uuid: target_uuid,
+        origin: origin.clone(),
+        metadata: payload.metadata.clone(),
+        embedding,
+        model_version: Some(state.version.model_version().to_string()),
+        expires_at: payload.expires_at,
+    };
+
+    // Store the embeddings in the database, unless they only live in memory
+    let mut added = false;
+    let replaced_keys = if queued.is_some() {
+        Vec::new()
+    } else if state.storage.persists_targets() {
+        if payload.mode != RegisterMode::Append {
+            settle_writes(state, tenant.id(), name, payload.target_uuid).await;
+        }
+        store_registration(state, tenant, name, payload, &faces, &image_keys).await?
+    } else {
+        if payload.mode == RegisterMode::RejectIfExists {
+            // Checked and inserted under the shard lock, so two concurrent
+            // registrations of the uuid cannot both get in
+            let absent = if payload.register_all_faces {
+                !collection.store.contains(&payload.target_uuid).await
+            } else {
+                added = true;
+                let embeddings = faces
+                    .iter()
+                    .map(|(target_uuid, embedding, _)| {
+                        new_embedding(*target_uuid, embedding.clone())
+                    })
+                    .collect();
+                collection
+                    .store
+                    .add_if_absent(payload.target_uuid, embeddings)
+                    .await
+            };
+            if !absent {
+                tracing::warn!(uuid = %payload.target_uuid, "Target registered concurrently, rejecting registration");
+                delete_crops(state, payload.target_uuid, image_keys);
+                return Err(StatusCode::CONFLICT.into());
+            }
+        }
+        // Replaced crop keys are not tracked without the database
+        Vec::new()
+    };
+
+    // Add the embeddings to in-memory storage
+    let embeddings_store = &collection.store;
+    if payload.mode == RegisterMode::Replace {
+        let removed = embeddings_store.remove(&payload.target_uuid).await;
+        tracing::info!(uuid = %payload.target_uuid, replaced = replaced_keys.len(), removed, "Previous embeddings replaced");
+        invalidate_cached_searches(state, tenant.id(), name).await;
+        delete_crops(state, payload.target_uuid, replaced_keys);
+    }
+    let mut registered = Vec::with_capacity(faces.len());
+    for (target_uuid, embedding_vec, mut analysis) in faces {
+        if !payload.return_crops {
+            analysis.crop = None;
+        }
+        if payload.return_embedding {
+            analysis.embedding = Some(embedding_vec.clone());
+        }
+        if !added {
+            embeddings_store
+                .add_embedding(new_embedding(target_uuid, embedding_vec))
+                .await;
+            tracing::info!(%target_uuid, "Successfully added embedding to in-memory store");
+        }
+        if let Some(webhooks) = &state.webhooks {
+            webhooks.registered(tenant.id(), name, target_uuid, &origin, &payload.metadata);
+        }
+        registered.push(RegisteredFace {
+            target_uuid,
+            analysis,
+        });
+    }
+    state.collections.enforce_memory_limits().await;
+    tracing::info!("Total embeddings in memory: {}", embeddings_store.len());
+
+    let duration = start.elapsed(); // Calculate duration
+    tracing::info!(faces = registered.len(), duration = ?duration, "Registration successful"); // Log duration
+
+    if payload.register_all_faces {
+        Ok(RegisterResponse {
+            analysis: Analysis::default(),
+            faces: registered,
+        })
+    } else {
+        let analysis = registered
+            .pop()
+            .map(|face| face.analysis)
+            .unwrap_or_default();
+        Ok(RegisterResponse {
+            analysis,
+            faces: Vec::new(),
+        })
+    }
+}
+
+// Unit-length copy of a client embedding (registered or searched), which must
+// have the dimension of the model, or of the gallery when the model output is
+// dynamic
+fn precomputed_embedding(
+    state: &AppState,
+    collection: &Collection,
+    embedding: &[f32],
+) -> Result<Vec<f32>, StatusCode> {
+    let expected = state
+        .embedding_dimension
+        .or_else(|| collection.store.dimension());
+    if expected.is_some_and(|expected| embedding.len() != expected) || embedding.is_empty() {
+        tracing::warn!(
+            dimension = embedding.len(),
+            ?expected,
+            "Received registration with an embedding of the wrong dimension"
+        );
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if embedding.iter().any(|value| !value.is_finite())
+        || embedding.iter().all(|&value| value == 0.0)
+    {
+        tracing::warn!("Received registration with a non-finite or zero embedding");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    Ok(normalized(embedding.to_vec()))
+}
+
+// Writes the rows of a registration in one transaction and returns the crop
+// keys of the rows it replaced
+async fn store_registration(
+    state: &AppState,
+    tenant: &Tenant,
+    name: &str,
+    payload: &RegisterPayload,
+    faces: &[(Uuid, Vec<f32>, Analysis)],
+    image_keys: &[Option<String>],
+) -> Result<Vec<Option<String>>, RegisterError> {
+    tracing::info!(faces = faces.len(), origin = %payload.origin, "Storing embeddings in the database...");
+    let targets = NewTargets {
+        tenant: tenant.id(),
+        collection: name,
+        target_uuid: payload.target_uuid,
+        mode: payload.mode,
+        origin: &payload.origin,
+        metadata: &payload.metadata,
+        expires_at: payload.expires_at,
+        model: state.version.model_version(),
+        faces: faces
+            .iter()
+            .zip(image_keys)
+            .map(|((target_uuid, embedding, _), image_key)| {
+                (*target_uuid, &embedding[..], image_key.as_deref())
+            })
+            .collect(),
+    };
+    // Retried as a whole on transient errors, the failed transaction being
+    // rolled back, but not when the connection was lost during the commit
+    let stored = state
+        .db_breaker
+        .run(|| state.targets.insert(&targets))
+        .instrument(tracing::info_span!("db_insert"))
+        .await;
+    let replaced_keys = match stored {
+        Ok(Some(replaced_keys)) => replaced_keys,
+        Ok(None) => {
+            tracing::warn!(uuid = %payload.target_uuid, "Target registered concurrently, rejecting registration");
+            delete_crops(state, payload.target_uuid, image_keys.to_vec());
+            return Err(StatusCode::CONFLICT.into());
+        }
+        Err(e) => {
+            tracing::error!(uuid = %payload.target_uuid, error = %e, "Failed to store embeddings in database");
+            return Err(e.status().into());
+        }
+    };
+    tracing::info!(
+        faces = faces.len(),
+        "Successfully stored embeddings in the database."
+    );
+    Ok(replaced_keys)
+}
+
+// Whether any enrollment gate is configured (LIVENESS_THRESHOLD,
+// POSE_MODEL_PATH, QUALITY_GATE)
+fn enrollment_gated(state: &AppState) -> bool {
+    state
+        .liveness
+        .as_ref()
+        .is_some_and(|model| model.threshold().is_some())
+        || state.pose.is_some()
+        || state.quality_gate.is_enabled()
+}
+
+// Enrollment gates; a face failing any of them is not stored
+fn check_enrollment(
+    state: &AppState,
+    target_uuid: Uuid,
+    analysis: &Analysis,
+) -> Result<(), RegisterError> {
+    // Spoofed enrollments (printed photos, screen replays) are refused when a gate is set
+    if let (Some(score), Some(threshold)) = (
+        analysis.liveness,
+        state.liveness.as_ref().and_then(|model| model.threshold()),
+    ) {
+        if score < threshold {
+            tracing::warn!(%target_uuid, score, threshold, "Liveness check failed, rejecting registration");
+            return Err(StatusCode::UNPROCESSABLE_ENTITY.into());
+        }
+    }
+    // Extreme profiles give unreliable embeddings and would be a bad reference
+    // for every later search
+    if let (Some(angles), Some(estimator)) = (analysis.pose, &state.pose) {
+        if estimator.limits().is_exceeded(&angles) {
+            tracing::warn!(%target_uuid, pose = ?angles, "Head pose beyond limits, rejecting registration");
+            return Err(StatusCode::UNPROCESSABLE_ENTITY.into());
+        }
+    }
+    // Blurry, badly lit or tiny faces make poor references for every later search
+    if let Some(scores) = analysis.quality {
+        let failed = state.quality_gate.failures(&scores);
+        if !failed.is_empty() {
+            tracing::warn!(%target_uuid, scores = ?scores, failed = ?failed, "Quality check failed, rejecting registration");
+            return Err(RegisterError {
+                status: StatusCode::UNPROCESSABLE_ENTITY,
+                quality: Some(QualityReport { scores, failed }),
+                duplicate: None,
+            });
+        }
+    }
+    Ok(())
+}
+
+// Refuses a face whose best match among the other uuids of the collection
+// reaches the threshold; evicted uuids are not in memory and not compared
+async fn check_duplicate(
+    state: &AppState,
+    tenant: &str,
+    name: &str,
+    collection: &Arc<Collection>,
+    target_uuid: Uuid,
+    embedding: &[f32],
+    threshold: f32,
+) -> Result<(), RegisterError> {
+    let options = SearchOptions {
+        threshold,
+        origin_thresholds: Arc::new(HashMap::new()),
+        // The uuid being enrolled may be the best match itself
+        limit: 2,
+        group_by_uuid: true,
+        origins: None,
+        metadata_filter: None,
+        exclude_uuids: None,
+        exclude_origins: None,
+        deadline: None,
+        similarity_weight: 1.0,
+        nprobe: None,
+    };
+    // The other gallery shards are checked as well, a look-alike may be any
+    // of theirs
+    let shard_query = state
+        .sharding
+        .as_ref()
+        .map(|_| ShardQuery::new(tenant, name, embedding, &options, false));
+    let collection = collection.clone();
+    let query = embedding.to_vec();
+    let mut found =
+        tokio::task::spawn_blocking(move || collection.store.find_similar(&query, &options))
+            .await
+            .map_err(|e| {
+                tracing::error!(error = %e, "Duplicate check task failed");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+    if let (Some(sharding), Some(shard_query)) = (&state.sharding, &shard_query) {
+        let peers = sharding.search_peers(shard_query).await;
+        // Without every shard's answer a duplicate could go unnoticed
+        if peers.partial {
+            tracing::warn!(%target_uuid, "Duplicate check missed a gallery shard, rejecting registration");
+            return Err(StatusCode::SERVICE_UNAVAILABLE.into());
+        }
+        found.matches.extend(peers.matches);
+        store::top_k(&mut found.matches, 2);
+    }
+    let Some(duplicate) = found
+        .matches
+        .into_iter()
+        .find(|found| found.uuid != target_uuid)
+    else {
+        return Ok(());
+    };
+    tracing::warn!(%target_uuid, duplicate = %duplicate.uuid, similarity = duplicate.similarity, threshold, "Face already enrolled under another uuid, rejecting registration");
+    Err(RegisterError {
+        status: StatusCode::CONFLICT,
+        quality: None,
+        duplicate: Some(Duplicate {
+            target_uuid: duplicate.uuid,
+            similarity: duplicate.similarity,
+        }),
+    })
+}
+
+// Deletes crop objects in the background; a failed removal only leaves an
+// orphan object
+pub(crate) fn delete_crops(state: &AppState, target_uuid: Uuid, image_keys: Vec<Option<String>>) {
+    let Some(crops) = state.crops.clone() else {
+        return;
+    };
+    tokio::spawn(async move {
+        for key in image_keys.into_iter().flatten() {
+            if let Err(e) = crops.delete(&key).await {
+                tracing::warn!(%target_uuid, error = %e, "Failed to delete enrollment crop");
+            }
+        }
+    });
+}
+
+// Handler for DELETE /targets/:uuid - soft-deletes every registration of a
+// target from ?collection= (default collection if unset); the rows are kept
+// with their deletion time so POST /targets/:uuid/restore can bring them back
+pub async fn delete_target(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Query(query): Query<CollectionQuery>,
+    Path(target_uuid): Path<Uuid>,
+) -> Result<StatusCode, StatusCode> {
+    let name = query.name();
+    let Some(collection) = state.collections.get(tenant.id(), name) else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+
+    // Without a database the target is gone for good, there is no tombstone
+    if !state.storage.persists_targets() {
+        let removed = collection.store.remove(&target_uuid).await;
+        if removed == 0 {
+            return Err(StatusCode::NOT_FOUND);
+        }
+        tracing::info!(%target_uuid, collection = %name, removed, "Target deleted from memory");
+        invalidate_cached_searches(&state, tenant.id(), name).await;
+        compact_if_needed(&state, &tenant, collection).await;
+        return Ok(StatusCode::NO_CONTENT);
+    }
+
+    settle_writes(&state, tenant.id(), name, target_uuid).await;
+    let deleted = state
+        .targets
+        .delete(tenant.id(), name, target_uuid)
+        .instrument(tracing::info_span!("db_delete"))
+        .await
+        .map_err(|e| {
+            tracing::error!(%target_uuid, error = %e, "Failed to delete target from database");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    if deleted == 0 {
+        return Err(StatusCode::NOT_FOUND);
+    }
 
-    // 3. Preprocess Image
-    let input_array: Array<f32, Ix4> = preprocess_image(img, 112, 112).map_err(|e| {
-        tracing::error!(error = %e, "Failed to preprocess image");
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
-    tracing::debug!(shape = ?input_array.shape(), "Image preprocessed");
+    // The crops stay in the bucket as long as the tombstones do
+    let removed = collection.store.remove(&target_uuid).await;
+    tracing::info!(%target_uuid, collection = %name, deleted, removed, "Target deleted");
+    invalidate_cached_searches(&state, tenant.id(), name).await;
 
-    // 4. Prepare ONNX Input Value
-    let shape: Vec<usize> = input_array.shape().to_vec();
-    let raw_vec = input_array.into_raw_vec();
-    let input_value = Value::from_array((shape, raw_vec)).map_err(|e| {
-        tracing::error!(error = %e, "Failed to create input value from array");
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+    compact_if_needed(&state, &tenant, collection).await;
 
-    // 5. Prepare session inputs and run ONNX Inference
-    let session_inputs = inputs![input_value].map_err(|e| {
-        tracing::error!(error = %e, "Failed to create session inputs");
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+    Ok(StatusCode::NO_CONTENT)
+}
 
-    // NOTE: Consider if session.run() needs to be blocking or if it's already async-friendly.
-    // If it's blocking, might need tokio::task::spawn_blocking for CPU-bound work.
-    let outputs: SessionOutputs = onnx_session.run(session_inputs).map_err(|e| {
-        tracing::error!(error = %e, "ONNX inference failed");
+// Reloads a target held out of memory from the database, its queued rows
+// written first
+async fn page_in(
+    state: &AppState,
+    tenant: &str,
+    name: &str,
+    collection: &Collection,
+    target_uuid: Uuid,
+) -> Result<(), StatusCode> {
+    if !collection.store.is_evicted(&target_uuid).await {
+        return Ok(());
+    }
+    settle_writes(state, tenant, name, target_uuid).await;
+    let key = (tenant.to_string(), name.to_string(), target_uuid);
+    resync::reload(state, &key).await.map_err(|e| {
+        tracing::error!(%target_uuid, error = %e, "Failed to page in evicted target");
         StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+    })
+}
 
-    // 6. Process Output (Get Embedding)
-    if outputs.len() == 0 {
-        tracing::error!("ONNX output is empty");
-        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+// Waits for the registrations of the target still queued for the database
+// (WRITE_BEHIND), so the write that follows is not overtaken by them
+pub(crate) async fn settle_writes(state: &AppState, tenant: &str, collection: &str, uuid: Uuid) {
+    if let Some(write_behind) = &state.write_behind {
+        write_behind.settle(tenant, collection, uuid).await;
     }
-    let embedding_value: &Value = &outputs[0];
+}
 
-    let embedding_tensor = embedding_value.try_extract_tensor::<f32>().map_err(|e| {
-        tracing::error!(error = %e, "Failed to extract tensor from ONNX output");
-        StatusCode::INTERNAL_SERVER_ERROR
-    })?;
+// Keeps cached searches from returning a target that was deleted or changed
+pub(crate) async fn invalidate_cached_searches(state: &AppState, tenant: &str, collection: &str) {
+    if let Some(cache) = &state.query_cache {
+        cache.invalidate(tenant, collection).await;
+    }
+}
 
-    let embedding_vec: Vec<f32> = embedding_tensor.view().iter().cloned().collect();
-    Ok(embedding_vec)
+// Reclaims the holes in the background once enough have piled up
+async fn compact_if_needed(state: &AppState, tenant: &Tenant, collection: Arc<Collection>) {
+    if state.flags.is_enabled(flags::COMPACTION, Some(tenant.id()))
+        && collection.store.needs_compaction().await
+    {
+        tokio::spawn(async move {
+            let reclaimed = collection.store.compact().await;
+            tracing::info!(reclaimed, "Embeddings store compacted");
+        });
+    }
 }
 
-// --- Struct Definitions ---
+// Define the payload for PATCH /targets/:uuid; absent fields are left as they are
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateTargetPayload {
+    origin: Option<String>,
+    // Replaces the metadata of the target as a whole
+    metadata: Option<Metadata>,
+}
 
-// Define a response payload struct
+// Define the response for PATCH /targets/:uuid
 #[derive(Serialize)]
-pub struct ResponsePayload {
-    reply: String,
+pub struct UpdateTargetResponse {
+    target_uuid: Uuid,
+    // Registrations relabeled
+    updated: u64,
 }
 
-// Define the request payload for /register/
-#[derive(Deserialize)]
-pub struct RegisterPayload {
+// Handler for PATCH /targets/:uuid - corrects the origin and/or metadata of
+// every registration of a target in ?collection= (default collection if unset)
+pub async fn update_target(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Query(query): Query<CollectionQuery>,
+    Path(target_uuid): Path<Uuid>,
+    Json(payload): Json<UpdateTargetPayload>,
+) -> Result<Json<UpdateTargetResponse>, StatusCode> {
+    if payload.origin.is_none() && payload.metadata.is_none() {
+        tracing::warn!(%target_uuid, "Received target update with nothing to update");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if payload
+        .origin
+        .as_ref()
+        .is_some_and(|origin| origin.trim().is_empty())
+    {
+        tracing::warn!(%target_uuid, "Received target update with empty origin");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let name = query.name();
+    let Some(collection) = state.collections.get(tenant.id(), name) else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let origin = payload.origin.as_deref();
+    let metadata = payload.metadata.as_ref();
+
+    // The registrations move to the new origin, so its quota must have room
+    if let Some(origin) = origin {
+        let used = state.collections.origin_count(tenant.id(), origin);
+        if state.quotas.is_exhausted(origin, used) {
+            tracing::warn!(%target_uuid, %origin, used, "Origin quota exhausted, rejecting target update");
+            return Err(StatusCode::INSUFFICIENT_STORAGE);
+        }
+    }
+
+    // Without a database the entries in memory are all there is
+    if !state.storage.persists_targets() {
+        let updated = collection
+            .store
+            .update(&target_uuid, origin, metadata)
+            .await;
+        if updated == 0 {
+            return Err(StatusCode::NOT_FOUND);
+        }
+        tracing::info!(%target_uuid, collection = %name, updated, "Target updated in memory");
+        invalidate_cached_searches(&state, tenant.id(), name).await;
+        return Ok(Json(UpdateTargetResponse {
+            target_uuid,
+            updated: updated as u64,
+        }));
+    }
+
+    settle_writes(&state, tenant.id(), name, target_uuid).await;
+    let updated = state
+        .targets
+        .update(tenant.id(), name, target_uuid, origin, metadata)
+        .instrument(tracing::info_span!("db_update"))
+        .await
+        .map_err(|e| {
+            tracing::error!(%target_uuid, error = %e, "Failed to update target in database");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    if updated == 0 {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    // Evicted entries are relabeled too, so their counts follow the origin
+    let in_memory = collection
+        .store
+        .update(&target_uuid, origin, metadata)
+        .await;
+    tracing::info!(%target_uuid, collection = %name, updated, in_memory, "Target updated");
+    invalidate_cached_searches(&state, tenant.id(), name).await;
+
+    Ok(Json(UpdateTargetResponse {
+        target_uuid,
+        updated,
+    }))
+}
+
+// Define the response for /targets/:uuid/restore
+#[derive(Serialize)]
+pub struct RestoreResponse {
     target_uuid: Uuid,
-    image_base64: String,
-    origin: String,
+    // Registrations brought back
+    restored: u64,
 }
 
-// Define the request payload for /search/
-#[derive(Deserialize)]
-pub struct SearchPayload {
-    image_base64: String,
-    threshold: Option<f32>,
-    limit: Option<usize>,
+// Handler for POST /targets/:uuid/restore - undoes the deletion of a target in
+// ?collection= (default collection if unset), making it searchable again
+pub async fn restore_target(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Query(query): Query<CollectionQuery>,
+    Path(target_uuid): Path<Uuid>,
+) -> Result<Json<RestoreResponse>, StatusCode> {
+    let name = query.name();
+    if state.collections.get(tenant.id(), name).is_none() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    // A new id, so a snapshot taken while the rows were deleted is recognized
+    // as stale
+    let restored = async {
+        let mut transaction = state.db_pool.begin().await?;
+        let restored = sqlx::query(
+            "UPDATE targets SET deleted_at = NULL, id = nextval(pg_get_serial_sequence('targets', 'id')) WHERE tenant = $1 AND collection = $2 AND uuid = $3 AND deleted_at IS NOT NULL",
+        )
+        .bind(tenant.id())
+        .bind(name)
+        .bind(target_uuid)
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+        if restored > 0 {
+            if let Some(notifier) = &state.notifier {
+                let change = Change::Target {
+                    tenant: tenant.id().to_string(),
+                    collection: name.to_string(),
+                    uuid: target_uuid,
+                };
+                notifier.publish(&mut *transaction, change).await?;
+            }
+        }
+        transaction.commit().await?;
+        Ok::<_, sqlx::Error>(restored)
+    }
+    .await
+    .map_err(|e| {
+        tracing::error!(%target_uuid, error = %e, "Failed to restore target in database");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    if restored == 0 {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    // Registrations made since the deletion are reloaded along with the
+    // restored ones
+    let key = (tenant.id().to_string(), name.to_string(), target_uuid);
+    resync::reload(&state, &key).await.map_err(|e| {
+        tracing::error!(%target_uuid, error = %e, "Failed to reload restored target");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    tracing::info!(%target_uuid, collection = %name, restored, "Target restored");
+
+    Ok(Json(RestoreResponse {
+        target_uuid,
+        restored,
+    }))
 }
 
-// Define the response for /search/
+// Define the response for /targets/:uuid/images
 #[derive(Serialize)]
-pub struct SearchResponse {
-    results: Vec<SearchResult>,
+pub struct TargetImagesResponse {
+    images: Vec<TargetImage>,
 }
 
 #[derive(Serialize)]
-pub struct SearchResult {
-    target_uuid: String,
-    similarity: f32,
+pub struct TargetImage {
+    key: String,
     origin: String,
+    // PNG of the aligned crop, as enrolled
+    image_base64: String,
 }
 
-// --- Handlers ---
+// Handler for GET /targets/:uuid/images - enrollment crops of a target in
+// ?collection= (default collection if unset), for human review of matches
+pub async fn target_images(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Query(query): Query<CollectionQuery>,
+    Path(target_uuid): Path<Uuid>,
+) -> Result<Json<TargetImagesResponse>, StatusCode> {
+    let Some(crops) = &state.crops else {
+        tracing::warn!("Target images requested but no S3_BUCKET is configured");
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let name = query.name();
 
-// Handler for GET / route, returns 200 OK
-pub async fn health_check() -> axum::http::StatusCode {
-    axum::http::StatusCode::OK
+    let rows: Vec<(String, Option<String>)> = sqlx::query_as(
+        "SELECT origin, image_key FROM targets WHERE tenant = $1 AND collection = $2 AND uuid = $3 AND deleted_at IS NULL",
+    )
+    .bind(tenant.id())
+    .bind(name)
+    .bind(target_uuid)
+    .fetch_all(&state.db_pool)
+    .await
+    .map_err(|e| {
+        tracing::error!(%target_uuid, error = %e, "Failed to read target images from database");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    if rows.is_empty() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    // Enrollments from before crop storage have no key
+    let mut images = Vec::new();
+    for (origin, key) in rows {
+        let Some(key) = key else {
+            continue;
+        };
+        let png = crops.get(&key).await.map_err(|e| {
+            tracing::error!(%target_uuid, error = %e, "Failed to download enrollment crop");
+            StatusCode::SERVICE_UNAVAILABLE
+        })?;
+        images.push(TargetImage {
+            key,
+            origin,
+            image_base64: general_purpose::STANDARD.encode(png),
+        });
+    }
+    tracing::debug!(%target_uuid, images = images.len(), "Target images retrieved");
+
+    Ok(Json(TargetImagesResponse { images }))
 }
 
-// Handler for POST /register/
-pub async fn register(
-    State(state): State<AppState>, // Extract state
-    Json(payload): Json<RegisterPayload>,
-) -> Result<StatusCode, StatusCode> {
+// Handler for POST /search/ - searches ?collection= (default collection if unset)
+pub async fn search(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Query(query): Query<CollectionQuery>,
+    Json(payload): Json<SearchPayload>,
+) -> Result<(Extension<audit::Summary>, Json<SearchResponse>), StatusCode> {
+    search_in(&state, &tenant, query.name(), payload).await
+}
+
+// Handler for POST /collections/:name/search/
+pub async fn search_in_collection(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(collection): Path<String>,
+    Json(payload): Json<SearchPayload>,
+) -> Result<(Extension<audit::Summary>, Json<SearchResponse>), StatusCode> {
+    search_in(&state, &tenant, &collection, payload).await
+}
+
+async fn search_in(
+    state: &AppState,
+    tenant: &Tenant,
+    collection: &str,
+    payload: SearchPayload,
+) -> Result<(Extension<audit::Summary>, Json<SearchResponse>), StatusCode> {
     let start = Instant::now(); // Record start time
 
-    // --- Payload Validation ---
-    if payload.target_uuid == Uuid::nil() {
-        // Check if UUID is nil (optional, but good practice)
-        tracing::warn!("Received registration request with nil UUID");
-        return Err(StatusCode::BAD_REQUEST);
+    let similar_embeddings = run_search(state, tenant, collection, &payload).await?;
+    let summary = search_summary(collection, &similar_embeddings);
+    let response = search_response(similar_embeddings, &payload, state.calibration.as_deref());
+
+    let duration = start.elapsed(); // Calculate duration
+    tracing::info!(duration = ?duration, results_count = response.results.len(), partial = response.partial, "Search successful"); // Log duration
+
+    Ok((Extension(audit::Summary(summary)), Json(response)))
+}
+
+// Format results
+pub(crate) fn search_response(
+    found: SearchResults,
+    payload: &SearchPayload,
+    calibration: Option<&Calibration>,
+) -> SearchResponse {
+    let mut response = face_response(&found, payload, calibration);
+    // With several faces each gets its own entry, the largest one included
+    if !found.other_faces.is_empty() {
+        response.faces = std::iter::once(&found)
+            .chain(&found.other_faces)
+            .map(|face| face_response(face, payload, calibration))
+            .collect();
     }
-    if payload.origin.trim().is_empty() {
-        tracing::warn!("Received registration request with empty origin");
-        return Err(StatusCode::BAD_REQUEST);
+    response
+}
+
+fn face_response(
+    found: &SearchResults,
+    payload: &SearchPayload,
+    calibration: Option<&Calibration>,
+) -> SearchResponse {
+    let results: Vec<SearchResult> = found
+        .matches
+        .iter()
+        .map(|found| SearchResult {
+            target_uuid: found.uuid.to_string(),
+            similarity: found.similarity,
+            match_probability: calibration
+                .map(|calibration| calibration.probability(found.similarity)),
+            origin: found.origin.clone(),
+            metadata: found.metadata.clone(),
+            hits: (payload.group_by_uuid && payload.hit_counts).then_some(found.hits),
+        })
+        .collect();
+    SearchResponse {
+        results,
+        partial: found.partial,
+        face: found.face,
+        liveness: found.liveness,
+        pose: found.pose,
+        mask: found.mask,
+        attributes: found.attributes,
+        emotion: found.emotion.clone(),
+        crop: found.crop.clone(),
+        embedding: found.embedding.clone(),
+        faces: Vec::new(),
     }
-    if payload.image_base64.trim().is_empty() {
-        tracing::warn!("Received registration request with empty image_base64");
-        return Err(StatusCode::BAD_REQUEST);
+}
+
+// Shared search pipeline: validation, embedding and in-memory lookup
+pub(crate) async fn run_search(
+    state: &AppState,
+    tenant: &Tenant,
+    name: &str,
+    payload: &SearchPayload,
+) -> Result<SearchResults, StatusCode> {
+    run_search_image(
+        state,
+        tenant,
+        name,
+        payload,
+        ImageInput::Base64(&payload.image_base64),
+    )
+    .await
+}
+
+// Same as run_search with the query image passed separately from the payload
+pub(crate) async fn run_search_image(
+    state: &AppState,
+    tenant: &Tenant,
+    name: &str,
+    payload: &SearchPayload,
+    image: ImageInput<'_>,
+) -> Result<SearchResults, StatusCode> {
+    if !payload.images_base64.is_empty() {
+        if !image.is_empty() {
+            tracing::warn!("Received search request with both image_base64 and images_base64");
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        return run_composite_search(state, tenant, name, payload).await;
+    }
+
+    // The budget covers the whole request, inference included
+    let start = Instant::now();
+    let deadline = payload
+        .time_budget_ms
+        .map(|budget| start + Duration::from_millis(budget));
+
+    // --- Payload Validation ---
+    match &payload.embedding {
+        None if image.is_empty() => {
+            tracing::warn!("Received search request with empty image");
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        // Enhancement, attributes, emotion and crops need an image
+        Some(_)
+            if !image.is_empty()
+                || payload.enhance.is_enabled()
+                || payload.attributes
+                || payload.emotion
+                || payload.return_crops =>
+        {
+            tracing::warn!("Received search request with an embedding and image options");
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        _ => {}
     }
     // --- End Validation ---
 
-    let target_uuid = payload.target_uuid;
-    let origin = payload.origin.clone();
-    tracing::debug!(%target_uuid, %origin, "Received registration request");
+    let Some(collection) = state.collections.get(tenant.id(), name) else {
+        tracing::warn!(collection = %name, "Received search for unknown collection");
+        return Err(StatusCode::NOT_FOUND);
+    };
 
-    // Get embedding using the helper function
-    let embedding_vec =
-        match get_embedding_from_base64(&payload.image_base64, &state.onnx_session).await {
-            Ok(vec) => vec,
-            Err(status) => return Err(status),
-        };
-    tracing::info!(%target_uuid, "Embedding calculated (first 5 values): {:?}", &embedding_vec[..5.min(embedding_vec.len())]);
+    tracing::debug!(collection = %name, "Received search request");
 
-    // Store the embedding in the database
-    tracing::info!(%target_uuid, %origin, "Storing embedding in the database...");
-    match sqlx::query("INSERT INTO targets (uuid, embeddings, origin) VALUES ($1, $2, $3)")
-        .bind(target_uuid)
-        .bind(&embedding_vec[..])
-        .bind(&origin)
-        .execute(&state.db_pool)
+    // Get query embeddings using the helper function; with a detector every
+    // face of the image is searched
+    let estimates = Estimates {
+        attributes: payload.attributes,
+        emotion: payload.emotion,
+        crop: payload.return_crops,
+        ..Estimates::default()
+    };
+    let selection = if state.detector.is_some() {
+        FaceSelection::All
+    } else {
+        FaceSelection::Largest
+    };
+    let mut faces = match &payload.embedding {
+        Some(embedding) => {
+            let embedding = precomputed_embedding(state, &collection, embedding)?;
+            vec![(embedding, Analysis::default())]
+        }
+        None => get_face_embeddings(image, state, payload.enhance, estimates, selection).await?,
+    }
+    .into_iter();
+    let Some((embedding_vec, analysis)) = faces.next() else {
+        return Err(StatusCode::BAD_REQUEST);
+    };
+
+    // The largest face decides the outcome of the request, the others are
+    // reported alongside it
+    let mut similar_embeddings = search_face(
+        state,
+        tenant,
+        name,
+        &collection,
+        payload,
+        embedding_vec,
+        analysis,
+        deadline,
+    )
+    .await?;
+    for (embedding_vec, analysis) in faces {
+        match search_face(
+            state,
+            tenant,
+            name,
+            &collection,
+            payload,
+            embedding_vec,
+            analysis,
+            deadline,
+        )
         .await
-    {
-        Ok(_) => {
-            tracing::info!(%target_uuid, "Successfully stored embedding in the database.");
-
-            // Add the embedding to in-memory storage
-            tracing::info!(%target_uuid, %origin, "Adding embedding to in-memory store...");
-            let mut embeddings_store = match state.embeddings_store.lock() {
-                Ok(store) => store,
-                Err(e) => {
-                    tracing::error!(%target_uuid, error = %e, "Failed to lock embeddings store");
-                    return Err(StatusCode::INTERNAL_SERVER_ERROR);
-                }
-            };
-            embeddings_store.add(target_uuid, origin.clone(), embedding_vec.clone());
-            tracing::info!(%target_uuid, "Successfully added embedding to in-memory store");
-            tracing::info!(%target_uuid, "Total embeddings in memory: {}", embeddings_store.len());
+        {
+            Ok(found) => similar_embeddings.other_faces.push(found),
+            // Rejected head pose, that face is left out
+            Err(StatusCode::UNPROCESSABLE_ENTITY) => {}
+            Err(status) => return Err(status),
+        }
+    }
+
+    let query_hash = match &payload.embedding {
+        Some(embedding) => embedding_sha256(embedding),
+        None => image.sha256(),
+    };
+    record_search(
+        state,
+        tenant,
+        name,
+        &collection,
+        payload,
+        query_hash,
+        start,
+        &similar_embeddings,
+    );
 
-            let duration = start.elapsed(); // Calculate duration
-            tracing::info!(%target_uuid, duration = ?duration, "Registration successful"); // Log duration
+    Ok(similar_embeddings)
+}
 
-            Ok(StatusCode::CREATED)
-        }
-        Err(e) => {
-            tracing::error!(%target_uuid, error = %e, "Failed to store embedding in database");
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
+// Match events, metrics and search history of a finished search
+#[allow(clippy::too_many_arguments)]
+fn record_search(
+    state: &AppState,
+    tenant: &Tenant,
+    name: &str,
+    collection: &Collection,
+    payload: &SearchPayload,
+    query_hash: String,
+    start: Instant,
+    similar_embeddings: &SearchResults,
+) {
+    for found in std::iter::once(similar_embeddings).chain(&similar_embeddings.other_faces) {
+        if found.partial {
+            tracing::warn!("Search time budget exhausted, returning partial results");
         }
+        state
+            .events
+            .publish_matches(tenant.id(), name, payload.source.as_deref(), &found.matches);
+    }
+    telemetry::observe_search(start.elapsed());
+    if let Some(search_history) = &state.search_history {
+        search_history.record(SearchEvent {
+            tenant: tenant.id().to_string(),
+            collection: name.to_string(),
+            source: payload.source.clone(),
+            query_hash,
+            threshold: payload
+                .threshold
+                .or(collection.settings.threshold)
+                .unwrap_or(state.tunables.default_threshold()),
+            latency_ms: start.elapsed().as_millis() as u64,
+            faces: 1 + similar_embeddings.other_faces.len(),
+            matches: history::recorded_matches(similar_embeddings),
+        });
     }
 }
 
-// Handler for POST /search/
-pub async fn search(
-    State(state): State<AppState>,
-    Json(payload): Json<SearchPayload>,
-) -> Result<Json<SearchResponse>, StatusCode> {
-    let start = Instant::now(); // Record start time
+// Search with several frames of the same person: a blurry or turned face in
+// one frame is made up for by the others
+async fn run_composite_search(
+    state: &AppState,
+    tenant: &Tenant,
+    name: &str,
+    payload: &SearchPayload,
+) -> Result<SearchResults, StatusCode> {
+    let start = Instant::now();
+    let deadline = payload
+        .time_budget_ms
+        .map(|budget| start + Duration::from_millis(budget));
 
-    // --- Payload Validation ---
-    if payload.image_base64.trim().is_empty() {
-        tracing::warn!("Received search request with empty image_base64");
+    if payload.images_base64.len() > MAX_QUERY_IMAGES {
+        tracing::warn!(
+            images = payload.images_base64.len(),
+            max = MAX_QUERY_IMAGES,
+            "Received composite search with too many images"
+        );
         return Err(StatusCode::BAD_REQUEST);
     }
-    // --- End Validation ---
-
-    tracing::debug!("Received search request");
+    // Estimates and crops belong to one face, the embedding to all of them
+    if payload.embedding.is_some()
+        || payload.enhance.is_enabled()
+        || payload.attributes
+        || payload.emotion
+        || payload.return_crops
+    {
+        tracing::warn!("Received composite search with an embedding or image options");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let Some(collection) = state.collections.get(tenant.id(), name) else {
+        tracing::warn!(collection = %name, "Received search for unknown collection");
+        return Err(StatusCode::NOT_FOUND);
+    };
+    tracing::debug!(collection = %name, images = payload.images_base64.len(), fusion = ?payload.fusion, "Received composite search request");
 
-    // Get query embedding using the helper function
-    let embedding_vec =
-        match get_embedding_from_base64(&payload.image_base64, &state.onnx_session).await {
-            Ok(vec) => vec,
+    // Frames without a usable face are left out, as long as one remains, and
+    // so are those the head pose gate rejects
+    let mut frames = Vec::with_capacity(payload.images_base64.len());
+    for (index, image_base64) in payload.images_base64.iter().enumerate() {
+        match get_face_embeddings(
+            ImageInput::Base64(image_base64),
+            state,
+            EnhanceOptions::default(),
+            Estimates::default(),
+            FaceSelection::Largest,
+        )
+        .await
+        {
+            Ok(faces) => {
+                for (embedding, analysis) in faces {
+                    let Some(weight) = pose_weight(state, &analysis) else {
+                        tracing::debug!(index, pose = ?analysis.pose, "Composite search frame beyond the head pose limits, skipped");
+                        continue;
+                    };
+                    frames.push((embedding, analysis, weight));
+                }
+            }
+            Err(StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY) => {
+                tracing::debug!(
+                    index,
+                    "Composite search frame without a usable face, skipped"
+                );
+            }
             Err(status) => return Err(status),
-        };
+        }
+    }
+    if frames.is_empty() {
+        tracing::warn!("Received composite search without a usable face");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let query_hash = embedding_sha256(
+        &frames
+            .iter()
+            .flat_map(|(embedding, _, _)| embedding.iter().copied())
+            .collect::<Vec<f32>>(),
+    );
+    // Down-weighted frames count for less in the fused embedding, which is
+    // the one returned with `return_embedding` whatever the fusion
+    let mut mean = vec![0.0; frames[0].0.len()];
+    for (embedding, _, weight) in &frames {
+        for (sum, value) in mean.iter_mut().zip(embedding) {
+            *sum += value * weight;
+        }
+    }
+    let fused = normalized(mean);
+
+    let similar_embeddings = match payload.fusion {
+        Fusion::Mean => {
+            // The fused face is masked, and held to the mask threshold, only
+            // when every frame is
+            let masked = frames
+                .iter()
+                .all(|(_, analysis, _)| analysis.mask.is_some_and(|check| check.masked));
+            let analysis = Analysis {
+                mask: frames[0].1.mask.filter(|_| masked),
+                ..Analysis::default()
+            };
+            search_face(
+                state,
+                tenant,
+                name,
+                &collection,
+                payload,
+                fused,
+                analysis,
+                deadline,
+            )
+            .await?
+        }
+        Fusion::Max => {
+            // Each uuid keeps its matches of the frame it is the most similar
+            // in, so grouping and hit counts are those of a single search
+            let mut best: HashMap<Uuid, (f32, Vec<SearchMatch>)> = HashMap::new();
+            let mut partial = false;
+            for (embedding, mut analysis, _) in frames {
+                analysis.crop = None;
+                let found = search_face(
+                    state,
+                    tenant,
+                    name,
+                    &collection,
+                    payload,
+                    embedding,
+                    analysis,
+                    deadline,
+                )
+                .await?;
+                partial |= found.partial;
+                let mut by_uuid: HashMap<Uuid, (f32, Vec<SearchMatch>)> = HashMap::new();
+                for found in found.matches {
+                    let (similarity, matches) =
+                        by_uuid.entry(found.uuid).or_insert((f32::MIN, Vec::new()));
+                    *similarity = similarity.max(found.similarity);
+                    matches.push(found);
+                }
+                for (uuid, frame) in by_uuid {
+                    match best.get(&uuid) {
+                        Some(kept) if kept.0 >= frame.0 => {}
+                        _ => {
+                            best.insert(uuid, frame);
+                        }
+                    }
+                }
+            }
+            let mut matches: Vec<SearchMatch> = best
+                .into_values()
+                .flat_map(|(_, matches)| matches)
+                .collect();
+            store::top_k(
+                &mut matches,
+                payload.limit.unwrap_or(state.tunables.default_limit()),
+            );
+            SearchResults {
+                matches,
+                partial,
+                embedding: payload.return_embedding.then_some(fused),
+                ..SearchResults::default()
+            }
+        }
+    };
+
+    record_search(
+        state,
+        tenant,
+        name,
+        &collection,
+        payload,
+        query_hash,
+        start,
+        &similar_embeddings,
+    );
+    Ok(similar_embeddings)
+}
+
+// Search of the part of a collection this instance holds: its store, then
+// its archived targets when asked
+pub(crate) async fn search_local(
+    state: &AppState,
+    tenant: &str,
+    name: &str,
+    collection: &Arc<Collection>,
+    embedding: &[f32],
+    options: &SearchOptions,
+    include_archived: bool,
+) -> Result<SearchResults, StatusCode> {
+    // The scan is CPU-bound and takes blocking shard locks, keep it off the async workers
+    let store_collection = collection.clone();
+    let (query, store_options) = (embedding.to_vec(), options.clone());
+    let mut found = tokio::task::spawn_blocking(move || {
+        store_collection.store.find_similar(&query, &store_options)
+    })
+    .instrument(tracing::info_span!("store_search"))
+    .await
+    .map_err(|e| {
+        tracing::error!(error = %e, "Search task failed");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    // Archived targets are scanned from the database after the store
+    if let (Some(policy), true) = (&state.archive, include_archived) {
+        let archived = archive::search(state, policy, tenant, name, collection, embedding, options)
+            .await
+            .map_err(|e| {
+                tracing::error!(error = %e, "Failed to search archived targets");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+        found.matches.extend(archived.matches);
+        found.partial |= archived.partial;
+        store::top_k(&mut found.matches, options.limit);
+    }
+    Ok(found)
+}
+
+// Weight of the matches of a face for its head pose: 1 within the limits,
+// the PoseMode weight beyond them, None when the pose rejects the face
+fn pose_weight(state: &AppState, analysis: &Analysis) -> Option<f32> {
+    let (Some(angles), Some(estimator)) = (analysis.pose, &state.pose) else {
+        return Some(1.0);
+    };
+    if !estimator.limits().is_exceeded(&angles) {
+        return Some(1.0);
+    }
+    match estimator.mode() {
+        PoseMode::Reject => None,
+        PoseMode::DownWeight(weight) => {
+            tracing::debug!(pose = ?angles, weight, "Head pose beyond limits, down-weighting matches");
+            Some(weight)
+        }
+    }
+}
+
+async fn search_face(
+    state: &AppState,
+    tenant: &Tenant,
+    name: &str,
+    collection: &Arc<Collection>,
+    payload: &SearchPayload,
+    embedding_vec: Vec<f32>,
+    analysis: Analysis,
+    deadline: Option<Instant>,
+) -> Result<SearchResults, StatusCode> {
+    let Some(similarity_weight) = pose_weight(state, &analysis) else {
+        tracing::warn!(pose = ?analysis.pose, "Head pose beyond limits, rejecting search");
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    };
     tracing::info!(
-        "Query embedding calculated (first 5 values): {:?}",
-        &embedding_vec[..5.min(embedding_vec.len())]
+        dimension = embedding_vec.len(),
+        "Query embedding calculated"
     );
+    let returned_embedding = payload.return_embedding.then(|| embedding_vec.clone());
 
     // Search for similar embeddings in memory
-    let threshold = payload.threshold.unwrap_or(0.7); // Default threshold
-    let limit = payload.limit.unwrap_or(10); // Default limit
+    // Masked faces reach lower similarities; the mask threshold replaces the
+    // collection and default thresholds for them, a request threshold still wins
+    let mask_threshold = analysis
+        .mask
+        .filter(|check| check.masked)
+        .and(state.mask.as_ref().and_then(|mask| mask.match_threshold()));
+    let threshold = payload
+        .threshold
+        .or(mask_threshold)
+        .or(collection.settings.threshold)
+        .unwrap_or(state.tunables.default_threshold());
+    let limit = payload.limit.unwrap_or(state.tunables.default_limit());
 
     tracing::info!(
         "Searching for similar embeddings with threshold={} and limit={}",
@@ -230,58 +2711,325 @@ pub async fn search(
         limit
     );
 
-    let embeddings_store = match state.embeddings_store.lock() {
-        Ok(store) => store,
-        Err(e) => {
-            tracing::error!(error = %e, "Failed to lock embeddings store");
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    let search_options = SearchOptions {
+        threshold,
+        origin_thresholds: state.origin_thresholds.clone(),
+        limit,
+        group_by_uuid: payload.group_by_uuid,
+        origins: payload
+            .origins
+            .as_ref()
+            .filter(|origins| !origins.is_empty())
+            .map(|origins| origins.iter().cloned().collect()),
+        metadata_filter: payload.metadata.clone().filter(|filter| !filter.is_empty()),
+        exclude_uuids: payload
+            .exclude_uuids
+            .as_ref()
+            .filter(|uuids| !uuids.is_empty())
+            .map(|uuids| uuids.iter().copied().collect()),
+        exclude_origins: payload
+            .exclude_origins
+            .as_ref()
+            .filter(|origins| !origins.is_empty())
+            .map(|origins| origins.iter().cloned().collect()),
+        deadline,
+        similarity_weight,
+        nprobe: payload.nprobe,
+    };
+    // Recently searched faces skip the scan, when the query cache is enabled;
+    // searches of archived targets are not cached, the entries hold hot ones
+    let cache_key = state
+        .query_cache
+        .as_ref()
+        .filter(|_| !payload.include_archived)
+        .and_then(|cache| cache.key(tenant.id(), name, &embedding_vec, &search_options));
+    let (cached, generation) = match (&state.query_cache, &cache_key) {
+        (Some(cache), Some(key)) => {
+            let lookup = cache.get(key, &embedding_vec).await;
+            (lookup.matches, lookup.generation)
+        }
+        _ => (None, None),
+    };
+    let mut similar_embeddings = match cached {
+        Some(matches) => {
+            tracing::debug!("Query cache hit, skipping the scan");
+            SearchResults {
+                matches,
+                ..SearchResults::default()
+            }
+        }
+        None => {
+            let query = cache_key.as_ref().map(|_| embedding_vec.clone());
+            let search_start = Instant::now();
+            // The other shards hold the rest of the gallery, searched while
+            // the local part is
+            let shard_query = state.sharding.as_ref().map(|_| {
+                ShardQuery::new(
+                    tenant.id(),
+                    name,
+                    &embedding_vec,
+                    &search_options,
+                    payload.include_archived,
+                )
+            });
+            let peer_search = async {
+                match (&state.sharding, &shard_query) {
+                    (Some(sharding), Some(shard_query)) => Some(
+                        sharding
+                            .search_peers(shard_query)
+                            .instrument(tracing::info_span!("shard_search"))
+                            .await,
+                    ),
+                    _ => None,
+                }
+            };
+            let (found, peers) = tokio::join!(
+                search_local(
+                    state,
+                    tenant.id(),
+                    name,
+                    collection,
+                    &embedding_vec,
+                    &search_options,
+                    payload.include_archived,
+                ),
+                peer_search
+            );
+            let mut found = found?;
+            if let Some(peers) = peers {
+                found.matches.extend(peers.matches);
+                found.partial |= peers.partial;
+                store::top_k(&mut found.matches, search_options.limit);
+            }
+            telemetry::observe_stage(telemetry::STAGE_SEARCH, search_start.elapsed());
+            // Partial results are never cached, nor those with archived targets
+            if let (Some(cache), Some(key), Some(generation), Some(query)) =
+                (&state.query_cache, cache_key, generation, query)
+            {
+                if !found.partial && !payload.include_archived {
+                    cache.put(key, generation, query, found.matches.clone());
+                }
+            }
+            found
         }
     };
+    similar_embeddings.face = analysis.face;
+    similar_embeddings.liveness = analysis.liveness;
+    similar_embeddings.pose = analysis.pose;
+    similar_embeddings.mask = analysis.mask;
+    similar_embeddings.attributes = analysis.attributes;
+    similar_embeddings.emotion = analysis.emotion;
+    similar_embeddings.crop = analysis.crop;
+    similar_embeddings.embedding = returned_embedding;
+    tracing::info!(
+        "Found {} similar embeddings",
+        similar_embeddings.matches.len()
+    );
 
-    let similar_embeddings = embeddings_store.find_similar(&embedding_vec, threshold, limit);
-    tracing::info!("Found {} similar embeddings", similar_embeddings.len());
+    Ok(similar_embeddings)
+}
 
-    // Format results
-    let results: Vec<SearchResult> = similar_embeddings
-        .into_iter()
-        .map(|(uuid, origin, similarity)| SearchResult {
-            target_uuid: uuid.to_string(),
-            similarity,
-            origin,
-        })
-        .collect();
+// Define the request payload for /analyze/
+#[derive(Deserialize)]
+pub struct AnalyzePayload {
+    pub image_base64: String,
+}
 
-    let duration = start.elapsed(); // Calculate duration
-    tracing::info!(duration = ?duration, results_count = results.len(), "Search successful"); // Log duration
+// Handler for POST /analyze/ - quality, liveness, head pose, mask and
+// age/gender of the largest face of an image without searching; each model-based estimate is only present when
+// its model is configured
+pub async fn analyze(
+    State(state): State<AppState>,
+    Json(payload): Json<AnalyzePayload>,
+) -> Result<Json<Analysis>, StatusCode> {
+    let start = Instant::now();
+    let image = ImageInput::Base64(&payload.image_base64);
+    if image.is_empty() {
+        tracing::warn!("Received analysis request with empty image");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let slot = match &state.inference_queue {
+        Some(queue) => Some(queue.acquire().await?),
+        None => None,
+    };
+    let image = image.to_owned_image();
+    let analysis = run_bounded(&state, slot, move |state| {
+        let (frames, scale) = decode_frames(image.as_input(), &state.image_limits)?;
+        let img = select_frame(frames, state)?;
+        let estimates = Estimates {
+            quality: true,
+            attributes: state.attributes.is_some(),
+            emotion: state.emotion.is_some(),
+            ..Estimates::default()
+        };
+        let face = locate_faces(&img, state, FaceSelection::Largest)?
+            .pop()
+            .flatten();
+        let mut analysis = analyze_image(&img, face, state, estimates)?;
+        analysis.rescale(scale);
+        Ok(analysis)
+    })
+    .await?;
+    tracing::info!(duration = ?start.elapsed(), "Analysis successful");
+    Ok(Json(analysis))
+}
 
-    Ok(Json(SearchResponse { results }))
+#[derive(Deserialize)]
+pub struct DetectPayload {
+    pub image_base64: String,
 }
 
-// --- Image Preprocessing Helper (moved here for locality) ---
+#[derive(Serialize)]
+pub struct DetectResponse {
+    // Size of the image (of the selected frame for animations), which the
+    // boxes and landmarks are in pixels of
+    pub width: u32,
+    pub height: u32,
+    // Largest first
+    pub faces: Vec<DetectedFace>,
+}
 
-fn preprocess_image(
-    img: DynamicImage,
-    target_width: u32,
-    target_height: u32,
-) -> Result<Array<f32, Ix4>, Box<dyn std::error::Error>> {
-    let resized_img = img.resize_exact(
-        target_width,
-        target_height,
-        image::imageops::FilterType::Triangle,
-    );
-    let rgb_img: ImageBuffer<Rgb<u8>, Vec<u8>> = resized_img.to_rgb8();
+// Handler for POST /detect/ - boxes, landmarks and confidences of every face
+// of an image, without embedding them; an image without a face answers an
+// empty list
+pub async fn detect(
+    State(state): State<AppState>,
+    Json(payload): Json<DetectPayload>,
+) -> Result<Json<DetectResponse>, StatusCode> {
+    let start = Instant::now();
+    let Some(detector) = state.detector.clone() else {
+        tracing::warn!("Detection requested but no DETECTOR_MODEL_PATH is configured");
+        return Err(StatusCode::BAD_REQUEST);
+    };
+    let image = ImageInput::Base64(&payload.image_base64);
+    if image.is_empty() {
+        tracing::warn!("Received detection request with empty image");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let slot = match &state.inference_queue {
+        Some(queue) => Some(queue.acquire().await?),
+        None => None,
+    };
+    let image = image.to_owned_image();
+    let response = run_bounded(&state, slot, move |state| {
+        let (frames, scale) = decode_frames(image.as_input(), &state.image_limits)?;
+        let img = select_frame(frames, state)?;
+        let faces = detect_all(&detector, &img)?;
+        let (width, height) = img.dimensions();
+        Ok(DetectResponse {
+            width: (width as f32 * scale).round() as u32,
+            height: (height as f32 * scale).round() as u32,
+            faces: faces.into_iter().map(|face| face.scaled(scale)).collect(),
+        })
+    })
+    .await?;
+    tracing::info!(faces = response.faces.len(), duration = ?start.elapsed(), "Detection successful");
+    Ok(Json(response))
+}
+
+// Every face the detector finds, largest first
+fn detect_all(
+    detector: &FaceDetector,
+    img: &DynamicImage,
+) -> Result<Vec<DetectedFace>, StatusCode> {
+    let detect_start = Instant::now();
+    let faces = tracing::info_span!("detect")
+        .in_scope(|| detector.detect(img))
+        .map_err(|e| {
+            tracing::error!(error = %e, "Face detection failed");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    telemetry::observe_stage(telemetry::STAGE_DETECT, detect_start.elapsed());
+    Ok(faces)
+}
 
-    let mut input_tensor = Array::zeros((1, 3, target_height as usize, target_width as usize));
+#[derive(Serialize)]
+pub struct FaceLandmarks {
+    // [x1, y1, x2, y2]
+    pub bbox: [f32; 4],
+    pub score: f32,
+    // Eyes, nose tip and mouth corners, from the detector
+    pub landmarks_5: [[f32; 2]; 5],
+    // Points of the landmark model, when LANDMARKS_MODEL_PATH is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub landmarks_68: Option<Vec<[f32; 2]>>,
+}
 
-    for (x, y, pixel) in rgb_img.enumerate_pixels() {
-        let r = pixel[0] as f32;
-        let g = pixel[1] as f32;
-        let b = pixel[2] as f32;
+#[derive(Serialize)]
+pub struct LandmarksResponse {
+    pub width: u32,
+    pub height: u32,
+    // Largest first
+    pub faces: Vec<FaceLandmarks>,
+}
 
-        input_tensor[[0, 0, y as usize, x as usize]] = (b - 127.5) / 128.0;
-        input_tensor[[0, 1, y as usize, x as usize]] = (g - 127.5) / 128.0;
-        input_tensor[[0, 2, y as usize, x as usize]] = (r - 127.5) / 128.0;
+// Handler for POST /landmarks/ - keypoints of every face of an image: the
+// five of the detector, and the dense ones of the landmark model when
+// configured. Takes the body of /detect/.
+pub async fn landmarks(
+    State(state): State<AppState>,
+    Json(payload): Json<DetectPayload>,
+) -> Result<Json<LandmarksResponse>, StatusCode> {
+    let start = Instant::now();
+    let Some(detector) = state.detector.clone() else {
+        tracing::warn!("Landmarks requested but no DETECTOR_MODEL_PATH is configured");
+        return Err(StatusCode::BAD_REQUEST);
+    };
+    let image = ImageInput::Base64(&payload.image_base64);
+    if image.is_empty() {
+        tracing::warn!("Received landmarks request with empty image");
+        return Err(StatusCode::BAD_REQUEST);
     }
+    let slot = match &state.inference_queue {
+        Some(queue) => Some(queue.acquire().await?),
+        None => None,
+    };
+    let image = image.to_owned_image();
+    let response = run_bounded(&state, slot, move |state| {
+        let (frames, scale) = decode_frames(image.as_input(), &state.image_limits)?;
+        let img = select_frame(frames, state)?;
+        let mut detected = detect_all(&detector, &img)?;
+        // Every face costs a landmark model run
+        detected.truncate(MAX_FACES);
 
-    Ok(input_tensor)
+        let landmarks_start = Instant::now();
+        let mut faces = Vec::with_capacity(detected.len());
+        for face in detected {
+            let dense = match &state.landmarks {
+                Some(model) => Some(
+                    tracing::info_span!("landmarks")
+                        .in_scope(|| model.locate(&img, &face))
+                        .map_err(|e| {
+                            tracing::error!(error = %e, "Landmark estimation failed");
+                            StatusCode::INTERNAL_SERVER_ERROR
+                        })?,
+                ),
+                None => None,
+            };
+            let face = face.scaled(scale);
+            faces.push(FaceLandmarks {
+                bbox: face.bbox,
+                score: face.score,
+                landmarks_5: face.landmarks,
+                landmarks_68: dense.map(|points| {
+                    points
+                        .into_iter()
+                        .map(|[x, y]| [x * scale, y * scale])
+                        .collect()
+                }),
+            });
+        }
+        if state.landmarks.is_some() {
+            telemetry::observe_stage(telemetry::STAGE_LANDMARKS, landmarks_start.elapsed());
+        }
+        let (width, height) = img.dimensions();
+        Ok(LandmarksResponse {
+            width: (width as f32 * scale).round() as u32,
+            height: (height as f32 * scale).round() as u32,
+            faces,
+        })
+    })
+    .await?;
+    tracing::info!(faces = response.faces.len(), duration = ?start.elapsed(), "Landmarks located");
+    Ok(Json(response))
 }
@@ -0,0 +1,280 @@
+use async_graphql::SimpleObject;
+use axum::{
+    extract::{Extension, Query, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Row};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+use crate::store::SearchResults;
+use crate::tenant::Tenant;
+use crate::util;
+use crate::AppState;
+
+// Searches waiting for the writer; more are dropped, searches never wait
+const QUEUE_SIZE: usize = 10_000;
+// Searches inserted per transaction
+const BATCH_EVENTS: usize = 500;
+const DEFAULT_QUERY_LIMIT: i64 = 100;
+const MAX_QUERY_LIMIT: i64 = 1000;
+
+// A match of a recorded search
+#[derive(Serialize, Deserialize, SimpleObject)]
+pub struct RecordedMatch {
+    // Index of the query face, 0 being the largest
+    face: usize,
+    target_uuid: Uuid,
+    similarity: f32,
+    origin: String,
+}
+
+// One search, as kept in the history
+pub struct SearchEvent {
+    pub tenant: String,
+    pub collection: String,
+    // Camera of the searches of the RTSP workers
+    pub source: Option<String>,
+    // SHA-256 of the query image
+    pub query_hash: String,
+    pub threshold: f32,
+    pub latency_ms: u64,
+    // Faces searched in the image
+    pub faces: usize,
+    pub matches: Vec<RecordedMatch>,
+}
+
+// Matches of every face of a search, best first per face
+pub fn recorded_matches(found: &SearchResults) -> Vec<RecordedMatch> {
+    std::iter::once(found)
+        .chain(&found.other_faces)
+        .enumerate()
+        .flat_map(|(face, found)| {
+            found.matches.iter().map(move |found| RecordedMatch {
+                face,
+                target_uuid: found.uuid,
+                similarity: found.similarity,
+                origin: found.origin.clone(),
+            })
+        })
+        .collect()
+}
+
+// Persists every search with its outcome, written in the background so
+// searches do not wait on the database
+pub struct SearchHistory {
+    sender: mpsc::Sender<(i64, SearchEvent)>,
+    closing: Arc<Notify>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl SearchHistory {
+    pub fn start(pool: PgPool) -> Self {
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        let closing = Arc::new(Notify::new());
+        let writer = tokio::spawn(write_events(pool, receiver, closing.clone()));
+        Self {
+            sender,
+            closing,
+            writer: Mutex::new(Some(writer)),
+        }
+    }
+
+    // Stops taking searches and waits for the queued ones to be written, at
+    // shutdown
+    pub async fn close(&self) {
+        self.closing.notify_one();
+        let writer = self.writer.lock().unwrap_or_else(|e| e.into_inner()).take();
+        if let Some(writer) = writer {
+            writer.await.ok();
+        }
+    }
+
+    pub fn record(&self, event: SearchEvent) {
+        if self.sender.try_send((util::unix_now(), event)).is_err() {
+            tracing::warn!("Search history queue full, search not recorded");
+        }
+    }
+}
+
+async fn write_events(
+    pool: PgPool,
+    mut receiver: mpsc::Receiver<(i64, SearchEvent)>,
+    closing: Arc<Notify>,
+) {
+    let mut batch = Vec::with_capacity(BATCH_EVENTS);
+    loop {
+        let event = tokio::select! {
+            event = receiver.recv() => event,
+            _ = closing.notified() => {
+                // Queued searches are still received, then `recv` ends
+                receiver.close();
+                continue;
+            }
+        };
+        let Some(event) = event else {
+            break;
+        };
+        batch.push(event);
+        while batch.len() < BATCH_EVENTS {
+            match receiver.try_recv() {
+                Ok(event) => batch.push(event),
+                Err(_) => break,
+            }
+        }
+        if let Err(e) = write_batch(&pool, &batch).await {
+            tracing::error!(searches = batch.len(), error = %e, "Failed to write search history");
+        }
+        batch.clear();
+    }
+}
+
+async fn write_batch(pool: &PgPool, batch: &[(i64, SearchEvent)]) -> Result<(), sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    for (created_at, event) in batch {
+        let mut matched_uuids: Vec<Uuid> = event
+            .matches
+            .iter()
+            .map(|found| found.target_uuid)
+            .collect();
+        matched_uuids.sort();
+        matched_uuids.dedup();
+        sqlx::query(
+            "INSERT INTO search_events (created_at, tenant, collection, source, query_hash, threshold, latency_ms, faces, matches, matched_uuids) VALUES (to_timestamp($1), $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+        )
+        .bind(*created_at as f64)
+        .bind(&event.tenant)
+        .bind(&event.collection)
+        .bind(&event.source)
+        .bind(&event.query_hash)
+        .bind(event.threshold)
+        .bind(event.latency_ms as i32)
+        .bind(event.faces as i32)
+        .bind(sqlx::types::Json(&event.matches))
+        .bind(&matched_uuids)
+        .execute(&mut *transaction)
+        .await?;
+    }
+    transaction.commit().await
+}
+
+#[derive(Deserialize, Default)]
+pub struct SearchesQuery {
+    // Searches that matched this target
+    pub target_uuid: Option<Uuid>,
+    pub collection: Option<String>,
+    pub source: Option<String>,
+    // Unix seconds, inclusive
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    // Searches older than this id, for paging back from `next_before_id`
+    pub before_id: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize, SimpleObject)]
+pub struct RecordedSearch {
+    pub id: i64,
+    timestamp: String,
+    collection: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+    query_hash: String,
+    threshold: f32,
+    latency_ms: i32,
+    faces: i32,
+    matches: Vec<RecordedMatch>,
+}
+
+// Define the response for /searches
+#[derive(Serialize)]
+pub struct SearchesResponse {
+    searches: Vec<RecordedSearch>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_before_id: Option<i64>,
+}
+
+// Handler for GET /searches - past searches of the tenant, newest first
+pub async fn list_searches(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Query(query): Query<SearchesQuery>,
+) -> Result<Json<SearchesResponse>, StatusCode> {
+    if state.search_history.is_none() {
+        tracing::warn!("Search history requested but SEARCH_HISTORY is disabled");
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_QUERY_LIMIT)
+        .clamp(1, MAX_QUERY_LIMIT);
+    let searches = fetch_searches(&state.read_pool, tenant.id(), &query, limit)
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, "Failed to query search history");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let next_before_id = (searches.len() as i64 == limit)
+        .then(|| searches.last().map(|search| search.id))
+        .flatten();
+    Ok(Json(SearchesResponse {
+        searches,
+        next_before_id,
+    }))
+}
+
+// Searches of a tenant matching `query`, newest first; `limit` replaces
+// `query.limit`
+pub(crate) async fn fetch_searches(
+    pool: &PgPool,
+    tenant: &str,
+    query: &SearchesQuery,
+    limit: i64,
+) -> Result<Vec<RecordedSearch>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"
+        SELECT id, EXTRACT(EPOCH FROM created_at)::BIGINT AS created_at, collection, source,
+               query_hash, threshold, latency_ms, faces, matches
+        FROM search_events
+        WHERE tenant = $1
+          AND ($2::UUID IS NULL OR matched_uuids @> ARRAY[$2::UUID])
+          AND ($3::TEXT IS NULL OR collection = $3)
+          AND ($4::TEXT IS NULL OR source = $4)
+          AND ($5::BIGINT IS NULL OR created_at >= to_timestamp($5))
+          AND ($6::BIGINT IS NULL OR created_at <= to_timestamp($6))
+          AND ($7::BIGINT IS NULL OR id < $7)
+        ORDER BY id DESC
+        LIMIT $8
+        "#,
+    )
+    .bind(tenant)
+    .bind(query.target_uuid)
+    .bind(&query.collection)
+    .bind(&query.source)
+    .bind(query.since)
+    .bind(query.until)
+    .bind(query.before_id)
+    .bind(limit)
+    .fetch_all(pool)
+    .await?;
+    rows.iter()
+        .map(|row| {
+            let matches: sqlx::types::Json<Vec<RecordedMatch>> = row.try_get("matches")?;
+            Ok(RecordedSearch {
+                id: row.try_get("id")?,
+                timestamp: util::format_rfc3339(row.try_get("created_at")?),
+                collection: row.try_get("collection")?,
+                source: row.try_get("source")?,
+                query_hash: row.try_get("query_hash")?,
+                threshold: row.try_get("threshold")?,
+                latency_ms: row.try_get("latency_ms")?,
+                faces: row.try_get("faces")?,
+                matches: matches.0,
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,315 @@
+use axum::{
+    body::{self, Body},
+    extract::{Request, State},
+    http::{header, HeaderName, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    RequestExt,
+};
+use sha2::{Digest, Sha256};
+use sqlx::{PgPool, Row};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::tenant::Tenant;
+use crate::AppState;
+
+pub static IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+// Set on responses replayed from a previous request with the same key
+static IDEMPOTENT_REPLAYED: HeaderName = HeaderName::from_static("idempotent-replayed");
+const MAX_KEY_LENGTH: usize = 255;
+// A claim this old without an outcome belongs to a request that never
+// finished (e.g. the instance died), and the key can be claimed again
+const IN_FLIGHT_TIMEOUT: Duration = Duration::from_secs(300);
+const PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+
+// Outcomes of registrations by Idempotency-Key header, kept in Postgres so a
+// retry reaching another instance gets the same answer
+pub struct Idempotency {
+    pool: PgPool,
+    ttl: Duration,
+}
+
+// Response of the first request with a key
+struct Outcome {
+    status: StatusCode,
+    content_type: Option<HeaderValue>,
+    body: Vec<u8>,
+}
+
+// What a request holding a key finds
+enum Claim {
+    // First request with the key, or its outcome has expired: run it
+    Claimed,
+    // Another request with the key has not finished yet
+    InFlight,
+    // Outcome of the first request
+    Done(Outcome),
+    // The first request with the key had another body
+    Mismatch,
+}
+
+impl Idempotency {
+    // Also purges the expired keys in the background
+    pub fn start(pool: PgPool, ttl: Duration) -> Self {
+        tokio::spawn(purge(pool.clone(), ttl));
+        Self { pool, ttl }
+    }
+
+    async fn claim(&self, tenant: &str, key: &str, hash: &[u8]) -> Result<Claim, sqlx::Error> {
+        let claimed = sqlx::query(
+            r#"
+            INSERT INTO idempotency_keys (tenant, key, request_hash) VALUES ($1, $2, $5)
+            ON CONFLICT (tenant, key) DO UPDATE
+            SET created_at = now(), status = NULL, body = NULL, content_type = NULL, request_hash = EXCLUDED.request_hash
+            WHERE idempotency_keys.created_at <= now() - $3::BIGINT * interval '1 second'
+               OR (idempotency_keys.status IS NULL AND idempotency_keys.created_at <= now() - $4::BIGINT * interval '1 second')
+            "#,
+        )
+        .bind(tenant)
+        .bind(key)
+        .bind(self.ttl.as_secs() as i64)
+        .bind(IN_FLIGHT_TIMEOUT.as_secs() as i64)
+        .bind(hash)
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+        if claimed > 0 {
+            return Ok(Claim::Claimed);
+        }
+        let row = sqlx::query(
+            "SELECT status, body, content_type, request_hash FROM idempotency_keys WHERE tenant = $1 AND key = $2",
+        )
+        .bind(tenant)
+        .bind(key)
+        .fetch_one(&self.pool)
+        .await?;
+        // Keys claimed before hashes were stored match any body
+        let request_hash: Option<Vec<u8>> = row.get("request_hash");
+        if request_hash.is_some_and(|stored| stored != hash) {
+            return Ok(Claim::Mismatch);
+        }
+        let status: Option<i16> = row.get("status");
+        let Some(status) = status.and_then(|status| StatusCode::from_u16(status as u16).ok())
+        else {
+            return Ok(Claim::InFlight);
+        };
+        let body = row.get::<Option<Vec<u8>>, _>("body").unwrap_or_default();
+        // Outcomes stored before the content type were all JSON
+        let content_type = match row.get::<Option<String>, _>("content_type") {
+            Some(content_type) => HeaderValue::try_from(content_type).ok(),
+            None => (!body.is_empty()).then(|| HeaderValue::from_static("application/json")),
+        };
+        Ok(Claim::Done(Outcome {
+            status,
+            content_type,
+            body,
+        }))
+    }
+
+    async fn complete(
+        &self,
+        tenant: &str,
+        key: &str,
+        status: StatusCode,
+        content_type: Option<&str>,
+        body: &[u8],
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE idempotency_keys SET status = $3, body = $4, content_type = $5 WHERE tenant = $1 AND key = $2",
+        )
+        .bind(tenant)
+        .bind(key)
+        .bind(status.as_u16() as i16)
+        .bind(body)
+        .bind(content_type)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    // Frees the key of a request that failed on the server side, so a retry
+    // runs again
+    async fn release(&self, tenant: &str, key: &str) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "DELETE FROM idempotency_keys WHERE tenant = $1 AND key = $2 AND status IS NULL",
+        )
+        .bind(tenant)
+        .bind(key)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
+
+// Key claimed by a request still running. Dropped before its outcome is
+// stored (the client went away and the handler was cancelled), it frees the
+// key so a retry runs again instead of finding it in flight until it times out.
+struct InFlightKey {
+    idempotency: Arc<Idempotency>,
+    tenant: String,
+    key: String,
+    armed: bool,
+}
+
+impl InFlightKey {
+    async fn release(mut self) {
+        self.armed = false;
+        if let Err(e) = self.idempotency.release(&self.tenant, &self.key).await {
+            tracing::warn!(key = %self.key, error = %e, "Failed to release Idempotency-Key");
+        }
+    }
+
+    fn keep(mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for InFlightKey {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+        let idempotency = self.idempotency.clone();
+        let tenant = std::mem::take(&mut self.tenant);
+        let key = std::mem::take(&mut self.key);
+        tokio::spawn(async move {
+            if let Err(e) = idempotency.release(&tenant, &key).await {
+                tracing::warn!(%key, error = %e, "Failed to release Idempotency-Key");
+            }
+        });
+    }
+}
+
+async fn purge(pool: PgPool, ttl: Duration) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let purged = sqlx::query(
+            "DELETE FROM idempotency_keys WHERE created_at <= now() - $1::BIGINT * interval '1 second'",
+        )
+        .bind(ttl.as_secs() as i64)
+        .execute(&pool)
+        .await;
+        match purged {
+            Ok(result) => tracing::debug!(
+                purged = result.rows_affected(),
+                "Expired idempotency keys purged"
+            ),
+            Err(e) => tracing::warn!(error = %e, "Failed to purge expired idempotency keys"),
+        }
+    }
+}
+
+// Route layer of /register/ and /collections/{name}/register/: the first request with an Idempotency-Key runs,
+// retries with the same key get its response replayed instead of registering
+// again. Server errors are not kept, so their retries run again. A retry
+// with another body or collection than the first request is answered 422.
+pub async fn replay(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let (Some(idempotency), Some(key)) = (
+        state.idempotency.clone(),
+        request.headers().get(&IDEMPOTENCY_KEY),
+    ) else {
+        return next.run(request).await;
+    };
+    let key = match key.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LENGTH => key.to_string(),
+        _ => {
+            tracing::warn!("Received request with an invalid Idempotency-Key");
+            return StatusCode::BAD_REQUEST.into_response();
+        }
+    };
+    let tenant = request
+        .extensions()
+        .get::<Tenant>()
+        .map(|tenant| tenant.id().to_string())
+        .unwrap_or_default();
+
+    // The body is read here to hash it, within the limit of the route
+    let (parts, request_body) = request.with_limited_body().into_parts();
+    let request_bytes = match body::to_bytes(request_body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            tracing::warn!(%key, error = %e, "Failed to read request of Idempotency-Key");
+            return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+        }
+    };
+    // The path too, so a key reused for another collection is a mismatch
+    let hash = Sha256::new()
+        .chain_update(parts.uri.path())
+        .chain_update(&request_bytes)
+        .finalize();
+    let request = Request::from_parts(parts, Body::from(request_bytes));
+
+    match idempotency.claim(&tenant, &key, &hash).await {
+        Ok(Claim::Claimed) => {}
+        Ok(Claim::Mismatch) => {
+            tracing::warn!(%key, "Idempotency-Key reused with another request body");
+            return StatusCode::UNPROCESSABLE_ENTITY.into_response();
+        }
+        Ok(Claim::InFlight) => {
+            tracing::warn!(%key, "Request with the same Idempotency-Key still in flight");
+            return StatusCode::CONFLICT.into_response();
+        }
+        Ok(Claim::Done(outcome)) => {
+            tracing::info!(%key, status = %outcome.status, "Replaying response of Idempotency-Key");
+            let mut response = (outcome.status, outcome.body).into_response();
+            // The same Content-Type as the first response, JSON or protobuf
+            match outcome.content_type {
+                Some(content_type) => {
+                    response
+                        .headers_mut()
+                        .insert(header::CONTENT_TYPE, content_type);
+                }
+                None => {
+                    response.headers_mut().remove(header::CONTENT_TYPE);
+                }
+            }
+            response.headers_mut().insert(
+                IDEMPOTENT_REPLAYED.clone(),
+                HeaderValue::from_static("true"),
+            );
+            return response;
+        }
+        Err(e) => {
+            tracing::error!(%key, error = %e, "Failed to claim Idempotency-Key");
+            return StatusCode::SERVICE_UNAVAILABLE.into_response();
+        }
+    }
+
+    let in_flight = InFlightKey {
+        idempotency: idempotency.clone(),
+        tenant: tenant.clone(),
+        key: key.clone(),
+        armed: true,
+    };
+    let response = next.run(request).await;
+    let status = response.status();
+    if status.is_server_error() {
+        in_flight.release().await;
+        return response;
+    }
+    // The registration went through: from here on the key stays taken
+    in_flight.keep();
+    let (parts, response_body) = response.into_parts();
+    let bytes = match body::to_bytes(response_body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            tracing::error!(%key, error = %e, "Failed to read response of Idempotency-Key");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let content_type = parts
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    if let Err(e) = idempotency
+        .complete(&tenant, &key, status, content_type, &bytes)
+        .await
+    {
+        // The registration went through; a retry finds the key in flight
+        // until it times out
+        tracing::error!(%key, error = %e, "Failed to store outcome of Idempotency-Key");
+    }
+    Response::from_parts(parts, Body::from(bytes))
+}
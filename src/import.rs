@@ -0,0 +1,268 @@
+use axum::{
+    body::Bytes,
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::time::Instant;
+use uuid::Uuid;
+
+use crate::collections::DEFAULT_COLLECTION;
+use crate::encryption;
+use crate::jobs::JobProgress;
+use crate::notify::Change;
+use crate::store::Metadata;
+use crate::tenant::DEFAULT_TENANT;
+use crate::AppState;
+
+pub const DEFAULT_MAX_IMPORT_BYTES: usize = 512 * 1024 * 1024;
+// Rows per database transaction
+const BATCH_ROWS: usize = 1000;
+// Rejected rows listed in the response; the count covers all of them
+const MAX_REPORTED_ERRORS: usize = 100;
+// Width of the origin column
+const MAX_ORIGIN_LEN: usize = 64;
+
+#[derive(Deserialize, Default, Clone, Copy, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportFormat {
+    #[default]
+    Jsonl,
+    Csv,
+}
+
+#[derive(Deserialize)]
+pub struct ImportQuery {
+    #[serde(default)]
+    format: ImportFormat,
+    tenant: Option<String>,
+    collection: Option<String>,
+}
+
+// One registration of the file
+#[derive(Deserialize)]
+struct ImportRecord {
+    uuid: Uuid,
+    origin: String,
+    embedding: Vec<f32>,
+    #[serde(default)]
+    metadata: Metadata,
+}
+
+#[derive(Serialize)]
+pub struct RejectedRow {
+    // 1-based line of the file
+    line: usize,
+    error: String,
+}
+
+// Define the response for /admin/import
+#[derive(Serialize)]
+pub struct ImportResponse {
+    imported: usize,
+    rejected: usize,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<RejectedRow>,
+}
+
+// `uuid,origin,v0,v1,...`; an optional header row starts with `uuid`
+fn parse_csv_line(line: &str) -> Result<Option<ImportRecord>, String> {
+    let mut fields = line.split(',').map(str::trim);
+    let uuid = fields.next().unwrap_or_default();
+    if uuid.eq_ignore_ascii_case("uuid") {
+        return Ok(None);
+    }
+    let uuid = Uuid::parse_str(uuid).map_err(|e| format!("invalid uuid: {}", e))?;
+    let origin = fields.next().ok_or("missing origin")?.to_string();
+    let embedding = fields
+        .map(|value| value.parse::<f32>())
+        .collect::<Result<Vec<f32>, _>>()
+        .map_err(|e| format!("invalid embedding value: {}", e))?;
+    Ok(Some(ImportRecord {
+        uuid,
+        origin,
+        embedding,
+        metadata: Metadata::new(),
+    }))
+}
+
+fn parse_line(format: ImportFormat, line: &str) -> Result<Option<ImportRecord>, String> {
+    match format {
+        ImportFormat::Jsonl => serde_json::from_str(line)
+            .map(Some)
+            .map_err(|e| e.to_string()),
+        ImportFormat::Csv => parse_csv_line(line),
+    }
+}
+
+fn validate(record: &ImportRecord, dimension: usize) -> Result<(), String> {
+    if record.uuid.is_nil() {
+        return Err("nil uuid".to_string());
+    }
+    if record.origin.trim().is_empty() || record.origin.len() > MAX_ORIGIN_LEN {
+        return Err(format!("origin must be 1 to {} bytes", MAX_ORIGIN_LEN));
+    }
+    if record.embedding.len() != dimension {
+        return Err(format!(
+            "embedding has {} values, expected {}",
+            record.embedding.len(),
+            dimension
+        ));
+    }
+    if record.embedding.iter().any(|value| !value.is_finite()) {
+        return Err("embedding has non-finite values".to_string());
+    }
+    Ok(())
+}
+
+// Handler for POST /admin/import - registers precomputed embeddings from a
+// JSONL or CSV file, e.g. when migrating from another face system
+pub async fn import_embeddings(
+    State(state): State<AppState>,
+    Query(query): Query<ImportQuery>,
+    body: Bytes,
+) -> Result<Json<ImportResponse>, StatusCode> {
+    let tenant = query.tenant.as_deref().unwrap_or(DEFAULT_TENANT);
+    let name = query.collection.as_deref().unwrap_or(DEFAULT_COLLECTION);
+    let Ok(text) = std::str::from_utf8(&body) else {
+        tracing::warn!("Received import file that is not UTF-8");
+        return Err(StatusCode::BAD_REQUEST);
+    };
+    import_into(
+        &state,
+        tenant,
+        name,
+        query.format,
+        text,
+        &JobProgress::default(),
+    )
+    .await
+    .map(Json)
+}
+
+// Validates the rows of the file and stores them in batches; shared with the
+// `import` command and import jobs, whose progress counts the rows stored
+pub(crate) async fn import_into(
+    state: &AppState,
+    tenant: &str,
+    name: &str,
+    format: ImportFormat,
+    text: &str,
+    progress: &JobProgress,
+) -> Result<ImportResponse, StatusCode> {
+    let start = Instant::now();
+    let Some(collection) = state.collections.get(tenant, name) else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+
+    // Vectors must match the model, or the gallery when the model output is
+    // dynamic, or else the first row of the file
+    let mut dimension = state
+        .embedding_dimension
+        .or_else(|| collection.store.dimension());
+    let mut records = Vec::new();
+    let mut rejected = 0;
+    let mut errors = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let parsed = parse_line(format, line).and_then(|record| match record {
+            Some(record) => {
+                let expected = *dimension.get_or_insert(record.embedding.len());
+                validate(&record, expected).map(|_| Some(record))
+            }
+            None => Ok(None),
+        });
+        match parsed {
+            Ok(Some(record)) => records.push(record),
+            Ok(None) => {}
+            Err(error) => {
+                rejected += 1;
+                if errors.len() < MAX_REPORTED_ERRORS {
+                    errors.push(RejectedRow {
+                        line: index + 1,
+                        error,
+                    });
+                }
+            }
+        }
+    }
+    tracing::info!(
+        tenant,
+        collection = %name,
+        rows = records.len(),
+        rejected,
+        "Importing embeddings..."
+    );
+
+    // Each batch is committed, then made searchable; a failed batch stops the
+    // import with the earlier ones kept
+    let mut imported = 0;
+    for batch in records.chunks(BATCH_ROWS) {
+        let stored = async {
+            let mut transaction = state.bulk_pool.begin().await?;
+            for record in batch {
+                let (plain, sealed) = encryption::columns(&record.embedding)?;
+                sqlx::query(
+                    "INSERT INTO targets (uuid, embeddings, embeddings_sealed, origin, metadata, collection, tenant, model) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+                )
+                .bind(record.uuid)
+                .bind(plain)
+                .bind(sealed)
+                .bind(&record.origin)
+                .bind(sqlx::types::Json(&record.metadata))
+                .bind(name)
+                .bind(tenant)
+                .bind(state.version.model_version())
+                .execute(&mut *transaction)
+                .await?;
+            }
+            if let Some(notifier) = &state.notifier {
+                let uuids: HashSet<Uuid> = batch.iter().map(|record| record.uuid).collect();
+                for uuid in uuids {
+                    notifier
+                        .publish(
+                            &mut *transaction,
+                            Change::Target {
+                                tenant: tenant.to_string(),
+                                collection: name.to_string(),
+                                uuid,
+                            },
+                        )
+                        .await?;
+                }
+            }
+            transaction.commit().await
+        }
+        .await;
+        if let Err(e) = stored {
+            tracing::error!(imported, error = %e, "Failed to store imported embeddings");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        for record in batch {
+            collection
+                .store
+                .add(
+                    record.uuid,
+                    record.origin.clone(),
+                    record.metadata.clone(),
+                    record.embedding.clone(),
+                    Some(state.version.model_version().to_string()),
+                )
+                .await;
+        }
+        state.collections.enforce_memory_limits().await;
+        imported += batch.len();
+        progress.set(imported, Some(records.len()));
+    }
+    tracing::info!(imported, rejected, duration = ?start.elapsed(), "Embeddings imported");
+
+    Ok(ImportResponse {
+        imported,
+        rejected,
+        errors,
+    })
+}
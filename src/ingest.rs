@@ -0,0 +1,209 @@
+use async_nats::jetstream::{self, consumer::pull, consumer::AckPolicy, AckKind, Message};
+use axum::http::StatusCode;
+use serde::Deserialize;
+use std::time::Duration;
+use tokio_stream::StreamExt;
+use uuid::Uuid;
+
+use crate::backpressure::{with_priority, Priority};
+use crate::collections::{self, DEFAULT_COLLECTION};
+use crate::handlers::{self, ImageInput, RegisterMode, RegisterPayload};
+use crate::store::Metadata;
+use crate::tenant::{Tenant, DEFAULT_TENANT};
+use crate::AppState;
+
+// Delay before a message that failed for a transient reason is redelivered
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+// Delay before reconnecting after the consumer itself failed
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+const IMAGE_FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+// JetStream source of enrollment messages
+#[derive(Clone, Debug)]
+pub struct IngestConfig {
+    pub url: String,
+    pub stream: String,
+    pub subject: String,
+    // Durable consumer name, shared by all instances so each message is
+    // processed once
+    pub consumer: String,
+}
+
+// One enrollment, the message equivalent of a /register/ body; the image is
+// inline (`image_base64`) or fetched from `image_url`
+#[derive(Deserialize)]
+struct EnrollmentMessage {
+    target_uuid: Uuid,
+    origin: String,
+    image_base64: Option<String>,
+    image_url: Option<String>,
+    collection: Option<String>,
+    tenant: Option<String>,
+    #[serde(default)]
+    metadata: Metadata,
+    #[serde(default)]
+    mode: RegisterMode,
+    // Unix time (seconds) after which the registration is deleted
+    expires_at: Option<i64>,
+}
+
+enum Outcome {
+    // Persisted; acknowledge
+    Registered,
+    // Will never succeed (bad message, unknown collection...); terminate
+    Rejected,
+    // Might succeed later (database or image host down); redeliver
+    Retry,
+}
+
+// Consumes enrollments until the process exits, reconnecting on failures
+pub async fn run(state: AppState, config: IngestConfig) {
+    let client = match reqwest::Client::builder()
+        .timeout(IMAGE_FETCH_TIMEOUT)
+        .build()
+    {
+        Ok(client) => client,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to build image fetch client, ingestion disabled");
+            return;
+        }
+    };
+    loop {
+        if let Err(e) = consume(&state, &config, &client).await {
+            tracing::error!(error = %e, "Enrollment consumer failed, reconnecting");
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn consume(
+    state: &AppState,
+    config: &IngestConfig,
+    client: &reqwest::Client,
+) -> Result<(), Error> {
+    let jetstream = jetstream::new(async_nats::connect(&config.url).await?);
+    let stream = jetstream
+        .get_or_create_stream(jetstream::stream::Config {
+            name: config.stream.clone(),
+            subjects: vec![config.subject.clone()],
+            ..Default::default()
+        })
+        .await?;
+    let consumer = stream
+        .get_or_create_consumer(
+            &config.consumer,
+            pull::Config {
+                durable_name: Some(config.consumer.clone()),
+                filter_subject: config.subject.clone(),
+                ack_policy: AckPolicy::Explicit,
+                ..Default::default()
+            },
+        )
+        .await?;
+    tracing::info!(config = ?config, "Consuming enrollment messages");
+
+    let mut messages = consumer.messages().await?;
+    while let Some(message) = messages.next().await {
+        let message = message?;
+        // Acknowledged only once the registration is in the database, so a
+        // crash in between means a redelivery, never a lost enrollment. Bulk
+        // enrollments yield the inference slots to the requests.
+        let ack = match with_priority(Priority::Low, enroll(state, client, &message)).await {
+            Outcome::Registered => message.ack().await,
+            Outcome::Rejected => message.ack_with(AckKind::Term).await,
+            Outcome::Retry => message.ack_with(AckKind::Nak(Some(RETRY_DELAY))).await,
+        };
+        if let Err(e) = ack {
+            tracing::warn!(error = %e, "Failed to acknowledge enrollment message");
+        }
+    }
+    Ok(())
+}
+
+async fn enroll(state: &AppState, client: &reqwest::Client, message: &Message) -> Outcome {
+    let enrollment: EnrollmentMessage = match serde_json::from_slice(&message.payload) {
+        Ok(enrollment) => enrollment,
+        Err(e) => {
+            tracing::warn!(subject = %message.subject, error = %e, "Rejected malformed enrollment message");
+            return Outcome::Rejected;
+        }
+    };
+    let target_uuid = enrollment.target_uuid;
+    let tenant = Tenant(
+        enrollment
+            .tenant
+            .unwrap_or_else(|| DEFAULT_TENANT.to_string()),
+    );
+    if !collections::is_valid_name(tenant.id()) {
+        tracing::warn!(%target_uuid, "Rejected enrollment with invalid tenant");
+        return Outcome::Rejected;
+    }
+    let collection = enrollment
+        .collection
+        .unwrap_or_else(|| DEFAULT_COLLECTION.to_string());
+
+    let fetched;
+    let image = match (&enrollment.image_base64, &enrollment.image_url) {
+        (Some(image_base64), _) => ImageInput::Base64(image_base64),
+        (None, Some(image_url)) => match fetch_image(client, image_url).await {
+            Ok(bytes) => {
+                fetched = bytes;
+                ImageInput::Bytes(&fetched)
+            }
+            Err(outcome) => return outcome,
+        },
+        (None, None) => {
+            tracing::warn!(%target_uuid, "Rejected enrollment without image");
+            return Outcome::Rejected;
+        }
+    };
+
+    let payload = RegisterPayload {
+        target_uuid,
+        image_base64: String::new(),
+        embedding: None,
+        origin: enrollment.origin,
+        metadata: enrollment.metadata,
+        register_all_faces: false,
+        face_index: None,
+        return_crops: false,
+        return_embedding: false,
+        mode: enrollment.mode,
+        expires_at: enrollment.expires_at,
+        reject_if_similar_above: None,
+    };
+    match handlers::register_into(state, &tenant, &collection, &payload, image).await {
+        Ok(_) => Outcome::Registered,
+        Err(e) if e.status.is_server_error() && e.status != StatusCode::INSUFFICIENT_STORAGE => {
+            tracing::warn!(%target_uuid, status = %e.status, "Enrollment failed, will be retried");
+            Outcome::Retry
+        }
+        Err(e) => {
+            tracing::warn!(%target_uuid, status = %e.status, quality = ?e.quality, "Enrollment rejected");
+            Outcome::Rejected
+        }
+    }
+}
+
+async fn fetch_image(client: &reqwest::Client, image_url: &str) -> Result<Vec<u8>, Outcome> {
+    let response = client.get(image_url).send().await.map_err(|e| {
+        tracing::warn!(%image_url, error = %e, "Failed to fetch enrollment image");
+        Outcome::Retry
+    })?;
+    let status = response.status();
+    if !status.is_success() {
+        tracing::warn!(%image_url, %status, "Failed to fetch enrollment image");
+        return Err(if status.is_client_error() {
+            Outcome::Rejected
+        } else {
+            Outcome::Retry
+        });
+    }
+    let bytes = response.bytes().await.map_err(|e| {
+        tracing::warn!(%image_url, error = %e, "Failed to read enrollment image");
+        Outcome::Retry
+    })?;
+    Ok(bytes.to_vec())
+}
@@ -0,0 +1,263 @@
+use rand::{rngs::StdRng, seq::index, Rng, SeedableRng};
+use rayon::prelude::*;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::collections::Collections;
+use crate::snapshot;
+
+pub const DEFAULT_NPROBE: usize = 8;
+pub const DEFAULT_TRAIN_INTERVAL: Duration = Duration::from_secs(60);
+// Centroids are only trained once there are enough vectors for each list to
+// be a real cluster rather than a handful of points
+pub const MIN_POINTS_PER_LIST: usize = 39;
+// Vectors sampled per list to train on; more barely moves the centroids
+const SAMPLE_POINTS_PER_LIST: usize = 256;
+// Training runs again once the gallery grew by this factor, since centroids
+// fitted on a small gallery partition a large one unevenly
+pub const RETRAIN_GROWTH: usize = 2;
+const KMEANS_ITERATIONS: usize = 20;
+const KMEANS_SEED: u64 = 0x6976_665f_6b6d_6e73;
+
+// Index configuration of the stores
+#[derive(Clone, Copy, Debug)]
+pub struct IvfConfig {
+    // Number of inverted lists (k-means clusters)
+    pub nlist: usize,
+    // Lists scanned by searches that do not set their own
+    pub nprobe: usize,
+}
+
+// File the trained centroids are saved to, next to the snapshot, and the
+// model whose vectors they partition
+pub struct SavedIndex {
+    pub path: PathBuf,
+    pub model: String,
+}
+
+// Unit-length centers of the lists, row by row. The vectors are compared by
+// cosine, so this is spherical k-means: a vector belongs to the centroid of
+// highest dot product.
+pub struct Centroids {
+    vectors: Vec<f32>,
+    dimension: usize,
+}
+
+impl Centroids {
+    // Spherical k-means over `sample`, seeded so retraining on the same
+    // vectors gives the same lists
+    pub fn train(sample: &[Vec<f32>], nlist: usize) -> Self {
+        let dimension = sample[0].len();
+        let points: Vec<Vec<f32>> = sample.iter().map(|vector| normalized(vector)).collect();
+        let mut rng = StdRng::seed_from_u64(KMEANS_SEED);
+        let mut centroids = Self {
+            vectors: index::sample(&mut rng, points.len(), nlist)
+                .into_iter()
+                .flat_map(|i| points[i].iter().copied())
+                .collect(),
+            dimension,
+        };
+
+        let mut assignment = vec![u32::MAX; points.len()];
+        for _ in 0..KMEANS_ITERATIONS {
+            let next: Vec<u32> = points
+                .par_iter()
+                .map(|point| centroids.nearest(point))
+                .collect();
+            if next == assignment {
+                break;
+            }
+            assignment = next;
+
+            let mut sums = vec![0.0f32; nlist * dimension];
+            let mut sizes = vec![0usize; nlist];
+            for (point, &list) in points.iter().zip(&assignment) {
+                let list = list as usize;
+                sizes[list] += 1;
+                for (sum, value) in sums[list * dimension..][..dimension].iter_mut().zip(point) {
+                    *sum += value;
+                }
+            }
+            for (list, size) in sizes.iter().enumerate() {
+                let center = &mut sums[list * dimension..][..dimension];
+                // An empty list restarts from a random point instead of
+                // staying dead
+                if *size == 0 {
+                    center.copy_from_slice(&points[rng.gen_range(0..points.len())]);
+                }
+                normalize(center);
+            }
+            centroids.vectors = sums;
+        }
+        centroids
+    }
+
+    // Centroids read back from disk, row by row
+    pub fn from_values(vectors: Vec<f32>, dimension: usize) -> Self {
+        Self { vectors, dimension }
+    }
+
+    pub fn values(&self) -> &[f32] {
+        &self.vectors
+    }
+
+    pub fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    pub fn nlist(&self) -> usize {
+        self.vectors.len() / self.dimension
+    }
+
+    fn scores<'a>(&'a self, vector: &'a [f32]) -> impl Iterator<Item = f32> + 'a {
+        self.vectors
+            .chunks_exact(self.dimension)
+            .map(move |center| center.iter().zip(vector).map(|(c, x)| c * x).sum())
+    }
+
+    // List of a vector; its length does not matter
+    pub fn nearest(&self, vector: &[f32]) -> u32 {
+        self.scores(vector)
+            .enumerate()
+            .fold((0, f32::MIN), |best, (list, score)| {
+                if score > best.1 {
+                    (list as u32, score)
+                } else {
+                    best
+                }
+            })
+            .0
+    }
+
+    // The `nprobe` lists closest to the query
+    pub fn probe(&self, query: &[f32], nprobe: usize) -> Vec<u32> {
+        let mut lists: Vec<(u32, f32)> = self
+            .scores(query)
+            .enumerate()
+            .map(|(list, score)| (list as u32, score))
+            .collect();
+        let nprobe = nprobe.clamp(1, lists.len());
+        lists.select_nth_unstable_by(nprobe - 1, |a, b| b.1.total_cmp(&a.1));
+        lists.truncate(nprobe);
+        lists.into_iter().map(|(list, _)| list).collect()
+    }
+}
+
+fn normalize(vector: &mut [f32]) {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+fn normalized(vector: &[f32]) -> Vec<f32> {
+    let mut vector = vector.to_vec();
+    normalize(&mut vector);
+    vector
+}
+
+// Vectors sampled to train `nlist` lists on
+pub fn sample_size(nlist: usize) -> usize {
+    nlist * SAMPLE_POINTS_PER_LIST
+}
+
+// Entry positions of a shard per list. An entry whose vectors fall in
+// several lists is in each of them; an entry whose list changed (a `Mean`
+// template moving) may linger in the old one, which only costs a wasted
+// comparison until the next compaction or training.
+#[derive(Clone)]
+pub struct InvertedLists {
+    pub centroids: Arc<Centroids>,
+    lists: Vec<Vec<u32>>,
+}
+
+impl InvertedLists {
+    pub fn new(centroids: Arc<Centroids>) -> Self {
+        let lists = vec![Vec::new(); centroids.nlist()];
+        Self { centroids, lists }
+    }
+
+    // Files the vector of the entry at `position`. Entries with several
+    // vectors in one list are filed there once when filed in position order;
+    // otherwise `candidates` drops the repeats.
+    pub fn add(&mut self, position: usize, vector: &[f32]) {
+        let list = &mut self.lists[self.centroids.nearest(vector) as usize];
+        let position = position as u32;
+        if list.last() != Some(&position) {
+            list.push(position);
+        }
+    }
+
+    // Moves every position through `moved`, dropping those it maps to None
+    pub fn remap(&mut self, moved: impl Fn(u32) -> Option<u32>) {
+        for list in &mut self.lists {
+            *list = list
+                .iter()
+                .filter_map(|&position| moved(position))
+                .collect();
+        }
+    }
+
+    // Positions in any of the probed lists, in storage order and once each
+    pub fn candidates(&self, probe: &[u32]) -> Vec<u32> {
+        let mut positions: Vec<u32> = probe
+            .iter()
+            .flat_map(|&list| self.lists[list as usize].iter().copied())
+            .collect();
+        positions.sort_unstable();
+        positions.dedup();
+        positions
+    }
+}
+
+// Trains the centroids of every store that is new to the index or outgrew
+// its training, one at a time so searches keep most of the CPU. With a
+// saved index, the stores first get their centroids back from it and new
+// ones are saved after each training.
+pub async fn run(collections: Arc<Collections>, interval: Duration, saved: Option<SavedIndex>) {
+    if let Some(saved) = &saved {
+        snapshot::restore_index(&saved.path, &collections, &saved.model).await;
+    }
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let mut trained_any = false;
+        for (tenant, name, collection) in collections.all() {
+            if !collection.store.needs_training() {
+                continue;
+            }
+            let start = Instant::now();
+            let trained = tokio::task::spawn_blocking({
+                let collection = collection.clone();
+                move || collection.store.train_ivf()
+            })
+            .await;
+            match trained {
+                Ok(Some(vectors)) => {
+                    trained_any = true;
+                    tracing::info!(
+                        %tenant,
+                        collection = %name,
+                        vectors,
+                        duration = ?start.elapsed(),
+                        "IVF index trained"
+                    )
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    tracing::error!(%tenant, collection = %name, error = %e, "IVF training failed")
+                }
+            }
+        }
+        if let (true, Some(saved)) = (trained_any, &saved) {
+            match snapshot::write_index(&collections, &saved.path, &saved.model).await {
+                Ok(stores) => tracing::info!(stores, path = ?saved.path, "IVF index saved"),
+                Err(e) => {
+                    tracing::error!(path = ?saved.path, error = %e, "Failed to save IVF index")
+                }
+            }
+        }
+    }
+}
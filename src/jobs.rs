@@ -0,0 +1,406 @@
+use axum::{
+    extract::{Extension, Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use base64::{engine::general_purpose, Engine as _};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Semaphore;
+use uuid::Uuid;
+
+use crate::backpressure::{self, Priority};
+use crate::cli;
+use crate::cluster::{self, ClusterPayload};
+use crate::collections::DEFAULT_COLLECTION;
+use crate::import::{self, ImportFormat};
+use crate::tenant::Tenant;
+use crate::tenant::DEFAULT_TENANT;
+use crate::util;
+use crate::video::{self, VideoQuery};
+use crate::AppState;
+
+pub const DEFAULT_MAX_CONCURRENCY: usize = 2;
+pub const DEFAULT_MAX_QUEUE: usize = 16;
+// Finished jobs are forgotten this long after they end
+const RETENTION_SECS: i64 = 3600;
+
+// Define the request payload for POST /jobs
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JobRequest {
+    // Precomputed embeddings, as for /admin/import
+    Import {
+        #[serde(default)]
+        format: ImportFormat,
+        collection: Option<String>,
+        // Contents of the JSONL or CSV file
+        data: String,
+    },
+    // Video file, as for /search/video/
+    VideoSearch {
+        #[serde(flatten)]
+        query: VideoQuery,
+        video_base64: String,
+    },
+    // Re-embedding of the stored crops, as the `reindex` command
+    Reindex {
+        collection: Option<String>,
+        #[serde(default)]
+        all: bool,
+    },
+    Cluster(ClusterPayload),
+}
+
+impl JobRequest {
+    fn kind(&self) -> &'static str {
+        match self {
+            JobRequest::Import { .. } => "import",
+            JobRequest::VideoSearch { .. } => "video_search",
+            JobRequest::Reindex { .. } => "reindex",
+            JobRequest::Cluster(_) => "cluster",
+        }
+    }
+
+    // Imports and reindexing change the gallery
+    fn writes(&self) -> bool {
+        matches!(self, JobRequest::Import { .. } | JobRequest::Reindex { .. })
+    }
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    // Waiting for a free slot
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Serialize, Clone, Copy)]
+pub struct Progress {
+    done: usize,
+    // Unknown for video searches, whose frame count is only known at the end
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total: Option<usize>,
+}
+
+// Define the response for /jobs and /jobs/:id
+#[derive(Serialize, Clone)]
+pub struct JobView {
+    id: Uuid,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    status: JobStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    progress: Option<Progress>,
+    // Response the synchronous endpoint would have given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    // Unix times (seconds)
+    created_at: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finished_at: Option<i64>,
+}
+
+pub struct Job {
+    // None for the jobs started by an admin
+    tenant: Option<String>,
+    view: Mutex<JobView>,
+}
+
+impl Job {
+    fn view(&self) -> JobView {
+        self.view.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn update(&self, update: impl FnOnce(&mut JobView)) {
+        update(&mut self.view.lock().unwrap_or_else(|e| e.into_inner()));
+    }
+}
+
+// Progress reporting of a long operation; the default reports nowhere, for
+// the synchronous endpoints and the commands
+#[derive(Clone, Default)]
+pub struct JobProgress(Option<Arc<Job>>);
+
+impl JobProgress {
+    pub fn set(&self, done: usize, total: Option<usize>) {
+        if let Some(job) = &self.0 {
+            job.update(|view| view.progress = Some(Progress { done, total }));
+        }
+    }
+}
+
+// Heavy operations run in the background of this instance, at most
+// `max_concurrency` at once with `max_queue` more waiting, each holding its
+// request in memory; jobs do not survive a restart
+pub struct Jobs {
+    jobs: Mutex<HashMap<Uuid, Arc<Job>>>,
+    slots: Arc<Semaphore>,
+    // Jobs queued or running
+    pending: Arc<AtomicUsize>,
+    max_pending: usize,
+}
+
+// Counts a job as pending until it is dropped with its task
+struct Pending(Arc<AtomicUsize>);
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Jobs {
+    pub fn new(max_concurrency: usize, max_queue: usize) -> Self {
+        let max_concurrency = max_concurrency.max(1);
+        Self {
+            jobs: Mutex::new(HashMap::new()),
+            slots: Arc::new(Semaphore::new(max_concurrency)),
+            pending: Arc::new(AtomicUsize::new(0)),
+            max_pending: max_concurrency + max_queue,
+        }
+    }
+
+    fn insert(&self, tenant: Option<&str>, kind: &'static str) -> Arc<Job> {
+        let id = Uuid::new_v4();
+        let job = Arc::new(Job {
+            tenant: tenant.map(str::to_string),
+            view: Mutex::new(JobView {
+                id,
+                kind,
+                status: JobStatus::Queued,
+                progress: None,
+                result: None,
+                error: None,
+                created_at: util::unix_now(),
+                finished_at: None,
+            }),
+        });
+        let mut jobs = self.jobs.lock().unwrap_or_else(|e| e.into_inner());
+        let now = util::unix_now();
+        jobs.retain(|_, job| {
+            job.view()
+                .finished_at
+                .map_or(true, |finished_at| now - finished_at < RETENTION_SECS)
+        });
+        jobs.insert(id, job.clone());
+        job
+    }
+
+    // Admins (no tenant) see every job
+    fn get(&self, tenant: Option<&str>, id: &Uuid) -> Option<Arc<Job>> {
+        self.jobs
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(id)
+            .filter(|job| tenant.is_none() || job.tenant.as_deref() == tenant)
+            .cloned()
+    }
+
+    // Runs `work` in the background once a slot is free and returns the
+    // queued job; 503 when the queue is full
+    pub(crate) fn start<F>(
+        &self,
+        tenant: Option<&str>,
+        kind: &'static str,
+        work: impl FnOnce(JobProgress) -> F + Send + 'static,
+    ) -> Result<JobView, StatusCode>
+    where
+        F: Future<Output = Result<Value, String>> + Send + 'static,
+    {
+        let pending = Pending(self.pending.clone());
+        if self.pending.fetch_add(1, Ordering::SeqCst) >= self.max_pending {
+            tracing::warn!(kind, ?tenant, "Rejected job with the job queue full");
+            return Err(StatusCode::SERVICE_UNAVAILABLE);
+        }
+        let job = self.insert(tenant, kind);
+        let view = job.view();
+        tracing::info!(job = %view.id, kind, ?tenant, "Job queued");
+        let slots = self.slots.clone();
+        tokio::spawn(async move {
+            let _pending = pending;
+            let Ok(_slot) = slots.acquire_owned().await else {
+                return;
+            };
+            job.update(|view| view.status = JobStatus::Running);
+            let progress = JobProgress(Some(job.clone()));
+            // Background work comes after the requests for inference
+            let outcome = backpressure::with_priority(Priority::Low, work(progress)).await;
+            job.update(|view| {
+                view.finished_at = Some(util::unix_now());
+                match outcome {
+                    Ok(result) => {
+                        view.status = JobStatus::Succeeded;
+                        view.result = Some(result);
+                    }
+                    Err(error) => {
+                        view.status = JobStatus::Failed;
+                        view.error = Some(error);
+                    }
+                }
+            });
+            let view = job.view();
+            tracing::info!(job = %view.id, kind = view.kind, status = ?view.status, "Job finished");
+        });
+        Ok(view)
+    }
+}
+
+// Handler for POST /jobs - starts a heavy operation in the background and
+// answers 202 Accepted with the job to poll
+pub async fn create_job(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Json(request): Json<JobRequest>,
+) -> Result<(StatusCode, Json<JobView>), StatusCode> {
+    // Imports and reindexing rewrite the gallery in bulk, like their admin
+    // endpoints: POST /admin/jobs only
+    if request.writes() {
+        tracing::warn!(
+            kind = request.kind(),
+            "Rejected write job outside the admin routes"
+        );
+        return Err(StatusCode::FORBIDDEN);
+    }
+    check(&state, &request)?;
+
+    let jobs = state.jobs.clone();
+    let owner = tenant.id().to_string();
+    let view = jobs.start(Some(&owner), request.kind(), move |progress| async move {
+        run(&state, &tenant, request, &progress).await
+    })?;
+    Ok((StatusCode::ACCEPTED, Json(view)))
+}
+
+// Define the query parameters for POST /admin/jobs
+#[derive(Deserialize)]
+pub struct AdminJobQuery {
+    tenant: Option<String>,
+}
+
+// Handler for POST /admin/jobs - any job, imports and reindexing included,
+// run in `tenant` (the default one when unset); polled on /admin/jobs/:id
+pub async fn create_admin_job(
+    State(state): State<AppState>,
+    Query(query): Query<AdminJobQuery>,
+    Json(request): Json<JobRequest>,
+) -> Result<(StatusCode, Json<JobView>), StatusCode> {
+    check(&state, &request)?;
+
+    let tenant = Tenant(query.tenant.unwrap_or_else(|| DEFAULT_TENANT.to_string()));
+    let jobs = state.jobs.clone();
+    let view = jobs.start(None, request.kind(), move |progress| async move {
+        run(&state, &tenant, request, &progress).await
+    })?;
+    Ok((StatusCode::ACCEPTED, Json(view)))
+}
+
+// Whether this instance can run the job at all
+fn check(state: &AppState, request: &JobRequest) -> Result<(), StatusCode> {
+    if request.writes() && !state.run_mode.is_writable() {
+        tracing::warn!(
+            kind = request.kind(),
+            "Rejected write job on read-only instance"
+        );
+        return Err(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    // Both go straight to the Postgres tables
+    if request.writes() && !state.storage.is_postgres() {
+        tracing::warn!(kind = request.kind(), storage = ?state.storage, "Rejected job needing Postgres");
+        return Err(StatusCode::NOT_IMPLEMENTED);
+    }
+    if let JobRequest::Reindex { .. } = request {
+        if state.crops.is_none() {
+            tracing::warn!("Rejected reindex job without enrollment crops");
+            return Err(StatusCode::NOT_IMPLEMENTED);
+        }
+    }
+    Ok(())
+}
+
+// Handler for GET /jobs/:id - status, progress and, once finished, result of
+// a job of the tenant
+pub async fn get_job(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<JobView>, StatusCode> {
+    state
+        .jobs
+        .get(Some(tenant.id()), &id)
+        .map(|job| Json(job.view()))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+// Handler for GET /admin/jobs/:id - any job, those started by an admin
+// included
+pub async fn get_admin_job(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<JobView>, StatusCode> {
+    state
+        .jobs
+        .get(None, &id)
+        .map(|job| Json(job.view()))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn run(
+    state: &AppState,
+    tenant: &Tenant,
+    request: JobRequest,
+    progress: &JobProgress,
+) -> Result<Value, String> {
+    match request {
+        JobRequest::Import {
+            format,
+            collection,
+            data,
+        } => {
+            let name = collection.as_deref().unwrap_or(DEFAULT_COLLECTION);
+            let response =
+                import::import_into(state, tenant.id(), name, format, &data, progress).await;
+            to_result(response)
+        }
+        JobRequest::VideoSearch {
+            query,
+            video_base64,
+        } => {
+            let video = general_purpose::STANDARD
+                .decode(video_base64)
+                .map_err(|e| format!("invalid video_base64: {}", e))?;
+            let response = video::run_video_search(state, tenant, &query, &video, progress).await;
+            to_result(response)
+        }
+        JobRequest::Reindex { collection, all } => {
+            let summary = cli::reindex(
+                state,
+                Some(tenant.id()),
+                collection.as_deref(),
+                all,
+                progress,
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+            serde_json::to_value(summary).map_err(|e| e.to_string())
+        }
+        JobRequest::Cluster(payload) => {
+            to_result(cluster::run_cluster(state, tenant, payload).await)
+        }
+    }
+}
+
+// Failed operations report the status the synchronous endpoint answers with
+fn to_result<T: Serialize>(response: Result<T, StatusCode>) -> Result<Value, String> {
+    let response = response.map_err(|status| status.to_string())?;
+    serde_json::to_value(response).map_err(|e| e.to_string())
+}
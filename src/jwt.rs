@@ -0,0 +1,198 @@
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+
+// Claims of a validated token, inserted as a request extension by
+// `auth::authenticate` for handlers that need to make authorization decisions
+#[derive(Clone, Debug, Default)]
+pub struct Claims(pub serde_json::Map<String, serde_json::Value>);
+
+impl Claims {
+    pub fn get_str(&self, claim: &str) -> Option<&str> {
+        self.0.get(claim).and_then(|value| value.as_str())
+    }
+}
+
+// Unknown key ids trigger a refresh, but not more often than this, so tokens
+// signed with bogus kids cannot hammer the identity provider
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum JwtError {
+    // Malformed, expired, wrongly signed or issued for someone else
+    Invalid(String),
+    // The signing keys could not be fetched
+    Jwks(String),
+}
+
+impl std::fmt::Display for JwtError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JwtError::Invalid(e) => write!(f, "invalid token: {}", e),
+            JwtError::Jwks(e) => write!(f, "failed to fetch JWKS: {}", e),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct JwtConfig {
+    pub issuer: String,
+    // None skips the audience check
+    pub audience: Option<String>,
+    // None discovers it from the issuer's /.well-known/openid-configuration
+    pub jwks_url: Option<String>,
+    pub jwks_cache_ttl: Duration,
+}
+
+#[derive(Default)]
+struct KeyCache {
+    jwks_url: Option<String>,
+    // kid -> key and the algorithm it is published for, if the JWKS says
+    keys: HashMap<String, (DecodingKey, Option<Algorithm>)>,
+    fetched_at: Option<Instant>,
+}
+
+#[derive(Deserialize)]
+struct OpenIdConfiguration {
+    jwks_uri: String,
+}
+
+// Validates bearer tokens of an OIDC provider (e.g. Keycloak) against its JWKS
+pub struct JwtVerifier {
+    config: JwtConfig,
+    client: reqwest::Client,
+    cache: RwLock<KeyCache>,
+}
+
+impl JwtVerifier {
+    pub fn new(config: JwtConfig) -> Self {
+        Self {
+            config,
+            client: reqwest::Client::new(),
+            cache: RwLock::new(KeyCache::default()),
+        }
+    }
+
+    async fn jwks_url(&self) -> Result<String, JwtError> {
+        if let Some(url) = &self.config.jwks_url {
+            return Ok(url.clone());
+        }
+        if let Some(url) = &self.cache.read().await.jwks_url {
+            return Ok(url.clone());
+        }
+        let discovery = format!(
+            "{}/.well-known/openid-configuration",
+            self.config.issuer.trim_end_matches('/')
+        );
+        let configuration: OpenIdConfiguration = self
+            .client
+            .get(&discovery)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| JwtError::Jwks(e.to_string()))?
+            .json()
+            .await
+            .map_err(|e| JwtError::Jwks(e.to_string()))?;
+        self.cache.write().await.jwks_url = Some(configuration.jwks_uri.clone());
+        Ok(configuration.jwks_uri)
+    }
+
+    async fn refresh_keys(&self) -> Result<(), JwtError> {
+        let url = self.jwks_url().await?;
+        let jwks: JwkSet = self
+            .client
+            .get(&url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| JwtError::Jwks(e.to_string()))?
+            .json()
+            .await
+            .map_err(|e| JwtError::Jwks(e.to_string()))?;
+
+        let keys: HashMap<String, (DecodingKey, Option<Algorithm>)> = jwks
+            .keys
+            .iter()
+            .filter_map(|jwk| {
+                let kid = jwk.common.key_id.clone()?;
+                let algorithm = jwk
+                    .common
+                    .key_algorithm
+                    .and_then(|alg| alg.to_string().parse::<Algorithm>().ok());
+                let key = DecodingKey::from_jwk(jwk).ok()?;
+                Some((kid, (key, algorithm)))
+            })
+            .collect();
+        tracing::info!(url = %url, keys = keys.len(), "JWKS refreshed");
+
+        let mut cache = self.cache.write().await;
+        cache.keys = keys;
+        cache.fetched_at = Some(Instant::now());
+        Ok(())
+    }
+
+    async fn key_for(&self, kid: &str) -> Result<(DecodingKey, Option<Algorithm>), JwtError> {
+        let (fresh, may_refresh) = {
+            let cache = self.cache.read().await;
+            let age = cache.fetched_at.map(|at| at.elapsed());
+            let fresh = age.is_some_and(|age| age < self.config.jwks_cache_ttl);
+            if fresh {
+                if let Some(key) = cache.keys.get(kid) {
+                    return Ok(key.clone());
+                }
+            }
+            (fresh, age.map_or(true, |age| age >= MIN_REFRESH_INTERVAL))
+        };
+
+        // Expired cache, or a kid we do not know yet (key rotation)
+        if !fresh || may_refresh {
+            self.refresh_keys().await?;
+        }
+        self.cache
+            .read()
+            .await
+            .keys
+            .get(kid)
+            .cloned()
+            .ok_or_else(|| JwtError::Invalid(format!("unknown key id '{}'", kid)))
+    }
+
+    pub async fn verify(&self, token: &str) -> Result<Claims, JwtError> {
+        let header = decode_header(token).map_err(|e| JwtError::Invalid(e.to_string()))?;
+        // Only asymmetric signatures: the keys come from a public JWKS
+        if matches!(
+            header.alg,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+        ) {
+            return Err(JwtError::Invalid(format!(
+                "unsupported algorithm {:?}",
+                header.alg
+            )));
+        }
+        let kid = header
+            .kid
+            .ok_or_else(|| JwtError::Invalid("missing key id".to_string()))?;
+        let (key, algorithm) = self.key_for(&kid).await?;
+        // Keys published without "alg" still only verify their own key family
+        let algorithm = algorithm.unwrap_or(header.alg);
+        if header.alg != algorithm {
+            return Err(JwtError::Invalid(format!(
+                "algorithm {:?} does not match key '{}'",
+                header.alg, kid
+            )));
+        }
+
+        let mut validation = Validation::new(algorithm);
+        validation.set_issuer(&[&self.config.issuer]);
+        match &self.config.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        decode::<serde_json::Map<String, serde_json::Value>>(token, &key, &validation)
+            .map(|data| Claims(data.claims))
+            .map_err(|e| JwtError::Invalid(e.to_string()))
+    }
+}
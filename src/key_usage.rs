@@ -0,0 +1,418 @@
+use axum::{
+    extract::{Query, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Row};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+use crate::auth::ApiKey;
+use crate::util;
+use crate::AppState;
+
+// Counts are written, and the totals of the other instances read back, this
+// often; quotas can be overshot by what the instances serve in between
+pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+// Totals of a key not refreshed by a flush for this long are read again
+// before its next request is checked
+const TOTALS_TTL: Duration = Duration::from_secs(60);
+
+tokio::task_local! {
+    // Model time of the request being accounted, in microseconds
+    static MODEL_TIME: Arc<AtomicU64>;
+}
+
+// Adds the time of an image pipeline run (decoding to inference) to the
+// request being accounted, if any
+pub fn charge_inference(duration: Duration) {
+    let _ = MODEL_TIME.try_with(|total| {
+        total.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    });
+}
+
+// Limits of a key per UTC day and calendar month; None is unlimited
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct Quotas {
+    pub daily_requests: Option<i64>,
+    pub monthly_requests: Option<i64>,
+    pub daily_inference_seconds: Option<f64>,
+    pub monthly_inference_seconds: Option<f64>,
+}
+
+impl Quotas {
+    fn is_unlimited(&self) -> bool {
+        self.daily_requests.is_none()
+            && self.monthly_requests.is_none()
+            && self.daily_inference_seconds.is_none()
+            && self.monthly_inference_seconds.is_none()
+    }
+
+    // Name of the first quota `day` or `month` reaches
+    fn exceeded(&self, day: Usage, month: Usage) -> Option<&'static str> {
+        let reached = |used: i64, quota: Option<i64>| quota.is_some_and(|quota| used >= quota);
+        let spent = |used: f64, quota: Option<f64>| quota.is_some_and(|quota| used >= quota);
+        if reached(day.requests, self.daily_requests) {
+            Some("daily_requests")
+        } else if reached(month.requests, self.monthly_requests) {
+            Some("monthly_requests")
+        } else if spent(day.inference_seconds, self.daily_inference_seconds) {
+            Some("daily_inference_seconds")
+        } else if spent(month.inference_seconds, self.monthly_inference_seconds) {
+            Some("monthly_inference_seconds")
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct Usage {
+    requests: i64,
+    inference_seconds: f64,
+}
+
+impl Usage {
+    fn add(self, other: Usage) -> Usage {
+        Usage {
+            requests: self.requests + other.requests,
+            inference_seconds: self.inference_seconds + other.inference_seconds,
+        }
+    }
+}
+
+// Usage of a key known to this instance
+#[derive(Default)]
+struct Tally {
+    // Totals of the current day and month in the database, as of `read_at`
+    day: Usage,
+    month: Usage,
+    // None until the totals are read
+    read_at: Option<Instant>,
+    // Day (Unix days) the totals are of
+    read_day: i64,
+    // Served here since the last flush
+    pending: Usage,
+}
+
+impl Tally {
+    fn is_fresh(&self) -> bool {
+        self.read_at.is_some_and(|at| at.elapsed() < TOTALS_TTL)
+            && self.read_day == util::unix_now() / 86_400
+    }
+}
+
+// Requests and model time per API key, counted in memory and added to the
+// daily rows of `api_key_usage` in the background
+pub struct KeyUsage {
+    pool: PgPool,
+    keys: Mutex<HashMap<Uuid, Tally>>,
+    closing: Arc<Notify>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl KeyUsage {
+    pub fn start(pool: PgPool, interval: Duration) -> Arc<Self> {
+        let usage = Arc::new(Self {
+            pool,
+            keys: Mutex::new(HashMap::new()),
+            closing: Arc::new(Notify::new()),
+            writer: Mutex::new(None),
+        });
+        let writer = tokio::spawn(write_usage(usage.clone(), interval));
+        *usage.writer.lock().unwrap_or_else(|e| e.into_inner()) = Some(writer);
+        usage
+    }
+
+    // Writes the pending counts and stops the writer, at shutdown
+    pub async fn close(&self) {
+        self.closing.notify_one();
+        let writer = self.writer.lock().unwrap_or_else(|e| e.into_inner()).take();
+        if let Some(writer) = writer {
+            writer.await.ok();
+        }
+    }
+
+    // The quota `key` has used up, if any
+    async fn exceeded(&self, key: &ApiKey) -> Result<Option<&'static str>, sqlx::Error> {
+        if key.quotas.is_unlimited() {
+            return Ok(None);
+        }
+        let fresh = self
+            .keys
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&key.id)
+            .is_some_and(Tally::is_fresh);
+        if !fresh {
+            self.read_totals(&[key.id]).await?;
+        }
+        let keys = self.keys.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(keys.get(&key.id).and_then(|tally| {
+            key.quotas
+                .exceeded(tally.day.add(tally.pending), tally.month.add(tally.pending))
+        }))
+    }
+
+    fn record(&self, key: Uuid, inference_seconds: f64) {
+        let mut keys = self.keys.lock().unwrap_or_else(|e| e.into_inner());
+        let pending = &mut keys.entry(key).or_default().pending;
+        *pending = pending.add(Usage {
+            requests: 1,
+            inference_seconds,
+        });
+    }
+
+    // Replaces the totals of `ids` with those of the database
+    async fn read_totals(&self, ids: &[Uuid]) -> Result<(), sqlx::Error> {
+        let rows = sqlx::query(
+            r#"
+            SELECT key_id,
+                   COALESCE(SUM(requests) FILTER (WHERE day = (now() AT TIME ZONE 'UTC')::DATE), 0)::BIGINT AS day_requests,
+                   COALESCE(SUM(inference_seconds) FILTER (WHERE day = (now() AT TIME ZONE 'UTC')::DATE), 0) AS day_seconds,
+                   COALESCE(SUM(requests), 0)::BIGINT AS month_requests,
+                   COALESCE(SUM(inference_seconds), 0) AS month_seconds
+            FROM api_key_usage
+            WHERE key_id = ANY($1) AND day >= date_trunc('month', now() AT TIME ZONE 'UTC')::DATE
+            GROUP BY key_id
+            "#,
+        )
+        .bind(ids)
+        .fetch_all(&self.pool)
+        .await?;
+        let mut totals = HashMap::new();
+        for row in rows {
+            let id: Uuid = row.try_get("key_id")?;
+            let day = Usage {
+                requests: row.try_get("day_requests")?,
+                inference_seconds: row.try_get("day_seconds")?,
+            };
+            let month = Usage {
+                requests: row.try_get("month_requests")?,
+                inference_seconds: row.try_get("month_seconds")?,
+            };
+            totals.insert(id, (day, month));
+        }
+
+        let now = Instant::now();
+        let today = util::unix_now() / 86_400;
+        let mut keys = self.keys.lock().unwrap_or_else(|e| e.into_inner());
+        for id in ids {
+            let (day, month) = totals.remove(id).unwrap_or_default();
+            let tally = keys.entry(*id).or_default();
+            tally.day = day;
+            tally.month = month;
+            tally.read_at = Some(now);
+            tally.read_day = today;
+        }
+        Ok(())
+    }
+
+    // Adds the pending counts to today's rows, then reads back the totals of
+    // the keys written, which include what the other instances wrote
+    async fn flush(&self) -> Result<(), sqlx::Error> {
+        let pending: Vec<(Uuid, Usage)> = {
+            let mut keys = self.keys.lock().unwrap_or_else(|e| e.into_inner());
+            // Keys idle since the last flush are forgotten
+            keys.retain(|_, tally| tally.pending.requests > 0 || tally.is_fresh());
+            keys.iter_mut()
+                .filter(|(_, tally)| tally.pending.requests > 0)
+                .map(|(id, tally)| (*id, std::mem::take(&mut tally.pending)))
+                .collect()
+        };
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let written = self.write(&pending).await;
+        if written.is_err() {
+            // Counted again on the next flush rather than lost
+            let mut keys = self.keys.lock().unwrap_or_else(|e| e.into_inner());
+            for (id, usage) in &pending {
+                let tally = keys.entry(*id).or_default();
+                tally.pending = tally.pending.add(*usage);
+            }
+            return written;
+        }
+        let ids: Vec<Uuid> = pending.iter().map(|(id, _)| *id).collect();
+        self.read_totals(&ids).await
+    }
+
+    async fn write(&self, pending: &[(Uuid, Usage)]) -> Result<(), sqlx::Error> {
+        let mut transaction = self.pool.begin().await?;
+        for (id, usage) in pending {
+            sqlx::query(
+                r#"
+                INSERT INTO api_key_usage (key_id, day, requests, inference_seconds)
+                VALUES ($1, (now() AT TIME ZONE 'UTC')::DATE, $2, $3)
+                ON CONFLICT (key_id, day) DO UPDATE
+                SET requests = api_key_usage.requests + EXCLUDED.requests,
+                    inference_seconds = api_key_usage.inference_seconds + EXCLUDED.inference_seconds
+                "#,
+            )
+            .bind(id)
+            .bind(usage.requests)
+            .bind(usage.inference_seconds)
+            .execute(&mut *transaction)
+            .await?;
+        }
+        transaction.commit().await
+    }
+}
+
+async fn write_usage(usage: Arc<KeyUsage>, interval: Duration) {
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        let closing = tokio::select! {
+            _ = ticker.tick() => false,
+            _ = usage.closing.notified() => true,
+        };
+        if let Err(e) = usage.flush().await {
+            tracing::error!(error = %e, "Failed to write API key usage");
+        }
+        if closing {
+            break;
+        }
+    }
+}
+
+// Middleware of the tenant routes: requests made with an API key over one of
+// its quotas get 429, the others are counted with the model time they took
+pub async fn account(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let (Some(usage), Some(key)) = (
+        state.key_usage.as_ref(),
+        request.extensions().get::<ApiKey>().cloned(),
+    ) else {
+        return next.run(request).await;
+    };
+    match usage.exceeded(&key).await {
+        Ok(None) => {}
+        Ok(Some(quota)) => {
+            tracing::warn!(key_id = %key.id, quota, "API key quota exceeded");
+            return (
+                StatusCode::TOO_MANY_REQUESTS,
+                Json(serde_json::json!({ "quota_exceeded": quota })),
+            )
+                .into_response();
+        }
+        // Quotas are not enforced while the database is down, requests
+        // are still counted
+        Err(e) => tracing::error!(key_id = %key.id, error = %e, "Failed to read API key usage"),
+    }
+
+    let model_time = Arc::new(AtomicU64::new(0));
+    let response = MODEL_TIME
+        .scope(model_time.clone(), next.run(request))
+        .await;
+    usage.record(
+        key.id,
+        model_time.load(Ordering::Relaxed) as f64 / 1_000_000.0,
+    );
+    response
+}
+
+#[derive(Deserialize)]
+pub struct UsageQuery {
+    tenant: Option<String>,
+    // Unix seconds; from the start of the current month by default
+    since: Option<i64>,
+    // Unix seconds, inclusive; today by default
+    until: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct KeyUsageReport {
+    id: Uuid,
+    tenant: String,
+    name: String,
+    requests: i64,
+    inference_seconds: f64,
+    quotas: Quotas,
+}
+
+// Define the response for /admin/usage
+#[derive(Serialize)]
+pub struct UsageReport {
+    since: String,
+    until: String,
+    keys: Vec<KeyUsageReport>,
+}
+
+// Handler for GET /admin/usage - requests and inference seconds of every API
+// key over whole UTC days, for billing. Counts reach the database every
+// flush interval, so the last seconds are not included yet.
+pub async fn usage_report(
+    State(state): State<AppState>,
+    Query(query): Query<UsageQuery>,
+) -> Result<Json<UsageReport>, StatusCode> {
+    let rows = sqlx::query(
+        r#"
+        WITH period AS (
+            SELECT COALESCE((to_timestamp($2) AT TIME ZONE 'UTC')::DATE, date_trunc('month', now() AT TIME ZONE 'UTC')::DATE) AS since,
+                   COALESCE((to_timestamp($3) AT TIME ZONE 'UTC')::DATE, (now() AT TIME ZONE 'UTC')::DATE) AS until
+        )
+        SELECT k.id, k.tenant, k.name,
+               k.daily_requests, k.monthly_requests,
+               k.daily_inference_seconds, k.monthly_inference_seconds,
+               COALESCE(SUM(u.requests), 0)::BIGINT AS requests,
+               COALESCE(SUM(u.inference_seconds), 0) AS inference_seconds,
+               EXTRACT(EPOCH FROM period.since)::BIGINT AS since,
+               EXTRACT(EPOCH FROM period.until)::BIGINT AS until
+        FROM api_keys k
+        CROSS JOIN period
+        LEFT JOIN api_key_usage u
+            ON u.key_id = k.id AND u.day BETWEEN period.since AND period.until
+        WHERE $1::TEXT IS NULL OR k.tenant = $1
+        GROUP BY k.id, period.since, period.until
+        ORDER BY k.tenant, k.created_at
+        "#,
+    )
+    .bind(&query.tenant)
+    .bind(query.since)
+    .bind(query.until)
+    .fetch_all(&state.read_pool)
+    .await
+    .map_err(|e| {
+        tracing::error!(error = %e, "Failed to query API key usage");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let mut range = None;
+    let keys = rows
+        .iter()
+        .map(|row| {
+            range = Some((row.try_get("since")?, row.try_get("until")?));
+            Ok(KeyUsageReport {
+                id: row.try_get("id")?,
+                tenant: row.try_get("tenant")?,
+                name: row.try_get("name")?,
+                requests: row.try_get("requests")?,
+                inference_seconds: row.try_get("inference_seconds")?,
+                quotas: Quotas {
+                    daily_requests: row.try_get("daily_requests")?,
+                    monthly_requests: row.try_get("monthly_requests")?,
+                    daily_inference_seconds: row.try_get("daily_inference_seconds")?,
+                    monthly_inference_seconds: row.try_get("monthly_inference_seconds")?,
+                },
+            })
+        })
+        .collect::<Result<Vec<KeyUsageReport>, sqlx::Error>>()
+        .map_err(|e| {
+            tracing::error!(error = %e, "Failed to decode API key usage");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let (since, until) = range.unwrap_or((query.since.unwrap_or(0), util::unix_now()));
+    Ok(Json(UsageReport {
+        since: util::format_rfc3339(since),
+        until: util::format_rfc3339(until),
+        keys,
+    }))
+}
@@ -0,0 +1,101 @@
+use image::{DynamicImage, GenericImageView, RgbImage};
+use ndarray::Array;
+use std::path::Path;
+
+use crate::detect::DetectedFace;
+use crate::embedder::SessionThreads;
+use crate::onnx::ImageModel;
+
+// Margin added around the detector box on every side, as a share of its
+// longest side: landmark models are trained on crops with the chin and brows
+// well inside
+const CROP_MARGIN: f32 = 0.1;
+
+// Dense landmark regressor exported to ONNX (e.g. PFLD with 68 points): a
+// [1, 3, H, W] RGB input scaled to 0-1 of a square crop around the face, and
+// the x, y pairs of the points relative to that crop (0-1) as output
+pub struct LandmarkModel {
+    model: ImageModel,
+    input_width: u32,
+    input_height: u32,
+}
+
+impl LandmarkModel {
+    pub fn load(
+        model_path: &Path,
+        threads: SessionThreads,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let model = ImageModel::load(model_path, threads)?;
+
+        // Dynamic dimensions fall back to 112x112
+        Ok(Self {
+            input_height: model.dim(2, 112),
+            input_width: model.dim(3, 112),
+            model,
+        })
+    }
+
+    // Points of `face` in pixel coordinates of `img`
+    pub fn locate(
+        &self,
+        img: &DynamicImage,
+        face: &DetectedFace,
+    ) -> Result<Vec<[f32; 2]>, Box<dyn std::error::Error>> {
+        let (left, top, side) = square(face);
+        // Parts of the square outside the image are left black
+        let (width, height) = img.dimensions();
+        let x = left.clamp(0, width as i64) as u32;
+        let y = top.clamp(0, height as i64) as u32;
+        let mut crop = RgbImage::new(side, side);
+        image::imageops::overlay(
+            &mut crop,
+            &img.crop_imm(x, y, side, side).to_rgb8(),
+            x as i64 - left,
+            y as i64 - top,
+        );
+        let resized = image::imageops::resize(
+            &crop,
+            self.input_width,
+            self.input_height,
+            image::imageops::FilterType::Triangle,
+        );
+
+        let mut input = Array::zeros((1, 3, self.input_height as usize, self.input_width as usize));
+        for (px, py, pixel) in resized.enumerate_pixels() {
+            for channel in 0..3 {
+                input[[0, channel, py as usize, px as usize]] = pixel[channel] as f32 / 255.0;
+            }
+        }
+
+        let values = self.model.run_first(input)?;
+        if values.is_empty() || values.len() % 2 != 0 {
+            return Err(format!(
+                "expected x, y pairs of landmarks, got {} values",
+                values.len()
+            )
+            .into());
+        }
+        Ok(values
+            .chunks_exact(2)
+            .map(|point| {
+                [
+                    left as f32 + point[0] * side as f32,
+                    top as f32 + point[1] * side as f32,
+                ]
+            })
+            .collect())
+    }
+}
+
+// Square around the box of a face with the margin, as (left, top, side); it
+// may stick out of the image
+fn square(face: &DetectedFace) -> (i64, i64, u32) {
+    let [x1, y1, x2, y2] = face.bbox;
+    let side = (x2 - x1).max(y2 - y1) * (1.0 + 2.0 * CROP_MARGIN);
+    let (center_x, center_y) = ((x1 + x2) / 2.0, (y1 + y2) / 2.0);
+    (
+        (center_x - side / 2.0).round() as i64,
+        (center_y - side / 2.0).round() as i64,
+        (side.round() as u32).max(1),
+    )
+}
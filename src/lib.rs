@@ -0,0 +1,9 @@
+// Library side of the crate, for Rust consumers of the API; the server itself
+// is the binary (main.rs)
+
+// Payloads of the HTTP API, shared with the server
+pub mod api;
+
+// Typed async client of the HTTP API
+#[cfg(feature = "client")]
+pub mod client;
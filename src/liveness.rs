@@ -0,0 +1,74 @@
+use image::DynamicImage;
+use ndarray::Array;
+use std::path::Path;
+
+use crate::embedder::SessionThreads;
+use crate::onnx::{self, ImageModel};
+
+// Passive (single image) anti-spoofing classifier, e.g. MiniFASNet from
+// Silent-Face-Anti-Spoofing exported to ONNX: a [1, 3, H, W] BGR input with
+// raw 0-255 values and per-class logits as output
+pub struct Liveness {
+    model: ImageModel,
+    input_width: u32,
+    input_height: u32,
+    // Output class meaning "live face"
+    live_class: usize,
+    // Enrollments scoring below are rejected; None only reports the score
+    threshold: Option<f32>,
+}
+
+impl Liveness {
+    pub fn load(
+        model_path: &Path,
+        live_class: usize,
+        threads: SessionThreads,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let model = ImageModel::load(model_path, threads)?;
+
+        // Dynamic dimensions fall back to 80x80, the MiniFASNet input size
+
+        Ok(Self {
+            input_height: model.dim(2, 80),
+            input_width: model.dim(3, 80),
+            live_class,
+            threshold: None,
+            model,
+        })
+    }
+
+    pub fn with_threshold(mut self, threshold: Option<f32>) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    pub fn threshold(&self) -> Option<f32> {
+        self.threshold
+    }
+
+    // Probability that the image shows a live face, between 0 and 1
+    pub fn score(&self, img: &DynamicImage) -> Result<f32, Box<dyn std::error::Error>> {
+        let resized = img
+            .resize_exact(
+                self.input_width,
+                self.input_height,
+                image::imageops::FilterType::Triangle,
+            )
+            .to_rgb8();
+
+        let mut input = Array::zeros((1, 3, self.input_height as usize, self.input_width as usize));
+        for (x, y, pixel) in resized.enumerate_pixels() {
+            input[[0, 0, y as usize, x as usize]] = pixel[2] as f32;
+            input[[0, 1, y as usize, x as usize]] = pixel[1] as f32;
+            input[[0, 2, y as usize, x as usize]] = pixel[0] as f32;
+        }
+
+        let logits = self.model.run_first(input)?;
+
+        Ok(onnx::class_probability(
+            &logits,
+            self.live_class,
+            "liveness",
+        )?)
+    }
+}
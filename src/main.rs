@@ -1,179 +1,482 @@
 use axum::{
-    routing::{get, post},
+    extract::DefaultBodyLimit,
+    http::HeaderName,
+    middleware,
+    routing::{delete, get, post, put},
     Router,
 };
-use ort::{init, session::builder::GraphOptimizationLevel, session::Session};
-use rayon::prelude::*;
+use clap::Parser;
+#[cfg(feature = "onnx")]
+use ort::init;
 use sqlx::postgres::PgPoolOptions;
 use sqlx::Connection;
 use sqlx::PgPool;
 use sqlx::Row;
-use std::env;
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-use uuid::Uuid;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing_subscriber::{
+    fmt::{format::JsonFields, writer::BoxMakeWriter},
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
+};
 
+mod access_log;
+mod admin_ui;
+mod api;
+mod api_version;
+mod archive;
+mod attributes;
+mod audit;
+mod auth;
+mod backpressure;
+mod backup;
+mod bench;
+mod breaker;
+mod calibration;
+#[cfg(feature = "candle")]
+mod candle_model;
+mod chunked;
+mod cli;
+mod cluster;
+mod collections;
+mod config;
+mod consistency;
+mod dataset;
+mod db;
+mod dedupe;
+mod detect;
+mod embedder;
+mod emotion;
+mod encryption;
+mod enhance;
+mod enrollment;
+mod error_reporting;
+mod events;
+mod expiry;
+mod export;
+mod ffmpeg;
+mod flags;
+mod formats;
+#[cfg(feature = "gpu-search")]
+mod gpu;
+mod graphql;
+mod grpc;
 mod handlers;
+mod history;
+mod idempotency;
+mod import;
+mod ingest;
+mod ivf;
+mod jobs;
+mod jwt;
+mod key_usage;
+mod landmarks;
+mod liveness;
+mod log_format;
+mod mask;
+mod matrix;
+mod mysql;
+mod notify;
+mod objects;
+mod onnx;
+mod origins;
+mod otel;
+mod partition;
+mod pca;
+mod pose;
+mod profiles;
+mod protobuf;
+mod quality;
+mod query_cache;
+mod quota;
+mod rate_limit;
+mod readiness;
+mod redact;
+mod reembed;
+mod register_stream;
+mod request_id;
+mod resync;
+mod rtsp;
+mod run_mode;
+mod shadow;
+mod sharding;
+mod sketch;
+mod snapshot;
+mod storage;
+mod store;
+mod target_store;
+mod telemetry;
+mod tenant;
+#[cfg(all(test, feature = "mock-inference"))]
+mod testing;
+mod tls;
+mod tunables;
+mod util;
+mod version;
+mod video;
+mod webhooks;
+mod write_behind;
+mod ws;
 
-// Estructura para associar uuid com embeddings
-#[derive(Clone)]
-pub struct EmbeddingEntry {
-    pub uuid: Uuid,
-    pub origin: String,
-    pub embedding: Vec<f32>,
-}
-
-// Implementação de funções de similaridade para embeddings
-pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
-    if a.len() != b.len() {
-        panic!("Vectors with different sizes!");
-    }
-
-    let mut dot_product = 0.0;
-    let mut norm_a = 0.0;
-    let mut norm_b = 0.0;
-
-    for i in 0..a.len().min(b.len()) {
-        dot_product += a[i] * b[i];
-        norm_a += a[i] * a[i];
-        norm_b += b[i] * b[i];
-    }
-
-    if norm_a == 0.0 || norm_b == 0.0 {
-        return 0.0;
-    }
-
-    dot_product / (norm_a.sqrt() * norm_b.sqrt())
-}
-
-// Armazenamento e função de busca para embeddings
-#[derive(Clone)]
-pub struct EmbeddingsStore {
-    entries: Vec<EmbeddingEntry>,
-}
-
-impl EmbeddingsStore {
-    pub fn new() -> Self {
-        Self {
-            entries: Vec::new(),
-        }
-    }
-
-    pub fn add(&mut self, uuid: Uuid, origin: String, embedding: Vec<f32>) {
-        self.entries.push(EmbeddingEntry {
-            uuid,
-            embedding,
-            origin,
-        });
-    }
-
-    pub fn find_similar(
-        &self,
-        query: &[f32],
-        threshold: f32,
-        limit: usize,
-    ) -> Vec<(Uuid, String, f32)> {
-        let mut results: Vec<(Uuid, String, f32)> = self
-            .entries
-            .par_iter()
-            .map(|entry| {
-                let similarity = cosine_similarity(query, &entry.embedding);
-                (entry.uuid, entry.origin.clone(), similarity)
-            })
-            .filter(|&(_, _, similarity)| similarity >= threshold)
-            .collect();
-
-        // Ordenar por similaridade (maior primeiro)
-        results.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap_or(std::cmp::Ordering::Equal));
-
-        // Limitar o número de resultados
-        results.truncate(limit);
+use archive::ArchivePolicy;
+use attributes::AttributeModel;
+use audit::AuditLog;
+use auth::{Auth, AuthMode};
+use backpressure::InferenceQueue;
+use breaker::DbBreaker;
+use calibration::Calibration;
+use cli::{Cli, Command};
+use collections::{Collections, MemoryLimits};
+use db::PoolConfig;
+use detect::FaceDetector;
+use embedder::{ChannelOrder, EmbeddingModel, Layout, Preprocessing, ResizeMode, SessionThreads};
+use emotion::EmotionModel;
+use enhance::SuperResolution;
+use enrollment::Enrollments;
+use events::Events;
+use flags::{FeatureFlags, FlagRule};
+use formats::ImageLimits;
+use history::SearchHistory;
+use idempotency::Idempotency;
+use ingest::IngestConfig;
+use ivf::{IvfConfig, SavedIndex};
+use jobs::Jobs;
+use jwt::{JwtConfig, JwtVerifier};
+use key_usage::KeyUsage;
+use landmarks::LandmarkModel;
+use liveness::Liveness;
+use log_format::LogFormat;
+use mask::MaskDetector;
+use mysql::MySqlTargets;
+use notify::Notifier;
+use objects::ObjectStore;
+use pca::PcaConfig;
+use pose::{PoseEstimator, PoseLimits, PoseMode};
+use quality::QualityGate;
+use query_cache::QueryCache;
+use quota::Quotas;
+use rate_limit::RateLimiter;
+use redact::Redacting;
+use rtsp::RtspConfig;
+use run_mode::RunMode;
+use shadow::Shadow;
+use sharding::{GalleryShard, Sharding};
+use storage::Storage;
+use store::{Precision, TemplateMode};
+use target_store::{PostgresTargets, TargetStore};
+use tenant::TenantResolver;
+use tls::Tls;
+use tunables::Tunables;
+use version::VersionInfo;
+use webhooks::Webhooks;
+use write_behind::{WriteBehind, WriteBehindConfig};
 
-        results
-    }
+// Longest the queued database writes may take to flush at shutdown
+const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(30);
 
-    pub fn len(&self) -> usize {
-        self.entries.len()
-    }
-
-    pub fn is_empty(&self) -> bool {
-        self.entries.is_empty()
-    }
-}
+// Embedding model under models/ when MODEL_PATH is not set; edge builds
+// default to MobileFaceNet (13MB, about 10x faster than ResNet-100 on ARM)
+#[cfg(not(feature = "edge"))]
+const DEFAULT_MODEL_FILE: &str = "arcfaceresnet100-8.onnx";
+#[cfg(not(feature = "edge"))]
+const DEFAULT_CHANNEL_ORDER: ChannelOrder = ChannelOrder::Bgr;
+#[cfg(feature = "edge")]
+const DEFAULT_MODEL_FILE: &str = "w600k_mbf.onnx";
+#[cfg(feature = "edge")]
+const DEFAULT_CHANNEL_ORDER: ChannelOrder = ChannelOrder::Rgb;
 
 // Shared application state
 #[derive(Clone)]
 pub struct AppState {
-    onnx_session: Arc<Session>,
+    // The face recognition runtime: ort with an ONNX model, or candle with
+    // safetensors weights
+    embedder: Arc<dyn EmbeddingModel>,
+    // Model identifier reported in exports (file stem of the ONNX model)
+    model_name: String,
+    // Width of the embeddings, when the model output declares it
+    embedding_dimension: Option<usize>,
+    // Build and model information reported on /version
+    version: Arc<VersionInfo>,
+    super_resolution: Option<Arc<SuperResolution>>,
+    // Face detector locating and aligning faces before embedding; without it
+    // images are taken to be face crops
+    detector: Option<Arc<FaceDetector>>,
+    // Anti-spoofing model scoring enrolled and query images, when configured
+    liveness: Option<Arc<Liveness>>,
+    // Mask classifier of query faces, with the threshold of masked searches
+    mask: Option<Arc<MaskDetector>>,
+    // Age and gender model, run on searches that ask for attributes and on /analyze/
+    attributes: Option<Arc<AttributeModel>>,
+    // Emotion classifier, run on searches that ask for it and on /analyze/
+    emotion: Option<Arc<EmotionModel>>,
+    // Dense landmark model, run on /landmarks/
+    landmarks: Option<Arc<LandmarkModel>>,
+    // Head pose model with the angle limits of usable faces, when configured
+    pose: Option<Arc<PoseEstimator>>,
+    // Minimum image quality of enrollments
+    quality_gate: Arc<QualityGate>,
+    // Resolution caps checked before images are decoded
+    image_limits: ImageLimits,
+    // Average the embeddings of each face and its mirror image
+    flip_tta: bool,
+    // Longest preprocessing and inference of a face before answering 504
+    inference_timeout: Option<Duration>,
+    // Bounds the image pipelines running at once, when configured
+    inference_queue: Option<Arc<InferenceQueue>>,
+    // Bucket keeping the aligned crop of every enrollment for human review
+    crops: Option<Arc<ObjectStore>>,
     db_pool: PgPool,
-    embeddings_store: Arc<Mutex<EmbeddingsStore>>,
+    // Replica for listings, statistics and bulk reads; the bulk pool without
+    // POSTGRES_READ_URL
+    read_pool: PgPool,
+    // Primary pool of the bulk work, which DB_STATEMENT_TIMEOUT_MS does not
+    // bound; `db_pool` without it
+    bulk_pool: PgPool,
+    // Persistence of the registrations, the targets table
+    targets: Arc<dyn TargetStore>,
+    // Retries and circuit breaking of the registration writes
+    db_breaker: Arc<DbBreaker>,
+    collections: Arc<Collections>,
+    quotas: Arc<Quotas>,
+    // Similarity thresholds that override the request threshold per origin
+    origin_thresholds: Arc<HashMap<String, f32>>,
+    // Match probability reported alongside the similarity, when configured
+    calibration: Option<Arc<Calibration>>,
+    tenants: Arc<TenantResolver>,
+    auth: Arc<Auth>,
+    // Enabled and disabled at runtime with the other tunables
+    rate_limiter: Arc<RateLimiter>,
+    // Mirrors sampled searches to staging when configured
+    shadow: Option<Arc<Shadow>>,
+    // Matches of recently searched faces in Redis, when QUERY_CACHE_URL is set
+    query_cache: Option<Arc<QueryCache>>,
+    flags: Arc<FeatureFlags>,
+    // Search hits above the alert threshold, streamed on /events
+    events: Arc<Events>,
+    // Search defaults, alert threshold and rate limits, changeable at runtime
+    tunables: Arc<Tunables>,
+    // None when no WEBHOOK_URLS are configured
+    webhooks: Option<Arc<Webhooks>>,
+    // Publishes gallery writes to the other instances, when enabled
+    notifier: Option<Arc<Notifier>>,
+    // Hash-chained log of mutations and searches, when AUDIT_LOG is set
+    audit: Option<Arc<AuditLog>>,
+    // Queue of the database writes of `Append` registrations, when
+    // WRITE_BEHIND is set
+    write_behind: Option<Arc<WriteBehind>>,
+    // Persisted searches and their matches, when SEARCH_HISTORY is set
+    search_history: Option<Arc<SearchHistory>>,
+    // Outcomes of registrations by Idempotency-Key, when IDEMPOTENCY_TTL_SECS is set
+    idempotency: Option<Arc<Idempotency>>,
+    // Requests and model time per API key, in the API key auth mode
+    key_usage: Option<Arc<KeyUsage>>,
+    // Imports, video searches, reindexing and clustering run in the background
+    jobs: Arc<Jobs>,
+    // Multi-photo enrollments waiting to be committed
+    enrollments: Arc<Enrollments>,
+    run_mode: RunMode,
+    // Postgres, or the in-memory stores alone
+    storage: Storage,
+    // Targets kept out of memory, when ARCHIVE_AFTER_DAYS or ARCHIVE_ORIGINS
+    // is set
+    archive: Option<Arc<ArchivePolicy>>,
+    // The other shards of the gallery, when GALLERY_SHARD_COUNT is set
+    sharding: Option<Arc<Sharding>>,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // `serve` unless another command is given
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or(Command::Serve);
+    let serve = matches!(command, Command::Serve);
+
     // Load environment variables and initialize tracing
     dotenvy::dotenv().ok();
 
+    // Optional config.toml; the environment overrides its settings
+    let (settings, config_file) = match config::path(cli.config) {
+        Some(path) => {
+            let settings = config::Config::load(&path)?.resolve();
+            let file_variables = settings.file_variables();
+            (settings, Some((path, file_variables)))
+        }
+        None => (config::Settings::from_env(), None),
+    };
+
     // Get log level from LOG_LEVEL first, then RUST_LOG, or default to "debug"
-    let log_level = std::env::var("LOG_LEVEL")
-        .or_else(|_| std::env::var("RUST_LOG"))
+    let log_level = settings
+        .var("LOG_LEVEL")
+        .or_else(|_| settings.var("RUST_LOG"))
         .unwrap_or_else(|_| "debug".into());
 
+    // Optional OTLP trace export, e.g. OTEL_EXPORTER_OTLP_ENDPOINT=http://otel-collector:4317
+    let tracer_provider = match settings.var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+        Ok(endpoint) => {
+            let service_name = settings
+                .var("OTEL_SERVICE_NAME")
+                .unwrap_or_else(|_| "owlfacerec".to_string());
+            Some(otel::init(&endpoint, &service_name)?)
+        }
+        Err(_) => None,
+    };
+
+    // Optional error reporting to Sentry or a compatible backend (e.g. GlitchTip)
+    let error_reporter = match settings.var("SENTRY_DSN") {
+        Ok(dsn) => Some(error_reporting::init(
+            &dsn,
+            settings.var("SENTRY_ENVIRONMENT").ok(),
+        )?),
+        Err(_) => None,
+    };
+
+    // Human-readable lines by default, JSON lines for log pipelines
+    let log_format = match settings.var("LOG_FORMAT") {
+        Ok(format) => format.parse::<LogFormat>()?,
+        Err(_) => LogFormat::Pretty,
+    };
+    // Offline commands print their results on stdout. Images and embeddings
+    // are scrubbed from every line, whatever logged them.
+    let log_writer = if serve {
+        BoxMakeWriter::new(Redacting(std::io::stdout))
+    } else {
+        BoxMakeWriter::new(Redacting(std::io::stderr))
+    };
+    let (pretty_logs, json_logs) = match log_format {
+        LogFormat::Pretty => (
+            Some(tracing_subscriber::fmt::layer().with_writer(log_writer)),
+            None,
+        ),
+        LogFormat::Json => (
+            None,
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .fmt_fields(JsonFields::new())
+                    .event_format(log_format::JsonLines)
+                    .with_writer(log_writer),
+            ),
+        ),
+    };
+
     tracing_subscriber::registry()
         .with(tracing_subscriber::EnvFilter::new(log_level))
-        .with(tracing_subscriber::fmt::layer())
+        .with(pretty_logs)
+        .with(json_logs)
+        .with(tracer_provider.as_ref().map(otel::layer))
+        .with(error_reporter.as_ref().map(|_| error_reporting::layer()))
         .init();
-
-    tracing::info!("Testing database connection...");
+    if tracer_provider.is_some() {
+        tracing::info!("OpenTelemetry trace export enabled");
+    }
+    if error_reporter.is_some() {
+        tracing::info!("Error reporting enabled");
+    }
+    if let Some((path, file_variables)) = &config_file {
+        tracing::info!(path = %path.display(), settings = file_variables.len(), "Config file loaded");
+    }
 
     // Get database connection parameters from environment variables
-    let postgres_user = env::var("POSTGRES_USER").unwrap_or_else(|_| "postgres".to_string());
-    let postgres_password =
-        env::var("POSTGRES_PASSWORD").unwrap_or_else(|_| "postgres".to_string());
-    let postgres_host = env::var("POSTGRES_HOST").unwrap_or_else(|_| "localhost".to_string());
-    let postgres_port = env::var("POSTGRES_PORT").unwrap_or_else(|_| "5432".to_string());
-    let postgres_db = env::var("POSTGRES_DB").unwrap_or_else(|_| "owlfacerec".to_string());
-
-    // --- Database Creation Logic ---
+    let postgres_user = settings
+        .var("POSTGRES_USER")
+        .unwrap_or_else(|_| "postgres".to_string());
+    let postgres_password = settings
+        .var("POSTGRES_PASSWORD")
+        .unwrap_or_else(|_| "postgres".to_string());
+    let postgres_host = settings
+        .var("POSTGRES_HOST")
+        .unwrap_or_else(|_| "localhost".to_string());
+    let postgres_port = settings
+        .var("POSTGRES_PORT")
+        .unwrap_or_else(|_| "5432".to_string());
+    let postgres_db = settings
+        .var("POSTGRES_DB")
+        .unwrap_or_else(|_| "owlfacerec".to_string());
+
     let pg_options = sqlx::postgres::PgConnectOptions::new()
         .host(&postgres_host)
         .port(postgres_port.parse::<u16>()?)
         .username(&postgres_user)
         .password(&postgres_password);
 
-    // 1. Connect to the default 'postgres' database
-    tracing::info!("Connecting to default 'postgres' database to ensure target database exists...");
-    let mut conn =
-        sqlx::PgConnection::connect_with(&pg_options.clone().database("postgres")).await?;
+    // Read-only replicas never create or alter anything in the database
+    let run_mode = settings
+        .var("RUN_MODE")
+        .unwrap_or_else(|_| "read-write".to_string())
+        .parse::<RunMode>()?;
+    tracing::info!(run_mode = ?run_mode, "Run mode configured");
 
-    // 2. Try to create the target database
-    tracing::info!(target_db = %postgres_db, "Attempting to create database if it doesn't exist...");
-    match sqlx::query(&format!("CREATE DATABASE \"{}\"", postgres_db))
-        .execute(&mut conn)
-        .await
-    {
-        Ok(_) => {
-            tracing::info!(target_db = %postgres_db, "Database created successfully or already existed.")
-        }
-        Err(e) => {
-            if let Some(db_err) = e.as_database_error() {
-                // Check for PostgreSQL error code '42P04' (database already exists)
-                if db_err.code().map_or(false, |code| code == "42P04") {
-                    tracing::info!(target_db = %postgres_db, "Database already exists.");
-                } else {
-                    tracing::error!(error = %e, target_db = %postgres_db, "Failed to create database");
-                    return Err(e.into()); // Return the original error
-                }
-            } else {
-                tracing::error!(error = %e, target_db = %postgres_db, "Non-database error occurred during creation check");
-                return Err(e.into()); // Return the original error
-            }
+    // Without Postgres, registrations live in the MySQL or MariaDB database
+    // of DATABASE_URL, or only in memory and in the snapshot file
+    let database_url = settings.var("DATABASE_URL").ok();
+    let storage = match settings.var("STORAGE") {
+        Ok(storage) => storage.parse::<Storage>()?,
+        Err(_) => database_url
+            .as_deref()
+            .map_or(Storage::Postgres, Storage::from_url),
+    };
+    tracing::info!(storage = ?storage, "Storage configured");
+
+    // Vectors sealed at rest, in the targets table and the snapshots; the key
+    // can come from a file, e.g. decrypted by a KMS at deploy time
+    let encryption_key = match settings.var("EMBEDDING_ENCRYPTION_KEY_FILE") {
+        Ok(path) => Some(
+            std::fs::read_to_string(&path)
+                .map_err(|e| format!("failed to read {}: {}", path, e))?,
+        ),
+        Err(_) => settings.var("EMBEDDING_ENCRYPTION_KEY").ok(),
+    };
+    if let Some(key) = encryption_key {
+        if storage == Storage::MySql {
+            return Err("EMBEDDING_ENCRYPTION_KEY requires STORAGE=postgres or memory".into());
         }
+        encryption::init(&key)?;
+        tracing::info!("Embeddings encrypted at rest");
+    }
+
+    if run_mode.is_writable() && storage.is_postgres() {
+        db::ensure_database(&pg_options, &postgres_db).await?;
+    }
+
+    // Pool of the Postgres or MySQL database, sized for the load of the
+    // instance
+    let pool_config = PoolConfig {
+        max_connections: match settings.var("DB_MAX_CONNECTIONS") {
+            Ok(connections) => connections.parse::<u32>()?,
+            Err(_) => db::DEFAULT_MAX_CONNECTIONS,
+        },
+        min_connections: match settings.var("DB_MIN_CONNECTIONS") {
+            Ok(connections) => connections.parse::<u32>()?,
+            Err(_) => 0,
+        },
+        acquire_timeout: match settings.var("DB_ACQUIRE_TIMEOUT_MS") {
+            Ok(ms) => Duration::from_millis(ms.parse::<u64>()?),
+            Err(_) => db::DEFAULT_ACQUIRE_TIMEOUT,
+        },
+        // 0 keeps idle connections open
+        idle_timeout: match settings.var("DB_IDLE_TIMEOUT_SECS") {
+            Ok(secs) => Some(Duration::from_secs(secs.parse::<u64>()?)).filter(|t| !t.is_zero()),
+            Err(_) => Some(db::DEFAULT_IDLE_TIMEOUT),
+        },
+        statement_timeout: match settings.var("DB_STATEMENT_TIMEOUT_MS") {
+            Ok(ms) => Some(Duration::from_millis(ms.parse::<u64>()?)).filter(|t| !t.is_zero()),
+            Err(_) => None,
+        },
+    };
+    if pool_config.max_connections == 0 || pool_config.min_connections > pool_config.max_connections
+    {
+        return Err(
+            "DB_MAX_CONNECTIONS must be at least 1 and DB_MIN_CONNECTIONS at most it".into(),
+        );
+    }
+    if pool_config.statement_timeout.is_some() && storage == Storage::MySql {
+        return Err("DB_STATEMENT_TIMEOUT_MS requires STORAGE=postgres".into());
+    }
+    if storage.persists_targets() {
+        tracing::info!(pool = ?pool_config, "Database pool configured");
     }
-    // Connection to 'postgres' DB is implicitly closed when 'conn' goes out of scope here.
-    // --- End Database Creation Logic ---
 
     // 4. Connect to the target database for the application using a pool
     let target_db_url = format!(
@@ -181,99 +484,1744 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         postgres_user, postgres_password, postgres_host, postgres_port, postgres_db
     );
 
+    let pool = if storage.is_postgres() {
+        tracing::info!("Testing database connection...");
+        tracing::info!(
+            "Connecting to target database '{}' with a connection pool...",
+            postgres_db
+        );
+        let pool = pool_config.postgres().connect(&target_db_url).await?;
+
+        // 5. Ping the database to verify connection
+        pool.acquire().await?.ping().await?;
+        tracing::info!(
+            "Connection to target database '{}' successful.",
+            postgres_db
+        );
+        pool
+    } else {
+        // Never connected: every code path using it is skipped or refused
+        tracing::info!(storage = ?storage, "Not connecting to Postgres");
+        PgPoolOptions::new().connect_lazy(&target_db_url)?
+    };
+
+    // Bulk work on the primary (migrations, snapshots, imports, reindexing,
+    // consistency checks) gets a pool of its own without the statement
+    // timeout; the primary pool itself when no timeout is set
+    let bulk_pool = if storage.is_postgres() && pool_config.statement_timeout.is_some() {
+        pool_config.bulk().postgres().connect_lazy(&target_db_url)?
+    } else {
+        pool.clone()
+    };
+
+    // Bulk and listing reads go to a replica when one is configured, so they
+    // do not load the primary; the primary pool otherwise
+    let read_pool = match settings.var("POSTGRES_READ_URL") {
+        Ok(url) => {
+            if !storage.is_postgres() {
+                return Err("POSTGRES_READ_URL requires STORAGE=postgres".into());
+            }
+            tracing::info!("Connecting to the read replica...");
+            let read_pool = pool_config.bulk().postgres().connect(&url).await?;
+            read_pool.acquire().await?.ping().await?;
+            tracing::info!("Connection to the read replica successful.");
+            read_pool
+        }
+        Err(_) => bulk_pool.clone(),
+    };
+
+    // Registrations and deletions of one instance applied by the others
+    let notifier = if settings
+        .var("NOTIFY_CHANGES")
+        .map(|value| value == "true" || value == "1")
+        .unwrap_or(false)
+    {
+        if !storage.is_postgres() {
+            return Err("NOTIFY_CHANGES requires STORAGE=postgres".into());
+        }
+        let channel = settings
+            .var("NOTIFY_CHANNEL")
+            .unwrap_or_else(|_| notify::DEFAULT_NOTIFY_CHANNEL.to_string());
+        tracing::info!(%channel, "Change notifications enabled");
+        Some(Arc::new(Notifier::new(channel)))
+    } else {
+        None
+    };
+
+    // Stale targets stay in the database, out of memory, and are searched on
+    // request
+    let archive_origins: Vec<String> = settings
+        .var("ARCHIVE_ORIGINS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|origin| !origin.is_empty())
+        .map(str::to_string)
+        .collect();
+    let archive_after_secs = match settings.var("ARCHIVE_AFTER_DAYS") {
+        Ok(days) => Some(days.parse::<i64>()? * 24 * 60 * 60),
+        Err(_) => None,
+    };
+    let archive_policy = if archive_after_secs.is_some() || !archive_origins.is_empty() {
+        if !storage.is_postgres() {
+            return Err("ARCHIVE_AFTER_DAYS and ARCHIVE_ORIGINS require STORAGE=postgres".into());
+        }
+        let policy = ArchivePolicy {
+            after_secs: archive_after_secs,
+            origins: archive_origins,
+        };
+        tracing::info!(?policy, "Target archival enabled");
+        Some(Arc::new(policy))
+    } else {
+        None
+    };
+
+    // Each instance holds the targets of one shard of the gallery and fans
+    // searches out to the others
+    let sharding = match settings.var("GALLERY_SHARD_COUNT") {
+        Ok(count) => {
+            let count: usize = count.parse()?;
+            let index: usize = settings
+                .var("GALLERY_SHARD_INDEX")
+                .map_err(|_| "GALLERY_SHARD_COUNT requires GALLERY_SHARD_INDEX")?
+                .parse()?;
+            if count < 2 || index >= count {
+                return Err(
+                    "GALLERY_SHARD_COUNT must be at least 2 and GALLERY_SHARD_INDEX below it"
+                        .into(),
+                );
+            }
+            if !storage.is_postgres() {
+                return Err("GALLERY_SHARD_COUNT requires STORAGE=postgres".into());
+            }
+            let peers: Vec<String> = settings
+                .var("GALLERY_SHARD_PEERS")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|peer| !peer.is_empty())
+                .map(str::to_string)
+                .collect();
+            if peers.len() != count - 1 {
+                return Err("GALLERY_SHARD_PEERS must list the URL of every other shard".into());
+            }
+            let secret = settings
+                .var("GALLERY_SHARD_SECRET")
+                .map_err(|_| "GALLERY_SHARD_COUNT requires GALLERY_SHARD_SECRET")?;
+            let timeout = match settings.var("GALLERY_SHARD_TIMEOUT_MS") {
+                Ok(ms) => Duration::from_millis(ms.parse()?),
+                Err(_) => sharding::DEFAULT_PEER_TIMEOUT,
+            };
+            // Registrations received by another shard reach this one through
+            // the database only
+            if notifier.is_none() && settings.var("RESYNC_INTERVAL_SECS").is_err() {
+                tracing::warn!("GALLERY_SHARD_COUNT without NOTIFY_CHANGES or RESYNC_INTERVAL_SECS: targets registered on other shards stay out of memory until restart");
+            }
+            let shard = GalleryShard { index, count };
+            tracing::info!(index, count, ?peers, "Gallery sharding enabled");
+            Some(Arc::new(Sharding::new(shard, peers, secret, timeout)?))
+        }
+        Err(_) => None,
+    };
+    let gallery_shard = sharding.as_ref().map(|sharding| sharding.shard());
+
+    // Registrations are persisted in the targets table of the database
+    let targets: Arc<dyn TargetStore> = match storage {
+        Storage::MySql => {
+            let url = database_url
+                .as_deref()
+                .ok_or("STORAGE=mysql requires DATABASE_URL")?;
+            tracing::info!("Connecting to the MySQL database...");
+            let targets = MySqlTargets::connect(url, &pool_config).await?;
+            if run_mode.is_writable() {
+                targets.ensure_schema().await?;
+            }
+            tracing::info!("Connection to the MySQL database successful.");
+            Arc::new(targets)
+        }
+        // Never called in memory-only mode
+        Storage::Postgres | Storage::Memory => Arc::new(
+            PostgresTargets::new(pool.clone())
+                .with_bulk_pool(bulk_pool.clone())
+                .with_notifier(notifier.clone())
+                .with_archive(archive_policy.clone()),
+        ),
+    };
+
+    // Transient database errors of registrations are retried; past the
+    // threshold in a row they fail fast until the cooldown has passed
+    let db_breaker = Arc::new(DbBreaker::new(
+        match settings.var("DB_RETRIES") {
+            Ok(retries) => retries.parse::<u32>()?,
+            Err(_) => breaker::DEFAULT_RETRIES,
+        },
+        match settings.var("DB_BREAKER_THRESHOLD") {
+            Ok(threshold) => threshold.parse::<u32>()?,
+            Err(_) => breaker::DEFAULT_THRESHOLD,
+        },
+        match settings.var("DB_BREAKER_COOLDOWN_SECS") {
+            Ok(secs) => Duration::from_secs(secs.parse::<u64>()?),
+            Err(_) => breaker::DEFAULT_COOLDOWN,
+        },
+    ));
+
+    // 6. Create tables and apply migrations
+    if !storage.is_postgres() {
+        tracing::info!(storage = ?storage, "No Postgres schema to migrate");
+    } else if run_mode.is_writable() {
+        db::migrate(&bulk_pool).await?;
+    } else {
+        tracing::info!("Read-only mode, skipping schema migrations");
+    }
+
+    // Initialize ONNX Runtime environment globally
+    #[cfg(feature = "onnx")]
+    {
+        init().with_name("ArcFaceApp").commit()?;
+        tracing::info!("ONNX Runtime environment initialized.");
+    }
+
+    // ONNX Runtime thread pools, shared by the embedding and optional models
+    let session_threads = SessionThreads {
+        intra_op: match settings.var("ORT_INTRA_OP_THREADS") {
+            Ok(threads) => Some(threads.parse::<usize>()?),
+            Err(_) => None,
+        },
+        inter_op: match settings.var("ORT_INTER_OP_THREADS") {
+            Ok(threads) => Some(threads.parse::<usize>()?),
+            Err(_) => None,
+        },
+        parallel_execution: settings
+            .var("ORT_PARALLEL_EXECUTION")
+            .map(|value| value == "true" || value == "1")
+            .unwrap_or(false),
+    };
+    if session_threads.intra_op == Some(0) || session_threads.inter_op == Some(0) {
+        return Err("ORT_INTRA_OP_THREADS and ORT_INTER_OP_THREADS must be positive".into());
+    }
+    tracing::info!(threads = ?session_threads, "ONNX Runtime threads configured");
+
+    tracing::info!("Loading ArcFace model...");
+    // Build session with absolute path to ONNX model
+    let model_path = match settings.var("MODEL_PATH") {
+        Ok(model_path) => PathBuf::from(model_path),
+        Err(_) => PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("models")
+            .join(DEFAULT_MODEL_FILE),
+    };
+    tracing::info!(model_path = ?model_path, "Using model file");
+    // Preprocessing of the ArcFace exports unless the model needs another
+    let mut preprocessing = Preprocessing::arcface(DEFAULT_CHANNEL_ORDER);
+    if let Ok(order) = settings.var("MODEL_CHANNEL_ORDER") {
+        preprocessing.channels = order.parse::<ChannelOrder>()?;
+    }
+    if let Ok(mean) = settings.var("MODEL_INPUT_MEAN") {
+        preprocessing.mean = mean.parse()?;
+    }
+    if let Ok(std) = settings.var("MODEL_INPUT_STD") {
+        preprocessing.std = std.parse()?;
+    }
+    if let Ok(layout) = settings.var("MODEL_INPUT_LAYOUT") {
+        preprocessing.layout = Some(layout.parse::<Layout>()?);
+    }
+    // Face crops of another aspect ratio than the model input, e.g. tall
+    // phone photos without a detector, are stretched unless set otherwise
+    if let Ok(mode) = settings.var("MODEL_RESIZE_MODE") {
+        preprocessing.resize = mode.parse::<ResizeMode>()?;
+    }
+    preprocessing.validate()?;
+    let embedder = embedder::load(&model_path, session_threads, preprocessing)?;
+
+    tracing::info!(model_path = ?model_path, "Model loaded successfully.");
+    let model_name = profiles::model_name(&model_path);
+    // Thresholds of the model, for those neither the environment nor the
+    // config file sets
+    let model_profile = profiles::builtin(&model_name).unwrap_or_else(|| {
+        if !settings.is_set("SEARCH_THRESHOLD") || !settings.is_set("ALERT_THRESHOLD") {
+            tracing::warn!(model = %model_name, "No built-in thresholds for the model, using those of ResNet-100; calibrate SEARCH_THRESHOLD and ALERT_THRESHOLD for it");
+        }
+        profiles::FALLBACK
+    });
+    let model_profile = profiles::resolve(&settings, model_profile)?;
+    let embedding_dimension = embedder.dimension();
+    tracing::info!(?embedding_dimension, "Embedding dimension of the model");
+    let version = VersionInfo::new(&model_path, embedding_dimension)?;
+
+    // Flip test-time augmentation: one extra inference per face
+    let flip_tta = settings
+        .var("FLIP_TTA")
+        .map(|value| value == "true" || value == "1")
+        .unwrap_or(false);
+    tracing::info!(flip_tta, "Embedding mode configured");
+
+    // Bound on the preprocessing and inference of a face, e.g. for a hung
+    // execution provider
+    let inference_timeout = match settings.var("INFERENCE_TIMEOUT_MS") {
+        Ok(millis) => Some(Duration::from_millis(millis.parse::<u64>()?)),
+        Err(_) => None,
+    };
+    tracing::info!(?inference_timeout, "Inference timeout configured");
+
+    // Image pipelines running at once; past INFERENCE_MAX_QUEUE waiting
+    // requests, new ones are answered 503
+    let inference_queue = match settings.var("INFERENCE_MAX_CONCURRENCY") {
+        Ok(concurrency) => {
+            let concurrency = concurrency.parse::<usize>()?;
+            let max_queue = match settings.var("INFERENCE_MAX_QUEUE") {
+                Ok(queue) => queue.parse::<usize>()?,
+                Err(_) => backpressure::DEFAULT_MAX_QUEUE,
+            };
+            tracing::info!(concurrency, max_queue, "Inference concurrency limited");
+            Some(Arc::new(InferenceQueue::new(concurrency, max_queue)))
+        }
+        Err(_) => None,
+    };
+
+    // Optional super-resolution model for query-side enhancement
+    let super_resolution = match settings.var("SR_MODEL_PATH") {
+        Ok(sr_model_path) => {
+            tracing::info!(sr_model_path = %sr_model_path, "Loading super-resolution ONNX model...");
+            Some(Arc::new(SuperResolution::load(
+                &PathBuf::from(sr_model_path),
+                session_threads,
+            )?))
+        }
+        Err(_) => None,
+    };
+
+    // Optional face detector
+    let detector = match settings.var("DETECTOR_MODEL_PATH") {
+        Ok(detector_model_path) => {
+            tracing::info!(detector_model_path = %detector_model_path, "Loading face detector ONNX model...");
+            let threshold = match settings.var("DETECTOR_THRESHOLD") {
+                Ok(threshold) => threshold.parse::<f32>()?,
+                Err(_) => detect::DEFAULT_DETECTION_THRESHOLD,
+            };
+            Some(Arc::new(
+                FaceDetector::load(&PathBuf::from(detector_model_path), session_threads)?
+                    .with_threshold(threshold),
+            ))
+        }
+        Err(_) => None,
+    };
+
+    // Optional passive liveness model; LIVENESS_THRESHOLD turns it into an enrollment gate
+    let liveness = match settings.var("LIVENESS_MODEL_PATH") {
+        Ok(liveness_model_path) => {
+            tracing::info!(liveness_model_path = %liveness_model_path, "Loading liveness ONNX model...");
+            let live_class = match settings.var("LIVENESS_LIVE_CLASS") {
+                Ok(class) => class.parse::<usize>()?,
+                Err(_) => 1,
+            };
+            let threshold = match settings.var("LIVENESS_THRESHOLD") {
+                Ok(threshold) => Some(threshold.parse::<f32>()?),
+                Err(_) => None,
+            };
+            tracing::info!(live_class, threshold = ?threshold, "Liveness scoring enabled");
+            Some(Arc::new(
+                Liveness::load(
+                    &PathBuf::from(liveness_model_path),
+                    live_class,
+                    session_threads,
+                )?
+                .with_threshold(threshold),
+            ))
+        }
+        Err(_) => None,
+    };
+
+    // Optional mask classifier; MASK_THRESHOLD sets the search threshold of masked faces
+    let mask = match settings.var("MASK_MODEL_PATH") {
+        Ok(mask_model_path) => {
+            tracing::info!(mask_model_path = %mask_model_path, "Loading mask ONNX model...");
+            let mask_class = match settings.var("MASK_CLASS") {
+                Ok(class) => class.parse::<usize>()?,
+                Err(_) => 0,
+            };
+            let match_threshold = match settings.var("MASK_THRESHOLD") {
+                Ok(threshold) => Some(threshold.parse::<f32>()?),
+                Err(_) => None,
+            };
+            tracing::info!(mask_class, match_threshold = ?match_threshold, "Mask detection enabled");
+            Some(Arc::new(
+                MaskDetector::load(&PathBuf::from(mask_model_path), mask_class, session_threads)?
+                    .with_match_threshold(match_threshold),
+            ))
+        }
+        Err(_) => None,
+    };
+
+    // Optional age and gender model
+    let attributes = match settings.var("ATTRIBUTES_MODEL_PATH") {
+        Ok(attributes_model_path) => {
+            tracing::info!(attributes_model_path = %attributes_model_path, "Loading attributes ONNX model...");
+            Some(Arc::new(AttributeModel::load(
+                &PathBuf::from(attributes_model_path),
+                session_threads,
+            )?))
+        }
+        Err(_) => None,
+    };
+
+    // Optional emotion model; EMOTION_LABELS names its classes in output order
+    let emotion = match settings.var("EMOTION_MODEL_PATH") {
+        Ok(emotion_model_path) => {
+            tracing::info!(emotion_model_path = %emotion_model_path, "Loading emotion ONNX model...");
+            let labels: Vec<String> = match settings.var("EMOTION_LABELS") {
+                Ok(labels) => labels
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|label| !label.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+                Err(_) => emotion::DEFAULT_LABELS
+                    .iter()
+                    .map(|label| label.to_string())
+                    .collect(),
+            };
+            tracing::info!(labels = ?labels, "Emotion estimation enabled");
+            Some(Arc::new(EmotionModel::load(
+                &PathBuf::from(emotion_model_path),
+                labels,
+                session_threads,
+            )?))
+        }
+        Err(_) => None,
+    };
+
+    // Optional dense landmark model
+    let landmarks = match settings.var("LANDMARKS_MODEL_PATH") {
+        Ok(landmarks_model_path) => {
+            tracing::info!(landmarks_model_path = %landmarks_model_path, "Loading landmarks ONNX model...");
+            Some(Arc::new(LandmarkModel::load(
+                &PathBuf::from(landmarks_model_path),
+                session_threads,
+            )?))
+        }
+        Err(_) => None,
+    };
+
+    // Optional head pose model; POSE_LIMITS sets the angles beyond which faces are unusable
+    let pose = match settings.var("POSE_MODEL_PATH") {
+        Ok(pose_model_path) => {
+            tracing::info!(pose_model_path = %pose_model_path, "Loading head pose ONNX model...");
+            let limits = settings
+                .var("POSE_LIMITS")
+                .unwrap_or_default()
+                .parse::<PoseLimits>()?;
+            let mode = match settings.var("POSE_MODE") {
+                Ok(mode) => mode.parse::<PoseMode>()?,
+                Err(_) => PoseMode::Reject,
+            };
+            let mode = match (mode, settings.var("POSE_DOWN_WEIGHT")) {
+                (PoseMode::DownWeight(_), Ok(weight)) => {
+                    let weight = weight.parse::<f32>()?;
+                    if !(weight > 0.0 && weight <= 1.0) {
+                        return Err("POSE_DOWN_WEIGHT must be in (0, 1]".into());
+                    }
+                    PoseMode::DownWeight(weight)
+                }
+                (mode, _) => mode,
+            };
+            tracing::info!(limits = ?limits, mode = ?mode, "Head pose estimation enabled");
+            Some(Arc::new(
+                PoseEstimator::load(&PathBuf::from(pose_model_path), session_threads)?
+                    .with_policy(limits, mode),
+            ))
+        }
+        Err(_) => None,
+    };
+
+    // Enrollment quality thresholds, e.g. QUALITY_GATE=sharpness=100,face_size=80
+    let quality_gate = settings
+        .var("QUALITY_GATE")
+        .unwrap_or_default()
+        .parse::<QualityGate>()?;
+    tracing::info!(quality_gate = ?quality_gate, "Quality gate configured");
+
+    // Images over these are rejected with 422 before their pixels are decoded
+    let image_limits = ImageLimits {
+        max_dimension: match settings.var("IMAGE_MAX_DIMENSION") {
+            Ok(pixels) => pixels.parse::<u32>()?,
+            Err(_) => formats::DEFAULT_MAX_DIMENSION,
+        },
+        max_megapixels: match settings.var("IMAGE_MAX_MEGAPIXELS") {
+            Ok(megapixels) => megapixels.parse::<f32>()?,
+            Err(_) => formats::DEFAULT_MAX_MEGAPIXELS,
+        },
+        max_frames: match settings.var("IMAGE_MAX_FRAMES") {
+            Ok(frames) => frames.parse::<usize>()?.max(1),
+            Err(_) => formats::DEFAULT_MAX_FRAMES,
+        },
+        // 0 keeps images at full resolution
+        working_dimension: match settings.var("IMAGE_WORKING_DIMENSION") {
+            Ok(pixels) => Some(pixels.parse::<u32>()?).filter(|&pixels| pixels > 0),
+            Err(_) => Some(formats::DEFAULT_WORKING_DIMENSION),
+        },
+    };
+    tracing::info!(image_limits = ?image_limits, "Image limits configured");
+
+    // Enrollment crops uploaded to S3/MinIO, e.g. S3_ENDPOINT=http://minio:9000
+    let crops = match settings.var("S3_BUCKET") {
+        Ok(bucket) => {
+            let region = settings
+                .var("S3_REGION")
+                .unwrap_or_else(|_| "us-east-1".to_string());
+            let endpoint = settings
+                .var("S3_ENDPOINT")
+                .unwrap_or_else(|_| format!("https://s3.{}.amazonaws.com", region));
+            let access_key = settings
+                .var("S3_ACCESS_KEY")
+                .map_err(|_| "S3_BUCKET requires S3_ACCESS_KEY")?;
+            let secret_key = settings
+                .var("S3_SECRET_KEY")
+                .map_err(|_| "S3_BUCKET requires S3_SECRET_KEY")?;
+            tracing::info!(%endpoint, %bucket, %region, "Enrollment crop storage enabled");
+            Some(Arc::new(ObjectStore::new(
+                &endpoint, bucket, region, access_key, secret_key,
+            )?))
+        }
+        Err(_) => None,
+    };
+
+    // Inicializar o armazenamento de embeddings
+    let template_mode = settings
+        .var("TEMPLATE_MODE")
+        .unwrap_or_else(|_| "off".to_string())
+        .parse::<TemplateMode>()?;
+    let store_shards = match settings.var("STORE_SHARDS") {
+        Ok(shards) => Some(shards.parse::<usize>()?),
+        Err(_) => None,
+    };
+    // Share of deleted entries in a shard that triggers its compaction
+    let compaction_ratio = match settings.var("COMPACTION_RATIO") {
+        Ok(ratio) => ratio.parse::<f32>()?,
+        Err(_) => store::DEFAULT_COMPACTION_RATIO,
+    };
+    // Binary sketches pruning most entries before the exact scoring, off by
+    // default
+    let prefilter_bits = match settings.var("SEARCH_PREFILTER_BITS") {
+        Ok(bits) => bits.parse::<usize>()?,
+        Err(_) => 0,
+    };
+    if prefilter_bits % 64 != 0 {
+        return Err("SEARCH_PREFILTER_BITS must be a multiple of 64".into());
+    }
+    // Inverted file index: searches only scan the entries of the IVF_NPROBE
+    // lists closest to the query, off by default
+    let ivf = match settings.var("IVF_NLIST") {
+        Ok(nlist) => {
+            let nlist = nlist.parse::<usize>()?;
+            let nprobe = match settings.var("IVF_NPROBE") {
+                Ok(nprobe) => nprobe.parse::<usize>()?,
+                Err(_) => ivf::DEFAULT_NPROBE,
+            };
+            if nlist < 2 || nprobe == 0 {
+                return Err("IVF_NLIST must be at least 2 and IVF_NPROBE at least 1".into());
+            }
+            Some(IvfConfig { nlist, nprobe })
+        }
+        Err(_) => None,
+    };
+    // Searches scan vectors reduced to PCA_DIMENSIONS by a projection fitted
+    // on the gallery, then re-score their best entries exactly; off by default
+    let pca = match settings.var("PCA_DIMENSIONS") {
+        Ok(dimensions) => {
+            let dimensions = dimensions.parse::<usize>()?;
+            let margin = match settings.var("PCA_RESCORE_MARGIN") {
+                Ok(margin) => margin.parse::<f32>()?,
+                Err(_) => pca::DEFAULT_RESCORE_MARGIN,
+            };
+            if dimensions == 0 || embedding_dimension.is_some_and(|d| dimensions >= d) {
+                return Err(
+                    "PCA_DIMENSIONS must be at least 1 and below the embedding dimension".into(),
+                );
+            }
+            if !(0.0..=2.0).contains(&margin) {
+                return Err("PCA_RESCORE_MARGIN must be between 0 and 2".into());
+            }
+            Some(PcaConfig { dimensions, margin })
+        }
+        Err(_) => None,
+    };
+    // Half precision halves the memory of the vectors. With PCA_DIMENSIONS the
+    // full vectors are only read to re-score the shortlist, so they default
+    // to it
+    let precision = match settings.var("STORE_PRECISION") {
+        Ok(precision) => precision.parse::<Precision>()?,
+        Err(_) if pca.is_some() => Precision::F16,
+        Err(_) => Precision::F32,
+    };
+    // Caps on the entries held in memory, past which the least recently
+    // matched targets are evicted
+    let memory_limits = MemoryLimits {
+        max_entries: match settings.var("STORE_MAX_ENTRIES") {
+            Ok(entries) => Some(entries.parse::<usize>()?),
+            Err(_) => None,
+        },
+        max_bytes: match settings.var("STORE_MAX_BYTES") {
+            Ok(bytes) => Some(bytes.parse::<usize>()?),
+            Err(_) => None,
+        },
+    };
+    // Evicted targets are paged back in from the database
+    if !storage.is_postgres()
+        && (memory_limits.max_entries.is_some() || memory_limits.max_bytes.is_some())
+    {
+        return Err("STORE_MAX_ENTRIES and STORE_MAX_BYTES require STORAGE=postgres".into());
+    }
+    // Memory entries do not keep their creation time
+    if !storage.persists_targets() && settings.is_set("RETENTION_SECS") {
+        return Err("RETENTION_SECS requires STORAGE=postgres or STORAGE=mysql".into());
+    }
+    // Similarities of every vector computed on the GPU, falling back to the
+    // CPU scan when there is no adapter
+    let gpu_search = settings
+        .var("GPU_SEARCH")
+        .map(|value| value == "true" || value == "1")
+        .unwrap_or(false);
+    #[cfg(not(feature = "gpu-search"))]
+    if gpu_search {
+        return Err("GPU_SEARCH needs the gpu-search feature".into());
+    }
+    #[cfg(feature = "gpu-search")]
+    let gpu = if gpu_search {
+        match gpu::GpuContext::request().await {
+            Ok(context) => {
+                tracing::info!(adapter = %context.adapter, "GPU search enabled");
+                Some(context)
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, "No GPU for the searches, scanning on the CPU");
+                None
+            }
+        }
+    } else {
+        None
+    };
+    // Rows written before models were recorded are of the model that wrote
+    // the oldest tagged one; skipped by searches once it was swapped
+    let legacy_model_version = match storage {
+        Storage::Memory => None,
+        Storage::Postgres | Storage::MySql => targets.legacy_model_version().await?,
+    };
+    if let Some(legacy) = legacy_model_version
+        .as_deref()
+        .filter(|legacy| *legacy != version.model_version())
+    {
+        tracing::warn!(
+            legacy_model_version = legacy,
+            "Registrations without a model are of a previous model, searches skip them until reindexed"
+        );
+    }
+    let collections = Collections::new(store_shards, template_mode)
+        .with_compaction_ratio(compaction_ratio)
+        .with_prefilter_bits(prefilter_bits)
+        .with_precision(precision)
+        .with_ivf(ivf)
+        .with_pca(pca)
+        .with_memory_limits(memory_limits)
+        .with_model_version(version.model_version())
+        .with_legacy_model_version(legacy_model_version.as_deref())
+        .with_dimension(embedding_dimension)
+        .with_gallery_shard(gallery_shard);
+    #[cfg(feature = "gpu-search")]
+    let collections = collections.with_gpu(gpu);
     tracing::info!(
-        "Connecting to target database '{}' with a connection pool...",
-        postgres_db
+        shards = ?store_shards,
+        template_mode = ?template_mode,
+        precision = precision.as_str(),
+        compaction_ratio,
+        prefilter_bits,
+        ivf = ?ivf,
+        pca = ?pca,
+        memory_limits = ?memory_limits,
+        "Initializing embeddings store..."
     );
-    let pool = PgPoolOptions::new()
-        .max_connections(5)
-        .connect(&target_db_url)
-        .await?;
 
-    // 5. Ping the database to verify connection
-    pool.acquire().await?.ping().await?;
+    // One in-memory store per collection, including empty ones
+    if storage.is_postgres() {
+        db::load_collections(&pool, &collections).await?;
+    }
+
+    // Per-origin capacity limits
+    let quotas = settings
+        .var("ORIGIN_QUOTAS")
+        .unwrap_or_default()
+        .parse::<Quotas>()?
+        .with_default_limit(match settings.var("DEFAULT_ORIGIN_QUOTA") {
+            Ok(limit) => Some(limit.parse::<usize>()?),
+            Err(_) => None,
+        });
+    tracing::info!(quotas = ?quotas, "Origin quotas configured");
+
+    let origin_thresholds: HashMap<String, f32> =
+        util::parse_key_values(&settings.var("ORIGIN_THRESHOLDS").unwrap_or_default())?;
+    tracing::info!(origin_thresholds = ?origin_thresholds, "Origin thresholds configured");
+
+    // Tenant resolution: API keys mapped to tenants and/or a trusted tenant header
+    let tenant_api_keys: HashMap<String, String> =
+        util::parse_key_values(&settings.var("TENANT_API_KEYS").unwrap_or_default())?;
+    let tenant_header = match settings.var("TENANT_HEADER") {
+        Ok(header) => Some(HeaderName::from_bytes(header.trim().as_bytes())?),
+        Err(_) => None,
+    };
+    let tenants = TenantResolver::new(tenant_api_keys, tenant_header);
     tracing::info!(
-        "Connection to target database '{}' successful.",
-        postgres_db
+        multi_tenant = tenants.is_multi_tenant(),
+        "Tenant resolution configured"
     );
 
-    // 6. Create 'targets' table if it doesn't exist
-    tracing::info!("Ensuring 'targets' table exists...");
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS targets (
-            uuid UUID NOT NULL,
-            origin VARCHAR(64) NOT NULL DEFAULT 'unknown',
-            embeddings REAL[] NOT NULL
+    // API keys stored in Postgres; the admin key manages them through /admin/
+    let auth_mode = settings
+        .var("AUTH_MODE")
+        .unwrap_or_else(|_| "none".to_string())
+        .parse::<AuthMode>()?;
+    if auth_mode == AuthMode::ApiKey && !storage.is_postgres() {
+        return Err("AUTH_MODE=api-key requires STORAGE=postgres".into());
+    }
+    let admin_api_key = settings.var("ADMIN_API_KEY").ok();
+    let mut auth = Auth::new(auth_mode, pool.clone(), admin_api_key.as_deref());
+    if auth_mode == AuthMode::Jwt {
+        // OIDC provider, e.g. https://keycloak.example.com/realms/acme
+        let issuer = settings
+            .var("JWT_ISSUER")
+            .map_err(|_| "AUTH_MODE=jwt requires JWT_ISSUER")?;
+        let jwks_cache_ttl = match settings.var("JWT_JWKS_CACHE_SECS") {
+            Ok(secs) => Duration::from_secs(secs.parse::<u64>()?),
+            Err(_) => Duration::from_secs(300),
+        };
+        let config = JwtConfig {
+            issuer,
+            audience: settings.var("JWT_AUDIENCE").ok(),
+            jwks_url: settings.var("JWT_JWKS_URL").ok(),
+            jwks_cache_ttl,
+        };
+        tracing::info!(config = ?config, "JWT validation configured");
+        auth = auth.with_jwt(
+            JwtVerifier::new(config),
+            settings.var("JWT_TENANT_CLAIM").ok(),
         );
-        "#,
-    )
-    .execute(&pool)
-    .await?;
-    tracing::info!("'targets' table is ready.");
+    }
+    tracing::info!(
+        auth_mode = ?auth_mode,
+        admin = auth.has_admin(),
+        "Authentication configured"
+    );
 
-    // Initialize ONNX Runtime environment globally
-    init().with_name("ArcFaceApp").commit()?;
-    tracing::info!("ONNX Runtime environment initialized.");
+    // Per-client token buckets on the inference endpoints
+    let rate_limit_rps = match settings.var("RATE_LIMIT_RPS") {
+        Ok(rate) => Some(rate.parse::<f64>()?),
+        Err(_) => None,
+    };
+    let rate_limit_burst = match settings.var("RATE_LIMIT_BURST") {
+        Ok(burst) => Some(burst.parse::<f64>()?),
+        Err(_) => None,
+    };
+    let trust_forwarded_for = settings
+        .var("RATE_LIMIT_TRUST_FORWARDED_FOR")
+        .map(|value| value == "true" || value == "1")
+        .unwrap_or(false);
 
-    tracing::info!("Loading ArcFace ONNX model...");
-    // Build session with absolute path to ONNX model
-    let model_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
-        .join("models")
-        .join("arcfaceresnet100-8.onnx");
-    tracing::info!(model_path = ?model_path, "Using ONNX model file");
-    let onnx_session = Session::builder()?
-        .with_optimization_level(GraphOptimizationLevel::Level3)?
-        .commit_from_file(model_path.clone())?;
+    // Traffic mirroring of /search/ to a staging deployment
+    let shadow = match settings.var("SHADOW_BASE_URL") {
+        Ok(base_url) => {
+            let sample_rate = match settings.var("SHADOW_SAMPLE_RATE") {
+                Ok(rate) => rate.parse::<f64>()?,
+                Err(_) => 0.01,
+            };
+            let max_in_flight = match settings.var("SHADOW_MAX_IN_FLIGHT") {
+                Ok(max) => max.parse::<usize>()?,
+                Err(_) => 16,
+            };
+            tracing::info!(%base_url, sample_rate, max_in_flight, "Search shadowing enabled");
+            Some(Shadow::new(
+                base_url,
+                sample_rate,
+                settings.var("SHADOW_API_KEY").ok(),
+                max_in_flight,
+            )?)
+        }
+        Err(_) => None,
+    };
 
-    tracing::info!(model_path = ?model_path, "ONNX model loaded successfully.");
+    // Kiosks re-submitting near-identical frames get the cached matches
+    let query_cache = match settings.var("QUERY_CACHE_URL") {
+        Ok(url) => {
+            let ttl = match settings.var("QUERY_CACHE_TTL_SECS") {
+                Ok(secs) => Duration::from_secs(secs.parse::<u64>()?),
+                Err(_) => query_cache::DEFAULT_TTL,
+            };
+            let min_similarity = match settings.var("QUERY_CACHE_MIN_SIMILARITY") {
+                Ok(similarity) => similarity.parse::<f32>()?,
+                Err(_) => query_cache::DEFAULT_MIN_SIMILARITY,
+            };
+            tracing::info!(?ttl, min_similarity, "Query cache enabled");
+            Some(QueryCache::new(&url, ttl, min_similarity)?)
+        }
+        Err(_) => None,
+    };
 
-    // Inicializar o armazenamento de embeddings
-    tracing::info!("Initializing embeddings store...");
-    let mut embeddings_store = EmbeddingsStore::new();
+    // Runtime switches of risky subsystems, also changeable through /admin/flags/
+    let configured_flags: HashMap<String, FlagRule> =
+        util::parse_key_values(&settings.var("FEATURE_FLAGS").unwrap_or_default())?;
+    let flags = FeatureFlags::new(configured_flags);
+    tracing::info!(flags = ?flags.list(), "Feature flags configured");
 
-    // Carregar todos os embeddings existentes do banco de dados
-    tracing::info!("Loading existing embeddings from database into memory...");
-    let all_embeddings = sqlx::query("SELECT uuid, embeddings, origin FROM targets")
-        .fetch_all(&pool)
-        .await?;
+    // Similarity from which search hits are published as match events
+    let alert_threshold = model_profile.alert_threshold;
+    tracing::info!(alert_threshold, "Match events configured");
+    let events = Events::new(alert_threshold);
 
-    if !all_embeddings.is_empty() {
-        for record in &all_embeddings {
-            let uuid: Uuid = record.try_get("uuid")?;
-            let origin: String = record.try_get("origin").unwrap_or_else(|_| "".to_string());
-            let embeddings: Vec<f32> = record.try_get("embeddings")?;
+    // Settings changeable at runtime through /admin/config and SIGHUP
+    let runtime_settings = tunables::Settings {
+        default_threshold: model_profile.search_threshold,
+        default_limit: match settings.var("SEARCH_LIMIT") {
+            Ok(limit) => limit.parse::<usize>()?,
+            Err(_) => handlers::DEFAULT_LIMIT,
+        },
+        alert_threshold,
+        rate_limit_rps,
+        rate_limit_burst,
+    };
+    runtime_settings.validate()?;
+    if let Some((rate, burst)) = runtime_settings.rate_limits() {
+        tracing::info!(rate, burst, trust_forwarded_for, "Rate limiting enabled");
+    }
+    let rate_limiter = RateLimiter::new(runtime_settings.rate_limits(), trust_forwarded_for);
+    let tunables = Tunables::new(runtime_settings);
 
-            embeddings_store.add(uuid, origin, embeddings);
+    // Similarities mapped to match probabilities in the responses
+    let calibration = match settings.var("CALIBRATION_PATH") {
+        Ok(path) => {
+            let calibration = Calibration::load(&PathBuf::from(&path))?;
+            tracing::info!(%path, ?calibration, "Match probability calibration loaded");
+            Some(Arc::new(calibration))
         }
-        tracing::info!("Loaded {} embeddings into memory", embeddings_store.len());
+        Err(_) => None,
+    };
+
+    // Registrations and match events POSTed to downstream systems
+    let webhook_urls: Vec<String> = settings
+        .var("WEBHOOK_URLS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+        .map(str::to_string)
+        .collect();
+    let webhooks = if webhook_urls.is_empty() {
+        None
+    } else {
+        let max_attempts = match settings.var("WEBHOOK_MAX_ATTEMPTS") {
+            Ok(attempts) => attempts.parse::<u32>()?,
+            Err(_) => 5,
+        };
+        let secret = settings.var("WEBHOOK_SECRET").ok();
+        tracing::info!(
+            urls = ?webhook_urls,
+            signed = secret.is_some(),
+            max_attempts,
+            "Webhooks enabled"
+        );
+        Some(Webhooks::start(
+            webhook_urls,
+            secret,
+            max_attempts,
+            &events,
+        )?)
+    };
+
+    let host = settings
+        .var("HOST")
+        .unwrap_or_else(|_| "0.0.0.0".to_string());
+    let port = settings.var("PORT").unwrap_or_else(|_| "3000".to_string());
+    let addr_str = format!("{}:{}", host, port);
+    let addr: SocketAddr = addr_str.parse().expect("Invalid address format");
+
+    // Optional HTTPS (and gRPC over TLS) without a reverse proxy in front
+    let tls = match (settings.var("TLS_CERT_PATH"), settings.var("TLS_KEY_PATH")) {
+        (Ok(cert_path), Ok(key_path)) if serve => {
+            let tls = Tls::load(PathBuf::from(&cert_path), PathBuf::from(key_path))
+                .await
+                .map_err(|e| format!("failed to load TLS certificate {}: {}", cert_path, e))?;
+            tracing::info!(cert = %cert_path, "Serving over TLS");
+            Some(tls)
+        }
+        (Ok(_), Err(_)) | (Err(_), Ok(_)) => {
+            return Err("TLS_CERT_PATH and TLS_KEY_PATH must be set together".into());
+        }
+        _ => None,
+    };
+
+    // The API port is bound before the gallery is loaded, so liveness and
+    // readiness probes get an answer while it streams into memory; offline
+    // commands bind nothing
+    let (listener, loading) = if serve {
+        let listener = std::net::TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        tracing::info!(address = %addr, "Serving probes while the gallery loads");
+        let loading = readiness::serve_loading(listener.try_clone()?, tls.clone())?;
+        (Some(listener), Some(loading))
+    } else {
+        (None, None)
+    };
+
+    // Binary snapshot of the targets table, restored at startup so only newer
+    // rows are loaded from the database; in memory-only mode it is the
+    // registrations' only copy
+    let snapshot_path = settings.var("SNAPSHOT_PATH").ok().map(PathBuf::from);
+    if !command.loads_gallery() {
+        tracing::info!("Offline command, not loading the gallery");
+    } else if storage == Storage::MySql {
+        if snapshot_path.is_some() {
+            tracing::warn!("Snapshots need Postgres or memory storage, ignoring SNAPSHOT_PATH");
+        }
+        tracing::info!("Loading existing embeddings from MySQL into memory...");
+        targets.load_all(&collections, 0).await?;
+    } else if storage.is_postgres() {
+        let replay_from = match &snapshot_path {
+            Some(path) => snapshot::restore(path, &bulk_pool, &collections).await,
+            None => None,
+        };
+
+        // Carregar todos os embeddings existentes do banco de dados
+        tracing::info!(
+            ?replay_from,
+            "Loading existing embeddings from database into memory..."
+        );
+        targets
+            .load_all(&collections, replay_from.unwrap_or(0))
+            .await?;
+        // Archived rows of the snapshot are dropped again, the others
+        // accounted for
+        if let Some(policy) = &archive_policy {
+            archive::sweep(&read_pool, &collections, policy).await?;
+        }
+    } else if let Some(path) = &snapshot_path {
+        // Starting empty would overwrite the only copy with the next snapshot
+        if !encryption::enabled() && snapshot::is_sealed(path) {
+            return Err("the snapshot is encrypted, set EMBEDDING_ENCRYPTION_KEY".into());
+        }
+        snapshot::restore_memory(path, &collections).await;
+    } else {
+        tracing::warn!(
+            "Memory-only storage without SNAPSHOT_PATH, registrations are lost on restart"
+        );
+    }
+    // Returns the slack of the growing store vectors, which on small devices
+    // can be the margin between loading the gallery and running out of memory
+    #[cfg(feature = "edge")]
+    for (_, _, collection) in collections.all() {
+        collection.store.shrink_to_fit().await;
+    }
+
+    if !collections.is_empty() {
+        tracing::info!("Loaded {} embeddings into memory", collections.len());
     } else {
         tracing::info!("No existing embeddings found in database");
     }
+    if let Some(loading) = loading {
+        loading.finish().await?;
+    }
+    let collections = Arc::new(collections);
+    let snapshot_source = match storage {
+        Storage::Postgres => Some(snapshot::Source::Database(bulk_pool.clone())),
+        Storage::MySql => None,
+        Storage::Memory => Some(snapshot::Source::Memory(collections.clone())),
+    };
+    if let (true, Some(path), Some(snapshot_source)) = (serve, &snapshot_path, &snapshot_source) {
+        let interval = match settings.var("SNAPSHOT_INTERVAL_SECS") {
+            Ok(secs) => Duration::from_secs(secs.parse::<u64>()?),
+            Err(_) => snapshot::DEFAULT_SNAPSHOT_INTERVAL,
+        };
+        tracing::info!(path = ?path, ?interval, "Snapshots enabled");
+        snapshot::spawn_writer(snapshot_source.clone(), path.clone(), interval);
+    }
+
+    // Centroids are trained in the background once a store is large enough,
+    // and trained again as it grows
+    if let (true, Some(ivf)) = (serve, ivf) {
+        let interval = match settings.var("IVF_TRAIN_INTERVAL_SECS") {
+            Ok(secs) => Duration::from_secs(secs.parse::<u64>()?),
+            Err(_) => ivf::DEFAULT_TRAIN_INTERVAL,
+        };
+        // Saved next to the snapshot, so restarts do not train again
+        let saved = snapshot_path.as_ref().map(|path| SavedIndex {
+            path: snapshot::index_path(path),
+            model: version.model_version().to_string(),
+        });
+        tracing::info!(
+            nlist = ivf.nlist,
+            nprobe = ivf.nprobe,
+            ?interval,
+            "IVF index enabled"
+        );
+        tokio::spawn(ivf::run(collections.clone(), interval, saved));
+    }
+
+    // The projection is fitted the same way, and fitted again as the store
+    // grows
+    if let (true, Some(pca)) = (serve, pca) {
+        let interval = match settings.var("PCA_TRAIN_INTERVAL_SECS") {
+            Ok(secs) => Duration::from_secs(secs.parse::<u64>()?),
+            Err(_) => pca::DEFAULT_TRAIN_INTERVAL,
+        };
+        tracing::info!(
+            dimensions = pca.dimensions,
+            margin = pca.margin,
+            ?interval,
+            "PCA search enabled"
+        );
+        tokio::spawn(pca::run(collections.clone(), interval));
+    }
+
+    // Tamper-evident trail of every mutation and search
+    let audit = if settings
+        .var("AUDIT_LOG")
+        .map(|value| value == "true" || value == "1")
+        .unwrap_or(false)
+    {
+        if !storage.is_postgres() {
+            return Err("AUDIT_LOG requires STORAGE=postgres".into());
+        }
+        tracing::info!("Audit log enabled");
+        Some(Arc::new(AuditLog::start(pool.clone())))
+    } else {
+        None
+    };
+
+    // Append registrations are acknowledged once in memory and written to
+    // the database in batched transactions
+    let write_behind = if settings
+        .var("WRITE_BEHIND")
+        .map(|value| value == "true" || value == "1")
+        .unwrap_or(false)
+    {
+        if !storage.persists_targets() {
+            return Err("WRITE_BEHIND requires a database STORAGE".into());
+        }
+        let config = WriteBehindConfig {
+            max_batch: match settings.var("WRITE_BEHIND_MAX_BATCH") {
+                Ok(batch) => batch.parse::<usize>()?,
+                Err(_) => write_behind::DEFAULT_MAX_BATCH,
+            },
+            flush_interval: match settings.var("WRITE_BEHIND_FLUSH_MS") {
+                Ok(ms) => Duration::from_millis(ms.parse::<u64>()?),
+                Err(_) => write_behind::DEFAULT_FLUSH_INTERVAL,
+            },
+            queue_size: match settings.var("WRITE_BEHIND_QUEUE") {
+                Ok(size) => size.parse::<usize>()?,
+                Err(_) => write_behind::DEFAULT_QUEUE_SIZE,
+            },
+        };
+        if config.max_batch == 0 || config.queue_size == 0 {
+            return Err("WRITE_BEHIND_MAX_BATCH and WRITE_BEHIND_QUEUE must be at least 1".into());
+        }
+        tracing::info!(?config, "Write-behind of registrations enabled");
+        Some(Arc::new(WriteBehind::start(
+            targets.clone(),
+            db_breaker.clone(),
+            config,
+        )))
+    } else {
+        None
+    };
+
+    // Searches kept with their matches, for GET /searches
+    let search_history = if settings
+        .var("SEARCH_HISTORY")
+        .map(|value| value == "true" || value == "1")
+        .unwrap_or(false)
+    {
+        if !storage.is_postgres() {
+            return Err("SEARCH_HISTORY requires STORAGE=postgres".into());
+        }
+        tracing::info!("Search history enabled");
+        Some(Arc::new(SearchHistory::start(pool.clone())))
+    } else {
+        None
+    };
+
+    // Retried registrations replay the first response, for clients on flaky networks
+    let idempotency = match settings.var("IDEMPOTENCY_TTL_SECS") {
+        Ok(secs) => {
+            if !storage.is_postgres() {
+                return Err("IDEMPOTENCY_TTL_SECS requires STORAGE=postgres".into());
+            }
+            let ttl = Duration::from_secs(secs.parse()?);
+            tracing::info!(ttl = ?ttl, "Idempotency keys enabled");
+            Some(Arc::new(Idempotency::start(pool.clone(), ttl)))
+        }
+        Err(_) => None,
+    };
+
+    // Quotas and billing counts of API keys
+    let key_usage = if auth_mode == AuthMode::ApiKey {
+        let interval = match settings.var("KEY_USAGE_FLUSH_SECS") {
+            Ok(secs) => Duration::from_secs(secs.parse()?),
+            Err(_) => key_usage::DEFAULT_FLUSH_INTERVAL,
+        };
+        tracing::info!(?interval, "API key usage accounting enabled");
+        Some(KeyUsage::start(pool.clone(), interval))
+    } else {
+        None
+    };
+
+    // Background jobs run at once, the others wait in line
+    let job_max_concurrency = match settings.var("JOB_MAX_CONCURRENCY") {
+        Ok(jobs) => jobs.parse::<usize>()?,
+        Err(_) => jobs::DEFAULT_MAX_CONCURRENCY,
+    };
+    let job_max_queue = match settings.var("JOB_MAX_QUEUE") {
+        Ok(jobs) => jobs.parse::<usize>()?,
+        Err(_) => jobs::DEFAULT_MAX_QUEUE,
+    };
 
     // Create the application state
     let app_state = AppState {
-        onnx_session: Arc::new(onnx_session),
+        embedder,
+        model_name,
+        embedding_dimension,
+        version: Arc::new(version),
+        super_resolution,
+        detector,
+        liveness,
+        mask,
+        attributes,
+        emotion,
+        landmarks,
+        pose,
+        quality_gate: Arc::new(quality_gate),
+        image_limits,
+        flip_tta,
+        inference_timeout,
+        inference_queue,
+        crops,
         db_pool: pool.clone(),
-        embeddings_store: Arc::new(Mutex::new(embeddings_store)),
+        read_pool: read_pool.clone(),
+        bulk_pool: bulk_pool.clone(),
+        targets,
+        db_breaker,
+        collections,
+        quotas: Arc::new(quotas),
+        origin_thresholds: Arc::new(origin_thresholds),
+        calibration,
+        tenants: Arc::new(tenants),
+        auth: Arc::new(auth),
+        rate_limiter: Arc::new(rate_limiter),
+        shadow: shadow.map(Arc::new),
+        query_cache: query_cache.map(Arc::new),
+        flags: Arc::new(flags),
+        events: Arc::new(events),
+        tunables: Arc::new(tunables),
+        webhooks: webhooks.map(Arc::new),
+        notifier,
+        audit,
+        write_behind,
+        search_history,
+        idempotency,
+        key_usage,
+        jobs: Arc::new(Jobs::new(job_max_concurrency, job_max_queue)),
+        enrollments: Arc::new(Enrollments::default()),
+        run_mode,
+        storage,
+        archive: archive_policy,
+        sharding,
+    };
+
+    // Offline commands share the startup above, then exit without serving
+    let Some(listener) = listener else {
+        return cli::run(command, app_state).await;
+    };
+
+    if let Some(notifier) = app_state.notifier.clone() {
+        tokio::spawn(notify::listen(app_state.clone(), notifier));
+    }
+
+    // SIGHUP re-reads the config file's runtime settings
+    #[cfg(unix)]
+    if let Some((path, file_variables)) = config_file {
+        tokio::spawn(tunables::reload_on_hangup(
+            app_state.clone(),
+            path,
+            file_variables,
+        ));
+    }
+
+    // Expired registrations and those older than the retention policy are
+    // deleted for good
+    if run_mode.is_writable() && storage.persists_targets() {
+        let interval = match settings.var("EXPIRY_SWEEP_INTERVAL_SECS") {
+            Ok(secs) => Duration::from_secs(secs.parse::<u64>()?),
+            Err(_) => expiry::DEFAULT_SWEEP_INTERVAL,
+        };
+        let retention = match settings.var("RETENTION_SECS") {
+            Ok(secs) => Some(Duration::from_secs(secs.parse::<u64>()?)),
+            Err(_) => None,
+        };
+        tracing::info!(?interval, ?retention, "Expiry sweeper started");
+        tokio::spawn(expiry::run(app_state.clone(), interval, retention));
+    } else if run_mode.is_writable() {
+        let interval = match settings.var("EXPIRY_SWEEP_INTERVAL_SECS") {
+            Ok(secs) => Duration::from_secs(secs.parse::<u64>()?),
+            Err(_) => expiry::DEFAULT_SWEEP_INTERVAL,
+        };
+        tracing::info!(?interval, "Memory expiry sweeper started");
+        tokio::spawn(expiry::run_memory(app_state.clone(), interval));
+    }
+
+    // Month partitions are created ahead of the rows, origin partitions for
+    // new origins; nothing to do on a table that is not partitioned
+    if run_mode.is_writable() && storage.is_postgres() {
+        let interval = match settings.var("PARTITION_MAINTENANCE_INTERVAL_SECS") {
+            Ok(secs) => Duration::from_secs(secs.parse::<u64>()?),
+            Err(_) => partition::DEFAULT_MAINTENANCE_INTERVAL,
+        };
+        tokio::spawn(partition::run(bulk_pool.clone(), interval));
+    }
+
+    if let Some(policy) = app_state.archive.clone() {
+        let interval = match settings.var("ARCHIVE_INTERVAL_SECS") {
+            Ok(secs) => Duration::from_secs(secs.parse::<u64>()?),
+            Err(_) => archive::DEFAULT_INTERVAL,
+        };
+        tokio::spawn(archive::run(
+            read_pool.clone(),
+            app_state.collections.clone(),
+            policy,
+            interval,
+        ));
+    }
+
+    // Periodic reconciliation with rows written by other processes
+    if let Ok(secs) = settings.var("RESYNC_INTERVAL_SECS") {
+        if !storage.is_postgres() {
+            return Err("RESYNC_INTERVAL_SECS requires STORAGE=postgres".into());
+        }
+        let interval = Duration::from_secs(secs.parse::<u64>()?);
+        tracing::info!(?interval, "Store resync enabled");
+        tokio::spawn(resync::run(app_state.clone(), interval));
+    }
+
+    // Event-driven enrollment from a NATS JetStream subject
+    if let Ok(url) = settings.var("INGEST_NATS_URL") {
+        let config = IngestConfig {
+            url,
+            stream: settings
+                .var("INGEST_STREAM")
+                .unwrap_or_else(|_| "ENROLLMENTS".to_string()),
+            subject: settings
+                .var("INGEST_SUBJECT")
+                .unwrap_or_else(|_| "owlfacerec.enroll".to_string()),
+            consumer: settings
+                .var("INGEST_CONSUMER")
+                .unwrap_or_else(|_| "owlfacerec".to_string()),
+        };
+        if run_mode.is_writable() {
+            tokio::spawn(ingest::run(app_state.clone(), config));
+        } else {
+            tracing::warn!("Read-only mode, enrollment ingestion disabled");
+        }
+    }
+
+    // Cameras searched continuously, e.g. RTSP_STREAMS=gate=rtsp://10.0.0.5/stream1
+    let cameras: HashMap<String, String> =
+        util::parse_key_values(&settings.var("RTSP_STREAMS").unwrap_or_default())?;
+    if !cameras.is_empty() {
+        let mut cameras: Vec<(String, String)> = cameras.into_iter().collect();
+        cameras.sort();
+        let fps = match settings.var("RTSP_FPS") {
+            Ok(fps) => fps.parse::<f32>()?,
+            Err(_) => 1.0,
+        };
+        if fps <= 0.0 {
+            return Err("RTSP_FPS must be positive".into());
+        }
+        let config = RtspConfig {
+            cameras,
+            fps,
+            tenant: settings
+                .var("RTSP_TENANT")
+                .unwrap_or_else(|_| tenant::DEFAULT_TENANT.to_string()),
+            collection: settings
+                .var("RTSP_COLLECTION")
+                .unwrap_or_else(|_| collections::DEFAULT_COLLECTION.to_string()),
+        };
+        // URLs often embed camera credentials, only names are logged
+        tracing::info!(
+            cameras = ?config.cameras.iter().map(|(name, _)| name).collect::<Vec<_>>(),
+            fps,
+            "Starting camera stream workers"
+        );
+        rtsp::start(app_state.clone(), config);
+    }
+
+    // Largest JSON body and gRPC message, and resolution caps of the images
+    // they carry
+    let max_body_bytes = match settings.var("BODY_MAX_BYTES") {
+        Ok(bytes) => bytes.parse::<usize>()?,
+        Err(_) => handlers::DEFAULT_MAX_BODY_BYTES,
+    };
+
+    // Largest video accepted by /search/video/
+    let max_video_bytes = match settings.var("VIDEO_MAX_BYTES") {
+        Ok(bytes) => bytes.parse::<usize>()?,
+        Err(_) => video::DEFAULT_MAX_VIDEO_BYTES,
+    };
+    let max_import_bytes = match settings.var("IMPORT_MAX_BYTES") {
+        Ok(bytes) => bytes.parse::<usize>()?,
+        Err(_) => import::DEFAULT_MAX_IMPORT_BYTES,
     };
 
     // build our application with multiple routes and state
-    let app = Router::new()
+    // Mutating endpoints answer 405 on read-only replicas
+    let writes = middleware::from_fn_with_state(app_state.clone(), run_mode::reject_writes);
+    // Registrations and searches run inference, so they share the per-client rate limit
+    let limited = middleware::from_fn_with_state(app_state.clone(), rate_limit::limit);
+    // Sampled searches are also sent to staging, when configured
+    let mirrored = middleware::from_fn_with_state(app_state.clone(), shadow::mirror);
+    // Endpoints backed by tables only, 501 in memory-only mode
+    let database = middleware::from_fn_with_state(app_state.clone(), storage::require_database);
+    // Retries with an Idempotency-Key get the first response replayed
+    let idempotent = middleware::from_fn_with_state(app_state.clone(), idempotency::replay);
+    // Protobuf bodies are answered in protobuf, JSON ones reach the handler
+    let protobuf_register = middleware::from_fn_with_state(app_state.clone(), protobuf::register);
+    let protobuf_search = middleware::from_fn_with_state(app_state.clone(), protobuf::search);
+
+    // Every route touching targets is scoped to the tenant of the request
+    let tenant_routes = Router::new()
+        .route(
+            "/register/",
+            post(handlers::register)
+                .route_layer(protobuf_register.clone())
+                .route_layer(idempotent.clone())
+                .route_layer(writes.clone())
+                .route_layer(limited.clone()),
+        )
+        // Records are read one at a time as they arrive, so the body has no
+        // overall limit; each one is charged to the rate limit by the handler
+        .route(
+            "/register/stream",
+            post(register_stream::register_stream).route_layer(writes.clone()),
+        )
+        .route(
+            "/analyze/",
+            post(handlers::analyze).route_layer(limited.clone()),
+        )
+        .route(
+            "/detect/",
+            post(handlers::detect).route_layer(limited.clone()),
+        )
+        .route(
+            "/landmarks/",
+            post(handlers::landmarks).route_layer(limited.clone()),
+        )
+        .route("/usage/", get(handlers::usage))
+        .route(
+            "/stats/origins",
+            get(handlers::origin_stats).route_layer(database.clone()),
+        )
+        .route("/metrics/", get(handlers::metrics))
+        .route(
+            "/search/",
+            post(handlers::search)
+                .route_layer(protobuf_search.clone())
+                .route_layer(mirrored.clone())
+                .route_layer(limited.clone()),
+        )
+        .route(
+            "/verify/",
+            post(protobuf::verify).route_layer(limited.clone()),
+        )
+        .route(
+            "/search/video/",
+            post(video::search_video)
+                .layer(DefaultBodyLimit::max(max_video_bytes))
+                .route_layer(limited.clone()),
+        )
+        // Live video: one upgrade, then a search per frame on the socket
+        .route(
+            "/ws/search",
+            get(ws::ws_search).route_layer(limited.clone()),
+        )
+        .route(
+            "/targets/:uuid",
+            delete(handlers::delete_target)
+                .patch(handlers::update_target)
+                .route_layer(writes.clone()),
+        )
+        .route(
+            "/targets/:uuid/restore",
+            post(handlers::restore_target)
+                .route_layer(writes.clone())
+                .route_layer(database.clone()),
+        )
+        .route(
+            "/targets/:uuid/images",
+            get(handlers::target_images).route_layer(database.clone()),
+        )
+        .route(
+            "/collections/",
+            get(collections::list_collections).merge(
+                post(collections::create_collection)
+                    .route_layer(writes.clone())
+                    .route_layer(database.clone()),
+            ),
+        )
+        .route(
+            "/collections/:name",
+            delete(collections::delete_collection)
+                .route_layer(writes.clone())
+                .route_layer(database.clone()),
+        )
+        .route(
+            "/collections/:name/register/",
+            post(handlers::register_in_collection)
+                .route_layer(protobuf_register)
+                .route_layer(idempotent.clone())
+                .route_layer(writes.clone())
+                .route_layer(limited.clone()),
+        )
+        .route(
+            "/collections/:name/search/",
+            post(handlers::search_in_collection)
+                .route_layer(protobuf_search)
+                .route_layer(mirrored)
+                .route_layer(limited.clone()),
+        )
+        .route("/export/templates/", get(export::export_templates))
+        .route("/export/search/", post(export::export_search))
+        .route(
+            "/match/matrix",
+            post(matrix::match_matrix).route_layer(limited.clone()),
+        )
+        .route(
+            "/compare/matrix",
+            post(matrix::compare_matrix).route_layer(limited.clone()),
+        )
+        .route(
+            "/cluster",
+            post(cluster::cluster).route_layer(limited.clone()),
+        )
+        // Files travel base64-encoded in the job request
+        .route(
+            "/jobs",
+            post(jobs::create_job)
+                .layer(DefaultBodyLimit::max(max_import_bytes))
+                .route_layer(limited.clone()),
+        )
+        .route("/jobs/:id", get(jobs::get_job))
+        .route(
+            "/enrollments",
+            post(enrollment::open_session).route_layer(writes.clone()),
+        )
+        .route(
+            "/enrollments/:id",
+            get(enrollment::get_session).delete(enrollment::delete_session),
+        )
+        .route(
+            "/enrollments/:id/photos",
+            post(enrollment::add_photo)
+                .route_layer(writes.clone())
+                .route_layer(limited.clone()),
+        )
+        .route(
+            "/enrollments/:id/commit",
+            post(enrollment::commit_session).route_layer(writes.clone()),
+        )
+        .route("/events", get(events::match_events))
+        .route("/searches", get(history::list_searches))
+        .route(
+            "/graphql",
+            post(graphql::graphql).route_layer(database.clone()),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            audit::record,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            key_usage::account,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            tenant::resolve_tenant,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            auth::authenticate,
+        ));
+
+    // Probes and operational endpoints stay unversioned
+    let mut app = Router::new()
         .route("/", get(handlers::health_check))
         .route("/health/", get(handlers::health_check))
-        .route("/register/", post(handlers::register))
-        .route("/search/", post(handlers::search))
-        .with_state(app_state);
+        .route("/health/deep", get(handlers::deep_health_check))
+        .route("/livez", get(readiness::livez))
+        .route("/readyz", get(readiness::readyz))
+        // Prometheus scrape target, unauthenticated like the health checks
+        .route("/metrics", get(telemetry::prometheus_metrics))
+        // Its JSON summary covers every tenant, so it takes the admin key
+        .route(
+            "/stats",
+            get(telemetry::stats).route_layer(middleware::from_fn_with_state(
+                app_state.clone(),
+                auth::require_admin,
+            )),
+        )
+        .route("/version", get(version::version));
+    // Local part of the searches and verifications of the other shards, checked against
+    // GALLERY_SHARD_SECRET rather than the API keys
+    if app_state.sharding.is_some() {
+        app = app
+            .route("/shard/search", post(sharding::shard_search))
+            .route("/shard/score", post(sharding::shard_score));
+    }
+    // Every route of the API, served under /v1
+    let mut v1 = tenant_routes;
 
-    let host = env::var("HOST").unwrap_or_else(|_| "0.0.0.0".to_string());
-    let port = env::var("PORT").unwrap_or_else(|_| "3000".to_string());
-    let addr_str = format!("{}:{}", host, port);
-    let addr: SocketAddr = addr_str.parse().expect("Invalid address format");
+    // Web dashboard over the tenant API, when ADMIN_UI is set
+    if settings
+        .var("ADMIN_UI")
+        .map(|value| value == "true" || value == "1")
+        .unwrap_or(false)
+    {
+        tracing::info!("Admin dashboard served on /admin/ui");
+        app = app
+            .route("/admin/ui", get(admin_ui::index))
+            .route("/admin/ui/:file", get(admin_ui::file));
+    }
+
+    // API key management, only exposed when ADMIN_API_KEY is set
+    if app_state.auth.has_admin() {
+        let admin_routes = Router::new()
+            .route(
+                "/admin/api-keys/",
+                get(auth::list_api_keys)
+                    .merge(post(auth::create_api_key).route_layer(writes.clone()))
+                    .route_layer(database.clone()),
+            )
+            .route(
+                "/admin/api-keys/:id",
+                delete(auth::revoke_api_key)
+                    .route_layer(writes.clone())
+                    .route_layer(database.clone()),
+            )
+            .route(
+                "/admin/api-keys/:id/scopes",
+                put(auth::set_api_key_scopes)
+                    .route_layer(writes.clone())
+                    .route_layer(database.clone()),
+            )
+            .route(
+                "/admin/api-keys/:id/quotas",
+                put(auth::set_api_key_quotas)
+                    .route_layer(writes.clone())
+                    .route_layer(database.clone()),
+            )
+            .route(
+                "/admin/usage",
+                get(key_usage::usage_report).route_layer(database.clone()),
+            )
+            .route(
+                "/admin/audit",
+                get(audit::query_audit).route_layer(database.clone()),
+            )
+            .route(
+                "/admin/audit/verify",
+                get(audit::verify_audit).route_layer(database.clone()),
+            )
+            .route(
+                "/admin/consistency",
+                get(consistency::consistency).route_layer(database.clone()),
+            )
+            .route(
+                "/admin/dedupe",
+                post(dedupe::dedupe).route_layer(database.clone()),
+            )
+            .route("/admin/export", get(dataset::export_dataset))
+            .route("/admin/backup", post(backup::backup))
+            .route(
+                "/admin/restore",
+                post(backup::restore)
+                    .layer(DefaultBodyLimit::max(max_import_bytes))
+                    .route_layer(writes.clone()),
+            )
+            .route("/admin/flags/", get(flags::list_flags))
+            .route(
+                "/admin/import",
+                post(import::import_embeddings)
+                    .layer(DefaultBodyLimit::max(max_import_bytes))
+                    .route_layer(writes.clone())
+                    .route_layer(database.clone()),
+            )
+            .route("/admin/flags/:name", put(flags::set_flag))
+            .route(
+                "/admin/jobs",
+                post(jobs::create_admin_job).layer(DefaultBodyLimit::max(max_import_bytes)),
+            )
+            .route("/admin/jobs/:id", get(jobs::get_admin_job))
+            .route(
+                "/admin/origins/rename",
+                post(origins::rename_origin)
+                    .route_layer(writes.clone())
+                    .route_layer(database.clone()),
+            )
+            .route(
+                "/admin/reenrollment",
+                get(reembed::reenrollment).route_layer(database.clone()),
+            )
+            .route(
+                "/admin/reindex",
+                post(reembed::reindex)
+                    .route_layer(writes.clone())
+                    .route_layer(database.clone()),
+            )
+            .route(
+                "/admin/config",
+                get(tunables::get_config).put(tunables::update_config),
+            )
+            .route(
+                "/admin/reload",
+                post(consistency::reload).route_layer(database.clone()),
+            )
+            .route_layer(middleware::from_fn_with_state(
+                app_state.clone(),
+                audit::record,
+            ))
+            .route_layer(middleware::from_fn_with_state(
+                app_state.clone(),
+                auth::require_admin,
+            ));
+        v1 = v1.merge(admin_routes);
+    }
+    let app = app
+        .nest(api_version::V1, v1.clone())
+        // Paths from before versioning answer as /v1 until they are removed
+        .merge(v1.route_layer(middleware::from_fn(api_version::deprecated)))
+        // Routes with their own limit (videos, imports) override it
+        .layer(DefaultBodyLimit::max(max_body_bytes))
+        .layer(middleware::from_fn(backpressure::prioritize))
+        .layer(middleware::from_fn(telemetry::track_requests))
+        .layer(middleware::from_fn(access_log::log_requests))
+        .layer(middleware::from_fn(otel::trace_requests))
+        .layer(middleware::from_fn(error_reporting::report_requests))
+        .layer(middleware::from_fn(request_id::propagate))
+        .with_state(app_state.clone());
+
+    // Optional gRPC API on its own port, sharing the state of the HTTP API
+    let grpc_server = match settings.var("GRPC_PORT") {
+        Ok(grpc_port) => {
+            let grpc_addr: SocketAddr = format!("{}:{}", host, grpc_port).parse()?;
+            tracing::info!(address = %grpc_addr, "gRPC listening on address");
+            let router = tonic::transport::Server::builder()
+                .add_service(grpc::GrpcService::server(app_state.clone(), max_body_bytes));
+            // With TLS the connections are handshaken with the reloadable
+            // certificate of the HTTPS port
+            let server = match &tls {
+                Some(tls) => {
+                    let incoming =
+                        tls.grpc_incoming(tokio::net::TcpListener::bind(grpc_addr).await?);
+                    tokio::spawn(router.serve_with_incoming_shutdown(incoming, shutdown_signal()))
+                }
+                None => tokio::spawn(router.serve_with_shutdown(grpc_addr, shutdown_signal())),
+            };
+            Some(server)
+        }
+        Err(_) => None,
+    };
 
     tracing::info!(address = %addr, "listening on address");
-    let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    // On shutdown stop accepting connections and let in-flight requests finish
+    // Peer addresses identify clients for rate limiting
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+    match tls {
+        Some(tls) => {
+            let handle = axum_server::Handle::new();
+            let shutdown = handle.clone();
+            tokio::spawn(async move {
+                shutdown_signal().await;
+                shutdown.graceful_shutdown(None);
+            });
+            axum_server::from_tcp_rustls(listener, tls.config)
+                .handle(handle)
+                .serve(app)
+                .await?;
+        }
+        None => {
+            let listener = tokio::net::TcpListener::from_std(listener)?;
+            axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown_signal())
+                .await?;
+        }
+    }
+    if let Some(grpc_server) = grpc_server {
+        grpc_server.await??;
+    }
+    tracing::info!("Server stopped, all connections drained");
+
+    // Write what is still queued for the database and a fresh snapshot, so
+    // the next start replays as little as possible, then close the pool
+    let flush = async {
+        if let Some(write_behind) = &app_state.write_behind {
+            write_behind.close().await;
+        }
+        if let Some(audit) = &app_state.audit {
+            audit.close().await;
+        }
+        if let Some(search_history) = &app_state.search_history {
+            search_history.close().await;
+        }
+        if let Some(key_usage) = &app_state.key_usage {
+            key_usage.close().await;
+        }
+        if let (Some(path), Some(snapshot_source)) = (&snapshot_path, &snapshot_source) {
+            snapshot::write_now(snapshot_source, path).await;
+        }
+        pool.close().await;
+        read_pool.close().await;
+    };
+    match tokio::time::timeout(SHUTDOWN_FLUSH_TIMEOUT, flush).await {
+        Ok(()) => tracing::info!("Pending writes flushed, database pool closed"),
+        Err(_) => {
+            tracing::warn!(timeout = ?SHUTDOWN_FLUSH_TIMEOUT, "Timed out flushing pending writes")
+        }
+    }
+
+    // Flush the spans still buffered by the batch exporter
+    if let Some(tracer_provider) = tracer_provider {
+        if let Err(e) = tracer_provider.shutdown() {
+            tracing::warn!(error = %e, "Failed to flush OpenTelemetry spans");
+        }
+    }
 
     Ok(())
 }
+
+// Resolves on Ctrl+C or SIGTERM (sent by Docker on `docker stop`)
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    tracing::info!("Shutdown signal received, draining connections...");
+}
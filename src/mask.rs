@@ -0,0 +1,73 @@
+use image::DynamicImage;
+use ndarray::Array;
+use std::path::Path;
+
+pub use crate::api::MaskCheck;
+use crate::embedder::SessionThreads;
+use crate::onnx::{self, ImageModel};
+
+// Face mask classifier exported to ONNX: a [1, 3, H, W] RGB input scaled to
+// 0-1, and per-class logits (or a single mask logit) as output
+pub struct MaskDetector {
+    model: ImageModel,
+    input_width: u32,
+    input_height: u32,
+    // Output class meaning "mask"
+    mask_class: usize,
+    // Search threshold of masked queries; None searches them like any other
+    match_threshold: Option<f32>,
+}
+
+impl MaskDetector {
+    pub fn load(
+        model_path: &Path,
+        mask_class: usize,
+        threads: SessionThreads,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let model = ImageModel::load(model_path, threads)?;
+
+        // Dynamic dimensions fall back to 128x128
+
+        Ok(Self {
+            input_height: model.dim(2, 128),
+            input_width: model.dim(3, 128),
+            mask_class,
+            match_threshold: None,
+            model,
+        })
+    }
+
+    pub fn with_match_threshold(mut self, match_threshold: Option<f32>) -> Self {
+        self.match_threshold = match_threshold;
+        self
+    }
+
+    pub fn match_threshold(&self) -> Option<f32> {
+        self.match_threshold
+    }
+
+    pub fn check(&self, img: &DynamicImage) -> Result<MaskCheck, Box<dyn std::error::Error>> {
+        let resized = img
+            .resize_exact(
+                self.input_width,
+                self.input_height,
+                image::imageops::FilterType::Triangle,
+            )
+            .to_rgb8();
+
+        let mut input = Array::zeros((1, 3, self.input_height as usize, self.input_width as usize));
+        for (x, y, pixel) in resized.enumerate_pixels() {
+            for channel in 0..3 {
+                input[[0, channel, y as usize, x as usize]] = pixel[channel] as f32 / 255.0;
+            }
+        }
+
+        let logits = self.model.run_first(input)?;
+
+        let score = onnx::class_probability(&logits, self.mask_class, "mask")?;
+        Ok(MaskCheck {
+            masked: score >= 0.5,
+            score,
+        })
+    }
+}
@@ -0,0 +1,230 @@
+use axum::{extract::State, http::StatusCode, Json};
+use ndarray::Array2;
+use serde::{Deserialize, Serialize};
+
+use crate::enhance::EnhanceOptions;
+use crate::handlers::{get_embedding_from_base64, get_embeddings_batch};
+use crate::AppState;
+
+// Upper bound on the rows/columns of a single matrix request
+const MAX_SET_SIZE: usize = 1000;
+// Upper bound on the images of a single comparison request
+const MAX_COMPARE_IMAGES: usize = 100;
+
+// One item of a set: a raw embedding or an image to embed first
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum MatrixItem {
+    Embedding(Vec<f32>),
+    Image { image_base64: String },
+}
+
+// Define the request payload for /match/matrix
+#[derive(Deserialize)]
+pub struct MatrixPayload {
+    a: Vec<MatrixItem>,
+    // Omitted: `a` is compared against itself (deduplication)
+    b: Option<Vec<MatrixItem>>,
+    #[serde(default)]
+    enhance: EnhanceOptions,
+}
+
+// Define the response for /match/matrix
+#[derive(Serialize)]
+pub struct MatrixResponse {
+    rows: usize,
+    cols: usize,
+    // similarities[i][j] is the cosine similarity of a[i] and b[j]
+    similarities: Vec<Vec<f32>>,
+}
+
+// Define the request payload for /compare/matrix
+#[derive(Deserialize)]
+pub struct ComparePayload {
+    images_base64: Vec<String>,
+    #[serde(default)]
+    enhance: EnhanceOptions,
+}
+
+// Define the response for /compare/matrix
+#[derive(Serialize)]
+pub struct CompareResponse {
+    size: usize,
+    // similarities[i][j] is the cosine similarity of the largest faces of
+    // images i and j; null when either could not be embedded
+    similarities: Vec<Vec<Option<f32>>>,
+    // Images that could not be embedded (no face, undecodable...)
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    failed: Vec<FailedImage>,
+}
+
+#[derive(Serialize)]
+pub struct FailedImage {
+    index: usize,
+    status: u16,
+}
+
+pub(crate) async fn embed_set(
+    items: Vec<MatrixItem>,
+    state: &AppState,
+    enhance: EnhanceOptions,
+) -> Result<Vec<Vec<f32>>, StatusCode> {
+    let mut embeddings = Vec::with_capacity(items.len());
+    for item in items {
+        embeddings.push(match item {
+            MatrixItem::Embedding(embedding) => embedding,
+            MatrixItem::Image { image_base64 } => {
+                get_embedding_from_base64(&image_base64, state, enhance).await?
+            }
+        });
+    }
+    Ok(embeddings)
+}
+
+// Rows of unit length, so one matrix product yields every cosine similarity
+pub(crate) fn normalized_rows(embeddings: &[Vec<f32>], dimension: usize) -> Array2<f32> {
+    let mut matrix = Array2::<f32>::zeros((embeddings.len(), dimension));
+    for (mut row, embedding) in matrix.rows_mut().into_iter().zip(embeddings) {
+        let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            row.iter_mut()
+                .zip(embedding)
+                .for_each(|(cell, x)| *cell = x / norm);
+        }
+    }
+    matrix
+}
+
+fn similarity_matrix(a: &[Vec<f32>], b: &[Vec<f32>], dimension: usize) -> Vec<Vec<f32>> {
+    let a = normalized_rows(a, dimension);
+    let b = normalized_rows(b, dimension);
+    // ndarray dispatches this to its blocked GEMM kernel
+    a.dot(&b.t()).outer_iter().map(|row| row.to_vec()).collect()
+}
+
+// Handler for POST /match/matrix - full similarity matrix of two sets
+pub async fn match_matrix(
+    State(state): State<AppState>,
+    Json(payload): Json<MatrixPayload>,
+) -> Result<Json<MatrixResponse>, StatusCode> {
+    let b_len = payload.b.as_ref().map_or(payload.a.len(), Vec::len);
+    if payload.a.is_empty() || b_len == 0 {
+        tracing::warn!("Received matrix request with an empty set");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if payload.a.len() > MAX_SET_SIZE || b_len > MAX_SET_SIZE {
+        tracing::warn!(
+            a = payload.a.len(),
+            b = b_len,
+            "Received matrix request over {} items per set",
+            MAX_SET_SIZE
+        );
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    let a = embed_set(payload.a, &state, payload.enhance).await?;
+    let b = match payload.b {
+        Some(b) => Some(embed_set(b, &state, payload.enhance).await?),
+        None => None,
+    };
+
+    let dimension = a[0].len();
+    if dimension == 0
+        || a.iter()
+            .chain(b.iter().flatten())
+            .any(|embedding| embedding.len() != dimension)
+    {
+        tracing::warn!("Received matrix request with mismatched embedding dimensions");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let similarities = tokio::task::spawn_blocking(move || {
+        similarity_matrix(&a, b.as_deref().unwrap_or(&a), dimension)
+    })
+    .await
+    .map_err(|e| {
+        tracing::error!(error = %e, "Similarity matrix task failed");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    tracing::info!(
+        rows = similarities.len(),
+        cols = b_len,
+        "Similarity matrix computed"
+    );
+
+    Ok(Json(MatrixResponse {
+        rows: similarities.len(),
+        cols: b_len,
+        similarities,
+    }))
+}
+
+// Handler for POST /compare/matrix - similarity of every pair of a set of
+// images, for dataset triage and threshold tuning. An image without a usable
+// face leaves its row and column empty instead of failing the request.
+pub async fn compare_matrix(
+    State(state): State<AppState>,
+    Json(payload): Json<ComparePayload>,
+) -> Result<Json<CompareResponse>, StatusCode> {
+    let size = payload.images_base64.len();
+    if size == 0 {
+        tracing::warn!("Received comparison request without images");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if size > MAX_COMPARE_IMAGES {
+        tracing::warn!(
+            size,
+            "Received comparison request over {} images",
+            MAX_COMPARE_IMAGES
+        );
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    let embedded = get_embeddings_batch(&payload.images_base64, &state, payload.enhance).await?;
+
+    let mut failed = Vec::new();
+    let mut embeddings = Vec::with_capacity(size);
+    // Position of each embedded image in `embeddings`
+    let mut positions = Vec::with_capacity(size);
+    for (index, result) in embedded.into_iter().enumerate() {
+        match result {
+            Ok(embedding) => {
+                positions.push(Some(embeddings.len()));
+                embeddings.push(embedding);
+            }
+            Err(status) => {
+                tracing::warn!(index, %status, "Image of comparison could not be embedded");
+                positions.push(None);
+                failed.push(FailedImage {
+                    index,
+                    status: status.as_u16(),
+                });
+            }
+        }
+    }
+
+    let dimension = embeddings.first().map_or(0, Vec::len);
+    let similarities =
+        tokio::task::spawn_blocking(move || similarity_matrix(&embeddings, &embeddings, dimension))
+            .await
+            .map_err(|e| {
+                tracing::error!(error = %e, "Similarity matrix task failed");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+    let similarities = positions
+        .iter()
+        .map(|row| {
+            positions
+                .iter()
+                .map(|col| Some(similarities[(*row)?][(*col)?]))
+                .collect()
+        })
+        .collect();
+    tracing::info!(size, failed = failed.len(), "Comparison matrix computed");
+
+    Ok(Json(CompareResponse {
+        size,
+        similarities,
+        failed,
+    }))
+}
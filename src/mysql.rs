@@ -0,0 +1,315 @@
+use async_trait::async_trait;
+use futures_util::stream::BoxStream;
+use futures_util::StreamExt;
+use sqlx::mysql::{MySqlConnection, MySqlPool};
+use sqlx::Row;
+use uuid::Uuid;
+
+use crate::breaker::WriteError;
+use crate::db::PoolConfig;
+use crate::handlers::RegisterMode;
+use crate::store::{Metadata, NewEmbedding};
+use crate::target_store::{ExpiredRow, NewTargets, StoredTarget, TargetStore};
+
+// Only the targets table: collections, API keys, the audit log and the other
+// tables stay Postgres-only. Vectors are little-endian f32 BLOBs and metadata
+// JSON text, which MySQL and MariaDB store alike.
+const SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS targets (
+    id BIGINT NOT NULL AUTO_INCREMENT PRIMARY KEY,
+    uuid BINARY(16) NOT NULL,
+    embeddings LONGBLOB NOT NULL,
+    origin VARCHAR(255) NOT NULL,
+    metadata LONGTEXT NOT NULL,
+    collection VARCHAR(255) NOT NULL,
+    tenant VARCHAR(255) NOT NULL,
+    image_key TEXT NULL,
+    expires_at DATETIME NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    deleted_at DATETIME NULL,
+    INDEX targets_tenant_collection_uuid_idx (tenant, collection, uuid),
+    INDEX targets_expires_at_idx (expires_at)
+)
+"#;
+
+// The targets table in MySQL or MariaDB; deletions are soft, like in Postgres
+pub struct MySqlTargets {
+    pool: MySqlPool,
+}
+
+impl MySqlTargets {
+    pub async fn connect(url: &str, pool: &PoolConfig) -> Result<Self, sqlx::Error> {
+        // sqlx only knows the mysql:// scheme, MariaDB speaks the same protocol
+        let url = match url.strip_prefix("mariadb://") {
+            Some(rest) => format!("mysql://{}", rest),
+            None => url.to_string(),
+        };
+        let pool = pool.mysql().connect(&url).await?;
+        Ok(Self { pool })
+    }
+
+    // Creates the targets table if it does not exist yet
+    pub async fn ensure_schema(&self) -> Result<(), sqlx::Error> {
+        sqlx::query(SCHEMA).execute(&self.pool).await?;
+        Ok(())
+    }
+}
+
+fn to_blob(embedding: &[f32]) -> Vec<u8> {
+    embedding
+        .iter()
+        .flat_map(|value| value.to_le_bytes())
+        .collect()
+}
+
+fn from_blob(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|value| f32::from_le_bytes([value[0], value[1], value[2], value[3]]))
+        .collect()
+}
+
+// The rows of a registration, in the caller's transaction
+async fn insert_faces(
+    connection: &mut MySqlConnection,
+    targets: &NewTargets<'_>,
+) -> Result<(), sqlx::Error> {
+    let metadata =
+        serde_json::to_string(targets.metadata).map_err(|e| sqlx::Error::Encode(e.into()))?;
+    // The model version is not kept, re-embedding being Postgres only
+    for (target_uuid, embedding, image_key) in &targets.faces {
+        sqlx::query(
+            "INSERT INTO targets (uuid, embeddings, origin, metadata, collection, tenant, image_key, expires_at) VALUES (?, ?, ?, ?, ?, ?, ?, FROM_UNIXTIME(?))",
+        )
+        .bind(target_uuid)
+        .bind(to_blob(embedding))
+        .bind(targets.origin)
+        .bind(&metadata)
+        .bind(targets.collection)
+        .bind(targets.tenant)
+        .bind(image_key)
+        .bind(targets.expires_at)
+        .execute(&mut *connection)
+        .await?;
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl TargetStore for MySqlTargets {
+    async fn insert(
+        &self,
+        targets: &NewTargets<'_>,
+    ) -> Result<Option<Vec<Option<String>>>, WriteError> {
+        let mut transaction = self.pool.begin().await?;
+        let mut replaced_keys: Vec<Option<String>> = Vec::new();
+        // The locking reads hold the uuid's index range until commit, which
+        // serializes the registrations of the uuid like the advisory lock does
+        // in Postgres
+        match targets.mode {
+            RegisterMode::Append => {}
+            RegisterMode::Replace => {
+                replaced_keys = sqlx::query_scalar(
+                    "SELECT image_key FROM targets WHERE tenant = ? AND collection = ? AND uuid = ? FOR UPDATE",
+                )
+                .bind(targets.tenant)
+                .bind(targets.collection)
+                .bind(targets.target_uuid)
+                .fetch_all(&mut *transaction)
+                .await?;
+                sqlx::query("DELETE FROM targets WHERE tenant = ? AND collection = ? AND uuid = ?")
+                    .bind(targets.tenant)
+                    .bind(targets.collection)
+                    .bind(targets.target_uuid)
+                    .execute(&mut *transaction)
+                    .await?;
+            }
+            RegisterMode::RejectIfExists => {
+                let live: i64 = sqlx::query_scalar(
+                    "SELECT COUNT(*) FROM targets WHERE tenant = ? AND collection = ? AND uuid = ? AND deleted_at IS NULL FOR UPDATE",
+                )
+                .bind(targets.tenant)
+                .bind(targets.collection)
+                .bind(targets.target_uuid)
+                .fetch_one(&mut *transaction)
+                .await?;
+                if live > 0 {
+                    return Ok(None);
+                }
+            }
+        }
+        insert_faces(&mut transaction, targets).await?;
+        transaction.commit().await.map_err(WriteError::Commit)?;
+        Ok(Some(replaced_keys))
+    }
+
+    async fn insert_batch(&self, batch: &[NewTargets<'_>]) -> Result<(), WriteError> {
+        let mut transaction = self.pool.begin().await?;
+        for targets in batch {
+            insert_faces(&mut transaction, targets).await?;
+        }
+        transaction.commit().await.map_err(WriteError::Commit)
+    }
+
+    async fn exists(
+        &self,
+        tenant: &str,
+        collection: &str,
+        uuid: Uuid,
+    ) -> Result<bool, sqlx::Error> {
+        let live: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM targets WHERE tenant = ? AND collection = ? AND uuid = ? AND deleted_at IS NULL",
+        )
+        .bind(tenant)
+        .bind(collection)
+        .bind(uuid)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(live > 0)
+    }
+
+    async fn delete(&self, tenant: &str, collection: &str, uuid: Uuid) -> Result<u64, sqlx::Error> {
+        let deleted = sqlx::query(
+            "UPDATE targets SET deleted_at = NOW() WHERE tenant = ? AND collection = ? AND uuid = ? AND deleted_at IS NULL",
+        )
+        .bind(tenant)
+        .bind(collection)
+        .bind(uuid)
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+        Ok(deleted)
+    }
+
+    async fn update(
+        &self,
+        tenant: &str,
+        collection: &str,
+        uuid: Uuid,
+        origin: Option<&str>,
+        metadata: Option<&Metadata>,
+    ) -> Result<u64, sqlx::Error> {
+        let metadata = metadata
+            .map(serde_json::to_string)
+            .transpose()
+            .map_err(|e| sqlx::Error::Encode(e.into()))?;
+        let updated = sqlx::query(
+            "UPDATE targets SET origin = COALESCE(?, origin), metadata = COALESCE(?, metadata) WHERE tenant = ? AND collection = ? AND uuid = ? AND deleted_at IS NULL",
+        )
+        .bind(origin)
+        .bind(metadata)
+        .bind(tenant)
+        .bind(collection)
+        .bind(uuid)
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+        Ok(updated)
+    }
+
+    async fn ping(&self) -> Result<(), sqlx::Error> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    // MySQL has no DELETE ... RETURNING: the rows are locked and read, then
+    // deleted by id in the same transaction
+    async fn delete_expired(
+        &self,
+        retention_secs: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<ExpiredRow>, sqlx::Error> {
+        let mut transaction = self.pool.begin().await?;
+        let rows = sqlx::query(
+            "SELECT id, tenant, collection, uuid, image_key FROM targets WHERE expires_at <= NOW() OR (? IS NOT NULL AND created_at <= NOW() - INTERVAL ? SECOND) LIMIT ? FOR UPDATE SKIP LOCKED",
+        )
+        .bind(retention_secs)
+        .bind(retention_secs)
+        .bind(limit)
+        .fetch_all(&mut *transaction)
+        .await?;
+        if rows.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut ids = Vec::with_capacity(rows.len());
+        let mut expired = Vec::with_capacity(rows.len());
+        for row in rows {
+            ids.push(row.try_get::<i64, _>("id")?);
+            expired.push((
+                row.try_get("tenant")?,
+                row.try_get("collection")?,
+                row.try_get("uuid")?,
+                row.try_get("image_key")?,
+            ));
+        }
+        let placeholders = vec!["?"; ids.len()].join(", ");
+        let query = format!("DELETE FROM targets WHERE id IN ({})", placeholders);
+        let mut delete = sqlx::query(&query);
+        for id in ids {
+            delete = delete.bind(id);
+        }
+        delete.execute(&mut *transaction).await?;
+        transaction.commit().await?;
+        Ok(expired)
+    }
+
+    async fn target(
+        &self,
+        tenant: &str,
+        collection: &str,
+        uuid: Uuid,
+    ) -> Result<Vec<NewEmbedding>, sqlx::Error> {
+        let rows = sqlx::query(
+            "SELECT embeddings, origin, metadata FROM targets WHERE tenant = ? AND collection = ? AND uuid = ? AND deleted_at IS NULL",
+        )
+        .bind(tenant)
+        .bind(collection)
+        .bind(uuid)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.into_iter()
+            .map(|row| {
+                let embeddings: Vec<u8> = row.try_get("embeddings")?;
+                let metadata: String = row.try_get("metadata")?;
+                Ok(NewEmbedding {
+                    uuid,
+                    origin: row.try_get("origin")?,
+                    metadata: serde_json::from_str(&metadata)
+                        .map_err(|e| sqlx::Error::Decode(e.into()))?,
+                    embedding: from_blob(&embeddings),
+                    model_version: None,
+                    expires_at: None,
+                })
+            })
+            .collect()
+    }
+
+    // The cursor is the row id
+    fn stream(&self, after_id: i64) -> BoxStream<'_, Result<StoredTarget, sqlx::Error>> {
+        sqlx::query(
+            "SELECT uuid, embeddings, origin, metadata, collection, tenant FROM targets WHERE id > ? AND deleted_at IS NULL ORDER BY id",
+        )
+        .bind(after_id)
+        .fetch(&self.pool)
+        .map(|record| {
+            let record = record?;
+            let embeddings: Vec<u8> = record.try_get("embeddings")?;
+            let metadata: String = record.try_get("metadata")?;
+            let metadata: Metadata =
+                serde_json::from_str(&metadata).map_err(|e| sqlx::Error::Decode(e.into()))?;
+            Ok(StoredTarget {
+                tenant: record.try_get("tenant")?,
+                collection: record.try_get("collection")?,
+                embedding: NewEmbedding {
+                    uuid: record.try_get("uuid")?,
+                    origin: record.try_get("origin")?,
+                    metadata,
+                    embedding: from_blob(&embeddings),
+                    model_version: None,
+                    expires_at: None,
+                },
+            })
+        })
+        .boxed()
+    }
+}
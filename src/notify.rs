@@ -0,0 +1,155 @@
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgListener;
+use sqlx::PgExecutor;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::collections::CollectionSettings;
+use crate::resync;
+use crate::store::TemplateMode;
+use crate::AppState;
+
+pub const DEFAULT_NOTIFY_CHANNEL: &str = "owlfacerec_changes";
+// Pause before listening again after the listener connection failed
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+// A write to the gallery that other instances have to apply to their stores
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum Change {
+    // Rows of a target were inserted, deleted or relabeled; listeners reload it
+    Target {
+        tenant: String,
+        collection: String,
+        uuid: Uuid,
+    },
+    CollectionCreated {
+        tenant: String,
+        collection: String,
+        threshold: Option<f32>,
+        template_mode: Option<String>,
+    },
+    CollectionDeleted {
+        tenant: String,
+        collection: String,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Notification {
+    // Sender, which has already applied the change itself
+    instance: Uuid,
+    #[serde(flatten)]
+    change: Change,
+}
+
+// Publishes gallery changes on a Postgres NOTIFY channel shared by every
+// instance of the deployment
+pub struct Notifier {
+    channel: String,
+    instance: Uuid,
+}
+
+impl Notifier {
+    pub fn new(channel: String) -> Self {
+        Self {
+            channel,
+            instance: Uuid::new_v4(),
+        }
+    }
+
+    // Run inside the transaction of the change, so the notification is only
+    // delivered once it commits
+    pub async fn publish<'e, E: PgExecutor<'e>>(
+        &self,
+        executor: E,
+        change: Change,
+    ) -> Result<(), sqlx::Error> {
+        let payload = serde_json::to_string(&Notification {
+            instance: self.instance,
+            change,
+        })
+        .map_err(|e| sqlx::Error::Encode(e.into()))?;
+        sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(&self.channel)
+            .bind(payload)
+            .execute(executor)
+            .await?;
+        Ok(())
+    }
+}
+
+async fn apply(state: &AppState, change: Change) -> Result<(), sqlx::Error> {
+    match change {
+        Change::Target {
+            tenant,
+            collection,
+            uuid,
+        } => {
+            resync::reload(state, &(tenant, collection, uuid)).await?;
+        }
+        Change::CollectionCreated {
+            tenant,
+            collection,
+            threshold,
+            template_mode,
+        } => {
+            let settings = CollectionSettings {
+                threshold,
+                template_mode: template_mode.and_then(|mode| mode.parse::<TemplateMode>().ok()),
+            };
+            state.collections.insert(&tenant, &collection, settings);
+        }
+        Change::CollectionDeleted { tenant, collection } => {
+            state.collections.remove(&tenant, &collection);
+        }
+    }
+    Ok(())
+}
+
+// Applies the changes published by the other instances. Notifications sent
+// while the listener is disconnected are lost; RESYNC_INTERVAL_SECS catches
+// the targets they concerned
+pub async fn listen(state: AppState, notifier: Arc<Notifier>) {
+    loop {
+        let mut listener = match PgListener::connect_with(&state.db_pool).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to connect the change listener");
+                tokio::time::sleep(RETRY_DELAY).await;
+                continue;
+            }
+        };
+        if let Err(e) = listener.listen(&notifier.channel).await {
+            tracing::error!(channel = %notifier.channel, error = %e, "Failed to listen for changes");
+            tokio::time::sleep(RETRY_DELAY).await;
+            continue;
+        }
+        tracing::info!(channel = %notifier.channel, "Listening for changes of other instances");
+
+        loop {
+            let notification = match listener.recv().await {
+                Ok(notification) => notification,
+                Err(e) => {
+                    tracing::error!(error = %e, "Change listener failed");
+                    break;
+                }
+            };
+            let notification: Notification = match serde_json::from_str(notification.payload()) {
+                Ok(notification) => notification,
+                Err(e) => {
+                    tracing::warn!(error = %e, "Ignoring malformed change notification");
+                    continue;
+                }
+            };
+            if notification.instance == notifier.instance {
+                continue;
+            }
+            if let Err(e) = apply(&state, notification.change).await {
+                tracing::error!(error = %e, "Failed to apply change of another instance");
+            }
+        }
+        tokio::time::sleep(RETRY_DELAY).await;
+    }
+}
@@ -0,0 +1,153 @@
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::util;
+
+const OBJECT_TIMEOUT: Duration = Duration::from_secs(30);
+const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+
+pub type ObjectError = Box<dyn std::error::Error + Send + Sync>;
+
+// S3-compatible bucket (AWS S3, MinIO...) holding the face crops of
+// enrollments; requests are signed with AWS Signature Version 4 and use
+// path-style URLs, which every S3 implementation accepts
+pub struct ObjectStore {
+    endpoint: String,
+    // Host header as signed, with the port when not the default one
+    host: String,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    client: reqwest::Client,
+}
+
+impl ObjectStore {
+    pub fn new(
+        endpoint: &str,
+        bucket: String,
+        region: String,
+        access_key: String,
+        secret_key: String,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let endpoint = endpoint.trim_end_matches('/').to_string();
+        let url = reqwest::Url::parse(&endpoint)?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err(format!("no host in S3 endpoint {}", endpoint).into()),
+        };
+        Ok(Self {
+            endpoint,
+            host,
+            bucket,
+            region,
+            access_key,
+            secret_key,
+            client: reqwest::Client::builder().timeout(OBJECT_TIMEOUT).build()?,
+        })
+    }
+
+    // Key of a new crop of a target; every enrollment gets its own object
+    pub fn crop_key(tenant: &str, collection: &str, target_uuid: Uuid) -> String {
+        format!(
+            "{}/{}/{}/{}.png",
+            tenant,
+            collection,
+            target_uuid,
+            Uuid::new_v4()
+        )
+    }
+
+    pub async fn put(&self, key: &str, body: Vec<u8>) -> Result<(), ObjectError> {
+        self.send(reqwest::Method::PUT, key, body).await?;
+        Ok(())
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Vec<u8>, ObjectError> {
+        let response = self.send(reqwest::Method::GET, key, Vec::new()).await?;
+        Ok(response.bytes().await?.to_vec())
+    }
+
+    pub async fn delete(&self, key: &str) -> Result<(), ObjectError> {
+        self.send(reqwest::Method::DELETE, key, Vec::new()).await?;
+        Ok(())
+    }
+
+    async fn send(
+        &self,
+        method: reqwest::Method,
+        key: &str,
+        body: Vec<u8>,
+    ) -> Result<reqwest::Response, ObjectError> {
+        let path = format!("/{}/{}", uri_encode(&self.bucket), uri_encode(key));
+        // 2024-05-01T12:00:00Z -> 20240501T120000Z
+        let timestamp: String = util::format_rfc3339(util::unix_now())
+            .chars()
+            .filter(|c| *c != '-' && *c != ':')
+            .collect();
+        let date = &timestamp[..8];
+        let payload_hash = hex::encode(Sha256::digest(&body));
+
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, path, self.host, payload_hash, timestamp, SIGNED_HEADERS, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            timestamp,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let mut signing_key = format!("AWS4{}", self.secret_key).into_bytes();
+        for part in [date, self.region.as_str(), "s3", "aws4_request"] {
+            signing_key = hmac_sha256(&signing_key, part.as_bytes());
+        }
+        let signature = hex::encode(hmac_sha256(&signing_key, string_to_sign.as_bytes()));
+
+        let response = self
+            .client
+            .request(method, format!("{}{}", self.endpoint, path))
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", timestamp)
+            .header(
+                "authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                    self.access_key, scope, SIGNED_HEADERS, signature
+                ),
+            )
+            .body(body)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(
+                format!("object storage answered {} for {}", response.status(), key).into(),
+            );
+        }
+        Ok(response)
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+// Percent-encoding of the SigV4 canonical URI; '/' separates key segments
+fn uri_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
@@ -0,0 +1,126 @@
+use ndarray::{Array, Ix4};
+#[cfg(feature = "onnx")]
+use ort::{inputs, session::Session, value::Value};
+use std::path::Path;
+
+use crate::embedder::SessionThreads;
+
+// ONNX model with a single [1, C, H, W] image input, as the detector and the
+// per-face models (liveness, pose, mask, attributes...) all are. Builds
+// without the `onnx` feature refuse to load them.
+#[cfg_attr(not(feature = "onnx"), allow(dead_code))]
+pub struct ImageModel {
+    #[cfg(feature = "onnx")]
+    session: Session,
+    // Input dimensions as declared, dynamic ones negative
+    dims: Vec<i64>,
+}
+
+// An output of the model, flattened in row-major order
+pub struct Output {
+    pub shape: Vec<usize>,
+    pub values: Vec<f32>,
+}
+
+impl ImageModel {
+    #[cfg(feature = "onnx")]
+    pub fn load(
+        model_path: &Path,
+        threads: SessionThreads,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let session = threads.builder()?.commit_from_file(model_path)?;
+        let dims = session
+            .inputs
+            .first()
+            .and_then(|input| input.input_type.tensor_dimensions())
+            .cloned()
+            .unwrap_or_default();
+        Ok(Self { session, dims })
+    }
+
+    #[cfg(not(feature = "onnx"))]
+    pub fn load(
+        _model_path: &Path,
+        _threads: SessionThreads,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Err("ONNX models need the onnx feature".into())
+    }
+
+    // Input dimension `i`, `default` when it is dynamic
+    pub fn dim(&self, i: usize, default: u32) -> u32 {
+        self.dims
+            .get(i)
+            .copied()
+            .filter(|&d| d > 0)
+            .map_or(default, |d| d as u32)
+    }
+
+    #[cfg(feature = "onnx")]
+    pub fn output_count(&self) -> usize {
+        self.session.outputs.len()
+    }
+
+    #[cfg(not(feature = "onnx"))]
+    pub fn output_count(&self) -> usize {
+        0
+    }
+
+    #[cfg(feature = "onnx")]
+    pub fn run(&self, input: Array<f32, Ix4>) -> Result<Vec<Output>, Box<dyn std::error::Error>> {
+        let shape: Vec<usize> = input.shape().to_vec();
+        let input_value = Value::from_array((shape, input.into_raw_vec()))?;
+        let outputs = self.session.run(inputs![input_value]?)?;
+        let mut extracted = Vec::with_capacity(outputs.len());
+        for index in 0..outputs.len() {
+            let tensor = outputs[index].try_extract_tensor::<f32>()?;
+            extracted.push(Output {
+                shape: tensor.shape().to_vec(),
+                values: tensor.iter().copied().collect(),
+            });
+        }
+        Ok(extracted)
+    }
+
+    #[cfg(not(feature = "onnx"))]
+    pub fn run(&self, _input: Array<f32, Ix4>) -> Result<Vec<Output>, Box<dyn std::error::Error>> {
+        Err("ONNX models need the onnx feature".into())
+    }
+
+    // The first output, flattened
+    pub fn run_first(
+        &self,
+        input: Array<f32, Ix4>,
+    ) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
+        self.run(input)?
+            .into_iter()
+            .next()
+            .map(|output| output.values)
+            .ok_or_else(|| "model without outputs".into())
+    }
+}
+
+// Probabilities of logits, shifted by the largest one to stay finite
+pub fn softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let exps: Vec<f32> = logits.iter().map(|logit| (logit - max).exp()).collect();
+    let sum: f32 = exps.iter().sum();
+    exps.into_iter().map(|exp| exp / sum).collect()
+}
+
+pub fn sigmoid(logit: f32) -> f32 {
+    1.0 / (1.0 + (-logit).exp())
+}
+
+// Probability of `class` from the output of a classifier: per-class logits,
+// or a single logit of that class before the sigmoid
+pub fn class_probability(logits: &[f32], class: usize, what: &str) -> Result<f32, String> {
+    match logits.len() {
+        0 => Err(format!("empty {} output", what)),
+        1 => Ok(sigmoid(logits[0])),
+        classes if class < classes => Ok(softmax(logits)[class]),
+        classes => Err(format!(
+            "{} class {} out of range for {} {} classes",
+            what, class, classes, what
+        )),
+    }
+}
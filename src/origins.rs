@@ -0,0 +1,116 @@
+use axum::{extract::State, http::StatusCode, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::Row;
+use std::collections::HashSet;
+use uuid::Uuid;
+
+use crate::handlers;
+use crate::notify::Change;
+use crate::tenant::DEFAULT_TENANT;
+use crate::AppState;
+
+// Define the request payload for /admin/origins/rename
+#[derive(Deserialize)]
+pub struct RenameOriginPayload {
+    #[serde(default)]
+    tenant: Option<String>,
+    from: String,
+    // An origin that already has registrations gets those of `from` merged in
+    to: String,
+}
+
+// Define the response for /admin/origins/rename
+#[derive(Serialize)]
+pub struct RenameOriginResponse {
+    tenant: String,
+    from: String,
+    to: String,
+    // Database rows relabeled, deleted ones included
+    rows: u64,
+    // Whether `to` already had registrations
+    merged: bool,
+}
+
+// Handler for POST /admin/origins/rename - renames an origin, or merges it
+// into another, in every collection of a tenant
+pub async fn rename_origin(
+    State(state): State<AppState>,
+    Json(payload): Json<RenameOriginPayload>,
+) -> Result<Json<RenameOriginResponse>, StatusCode> {
+    let tenant = payload.tenant.as_deref().unwrap_or(DEFAULT_TENANT);
+    let (from, to) = (payload.from.trim(), payload.to.trim());
+    if from.is_empty() || to.is_empty() || from == to {
+        tracing::warn!(%from, %to, "Received origin rename with an empty or unchanged origin");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let merged = state.collections.origin_count(tenant, to) > 0;
+
+    let (rows, changed) = relabel(&state, tenant, from, to).await.map_err(|e| {
+        tracing::error!(%from, %to, error = %e, "Failed to rename origin in database");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    if rows == 0 {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let mut entries = 0;
+    for (name, collection) in state.collections.list(tenant) {
+        entries += collection.store.rename_origin(from, to).await;
+        handlers::invalidate_cached_searches(&state, tenant, &name).await;
+    }
+    tracing::info!(%tenant, %from, %to, rows, entries, targets = changed, merged, "Origin renamed");
+
+    Ok(Json(RenameOriginResponse {
+        tenant: tenant.to_string(),
+        from: from.to_string(),
+        to: to.to_string(),
+        rows,
+        merged,
+    }))
+}
+
+// Relabels the rows of the origin in one transaction and notifies the other
+// instances of every live target touched; returns the rows and the targets
+async fn relabel(
+    state: &AppState,
+    tenant: &str,
+    from: &str,
+    to: &str,
+) -> Result<(u64, usize), sqlx::Error> {
+    // Rows still queued for the database would keep the old origin
+    if let Some(write_behind) = &state.write_behind {
+        write_behind.settle_all().await;
+    }
+    let mut transaction = state.db_pool.begin().await?;
+    // A new id, so a snapshot holding the rows under their old origin is
+    // recognized as stale
+    let rows = sqlx::query(
+        "UPDATE targets SET origin = $1, id = nextval(pg_get_serial_sequence('targets', 'id')) WHERE tenant = $2 AND origin = $3 RETURNING collection, uuid, deleted_at IS NULL AS live",
+    )
+    .bind(to)
+    .bind(tenant)
+    .bind(from)
+    .fetch_all(&mut *transaction)
+    .await?;
+    let targets: HashSet<(String, Uuid)> = rows
+        .iter()
+        .filter(|row| row.get::<bool, _>("live"))
+        .map(|row| (row.get("collection"), row.get("uuid")))
+        .collect();
+    if let Some(notifier) = &state.notifier {
+        for (collection, uuid) in &targets {
+            notifier
+                .publish(
+                    &mut *transaction,
+                    Change::Target {
+                        tenant: tenant.to_string(),
+                        collection: collection.clone(),
+                        uuid: *uuid,
+                    },
+                )
+                .await?;
+        }
+    }
+    transaction.commit().await?;
+    Ok((rows.len() as u64, targets.len()))
+}
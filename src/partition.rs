@@ -0,0 +1,299 @@
+use serde::Serialize;
+use sqlx::PgPool;
+use std::time::{Duration, Instant};
+
+// Months of partitions created ahead of the current one, so new rows never
+// land in the default partition
+const MONTHS_AHEAD: u32 = 2;
+pub const DEFAULT_MAINTENANCE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+type PartitionError = Box<dyn std::error::Error + Send + Sync>;
+
+// How the targets table is split
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum PartitionScheme {
+    // One partition per registration month (created_at)
+    Month,
+    // One partition per origin
+    Origin,
+}
+
+impl PartitionScheme {
+    fn key(self) -> &'static str {
+        match self {
+            PartitionScheme::Month => "created_at",
+            PartitionScheme::Origin => "origin",
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct PartitionSummary {
+    scheme: PartitionScheme,
+    partitions: usize,
+    rows: i64,
+}
+
+// Scheme the targets table is partitioned with, None when it is a plain table
+pub async fn current_scheme(pool: &PgPool) -> Result<Option<PartitionScheme>, sqlx::Error> {
+    let definition: Option<String> = sqlx::query_scalar(
+        "SELECT pg_get_partkeydef(partrelid) FROM pg_partitioned_table WHERE partrelid = 'targets'::regclass",
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(definition.and_then(|definition| {
+        if definition.contains("created_at") {
+            Some(PartitionScheme::Month)
+        } else if definition.contains("origin") {
+            Some(PartitionScheme::Origin)
+        } else {
+            None
+        }
+    }))
+}
+
+// Partition name of a month, e.g. targets_y2024m05
+fn month_name(year: i32, month: u32) -> String {
+    format!("targets_y{:04}m{:02}", year, month)
+}
+
+fn next_month(year: i32, month: u32) -> (i32, u32) {
+    if month == 12 {
+        (year + 1, 1)
+    } else {
+        (year, month + 1)
+    }
+}
+
+// SQL string literal of a value
+fn literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+// Bounds of a month partition, in UTC
+fn month_bounds(year: i32, month: u32) -> (String, String) {
+    let (next_year, next) = next_month(year, month);
+    (
+        format!("'{:04}-{:02}-01 00:00:00+00'", year, month),
+        format!("'{:04}-{:02}-01 00:00:00+00'", next_year, next),
+    )
+}
+
+// Creates a partition for the rows matching `filter`, moving those already in
+// the default partition into it first: a partition cannot be attached over
+// rows of the default one. Inserts wait until it commits.
+async fn split_default(
+    pool: &PgPool,
+    name: &str,
+    filter: &str,
+    bounds: &str,
+) -> Result<(), sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    for statement in [
+        "LOCK TABLE targets IN SHARE ROW EXCLUSIVE MODE".to_string(),
+        format!(
+            "CREATE TABLE {} (LIKE targets INCLUDING DEFAULTS INCLUDING CONSTRAINTS)",
+            name
+        ),
+        format!(
+            "WITH moved AS (DELETE FROM targets_default WHERE {} RETURNING *) INSERT INTO {} SELECT * FROM moved",
+            filter, name
+        ),
+        format!("ALTER TABLE targets ATTACH PARTITION {} {}", name, bounds),
+    ] {
+        sqlx::query(&statement).execute(&mut *transaction).await?;
+    }
+    transaction.commit().await
+}
+
+// Year and month of now plus `ahead` months, in UTC as Postgres sees it
+async fn month_of_now(
+    executor: impl sqlx::PgExecutor<'_>,
+    ahead: u32,
+) -> Result<(i32, u32), sqlx::Error> {
+    let (year, month): (f64, f64) = sqlx::query_as(&format!(
+        "SELECT EXTRACT(YEAR FROM now() AT TIME ZONE 'UTC' + interval '{0} months'), EXTRACT(MONTH FROM now() AT TIME ZONE 'UTC' + interval '{0} months')",
+        ahead
+    ))
+    .fetch_one(executor)
+    .await?;
+    Ok((year as i32, month as u32))
+}
+
+// Turns the targets table into a partitioned one in a single transaction:
+// the rows are copied into the new table, the old one dropped, and the ids
+// keep their sequence so snapshots stay valid. Every other access to the
+// table waits until it commits.
+pub async fn partition_targets(
+    pool: &PgPool,
+    scheme: PartitionScheme,
+) -> Result<PartitionSummary, PartitionError> {
+    if let Some(current) = current_scheme(pool).await? {
+        return Err(format!("targets is already partitioned by {:?}", current).into());
+    }
+    let start = Instant::now();
+    let mut transaction = pool.begin().await?;
+    sqlx::query("LOCK TABLE targets IN ACCESS EXCLUSIVE MODE")
+        .execute(&mut *transaction)
+        .await?;
+    let sequence: String = sqlx::query_scalar("SELECT pg_get_serial_sequence('targets', 'id')")
+        .fetch_one(&mut *transaction)
+        .await?;
+    for statement in [
+        format!("ALTER SEQUENCE {} OWNED BY NONE", sequence),
+        "ALTER TABLE targets RENAME TO targets_unpartitioned".to_string(),
+        // Check constraints come along; indexes are created again below, once
+        // the old ones are gone with their names
+        format!(
+            "CREATE TABLE targets (LIKE targets_unpartitioned INCLUDING DEFAULTS INCLUDING CONSTRAINTS) PARTITION BY {} ({})",
+            match scheme {
+                PartitionScheme::Month => "RANGE",
+                PartitionScheme::Origin => "LIST",
+            },
+            scheme.key()
+        ),
+        "CREATE TABLE targets_default PARTITION OF targets DEFAULT".to_string(),
+    ] {
+        sqlx::query(&statement).execute(&mut *transaction).await?;
+    }
+
+    let mut partitions = 1;
+    match scheme {
+        PartitionScheme::Month => {
+            let first: Option<(f64, f64)> = sqlx::query_as(
+                "SELECT EXTRACT(YEAR FROM MIN(created_at) AT TIME ZONE 'UTC'), EXTRACT(MONTH FROM MIN(created_at) AT TIME ZONE 'UTC') FROM targets_unpartitioned HAVING COUNT(*) > 0",
+            )
+            .fetch_optional(&mut *transaction)
+            .await?;
+            let last = month_of_now(&mut *transaction, MONTHS_AHEAD).await?;
+            let (mut year, mut month) = match first {
+                Some((year, month)) => (year as i32, month as u32),
+                None => month_of_now(&mut *transaction, 0).await?,
+            };
+            while (year, month) <= last {
+                let (from, to) = month_bounds(year, month);
+                sqlx::query(&format!(
+                    "CREATE TABLE {} PARTITION OF targets FOR VALUES FROM ({}) TO ({})",
+                    month_name(year, month),
+                    from,
+                    to
+                ))
+                .execute(&mut *transaction)
+                .await?;
+                partitions += 1;
+                (year, month) = next_month(year, month);
+            }
+        }
+        PartitionScheme::Origin => {
+            let origins: Vec<String> = sqlx::query_scalar(
+                "SELECT DISTINCT origin FROM targets_unpartitioned ORDER BY origin",
+            )
+            .fetch_all(&mut *transaction)
+            .await?;
+            // Numbered: origins are free text, not identifiers
+            for (index, origin) in origins.iter().enumerate() {
+                sqlx::query(&format!(
+                    "CREATE TABLE targets_origin_{} PARTITION OF targets FOR VALUES IN ({})",
+                    index + 1,
+                    literal(origin)
+                ))
+                .execute(&mut *transaction)
+                .await?;
+                partitions += 1;
+            }
+        }
+    }
+
+    let rows = sqlx::query("INSERT INTO targets SELECT * FROM targets_unpartitioned")
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected() as i64;
+    for statement in [
+        "DROP TABLE targets_unpartitioned".to_string(),
+        // The partition key must be part of the primary key; ids stay unique
+        // through the sequence
+        format!("ALTER TABLE targets ADD PRIMARY KEY (id, {})", scheme.key()),
+        "CREATE INDEX targets_tenant_collection_uuid_idx ON targets (tenant, collection, uuid)"
+            .to_string(),
+        "CREATE INDEX targets_tenant_origin_idx ON targets (tenant, origin)".to_string(),
+        "CREATE INDEX targets_deleted_at_idx ON targets (deleted_at) WHERE deleted_at IS NOT NULL"
+            .to_string(),
+        "CREATE INDEX targets_created_at_idx ON targets (created_at)".to_string(),
+        "CREATE INDEX targets_expires_at_idx ON targets (expires_at) WHERE expires_at IS NOT NULL"
+            .to_string(),
+        format!("ALTER SEQUENCE {} OWNED BY targets.id", sequence),
+    ] {
+        sqlx::query(&statement).execute(&mut *transaction).await?;
+    }
+    transaction.commit().await?;
+    tracing::info!(?scheme, partitions, rows, duration = ?start.elapsed(), "Targets table partitioned");
+
+    Ok(PartitionSummary {
+        scheme,
+        partitions,
+        rows,
+    })
+}
+
+// Creates the partitions of the coming months (month scheme) or of the
+// origins that only live in the default partition so far (origin scheme)
+pub async fn maintain(pool: &PgPool) -> Result<usize, PartitionError> {
+    let mut created = 0;
+    match current_scheme(pool).await? {
+        None => {}
+        Some(PartitionScheme::Month) => {
+            let (mut year, mut month) = month_of_now(pool, 0).await?;
+            let last = month_of_now(pool, MONTHS_AHEAD).await?;
+            while (year, month) <= last {
+                let name = month_name(year, month);
+                let exists: bool = sqlx::query_scalar("SELECT to_regclass($1) IS NOT NULL")
+                    .bind(&name)
+                    .fetch_one(pool)
+                    .await?;
+                if !exists {
+                    let (from, to) = month_bounds(year, month);
+                    let filter = format!("created_at >= {} AND created_at < {}", from, to);
+                    let bounds = format!("FOR VALUES FROM ({}) TO ({})", from, to);
+                    split_default(pool, &name, &filter, &bounds).await?;
+                    tracing::info!(partition = %name, "Targets partition created");
+                    created += 1;
+                }
+                (year, month) = next_month(year, month);
+            }
+        }
+        Some(PartitionScheme::Origin) => {
+            let origins: Vec<String> =
+                sqlx::query_scalar("SELECT DISTINCT origin FROM targets_default")
+                    .fetch_all(pool)
+                    .await?;
+            for origin in origins {
+                // Numbered: origins are free text, not identifiers
+                let number: i32 = sqlx::query_scalar(
+                    "SELECT COALESCE(MAX(substring(relname FROM '^targets_origin_([0-9]+)$')::int), 0) + 1 FROM pg_class",
+                )
+                .fetch_one(pool)
+                .await?;
+                let name = format!("targets_origin_{}", number);
+                let filter = format!("origin = {}", literal(&origin));
+                let bounds = format!("FOR VALUES IN ({})", literal(&origin));
+                split_default(pool, &name, &filter, &bounds).await?;
+                tracing::info!(partition = %name, %origin, "Targets partition created");
+                created += 1;
+            }
+        }
+    }
+    Ok(created)
+}
+
+// Keeps the partitions ahead of the rows every `interval`
+pub async fn run(pool: PgPool, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = maintain(&pool).await {
+            tracing::error!(error = %e, "Failed to maintain the targets partitions");
+        }
+    }
+}
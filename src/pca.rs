@@ -0,0 +1,227 @@
+use ndarray::{Array1, Array2, Axis};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::collections::Collections;
+use crate::store::Vector;
+
+pub const DEFAULT_TRAIN_INTERVAL: Duration = Duration::from_secs(60);
+pub const DEFAULT_RESCORE_MARGIN: f32 = 0.05;
+// The projection is only fitted once there are this many vectors per kept
+// dimension, fewer give noisy components
+pub const MIN_POINTS_PER_DIMENSION: usize = 16;
+// Vectors sampled to fit on; more barely moves the components
+const SAMPLE_POINTS: usize = 20_000;
+// Fitting runs again once the gallery grew by this factor
+pub const RETRAIN_GROWTH: usize = 2;
+// Entries re-scored exactly per shard, as a multiple of the search limit
+pub const RESCORE_FACTOR: usize = 8;
+const POWER_ITERATIONS: usize = 30;
+const PCA_SEED: u64 = 0x7063_615f_7365_6564;
+
+// Reduction of the stores
+#[derive(Clone, Copy, Debug)]
+pub struct PcaConfig {
+    // Length of the reduced vectors, e.g. 128 for a 512-dimensional model
+    pub dimensions: usize,
+    // Entries whose approximate similarity is at least the threshold minus
+    // this are re-scored exactly
+    pub margin: f32,
+}
+
+// Mean and principal components of the gallery vectors
+pub struct Projection {
+    mean: Array1<f32>,
+    // Squared length of the mean
+    mean_norm: f32,
+    // One unit-length component per row, the strongest first
+    components: Array2<f32>,
+}
+
+impl Projection {
+    // Top `dimensions` principal components of `sample`, by subspace
+    // iteration on its covariance, seeded so refitting on the same vectors
+    // gives the same projection
+    pub fn fit(sample: &[Vec<f32>], dimensions: usize) -> Self {
+        let dimension = sample[0].len();
+        let mut points = Array2::<f32>::zeros((sample.len(), dimension));
+        for (mut row, vector) in points.rows_mut().into_iter().zip(sample) {
+            row.iter_mut().zip(vector).for_each(|(cell, x)| *cell = *x);
+        }
+        let mean = points
+            .mean_axis(Axis(0))
+            .unwrap_or_else(|| Array1::zeros(dimension));
+        points -= &mean;
+        let covariance = points.t().dot(&points) / sample.len() as f32;
+
+        let mut rng = StdRng::seed_from_u64(PCA_SEED);
+        let mut basis =
+            Array2::<f32>::from_shape_fn((dimension, dimensions), |_| rng.gen_range(-1.0..1.0));
+        orthonormalize(&mut basis);
+        for _ in 0..POWER_ITERATIONS {
+            basis = covariance.dot(&basis);
+            orthonormalize(&mut basis);
+        }
+        Self {
+            mean_norm: mean.dot(&mean),
+            mean,
+            components: basis.reversed_axes(),
+        }
+    }
+
+    pub fn dimensions(&self) -> usize {
+        self.components.nrows()
+    }
+
+    pub fn reduce(&self, vector: &[f32]) -> Reduced {
+        let vector = Array1::from_vec(vector.to_vec());
+        let centered = &vector - &self.mean;
+        Reduced {
+            coordinates: self.components.dot(&centered).to_vec(),
+            offset: vector.dot(&self.mean) - self.mean_norm / 2.0,
+            norm: vector.dot(&vector).sqrt(),
+        }
+    }
+}
+
+// Gram-Schmidt on the columns; a column that vanishes restarts at zero and
+// only costs a weaker component
+fn orthonormalize(basis: &mut Array2<f32>) {
+    for j in 0..basis.ncols() {
+        for i in 0..j {
+            let previous = basis.column(i).to_owned();
+            let overlap = previous.dot(&basis.column(j));
+            basis.column_mut(j).scaled_add(-overlap, &previous);
+        }
+        let norm = basis.column(j).dot(&basis.column(j)).sqrt();
+        if norm > f32::EPSILON {
+            basis.column_mut(j).mapv_inplace(|x| x / norm);
+        }
+    }
+}
+
+// A vector as scanned by the searches. q·x equals (q-m)·(x-m) + q·m + x·m - m·m
+// for the mean m: the first term is approximated in the reduced space, the
+// others are exact and kept as `offset`.
+#[derive(Clone)]
+pub struct Reduced {
+    coordinates: Vec<f32>,
+    // Projection on the mean, minus half the squared mean
+    offset: f32,
+    // Length of the full vector
+    norm: f32,
+}
+
+impl Reduced {
+    // Approximate cosine similarity of the full vectors
+    pub fn similarity(&self, other: &Reduced) -> f32 {
+        let dot: f32 = self
+            .coordinates
+            .iter()
+            .zip(&other.coordinates)
+            .map(|(a, b)| a * b)
+            .sum();
+        (dot + self.offset + other.offset) / (self.norm * other.norm).max(f32::EPSILON)
+    }
+}
+
+// Reduced vectors of the entries of a shard, by position
+#[derive(Clone)]
+pub struct ReducedVectors {
+    pub projection: Arc<Projection>,
+    rows: Vec<Vec<Reduced>>,
+}
+
+impl ReducedVectors {
+    pub fn new(projection: Arc<Projection>) -> Self {
+        Self {
+            projection,
+            rows: Vec::new(),
+        }
+    }
+
+    // Reduces the vectors of the entry at `position`, replacing older ones
+    pub fn set(&mut self, position: usize, vectors: &[Vector]) {
+        if self.rows.len() <= position {
+            self.rows.resize(position + 1, Vec::new());
+        }
+        self.rows[position] = vectors
+            .iter()
+            .map(|vector| self.projection.reduce(&vector.to_f32()))
+            .collect();
+    }
+
+    pub fn clear(&mut self, position: usize) {
+        if let Some(row) = self.rows.get_mut(position) {
+            *row = Vec::new();
+        }
+    }
+
+    // Moves every position through `moved`, dropping those it maps to None
+    pub fn remap(&mut self, moved: impl Fn(u32) -> Option<u32>) {
+        let mut rows = Vec::with_capacity(self.rows.len());
+        for (position, row) in std::mem::take(&mut self.rows).into_iter().enumerate() {
+            if let Some(position) = moved(position as u32) {
+                let position = position as usize;
+                if rows.len() <= position {
+                    rows.resize(position + 1, Vec::new());
+                }
+                rows[position] = row;
+            }
+        }
+        self.rows = rows;
+    }
+
+    // Best approximate similarity of the entry at `position`, None when it
+    // has no reduced vector
+    pub fn score(&self, position: usize, query: &Reduced) -> Option<f32> {
+        self.rows
+            .get(position)
+            .filter(|row| !row.is_empty())
+            .map(|row| {
+                row.iter()
+                    .map(|reduced| reduced.similarity(query))
+                    .fold(f32::MIN, f32::max)
+            })
+    }
+}
+
+// Vectors sampled to fit the projection on
+pub fn sample_size() -> usize {
+    SAMPLE_POINTS
+}
+
+// Fits the projection of every store that is large enough or outgrew its
+// fitting, one at a time so searches keep most of the CPU
+pub async fn run(collections: Arc<Collections>, interval: Duration) {
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        for (tenant, name, collection) in collections.all() {
+            if !collection.store.needs_pca_training() {
+                continue;
+            }
+            let start = Instant::now();
+            let trained = tokio::task::spawn_blocking({
+                let collection = collection.clone();
+                move || collection.store.train_pca()
+            })
+            .await;
+            match trained {
+                Ok(Some(vectors)) => tracing::info!(
+                    %tenant,
+                    collection = %name,
+                    vectors,
+                    duration = ?start.elapsed(),
+                    "PCA projection fitted"
+                ),
+                Ok(None) => {}
+                Err(e) => {
+                    tracing::error!(%tenant, collection = %name, error = %e, "PCA fitting failed")
+                }
+            }
+        }
+    }
+}
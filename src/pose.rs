@@ -0,0 +1,142 @@
+use image::DynamicImage;
+use ndarray::Array;
+use std::path::Path;
+use std::str::FromStr;
+
+pub use crate::api::Pose;
+use crate::embedder::SessionThreads;
+use crate::onnx::ImageModel;
+use crate::util;
+
+const MEAN: [f32; 3] = [0.485, 0.456, 0.406];
+const STD: [f32; 3] = [0.229, 0.224, 0.225];
+
+// Largest angles (absolute, in degrees) of a usable face; unset axes are not limited
+#[derive(Clone, Debug, Default)]
+pub struct PoseLimits {
+    max_yaw: Option<f32>,
+    max_pitch: Option<f32>,
+    max_roll: Option<f32>,
+}
+
+impl PoseLimits {
+    pub fn is_exceeded(&self, pose: &Pose) -> bool {
+        let beyond = |max: Option<f32>, angle: f32| max.is_some_and(|max| angle.abs() > max);
+        beyond(self.max_yaw, pose.yaw)
+            || beyond(self.max_pitch, pose.pitch)
+            || beyond(self.max_roll, pose.roll)
+    }
+}
+
+// Parses "axis=degrees" pairs separated by commas, e.g. "yaw=45,pitch=30,roll=40"
+impl FromStr for PoseLimits {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut limits = PoseLimits::default();
+        for (axis, degrees) in
+            util::parse_key_values::<f32>(s).map_err(|e| format!("invalid pose limits: {}", e))?
+        {
+            let field = match axis.as_str() {
+                "yaw" => &mut limits.max_yaw,
+                "pitch" => &mut limits.max_pitch,
+                "roll" => &mut limits.max_roll,
+                _ => return Err(format!("unknown pose axis '{}'", axis)),
+            };
+            *field = Some(degrees);
+        }
+        Ok(limits)
+    }
+}
+
+pub const DEFAULT_DOWN_WEIGHT: f32 = 0.8;
+
+// What happens to a search whose face is beyond the limits; enrollments beyond
+// them are always rejected (see `check_enrollment`)
+#[derive(Clone, Copy, Debug)]
+pub enum PoseMode {
+    Reject,
+    // Similarities are multiplied by this weight before thresholding
+    DownWeight(f32),
+}
+
+impl FromStr for PoseMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "reject" => Ok(PoseMode::Reject),
+            "down_weight" => Ok(PoseMode::DownWeight(DEFAULT_DOWN_WEIGHT)),
+            other => Err(format!("invalid pose mode '{}'", other)),
+        }
+    }
+}
+
+// Head pose regressor exported to ONNX (e.g. 6DRepNet or WHENet with an
+// angle head): a [1, 3, H, W] RGB input normalized with the ImageNet mean and
+// std, and [yaw, pitch, roll] in degrees as output
+pub struct PoseEstimator {
+    model: ImageModel,
+    input_width: u32,
+    input_height: u32,
+    limits: PoseLimits,
+    mode: PoseMode,
+}
+
+impl PoseEstimator {
+    pub fn load(
+        model_path: &Path,
+        threads: SessionThreads,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let model = ImageModel::load(model_path, threads)?;
+
+        // Dynamic dimensions fall back to 224x224
+
+        Ok(Self {
+            input_height: model.dim(2, 224),
+            input_width: model.dim(3, 224),
+            limits: PoseLimits::default(),
+            mode: PoseMode::Reject,
+            model,
+        })
+    }
+
+    pub fn with_policy(mut self, limits: PoseLimits, mode: PoseMode) -> Self {
+        self.limits = limits;
+        self.mode = mode;
+        self
+    }
+
+    pub fn limits(&self) -> &PoseLimits {
+        &self.limits
+    }
+
+    pub fn mode(&self) -> PoseMode {
+        self.mode
+    }
+
+    pub fn estimate(&self, img: &DynamicImage) -> Result<Pose, Box<dyn std::error::Error>> {
+        let resized = img
+            .resize_exact(
+                self.input_width,
+                self.input_height,
+                image::imageops::FilterType::Triangle,
+            )
+            .to_rgb8();
+
+        let mut input = Array::zeros((1, 3, self.input_height as usize, self.input_width as usize));
+        for (x, y, pixel) in resized.enumerate_pixels() {
+            for channel in 0..3 {
+                input[[0, channel, y as usize, x as usize]] =
+                    (pixel[channel] as f32 / 255.0 - MEAN[channel]) / STD[channel];
+            }
+        }
+
+        let angles = self.model.run_first(input)?;
+
+        match angles[..] {
+            [yaw, pitch, roll] => Ok(Pose { yaw, pitch, roll }),
+            _ => Err(format!("expected 3 pose angles, got {}", angles.len()).into()),
+        }
+    }
+}
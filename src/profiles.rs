@@ -0,0 +1,59 @@
+use std::path::Path;
+
+use crate::config::Settings;
+use crate::events;
+use crate::handlers;
+
+// Defaults of the thresholds for one embedding model. Similarities are not on
+// the same scale across models, so the defaults of one model do not carry
+// over to another.
+#[derive(Clone, Copy, Debug)]
+pub struct ModelProfile {
+    pub search_threshold: f32,
+    pub alert_threshold: f32,
+}
+
+// Models without a built-in profile
+pub const FALLBACK: ModelProfile = ModelProfile {
+    search_threshold: handlers::DEFAULT_THRESHOLD,
+    alert_threshold: events::DEFAULT_ALERT_THRESHOLD,
+};
+
+// Name of a model as profiles and exports know it: the file stem of its path,
+// e.g. w600k_mbf for models/w600k_mbf.onnx
+pub fn model_name(model_path: &Path) -> String {
+    model_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+// Built-in profile of a model, None for models it has no defaults for.
+// Only the thresholds the server has always shipped with are built in; other
+// models are calibrated through a [profiles] table of the config file.
+pub fn builtin(model_name: &str) -> Option<ModelProfile> {
+    match model_name {
+        // Model zoo ResNet-100, the default model
+        "arcfaceresnet100-8" => Some(FALLBACK),
+        _ => None,
+    }
+}
+
+// Thresholds in effect: SEARCH_THRESHOLD and ALERT_THRESHOLD from the
+// environment, else from the config file (the [profiles] table of the model
+// over [thresholds]), else those of `profile`
+pub fn resolve(
+    settings: &Settings,
+    profile: ModelProfile,
+) -> Result<ModelProfile, std::num::ParseFloatError> {
+    Ok(ModelProfile {
+        search_threshold: match settings.var("SEARCH_THRESHOLD") {
+            Ok(threshold) => threshold.parse()?,
+            Err(_) => profile.search_threshold,
+        },
+        alert_threshold: match settings.var("ALERT_THRESHOLD") {
+            Ok(threshold) => threshold.parse()?,
+            Err(_) => profile.alert_threshold,
+        },
+    })
+}
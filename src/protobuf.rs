@@ -0,0 +1,187 @@
+use axum::{
+    body::Bytes,
+    extract::{FromRequest, Query, RawPathParams, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use prost::Message;
+use std::time::Instant;
+
+use crate::audit;
+use crate::collections::{CollectionQuery, DEFAULT_COLLECTION};
+use crate::grpc::{self, proto};
+use crate::handlers::{self, ImageInput};
+use crate::tenant::Tenant;
+use crate::AppState;
+
+// Bodies of this type are the messages of proto/owlfacerec.proto, so clients
+// generated for the gRPC service can reuse their types over plain HTTP
+pub const CONTENT_TYPE: &str = "application/x-protobuf";
+
+fn is_protobuf(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|value| value.trim().eq_ignore_ascii_case(CONTENT_TYPE))
+}
+
+// Reads the body under the route's size limit and decodes it
+async fn decode<T: Message + Default>(state: &AppState, request: Request) -> Result<T, Response> {
+    let body = Bytes::from_request(request, state)
+        .await
+        .map_err(IntoResponse::into_response)?;
+    T::decode(body).map_err(|e| {
+        tracing::warn!(error = %e, "Failed to decode protobuf body");
+        StatusCode::BAD_REQUEST.into_response()
+    })
+}
+
+fn encoded(status: StatusCode, message: &impl Message, summary: serde_json::Value) -> Response {
+    (
+        status,
+        [(header::CONTENT_TYPE, CONTENT_TYPE)],
+        Extension(audit::Summary(summary)),
+        message.encode_to_vec(),
+    )
+        .into_response()
+}
+
+fn invalid(status: tonic::Status) -> Response {
+    tracing::warn!(
+        message = status.message(),
+        "Received invalid protobuf message"
+    );
+    StatusCode::BAD_REQUEST.into_response()
+}
+
+// Collection of the request: the path, then ?collection=, then the message
+fn collection_name(params: &RawPathParams, query: &CollectionQuery, field: &str) -> String {
+    params
+        .iter()
+        .find(|(key, _)| *key == "name")
+        .map(|(_, name)| name)
+        .or(query.requested())
+        .or(Some(field).filter(|field| !field.is_empty()))
+        .unwrap_or(DEFAULT_COLLECTION)
+        .to_string()
+}
+
+// Middleware of the register routes: a protobuf `RegisterRequest` is
+// answered with a `RegisterResponse`, JSON goes on to the handler. Errors
+// are the same statuses (and JSON bodies) as the JSON API.
+pub async fn register(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    params: RawPathParams,
+    Query(query): Query<CollectionQuery>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !is_protobuf(request.headers()) {
+        return next.run(request).await;
+    }
+    let message: proto::RegisterRequest = match decode(&state, request).await {
+        Ok(message) => message,
+        Err(response) => return response,
+    };
+    let payload = match grpc::register_payload(&message) {
+        Ok(payload) => payload,
+        Err(status) => return invalid(status),
+    };
+    let name = collection_name(&params, &query, &message.collection);
+    match handlers::register_into(
+        &state,
+        &tenant,
+        &name,
+        &payload,
+        ImageInput::Bytes(&message.image),
+    )
+    .await
+    {
+        Ok(registered) => {
+            let summary = handlers::register_summary(&name, &payload, &registered);
+            encoded(
+                StatusCode::CREATED,
+                &grpc::register_response(registered),
+                summary,
+            )
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+// Middleware of the search routes: a protobuf `SearchRequest` is answered
+// with a `SearchResponse`, JSON goes on to the handler
+pub async fn search(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    params: RawPathParams,
+    Query(query): Query<CollectionQuery>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !is_protobuf(request.headers()) {
+        return next.run(request).await;
+    }
+    let start = Instant::now();
+    let message: proto::SearchRequest = match decode(&state, request).await {
+        Ok(message) => message,
+        Err(response) => return response,
+    };
+    let payload = match grpc::search_payload(&message) {
+        Ok(payload) => payload,
+        Err(status) => return invalid(status),
+    };
+    let name = collection_name(&params, &query, &message.collection);
+    let found = match handlers::run_search_image(
+        &state,
+        &tenant,
+        &name,
+        &payload,
+        ImageInput::Bytes(&message.image),
+    )
+    .await
+    {
+        Ok(found) => found,
+        Err(status) => return status.into_response(),
+    };
+    let summary = handlers::search_summary(&name, &found);
+    let response = grpc::search_response(&found, &payload, state.calibration.as_deref());
+    tracing::info!(
+        duration = ?start.elapsed(),
+        results_count = response.results.len(),
+        partial = response.partial,
+        "Search successful"
+    );
+    encoded(StatusCode::OK, &response, summary)
+}
+
+// Handler for POST /verify/ - protobuf `VerifyRequest` in, `VerifyResponse`
+// out; there is no JSON form
+pub async fn verify(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, StatusCode> {
+    if !is_protobuf(&headers) {
+        tracing::warn!("Received verification without a protobuf body");
+        return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+    let message = proto::VerifyRequest::decode(body).map_err(|e| {
+        tracing::warn!(error = %e, "Failed to decode protobuf body");
+        StatusCode::BAD_REQUEST
+    })?;
+    let (response, threshold) = grpc::verify_target(&state, &tenant, &message).await?;
+    tracing::info!(
+        uuid = %message.target_uuid,
+        similarity = response.similarity,
+        threshold,
+        "Verification completed"
+    );
+    let summary = grpc::verify_summary(&response, threshold);
+    Ok(encoded(StatusCode::OK, &response, summary))
+}
@@ -0,0 +1,130 @@
+use image::{imageops::FilterType, DynamicImage, GenericImageView, GrayImage};
+use serde::Serialize;
+use std::str::FromStr;
+
+pub use crate::api::QualityScores;
+use crate::util;
+
+// Side of the square sharpness is measured on: the embedding model input, so
+// softness the model never sees (a large, slightly out of focus photo) is
+// not held against the image
+const ASSESS_SIZE: u32 = 112;
+
+// Scores with the checks they failed, returned when an enrollment is refused
+#[derive(Debug, Serialize)]
+pub struct QualityReport {
+    #[serde(flatten)]
+    pub scores: QualityScores,
+    pub failed: Vec<&'static str>,
+}
+
+pub fn assess(img: &DynamicImage) -> QualityScores {
+    let (width, height) = img.dimensions();
+    let gray = img
+        .resize_exact(ASSESS_SIZE, ASSESS_SIZE, FilterType::Triangle)
+        .to_luma8();
+
+    let (brightness, contrast) = mean_and_deviation(gray.pixels().map(|pixel| pixel[0] as f32));
+    let (_, laplacian_deviation) = mean_and_deviation(laplacian(&gray));
+    QualityScores {
+        sharpness: laplacian_deviation * laplacian_deviation,
+        brightness,
+        contrast,
+        face_size: width.min(height),
+    }
+}
+
+// 4-neighbour Laplacian of the interior pixels
+fn laplacian(gray: &GrayImage) -> impl Iterator<Item = f32> + '_ {
+    let (width, height) = gray.dimensions();
+    let at = move |x: u32, y: u32| gray.get_pixel(x, y)[0] as f32;
+    (1..height.saturating_sub(1)).flat_map(move |y| {
+        (1..width.saturating_sub(1)).map(move |x| {
+            4.0 * at(x, y) - at(x - 1, y) - at(x + 1, y) - at(x, y - 1) - at(x, y + 1)
+        })
+    })
+}
+
+fn mean_and_deviation(values: impl Iterator<Item = f32>) -> (f32, f32) {
+    let (mut count, mut sum, mut sum_squares) = (0usize, 0f64, 0f64);
+    for value in values {
+        count += 1;
+        sum += value as f64;
+        sum_squares += (value as f64) * (value as f64);
+    }
+    if count == 0 {
+        return (0.0, 0.0);
+    }
+    let mean = sum / count as f64;
+    let variance = (sum_squares / count as f64 - mean * mean).max(0.0);
+    (mean as f32, variance.sqrt() as f32)
+}
+
+// Thresholds an enrollment must meet; unset checks always pass
+#[derive(Clone, Debug, Default)]
+pub struct QualityGate {
+    min_sharpness: Option<f32>,
+    min_brightness: Option<f32>,
+    max_brightness: Option<f32>,
+    min_contrast: Option<f32>,
+    min_face_size: Option<f32>,
+}
+
+impl QualityGate {
+    pub fn is_enabled(&self) -> bool {
+        self.min_sharpness.is_some()
+            || self.min_brightness.is_some()
+            || self.max_brightness.is_some()
+            || self.min_contrast.is_some()
+            || self.min_face_size.is_some()
+    }
+
+    // Names of the checks the scores fail, empty when they pass
+    pub fn failures(&self, scores: &QualityScores) -> Vec<&'static str> {
+        let below = |min: Option<f32>, value: f32| min.is_some_and(|min| value < min);
+        let mut failed = Vec::new();
+        if below(self.min_sharpness, scores.sharpness) {
+            failed.push("sharpness");
+        }
+        if below(self.min_brightness, scores.brightness) {
+            failed.push("brightness_min");
+        }
+        if self
+            .max_brightness
+            .is_some_and(|max| scores.brightness > max)
+        {
+            failed.push("brightness_max");
+        }
+        if below(self.min_contrast, scores.contrast) {
+            failed.push("contrast");
+        }
+        if below(self.min_face_size, scores.face_size as f32) {
+            failed.push("face_size");
+        }
+        failed
+    }
+}
+
+// Parses "check=value" pairs separated by commas, e.g.
+// "sharpness=100,brightness_min=40,brightness_max=220,contrast=20,face_size=80"
+impl FromStr for QualityGate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut gate = QualityGate::default();
+        for (check, value) in
+            util::parse_key_values::<f32>(s).map_err(|e| format!("invalid quality gate: {}", e))?
+        {
+            let field = match check.as_str() {
+                "sharpness" => &mut gate.min_sharpness,
+                "brightness_min" => &mut gate.min_brightness,
+                "brightness_max" => &mut gate.max_brightness,
+                "contrast" => &mut gate.min_contrast,
+                "face_size" => &mut gate.min_face_size,
+                _ => return Err(format!("unknown quality check '{}'", check)),
+            };
+            *field = Some(value);
+        }
+        Ok(gate)
+    }
+}
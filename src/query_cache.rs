@@ -0,0 +1,241 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::sync::OnceCell;
+use uuid::Uuid;
+
+use crate::store::{cosine_similarity, SearchMatch, SearchOptions};
+use crate::telemetry;
+
+pub const DEFAULT_TTL: Duration = Duration::from_secs(30);
+// Cached matches are only reused for a query this close to the cached one
+pub const DEFAULT_MIN_SIMILARITY: f32 = 0.98;
+// Width of the embedding hash: near-identical frames share a key, and the
+// similarity check above weeds out the rare unrelated query that does too
+const HASH_BITS: usize = 24;
+// Same hyperplanes on every instance, so they share the cache entries
+const HYPERPLANE_SEED: u64 = 0x6f77_6c66_6163_6572;
+// A slow Redis is skipped rather than waited for
+const REDIS_TIMEOUT: Duration = Duration::from_millis(50);
+const KEY_PREFIX: &str = "owlfacerec:search:";
+const GENERATION_PREFIX: &str = "owlfacerec:generation:";
+
+#[derive(Serialize, Deserialize)]
+struct CachedSearch {
+    embedding: Vec<f32>,
+    matches: Vec<SearchMatch>,
+    // Generation of the collection the matches were found at
+    generation: u64,
+}
+
+// Key of a search, and that of the generation of its collection
+pub struct CacheKey {
+    entry: String,
+    generation: String,
+}
+
+// Result of a lookup: the cached matches, if any, and the generation of the
+// collection to store fresh ones at, None when Redis could not be read
+pub struct Lookup {
+    pub matches: Option<Vec<SearchMatch>>,
+    pub generation: Option<u64>,
+}
+
+// Matches of recently searched faces, keyed by a locality-sensitive hash of
+// the query embedding and the search options, so kiosks re-submitting the
+// same frame skip the scan. Entries expire after the TTL. Deleting or
+// updating a target bumps the generation of its collection, which retires
+// every entry stored before; new registrations only show up once the
+// entries expire.
+pub struct QueryCache {
+    client: redis::Client,
+    // Connected on first use, so an unreachable Redis never blocks startup
+    connection: OnceCell<ConnectionManager>,
+    ttl: Duration,
+    min_similarity: f32,
+    // Random hyperplanes of the sign hash, drawn once the dimension is known
+    hyperplanes: OnceLock<Vec<Vec<f32>>>,
+}
+
+impl QueryCache {
+    pub fn new(url: &str, ttl: Duration, min_similarity: f32) -> Result<Self, redis::RedisError> {
+        Ok(Self {
+            client: redis::Client::open(url)?,
+            connection: OnceCell::new(),
+            ttl,
+            min_similarity,
+            hyperplanes: OnceLock::new(),
+        })
+    }
+
+    async fn connection(&self) -> Option<ConnectionManager> {
+        let connected = self
+            .connection
+            .get_or_try_init(|| ConnectionManager::new(self.client.clone()))
+            .await;
+        match connected {
+            Ok(connection) => Some(connection.clone()),
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to connect to the query cache");
+                None
+            }
+        }
+    }
+
+    // Sign of the embedding against each hyperplane
+    fn embedding_hash(&self, embedding: &[f32]) -> Option<u32> {
+        let hyperplanes = self.hyperplanes.get_or_init(|| {
+            let mut rng = StdRng::seed_from_u64(HYPERPLANE_SEED);
+            (0..HASH_BITS)
+                .map(|_| {
+                    (0..embedding.len())
+                        .map(|_| rng.gen_range(-1.0f32..1.0))
+                        .collect()
+                })
+                .collect()
+        });
+        if hyperplanes.first()?.len() != embedding.len() {
+            return None;
+        }
+        Some(
+            hyperplanes
+                .iter()
+                .enumerate()
+                .filter(|(_, plane)| {
+                    plane.iter().zip(embedding).map(|(a, b)| a * b).sum::<f32>() >= 0.0
+                })
+                .fold(0u32, |hash, (bit, _)| hash | 1 << bit),
+        )
+    }
+
+    // Key of a search, None when the embedding cannot be hashed
+    pub fn key(
+        &self,
+        tenant: &str,
+        collection: &str,
+        embedding: &[f32],
+        options: &SearchOptions,
+    ) -> Option<CacheKey> {
+        let hash = self.embedding_hash(embedding)?;
+        let mut origins: Vec<&String> = options.origins.iter().flatten().collect();
+        origins.sort();
+        let mut exclude_uuids: Vec<&Uuid> = options.exclude_uuids.iter().flatten().collect();
+        exclude_uuids.sort();
+        let mut exclude_origins: Vec<&String> = options.exclude_origins.iter().flatten().collect();
+        exclude_origins.sort();
+        let scope = serde_json::json!([
+            tenant,
+            collection,
+            options.threshold,
+            options.limit,
+            options.group_by_uuid,
+            origins,
+            options.metadata_filter,
+            exclude_uuids,
+            exclude_origins,
+            options.similarity_weight,
+            options.nprobe,
+        ]);
+        let scope = hex::encode(Sha256::digest(scope.to_string().as_bytes()));
+        Some(CacheKey {
+            entry: format!("{}{}:{:06x}", KEY_PREFIX, scope, hash),
+            generation: generation_key(tenant, collection),
+        })
+    }
+
+    // Cached matches of a query close enough to this one, stored at the
+    // current generation of the collection
+    pub async fn get(&self, key: &CacheKey, embedding: &[f32]) -> Lookup {
+        let read = async {
+            // Also bounds the connection attempt of the first lookups
+            let mut connection = self.connection().await?;
+            let read: Result<(Option<Vec<u8>>, Option<u64>), _> = redis::pipe()
+                .get(&key.entry)
+                .get(&key.generation)
+                .query_async(&mut connection)
+                .await;
+            match read {
+                Ok(read) => Some(read),
+                Err(e) => {
+                    tracing::warn!(error = %e, "Failed to read the query cache");
+                    None
+                }
+            }
+        };
+        let Ok(Some((cached, generation))) = tokio::time::timeout(REDIS_TIMEOUT, read).await else {
+            tracing::debug!("Query cache unavailable, searching");
+            return Lookup {
+                matches: None,
+                generation: None,
+            };
+        };
+        let generation = generation.unwrap_or(0);
+        let matches = cached
+            .and_then(|bytes| serde_json::from_slice::<CachedSearch>(&bytes).ok())
+            .filter(|cached| cached.generation == generation)
+            .filter(|cached| cosine_similarity(&cached.embedding, embedding) >= self.min_similarity)
+            .map(|cached| cached.matches);
+        telemetry::query_cache_lookup(matches.is_some());
+        Lookup {
+            matches,
+            generation: Some(generation),
+        }
+    }
+
+    // Stores the matches in the background, searches never wait on it
+    pub fn put(
+        self: &Arc<Self>,
+        key: CacheKey,
+        generation: u64,
+        embedding: Vec<f32>,
+        matches: Vec<SearchMatch>,
+    ) {
+        let cache = self.clone();
+        tokio::spawn(async move {
+            let Some(mut connection) = cache.connection().await else {
+                return;
+            };
+            let cached = CachedSearch {
+                embedding,
+                matches,
+                generation,
+            };
+            let Ok(bytes) = serde_json::to_vec(&cached) else {
+                return;
+            };
+            let stored: Result<(), redis::RedisError> = connection
+                .set_ex(&key.entry, bytes, cache.ttl.as_secs().max(1))
+                .await;
+            if let Err(e) = stored {
+                tracing::warn!(error = %e, "Failed to write the query cache");
+            }
+        });
+    }
+
+    // Retires the cached searches of a collection, once a target of it was
+    // deleted or changed
+    pub async fn invalidate(&self, tenant: &str, collection: &str) {
+        let key = generation_key(tenant, collection);
+        let bumped = async {
+            let mut connection = self.connection().await?;
+            Some(connection.incr::<_, _, u64>(&key, 1).await)
+        };
+        match tokio::time::timeout(REDIS_TIMEOUT, bumped).await {
+            Ok(Some(Ok(_))) => {}
+            Ok(Some(Err(e))) => {
+                tracing::warn!(error = %e, %tenant, %collection, "Failed to invalidate the query cache")
+            }
+            Ok(None) | Err(_) => {
+                tracing::warn!(%tenant, %collection, "Query cache unreachable, cached searches expire with the TTL")
+            }
+        }
+    }
+}
+
+fn generation_key(tenant: &str, collection: &str) -> String {
+    format!("{}{}:{}", GENERATION_PREFIX, tenant, collection)
+}
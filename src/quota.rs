@@ -0,0 +1,54 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use crate::util;
+
+// Maximum number of stored embeddings per origin
+//
+// Quotas are soft: the count is checked before the database insert, so a burst
+// of concurrent registrations for the same origin can overshoot by a few entries.
+#[derive(Clone, Debug, Default)]
+pub struct Quotas {
+    limits: HashMap<String, usize>,
+    // Applies to origins without an explicit limit; None means unlimited
+    default_limit: Option<usize>,
+}
+
+impl Quotas {
+    pub fn with_default_limit(mut self, default_limit: Option<usize>) -> Self {
+        self.default_limit = default_limit;
+        self
+    }
+
+    pub fn limit_for(&self, origin: &str) -> Option<usize> {
+        self.limits.get(origin).copied().or(self.default_limit)
+    }
+
+    // True if storing one more embedding would exceed the origin's quota
+    pub fn is_exhausted(&self, origin: &str, used: usize) -> bool {
+        self.would_exceed(origin, used, 1)
+    }
+
+    // True if storing `adding` more embeddings would exceed the origin's quota
+    pub fn would_exceed(&self, origin: &str, used: usize, adding: usize) -> bool {
+        self.limit_for(origin)
+            .is_some_and(|limit| used + adding > limit)
+    }
+
+    // Origins with an explicit limit
+    pub fn origins(&self) -> impl Iterator<Item = &str> {
+        self.limits.keys().map(String::as_str)
+    }
+}
+
+// Parses "origin=limit" pairs separated by commas, e.g. "users=10000,cctv=50000"
+impl FromStr for Quotas {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Quotas {
+            limits: util::parse_key_values(s).map_err(|e| format!("invalid quota: {}", e))?,
+            default_limit: None,
+        })
+    }
+}
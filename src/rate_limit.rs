@@ -0,0 +1,189 @@
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Mutex, RwLock};
+use std::time::Instant;
+
+use crate::auth::ApiKey;
+use crate::jwt::Claims;
+use crate::AppState;
+
+// Most clients tracked at once. Once reached, buckets that have refilled are
+// forgotten and, if that is not enough, the least recently used ones, down to
+// TRACKED_AFTER_EVICTION so the scan is not repeated for every new client.
+pub(crate) const MAX_TRACKED_CLIENTS: usize = 10_000;
+const TRACKED_AFTER_EVICTION: usize = MAX_TRACKED_CLIENTS * 3 / 4;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// Token bucket per client: `burst` requests at once, refilled at `rate` per second
+pub struct RateLimiter {
+    // (rate, burst), None when rate limiting is off; changes at runtime
+    limits: RwLock<Option<(f64, f64)>>,
+    // Use the first X-Forwarded-For address instead of the peer address
+    trust_forwarded_for: bool,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: Option<(f64, f64)>, trust_forwarded_for: bool) -> Self {
+        let limiter = Self {
+            limits: RwLock::new(None),
+            trust_forwarded_for,
+            buckets: Mutex::new(HashMap::new()),
+        };
+        limiter.set_limits(limits);
+        limiter
+    }
+
+    // Buckets keep their tokens, capped by the new burst on their next request
+    pub fn set_limits(&self, limits: Option<(f64, f64)>) {
+        *self.limits.write().unwrap_or_else(|e| e.into_inner()) =
+            limits.map(|(rate, burst)| (rate, burst.max(1.0)));
+        if limits.is_none() {
+            self.buckets
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .clear();
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.limits
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_some()
+    }
+
+    // Takes a token, or returns how many seconds until one is available
+    pub fn acquire(&self, client: String) -> Result<(), u64> {
+        let Some((rate, burst)) = *self.limits.read().unwrap_or_else(|e| e.into_inner()) else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if buckets.len() >= MAX_TRACKED_CLIENTS && !buckets.contains_key(&client) {
+            make_room(&mut buckets, now, rate, burst);
+        }
+
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / rate).ceil().max(1.0) as u64)
+        }
+    }
+
+    #[cfg(test)]
+    pub fn tracked(&self) -> usize {
+        self.buckets.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    // Authenticated subject first, then authenticated API key, then client
+    // address; an X-API-Key header nobody checked is not an identity, or
+    // every request could pick a fresh bucket
+    pub fn client_id(
+        &self,
+        headers: &HeaderMap,
+        claims: Option<&Claims>,
+        key: Option<&ApiKey>,
+        peer: Option<SocketAddr>,
+    ) -> String {
+        if let Some(subject) = claims.and_then(|claims| claims.get_str("sub")) {
+            return format!("sub:{}", subject);
+        }
+        if let Some(key) = key {
+            return format!("key:{}", key.id);
+        }
+        if self.trust_forwarded_for {
+            if let Some(address) = forwarded_for(headers) {
+                return format!("ip:{}", address);
+            }
+        }
+        match peer {
+            Some(address) => format!("ip:{}", address.ip()),
+            None => "ip:unknown".to_string(),
+        }
+    }
+}
+
+// Forgets buckets that have refilled, then the least recently used ones while
+// more than TRACKED_AFTER_EVICTION are left
+fn make_room(buckets: &mut HashMap<String, Bucket>, now: Instant, rate: f64, burst: f64) {
+    buckets.retain(|_, bucket| {
+        bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < burst
+    });
+    let excess = buckets.len().saturating_sub(TRACKED_AFTER_EVICTION);
+    if excess == 0 {
+        return;
+    }
+    let mut by_age: Vec<(Instant, &String)> = buckets
+        .iter()
+        .map(|(client, bucket)| (bucket.updated, client))
+        .collect();
+    by_age.select_nth_unstable(excess - 1);
+    let evicted: Vec<String> = by_age[..excess]
+        .iter()
+        .map(|(_, client)| (*client).clone())
+        .collect();
+    for client in evicted {
+        buckets.remove(&client);
+    }
+}
+
+fn forwarded_for(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .map(str::trim)
+        .filter(|address| !address.is_empty())
+}
+
+// Route layer of the inference endpoints (/register/ and /search/)
+pub async fn limit(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let limiter = &state.rate_limiter;
+    if !limiter.is_enabled() {
+        return Ok(next.run(request).await);
+    }
+
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(address)| *address);
+    let client = limiter.client_id(
+        request.headers(),
+        request.extensions().get::<Claims>(),
+        request.extensions().get::<ApiKey>(),
+        peer,
+    );
+    if let Err(retry_after) = limiter.acquire(client.clone()) {
+        tracing::warn!(%client, retry_after, path = %request.uri().path(), "Rate limit exceeded");
+        return Ok((
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, retry_after.to_string())],
+        )
+            .into_response());
+    }
+    Ok(next.run(request).await)
+}
@@ -0,0 +1,118 @@
+use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+use serde::Serialize;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+use crate::tls::Tls;
+use crate::AppState;
+
+// Longest the database may take to answer a readiness probe
+const DATABASE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Define the response for /readyz
+#[derive(Serialize)]
+pub struct ReadyResponse {
+    ready: bool,
+    database: bool,
+    model: bool,
+    store_loaded: bool,
+}
+
+// Handler for GET /livez - the process is up and serving requests
+pub async fn livez() -> StatusCode {
+    StatusCode::OK
+}
+
+// Handler for GET /readyz - the database answers and the gallery is in
+// memory; 503 otherwise so no traffic is routed to the instance
+pub async fn readyz(State(state): State<AppState>) -> (StatusCode, Json<ReadyResponse>) {
+    // Nothing to ping in memory-only mode
+    let database = !state.storage.persists_targets()
+        || matches!(
+            tokio::time::timeout(DATABASE_TIMEOUT, state.targets.ping()).await,
+            Ok(Ok(_))
+        );
+    // Registrations fail fast while the circuit is open
+    let database = database && !state.db_breaker.is_open();
+    if !database {
+        tracing::warn!("Readiness probe failed, database unreachable");
+    }
+    // The session is created before the state and the full router is only
+    // served once the gallery is loaded, see `serve_loading`
+    let response = ReadyResponse {
+        ready: database,
+        database,
+        model: true,
+        store_loaded: true,
+    };
+    let status = if response.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(response))
+}
+
+// The gallery is read from the database while loading, and startup aborts
+// when it cannot be
+async fn loading_readyz() -> (StatusCode, Json<ReadyResponse>) {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        Json(ReadyResponse {
+            ready: false,
+            database: true,
+            model: true,
+            store_loaded: false,
+        }),
+    )
+}
+
+// Probe server answering on the API port while the gallery streams into
+// memory: live but not ready, and 503 on every other route
+pub struct Loading {
+    stop: oneshot::Sender<()>,
+    server: JoinHandle<std::io::Result<()>>,
+}
+
+pub fn serve_loading(
+    listener: std::net::TcpListener,
+    tls: Option<Tls>,
+) -> std::io::Result<Loading> {
+    let app = Router::new()
+        .route("/livez", get(livez))
+        .route("/readyz", get(loading_readyz))
+        .fallback(|| async { StatusCode::SERVICE_UNAVAILABLE });
+    let (stop, stopped) = oneshot::channel();
+    let server = match tls {
+        Some(tls) => {
+            let handle = axum_server::Handle::new();
+            let shutdown = handle.clone();
+            tokio::spawn(async move {
+                stopped.await.ok();
+                shutdown.graceful_shutdown(None);
+            });
+            let server = axum_server::from_tcp_rustls(listener, tls.config).handle(handle);
+            tokio::spawn(async move { server.serve(app.into_make_service()).await })
+        }
+        None => {
+            let listener = tokio::net::TcpListener::from_std(listener)?;
+            tokio::spawn(async move {
+                axum::serve(listener, app)
+                    .with_graceful_shutdown(async {
+                        stopped.await.ok();
+                    })
+                    .await
+            })
+        }
+    };
+    Ok(Loading { stop, server })
+}
+
+impl Loading {
+    // Stops accepting on the listener so the full router can take it over
+    pub async fn finish(self) -> std::io::Result<()> {
+        self.stop.send(()).ok();
+        self.server.await.map_err(std::io::Error::other)?
+    }
+}
@@ -0,0 +1,105 @@
+use std::borrow::Cow;
+use std::io;
+use tracing_subscriber::fmt::MakeWriter;
+
+// Base64 runs this long are images (or other blobs), never ids or hashes
+const MIN_BASE64_RUN: usize = 256;
+// Number lists this long are embeddings (the smallest models have 128)
+const MIN_EMBEDDING_VALUES: usize = 32;
+
+// Replaces base64 images and embeddings in `text`, so a log line or report
+// that happens to include a request body cannot leak biometric data
+pub fn scrub(text: &str) -> Cow<'_, str> {
+    if !needs_scrubbing(text) {
+        return Cow::Borrowed(text);
+    }
+    let bytes = text.as_bytes();
+    let mut scrubbed = String::with_capacity(text.len());
+    let mut copied = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        if is_base64(bytes[i]) {
+            let end = i + bytes[i..].iter().take_while(|b| is_base64(**b)).count();
+            if end - i >= MIN_BASE64_RUN {
+                scrubbed.push_str(&text[copied..i]);
+                scrubbed.push_str(&format!("[redacted base64, {} bytes]", end - i));
+                copied = end;
+            }
+            i = end;
+        } else if bytes[i] == b'[' {
+            match number_list(&bytes[i..]) {
+                Some((len, values)) if values >= MIN_EMBEDDING_VALUES => {
+                    scrubbed.push_str(&text[copied..i]);
+                    scrubbed.push_str(&format!("[redacted embedding, {} values]", values));
+                    i += len;
+                    copied = i;
+                }
+                _ => i += 1,
+            }
+        } else {
+            i += 1;
+        }
+    }
+    scrubbed.push_str(&text[copied..]);
+    Cow::Owned(scrubbed)
+}
+
+// Cheap check first: most lines are far shorter than anything redacted
+fn needs_scrubbing(text: &str) -> bool {
+    text.len() >= MIN_BASE64_RUN.min(MIN_EMBEDDING_VALUES * 2)
+}
+
+fn is_base64(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || matches!(b, b'+' | b'/' | b'=' | b'-' | b'_')
+}
+
+// Length and count of a bracketed list of numbers at the start of `bytes`,
+// e.g. `[0.12, -0.5, 1e-3]` as Debug or JSON prints a Vec<f32>
+fn number_list(bytes: &[u8]) -> Option<(usize, usize)> {
+    let mut values = 0;
+    let mut in_value = false;
+    for (i, b) in bytes.iter().enumerate().skip(1) {
+        match b {
+            b']' => return Some((i + 1, values + usize::from(in_value))),
+            b',' => {
+                if !in_value {
+                    return None;
+                }
+                values += 1;
+                in_value = false;
+            }
+            b' ' | b'\n' => {}
+            b'0'..=b'9' | b'-' | b'+' | b'.' | b'e' | b'E' => in_value = true,
+            _ => return None,
+        }
+    }
+    None
+}
+
+// Log writer passing each formatted line through `scrub`
+pub struct Redacting<M>(pub M);
+
+impl<'a, M: MakeWriter<'a>> MakeWriter<'a> for Redacting<M> {
+    type Writer = RedactingWriter<M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RedactingWriter(self.0.make_writer())
+    }
+}
+
+pub struct RedactingWriter<W>(W);
+
+impl<W: io::Write> io::Write for RedactingWriter<W> {
+    // The fmt layer writes each line with a single call
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match std::str::from_utf8(buf) {
+            Ok(text) => self.0.write_all(scrub(text).as_bytes())?,
+            Err(_) => self.0.write_all(buf)?,
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
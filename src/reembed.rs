@@ -0,0 +1,142 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::Row;
+use uuid::Uuid;
+
+use crate::cli;
+use crate::jobs::JobView;
+use crate::AppState;
+
+const DEFAULT_REENROLLMENT_LIMIT: i64 = 100;
+const MAX_REENROLLMENT_LIMIT: i64 = 1000;
+
+// Define the request payload for POST /admin/reindex
+#[derive(Deserialize)]
+pub struct ReindexPayload {
+    // Every tenant when unset
+    #[serde(default)]
+    tenant: Option<String>,
+    #[serde(default)]
+    collection: Option<String>,
+    // Also the rows already embedded with the current model
+    #[serde(default)]
+    all: bool,
+}
+
+// Handler for POST /admin/reindex - re-embeds the stored crops of the rows
+// of another model in the background, e.g. after a model upgrade; answers
+// 202 Accepted with the job to poll on /admin/jobs/:id
+pub async fn reindex(
+    State(state): State<AppState>,
+    Json(payload): Json<ReindexPayload>,
+) -> Result<(StatusCode, Json<JobView>), StatusCode> {
+    if state.crops.is_none() {
+        tracing::warn!("Rejected reindex without enrollment crops");
+        return Err(StatusCode::NOT_IMPLEMENTED);
+    }
+    let jobs = state.jobs.clone();
+    let view = jobs.start(None, "reindex", move |progress| async move {
+        let summary = cli::reindex(
+            &state,
+            payload.tenant.as_deref(),
+            payload.collection.as_deref(),
+            payload.all,
+            &progress,
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+        serde_json::to_value(summary).map_err(|e| e.to_string())
+    })?;
+    Ok((StatusCode::ACCEPTED, Json(view)))
+}
+
+// Define the query parameters for GET /admin/reenrollment
+#[derive(Deserialize)]
+pub struct ReenrollmentQuery {
+    tenant: Option<String>,
+    collection: Option<String>,
+    limit: Option<i64>,
+}
+
+// Define the response for GET /admin/reenrollment
+#[derive(Serialize)]
+pub struct ReenrollmentResponse {
+    // Model the registrations are compared against
+    model: String,
+    // Registrations of another model, beyond the ones listed
+    total: i64,
+    targets: Vec<ReenrollmentTarget>,
+}
+
+#[derive(Serialize)]
+pub struct ReenrollmentTarget {
+    tenant: String,
+    collection: String,
+    target_uuid: Uuid,
+    origin: String,
+    // Whether the reindex job can re-embed it; otherwise the person has to
+    // be enrolled again with a new photo
+    has_crop: bool,
+    // None for the rows written before models were recorded
+    model: Option<String>,
+}
+
+// Handler for GET /admin/reenrollment - lists the registrations embedded
+// with another model than the running one, crops or not
+pub async fn reenrollment(
+    State(state): State<AppState>,
+    Query(query): Query<ReenrollmentQuery>,
+) -> Result<Json<ReenrollmentResponse>, StatusCode> {
+    let model = state.version.model_version();
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_REENROLLMENT_LIMIT)
+        .clamp(1, MAX_REENROLLMENT_LIMIT);
+    let filter = "deleted_at IS NULL AND ($1::text IS NULL OR tenant = $1) AND ($2::text IS NULL OR collection = $2) AND model IS DISTINCT FROM $3";
+
+    let total: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) FROM targets WHERE {}", filter))
+        .bind(query.tenant.as_deref())
+        .bind(query.collection.as_deref())
+        .bind(model)
+        .fetch_one(&state.db_pool)
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, "Failed to count registrations to re-enroll");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let rows = sqlx::query(&format!(
+        "SELECT tenant, collection, uuid AS target_uuid, origin, image_key IS NOT NULL AS has_crop, model FROM targets WHERE {} ORDER BY id LIMIT $4",
+        filter
+    ))
+    .bind(query.tenant.as_deref())
+    .bind(query.collection.as_deref())
+    .bind(model)
+    .bind(limit)
+    .fetch_all(&state.db_pool)
+    .await
+    .map_err(|e| {
+        tracing::error!(error = %e, "Failed to list registrations to re-enroll");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let targets = rows
+        .iter()
+        .map(|row| ReenrollmentTarget {
+            tenant: row.get("tenant"),
+            collection: row.get("collection"),
+            target_uuid: row.get("target_uuid"),
+            origin: row.get("origin"),
+            has_crop: row.get("has_crop"),
+            model: row.get("model"),
+        })
+        .collect();
+    Ok(Json(ReenrollmentResponse {
+        model: model.to_string(),
+        total,
+        targets,
+    }))
+}
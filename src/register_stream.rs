@@ -0,0 +1,347 @@
+use axum::{
+    body::{Body, Bytes},
+    extract::{ConnectInfo, Extension, Query, State},
+    http::{header, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Response},
+};
+use futures_util::{stream, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::Instant;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use uuid::Uuid;
+
+use crate::audit::{self, AuditEntry};
+use crate::auth::ApiKey;
+use crate::collections::DEFAULT_COLLECTION;
+use crate::handlers::{self, ImageInput, RegisterPayload, RegisterResponse};
+use crate::jwt::Claims;
+use crate::quality::QualityReport;
+use crate::request_id::RequestId;
+use crate::tenant::Tenant;
+use crate::AppState;
+
+// Largest accepted record, same bound as a JSON registration body
+const MAX_RECORD_SIZE: usize = handlers::DEFAULT_MAX_BODY_BYTES;
+pub const DEFAULT_CONCURRENCY: usize = 4;
+const MAX_CONCURRENCY: usize = 32;
+// Result lines waiting for a slow client before records stop being read
+const PENDING_RESULTS: usize = 64;
+
+#[derive(Deserialize)]
+pub struct StreamQuery {
+    collection: Option<String>,
+    // Records registered at once; results still come back in input order
+    concurrency: Option<usize>,
+}
+
+// One line of the response per record, in the order of the request
+#[derive(Serialize)]
+struct RecordResult {
+    // 1-based line of the record in the request body
+    line: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target_uuid: Option<Uuid>,
+    // HTTP status /register/ would have answered
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    registered: Option<RegisterResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quality: Option<QualityReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    conflict: Option<handlers::Duplicate>,
+}
+
+impl RecordResult {
+    fn failed(line: usize, status: StatusCode, error: impl Into<String>) -> Self {
+        RecordResult {
+            line,
+            target_uuid: None,
+            status: status.as_u16(),
+            registered: None,
+            error: Some(error.into()),
+            quality: None,
+            conflict: None,
+        }
+    }
+}
+
+// Last line of a stream read to its end; its absence means the stream was cut
+#[derive(Serialize)]
+struct StreamSummary {
+    done: bool,
+    registered: usize,
+    failed: usize,
+}
+
+// Who opened the stream: every record is charged to its rate limit bucket
+// and audited like a /register/ request of its own
+struct Caller {
+    client: String,
+    actor: String,
+    parameters: serde_json::Value,
+}
+
+// A record of the body by line number, or why it could not be read
+enum Record {
+    Line(usize, Bytes),
+    TooLarge(usize),
+    // The body broke off; it is the last record
+    Unreadable(usize, axum::Error),
+}
+
+// Splits the body on newlines without holding more than one record; blank
+// lines are skipped but still counted
+fn records(body: Body) -> impl Stream<Item = Record> + Send + Unpin + 'static {
+    struct Lines {
+        chunks: axum::body::BodyDataStream,
+        buffer: Vec<u8>,
+        // Bytes of the buffer already searched for a newline
+        scanned: usize,
+        line: usize,
+        // Rest of an oversized line, dropped up to its newline
+        skipping: bool,
+        ended: bool,
+    }
+
+    Box::pin(stream::unfold(
+        Lines {
+            chunks: body.into_data_stream(),
+            buffer: Vec::new(),
+            scanned: 0,
+            line: 0,
+            skipping: false,
+            ended: false,
+        },
+        |mut lines| async move {
+            loop {
+                let newline = lines.buffer[lines.scanned..]
+                    .iter()
+                    .position(|&byte| byte == b'\n');
+                if let Some(end) = newline.map(|offset| lines.scanned + offset) {
+                    lines.scanned = 0;
+                    let rest = lines.buffer.split_off(end + 1);
+                    let mut record = std::mem::replace(&mut lines.buffer, rest);
+                    record.truncate(end);
+                    lines.line += 1;
+                    if std::mem::take(&mut lines.skipping) {
+                        continue;
+                    }
+                    if record.iter().all(u8::is_ascii_whitespace) {
+                        continue;
+                    }
+                    let record = Record::Line(lines.line, Bytes::from(record));
+                    return Some((record, lines));
+                }
+                lines.scanned = lines.buffer.len();
+                if lines.buffer.len() > MAX_RECORD_SIZE {
+                    lines.buffer.clear();
+                    lines.scanned = 0;
+                    if !std::mem::replace(&mut lines.skipping, true) {
+                        let record = Record::TooLarge(lines.line + 1);
+                        return Some((record, lines));
+                    }
+                }
+                if lines.ended {
+                    // Last record without a trailing newline
+                    if lines.skipping || lines.buffer.iter().all(u8::is_ascii_whitespace) {
+                        return None;
+                    }
+                    lines.line += 1;
+                    let record =
+                        Record::Line(lines.line, Bytes::from(std::mem::take(&mut lines.buffer)));
+                    return Some((record, lines));
+                }
+                match lines.chunks.next().await {
+                    Some(Ok(chunk)) => lines.buffer.extend_from_slice(&chunk),
+                    Some(Err(e)) => {
+                        let line = lines.line + 1;
+                        lines.ended = true;
+                        lines.buffer.clear();
+                        lines.scanned = 0;
+                        lines.skipping = true;
+                        return Some((Record::Unreadable(line, e), lines));
+                    }
+                    None => lines.ended = true,
+                }
+            }
+        },
+    ))
+}
+
+// Handler for POST /register/stream - newline-delimited registration records,
+// each one a /register/ body, registered as they arrive and answered with one
+// newline-delimited result per record
+#[allow(clippy::too_many_arguments)]
+pub async fn register_stream(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Query(query): Query<StreamQuery>,
+    uri: Uri,
+    headers: HeaderMap,
+    claims: Option<Extension<Claims>>,
+    key: Option<Extension<ApiKey>>,
+    request_id: Option<Extension<RequestId>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    body: Body,
+) -> Result<Response, StatusCode> {
+    let collection = query
+        .collection
+        .unwrap_or_else(|| DEFAULT_COLLECTION.to_string());
+    if state.collections.get(tenant.id(), &collection).is_none() {
+        tracing::warn!(%collection, "Received registration stream for unknown collection");
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let concurrency = query
+        .concurrency
+        .unwrap_or(DEFAULT_CONCURRENCY)
+        .clamp(1, MAX_CONCURRENCY);
+    tracing::info!(%collection, concurrency, "Registration stream opened");
+    let claims = claims.map(|Extension(claims)| claims);
+    let key = key.map(|Extension(key)| key);
+    let peer = peer.map(|ConnectInfo(address)| address);
+    let caller = Caller {
+        client: state
+            .rate_limiter
+            .client_id(&headers, claims.as_ref(), key.as_ref(), peer),
+        actor: audit::actor(claims.as_ref(), key.as_ref(), peer),
+        parameters: serde_json::json!({
+            "path": uri.path(),
+            "query": uri.query(),
+            "request_id": request_id.as_ref().map(|Extension(id)| id.as_str()),
+        }),
+    };
+
+    let (sender, receiver) = mpsc::channel::<Result<Bytes, Infallible>>(PENDING_RESULTS);
+    tokio::spawn(async move {
+        let start = Instant::now();
+        let mut results = records(body)
+            .map(|record| {
+                let (state, tenant, collection, caller) = (&state, &tenant, &collection, &caller);
+                async move {
+                    match record {
+                        Record::Line(line, bytes) => {
+                            register_record(state, tenant, collection, caller, line, &bytes).await
+                        }
+                        Record::TooLarge(line) => RecordResult::failed(
+                            line,
+                            StatusCode::PAYLOAD_TOO_LARGE,
+                            format!("record longer than {} bytes", MAX_RECORD_SIZE),
+                        ),
+                        Record::Unreadable(line, e) => RecordResult::failed(
+                            line,
+                            StatusCode::BAD_REQUEST,
+                            format!("failed to read the request body: {}", e),
+                        ),
+                    }
+                }
+            })
+            .buffered(concurrency);
+
+        let (mut registered, mut failed) = (0, 0);
+        while let Some(result) = results.next().await {
+            if result.registered.is_some() {
+                registered += 1;
+            } else {
+                failed += 1;
+            }
+            if sender.send(Ok(json_line(&result))).await.is_err() {
+                // The client went away: records not read yet are never registered
+                tracing::warn!(%collection, registered, failed, "Registration stream closed by the client");
+                return;
+            }
+        }
+        let summary = StreamSummary {
+            done: true,
+            registered,
+            failed,
+        };
+        let _ = sender.send(Ok(json_line(&summary))).await;
+        tracing::info!(%collection, registered, failed, duration = ?start.elapsed(), "Registration stream finished");
+    });
+
+    Ok((
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(ReceiverStream::new(receiver)),
+    )
+        .into_response())
+}
+
+async fn register_record(
+    state: &AppState,
+    tenant: &Tenant,
+    collection: &str,
+    caller: &Caller,
+    line: usize,
+    bytes: &[u8],
+) -> RecordResult {
+    if let Err(retry_after) = state.rate_limiter.acquire(caller.client.clone()) {
+        tracing::warn!(client = %caller.client, line, retry_after, "Rate limit exceeded in registration stream");
+        return RecordResult::failed(
+            line,
+            StatusCode::TOO_MANY_REQUESTS,
+            format!("rate limit exceeded, retry after {} s", retry_after),
+        );
+    }
+    let payload: RegisterPayload = match serde_json::from_slice(bytes) {
+        Ok(payload) => payload,
+        Err(e) => {
+            tracing::warn!(line, error = %e, "Received invalid record in registration stream");
+            return RecordResult::failed(line, StatusCode::BAD_REQUEST, e.to_string());
+        }
+    };
+    let target_uuid = (!payload.register_all_faces).then_some(payload.target_uuid);
+    let image = ImageInput::Base64(&payload.image_base64);
+    let outcome = handlers::register_into(state, tenant, collection, &payload, image).await;
+    if let Some(audit) = &state.audit {
+        let mut parameters = caller.parameters.clone();
+        parameters["line"] = line.into();
+        let (status, result) = match &outcome {
+            Ok(response) => (
+                StatusCode::CREATED,
+                Some(handlers::register_summary(collection, &payload, response)),
+            ),
+            Err(e) => (e.status, None),
+        };
+        audit
+            .record(AuditEntry {
+                tenant: Some(tenant.id().to_string()),
+                actor: caller.actor.clone(),
+                action: "POST /register/stream".to_string(),
+                parameters,
+                status: status.as_u16(),
+                result,
+            })
+            .await;
+    }
+    match outcome {
+        Ok(response) => RecordResult {
+            line,
+            target_uuid,
+            status: StatusCode::CREATED.as_u16(),
+            registered: Some(response),
+            error: None,
+            quality: None,
+            conflict: None,
+        },
+        Err(e) => RecordResult {
+            line,
+            target_uuid,
+            status: e.status.as_u16(),
+            registered: None,
+            error: e.status.canonical_reason().map(str::to_string),
+            quality: e.quality,
+            conflict: e.duplicate,
+        },
+    }
+}
+
+fn json_line(value: &impl Serialize) -> Bytes {
+    let mut line = serde_json::to_vec(value).unwrap_or_default();
+    line.push(b'\n');
+    Bytes::from(line)
+}
@@ -0,0 +1,153 @@
+use futures_util::TryStreamExt;
+use sqlx::Row;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::encryption;
+use crate::store::{Metadata, NewEmbedding};
+use crate::AppState;
+
+// (tenant, collection, uuid)
+pub(crate) type TargetKey = (String, String, Uuid);
+
+// Rows in the database and registrations in memory of a drifted target
+pub(crate) type Drift = (i64, i64);
+
+// Rows of a target as stored, replacing its in-memory entries; also pages
+// an evicted target back in
+pub(crate) async fn reload(state: &AppState, key: &TargetKey) -> Result<(), sqlx::Error> {
+    let (tenant, name, uuid) = key;
+    let embeddings = if state.storage.is_postgres() {
+        postgres_target(state, key).await?
+    } else {
+        state.targets.target(tenant, name, *uuid).await?
+    };
+    let store = &state.collections.get_or_create(tenant, name).store;
+    store.remove(uuid).await;
+    for uuid in store.add_batch(embeddings).await {
+        tracing::warn!(%tenant, collection = %name, %uuid, expected = ?store.dimension(), "Skipped a stored embedding of the wrong dimension");
+    }
+    state.collections.enforce_memory_limits().await;
+    Ok(())
+}
+
+async fn postgres_target(
+    state: &AppState,
+    (tenant, name, uuid): &TargetKey,
+) -> Result<Vec<NewEmbedding>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT embeddings, embeddings_sealed, origin, metadata, model FROM targets WHERE tenant = $1 AND collection = $2 AND uuid = $3 AND deleted_at IS NULL",
+    )
+    .bind(tenant)
+    .bind(name)
+    .bind(uuid)
+    .fetch_all(&state.db_pool)
+    .await?;
+    let mut embeddings = Vec::with_capacity(rows.len());
+    for row in rows {
+        let metadata: sqlx::types::Json<Metadata> = row.try_get("metadata")?;
+        embeddings.push(NewEmbedding {
+            uuid: *uuid,
+            origin: row.try_get("origin")?,
+            metadata: metadata.0,
+            embedding: encryption::from_row(&row)?,
+            model_version: row.try_get("model")?,
+            expires_at: None,
+        });
+    }
+    Ok(embeddings)
+}
+
+// Targets whose rows in the database differ in number from the
+// registrations in memory
+pub(crate) async fn drifted_targets(
+    state: &AppState,
+) -> Result<HashMap<TargetKey, Drift>, sqlx::Error> {
+    let mut counts: HashMap<TargetKey, Drift> = HashMap::new();
+    let mut rows = sqlx::query(
+        "SELECT tenant, collection, uuid, COUNT(*) AS rows FROM targets WHERE deleted_at IS NULL GROUP BY tenant, collection, uuid",
+    )
+    .fetch(&state.bulk_pool);
+    while let Some(row) = rows.try_next().await? {
+        let key: TargetKey = (
+            row.try_get("tenant")?,
+            row.try_get("collection")?,
+            row.try_get("uuid")?,
+        );
+        // Targets of the other gallery shards are never in memory here
+        if state
+            .sharding
+            .as_ref()
+            .is_some_and(|sharding| !sharding.owns(&key.2))
+        {
+            continue;
+        }
+        counts.insert(key, (row.try_get("rows")?, 0));
+    }
+    drop(rows);
+
+    for (tenant, name, collection) in state.collections.all() {
+        for (uuid, registrations) in collection.store.registration_counts().await {
+            counts
+                .entry((tenant.clone(), name.clone(), uuid))
+                .or_insert((0, 0))
+                .1 = registrations as i64;
+        }
+    }
+
+    Ok(counts
+        .into_iter()
+        .filter(|(_, (stored, loaded))| stored != loaded)
+        .collect())
+}
+
+// One pass: reloads the targets that drifted from the database. A target is
+// only reloaded when it shows the same drift on two passes in a row, so
+// registrations and deletions in flight between their database write and
+// their store update are left alone
+async fn resync(
+    state: &AppState,
+    suspects: &mut HashMap<TargetKey, Drift>,
+) -> Result<usize, sqlx::Error> {
+    let drifted = drifted_targets(state).await?;
+    let mut reloaded = 0;
+    for (key, drift) in &drifted {
+        if suspects.get(key) != Some(drift) {
+            continue;
+        }
+        reload(state, key).await?;
+        tracing::warn!(
+            tenant = %key.0,
+            collection = %key.1,
+            uuid = %key.2,
+            database_rows = drift.0,
+            memory_registrations = drift.1,
+            "Target drifted from the database, reloaded"
+        );
+        reloaded += 1;
+    }
+    *suspects = drifted;
+    Ok(reloaded)
+}
+
+// Reconciles the stores with the database every `interval`, for rows written
+// or removed by other processes
+pub async fn run(state: AppState, interval: Duration) {
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut suspects = HashMap::new();
+    loop {
+        ticker.tick().await;
+        let start = Instant::now();
+        match resync(&state, &mut suspects).await {
+            Ok(reloaded) => tracing::debug!(
+                reloaded,
+                suspects = suspects.len(),
+                duration = ?start.elapsed(),
+                "Resync pass done"
+            ),
+            Err(e) => tracing::error!(error = %e, "Resync pass failed"),
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+use crate::enhance::EnhanceOptions;
+use crate::ffmpeg;
+use crate::handlers::{self, Fusion, ImageInput, SearchPayload};
+use crate::tenant::Tenant;
+use crate::AppState;
+
+// Delay before reconnecting to a camera whose stream ended or failed
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+// Cameras watched by this instance and how their frames are searched
+#[derive(Clone)]
+pub struct RtspConfig {
+    // Camera name -> stream URL (rtsp://, or anything ffmpeg can read)
+    pub cameras: Vec<(String, String)>,
+    // Frames sampled per second of each stream
+    pub fps: f32,
+    pub tenant: String,
+    pub collection: String,
+}
+
+// Spawns one worker per camera; matches reach /events and the webhooks like
+// those of any other search
+pub fn start(state: AppState, config: RtspConfig) {
+    let config = Arc::new(config);
+    for (name, url) in config.cameras.clone() {
+        tokio::spawn(watch_camera(state.clone(), config.clone(), name, url));
+    }
+}
+
+async fn watch_camera(state: AppState, config: Arc<RtspConfig>, name: String, url: String) {
+    loop {
+        match search_stream(&state, &config, &name, &url).await {
+            Ok(frames) => tracing::warn!(camera = %name, frames, "Camera stream ended"),
+            Err(e) => tracing::warn!(camera = %name, error = %e, "Camera stream failed"),
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn search_stream(
+    state: &AppState,
+    config: &RtspConfig,
+    name: &str,
+    url: &str,
+) -> std::io::Result<u64> {
+    let (_child, mut frames) = ffmpeg::sample_frames(url, config.fps)?;
+    tracing::info!(camera = %name, fps = config.fps, "Camera stream opened");
+
+    // Only the latest frame is searched, so a slow search skips frames
+    // instead of falling further and further behind the live picture
+    let (latest_tx, mut latest_rx) = watch::channel::<Option<Arc<Vec<u8>>>>(None);
+    let reader = tokio::spawn(async move {
+        while let Some(frame) = frames.next().await? {
+            latest_tx.send_replace(Some(Arc::new(frame)));
+        }
+        Ok::<_, std::io::Error>(())
+    });
+
+    let tenant = Tenant(config.tenant.clone());
+    let payload = SearchPayload {
+        image_base64: String::new(),
+        embedding: None,
+        threshold: None,
+        limit: None,
+        enhance: EnhanceOptions::default(),
+        group_by_uuid: true,
+        hit_counts: false,
+        origins: None,
+        metadata: None,
+        exclude_uuids: None,
+        exclude_origins: None,
+        time_budget_ms: None,
+        nprobe: None,
+        attributes: false,
+        emotion: false,
+        return_crops: false,
+        return_embedding: false,
+        source: Some(name.to_string()),
+        images_base64: Vec::new(),
+        fusion: Fusion::default(),
+        include_archived: false,
+    };
+    let mut frames = 0u64;
+    while latest_rx.changed().await.is_ok() {
+        let Some(frame) = latest_rx.borrow_and_update().clone() else {
+            continue;
+        };
+        frames += 1;
+        if let Err(status) = handlers::run_search_image(
+            state,
+            &tenant,
+            &config.collection,
+            &payload,
+            ImageInput::Bytes(&frame),
+        )
+        .await
+        {
+            tracing::debug!(camera = %name, %status, "Camera frame search failed");
+        }
+    }
+
+    match reader.await {
+        Ok(result) => result?,
+        Err(e) => return Err(std::io::Error::other(e)),
+    }
+    Ok(frames)
+}
@@ -0,0 +1,48 @@
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use std::str::FromStr;
+
+use crate::AppState;
+
+// Whether this instance may write to the database
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RunMode {
+    ReadWrite,
+    // Search-only replica: no migrations, mutating endpoints answer 405
+    ReadOnly,
+}
+
+impl RunMode {
+    pub fn is_writable(&self) -> bool {
+        *self == RunMode::ReadWrite
+    }
+}
+
+impl FromStr for RunMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "read-write" | "readwrite" => Ok(RunMode::ReadWrite),
+            "read-only" | "readonly" => Ok(RunMode::ReadOnly),
+            other => Err(format!("invalid run mode '{}'", other)),
+        }
+    }
+}
+
+// Route layer of mutating endpoints
+pub async fn reject_writes(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    if !state.run_mode.is_writable() {
+        tracing::warn!(path = %request.uri().path(), "Rejected write on read-only instance");
+        return Err(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    Ok(next.run(request).await)
+}
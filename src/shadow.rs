@@ -0,0 +1,116 @@
+use axum::{
+    body::{self, Body},
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+use crate::flags;
+use crate::tenant::Tenant;
+use crate::AppState;
+
+// Larger requests are never mirrored, so buffering stays bounded
+const MAX_SHADOW_BODY: usize = 16 * 1024 * 1024;
+const SHADOW_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Mirrors a sample of production searches to a staging deployment
+pub struct Shadow {
+    base_url: String,
+    // Share of requests mirrored, between 0 and 1
+    sample_rate: f64,
+    // Sent as X-API-Key to staging instead of the caller's credentials
+    api_key: Option<String>,
+    client: reqwest::Client,
+    // Mirrored requests in flight; when exhausted new ones are dropped
+    in_flight: Arc<Semaphore>,
+}
+
+impl Shadow {
+    pub fn new(
+        base_url: String,
+        sample_rate: f64,
+        api_key: Option<String>,
+        max_in_flight: usize,
+    ) -> Result<Self, reqwest::Error> {
+        Ok(Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            sample_rate: sample_rate.clamp(0.0, 1.0),
+            api_key,
+            client: reqwest::Client::builder().timeout(SHADOW_TIMEOUT).build()?,
+            in_flight: Arc::new(Semaphore::new(max_in_flight)),
+        })
+    }
+
+    fn sampled(&self, request: &Request) -> bool {
+        let small_enough = request
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<usize>().ok())
+            .is_some_and(|length| length <= MAX_SHADOW_BODY);
+        small_enough && rand::random::<f64>() < self.sample_rate
+    }
+}
+
+// Route layer of the search endpoints; the staging response is discarded and
+// never delays or alters the production one
+pub async fn mirror(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let Some(shadow) = state.shadow.clone() else {
+        return Ok(next.run(request).await);
+    };
+    let tenant = request
+        .extensions()
+        .get::<Tenant>()
+        .map(|tenant| tenant.id());
+    if !shadow.sampled(&request) || !state.flags.is_enabled(flags::SEARCH_SHADOWING, tenant) {
+        return Ok(next.run(request).await);
+    }
+    let Ok(permit) = shadow.in_flight.clone().try_acquire_owned() else {
+        tracing::debug!("Shadow requests saturated, not mirroring");
+        return Ok(next.run(request).await);
+    };
+
+    let (parts, body) = request.into_parts();
+    let bytes = body::to_bytes(body, MAX_SHADOW_BODY).await.map_err(|e| {
+        tracing::warn!(error = %e, "Failed to read request body");
+        StatusCode::BAD_REQUEST
+    })?;
+
+    let path = parts
+        .uri
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+    let url = format!("{}{}", shadow.base_url, path);
+    let mut shadow_request = shadow
+        .client
+        .request(parts.method.clone(), &url)
+        .body(bytes.clone());
+    if let Some(content_type) = parts.headers.get(header::CONTENT_TYPE) {
+        shadow_request = shadow_request.header(header::CONTENT_TYPE, content_type);
+    }
+    if let Some(api_key) = &shadow.api_key {
+        shadow_request = shadow_request.header("x-api-key", api_key);
+    }
+    tokio::spawn(async move {
+        let _permit = permit;
+        match shadow_request.send().await {
+            Ok(response) => {
+                tracing::debug!(%url, status = %response.status(), "Shadow request completed")
+            }
+            Err(e) => tracing::debug!(%url, error = %e, "Shadow request failed"),
+        }
+    });
+
+    Ok(next
+        .run(Request::from_parts(parts, Body::from(bytes)))
+        .await)
+}
@@ -0,0 +1,342 @@
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::auth;
+use crate::handlers;
+use crate::resync;
+use crate::store::{Metadata, SearchMatch, SearchOptions, SearchResults};
+use crate::AppState;
+
+pub const DEFAULT_PEER_TIMEOUT: Duration = Duration::from_secs(5);
+// Shared secret of the instances, on every peer search
+const SECRET_HEADER: &str = "x-shard-secret";
+
+// Slice of the gallery an instance holds: the uuids whose hash modulo `count`
+// is `index`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GalleryShard {
+    pub index: usize,
+    pub count: usize,
+}
+
+impl GalleryShard {
+    // Both halves of the uuid, so random (v4) and time-ordered (v7) uuids
+    // spread evenly
+    pub fn owner(&self, uuid: &Uuid) -> usize {
+        let (high, low) = uuid.as_u64_pair();
+        ((high ^ low) % self.count as u64) as usize
+    }
+
+    pub fn owns(&self, uuid: &Uuid) -> bool {
+        self.owner(uuid) == self.index
+    }
+}
+
+// The other shards of the gallery, searched along with the local one
+pub struct Sharding {
+    shard: GalleryShard,
+    // Base URL of every other shard in shard order, e.g. http://shard-1:8080
+    peers: Vec<String>,
+    secret: String,
+    client: reqwest::Client,
+}
+
+impl Sharding {
+    pub fn new(
+        shard: GalleryShard,
+        peers: Vec<String>,
+        secret: String,
+        timeout: Duration,
+    ) -> Result<Self, reqwest::Error> {
+        Ok(Self {
+            shard,
+            peers: peers
+                .into_iter()
+                .map(|peer| peer.trim_end_matches('/').to_string())
+                .collect(),
+            secret,
+            client: reqwest::Client::builder().timeout(timeout).build()?,
+        })
+    }
+
+    pub fn shard(&self) -> GalleryShard {
+        self.shard
+    }
+
+    pub fn owns(&self, uuid: &Uuid) -> bool {
+        self.shard.owns(uuid)
+    }
+
+    // Peer holding a shard; this instance is left out of the list
+    fn peer(&self, index: usize) -> &str {
+        let position = if index < self.shard.index {
+            index
+        } else {
+            index - 1
+        };
+        &self.peers[position]
+    }
+
+    // Similarity of a target of another shard to the embedding, asked of the
+    // shard holding it; None when that shard does not know the target
+    pub async fn score_peer(&self, query: &ScoreQuery) -> Result<Option<f32>, reqwest::Error> {
+        let peer = self.peer(self.shard.owner(&query.target_uuid));
+        let response = self
+            .client
+            .post(format!("{}/shard/score", peer))
+            .header(SECRET_HEADER, &self.secret)
+            .json(query)
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let score: ShardScore = response.error_for_status()?.json().await?;
+        Ok(Some(score.similarity))
+    }
+
+    fn authorized(&self, headers: &HeaderMap) -> bool {
+        let secret = headers
+            .get(SECRET_HEADER)
+            .and_then(|value| value.to_str().ok());
+        // Compared by hash, so the comparison time says nothing of the secret
+        secret.map(auth::hash_key) == Some(auth::hash_key(&self.secret))
+    }
+
+    // Searches every peer at once; a peer that fails or runs out of time
+    // makes the results partial rather than failing the search
+    pub async fn search_peers(&self, query: &ShardQuery) -> SearchResults {
+        let searches = self.peers.iter().map(|peer| async move {
+            let mut request = self
+                .client
+                .post(format!("{}/shard/search", peer))
+                .header(SECRET_HEADER, &self.secret)
+                .json(query);
+            if let Some(budget) = query.budget_ms {
+                request = request.timeout(Duration::from_millis(budget));
+            }
+            let found = match request.send().await {
+                Ok(response) => match response.error_for_status() {
+                    Ok(response) => response.json::<ShardResults>().await,
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e),
+            };
+            (peer, found)
+        });
+
+        let mut merged = SearchResults::default();
+        for (peer, found) in join_all(searches).await {
+            match found {
+                Ok(found) => {
+                    merged.matches.extend(found.matches);
+                    merged.partial |= found.partial;
+                }
+                Err(e) => {
+                    tracing::warn!(%peer, error = %e, "Shard search failed, results are partial");
+                    merged.partial = true;
+                }
+            }
+        }
+        merged
+    }
+}
+
+// A search sent to the other shards: the query embedding with the options
+// resolved by the instance that received the request
+#[derive(Serialize, Deserialize)]
+pub struct ShardQuery {
+    tenant: String,
+    collection: String,
+    embedding: Vec<f32>,
+    threshold: f32,
+    limit: usize,
+    group_by_uuid: bool,
+    origins: Option<Vec<String>>,
+    metadata: Option<Metadata>,
+    exclude_uuids: Option<Vec<Uuid>>,
+    exclude_origins: Option<Vec<String>>,
+    // Left of the request's time budget
+    budget_ms: Option<u64>,
+    similarity_weight: f32,
+    nprobe: Option<usize>,
+    include_archived: bool,
+}
+
+impl ShardQuery {
+    pub fn new(
+        tenant: &str,
+        collection: &str,
+        embedding: &[f32],
+        options: &SearchOptions,
+        include_archived: bool,
+    ) -> Self {
+        Self {
+            tenant: tenant.to_string(),
+            collection: collection.to_string(),
+            embedding: embedding.to_vec(),
+            threshold: options.threshold,
+            limit: options.limit,
+            group_by_uuid: options.group_by_uuid,
+            origins: options
+                .origins
+                .as_ref()
+                .map(|origins| origins.iter().cloned().collect()),
+            metadata: options.metadata_filter.clone(),
+            exclude_uuids: options
+                .exclude_uuids
+                .as_ref()
+                .map(|uuids| uuids.iter().copied().collect()),
+            exclude_origins: options
+                .exclude_origins
+                .as_ref()
+                .map(|origins| origins.iter().cloned().collect()),
+            budget_ms: options.deadline.map(|deadline| {
+                deadline
+                    .saturating_duration_since(Instant::now())
+                    .as_millis() as u64
+            }),
+            similarity_weight: options.similarity_weight,
+            nprobe: options.nprobe,
+            include_archived,
+        }
+    }
+
+    // Thresholds per origin are the receiving instance's own, the same on
+    // every shard of a deployment
+    fn options(&self, origin_thresholds: Arc<HashMap<String, f32>>) -> SearchOptions {
+        SearchOptions {
+            threshold: self.threshold,
+            origin_thresholds,
+            limit: self.limit,
+            group_by_uuid: self.group_by_uuid,
+            origins: self
+                .origins
+                .as_ref()
+                .map(|origins| origins.iter().cloned().collect()),
+            metadata_filter: self.metadata.clone(),
+            exclude_uuids: self
+                .exclude_uuids
+                .as_ref()
+                .map(|uuids| uuids.iter().copied().collect()),
+            exclude_origins: self
+                .exclude_origins
+                .as_ref()
+                .map(|origins| origins.iter().cloned().collect()),
+            deadline: self
+                .budget_ms
+                .map(|budget| Instant::now() + Duration::from_millis(budget)),
+            similarity_weight: self.similarity_weight,
+            nprobe: self.nprobe,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ShardResults {
+    matches: Vec<SearchMatch>,
+    partial: bool,
+}
+
+// A 1:1 verification of a target held by another shard
+#[derive(Serialize, Deserialize)]
+pub struct ScoreQuery {
+    tenant: String,
+    collection: String,
+    target_uuid: Uuid,
+    embedding: Vec<f32>,
+}
+
+impl ScoreQuery {
+    pub fn new(tenant: &str, collection: &str, target_uuid: Uuid, embedding: &[f32]) -> Self {
+        Self {
+            tenant: tenant.to_string(),
+            collection: collection.to_string(),
+            target_uuid,
+            embedding: embedding.to_vec(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ShardScore {
+    similarity: f32,
+}
+
+// Handler for POST /shard/search - the local part of a search received by
+// another shard; only the instances of the deployment, which share
+// GALLERY_SHARD_SECRET, may call it
+pub async fn shard_search(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(query): Json<ShardQuery>,
+) -> Result<Json<ShardResults>, StatusCode> {
+    let Some(sharding) = &state.sharding else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    if !sharding.authorized(&headers) {
+        tracing::warn!("Rejected shard search with a missing or invalid secret");
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    let Some(collection) = state.collections.get(&query.tenant, &query.collection) else {
+        tracing::warn!(tenant = %query.tenant, collection = %query.collection, "Received shard search for unknown collection");
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let options = query.options(state.origin_thresholds.clone());
+    let found = handlers::search_local(
+        &state,
+        &query.tenant,
+        &query.collection,
+        &collection,
+        &query.embedding,
+        &options,
+        query.include_archived,
+    )
+    .await?;
+    Ok(Json(ShardResults {
+        matches: found.matches,
+        partial: found.partial,
+    }))
+}
+
+// Handler for POST /shard/score - scores a target of this shard for the
+// verification received by another one; same secret as /shard/search
+pub async fn shard_score(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(query): Json<ScoreQuery>,
+) -> Result<Json<ShardScore>, StatusCode> {
+    let Some(sharding) = &state.sharding else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    if !sharding.authorized(&headers) {
+        tracing::warn!("Rejected shard score with a missing or invalid secret");
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    let Some(collection) = state.collections.get(&query.tenant, &query.collection) else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let target_uuid = query.target_uuid;
+    if collection.store.is_evicted(&target_uuid).await {
+        let key = (query.tenant.clone(), query.collection.clone(), target_uuid);
+        if let Err(e) = resync::reload(&state, &key).await {
+            tracing::error!(%target_uuid, error = %e, "Failed to page in evicted target");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+    let similarity = collection
+        .store
+        .score_uuid(&target_uuid, &query.embedding)
+        .await
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(ShardScore { similarity }))
+}
@@ -0,0 +1,70 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+// Hyperplanes are drawn from this seed, so every store and instance sketches
+// a vector the same way
+const PLANES_SEED: u64 = 0x6f77_6c66_6163_6572;
+// Standard deviations of the angle estimate kept as a margin: a match right
+// at the threshold is pruned about once in a thousand searches
+const CUTOFF_SIGMAS: f32 = 3.0;
+
+// Binary sketch of a vector, one bit per hyperplane
+pub type Sketch = Box<[u64]>;
+
+// SimHash: bit i of a sketch is whether the vector lies above random
+// hyperplane i, and the share of bits two sketches differ in estimates the
+// angle between their vectors over pi. A Hamming distance over a few words
+// is far cheaper than a cosine over the full vectors, so searches prune the
+// entries that cannot reach the threshold before scoring them.
+pub struct Sketcher {
+    // `bits` hyperplanes of `dimension` Gaussian components, row by row
+    planes: Vec<f32>,
+    bits: usize,
+    dimension: usize,
+}
+
+impl Sketcher {
+    pub fn new(bits: usize, dimension: usize) -> Self {
+        let mut rng = StdRng::seed_from_u64(PLANES_SEED);
+        // Box-Muller, as the hyperplane normals must be uniform over the
+        // sphere
+        let planes = (0..bits * dimension)
+            .map(|_| {
+                let u: f32 = rng.gen_range(f32::EPSILON..1.0);
+                let v: f32 = rng.gen();
+                (-2.0 * u.ln()).sqrt() * (std::f32::consts::TAU * v).cos()
+            })
+            .collect();
+        Self {
+            planes,
+            bits,
+            dimension,
+        }
+    }
+
+    pub fn sketch(&self, embedding: &[f32]) -> Sketch {
+        let mut sketch = vec![0u64; self.bits.div_ceil(64)].into_boxed_slice();
+        for (bit, plane) in self.planes.chunks_exact(self.dimension).enumerate() {
+            let side: f32 = plane.iter().zip(embedding).map(|(p, x)| p * x).sum();
+            if side > 0.0 {
+                sketch[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+        sketch
+    }
+
+    // Largest Hamming distance at which two vectors may still have the
+    // cosine similarity `similarity`
+    pub fn cutoff(&self, similarity: f32) -> u32 {
+        if similarity <= -1.0 {
+            return self.bits as u32;
+        }
+        let share = similarity.min(1.0).acos() / std::f32::consts::PI;
+        let bits = self.bits as f32;
+        let margin = CUTOFF_SIGMAS * (bits * share * (1.0 - share)).sqrt();
+        (bits * share + margin).ceil().min(bits) as u32
+    }
+}
+
+pub fn hamming(a: &[u64], b: &[u64]) -> u32 {
+    a.iter().zip(b).map(|(x, y)| (x ^ y).count_ones()).sum()
+}
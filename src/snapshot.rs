@@ -0,0 +1,617 @@
+use futures_util::TryStreamExt;
+use sqlx::{PgPool, Row};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+use crate::collections::Collections;
+use crate::encryption;
+use crate::ivf::Centroids;
+use crate::store::{Metadata, NewEmbedding};
+
+// Where snapshots are written from
+#[derive(Clone)]
+pub enum Source {
+    Database(PgPool),
+    // The stores themselves, in memory-only mode
+    Memory(Arc<Collections>),
+}
+
+pub const DEFAULT_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(600);
+const MAGIC: &[u8; 8] = b"OWLSNAP\0";
+// Bumped on any change of the layout; other versions are ignored
+const VERSION: u32 = 5;
+// The same layout with sealed vectors, written with EMBEDDING_ENCRYPTION_KEY
+const SEALED_VERSION: u32 = 6;
+// Older layouts, still read so memory-only galleries survive the upgrade:
+// without the expiry of the rows, which then never expire, and before that
+// without their model, which leaves them untagged
+const UNEXPIRING_VERSION: u32 = 3;
+const UNEXPIRING_SEALED_VERSION: u32 = 4;
+const UNTAGGED_VERSION: u32 = 1;
+const UNTAGGED_SEALED_VERSION: u32 = 2;
+const INDEX_MAGIC: &[u8; 8] = b"OWLIVF\0\0";
+const INDEX_VERSION: u32 = 1;
+const SEALED_INDEX_VERSION: u32 = 2;
+// Entries preallocated at most; the counts come from the file and a
+// corrupt one must fail on the truncated read, not the allocation
+const PREALLOCATED_ENTRIES: usize = 1 << 20;
+
+type SnapshotError = Box<dyn std::error::Error + Send + Sync>;
+
+// Rows of the targets table up to `max_id`, as written by `write`. Layout,
+// little-endian: magic, version (u32), max_id (i64), row count (u64), then per
+// row tenant, collection, origin, metadata JSON and model (empty when
+// untagged) as u32-length-prefixed UTF-8, the uuid (16 bytes), the vector
+// as a u32 length and f32 values, and the expiry as a presence byte and Unix
+// seconds (i64).
+// Sealed snapshots hold the vector as a u32 length and the sealed bytes.
+struct Snapshot {
+    max_id: i64,
+    rows: Vec<SnapshotRow>,
+}
+
+struct SnapshotRow {
+    tenant: String,
+    collection: String,
+    uuid: Uuid,
+    origin: String,
+    metadata: Metadata,
+    embeddings: Vec<f32>,
+    model_version: Option<String>,
+    expires_at: Option<i64>,
+}
+
+// Shared with the backup archives, which use the same field encoding
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.bytes.len() < len {
+            return Err("truncated snapshot".into());
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    pub(crate) fn string(&mut self) -> Result<String, SnapshotError> {
+        let len = self.u32()? as usize;
+        Ok(String::from_utf8(self.take(len)?.to_vec())?)
+    }
+
+    pub(crate) fn expiry(&mut self) -> Result<Option<i64>, SnapshotError> {
+        let expiring = self.take(1)?[0] != 0;
+        let expires_at = i64::from_le_bytes(self.take(8)?.try_into()?);
+        Ok(expiring.then_some(expires_at))
+    }
+
+    pub(crate) fn vector(&mut self, sealed: bool) -> Result<Vec<f32>, SnapshotError> {
+        if sealed {
+            let len = self.u32()? as usize;
+            return Ok(encryption::open(self.take(len)?)?);
+        }
+        let dimension = self.u32()? as usize;
+        Ok(self
+            .take(dimension * 4)?
+            .chunks_exact(4)
+            .map(|value| f32::from_le_bytes([value[0], value[1], value[2], value[3]]))
+            .collect())
+    }
+}
+
+fn read(path: &Path) -> Result<Snapshot, SnapshotError> {
+    let bytes = std::fs::read(path)?;
+    let mut reader = Reader::new(&bytes);
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err("not a snapshot file".into());
+    }
+    let version = reader.u32()?;
+    let (sealed, tagged, expiring) = match version {
+        VERSION => (false, true, true),
+        SEALED_VERSION => (true, true, true),
+        UNEXPIRING_VERSION => (false, true, false),
+        UNEXPIRING_SEALED_VERSION => (true, true, false),
+        UNTAGGED_VERSION => (false, false, false),
+        UNTAGGED_SEALED_VERSION => (true, false, false),
+        _ => return Err(format!("snapshot version {} instead of {}", version, VERSION).into()),
+    };
+    let max_id = reader.u64()? as i64;
+    let count = reader.u64()? as usize;
+    let mut rows = Vec::with_capacity(count.min(PREALLOCATED_ENTRIES));
+    for _ in 0..count {
+        let tenant = reader.string()?;
+        let collection = reader.string()?;
+        let origin = reader.string()?;
+        let metadata = serde_json::from_str(&reader.string()?)?;
+        let model_version = if tagged {
+            Some(reader.string()?).filter(|model| !model.is_empty())
+        } else {
+            None
+        };
+        let uuid = Uuid::from_slice(reader.take(16)?)?;
+        let embeddings = reader.vector(sealed)?;
+        let expires_at = if expiring { reader.expiry()? } else { None };
+        rows.push(SnapshotRow {
+            tenant,
+            collection,
+            uuid,
+            origin,
+            metadata,
+            embeddings,
+            model_version,
+            expires_at,
+        });
+    }
+    Ok(Snapshot { max_id, rows })
+}
+
+pub(crate) fn put_string(buffer: &mut Vec<u8>, value: &str) {
+    buffer.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    buffer.extend_from_slice(value.as_bytes());
+}
+
+// Whether the snapshot at `path` holds sealed vectors
+pub fn is_sealed(path: &Path) -> bool {
+    let mut header = [0u8; 12];
+    std::fs::File::open(path)
+        .and_then(|mut file| std::io::Read::read_exact(&mut file, &mut header))
+        .is_ok_and(|()| {
+            header[..8] == MAGIC[..]
+                && [
+                    SEALED_VERSION,
+                    UNEXPIRING_SEALED_VERSION,
+                    UNTAGGED_SEALED_VERSION,
+                ]
+                .iter()
+                .any(|version| header[8..] == version.to_le_bytes())
+        })
+}
+
+fn put_header(buffer: &mut Vec<u8>, max_id: i64, count: i64) {
+    let version = if encryption::enabled() {
+        SEALED_VERSION
+    } else {
+        VERSION
+    };
+    buffer.extend_from_slice(MAGIC);
+    buffer.extend_from_slice(&version.to_le_bytes());
+    buffer.extend_from_slice(&max_id.to_le_bytes());
+    buffer.extend_from_slice(&count.to_le_bytes());
+}
+
+pub(crate) fn put_expiry(buffer: &mut Vec<u8>, expires_at: Option<i64>) {
+    buffer.push(expires_at.is_some() as u8);
+    buffer.extend_from_slice(&expires_at.unwrap_or(0).to_le_bytes());
+}
+
+pub(crate) fn put_row(
+    buffer: &mut Vec<u8>,
+    tenant: &str,
+    collection: &str,
+    origin: &str,
+    metadata: &Metadata,
+    model_version: Option<&str>,
+    uuid: &Uuid,
+    embeddings: &[f32],
+) -> Result<(), SnapshotError> {
+    put_string(buffer, tenant);
+    put_string(buffer, collection);
+    put_string(buffer, origin);
+    put_string(buffer, &serde_json::to_string(metadata)?);
+    put_string(buffer, model_version.unwrap_or(""));
+    buffer.extend_from_slice(uuid.as_bytes());
+    put_vector(buffer, embeddings)
+}
+
+// Sealed when EMBEDDING_ENCRYPTION_KEY is set
+fn put_vector(buffer: &mut Vec<u8>, values: &[f32]) -> Result<(), SnapshotError> {
+    match encryption::seal(values) {
+        Some(sealed) => {
+            let sealed = sealed?;
+            buffer.extend_from_slice(&(sealed.len() as u32).to_le_bytes());
+            buffer.extend_from_slice(&sealed);
+        }
+        None => {
+            buffer.extend_from_slice(&(values.len() as u32).to_le_bytes());
+            for value in values {
+                buffer.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+    }
+    Ok(())
+}
+
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    PathBuf::from(temporary)
+}
+
+// Dumps the targets table next to `path`, then renames the file over it so a
+// crash never leaves a partial snapshot behind
+async fn write(pool: &PgPool, path: &Path) -> Result<u64, SnapshotError> {
+    // A single repeatable read transaction, so the header matches the rows
+    let mut transaction = pool.begin().await?;
+    sqlx::query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ READ ONLY")
+        .execute(&mut *transaction)
+        .await?;
+    // Tombstones count towards the id, so replay starts after them too
+    let (max_id, count): (i64, i64) = sqlx::query_as(
+        "SELECT COALESCE(MAX(id), 0), COUNT(*) FILTER (WHERE deleted_at IS NULL) FROM targets",
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
+
+    let temporary = temporary_path(path);
+    let mut file = tokio::io::BufWriter::new(tokio::fs::File::create(&temporary).await?);
+    let mut buffer = Vec::new();
+    put_header(&mut buffer, max_id, count);
+    file.write_all(&buffer).await?;
+
+    let mut rows = sqlx::query(
+        "SELECT uuid, embeddings, embeddings_sealed, origin, metadata, collection, tenant, model, EXTRACT(EPOCH FROM expires_at)::BIGINT AS expires_at FROM targets WHERE deleted_at IS NULL ORDER BY id",
+    )
+    .fetch(&mut *transaction);
+    while let Some(row) = rows.try_next().await? {
+        let uuid: Uuid = row.try_get("uuid")?;
+        let embeddings = encryption::from_row(&row)?;
+        let metadata: sqlx::types::Json<Metadata> = row.try_get("metadata")?;
+        buffer.clear();
+        put_row(
+            &mut buffer,
+            row.try_get("tenant")?,
+            row.try_get("collection")?,
+            row.try_get("origin")?,
+            &metadata.0,
+            row.try_get("model")?,
+            &uuid,
+            &embeddings,
+        )?;
+        put_expiry(&mut buffer, row.try_get("expires_at")?);
+        file.write_all(&buffer).await?;
+    }
+    drop(rows);
+    transaction.commit().await?;
+
+    file.flush().await?;
+    file.into_inner().sync_all().await?;
+    tokio::fs::rename(&temporary, path).await?;
+    Ok(count as u64)
+}
+
+// Dumps the stores themselves, one row per vector: a `Mean` template is written
+// as a single registration of its mean. The max id is 0, nothing is replayed.
+async fn write_memory(collections: &Collections, path: &Path) -> Result<u64, SnapshotError> {
+    let mut stores = Vec::new();
+    for (tenant, name, collection) in collections.all() {
+        stores.push((tenant, name, collection.store.snapshot().await));
+    }
+    let count: usize = stores
+        .iter()
+        .flat_map(|(_, _, entries)| entries)
+        .map(|entry| entry.embeddings.len())
+        .sum();
+
+    let temporary = temporary_path(path);
+    let mut file = tokio::io::BufWriter::new(tokio::fs::File::create(&temporary).await?);
+    let mut buffer = Vec::new();
+    put_header(&mut buffer, 0, count as i64);
+    file.write_all(&buffer).await?;
+    for (tenant, name, entries) in &stores {
+        for entry in entries {
+            for embeddings in &entry.embeddings {
+                buffer.clear();
+                put_row(
+                    &mut buffer,
+                    tenant,
+                    name,
+                    &entry.origin,
+                    &entry.metadata,
+                    entry.model_version.as_deref(),
+                    &entry.uuid,
+                    &embeddings.to_f32(),
+                )?;
+                put_expiry(&mut buffer, entry.expires_at);
+                file.write_all(&buffer).await?;
+            }
+        }
+    }
+
+    file.flush().await?;
+    file.into_inner().sync_all().await?;
+    tokio::fs::rename(&temporary, path).await?;
+    Ok(count as u64)
+}
+
+// Reads the snapshot at `path` off the runtime, None when there is none
+async fn read_blocking(path: &Path) -> Option<Snapshot> {
+    match tokio::task::spawn_blocking({
+        let path = path.to_path_buf();
+        move || read(&path)
+    })
+    .await
+    {
+        Ok(Ok(snapshot)) => Some(snapshot),
+        Ok(Err(e)) => {
+            tracing::warn!(path = ?path, error = %e, "No usable snapshot");
+            None
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "Snapshot read task failed");
+            None
+        }
+    }
+}
+
+async fn add_rows(collections: &Collections, rows: Vec<SnapshotRow>) {
+    for row in rows {
+        let store = &collections
+            .get_or_create(&row.tenant, &row.collection)
+            .store;
+        let dimension = row.embeddings.len();
+        let added = store
+            .add_embedding(NewEmbedding {
+                uuid: row.uuid,
+                origin: row.origin,
+                metadata: row.metadata,
+                embedding: row.embeddings,
+                model_version: row.model_version,
+                expires_at: row.expires_at,
+            })
+            .await;
+        if !added {
+            tracing::warn!(tenant = %row.tenant, collection = %row.collection, uuid = %row.uuid, dimension, expected = ?store.dimension(), "Skipped a snapshot embedding of the wrong dimension");
+        }
+    }
+    collections.enforce_memory_limits().await;
+}
+
+// Loads the snapshot at `path` into the collections and returns the id to
+// replay the targets table from. None when there is no usable snapshot or the
+// rows it covers changed since (deletions, merges, late commits), in which
+// case the whole table has to be loaded
+pub async fn restore(path: &Path, pool: &PgPool, collections: &Collections) -> Option<i64> {
+    let Some(snapshot) = read_blocking(path).await else {
+        tracing::info!("Loading from the database");
+        return None;
+    };
+
+    let covered: Result<i64, sqlx::Error> =
+        sqlx::query_scalar("SELECT COUNT(*) FROM targets WHERE id <= $1 AND deleted_at IS NULL")
+            .bind(snapshot.max_id)
+            .fetch_one(pool)
+            .await;
+    match covered {
+        Ok(covered) if covered as usize == snapshot.rows.len() => {}
+        Ok(covered) => {
+            tracing::warn!(
+                snapshot_rows = snapshot.rows.len(),
+                database_rows = covered,
+                "Snapshot is stale, loading from the database"
+            );
+            return None;
+        }
+        Err(e) => {
+            tracing::warn!(error = %e, "Failed to check the snapshot, loading from the database");
+            return None;
+        }
+    }
+
+    let rows = snapshot.rows.len();
+    add_rows(collections, snapshot.rows).await;
+    tracing::info!(
+        rows,
+        max_id = snapshot.max_id,
+        "Embeddings restored from snapshot"
+    );
+    Some(snapshot.max_id)
+}
+
+// Loads the snapshot at `path` as is, in memory-only mode where it is the
+// only copy of the registrations; returns the rows restored
+pub async fn restore_memory(path: &Path, collections: &Collections) -> usize {
+    let Some(snapshot) = read_blocking(path).await else {
+        return 0;
+    };
+    let rows = snapshot.rows.len();
+    add_rows(collections, snapshot.rows).await;
+    tracing::info!(rows, "Embeddings restored from snapshot");
+    rows
+}
+
+// Rewrites the snapshot every `interval`
+pub fn spawn_writer(source: Source, path: PathBuf, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            write_now(&source, &path).await;
+        }
+    });
+}
+
+// Writes the snapshot right away, e.g. at shutdown; failures are only logged
+pub async fn write_now(source: &Source, path: &Path) {
+    let start = std::time::Instant::now();
+    let written = match source {
+        Source::Database(pool) => write(pool, path).await,
+        Source::Memory(collections) => write_memory(collections, path).await,
+    };
+    match written {
+        Ok(rows) => {
+            tracing::info!(rows, path = ?path, duration = ?start.elapsed(), "Snapshot written")
+        }
+        Err(e) => tracing::error!(path = ?path, error = %e, "Failed to write snapshot"),
+    }
+}
+
+// IVF centroids of a store, as saved by `write_index`
+struct StoredIndex {
+    tenant: String,
+    collection: String,
+    trained_vectors: usize,
+    centroids: Centroids,
+}
+
+// Where the IVF centroids are saved, next to the snapshot at `path`
+pub fn index_path(path: &Path) -> PathBuf {
+    let mut index = path.as_os_str().to_owned();
+    index.push(".ivf");
+    PathBuf::from(index)
+}
+
+// Layout, little-endian: magic, version (u32), model (u32-length-prefixed
+// UTF-8), store count (u64), then per store tenant and collection as
+// u32-length-prefixed UTF-8, the vectors trained on (u64), the dimension
+// (u32) and the centroids, row by row, as a vector like those of the
+// snapshot rows (sealed in version 2).
+fn read_index(path: &Path) -> Result<(String, Vec<StoredIndex>), SnapshotError> {
+    let bytes = std::fs::read(path)?;
+    let mut reader = Reader::new(&bytes);
+    if reader.take(INDEX_MAGIC.len())? != INDEX_MAGIC {
+        return Err("not an index file".into());
+    }
+    let sealed = match reader.u32()? {
+        INDEX_VERSION => false,
+        SEALED_INDEX_VERSION => true,
+        version => {
+            return Err(format!("index version {} instead of {}", version, INDEX_VERSION).into())
+        }
+    };
+    let model = reader.string()?;
+    let count = reader.u64()? as usize;
+    let mut stores = Vec::with_capacity(count.min(PREALLOCATED_ENTRIES));
+    for _ in 0..count {
+        let tenant = reader.string()?;
+        let collection = reader.string()?;
+        let trained_vectors = reader.u64()? as usize;
+        let dimension = reader.u32()? as usize;
+        let values = reader.vector(sealed)?;
+        if dimension == 0 || values.is_empty() || values.len() % dimension != 0 {
+            return Err(format!("malformed centroids of {}/{}", tenant, collection).into());
+        }
+        stores.push(StoredIndex {
+            tenant,
+            collection,
+            trained_vectors,
+            centroids: Centroids::from_values(values, dimension),
+        });
+    }
+    Ok((model, stores))
+}
+
+// Saves the IVF centroids of every trained store, the same way as the
+// snapshot; returns the stores saved. Only the centroids are kept: filing
+// the entries under them again is far cheaper than training.
+pub async fn write_index(
+    collections: &Collections,
+    path: &Path,
+    model: &str,
+) -> Result<usize, SnapshotError> {
+    let stores: Vec<_> = collections
+        .all()
+        .into_iter()
+        .filter_map(|(tenant, name, collection)| {
+            collection
+                .store
+                .ivf_state()
+                .map(|state| (tenant, name, state))
+        })
+        .collect();
+    let version = if encryption::enabled() {
+        SEALED_INDEX_VERSION
+    } else {
+        INDEX_VERSION
+    };
+    let mut buffer = Vec::new();
+    buffer.extend_from_slice(INDEX_MAGIC);
+    buffer.extend_from_slice(&version.to_le_bytes());
+    put_string(&mut buffer, model);
+    buffer.extend_from_slice(&(stores.len() as u64).to_le_bytes());
+    for (tenant, name, (centroids, trained_vectors)) in &stores {
+        put_string(&mut buffer, tenant);
+        put_string(&mut buffer, name);
+        buffer.extend_from_slice(&(*trained_vectors as u64).to_le_bytes());
+        buffer.extend_from_slice(&(centroids.dimension() as u32).to_le_bytes());
+        put_vector(&mut buffer, centroids.values())?;
+    }
+
+    let temporary = temporary_path(path);
+    let mut file = tokio::fs::File::create(&temporary).await?;
+    file.write_all(&buffer).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&temporary, path).await?;
+    Ok(stores.len())
+}
+
+// Puts the saved IVF centroids back into the loaded stores; returns the
+// stores restored. Centroids of another model are left for training, as
+// they partition vectors that are no longer searched.
+pub async fn restore_index(path: &Path, collections: &Collections, model: &str) -> usize {
+    let read = tokio::task::spawn_blocking({
+        let path = path.to_path_buf();
+        move || read_index(&path)
+    })
+    .await;
+    let (saved_model, stores) = match read {
+        Ok(Ok(index)) => index,
+        Ok(Err(e)) => {
+            tracing::info!(path = ?path, error = %e, "No usable IVF index, training it");
+            return 0;
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "IVF index read task failed");
+            return 0;
+        }
+    };
+    if saved_model != model {
+        tracing::info!(path = ?path, saved_model, model, "IVF index of another model, training it");
+        return 0;
+    }
+
+    let mut restored = 0;
+    for stored in stores {
+        let Some(collection) = collections.get(&stored.tenant, &stored.collection) else {
+            continue;
+        };
+        let StoredIndex {
+            tenant,
+            collection: name,
+            trained_vectors,
+            centroids,
+        } = stored;
+        let installed = tokio::task::spawn_blocking(move || {
+            collection.store.restore_ivf(centroids, trained_vectors)
+        })
+        .await;
+        match installed {
+            Ok(true) => restored += 1,
+            Ok(false) => {
+                tracing::warn!(%tenant, collection = %name, "Saved IVF centroids do not fit the store, training it")
+            }
+            Err(e) => {
+                tracing::error!(%tenant, collection = %name, error = %e, "IVF index restore failed")
+            }
+        }
+    }
+    tracing::info!(stores = restored, path = ?path, "IVF index restored");
+    restored
+}
@@ -0,0 +1,69 @@
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use std::str::FromStr;
+
+use crate::AppState;
+
+// Where registrations are kept
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Storage {
+    Postgres,
+    // Registrations in a MySQL or MariaDB targets table; everything else the
+    // Postgres schema holds is unavailable
+    MySql,
+    // In-memory store only, optionally persisted to the snapshot file; the
+    // database is never connected to
+    Memory,
+}
+
+impl Storage {
+    // Default for a connection string, by its scheme
+    pub fn from_url(url: &str) -> Self {
+        if url.starts_with("mysql://") || url.starts_with("mariadb://") {
+            Storage::MySql
+        } else {
+            Storage::Postgres
+        }
+    }
+
+    // Collections, API keys, audit log and the other tables of the Postgres
+    // schema are available
+    pub fn is_postgres(&self) -> bool {
+        *self == Storage::Postgres
+    }
+
+    // Registrations are written to a database through the target store
+    pub fn persists_targets(&self) -> bool {
+        *self != Storage::Memory
+    }
+}
+
+impl FromStr for Storage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "postgres" | "postgresql" => Ok(Storage::Postgres),
+            "mysql" | "mariadb" => Ok(Storage::MySql),
+            "memory" => Ok(Storage::Memory),
+            other => Err(format!("invalid storage '{}'", other)),
+        }
+    }
+}
+
+// Route layer of endpoints that only work against the Postgres schema
+pub async fn require_database(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    if !state.storage.is_postgres() {
+        tracing::warn!(path = %request.uri().path(), storage = ?state.storage, "Rejected request needing Postgres");
+        return Err(StatusCode::NOT_IMPLEMENTED);
+    }
+    Ok(next.run(request).await)
+}
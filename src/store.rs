@@ -0,0 +1,1889 @@
+use half::f16;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use uuid::Uuid;
+
+use crate::attributes::Attributes;
+use crate::detect::{DetectedFace, FaceCrop};
+use crate::emotion::Emotion;
+#[cfg(feature = "gpu-search")]
+use crate::gpu::{GpuContext, GpuVectors};
+use crate::ivf::{self, Centroids, InvertedLists, IvfConfig};
+use crate::mask::MaskCheck;
+use crate::pca::{self, PcaConfig, Projection, ReducedVectors};
+use crate::pose::Pose;
+use crate::sharding::GalleryShard;
+use crate::sketch::{self, Sketch, Sketcher};
+
+// How registrations of the same uuid are represented in the store
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TemplateMode {
+    // Every registration is an independent entry
+    Off,
+    // One entry per uuid holding the running mean of all its embeddings
+    Mean,
+    // One entry per uuid holding all its embeddings, scored by the best match
+    Max,
+}
+
+impl TemplateMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TemplateMode::Off => "off",
+            TemplateMode::Mean => "mean",
+            TemplateMode::Max => "max",
+        }
+    }
+}
+
+impl FromStr for TemplateMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "off" | "none" => Ok(TemplateMode::Off),
+            "mean" => Ok(TemplateMode::Mean),
+            "max" => Ok(TemplateMode::Max),
+            other => Err(format!("invalid template mode '{}'", other)),
+        }
+    }
+}
+
+// How the in-memory vectors are held
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Precision {
+    F32,
+    // Half the memory; similarities are computed in f32 from the widened
+    // values and move by less than 1e-3
+    F16,
+}
+
+impl Precision {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Precision::F32 => "f32",
+            Precision::F16 => "f16",
+        }
+    }
+
+    // Bytes held per vector value
+    pub fn value_bytes(&self) -> usize {
+        match self {
+            Precision::F32 => std::mem::size_of::<f32>(),
+            Precision::F16 => std::mem::size_of::<f16>(),
+        }
+    }
+}
+
+impl FromStr for Precision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "f32" | "single" => Ok(Precision::F32),
+            "f16" | "half" => Ok(Precision::F16),
+            other => Err(format!("invalid store precision '{}'", other)),
+        }
+    }
+}
+
+// A stored vector, in the precision of its store
+#[derive(Clone, Debug)]
+pub enum Vector {
+    F32(Vec<f32>),
+    F16(Vec<f16>),
+}
+
+impl Vector {
+    fn new(values: Vec<f32>, precision: Precision) -> Self {
+        match precision {
+            Precision::F32 => Vector::F32(values),
+            Precision::F16 => Vector::F16(values.into_iter().map(f16::from_f32).collect()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Vector::F32(values) => values.len(),
+            Vector::F16(values) => values.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // The values as f32, borrowed when they are held in f32
+    pub fn to_f32(&self) -> Cow<'_, [f32]> {
+        match self {
+            Vector::F32(values) => Cow::Borrowed(values),
+            Vector::F16(values) => Cow::Owned(values.iter().map(|&x| f32::from(x)).collect()),
+        }
+    }
+
+    pub fn into_f32(self) -> Vec<f32> {
+        match self {
+            Vector::F32(values) => values,
+            Vector::F16(values) => values.into_iter().map(f32::from).collect(),
+        }
+    }
+
+    fn similarity(&self, query: &[f32]) -> f32 {
+        match self {
+            Vector::F32(values) => cosine_similarity(query, values),
+            // Widened value by value, without a temporary copy
+            Vector::F16(values) => similarity(query, values),
+        }
+    }
+}
+
+pub use crate::api::Metadata;
+
+// Tick of a process-wide clock, ordering entries of every store by their last use
+static USE_CLOCK: AtomicU64 = AtomicU64::new(0);
+
+fn tick() -> u64 {
+    USE_CLOCK.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+// When an entry was last registered or matched, updated under the read lock
+#[derive(Debug)]
+pub struct LastUsed(AtomicU64);
+
+impl LastUsed {
+    fn now() -> Self {
+        Self(AtomicU64::new(tick()))
+    }
+
+    fn touch(&self) {
+        self.0.store(tick(), Ordering::Relaxed);
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Clone for LastUsed {
+    fn clone(&self) -> Self {
+        Self(AtomicU64::new(self.get()))
+    }
+}
+
+// Bookkeeping overhead of an entry besides its vectors, for memory estimates
+const ENTRY_OVERHEAD_BYTES: usize = 128;
+
+// Estructura para associar uuid com embeddings
+#[derive(Clone)]
+pub struct EmbeddingEntry {
+    pub uuid: Uuid,
+    pub origin: String,
+    pub metadata: Metadata,
+    // A single vector per registration, the running mean in `Mean` mode, or
+    // every enrolled vector of the uuid in `Max` mode
+    pub embeddings: Vec<Vector>,
+    // Sketch of each vector, with the search prefilter
+    sketches: Vec<Sketch>,
+    // Number of registrations folded into this entry
+    pub samples: u32,
+    // Model that computed the vectors (`model_version` of /version), None
+    // for registrations written before it was recorded
+    pub model_version: Option<Arc<str>>,
+    // Unix seconds after which the entry is dropped by `remove_expired`; a
+    // template takes the earliest of its registrations
+    pub expires_at: Option<i64>,
+    // Removed entry left in place until its shard is compacted
+    deleted: bool,
+    last_used: LastUsed,
+}
+
+// Model of the running instance, and the one of the untagged entries
+#[derive(Clone, Copy)]
+struct Models<'a> {
+    current: Option<&'a str>,
+    legacy: Option<&'a str>,
+}
+
+impl EmbeddingEntry {
+    // Whether any vector of this entry may be within the Hamming cutoff of
+    // the query sketch
+    fn passes_prefilter(&self, query: &[u64], cutoff: u32) -> bool {
+        self.sketches
+            .iter()
+            .any(|sketch| sketch::hamming(sketch, query) <= cutoff)
+    }
+
+    // Best similarity between the query and any vector of this entry
+    fn score(&self, query: &[f32]) -> f32 {
+        self.embeddings
+            .iter()
+            .map(|embedding| embedding.similarity(query))
+            .fold(f32::MIN, f32::max)
+    }
+
+    // Every key of the filter must be present with an equal value
+    fn matches_metadata(&self, filter: &Metadata) -> bool {
+        filter
+            .iter()
+            .all(|(key, value)| self.metadata.get(key) == Some(value))
+    }
+
+    // Vectors of another model are not comparable with the query. Untagged
+    // ones are of the legacy model, or of the running one when it is unknown.
+    fn is_comparable(&self, models: Models<'_>) -> bool {
+        match (
+            self.model_version.as_deref().or(models.legacy),
+            models.current,
+        ) {
+            (Some(entry), Some(current)) => entry == current,
+            _ => true,
+        }
+    }
+
+    // Cheap pre-filters, checked before any vector is touched
+    fn is_candidate(&self, options: &SearchOptions, models: Models<'_>) -> bool {
+        if self.deleted || !self.is_comparable(models) {
+            return false;
+        }
+        if let Some(origins) = &options.origins {
+            if !origins.contains(&self.origin) {
+                return false;
+            }
+        }
+        if let Some(excluded) = &options.exclude_uuids {
+            if excluded.contains(&self.uuid) {
+                return false;
+            }
+        }
+        if let Some(excluded) = &options.exclude_origins {
+            if excluded.contains(&self.origin) {
+                return false;
+            }
+        }
+        match &options.metadata_filter {
+            Some(filter) => self.matches_metadata(filter),
+            None => true,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Shard {
+    entries: Vec<EmbeddingEntry>,
+    // Position of each uuid's entry, only maintained in template modes
+    index: HashMap<Uuid, usize>,
+    // Deleted entries still occupying a slot in `entries`
+    holes: usize,
+    // Registrations (origin, samples) of the uuids evicted to save memory;
+    // they stay in the database and count towards quotas
+    evicted: HashMap<Uuid, Vec<(String, u32)>>,
+    // Bumped by every mutation, so compaction can detect concurrent writes
+    version: u64,
+    // Entries per IVF list, once the store's centroids are trained
+    ivf: Option<InvertedLists>,
+    // Reduced vectors of the entries, once the store's projection is fitted
+    pca: Option<ReducedVectors>,
+    // Copy of the vectors in GPU memory, scored there by searches
+    #[cfg(feature = "gpu-search")]
+    gpu: Option<GpuVectors>,
+}
+
+impl Shard {
+    fn needs_compaction(&self, ratio: f32) -> bool {
+        self.holes > 0 && self.holes as f32 >= self.entries.len() as f32 * ratio
+    }
+
+    // Reduces the vectors of the entry at `position` again after they changed
+    fn reduce(&mut self, position: usize) {
+        if let Some(pca) = &mut self.pca {
+            pca.set(position, &self.entries[position].embeddings);
+        }
+    }
+}
+
+// Implementação de funções de similaridade para embeddings
+// Independent partial sums of the similarity loop. A single running sum is a
+// dependency chain the compiler may not reorder; lanes vectorize (NEON on
+// ARM, SSE/AVX on x86)
+const LANES: usize = 8;
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    similarity(a, b)
+}
+
+fn similarity<T: Copy + Into<f32>>(a: &[f32], b: &[T]) -> f32 {
+    if a.len() != b.len() {
+        panic!("Vectors with different sizes!");
+    }
+
+    let mut dot_product = [0.0f32; LANES];
+    let mut norm_a = [0.0f32; LANES];
+    let mut norm_b = [0.0f32; LANES];
+
+    let chunks_a = a.chunks_exact(LANES);
+    let chunks_b = b.chunks_exact(LANES);
+    let remainder = chunks_a.remainder().iter().zip(chunks_b.remainder());
+    for (chunk_a, chunk_b) in chunks_a.zip(chunks_b) {
+        for (lane, (x, &y)) in chunk_a.iter().zip(chunk_b).enumerate() {
+            let y: f32 = y.into();
+            dot_product[lane] += x * y;
+            norm_a[lane] += x * x;
+            norm_b[lane] += y * y;
+        }
+    }
+    for (x, &y) in remainder {
+        let y: f32 = y.into();
+        dot_product[0] += x * y;
+        norm_a[0] += x * x;
+        norm_b[0] += y * y;
+    }
+
+    let dot_product: f32 = dot_product.iter().sum();
+    let norm_a: f32 = norm_a.iter().sum();
+    let norm_b: f32 = norm_b.iter().sum();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+
+    dot_product / (norm_a.sqrt() * norm_b.sqrt())
+}
+
+// Parameters of a similarity search
+#[derive(Clone, Debug)]
+pub struct SearchOptions {
+    pub threshold: f32,
+    // Server-side thresholds that replace `threshold` for entries of an origin
+    pub origin_thresholds: Arc<HashMap<String, f32>>,
+    pub limit: usize,
+    // Return only the best-scoring entry of each uuid
+    pub group_by_uuid: bool,
+    // Only score entries enrolled from one of these origins
+    pub origins: Option<HashSet<String>>,
+    // Only score entries whose metadata contains all of these key/value pairs
+    pub metadata_filter: Option<Metadata>,
+    // Never score entries of these uuids or origins
+    pub exclude_uuids: Option<HashSet<Uuid>>,
+    pub exclude_origins: Option<HashSet<String>>,
+    // Stop scanning once this instant has passed and return what was found so far
+    pub deadline: Option<Instant>,
+    // Scales similarities before thresholding, below 1 for unreliable queries
+    pub similarity_weight: f32,
+    // IVF lists to scan, trading recall for latency; None uses the store
+    // default, and the number of lists or more scans every entry
+    pub nprobe: Option<usize>,
+}
+
+impl SearchOptions {
+    pub fn threshold_for(&self, origin: &str) -> f32 {
+        self.origin_thresholds
+            .get(origin)
+            .copied()
+            .unwrap_or(self.threshold)
+    }
+
+    // Lowest unweighted similarity any entry can match with
+    fn lowest_similarity(&self) -> f32 {
+        if self.similarity_weight <= 0.0 {
+            return -1.0;
+        }
+        self.origin_thresholds
+            .values()
+            .copied()
+            .fold(self.threshold, f32::min)
+            / self.similarity_weight
+    }
+}
+
+// Share of deleted slots in a shard above which it gets compacted
+pub const DEFAULT_COMPACTION_RATIO: f32 = 0.2;
+
+// Entries scanned between two deadline checks, so the clock is not read per entry
+const DEADLINE_CHECK_INTERVAL: usize = 1024;
+
+// Outcome of a similarity search
+#[derive(Clone, Debug, Default)]
+pub struct SearchResults {
+    pub matches: Vec<SearchMatch>,
+    // The deadline expired before every entry was scanned
+    pub partial: bool,
+    // Liveness score of the query image, set by the search pipeline when a
+    // liveness model is configured
+    pub liveness: Option<f32>,
+    // Head pose of the query face, set when a pose model is configured
+    pub pose: Option<Pose>,
+    // Mask check of the query face, set when a mask model is configured
+    pub mask: Option<MaskCheck>,
+    // Estimated age and gender of the query face, set when requested
+    pub attributes: Option<Attributes>,
+    // Estimated expression of the query face, set when requested
+    pub emotion: Option<Emotion>,
+    // Box of the query face, set when a face detector is configured
+    pub face: Option<DetectedFace>,
+    // Aligned crop of the query face fed to the model, set when requested
+    pub crop: Option<FaceCrop>,
+    // Embedding of the query face, set when requested
+    pub embedding: Option<Vec<f32>>,
+    // Results of the other faces of the query image, largest first
+    pub other_faces: Vec<SearchResults>,
+}
+
+// A single search hit
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SearchMatch {
+    pub uuid: Uuid,
+    pub origin: String,
+    pub metadata: Metadata,
+    pub similarity: f32,
+    // Number of entries of this uuid above the threshold (always 1 unless grouped)
+    pub hits: u32,
+}
+
+// Checked every DEADLINE_CHECK_INTERVAL entries; once one shard runs out of
+// time the others stop at their next check as well
+fn deadline_reached(position: usize, deadline: Option<Instant>, truncated: &AtomicBool) -> bool {
+    let Some(deadline) = deadline else {
+        return false;
+    };
+    if position % DEADLINE_CHECK_INTERVAL != 0 {
+        return false;
+    }
+    if truncated.load(Ordering::Relaxed) || Instant::now() >= deadline {
+        truncated.store(true, Ordering::Relaxed);
+        return true;
+    }
+    false
+}
+
+// Keep only the `limit` best results, sorted by similarity (highest first)
+pub(crate) fn top_k(results: &mut Vec<SearchMatch>, limit: usize) {
+    results.sort_by(|a, b| {
+        b.similarity
+            .partial_cmp(&a.similarity)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    results.truncate(limit);
+}
+
+// Collapse the matches of each uuid into its best one, counting the hits
+fn group_by_uuid(results: Vec<SearchMatch>) -> Vec<SearchMatch> {
+    let mut best: HashMap<Uuid, SearchMatch> = HashMap::with_capacity(results.len());
+    for result in results {
+        match best.get_mut(&result.uuid) {
+            Some(current) => {
+                current.hits += 1;
+                if result.similarity > current.similarity {
+                    current.similarity = result.similarity;
+                    current.origin = result.origin;
+                    current.metadata = result.metadata;
+                }
+            }
+            None => {
+                best.insert(result.uuid, result);
+            }
+        }
+    }
+    best.into_values().collect()
+}
+
+// Time spent waiting for one kind of shard lock
+#[derive(Default)]
+struct WaitStats {
+    acquisitions: AtomicU64,
+    total_wait_us: AtomicU64,
+    max_wait_us: AtomicU64,
+}
+
+impl WaitStats {
+    fn record(&self, waited: Duration) {
+        let waited_us = waited.as_micros() as u64;
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        self.total_wait_us.fetch_add(waited_us, Ordering::Relaxed);
+        self.max_wait_us.fetch_max(waited_us, Ordering::Relaxed);
+    }
+
+    fn summary(&self) -> LockWaitSummary {
+        let acquisitions = self.acquisitions.load(Ordering::Relaxed);
+        let total_wait_us = self.total_wait_us.load(Ordering::Relaxed);
+        LockWaitSummary {
+            acquisitions,
+            total_wait_us,
+            mean_wait_us: total_wait_us.checked_div(acquisitions).unwrap_or(0),
+            max_wait_us: self.max_wait_us.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct LockWaitSummary {
+    pub acquisitions: u64,
+    pub total_wait_us: u64,
+    pub mean_wait_us: u64,
+    pub max_wait_us: u64,
+}
+
+// Shard lock wait times since startup; a growing write wait next to a flat
+// read wait would mean registrations are being starved by searches
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct LockStats {
+    pub reads: LockWaitSummary,
+    pub writes: LockWaitSummary,
+}
+
+// One registration to add to the store
+pub struct NewEmbedding {
+    pub uuid: Uuid,
+    pub origin: String,
+    pub metadata: Metadata,
+    pub embedding: Vec<f32>,
+    pub model_version: Option<String>,
+    // Unix seconds; only memory-only mode sweeps entries, the database
+    // deletes the expired rows itself
+    pub expires_at: Option<i64>,
+}
+
+// Earliest of two expiries, None meaning never
+fn earliest(a: Option<i64>, b: Option<i64>) -> Option<i64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+// Armazenamento e função de busca para embeddings
+//
+// Entries are partitioned into shards keyed by a hash of the uuid. Every shard
+// has its own lock, so a registration only blocks searches on one shard, and
+// searches scan all shards in parallel before merging the per-shard top-k.
+// Since all embeddings of a uuid land in the same shard, templates can be
+// maintained without touching other shards.
+//
+// Shard locks are tokio's fair (FIFO) RwLock: once a registration is queued,
+// searches arriving after it wait behind it, so a stream of searches cannot
+// starve writers. Registrations await the lock; searches run on blocking
+// threads and take it with `blocking_read`.
+pub struct EmbeddingsStore {
+    shards: Vec<RwLock<Shard>>,
+    template_mode: TemplateMode,
+    // Number of entries across all shards
+    entry_count: AtomicUsize,
+    // Length of the stored vectors, that of the model or else fixed by the
+    // first registration; other vectors are rejected
+    dimension: OnceLock<usize>,
+    read_waits: WaitStats,
+    write_waits: WaitStats,
+    // Registrations stored per origin, independent of how templates fold them
+    origin_counts: Mutex<HashMap<String, usize>>,
+    // Deleted entries not yet compacted away, across all shards
+    hole_count: AtomicUsize,
+    // Share of holes in a shard that makes it worth compacting
+    compaction_ratio: f32,
+    // Vectors held across all shards, more than the entries in `Max` mode
+    vector_count: AtomicUsize,
+    // Model of the running instance; searches skip the entries of others
+    model_version: Option<Arc<str>>,
+    // Model the untagged entries were embedded with, the running one when None
+    legacy_model_version: Option<Arc<str>>,
+    // Bits of the prefilter sketches, 0 without prefilter
+    prefilter_bits: usize,
+    // Hyperplanes of the sketches, once the dimension is known
+    sketcher: OnceLock<Sketcher>,
+    // Precision of the vectors held
+    precision: Precision,
+    // Inverted file index, off when None
+    ivf: Option<IvfConfig>,
+    // Latest trained centroids, those of every shard once training is done
+    centroids: std::sync::RwLock<Option<Arc<Centroids>>>,
+    // Vectors held when the centroids were last trained
+    trained_vectors: AtomicUsize,
+    // Held by the running training
+    training: AtomicBool,
+    // Reduced scan with exact re-scoring, off when None
+    pca: Option<PcaConfig>,
+    // Latest fitted projection, that of every shard once fitting is done
+    projection: std::sync::RwLock<Option<Arc<Projection>>>,
+    // Vectors held when the projection was last fitted
+    pca_trained_vectors: AtomicUsize,
+    // Held by the running fitting
+    pca_training: AtomicBool,
+    // Slice of the gallery this instance holds; registrations of other uuids
+    // are left to their shard
+    gallery_shard: Option<GalleryShard>,
+}
+
+impl EmbeddingsStore {
+    pub fn new() -> Self {
+        let shards = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        Self::with_shards(shards)
+    }
+
+    pub fn with_shards(shards: usize) -> Self {
+        Self {
+            shards: (0..shards.max(1))
+                .map(|_| RwLock::new(Shard::default()))
+                .collect(),
+            template_mode: TemplateMode::Off,
+            entry_count: AtomicUsize::new(0),
+            dimension: OnceLock::new(),
+            read_waits: WaitStats::default(),
+            write_waits: WaitStats::default(),
+            origin_counts: Mutex::new(HashMap::new()),
+            hole_count: AtomicUsize::new(0),
+            compaction_ratio: DEFAULT_COMPACTION_RATIO,
+            vector_count: AtomicUsize::new(0),
+            model_version: None,
+            legacy_model_version: None,
+            prefilter_bits: 0,
+            sketcher: OnceLock::new(),
+            precision: Precision::F32,
+            ivf: None,
+            centroids: std::sync::RwLock::new(None),
+            trained_vectors: AtomicUsize::new(0),
+            training: AtomicBool::new(false),
+            pca: None,
+            projection: std::sync::RwLock::new(None),
+            pca_trained_vectors: AtomicUsize::new(0),
+            pca_training: AtomicBool::new(false),
+            gallery_shard: None,
+        }
+    }
+
+    pub fn with_compaction_ratio(mut self, compaction_ratio: f32) -> Self {
+        self.compaction_ratio = compaction_ratio;
+        self
+    }
+
+    pub fn with_gallery_shard(mut self, gallery_shard: Option<GalleryShard>) -> Self {
+        self.gallery_shard = gallery_shard;
+        self
+    }
+
+    // Whether the uuid belongs to the slice of the gallery held here
+    pub fn owns(&self, uuid: &Uuid) -> bool {
+        self.gallery_shard.map_or(true, |shard| shard.owns(uuid))
+    }
+
+    pub fn with_template_mode(mut self, template_mode: TemplateMode) -> Self {
+        self.template_mode = template_mode;
+        self
+    }
+
+    pub fn with_dimension(self, dimension: Option<usize>) -> Self {
+        if let Some(dimension) = dimension {
+            self.dimension.get_or_init(|| dimension);
+        }
+        self
+    }
+
+    pub fn with_prefilter_bits(mut self, prefilter_bits: usize) -> Self {
+        self.prefilter_bits = prefilter_bits;
+        self
+    }
+
+    pub fn with_precision(mut self, precision: Precision) -> Self {
+        self.precision = precision;
+        self
+    }
+
+    pub fn with_ivf(mut self, ivf: Option<IvfConfig>) -> Self {
+        self.ivf = ivf;
+        self
+    }
+
+    pub fn with_pca(mut self, pca: Option<PcaConfig>) -> Self {
+        self.pca = pca;
+        self
+    }
+
+    // Searches score every vector on the GPU and only the entries above the
+    // threshold on the CPU
+    #[cfg(feature = "gpu-search")]
+    pub fn with_gpu(mut self, context: Option<Arc<GpuContext>>) -> Self {
+        if let Some(context) = context {
+            for shard in &mut self.shards {
+                shard.get_mut().gpu = Some(GpuVectors::new(context.clone()));
+            }
+        }
+        self
+    }
+
+    pub fn with_model_version(mut self, model_version: Option<Arc<str>>) -> Self {
+        self.model_version = model_version;
+        self
+    }
+
+    pub fn with_legacy_model_version(mut self, legacy_model_version: Option<Arc<str>>) -> Self {
+        self.legacy_model_version = legacy_model_version;
+        self
+    }
+
+    fn models(&self) -> Models<'_> {
+        Models {
+            current: self.model_version.as_deref(),
+            legacy: self.legacy_model_version.as_deref(),
+        }
+    }
+
+    pub fn template_mode(&self) -> TemplateMode {
+        self.template_mode
+    }
+
+    pub fn precision(&self) -> Precision {
+        self.precision
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn shard_index(&self, uuid: &Uuid) -> usize {
+        let mut hasher = DefaultHasher::new();
+        uuid.hash(&mut hasher);
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+
+    async fn read_shard(&self, index: usize) -> RwLockReadGuard<'_, Shard> {
+        let start = Instant::now();
+        let guard = self.shards[index].read().await;
+        self.read_waits.record(start.elapsed());
+        guard
+    }
+
+    // Must not be called from an async context (see find_similar)
+    fn blocking_read_shard(&self, index: usize) -> RwLockReadGuard<'_, Shard> {
+        let start = Instant::now();
+        let guard = self.shards[index].blocking_read();
+        self.read_waits.record(start.elapsed());
+        guard
+    }
+
+    async fn write_shard(&self, index: usize) -> RwLockWriteGuard<'_, Shard> {
+        let start = Instant::now();
+        let guard = self.shards[index].write().await;
+        self.write_waits.record(start.elapsed());
+        guard
+    }
+
+    pub async fn add(
+        &self,
+        uuid: Uuid,
+        origin: String,
+        metadata: Metadata,
+        embedding: Vec<f32>,
+        model_version: Option<String>,
+    ) -> bool {
+        self.add_embedding(NewEmbedding {
+            uuid,
+            origin,
+            metadata,
+            embedding,
+            model_version,
+            expires_at: None,
+        })
+        .await
+    }
+
+    // Same as `add`, with the expiry of the registration
+    pub async fn add_embedding(&self, new: NewEmbedding) -> bool {
+        if !self.accepts(&new.embedding) {
+            return false;
+        }
+        if !self.owns(&new.uuid) {
+            return true;
+        }
+        *self
+            .origin_counts
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(new.origin.clone())
+            .or_insert(0) += 1;
+
+        let mut shard = self.write_shard(self.shard_index(&new.uuid)).await;
+        self.insert(&mut shard, new);
+        true
+    }
+
+    // Adds registrations of `uuid` unless it already has entries, evicted
+    // ones included; the check and the insert share the shard lock, so of
+    // two concurrent registrations only one gets in. False when it has some.
+    pub async fn add_if_absent(&self, uuid: Uuid, embeddings: Vec<NewEmbedding>) -> bool {
+        if !self.owns(&uuid) {
+            return true;
+        }
+        let mut shard = self.write_shard(self.shard_index(&uuid)).await;
+        if shard.evicted.contains_key(&uuid)
+            || shard
+                .entries
+                .iter()
+                .any(|entry| !entry.deleted && entry.uuid == uuid)
+        {
+            return false;
+        }
+        for new in embeddings {
+            debug_assert_eq!(new.uuid, uuid);
+            if !self.accepts(&new.embedding) {
+                continue;
+            }
+            *self
+                .origin_counts
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .entry(new.origin.clone())
+                .or_insert(0) += 1;
+            self.insert(&mut shard, new);
+        }
+        true
+    }
+
+    // Same as `add` for many registrations, taking every shard lock once; used
+    // by bulk loads where per-row locking dominates. Returns the uuids of the
+    // rejected vectors.
+    pub async fn add_batch(&self, embeddings: Vec<NewEmbedding>) -> Vec<Uuid> {
+        let mut rejected = Vec::new();
+        let mut per_shard: Vec<Vec<NewEmbedding>> =
+            (0..self.shards.len()).map(|_| Vec::new()).collect();
+        {
+            let mut origin_counts = self.origin_counts.lock().unwrap_or_else(|e| e.into_inner());
+            for new in embeddings {
+                if !self.accepts(&new.embedding) {
+                    rejected.push(new.uuid);
+                    continue;
+                }
+                if !self.owns(&new.uuid) {
+                    continue;
+                }
+                *origin_counts.entry(new.origin.clone()).or_insert(0) += 1;
+                per_shard[self.shard_index(&new.uuid)].push(new);
+            }
+        }
+        for (index, embeddings) in per_shard.into_iter().enumerate() {
+            if embeddings.is_empty() {
+                continue;
+            }
+            let mut shard = self.write_shard(index).await;
+            for new in embeddings {
+                self.insert(&mut shard, new);
+            }
+        }
+        rejected
+    }
+
+    // None without prefilter or before the first vector fixed the dimension
+    fn sketcher(&self) -> Option<&Sketcher> {
+        if self.prefilter_bits == 0 {
+            return None;
+        }
+        let dimension = self.dimension()?;
+        Some(
+            self.sketcher
+                .get_or_init(|| Sketcher::new(self.prefilter_bits, dimension)),
+        )
+    }
+
+    fn sketch(&self, embedding: &[f32]) -> Option<Sketch> {
+        self.sketcher().map(|sketcher| sketcher.sketch(embedding))
+    }
+
+    // Vectors of another length than the stored ones would be scored against
+    // mismatched values; the first one fixes the length when the model does
+    // not declare it
+    fn accepts(&self, embedding: &[f32]) -> bool {
+        !embedding.is_empty() && *self.dimension.get_or_init(|| embedding.len()) == embedding.len()
+    }
+
+    fn insert(&self, shard: &mut Shard, new: NewEmbedding) {
+        let NewEmbedding {
+            uuid,
+            origin,
+            metadata,
+            embedding,
+            model_version,
+            expires_at,
+        } = new;
+        shard.version += 1;
+        // Entries of the running model share its string
+        let model_version = model_version.map(|version| match &self.model_version {
+            Some(current) if **current == *version => current.clone(),
+            _ => Arc::from(version),
+        });
+        let sketch = self.sketch(&embedding);
+
+        // A cold uuid stays out of memory until it is paged back in whole;
+        // registrations page it in first, so only rows loaded or reloaded in
+        // the meantime land here
+        if let Some(evicted) = shard.evicted.get_mut(&uuid) {
+            evicted.push((origin, 1));
+            return;
+        }
+
+        if self.template_mode != TemplateMode::Off {
+            if let Some(&position) = shard.index.get(&uuid) {
+                let entry = &mut shard.entries[position];
+                entry.samples += 1;
+                entry.origin = origin;
+                // The latest registration's metadata describes the identity
+                entry.metadata = metadata;
+                // A template cannot be split again, it goes with its first
+                // expiring registration
+                entry.expires_at = earliest(entry.expires_at, expires_at);
+                entry.last_used.touch();
+                // A template is not mixed across models: the registration of
+                // another one starts it over
+                if entry.model_version != model_version {
+                    entry.model_version = model_version;
+                    self.vector_count
+                        .fetch_sub(entry.embeddings.len() - 1, Ordering::Relaxed);
+                    entry.sketches = sketch.into_iter().collect();
+                    if let Some(ivf) = &mut shard.ivf {
+                        ivf.add(position, &embedding);
+                    }
+                    #[cfg(feature = "gpu-search")]
+                    if let Some(gpu) = &mut shard.gpu {
+                        gpu.add(position, &embedding);
+                    }
+                    entry.embeddings = vec![Vector::new(embedding, self.precision)];
+                    shard.reduce(position);
+                    return;
+                }
+                match self.template_mode {
+                    TemplateMode::Mean => {
+                        // Running mean; cosine similarity is scale invariant so
+                        // the template does not need to be renormalized. It is
+                        // updated in f32 and only then rounded to the store's
+                        // precision
+                        let n = entry.samples as f32;
+                        let mut template = entry.embeddings[0].to_f32().into_owned();
+                        for (mean, value) in template.iter_mut().zip(&embedding) {
+                            *mean += (value - *mean) / n;
+                        }
+                        if let Some(sketcher) = self.sketcher() {
+                            entry.sketches = vec![sketcher.sketch(&template)];
+                        }
+                        if let Some(ivf) = &mut shard.ivf {
+                            ivf.add(position, &template);
+                        }
+                        #[cfg(feature = "gpu-search")]
+                        if let Some(gpu) = &mut shard.gpu {
+                            gpu.add(position, &template);
+                        }
+                        entry.embeddings[0] = Vector::new(template, self.precision);
+                        shard.reduce(position);
+                    }
+                    TemplateMode::Max => {
+                        if let Some(ivf) = &mut shard.ivf {
+                            ivf.add(position, &embedding);
+                        }
+                        #[cfg(feature = "gpu-search")]
+                        if let Some(gpu) = &mut shard.gpu {
+                            gpu.add(position, &embedding);
+                        }
+                        entry
+                            .embeddings
+                            .push(Vector::new(embedding, self.precision));
+                        entry.sketches.extend(sketch);
+                        self.vector_count.fetch_add(1, Ordering::Relaxed);
+                        shard.reduce(position);
+                    }
+                    TemplateMode::Off => unreachable!(),
+                }
+                return;
+            }
+            let position = shard.entries.len();
+            shard.index.insert(uuid, position);
+        }
+
+        if let Some(ivf) = &mut shard.ivf {
+            ivf.add(shard.entries.len(), &embedding);
+        }
+        #[cfg(feature = "gpu-search")]
+        if let Some(gpu) = &mut shard.gpu {
+            gpu.add(shard.entries.len(), &embedding);
+        }
+        shard.entries.push(EmbeddingEntry {
+            uuid,
+            origin,
+            metadata,
+            embeddings: vec![Vector::new(embedding, self.precision)],
+            sketches: sketch.into_iter().collect(),
+            samples: 1,
+            model_version,
+            expires_at,
+            deleted: false,
+            last_used: LastUsed::now(),
+        });
+        shard.reduce(shard.entries.len() - 1);
+        self.entry_count.fetch_add(1, Ordering::Relaxed);
+        self.vector_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // CPU-bound and blocking: call it from `spawn_blocking`, never directly
+    // from an async task
+    pub fn find_similar(&self, query: &[f32], options: &SearchOptions) -> SearchResults {
+        let truncated = AtomicBool::new(false);
+        let prefilter = self.sketcher().map(|sketcher| {
+            (
+                sketcher.sketch(query),
+                sketcher.cutoff(options.lowest_similarity()),
+            )
+        });
+        // Lists of the latest centroids are probed once; a shard still on
+        // older ones during a training probes its own
+        let nprobe = self.nprobe(options);
+        let centroids = nprobe.and_then(|_| self.centroids());
+        let probe = match (nprobe, &centroids) {
+            (Some(nprobe), Some(centroids)) => Some(centroids.probe(query, nprobe)),
+            _ => None,
+        };
+        let mut results: Vec<SearchMatch> = (0..self.shards.len())
+            .into_par_iter()
+            .flat_map_iter(|index| {
+                let shard = self.blocking_read_shard(index);
+                // The GPU scores every vector, so it wins over the IVF lists
+                #[cfg(feature = "gpu-search")]
+                let scored = shard
+                    .gpu
+                    .as_ref()
+                    .and_then(|gpu| gpu.candidates(query, options.lowest_similarity()));
+                #[cfg(not(feature = "gpu-search"))]
+                let scored = None;
+                let on_gpu = scored.is_some();
+                let candidates = scored.or_else(|| match (nprobe, &shard.ivf) {
+                    (Some(nprobe), Some(ivf)) => Some(match (&centroids, &probe) {
+                        (Some(centroids), Some(probe))
+                            if Arc::ptr_eq(centroids, &ivf.centroids) =>
+                        {
+                            ivf.candidates(probe)
+                        }
+                        _ => ivf.candidates(&ivf.centroids.probe(query, nprobe)),
+                    }),
+                    _ => None,
+                });
+                // The GPU already scored every vector exactly
+                let reduced = match (&shard.pca, self.pca) {
+                    (Some(pca), Some(config)) if !on_gpu => {
+                        Some((pca, pca.projection.reduce(query), config.margin))
+                    }
+                    _ => None,
+                };
+                let entries: Box<dyn Iterator<Item = (usize, &EmbeddingEntry)> + '_> =
+                    match &candidates {
+                        Some(positions) => Box::new(positions.iter().map(|&position| {
+                            (position as usize, &shard.entries[position as usize])
+                        })),
+                        None => Box::new(shard.entries.iter().enumerate()),
+                    };
+                let filtered = entries
+                    .enumerate()
+                    .take_while(|(scanned, _)| {
+                        !deadline_reached(*scanned, options.deadline, &truncated)
+                    })
+                    .map(|(_, entry)| entry)
+                    .filter(|(_, entry)| entry.is_candidate(options, self.models()))
+                    .filter(|(_, entry)| match &prefilter {
+                        Some((sketch, cutoff)) => entry.passes_prefilter(sketch, *cutoff),
+                        None => true,
+                    });
+                let similarities: Box<dyn Iterator<Item = (&EmbeddingEntry, f32)> + '_> =
+                    match &reduced {
+                        // Approximate similarities in the reduced space pick the
+                        // entries worth an exact comparison
+                        Some((pca, reduced_query, margin)) => {
+                            let mut shortlist: Vec<(&EmbeddingEntry, f32)> = filtered
+                                .filter_map(|(position, entry)| {
+                                    let approximate = pca
+                                        .score(position, reduced_query)
+                                        .map_or(f32::MAX, |score| {
+                                            score * options.similarity_weight
+                                        });
+                                    (approximate >= options.threshold_for(&entry.origin) - margin)
+                                        .then_some((entry, approximate))
+                                })
+                                .collect();
+                            let rescored = options.limit.saturating_mul(pca::RESCORE_FACTOR);
+                            if shortlist.len() > rescored {
+                                shortlist
+                                    .select_nth_unstable_by(rescored, |a, b| b.1.total_cmp(&a.1));
+                                shortlist.truncate(rescored);
+                            }
+                            Box::new(shortlist.into_iter().map(|(entry, _)| {
+                                (entry, entry.score(query) * options.similarity_weight)
+                            }))
+                        }
+                        None => Box::new(filtered.map(|(_, entry)| {
+                            (entry, entry.score(query) * options.similarity_weight)
+                        })),
+                    };
+                let mut shard_results: Vec<SearchMatch> = similarities
+                    .filter_map(|(entry, similarity)| {
+                        (similarity >= options.threshold_for(&entry.origin)).then(|| {
+                            entry.last_used.touch();
+                            SearchMatch {
+                                uuid: entry.uuid,
+                                origin: entry.origin.clone(),
+                                metadata: entry.metadata.clone(),
+                                similarity,
+                                hits: 1,
+                            }
+                        })
+                    })
+                    .collect();
+
+                // All entries of a uuid live in the same shard, so grouping
+                // can happen before the per-shard truncation
+                if options.group_by_uuid {
+                    shard_results = group_by_uuid(shard_results);
+                }
+
+                // Each shard contributes at most `limit` candidates to the merge
+                top_k(&mut shard_results, options.limit);
+                shard_results
+            })
+            .collect();
+
+        // Merge the per-shard candidates into the global top-k
+        top_k(&mut results, options.limit);
+
+        SearchResults {
+            matches: results,
+            partial: truncated.into_inner(),
+            liveness: None,
+            pose: None,
+            mask: None,
+            attributes: None,
+            emotion: None,
+            face: None,
+            crop: None,
+            embedding: None,
+            other_faces: Vec::new(),
+        }
+    }
+
+    // Lists a search scans, None when it scans every entry
+    fn nprobe(&self, options: &SearchOptions) -> Option<usize> {
+        let ivf = self.ivf?;
+        let nprobe = options.nprobe.unwrap_or(ivf.nprobe);
+        (nprobe < ivf.nlist).then_some(nprobe)
+    }
+
+    fn centroids(&self) -> Option<Arc<Centroids>> {
+        self.centroids
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    // Whether the IVF centroids are due: first trained once every list can
+    // get enough vectors, then again each time the gallery outgrew them
+    pub fn needs_training(&self) -> bool {
+        let Some(ivf) = self.ivf else {
+            return false;
+        };
+        let vectors = self.vector_count.load(Ordering::Relaxed);
+        let trained = self.trained_vectors.load(Ordering::Relaxed);
+        !self.training.load(Ordering::Relaxed)
+            && vectors >= ivf.nlist * ivf::MIN_POINTS_PER_LIST
+            && (trained == 0 || vectors >= trained * ivf::RETRAIN_GROWTH)
+    }
+
+    // Trains new IVF centroids on a sample of the vectors and files every
+    // entry under them, returning the vectors held, or None when training is
+    // not due or already running.
+    //
+    // CPU-bound and blocking: call it from `spawn_blocking`, never directly
+    // from an async task
+    pub fn train_ivf(&self) -> Option<usize> {
+        let ivf = self.ivf?;
+        if !self.needs_training() || self.training.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+        let vectors = self.vector_count.load(Ordering::Relaxed);
+
+        // Every n-th vector, so the sample spans the gallery evenly
+        let step = vectors.div_ceil(ivf::sample_size(ivf.nlist)).max(1);
+        let mut sample = Vec::new();
+        for index in 0..self.shards.len() {
+            let shard = self.blocking_read_shard(index);
+            sample.extend(
+                shard
+                    .entries
+                    .iter()
+                    .filter(|entry| !entry.deleted)
+                    .flat_map(|entry| &entry.embeddings)
+                    .step_by(step)
+                    .map(|embedding| embedding.to_f32().into_owned()),
+            );
+        }
+        if sample.len() < ivf.nlist {
+            self.training.store(false, Ordering::Release);
+            return None;
+        }
+        let centroids = Arc::new(Centroids::train(&sample, ivf.nlist));
+        self.install_ivf(centroids, vectors);
+        self.training.store(false, Ordering::Release);
+        Some(vectors)
+    }
+
+    // Puts back the centroids saved by an earlier run (see
+    // `snapshot::write_index`), so a restart files the gallery under them
+    // instead of training again. Rejected when they do not fit the store:
+    // another IVF_NLIST, vector length or no index at all.
+    //
+    // CPU-bound and blocking: call it from `spawn_blocking`, never directly
+    // from an async task
+    pub fn restore_ivf(&self, centroids: Centroids, trained_vectors: usize) -> bool {
+        let Some(ivf) = self.ivf else {
+            return false;
+        };
+        if centroids.nlist() != ivf.nlist
+            || self.dimension() != Some(centroids.dimension())
+            || self.training.swap(true, Ordering::AcqRel)
+        {
+            return false;
+        }
+        self.install_ivf(Arc::new(centroids), trained_vectors);
+        self.training.store(false, Ordering::Release);
+        true
+    }
+
+    // Current centroids and the vectors they were trained on, to be saved
+    pub fn ivf_state(&self) -> Option<(Arc<Centroids>, usize)> {
+        let centroids = self.centroids()?;
+        Some((centroids, self.trained_vectors.load(Ordering::Relaxed)))
+    }
+
+    // Files every entry under new centroids. Lists are built under the read
+    // lock and swapped in under the write lock, like `compact`; a shard
+    // written to in between is filed again under the write lock.
+    fn install_ivf(&self, centroids: Arc<Centroids>, trained_vectors: usize) {
+        let file = |entries: &[EmbeddingEntry]| {
+            let mut lists = InvertedLists::new(centroids.clone());
+            for (position, entry) in entries.iter().enumerate() {
+                for embedding in &entry.embeddings {
+                    lists.add(position, &embedding.to_f32());
+                }
+            }
+            lists
+        };
+        for index in 0..self.shards.len() {
+            let (version, lists) = {
+                let shard = self.blocking_read_shard(index);
+                (shard.version, file(&shard.entries))
+            };
+            let start = Instant::now();
+            let mut shard = self.shards[index].blocking_write();
+            self.write_waits.record(start.elapsed());
+            shard.ivf = Some(if shard.version == version {
+                lists
+            } else {
+                file(&shard.entries)
+            });
+            shard.version += 1;
+        }
+        *self.centroids.write().unwrap_or_else(|e| e.into_inner()) = Some(centroids);
+        self.trained_vectors
+            .store(trained_vectors, Ordering::Relaxed);
+    }
+
+    fn projection(&self) -> Option<Arc<Projection>> {
+        self.projection
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    // Whether the PCA projection is due: first fitted once there are enough
+    // vectors per kept dimension, then again each time the gallery outgrew it
+    pub fn needs_pca_training(&self) -> bool {
+        let Some(pca) = self.pca else {
+            return false;
+        };
+        let vectors = self.vector_count.load(Ordering::Relaxed);
+        let trained = self.pca_trained_vectors.load(Ordering::Relaxed);
+        !self.pca_training.load(Ordering::Relaxed)
+            && self
+                .dimension()
+                .is_some_and(|dimension| pca.dimensions < dimension)
+            && vectors >= pca.dimensions * pca::MIN_POINTS_PER_DIMENSION
+            && (trained == 0 || vectors >= trained * pca::RETRAIN_GROWTH)
+    }
+
+    // Fits a new projection on a sample of the vectors and reduces every
+    // entry with it, returning the vectors held, or None when fitting is not
+    // due or already running.
+    //
+    // CPU-bound and blocking: call it from `spawn_blocking`, never directly
+    // from an async task
+    pub fn train_pca(&self) -> Option<usize> {
+        let pca = self.pca?;
+        if !self.needs_pca_training() || self.pca_training.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+        let vectors = self.vector_count.load(Ordering::Relaxed);
+
+        // Every n-th vector, so the sample spans the gallery evenly
+        let step = vectors.div_ceil(pca::sample_size()).max(1);
+        let mut sample = Vec::new();
+        for index in 0..self.shards.len() {
+            let shard = self.blocking_read_shard(index);
+            sample.extend(
+                shard
+                    .entries
+                    .iter()
+                    .filter(|entry| !entry.deleted)
+                    .flat_map(|entry| &entry.embeddings)
+                    .step_by(step)
+                    .map(|embedding| embedding.to_f32().into_owned()),
+            );
+        }
+        if sample.len() < pca.dimensions {
+            self.pca_training.store(false, Ordering::Release);
+            return None;
+        }
+        let projection = Arc::new(Projection::fit(&sample, pca.dimensions));
+        self.install_pca(projection, vectors);
+        self.pca_training.store(false, Ordering::Release);
+        Some(vectors)
+    }
+
+    // Reduces every entry with a new projection, like `install_ivf`
+    fn install_pca(&self, projection: Arc<Projection>, trained_vectors: usize) {
+        let reduce = |entries: &[EmbeddingEntry]| {
+            let mut reduced = ReducedVectors::new(projection.clone());
+            for (position, entry) in entries.iter().enumerate() {
+                reduced.set(position, &entry.embeddings);
+            }
+            reduced
+        };
+        for index in 0..self.shards.len() {
+            let (version, reduced) = {
+                let shard = self.blocking_read_shard(index);
+                (shard.version, reduce(&shard.entries))
+            };
+            let start = Instant::now();
+            let mut shard = self.shards[index].blocking_write();
+            self.write_waits.record(start.elapsed());
+            shard.pca = Some(if shard.version == version {
+                reduced
+            } else {
+                reduce(&shard.entries)
+            });
+            shard.version += 1;
+        }
+        *self.projection.write().unwrap_or_else(|e| e.into_inner()) = Some(projection);
+        self.pca_trained_vectors
+            .store(trained_vectors, Ordering::Relaxed);
+    }
+
+    // Best similarity between the query and the entries of one uuid, or None
+    // when the uuid is not stored; used for 1:1 verification
+    pub async fn score_uuid(&self, uuid: &Uuid, query: &[f32]) -> Option<f32> {
+        self.read_shard(self.shard_index(uuid))
+            .await
+            .entries
+            .iter()
+            .filter(|entry| {
+                !entry.deleted && entry.uuid == *uuid && entry.is_comparable(self.models())
+            })
+            .map(|entry| {
+                entry.last_used.touch();
+                entry.score(query)
+            })
+            .reduce(f32::max)
+    }
+
+    // Copy of every entry, for exports and other full-gallery operations
+    pub async fn snapshot(&self) -> Vec<EmbeddingEntry> {
+        let mut entries = Vec::with_capacity(self.len());
+        for index in 0..self.shards.len() {
+            entries.extend(
+                self.read_shard(index)
+                    .await
+                    .entries
+                    .iter()
+                    .filter(|entry| !entry.deleted)
+                    .cloned(),
+            );
+        }
+        entries
+    }
+
+    // Whether a uuid has an entry in memory, evicted ones excluded
+    pub async fn contains(&self, uuid: &Uuid) -> bool {
+        self.read_shard(self.shard_index(uuid))
+            .await
+            .entries
+            .iter()
+            .any(|entry| !entry.deleted && entry.uuid == *uuid)
+    }
+
+    // Length of the stored vectors, or None while the store is empty
+    pub fn dimension(&self) -> Option<usize> {
+        self.dimension.get().copied()
+    }
+
+    // Bytes held per stored vector, 0 while the dimension is unknown
+    pub fn vector_bytes(&self) -> usize {
+        self.dimension().unwrap_or(0) * self.precision.value_bytes()
+    }
+
+    pub fn lock_stats(&self) -> LockStats {
+        LockStats {
+            reads: self.read_waits.summary(),
+            writes: self.write_waits.summary(),
+        }
+    }
+
+    // Number of registrations stored for an origin
+    pub fn origin_count(&self, origin: &str) -> usize {
+        self.origin_counts
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(origin)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    pub fn origin_counts(&self) -> HashMap<String, usize> {
+        self.origin_counts
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    // Registrations folded into the store per uuid, comparable to the rows of
+    // the uuid in the database whatever the template mode
+    pub async fn registration_counts(&self) -> HashMap<Uuid, u32> {
+        let mut counts = HashMap::new();
+        for index in 0..self.shards.len() {
+            let shard = self.read_shard(index).await;
+            for entry in shard.entries.iter().filter(|entry| !entry.deleted) {
+                *counts.entry(entry.uuid).or_insert(0) += entry.samples;
+            }
+            // Evicted uuids are in sync with the database, just not in memory
+            for (uuid, registrations) in &shard.evicted {
+                *counts.entry(*uuid).or_insert(0) += registrations
+                    .iter()
+                    .map(|(_, samples)| samples)
+                    .sum::<u32>();
+            }
+        }
+        counts
+    }
+
+    // Entries per model, None counting the untagged ones; more than one
+    // model means part of the gallery is not searched (see /stats)
+    pub async fn model_version_counts(&self) -> HashMap<Option<String>, usize> {
+        let mut counts = HashMap::new();
+        for index in 0..self.shards.len() {
+            let shard = self.read_shard(index).await;
+            for entry in shard.entries.iter().filter(|entry| !entry.deleted) {
+                *counts
+                    .entry(entry.model_version.as_deref().map(str::to_string))
+                    .or_insert(0) += 1;
+            }
+        }
+        counts
+    }
+
+    pub fn len(&self) -> usize {
+        self.entry_count.load(Ordering::Relaxed)
+    }
+
+    pub fn hole_count(&self) -> usize {
+        self.hole_count.load(Ordering::Relaxed)
+    }
+
+    // Marks the entries of a uuid deleted and returns their (origin, samples).
+    // Entries are only marked so positions stay valid; `compact` reclaims the slots.
+    fn drop_entries(&self, shard: &mut Shard, uuid: &Uuid) -> Vec<(String, u32)> {
+        shard.index.remove(uuid);
+        self.drop_where(shard, |entry| entry.uuid == *uuid)
+            .into_iter()
+            .map(|(_, origin, samples)| (origin, samples))
+            .collect()
+    }
+
+    // Drops the live entries matching `predicate`, returning the uuid, origin
+    // and samples of each; the index is left to the caller
+    fn drop_where(
+        &self,
+        shard: &mut Shard,
+        predicate: impl Fn(&EmbeddingEntry) -> bool,
+    ) -> Vec<(Uuid, String, u32)> {
+        shard.version += 1;
+        let mut dropped = Vec::new();
+        let mut positions = Vec::new();
+        for (position, entry) in shard
+            .entries
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, entry)| !entry.deleted && predicate(entry))
+        {
+            positions.push(position);
+            entry.deleted = true;
+            // Drop the vectors now, only the slot lingers until compaction
+            self.vector_count
+                .fetch_sub(entry.embeddings.len(), Ordering::Relaxed);
+            entry.embeddings = Vec::new();
+            entry.sketches = Vec::new();
+            entry.metadata = Metadata::new();
+            dropped.push((entry.uuid, std::mem::take(&mut entry.origin), entry.samples));
+        }
+        if let Some(pca) = &mut shard.pca {
+            for position in positions {
+                pca.clear(position);
+            }
+        }
+
+        shard.holes += dropped.len();
+        self.hole_count.fetch_add(dropped.len(), Ordering::Relaxed);
+        self.entry_count.fetch_sub(dropped.len(), Ordering::Relaxed);
+        dropped
+    }
+
+    // Removes the entries expired at `now` (Unix seconds), returning the
+    // uuids that lost one
+    pub async fn remove_expired(&self, now: i64) -> Vec<Uuid> {
+        let mut removed = Vec::new();
+        for index in 0..self.shards.len() {
+            let mut shard = self.write_shard(index).await;
+            if !shard
+                .entries
+                .iter()
+                .any(|entry| !entry.deleted && entry.expires_at.is_some_and(|at| at <= now))
+            {
+                continue;
+            }
+            let dropped = self.drop_where(&mut shard, |entry| {
+                entry.expires_at.is_some_and(|at| at <= now)
+            });
+            let mut origin_counts = self.origin_counts.lock().unwrap_or_else(|e| e.into_inner());
+            for (uuid, origin, samples) in dropped {
+                shard.index.remove(&uuid);
+                if let Some(count) = origin_counts.get_mut(&origin) {
+                    *count = count.saturating_sub(samples as usize);
+                }
+                removed.push(uuid);
+            }
+        }
+        removed.sort_unstable();
+        removed.dedup();
+        removed
+    }
+
+    // Removes every entry of a uuid, evicted ones included, returning how many
+    // were in memory
+    pub async fn remove(&self, uuid: &Uuid) -> usize {
+        let mut shard = self.write_shard(self.shard_index(uuid)).await;
+        let removed = self.drop_entries(&mut shard, uuid);
+        let evicted = shard.evicted.remove(uuid).unwrap_or_default();
+
+        let mut origin_counts = self.origin_counts.lock().unwrap_or_else(|e| e.into_inner());
+        for (origin, samples) in removed.iter().chain(&evicted) {
+            if let Some(count) = origin_counts.get_mut(origin) {
+                *count = count.saturating_sub(*samples as usize);
+            }
+        }
+        removed.len()
+    }
+
+    // Sets the origin and/or replaces the metadata of every entry of a uuid,
+    // moving its registrations between the origin counts; returns how many
+    // entries were in memory, evicted ones included
+    pub async fn update(
+        &self,
+        uuid: &Uuid,
+        origin: Option<&str>,
+        metadata: Option<&Metadata>,
+    ) -> usize {
+        let mut shard = self.write_shard(self.shard_index(uuid)).await;
+        shard.version += 1;
+        let mut moved = Vec::new();
+        let mut updated = 0;
+        for entry in shard
+            .entries
+            .iter_mut()
+            .filter(|entry| !entry.deleted && entry.uuid == *uuid)
+        {
+            if let Some(origin) = origin {
+                let previous = std::mem::replace(&mut entry.origin, origin.to_string());
+                moved.push((previous, entry.samples));
+            }
+            if let Some(metadata) = metadata {
+                entry.metadata = metadata.clone();
+            }
+            updated += 1;
+        }
+        // Evicted registrations keep no metadata, only their origin
+        if let Some(evicted) = shard.evicted.get_mut(uuid) {
+            for (previous, samples) in evicted.iter_mut() {
+                if let Some(origin) = origin {
+                    moved.push((std::mem::replace(previous, origin.to_string()), *samples));
+                }
+                updated += 1;
+            }
+        }
+
+        if let Some(origin) = origin {
+            let mut origin_counts = self.origin_counts.lock().unwrap_or_else(|e| e.into_inner());
+            for (previous, samples) in moved {
+                if let Some(count) = origin_counts.get_mut(&previous) {
+                    *count = count.saturating_sub(samples as usize);
+                }
+                *origin_counts.entry(origin.to_string()).or_insert(0) += samples as usize;
+            }
+        }
+        updated
+    }
+
+    // Drops the entries of a uuid from memory while keeping it accounted for:
+    // quotas and registration counts still include it, and registrations of
+    // the uuid are only counted until it is paged back in with `remove` and a
+    // reload from the database. Returns the entries freed.
+    pub async fn evict(&self, uuid: &Uuid) -> usize {
+        let mut shard = self.write_shard(self.shard_index(uuid)).await;
+        let dropped = self.drop_entries(&mut shard, uuid);
+        let freed = dropped.len();
+        if freed > 0 {
+            shard.evicted.entry(*uuid).or_default().extend(dropped);
+        }
+        freed
+    }
+
+    // Accounts for the registrations (origin, samples) of a uuid that was
+    // never loaded as if it had been evicted, e.g. an archived target skipped
+    // by the loader. Does nothing when the uuid has entries in memory or is
+    // evicted already; returns whether it is held.
+    pub async fn hold(&self, uuid: Uuid, registrations: Vec<(String, u32)>) -> bool {
+        if !self.owns(&uuid) {
+            return false;
+        }
+        let mut shard = self.write_shard(self.shard_index(&uuid)).await;
+        if shard.evicted.contains_key(&uuid)
+            || shard
+                .entries
+                .iter()
+                .any(|entry| !entry.deleted && entry.uuid == uuid)
+        {
+            return false;
+        }
+        let mut origin_counts = self.origin_counts.lock().unwrap_or_else(|e| e.into_inner());
+        for (origin, samples) in &registrations {
+            *origin_counts.entry(origin.clone()).or_insert(0) += *samples as usize;
+        }
+        shard.evicted.insert(uuid, registrations);
+        true
+    }
+
+    pub async fn is_evicted(&self, uuid: &Uuid) -> bool {
+        self.read_shard(self.shard_index(uuid))
+            .await
+            .evicted
+            .contains_key(uuid)
+    }
+
+    pub async fn evicted_count(&self) -> usize {
+        let mut evicted = 0;
+        for index in 0..self.shards.len() {
+            evicted += self.read_shard(index).await.evicted.len();
+        }
+        evicted
+    }
+
+    // Last use of every uuid in memory, with its number of entries and
+    // vectors, for least-recently-matched eviction
+    pub async fn usage(&self) -> Vec<(u64, Uuid, usize, usize)> {
+        let mut usage = Vec::new();
+        for index in 0..self.shards.len() {
+            let shard = self.read_shard(index).await;
+            let mut by_uuid: HashMap<Uuid, (u64, usize, usize)> = HashMap::new();
+            for entry in shard.entries.iter().filter(|entry| !entry.deleted) {
+                let (last_used, entries, vectors) = by_uuid.entry(entry.uuid).or_default();
+                *last_used = (*last_used).max(entry.last_used.get());
+                *entries += 1;
+                *vectors += entry.embeddings.len();
+            }
+            usage.extend(
+                by_uuid
+                    .into_iter()
+                    .map(|(uuid, (last_used, entries, vectors))| {
+                        (last_used, uuid, entries, vectors)
+                    }),
+            );
+        }
+        usage
+    }
+
+    // Estimated memory held by the vectors and entries of the store
+    pub fn memory_bytes(&self) -> usize {
+        let vectors = self.vector_count.load(Ordering::Relaxed);
+        // Reduced copies: coordinates plus offset and norm
+        let reduced = self
+            .projection()
+            .map_or(0, |projection| projection.dimensions() + 2);
+        entries_bytes(self.len(), vectors, self.vector_bytes())
+            + vectors * reduced * std::mem::size_of::<f32>()
+    }
+
+    // Relabels every entry of an origin, evicted ones included, and folds its
+    // registration count into the new one; returns the entries relabeled
+    pub async fn rename_origin(&self, from: &str, to: &str) -> usize {
+        let mut renamed = 0;
+        for index in 0..self.shards.len() {
+            let mut shard = self.write_shard(index).await;
+            shard.version += 1;
+            for entry in shard
+                .entries
+                .iter_mut()
+                .filter(|entry| !entry.deleted && entry.origin == from)
+            {
+                entry.origin = to.to_string();
+                renamed += 1;
+            }
+            for (origin, _) in shard.evicted.values_mut().flatten() {
+                if origin == from {
+                    *origin = to.to_string();
+                    renamed += 1;
+                }
+            }
+        }
+
+        let mut origin_counts = self.origin_counts.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = origin_counts.remove(from) {
+            *origin_counts.entry(to.to_string()).or_insert(0) += count;
+        }
+        renamed
+    }
+
+    // Whether any shard crossed the compaction ratio
+    pub async fn needs_compaction(&self) -> bool {
+        for index in 0..self.shards.len() {
+            if self
+                .read_shard(index)
+                .await
+                .needs_compaction(self.compaction_ratio)
+            {
+                return true;
+            }
+        }
+        false
+    }
+
+    // Frees the spare capacity of the shards, e.g. once the gallery is loaded
+    #[cfg(feature = "edge")]
+    pub async fn shrink_to_fit(&self) {
+        for index in 0..self.shards.len() {
+            let mut shard = self.write_shard(index).await;
+            shard.entries.shrink_to_fit();
+            shard.index.shrink_to_fit();
+            shard.evicted.shrink_to_fit();
+        }
+    }
+
+    // Rebuilds the storage of shards over the compaction ratio without their
+    // holes. The live entries are copied under the read lock, so searches keep
+    // running; the write lock is only taken to swap the new storage in. A shard
+    // written to in between is left for the next compaction.
+    pub async fn compact(&self) -> usize {
+        let mut reclaimed = 0;
+        for index in 0..self.shards.len() {
+            #[cfg(feature = "gpu-search")]
+            let mut gpu = None;
+            let (version, holes, entries, moved, mut ivf, mut pca) = {
+                let shard = self.read_shard(index).await;
+                if !shard.needs_compaction(self.compaction_ratio) {
+                    continue;
+                }
+                #[cfg(feature = "gpu-search")]
+                {
+                    gpu = shard.gpu.as_ref().map(GpuVectors::cleared);
+                }
+                // New position of each old one, None for the holes
+                let mut moved = Vec::with_capacity(shard.entries.len());
+                let mut entries = Vec::with_capacity(shard.entries.len() - shard.holes);
+                for entry in &shard.entries {
+                    if entry.deleted {
+                        moved.push(None);
+                    } else {
+                        moved.push(Some(entries.len() as u32));
+                        entries.push(entry.clone());
+                    }
+                }
+                (
+                    shard.version,
+                    shard.holes,
+                    entries,
+                    moved,
+                    shard.ivf.clone(),
+                    shard.pca.clone(),
+                )
+            };
+            if let Some(ivf) = &mut ivf {
+                ivf.remap(|position| moved[position as usize]);
+            }
+            if let Some(pca) = &mut pca {
+                pca.remap(|position| moved.get(position as usize).copied().flatten());
+            }
+            // Uploaded again rather than remapped, so the rows of the deleted
+            // entries and of superseded `Mean` templates are freed
+            #[cfg(feature = "gpu-search")]
+            if let Some(gpu) = &mut gpu {
+                for (position, entry) in entries.iter().enumerate() {
+                    for embedding in &entry.embeddings {
+                        gpu.add(position, &embedding.to_f32());
+                    }
+                }
+            }
+            let index_by_uuid: HashMap<Uuid, usize> = if self.template_mode != TemplateMode::Off {
+                entries
+                    .iter()
+                    .enumerate()
+                    .map(|(position, entry)| (entry.uuid, position))
+                    .collect()
+            } else {
+                HashMap::new()
+            };
+
+            let mut shard = self.write_shard(index).await;
+            if shard.version != version {
+                tracing::debug!(shard = index, "Shard changed during compaction, skipping");
+                continue;
+            }
+            shard.entries = entries;
+            shard.index = index_by_uuid;
+            shard.ivf = ivf;
+            shard.pca = pca;
+            #[cfg(feature = "gpu-search")]
+            {
+                shard.gpu = gpu;
+            }
+            shard.holes = 0;
+            shard.version += 1;
+            self.hole_count.fetch_sub(holes, Ordering::Relaxed);
+            reclaimed += holes;
+        }
+        reclaimed
+    }
+}
+
+// Estimated memory of `entries` entries holding `vectors` vectors of
+// `vector_bytes` each
+pub fn entries_bytes(entries: usize, vectors: usize, vector_bytes: usize) -> usize {
+    vectors * vector_bytes + entries * ENTRY_OVERHEAD_BYTES
+}
+
+impl Default for EmbeddingsStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
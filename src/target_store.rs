@@ -0,0 +1,460 @@
+use async_trait::async_trait;
+use futures_util::stream::BoxStream;
+use futures_util::{StreamExt, TryStreamExt};
+use sqlx::{PgConnection, PgPool, Row};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+use uuid::Uuid;
+
+use crate::archive::ArchivePolicy;
+use crate::breaker::WriteError;
+use crate::collections::Collections;
+use crate::encryption;
+use crate::handlers::RegisterMode;
+use crate::notify::{Change, Notifier};
+use crate::store::{Metadata, NewEmbedding};
+
+// Rows read from the stream before they are added to the stores; edge
+// builds keep fewer rows in flight to bound the startup memory
+#[cfg(not(feature = "edge"))]
+const LOAD_BATCH_ROWS: usize = 10_000;
+#[cfg(feature = "edge")]
+const LOAD_BATCH_ROWS: usize = 1_000;
+
+// The faces of one registration, written at once
+pub struct NewTargets<'a> {
+    pub tenant: &'a str,
+    pub collection: &'a str,
+    // The uuid the mode applies to; unused in `Append` mode
+    pub target_uuid: Uuid,
+    pub mode: RegisterMode,
+    pub origin: &'a str,
+    pub metadata: &'a Metadata,
+    // Unix seconds
+    pub expires_at: Option<i64>,
+    // Version of the model that computed the embeddings
+    pub model: &'a str,
+    // (uuid, embedding, crop key) of every face
+    pub faces: Vec<(Uuid, &'a [f32], Option<&'a str>)>,
+}
+
+// A row deleted by the expiry sweep: tenant, collection, uuid and crop key
+pub type ExpiredRow = (String, String, Uuid, Option<String>);
+
+// A live registration, as streamed by `TargetStore::stream`
+pub struct StoredTarget {
+    pub tenant: String,
+    pub collection: String,
+    pub embedding: NewEmbedding,
+}
+
+// Persistence of the registrations behind the in-memory stores. Handlers go
+// through it, so another backend only needs an implementation of its own.
+#[async_trait]
+pub trait TargetStore: Send + Sync {
+    // Writes the faces atomically and returns the crop keys of the rows
+    // replaced in `Replace` mode, or None when the uuid turned out to exist in
+    // `RejectIfExists` mode and nothing was written
+    async fn insert(
+        &self,
+        targets: &NewTargets<'_>,
+    ) -> Result<Option<Vec<Option<String>>>, WriteError>;
+
+    // Writes `Append` registrations in one transaction, all or none
+    async fn insert_batch(&self, batch: &[NewTargets<'_>]) -> Result<(), WriteError>;
+
+    // Whether the uuid has live registrations in the collection
+    async fn exists(&self, tenant: &str, collection: &str, uuid: Uuid)
+        -> Result<bool, sqlx::Error>;
+
+    // Deletes the live registrations of the uuid and returns how many there were
+    async fn delete(&self, tenant: &str, collection: &str, uuid: Uuid) -> Result<u64, sqlx::Error>;
+
+    // Sets the origin and/or replaces the metadata of the live registrations
+    // of the uuid, returning how many there were
+    async fn update(
+        &self,
+        tenant: &str,
+        collection: &str,
+        uuid: Uuid,
+        origin: Option<&str>,
+        metadata: Option<&Metadata>,
+    ) -> Result<u64, sqlx::Error>;
+
+    // Round trip to the backend, for health checks
+    async fn ping(&self) -> Result<(), sqlx::Error>;
+
+    // Deletes for good up to `limit` rows past their expiry or older than
+    // `retention_secs`, soft-deleted ones included. Postgres sweeps in
+    // expiry.rs, where the change notifications join the transaction.
+    async fn delete_expired(
+        &self,
+        _retention_secs: Option<i64>,
+        _limit: i64,
+    ) -> Result<Vec<ExpiredRow>, sqlx::Error> {
+        Ok(Vec::new())
+    }
+
+    // Live registrations of the uuid, to bring its in-memory entries back in
+    // line. Postgres reloads in resync.rs, which decrypts sealed vectors.
+    async fn target(
+        &self,
+        _tenant: &str,
+        _collection: &str,
+        _uuid: Uuid,
+    ) -> Result<Vec<NewEmbedding>, sqlx::Error> {
+        Ok(Vec::new())
+    }
+
+    // Model of the oldest row tagged with one. Untagged rows predate the
+    // tags, so they were embedded by it; None when no row is tagged or the
+    // backend keeps no models
+    async fn legacy_model_version(&self) -> Result<Option<String>, sqlx::Error> {
+        Ok(None)
+    }
+
+    // Live registrations written after the `after_id` cursor, in write order
+    fn stream(&self, after_id: i64) -> BoxStream<'_, Result<StoredTarget, sqlx::Error>>;
+
+    // Streams the registrations after `after_id` into the collections, so
+    // they are never held in memory as a whole; returns the rows loaded
+    async fn load_all(
+        &self,
+        collections: &Collections,
+        after_id: i64,
+    ) -> Result<usize, sqlx::Error> {
+        let start = Instant::now();
+        let mut rows = self.stream(after_id);
+        let mut batch: HashMap<(String, String), Vec<NewEmbedding>> = HashMap::new();
+        let mut pending = 0;
+        let mut loaded = 0;
+        let mut rejected = 0;
+        while let Some(target) = rows.try_next().await? {
+            batch
+                .entry((target.tenant, target.collection))
+                .or_default()
+                .push(target.embedding);
+            pending += 1;
+            if pending == LOAD_BATCH_ROWS {
+                rejected += add_loaded(collections, &mut batch).await;
+                loaded += pending;
+                pending = 0;
+                tracing::info!(rows = loaded, elapsed = ?start.elapsed(), "Loading embeddings...");
+            }
+        }
+        rejected += add_loaded(collections, &mut batch).await;
+        loaded += pending;
+        if rejected > 0 {
+            tracing::error!(
+                rejected,
+                "Rows with embeddings of the wrong dimension left out of memory, see /admin/consistency"
+            );
+        }
+        tracing::info!(rows = loaded - rejected, duration = ?start.elapsed(), "Embeddings loaded");
+        Ok(loaded - rejected)
+    }
+}
+
+// Adds a batch of loaded rows to their collections, one store call each;
+// returns the rows rejected for their dimension
+async fn add_loaded(
+    collections: &Collections,
+    batch: &mut HashMap<(String, String), Vec<NewEmbedding>>,
+) -> usize {
+    let mut rejected = 0;
+    for ((tenant, collection), embeddings) in batch.drain() {
+        let store = &collections.get_or_create(&tenant, &collection).store;
+        for uuid in store.add_batch(embeddings).await {
+            tracing::warn!(%tenant, %collection, %uuid, expected = ?store.dimension(), "Skipped a stored embedding of the wrong dimension");
+            rejected += 1;
+        }
+    }
+    collections.enforce_memory_limits().await;
+    rejected
+}
+
+// The targets table; deletions are soft, leaving tombstones to restore
+pub struct PostgresTargets {
+    pool: PgPool,
+    // Pool the registrations are streamed from: the primary, so a lagging
+    // replica never leaves rows out of memory, without the statement timeout
+    bulk_pool: PgPool,
+    // Writes are published to the other instances in their transaction
+    notifier: Option<Arc<Notifier>>,
+    // Archived targets are left out of the stream
+    archive: Option<Arc<ArchivePolicy>>,
+}
+
+impl PostgresTargets {
+    pub fn new(pool: PgPool) -> Self {
+        Self {
+            bulk_pool: pool.clone(),
+            pool,
+            notifier: None,
+            archive: None,
+        }
+    }
+
+    pub fn with_bulk_pool(mut self, bulk_pool: PgPool) -> Self {
+        self.bulk_pool = bulk_pool;
+        self
+    }
+
+    pub fn with_notifier(mut self, notifier: Option<Arc<Notifier>>) -> Self {
+        self.notifier = notifier;
+        self
+    }
+
+    pub fn with_archive(mut self, archive: Option<Arc<ArchivePolicy>>) -> Self {
+        self.archive = archive;
+        self
+    }
+
+    // The rows of a registration and their notifications, in the caller's
+    // transaction
+    async fn insert_faces(
+        &self,
+        connection: &mut PgConnection,
+        targets: &NewTargets<'_>,
+    ) -> Result<(), sqlx::Error> {
+        for (target_uuid, embedding, image_key) in &targets.faces {
+            let (plain, sealed) = encryption::columns(embedding)?;
+            sqlx::query(
+            "INSERT INTO targets (uuid, embeddings, embeddings_sealed, origin, metadata, collection, tenant, image_key, expires_at, model) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, to_timestamp($9), $10)",
+        )
+        .bind(target_uuid)
+        .bind(plain)
+        .bind(sealed)
+        .bind(targets.origin)
+        .bind(sqlx::types::Json(targets.metadata))
+        .bind(targets.collection)
+        .bind(targets.tenant)
+        .bind(image_key)
+        .bind(targets.expires_at.map(|expires_at| expires_at as f64))
+        .bind(targets.model)
+        .execute(&mut *connection)
+        .await?;
+        }
+        if let Some(notifier) = &self.notifier {
+            let mut uuids: Vec<Uuid> = targets.faces.iter().map(|(uuid, _, _)| *uuid).collect();
+            uuids.sort();
+            uuids.dedup();
+            for uuid in uuids {
+                notifier
+                    .publish(
+                        &mut *connection,
+                        Change::Target {
+                            tenant: targets.tenant.to_string(),
+                            collection: targets.collection.to_string(),
+                            uuid,
+                        },
+                    )
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Whether the uuid has live rows in the collection
+async fn target_exists<'e, E: sqlx::PgExecutor<'e>>(
+    executor: E,
+    tenant: &str,
+    name: &str,
+    target_uuid: Uuid,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM targets WHERE tenant = $1 AND collection = $2 AND uuid = $3 AND deleted_at IS NULL)",
+    )
+    .bind(tenant)
+    .bind(name)
+    .bind(target_uuid)
+    .fetch_one(executor)
+    .await
+}
+
+#[async_trait]
+impl TargetStore for PostgresTargets {
+    async fn insert(
+        &self,
+        targets: &NewTargets<'_>,
+    ) -> Result<Option<Vec<Option<String>>>, WriteError> {
+        let mut transaction = self.pool.begin().await?;
+        let mut replaced_keys: Vec<Option<String>> = Vec::new();
+        if targets.mode != RegisterMode::Append {
+            // Serializes the registrations of the uuid until commit
+            sqlx::query("SELECT pg_advisory_xact_lock(hashtextextended($1, 0))")
+                .bind(format!(
+                    "{}/{}/{}",
+                    targets.tenant, targets.collection, targets.target_uuid
+                ))
+                .execute(&mut *transaction)
+                .await?;
+        }
+        match targets.mode {
+            RegisterMode::Append => {}
+            RegisterMode::Replace => {
+                replaced_keys = sqlx::query_scalar(
+                    "DELETE FROM targets WHERE tenant = $1 AND collection = $2 AND uuid = $3 RETURNING image_key",
+                )
+                .bind(targets.tenant)
+                .bind(targets.collection)
+                .bind(targets.target_uuid)
+                .fetch_all(&mut *transaction)
+                .await?;
+            }
+            RegisterMode::RejectIfExists => {
+                if target_exists(
+                    &mut *transaction,
+                    targets.tenant,
+                    targets.collection,
+                    targets.target_uuid,
+                )
+                .await?
+                {
+                    return Ok(None);
+                }
+            }
+        }
+        self.insert_faces(&mut transaction, targets).await?;
+        transaction.commit().await.map_err(WriteError::Commit)?;
+        Ok(Some(replaced_keys))
+    }
+
+    async fn insert_batch(&self, batch: &[NewTargets<'_>]) -> Result<(), WriteError> {
+        let mut transaction = self.pool.begin().await?;
+        for targets in batch {
+            self.insert_faces(&mut transaction, targets).await?;
+        }
+        transaction.commit().await.map_err(WriteError::Commit)
+    }
+
+    async fn exists(
+        &self,
+        tenant: &str,
+        collection: &str,
+        uuid: Uuid,
+    ) -> Result<bool, sqlx::Error> {
+        target_exists(&self.pool, tenant, collection, uuid).await
+    }
+
+    // Soft delete: the rows are kept with their deletion time, and so are
+    // their crops
+    async fn delete(&self, tenant: &str, collection: &str, uuid: Uuid) -> Result<u64, sqlx::Error> {
+        let deleted = sqlx::query(
+            "UPDATE targets SET deleted_at = now() WHERE tenant = $1 AND collection = $2 AND uuid = $3 AND deleted_at IS NULL",
+        )
+        .bind(tenant)
+        .bind(collection)
+        .bind(uuid)
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+        if deleted > 0 {
+            if let Some(notifier) = &self.notifier {
+                let change = Change::Target {
+                    tenant: tenant.to_string(),
+                    collection: collection.to_string(),
+                    uuid,
+                };
+                // Resync, when enabled, catches instances that missed the deletion
+                if let Err(e) = notifier.publish(&self.pool, change).await {
+                    tracing::warn!(uuid = %uuid, error = %e, "Failed to notify target deletion");
+                }
+            }
+        }
+        Ok(deleted)
+    }
+
+    async fn update(
+        &self,
+        tenant: &str,
+        collection: &str,
+        uuid: Uuid,
+        origin: Option<&str>,
+        metadata: Option<&Metadata>,
+    ) -> Result<u64, sqlx::Error> {
+        let mut transaction = self.pool.begin().await?;
+        // A new id, so a snapshot holding the rows with their old labels is
+        // recognized as stale
+        let updated = sqlx::query(
+            "UPDATE targets SET origin = COALESCE($4, origin), metadata = COALESCE($5, metadata), id = nextval(pg_get_serial_sequence('targets', 'id')) WHERE tenant = $1 AND collection = $2 AND uuid = $3 AND deleted_at IS NULL",
+        )
+        .bind(tenant)
+        .bind(collection)
+        .bind(uuid)
+        .bind(origin)
+        .bind(metadata.map(sqlx::types::Json))
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+        if updated > 0 {
+            if let Some(notifier) = &self.notifier {
+                let change = Change::Target {
+                    tenant: tenant.to_string(),
+                    collection: collection.to_string(),
+                    uuid,
+                };
+                notifier.publish(&mut *transaction, change).await?;
+            }
+        }
+        transaction.commit().await?;
+        Ok(updated)
+    }
+
+    async fn ping(&self) -> Result<(), sqlx::Error> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn legacy_model_version(&self) -> Result<Option<String>, sqlx::Error> {
+        sqlx::query_scalar("SELECT model FROM targets WHERE model IS NOT NULL ORDER BY id LIMIT 1")
+            .fetch_optional(&self.bulk_pool)
+            .await
+    }
+
+    // The cursor is the row id
+    fn stream(&self, after_id: i64) -> BoxStream<'_, Result<StoredTarget, sqlx::Error>> {
+        let query = match &self.archive {
+            None => sqlx::query(
+                "SELECT uuid, embeddings, embeddings_sealed, origin, metadata, collection, tenant, model FROM targets WHERE id > $1 AND deleted_at IS NULL",
+            )
+            .bind(after_id),
+            // Same archived targets as `archive::sweep`
+            Some(archive) => sqlx::query(
+                r#"
+                SELECT uuid, embeddings, embeddings_sealed, origin, metadata, collection, tenant, model FROM targets
+                WHERE id > $1 AND deleted_at IS NULL AND (tenant, collection, uuid) NOT IN (
+                    SELECT tenant, collection, uuid FROM targets WHERE deleted_at IS NULL
+                    GROUP BY tenant, collection, uuid
+                    HAVING ($2::BIGINT IS NOT NULL AND MAX(created_at) < now() - $2::BIGINT * interval '1 second')
+                        OR bool_and(origin = ANY($3))
+                )
+                "#,
+            )
+            .bind(after_id)
+            .bind(archive.after_secs)
+            .bind(&archive.origins),
+        };
+        query
+            .fetch(&self.bulk_pool)
+            .map(|record| {
+                let record = record?;
+                let metadata: sqlx::types::Json<Metadata> = record.try_get("metadata")?;
+                Ok(StoredTarget {
+                    tenant: record.try_get("tenant")?,
+                    collection: record.try_get("collection")?,
+                    embedding: NewEmbedding {
+                        uuid: record.try_get("uuid")?,
+                        origin: record.try_get("origin").unwrap_or_else(|_| "".to_string()),
+                        metadata: metadata.0,
+                        embedding: encryption::from_row(&record)?,
+                        model_version: record.try_get("model")?,
+                        expires_at: None,
+                    },
+                })
+            })
+            .boxed()
+    }
+}
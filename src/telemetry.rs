@@ -0,0 +1,354 @@
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use prometheus::{
+    register_histogram, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge, register_int_gauge_vec, Encoder, Histogram, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+use crate::AppState;
+
+// Pipeline stages timed by `observe_stage`
+pub const STAGE_DECODE: &str = "decode";
+pub const STAGE_DETECT: &str = "detect";
+pub const STAGE_LIVENESS: &str = "liveness";
+pub const STAGE_POSE: &str = "pose";
+pub const STAGE_MASK: &str = "mask";
+pub const STAGE_ATTRIBUTES: &str = "attributes";
+pub const STAGE_EMOTION: &str = "emotion";
+pub const STAGE_LANDMARKS: &str = "landmarks";
+pub const STAGE_PREPROCESS: &str = "preprocess";
+pub const STAGE_INFERENCE: &str = "inference";
+pub const STAGE_SEARCH: &str = "search";
+
+// 1ms .. ~16s, covers a cache-hot search as well as a slow CPU inference
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 16.0,
+];
+
+static HTTP_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "owlfacerec_http_requests_total",
+        "HTTP requests by route and status",
+        &["method", "route", "status"]
+    )
+    .expect("valid metric")
+});
+
+static HTTP_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "owlfacerec_http_request_duration_seconds",
+        "HTTP request latency by route",
+        &["method", "route"],
+        LATENCY_BUCKETS.to_vec()
+    )
+    .expect("valid metric")
+});
+
+static STAGE_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "owlfacerec_stage_duration_seconds",
+        "Latency of the decode, detect, liveness, pose, mask, attributes, preprocess, inference and search stages",
+        &["stage"],
+        LATENCY_BUCKETS.to_vec()
+    )
+    .expect("valid metric")
+});
+
+static SEARCH_DURATION: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "owlfacerec_search_duration_seconds",
+        "Latency of whole image searches, from decoding to the last match, over every API",
+        LATENCY_BUCKETS.to_vec()
+    )
+    .expect("valid metric")
+});
+
+static INFERENCE_TIMEOUTS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "owlfacerec_inference_timeouts_total",
+        "Image pipeline runs abandoned after INFERENCE_TIMEOUT_MS"
+    )
+    .expect("valid metric")
+});
+
+static INFERENCE_QUEUED: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "owlfacerec_inference_queued",
+        "Requests waiting for an inference slot"
+    )
+    .expect("valid metric")
+});
+
+static INFERENCE_REJECTED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "owlfacerec_inference_rejected_total",
+        "Requests turned away with 503 because the inference queue was full"
+    )
+    .expect("valid metric")
+});
+
+static WRITE_BEHIND_PENDING: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "owlfacerec_write_behind_pending",
+        "Registrations acknowledged but not yet written to the database"
+    )
+    .expect("valid metric")
+});
+
+static QUERY_CACHE_LOOKUPS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "owlfacerec_query_cache_lookups_total",
+        "Query cache lookups of searched faces by result (hit, miss)",
+        &["result"]
+    )
+    .expect("valid metric")
+});
+
+static STORE_ENTRIES: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "owlfacerec_store_entries",
+        "Entries in the in-memory store per collection",
+        &["tenant", "collection"]
+    )
+    .expect("valid metric")
+});
+
+static STORE_HOLES: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "owlfacerec_store_holes",
+        "Deleted entries awaiting compaction per collection",
+        &["tenant", "collection"]
+    )
+    .expect("valid metric")
+});
+
+static DB_POOL_CONNECTIONS: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "owlfacerec_db_pool_connections",
+        "Database pool connections by state",
+        &["state"]
+    )
+    .expect("valid metric")
+});
+
+pub fn observe_stage(stage: &str, duration: Duration) {
+    STAGE_DURATION
+        .with_label_values(&[stage])
+        .observe(duration.as_secs_f64());
+}
+
+pub fn inference_timed_out() {
+    INFERENCE_TIMEOUTS.inc();
+}
+
+pub fn set_inference_queued(queued: usize) {
+    INFERENCE_QUEUED.set(queued as i64);
+}
+
+pub fn inference_rejected() {
+    INFERENCE_REJECTED.inc();
+}
+
+pub fn set_write_behind_pending(pending: usize) {
+    WRITE_BEHIND_PENDING.set(pending as i64);
+}
+
+pub fn query_cache_lookup(hit: bool) {
+    let result = if hit { "hit" } else { "miss" };
+    QUERY_CACHE_LOOKUPS.with_label_values(&[result]).inc();
+}
+
+pub fn observe_search(duration: Duration) {
+    SEARCH_DURATION.observe(duration.as_secs_f64());
+}
+
+// Layer of the whole router; routes are labelled by their pattern
+// (/collections/:name/search/), never by the raw path
+pub async fn track_requests(request: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+
+    let response = next.run(request).await;
+
+    HTTP_REQUESTS
+        .with_label_values(&[method.as_str(), route.as_str(), response.status().as_str()])
+        .inc();
+    HTTP_DURATION
+        .with_label_values(&[method.as_str(), route.as_str()])
+        .observe(start.elapsed().as_secs_f64());
+    response
+}
+
+// Operations timed since startup and their mean latency
+#[derive(Serialize)]
+pub struct Timing {
+    count: u64,
+    mean_ms: f64,
+}
+
+impl Timing {
+    fn of(histogram: &Histogram) -> Self {
+        let count = histogram.get_sample_count();
+        let mean_ms = if count == 0 {
+            0.0
+        } else {
+            histogram.get_sample_sum() * 1000.0 / count as f64
+        };
+        Self { count, mean_ms }
+    }
+}
+
+// Lookups of the query cache since startup
+#[derive(Serialize)]
+pub struct CacheStats {
+    hits: u64,
+    misses: u64,
+    hit_rate: f64,
+}
+
+impl CacheStats {
+    fn current() -> Self {
+        let hits = QUERY_CACHE_LOOKUPS.with_label_values(&["hit"]).get();
+        let misses = QUERY_CACHE_LOOKUPS.with_label_values(&["miss"]).get();
+        let hit_rate = if hits + misses == 0 {
+            0.0
+        } else {
+            hits as f64 / (hits + misses) as f64
+        };
+        Self {
+            hits,
+            misses,
+            hit_rate,
+        }
+    }
+}
+
+// Define the response for /stats
+#[derive(Serialize)]
+pub struct StatsResponse {
+    // In-memory entries across all tenants and collections
+    entries: usize,
+    // Targets evicted from memory by the memory limits
+    evicted: usize,
+    // Estimated memory of the entries
+    memory_bytes: usize,
+    collections: usize,
+    embedding_dimension: Option<usize>,
+    model: String,
+    model_version: String,
+    // In-memory entries per model that embedded them, largest first
+    model_versions: Vec<ModelVersionCount>,
+    // Entries of another model than the running one are skipped by searches
+    // until they are reindexed (see /admin/reenrollment)
+    mixed_model_versions: bool,
+    inference: Timing,
+    search: Timing,
+    // Only when QUERY_CACHE_URL is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    query_cache: Option<CacheStats>,
+}
+
+#[derive(Serialize)]
+pub struct ModelVersionCount {
+    // None for the entries written before models were recorded, taken to be
+    // of the model of the oldest recorded one
+    model_version: Option<String>,
+    entries: usize,
+}
+
+// Handler for GET /stats - instance-wide store and runtime statistics as
+// JSON, for dashboards that do not scrape Prometheus; behind the admin key
+pub async fn stats(State(state): State<AppState>) -> Json<StatsResponse> {
+    let collections = state.collections.all();
+    let mut evicted = 0;
+    let mut model_counts: HashMap<Option<String>, usize> = HashMap::new();
+    for (_, _, collection) in &collections {
+        evicted += collection.store.evicted_count().await;
+        for (model_version, entries) in collection.store.model_version_counts().await {
+            *model_counts.entry(model_version).or_insert(0) += entries;
+        }
+    }
+    let model_version = state.version.model_version();
+    let legacy_model_version = state.collections.legacy_model_version();
+    let mixed_model_versions = model_counts
+        .keys()
+        .filter_map(|version| version.as_deref().or(legacy_model_version))
+        .any(|version| version != model_version);
+    let mut model_versions: Vec<ModelVersionCount> = model_counts
+        .into_iter()
+        .map(|(model_version, entries)| ModelVersionCount {
+            model_version,
+            entries,
+        })
+        .collect();
+    model_versions.sort_by(|a, b| b.entries.cmp(&a.entries));
+    // Models that do not declare their output width report the stored one
+    let embedding_dimension = state.embedding_dimension.or_else(|| {
+        collections
+            .iter()
+            .find_map(|(_, _, collection)| collection.store.dimension())
+    });
+    Json(StatsResponse {
+        entries: state.collections.len(),
+        evicted,
+        memory_bytes: state.collections.memory_bytes(),
+        collections: collections.len(),
+        embedding_dimension,
+        model: state.model_name.clone(),
+        model_version: model_version.to_string(),
+        model_versions,
+        mixed_model_versions,
+        inference: Timing::of(&STAGE_DURATION.with_label_values(&[STAGE_INFERENCE])),
+        search: Timing::of(&SEARCH_DURATION),
+        query_cache: state.query_cache.as_ref().map(|_| CacheStats::current()),
+    })
+}
+
+// Handler for GET /metrics - Prometheus text format
+pub async fn prometheus_metrics(State(state): State<AppState>) -> Result<Response, StatusCode> {
+    // Gauges are refreshed at scrape time
+    STORE_ENTRIES.reset();
+    STORE_HOLES.reset();
+    for (tenant, name, collection) in state.collections.all() {
+        STORE_ENTRIES
+            .with_label_values(&[tenant.as_str(), name.as_str()])
+            .set(collection.store.len() as i64);
+        STORE_HOLES
+            .with_label_values(&[tenant.as_str(), name.as_str()])
+            .set(collection.store.hole_count() as i64);
+    }
+    let idle = state.db_pool.num_idle() as i64;
+    DB_POOL_CONNECTIONS.with_label_values(&["idle"]).set(idle);
+    DB_POOL_CONNECTIONS
+        .with_label_values(&["active"])
+        .set(state.db_pool.size() as i64 - idle);
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder
+        .encode(&prometheus::gather(), &mut buffer)
+        .map_err(|e| {
+            tracing::error!(error = %e, "Failed to encode Prometheus metrics");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok((
+        [(header::CONTENT_TYPE, encoder.format_type().to_string())],
+        buffer,
+    )
+        .into_response())
+}
@@ -0,0 +1,106 @@
+use axum::{
+    extract::{Request, State},
+    http::{HeaderMap, HeaderName, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use std::collections::HashMap;
+
+use crate::collections;
+use crate::AppState;
+
+// Tenant of single-tenant deployments (no API keys nor tenant header configured)
+pub const DEFAULT_TENANT: &str = "default";
+
+// Tenant a request acts on, inserted as a request extension by `resolve_tenant`
+#[derive(Clone, Debug)]
+pub struct Tenant(pub String);
+
+impl Tenant {
+    pub fn id(&self) -> &str {
+        &self.0
+    }
+}
+
+// How requests are mapped to tenants
+//
+// API keys are checked first (`X-API-Key` or `Authorization: Bearer`). The
+// tenant header is only meant for deployments behind a gateway that sets it
+// after authenticating the caller, since clients could otherwise pick any tenant.
+#[derive(Clone, Debug, Default)]
+pub struct TenantResolver {
+    // API key -> tenant id
+    api_keys: HashMap<String, String>,
+    header: Option<HeaderName>,
+}
+
+impl TenantResolver {
+    pub fn new(api_keys: HashMap<String, String>, header: Option<HeaderName>) -> Self {
+        Self { api_keys, header }
+    }
+
+    // Without keys or header every request belongs to the default tenant
+    pub fn is_multi_tenant(&self) -> bool {
+        !self.api_keys.is_empty() || self.header.is_some()
+    }
+
+    pub fn resolve(&self, headers: &HeaderMap) -> Result<Tenant, StatusCode> {
+        if !self.is_multi_tenant() {
+            return Ok(Tenant(DEFAULT_TENANT.to_string()));
+        }
+
+        if !self.api_keys.is_empty() {
+            if let Some(key) = api_key(headers) {
+                return self
+                    .api_keys
+                    .get(key)
+                    .map(|tenant| Tenant(tenant.clone()))
+                    .ok_or_else(|| {
+                        tracing::warn!("Rejected request with unknown API key");
+                        StatusCode::UNAUTHORIZED
+                    });
+            }
+        }
+
+        if let Some(header) = &self.header {
+            if let Some(value) = headers.get(header) {
+                let tenant = value.to_str().unwrap_or_default().trim();
+                if !collections::is_valid_name(tenant) {
+                    tracing::warn!("Rejected request with invalid tenant header");
+                    return Err(StatusCode::BAD_REQUEST);
+                }
+                return Ok(Tenant(tenant.to_string()));
+            }
+        }
+
+        tracing::warn!("Rejected request without tenant credentials");
+        Err(StatusCode::UNAUTHORIZED)
+    }
+}
+
+fn api_key(headers: &HeaderMap) -> Option<&str> {
+    if let Some(key) = headers.get("x-api-key") {
+        return key.to_str().ok().map(str::trim);
+    }
+    headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+// Middleware that scopes the request to its tenant, unless `auth::authenticate`
+// already did so from the request's API key
+pub async fn resolve_tenant(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    if request.extensions().get::<Tenant>().is_some() {
+        return Ok(next.run(request).await);
+    }
+    let tenant = state.tenants.resolve(request.headers())?;
+    tracing::debug!(tenant = %tenant.id(), "Resolved request tenant");
+    request.extensions_mut().insert(tenant);
+    Ok(next.run(request).await)
+}
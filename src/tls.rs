@@ -0,0 +1,115 @@
+use axum_server::accept::Accept;
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+// The certificate files are checked for changes this often
+const RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+// Handshaken gRPC connections waiting for the server to take them
+const GRPC_BACKLOG: usize = 64;
+// Pause after a failed accept, e.g. out of file descriptors
+const ACCEPT_RETRY: Duration = Duration::from_millis(100);
+
+// A gRPC connection after its TLS handshake
+pub type GrpcStream = <RustlsAcceptor as Accept<TcpStream, ()>>::Stream;
+
+// HTTPS on the API port with the PEM certificate (chain) and private key of
+// TLS_CERT_PATH and TLS_KEY_PATH. Renewed files (e.g. by certbot) are picked
+// up by new connections without a restart.
+#[derive(Clone)]
+pub struct Tls {
+    pub config: RustlsConfig,
+    cert_path: PathBuf,
+    key_path: PathBuf,
+}
+
+impl Tls {
+    // Also watches the files in the background
+    pub async fn load(cert_path: PathBuf, key_path: PathBuf) -> std::io::Result<Self> {
+        let config = RustlsConfig::from_pem_file(&cert_path, &key_path).await?;
+        let tls = Self {
+            config,
+            cert_path,
+            key_path,
+        };
+        tokio::spawn(tls.clone().watch());
+        Ok(tls)
+    }
+
+    // TLS connections of the gRPC port. Each handshake takes the certificate
+    // as currently loaded, so renewals reach gRPC clients as they do HTTPS
+    // ones; handshakes run apart from the accept loop, a slow client does not
+    // hold up the others.
+    pub fn grpc_incoming(
+        &self,
+        listener: TcpListener,
+    ) -> ReceiverStream<std::io::Result<GrpcStream>> {
+        let acceptor = RustlsAcceptor::new(self.config.clone());
+        let (sender, receiver) = mpsc::channel(GRPC_BACKLOG);
+        tokio::spawn(async move {
+            loop {
+                let accepted = tokio::select! {
+                    accepted = listener.accept() => accepted,
+                    // The server stopped
+                    _ = sender.closed() => return,
+                };
+                let stream = match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        tracing::warn!(error = %e, "Failed to accept gRPC connection");
+                        tokio::time::sleep(ACCEPT_RETRY).await;
+                        continue;
+                    }
+                };
+                let acceptor = acceptor.clone();
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    match acceptor.accept(stream, ()).await {
+                        Ok((stream, ())) => {
+                            let _ = sender.send(Ok(stream)).await;
+                        }
+                        Err(e) => tracing::debug!(error = %e, "gRPC TLS handshake failed"),
+                    }
+                });
+            }
+        });
+        ReceiverStream::new(receiver)
+    }
+
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let modified = |path: &PathBuf| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        Some((modified(&self.cert_path)?, modified(&self.key_path)?))
+    }
+
+    async fn watch(self) {
+        let mut loaded = self.modified();
+        let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+        // The first tick completes right away
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let modified = self.modified();
+            if modified.is_none() || modified == loaded {
+                continue;
+            }
+            // A failed reload (e.g. the key not written yet) keeps the
+            // previous certificate and is retried on the next check
+            match self
+                .config
+                .reload_from_pem_file(&self.cert_path, &self.key_path)
+                .await
+            {
+                Ok(()) => {
+                    loaded = modified;
+                    tracing::info!(cert = %self.cert_path.display(), "TLS certificate reloaded");
+                }
+                Err(e) => {
+                    tracing::warn!(cert = %self.cert_path.display(), error = %e, "Failed to reload TLS certificate")
+                }
+            }
+        }
+    }
+}
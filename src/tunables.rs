@@ -0,0 +1,189 @@
+use axum::{extract::State, http::StatusCode, Json};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::sync::RwLock;
+
+use crate::AppState;
+
+// Settings that change without a restart (and without reloading the
+// gallery), through PUT /admin/config or SIGHUP re-reading the config file
+#[derive(Clone, Copy, Debug, Serialize, PartialEq)]
+pub struct Settings {
+    // Search threshold of requests and collections that set none
+    pub default_threshold: f32,
+    // Matches returned by searches that set no limit
+    pub default_limit: usize,
+    // Similarity from which search hits are published as match events
+    pub alert_threshold: f32,
+    // Per-client requests per second on the inference endpoints, None when
+    // rate limiting is off
+    pub rate_limit_rps: Option<f64>,
+    // Defaults to the rate
+    pub rate_limit_burst: Option<f64>,
+}
+
+impl Settings {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(-1.0..=1.0).contains(&self.default_threshold) {
+            return Err("default_threshold must be between -1 and 1".to_string());
+        }
+        if !(-1.0..=1.0).contains(&self.alert_threshold) {
+            return Err("alert_threshold must be between -1 and 1".to_string());
+        }
+        if self.default_limit == 0 {
+            return Err("default_limit must be positive".to_string());
+        }
+        for (name, value) in [
+            ("rate_limit_rps", self.rate_limit_rps),
+            ("rate_limit_burst", self.rate_limit_burst),
+        ] {
+            if value.is_some_and(|value| value.is_nan() || value <= 0.0) {
+                return Err(format!("{} must be positive", name));
+            }
+        }
+        Ok(())
+    }
+
+    // (rate, burst) of the rate limiter
+    pub fn rate_limits(&self) -> Option<(f64, f64)> {
+        self.rate_limit_rps
+            .map(|rate| (rate, self.rate_limit_burst.unwrap_or(rate)))
+    }
+}
+
+// Changes to the settings; absent fields keep their value, and a null rate
+// turns rate limiting off
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SettingsUpdate {
+    pub default_threshold: Option<f32>,
+    pub default_limit: Option<usize>,
+    pub alert_threshold: Option<f32>,
+    #[serde(default, deserialize_with = "present")]
+    pub rate_limit_rps: Option<Option<f64>>,
+    #[serde(default, deserialize_with = "present")]
+    pub rate_limit_burst: Option<Option<f64>>,
+}
+
+// Tells a null field (Some(None)) from an absent one (None)
+fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
+}
+
+pub struct Tunables {
+    settings: RwLock<Settings>,
+}
+
+impl Tunables {
+    pub fn new(settings: Settings) -> Self {
+        Self {
+            settings: RwLock::new(settings),
+        }
+    }
+
+    pub fn get(&self) -> Settings {
+        *self.settings.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn default_threshold(&self) -> f32 {
+        self.get().default_threshold
+    }
+
+    pub fn default_limit(&self) -> usize {
+        self.get().default_limit
+    }
+}
+
+// Validates the update and applies it to the settings, the match events and
+// the rate limiter at once
+pub fn apply(state: &AppState, update: &SettingsUpdate) -> Result<Settings, String> {
+    let mut settings = state
+        .tunables
+        .settings
+        .write()
+        .unwrap_or_else(|e| e.into_inner());
+    let mut updated = *settings;
+    if let Some(threshold) = update.default_threshold {
+        updated.default_threshold = threshold;
+    }
+    if let Some(limit) = update.default_limit {
+        updated.default_limit = limit;
+    }
+    if let Some(threshold) = update.alert_threshold {
+        updated.alert_threshold = threshold;
+    }
+    if let Some(rate) = update.rate_limit_rps {
+        updated.rate_limit_rps = rate;
+    }
+    if let Some(burst) = update.rate_limit_burst {
+        updated.rate_limit_burst = burst;
+    }
+    updated.validate()?;
+
+    state.events.set_alert_threshold(updated.alert_threshold);
+    state.rate_limiter.set_limits(updated.rate_limits());
+    *settings = updated;
+    Ok(updated)
+}
+
+// Re-reads the config file on every SIGHUP and applies its runtime settings;
+// the other settings of the file only change on restart
+#[cfg(unix)]
+pub async fn reload_on_hangup(
+    state: AppState,
+    path: std::path::PathBuf,
+    file_variables: Vec<&'static str>,
+) {
+    use crate::config::Config;
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to install SIGHUP handler");
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        let update = match Config::load(&path) {
+            Ok(config) => config.settings_update(&file_variables),
+            Err(e) => {
+                tracing::error!(error = %e, "Config reload failed, keeping the current settings");
+                continue;
+            }
+        };
+        match apply(&state, &update) {
+            Ok(settings) => {
+                tracing::info!(path = %path.display(), settings = ?settings, "Runtime settings reloaded")
+            }
+            Err(e) => {
+                tracing::error!(error = %e, "Config reload failed, keeping the current settings")
+            }
+        }
+    }
+}
+
+// Handler for GET /admin/config
+pub async fn get_config(State(state): State<AppState>) -> Json<Settings> {
+    Json(state.tunables.get())
+}
+
+// Handler for PUT /admin/config
+pub async fn update_config(
+    State(state): State<AppState>,
+    Json(update): Json<SettingsUpdate>,
+) -> Result<Json<Settings>, StatusCode> {
+    match apply(&state, &update) {
+        Ok(settings) => {
+            tracing::info!(settings = ?settings, "Runtime settings updated");
+            Ok(Json(settings))
+        }
+        Err(e) => {
+            tracing::warn!(error = %e, "Received invalid runtime settings update");
+            Err(StatusCode::BAD_REQUEST)
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Parses "key=value" pairs separated by commas, e.g. "users=10000,cctv=50000"
+pub fn parse_key_values<T: FromStr>(s: &str) -> Result<HashMap<String, T>, String> {
+    let mut values = HashMap::new();
+    for pair in s.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair
+            .split_once('=')
+            .ok_or_else(|| format!("invalid entry '{}', expected key=value", pair))?;
+        let value = value
+            .trim()
+            .parse::<T>()
+            .map_err(|_| format!("invalid value in '{}'", pair))?;
+        values.insert(key.trim().to_string(), value);
+    }
+    Ok(values)
+}
+
+// Seconds since the Unix epoch
+pub fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+// Format a Unix timestamp as an RFC 3339 UTC date-time (e.g. 2024-05-01T12:00:00Z)
+pub fn format_rfc3339(unix_seconds: i64) -> String {
+    let days = unix_seconds.div_euclid(86_400);
+    let seconds_of_day = unix_seconds.rem_euclid(86_400);
+
+    // Civil-from-days conversion (proleptic Gregorian calendar)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds_of_day / 3_600,
+        (seconds_of_day % 3_600) / 60,
+        seconds_of_day % 60
+    )
+}
@@ -0,0 +1,82 @@
+use axum::{extract::State, Json};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+
+use crate::AppState;
+
+// Execution providers registered on the ONNX sessions, depending on the
+// features of the build, ahead of the CPU provider that runs the rest
+pub const EXECUTION_PROVIDERS: &[&str] = &[
+    #[cfg(feature = "coreml")]
+    "CoreMLExecutionProvider",
+    #[cfg(feature = "directml")]
+    "DirectMLExecutionProvider",
+    "CPUExecutionProvider",
+];
+
+// Hex digits of the model hash tagging the vectors, enough to tell models
+// apart
+const MODEL_VERSION_LENGTH: usize = 16;
+
+// What is deployed, gathered once at startup
+#[derive(Clone, Serialize)]
+pub struct VersionInfo {
+    version: &'static str,
+    // Commit the binary was built from, "unknown" without git or GIT_COMMIT
+    git_commit: &'static str,
+    model_file: String,
+    // SHA-256 of the model file as loaded
+    model_sha256: String,
+    // Tag of the vectors computed with this model in the targets table
+    model_version: String,
+    embedding_dimension: Option<usize>,
+    execution_providers: &'static [&'static str],
+}
+
+impl VersionInfo {
+    pub fn new(model_path: &Path, embedding_dimension: Option<usize>) -> std::io::Result<Self> {
+        // Streamed, as the model file can be larger than the free memory of
+        // small devices
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut std::fs::File::open(model_path)?, &mut hasher)?;
+        let model_sha256 = hex::encode(hasher.finalize());
+        Ok(Self {
+            version: env!("CARGO_PKG_VERSION"),
+            git_commit: env!("GIT_COMMIT"),
+            model_file: model_path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            model_version: model_sha256[..MODEL_VERSION_LENGTH].to_string(),
+            model_sha256,
+            embedding_dimension,
+            execution_providers: EXECUTION_PROVIDERS,
+        })
+    }
+
+    // For the mock backend, which has no model file
+    #[cfg(feature = "mock-inference")]
+    pub fn mock(embedding_dimension: usize) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            git_commit: env!("GIT_COMMIT"),
+            model_file: "mock".to_string(),
+            model_sha256: String::new(),
+            model_version: "mock".to_string(),
+            embedding_dimension: Some(embedding_dimension),
+            execution_providers: &[],
+        }
+    }
+}
+
+impl VersionInfo {
+    pub fn model_version(&self) -> &str {
+        &self.model_version
+    }
+}
+
+// Handler for GET /version - build and model information
+pub async fn version(State(state): State<AppState>) -> Json<VersionInfo> {
+    Json(state.version.as_ref().clone())
+}
@@ -0,0 +1,236 @@
+use axum::{
+    body::Bytes,
+    extract::{Extension, Query, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Instant;
+use uuid::Uuid;
+
+use crate::collections::DEFAULT_COLLECTION;
+use crate::enhance::EnhanceOptions;
+use crate::ffmpeg;
+use crate::handlers::{self, Fusion, ImageInput, SearchPayload};
+use crate::jobs::JobProgress;
+use crate::store::Metadata;
+use crate::tenant::Tenant;
+use crate::AppState;
+
+pub const DEFAULT_MAX_VIDEO_BYTES: usize = 100 * 1024 * 1024;
+const DEFAULT_FPS: f32 = 2.0;
+const MAX_FPS: f32 = 30.0;
+// Frames searched per video at most (e.g. 30 minutes at 2 fps)
+const MAX_FRAMES: usize = 3600;
+
+// Query of POST /search/video/
+#[derive(Deserialize)]
+pub struct VideoQuery {
+    collection: Option<String>,
+    // Frames sampled per second of video
+    fps: Option<f32>,
+    threshold: Option<f32>,
+    // Identities matched per frame
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct VideoSearchResponse {
+    // Frames searched, one every 1/fps seconds
+    frames: usize,
+    // The video was longer than the frame limit and only its beginning was searched
+    truncated: bool,
+    identities: Vec<Identity>,
+}
+
+// A target seen in the video, with every frame it was matched in
+#[derive(Serialize)]
+pub struct Identity {
+    target_uuid: Uuid,
+    origin: String,
+    #[serde(skip_serializing_if = "Metadata::is_empty")]
+    metadata: Metadata,
+    best_similarity: f32,
+    appearances: Vec<Appearance>,
+}
+
+#[derive(Serialize)]
+pub struct Appearance {
+    // Seconds from the start of the video
+    timestamp: f32,
+    similarity: f32,
+}
+
+// Handler for POST /search/video/ - the body is the video file itself (mp4 or
+// any container ffmpeg reads); ?collection= as for /search/
+pub async fn search_video(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Query(query): Query<VideoQuery>,
+    body: Bytes,
+) -> Result<Json<VideoSearchResponse>, StatusCode> {
+    run_video_search(&state, &tenant, &query, &body, &JobProgress::default())
+        .await
+        .map(Json)
+}
+
+// Search of an uploaded video, also run as a background job; progress counts
+// the frames searched
+pub(crate) async fn run_video_search(
+    state: &AppState,
+    tenant: &Tenant,
+    query: &VideoQuery,
+    body: &[u8],
+    progress: &JobProgress,
+) -> Result<VideoSearchResponse, StatusCode> {
+    let start = Instant::now();
+    let collection = query.collection.as_deref().unwrap_or(DEFAULT_COLLECTION);
+    if state.collections.get(tenant.id(), collection).is_none() {
+        tracing::warn!(collection = %collection, "Received video search for unknown collection");
+        return Err(StatusCode::NOT_FOUND);
+    }
+    if body.is_empty() {
+        tracing::warn!("Received video search with empty body");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let fps = query.fps.unwrap_or(DEFAULT_FPS);
+    if !(fps > 0.0 && fps <= MAX_FPS) {
+        tracing::warn!(fps, "Received video search with invalid fps");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    // mp4 keeps its index at the end of the file, so ffmpeg needs a seekable
+    // input rather than a pipe
+    let path = std::env::temp_dir().join(format!("owlfacerec-video-{}", Uuid::new_v4()));
+    tokio::fs::write(&path, body).await.map_err(|e| {
+        tracing::error!(error = %e, "Failed to write uploaded video");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let result = search_frames(state, tenant, collection, query, fps, &path, progress).await;
+    if let Err(e) = tokio::fs::remove_file(&path).await {
+        tracing::warn!(path = ?path, error = %e, "Failed to remove uploaded video");
+    }
+    let response = result?;
+
+    tracing::info!(
+        duration = ?start.elapsed(),
+        frames = response.frames,
+        identities = response.identities.len(),
+        "Video search successful"
+    );
+    Ok(response)
+}
+
+async fn search_frames(
+    state: &AppState,
+    tenant: &Tenant,
+    collection: &str,
+    query: &VideoQuery,
+    fps: f32,
+    path: &std::path::Path,
+    progress: &JobProgress,
+) -> Result<VideoSearchResponse, StatusCode> {
+    let (_child, mut frames) =
+        ffmpeg::sample_frames(&path.to_string_lossy(), fps).map_err(|e| {
+            tracing::error!(error = %e, "Failed to start ffmpeg");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let payload = SearchPayload {
+        image_base64: String::new(),
+        embedding: None,
+        threshold: query.threshold,
+        limit: query.limit,
+        enhance: EnhanceOptions::default(),
+        group_by_uuid: true,
+        hit_counts: false,
+        origins: None,
+        metadata: None,
+        exclude_uuids: None,
+        exclude_origins: None,
+        time_budget_ms: None,
+        nprobe: None,
+        attributes: false,
+        emotion: false,
+        return_crops: false,
+        return_embedding: false,
+        source: None,
+        images_base64: Vec::new(),
+        fusion: Fusion::default(),
+        include_archived: false,
+    };
+
+    let mut identities: HashMap<Uuid, Identity> = HashMap::new();
+    let mut searched = 0;
+    let mut truncated = false;
+    loop {
+        let frame = frames.next().await.map_err(|e| {
+            tracing::error!(error = %e, "Failed to read video frames");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        let Some(frame) = frame else {
+            break;
+        };
+        if searched == MAX_FRAMES {
+            truncated = true;
+            break;
+        }
+        let timestamp = searched as f32 / fps;
+        searched += 1;
+        progress.set(searched, None);
+
+        // Frames without a usable face are skipped, not fatal
+        let found = match handlers::run_search_image(
+            state,
+            tenant,
+            collection,
+            &payload,
+            ImageInput::Bytes(&frame),
+        )
+        .await
+        {
+            Ok(found) => found,
+            Err(StatusCode::BAD_REQUEST) => continue,
+            Err(status) => return Err(status),
+        };
+        // Every face of the frame counts, with a detector there can be several
+        let matches = found
+            .matches
+            .into_iter()
+            .chain(found.other_faces.into_iter().flat_map(|face| face.matches));
+        for found in matches {
+            let identity = identities.entry(found.uuid).or_insert_with(|| Identity {
+                target_uuid: found.uuid,
+                origin: found.origin.clone(),
+                metadata: found.metadata.clone(),
+                best_similarity: found.similarity,
+                appearances: Vec::new(),
+            });
+            identity.best_similarity = identity.best_similarity.max(found.similarity);
+            // Two faces of one frame matching the same identity make one appearance
+            match identity.appearances.last_mut() {
+                Some(last) if last.timestamp == timestamp => {
+                    last.similarity = last.similarity.max(found.similarity);
+                }
+                _ => identity.appearances.push(Appearance {
+                    timestamp,
+                    similarity: found.similarity,
+                }),
+            }
+        }
+    }
+
+    // An unreadable upload yields no frame at all
+    if searched == 0 {
+        tracing::warn!("Uploaded video has no decodable frame");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let mut identities: Vec<Identity> = identities.into_values().collect();
+    identities.sort_by(|a, b| b.best_similarity.total_cmp(&a.best_similarity));
+    Ok(VideoSearchResponse {
+        frames: searched,
+        truncated,
+        identities,
+    })
+}
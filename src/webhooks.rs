@@ -0,0 +1,213 @@
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, Semaphore};
+use uuid::Uuid;
+
+use crate::events::{Events, MatchEvent};
+use crate::store::Metadata;
+use crate::util;
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+// Events waiting for delivery; past this new events are dropped
+const QUEUE_SIZE: usize = 4096;
+// Deliveries in flight across all endpoints, retries included
+const MAX_IN_FLIGHT: usize = 32;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+// Body of a webhook POST
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WebhookEvent {
+    Registered {
+        tenant: String,
+        collection: String,
+        target_uuid: Uuid,
+        origin: String,
+        metadata: Metadata,
+        timestamp: String,
+    },
+    Match {
+        tenant: String,
+        #[serde(flatten)]
+        event: MatchEvent,
+    },
+}
+
+impl WebhookEvent {
+    fn kind(&self) -> &'static str {
+        match self {
+            WebhookEvent::Registered { .. } => "registered",
+            WebhookEvent::Match { .. } => "match",
+        }
+    }
+}
+
+// POSTs registrations and match events to the configured endpoints
+pub struct Webhooks {
+    queue: mpsc::Sender<WebhookEvent>,
+}
+
+struct Delivery {
+    urls: Vec<String>,
+    // HMAC-SHA256 key of the X-Owl-Signature header
+    secret: Option<String>,
+    max_attempts: u32,
+    client: reqwest::Client,
+    in_flight: Arc<Semaphore>,
+}
+
+impl Webhooks {
+    // Starts the delivery worker, which also forwards the match events of `events`
+    pub fn start(
+        urls: Vec<String>,
+        secret: Option<String>,
+        max_attempts: u32,
+        events: &Events,
+    ) -> Result<Self, reqwest::Error> {
+        let delivery = Arc::new(Delivery {
+            urls,
+            secret,
+            max_attempts: max_attempts.max(1),
+            client: reqwest::Client::builder()
+                .timeout(WEBHOOK_TIMEOUT)
+                .build()?,
+            in_flight: Arc::new(Semaphore::new(MAX_IN_FLIGHT)),
+        });
+        let (queue, mut receiver) = mpsc::channel::<WebhookEvent>(QUEUE_SIZE);
+
+        tokio::spawn(async move {
+            while let Some(event) = receiver.recv().await {
+                let body = match serde_json::to_vec(&event) {
+                    Ok(body) => Arc::new(body),
+                    Err(e) => {
+                        tracing::error!(error = %e, "Failed to serialize webhook event");
+                        continue;
+                    }
+                };
+                for url in &delivery.urls {
+                    // Waiting here applies backpressure to the queue
+                    let Ok(permit) = delivery.in_flight.clone().acquire_owned().await else {
+                        return;
+                    };
+                    let delivery = delivery.clone();
+                    let url = url.clone();
+                    let body = body.clone();
+                    let kind = event.kind();
+                    tokio::spawn(async move {
+                        let _permit = permit;
+                        delivery.deliver(&url, kind, &body).await;
+                    });
+                }
+            }
+        });
+
+        let webhooks = Self { queue };
+        webhooks.forward_matches(events.subscribe());
+        Ok(webhooks)
+    }
+
+    fn forward_matches(&self, mut matches: broadcast::Receiver<MatchEvent>) {
+        let queue = self.queue.clone();
+        tokio::spawn(async move {
+            loop {
+                match matches.recv().await {
+                    Ok(event) => {
+                        let tenant = event.tenant.clone();
+                        enqueue(&queue, WebhookEvent::Match { tenant, event });
+                    }
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        tracing::warn!(missed, "Webhooks fell behind, match events dropped")
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    pub fn registered(
+        &self,
+        tenant: &str,
+        collection: &str,
+        target_uuid: Uuid,
+        origin: &str,
+        metadata: &Metadata,
+    ) {
+        enqueue(
+            &self.queue,
+            WebhookEvent::Registered {
+                tenant: tenant.to_string(),
+                collection: collection.to_string(),
+                target_uuid,
+                origin: origin.to_string(),
+                metadata: metadata.clone(),
+                timestamp: util::format_rfc3339(util::unix_now()),
+            },
+        );
+    }
+}
+
+fn enqueue(queue: &mpsc::Sender<WebhookEvent>, event: WebhookEvent) {
+    if queue.try_send(event).is_err() {
+        tracing::warn!("Webhook queue full, event dropped");
+    }
+}
+
+impl Delivery {
+    // Retries network errors, 429 and 5xx with exponential backoff; other
+    // responses are final
+    async fn deliver(&self, url: &str, kind: &str, body: &[u8]) {
+        let delivery_id = Uuid::new_v4().to_string();
+        let mut backoff = INITIAL_BACKOFF;
+        for attempt in 1..=self.max_attempts {
+            let timestamp = util::unix_now().to_string();
+            let mut request = self
+                .client
+                .post(url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header("x-owl-event", kind)
+                .header("x-owl-delivery", &delivery_id)
+                .header("x-owl-timestamp", &timestamp)
+                .body(body.to_vec());
+            if let Some(secret) = &self.secret {
+                request = request.header("x-owl-signature", sign(secret, &timestamp, body));
+            }
+
+            let retryable = match request.send().await {
+                Ok(response) if response.status().is_success() => {
+                    tracing::debug!(%url, kind, attempt, "Webhook delivered");
+                    return;
+                }
+                Ok(response) => {
+                    let status = response.status();
+                    tracing::warn!(%url, kind, attempt, %status, "Webhook rejected");
+                    status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                }
+                Err(e) => {
+                    tracing::warn!(%url, kind, attempt, error = %e, "Webhook delivery failed");
+                    true
+                }
+            };
+            if !retryable || attempt == self.max_attempts {
+                break;
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+        tracing::error!(%url, kind, delivery_id = %delivery_id, "Webhook dropped");
+    }
+}
+
+// "sha256=" + hex HMAC of "{timestamp}.{body}"; the timestamp lets receivers
+// reject replayed deliveries
+fn sign(secret: &str, timestamp: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
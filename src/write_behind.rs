@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+use crate::breaker::{DbBreaker, DbError};
+use crate::handlers::RegisterMode;
+use crate::resync::TargetKey;
+use crate::store::Metadata;
+use crate::target_store::{NewTargets, TargetStore};
+use crate::telemetry;
+
+pub const DEFAULT_MAX_BATCH: usize = 256;
+pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_millis(50);
+pub const DEFAULT_QUEUE_SIZE: usize = 10_000;
+// Pause before writing again while the database is unavailable
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug)]
+pub struct WriteBehindConfig {
+    // Registrations written per transaction
+    pub max_batch: usize,
+    // Longest a registration waits for others to share its transaction
+    pub flush_interval: Duration,
+    // Registrations waiting for the writer; past it registrations are written
+    // by their request again
+    pub queue_size: usize,
+}
+
+// An `Append` registration waiting for the writer, owned so it outlives its
+// request
+pub struct QueuedTargets {
+    pub tenant: String,
+    pub collection: String,
+    pub origin: String,
+    pub metadata: Metadata,
+    pub expires_at: Option<i64>,
+    pub model: String,
+    // (uuid, embedding, crop key) of every face
+    pub faces: Vec<(Uuid, Vec<f32>, Option<String>)>,
+}
+
+impl QueuedTargets {
+    fn as_new(&self) -> NewTargets<'_> {
+        NewTargets {
+            tenant: &self.tenant,
+            collection: &self.collection,
+            target_uuid: Uuid::nil(),
+            mode: RegisterMode::Append,
+            origin: &self.origin,
+            metadata: &self.metadata,
+            expires_at: self.expires_at,
+            model: &self.model,
+            faces: self
+                .faces
+                .iter()
+                .map(|(uuid, embedding, image_key)| (*uuid, &embedding[..], image_key.as_deref()))
+                .collect(),
+        }
+    }
+
+    fn uuids(&self) -> Vec<Uuid> {
+        self.faces.iter().map(|(uuid, _, _)| *uuid).collect()
+    }
+
+    fn keys(&self) -> impl Iterator<Item = TargetKey> + '_ {
+        self.faces
+            .iter()
+            .map(|(uuid, _, _)| (self.tenant.clone(), self.collection.clone(), *uuid))
+    }
+}
+
+// Faces queued or being written, per target
+type Pending = Arc<Mutex<HashMap<TargetKey, usize>>>;
+
+// Database writes of `Append` registrations, acknowledged once in memory and
+// written in the background in batched transactions. Deletes, updates and
+// replacements of a target wait for its queued rows (`settle`), so an insert
+// still behind never lands after them and brings the target back.
+pub struct WriteBehind {
+    sender: mpsc::Sender<QueuedTargets>,
+    closing: Arc<Notify>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+    pending: Pending,
+    // Signaled after every batch written
+    flushed: Arc<Notify>,
+}
+
+impl WriteBehind {
+    // Starts the writer task; registrations are written in the order queued
+    pub fn start(
+        targets: Arc<dyn TargetStore>,
+        breaker: Arc<DbBreaker>,
+        config: WriteBehindConfig,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel(config.queue_size.max(1));
+        let closing = Arc::new(Notify::new());
+        let pending = Pending::default();
+        let flushed = Arc::new(Notify::new());
+        let writer = tokio::spawn(write_targets(
+            targets,
+            breaker,
+            receiver,
+            closing.clone(),
+            pending.clone(),
+            flushed.clone(),
+            config,
+        ));
+        Self {
+            sender,
+            closing,
+            writer: Mutex::new(Some(writer)),
+            pending,
+            flushed,
+        }
+    }
+
+    // Stops taking registrations and waits for the queued ones to be written,
+    // at shutdown
+    pub async fn close(&self) {
+        self.closing.notify_one();
+        let writer = self.writer.lock().unwrap_or_else(|e| e.into_inner()).take();
+        if let Some(writer) = writer {
+            writer.await.ok();
+        }
+    }
+
+    // Room for a registration in the queue, None when it is full or closed:
+    // the request then writes the registration itself
+    pub fn reserve(&self) -> Option<mpsc::Permit<'_, QueuedTargets>> {
+        self.sender.try_reserve().ok()
+    }
+
+    // Hands a registration to the writer; its targets count as pending
+    // until it is written
+    pub fn queue(&self, permit: mpsc::Permit<'_, QueuedTargets>, targets: QueuedTargets) {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        for key in targets.keys() {
+            *pending.entry(key).or_default() += 1;
+        }
+        drop(pending);
+        permit.send(targets);
+    }
+
+    // Waits until the queued rows of the target are written
+    pub async fn settle(&self, tenant: &str, collection: &str, uuid: Uuid) {
+        let key = (tenant.to_string(), collection.to_string(), uuid);
+        self.wait(|pending| !pending.contains_key(&key)).await;
+    }
+
+    // Waits until every queued row is written, before statements that touch
+    // many targets at once
+    pub async fn settle_all(&self) {
+        self.wait(HashMap::is_empty).await;
+    }
+
+    async fn wait(&self, settled: impl Fn(&HashMap<TargetKey, usize>) -> bool) {
+        loop {
+            // Registered before the check, so a flush in between is not missed
+            let flushed = self.flushed.notified();
+            tokio::pin!(flushed);
+            flushed.as_mut().enable();
+            let done = settled(&self.pending.lock().unwrap_or_else(|e| e.into_inner()));
+            if done {
+                return;
+            }
+            flushed.await;
+        }
+    }
+}
+
+async fn write_targets(
+    targets: Arc<dyn TargetStore>,
+    breaker: Arc<DbBreaker>,
+    mut receiver: mpsc::Receiver<QueuedTargets>,
+    closing: Arc<Notify>,
+    pending: Pending,
+    flushed: Arc<Notify>,
+    config: WriteBehindConfig,
+) {
+    let mut batch = Vec::with_capacity(config.max_batch);
+    loop {
+        let queued = tokio::select! {
+            queued = receiver.recv() => queued,
+            _ = closing.notified() => {
+                // Queued registrations are still received, then `recv` ends
+                receiver.close();
+                continue;
+            }
+        };
+        let Some(queued) = queued else {
+            break;
+        };
+        batch.push(queued);
+        let deadline = tokio::time::Instant::now() + config.flush_interval;
+        while batch.len() < config.max_batch {
+            match tokio::time::timeout_at(deadline, receiver.recv()).await {
+                Ok(Some(queued)) => batch.push(queued),
+                Ok(None) | Err(_) => break,
+            }
+        }
+        telemetry::set_write_behind_pending(batch.len() + receiver.len());
+        flush(&*targets, &breaker, &batch).await;
+        // Written or refused, the rows no longer hold back other writes
+        let mut waiting = pending.lock().unwrap_or_else(|e| e.into_inner());
+        for key in batch.iter().flat_map(QueuedTargets::keys) {
+            if let Some(count) = waiting.get_mut(&key) {
+                *count -= 1;
+                if *count == 0 {
+                    waiting.remove(&key);
+                }
+            }
+        }
+        drop(waiting);
+        flushed.notify_waiters();
+        batch.clear();
+        telemetry::set_write_behind_pending(receiver.len());
+    }
+}
+
+// Writes the batch in one transaction. A row the database refuses fails the
+// whole transaction, so the registrations are then written one by one and
+// only the refused ones are lost.
+async fn flush(targets: &dyn TargetStore, breaker: &DbBreaker, batch: &[QueuedTargets]) {
+    let rows: Vec<NewTargets<'_>> = batch.iter().map(QueuedTargets::as_new).collect();
+    let Err(e) = write(targets, breaker, &rows).await else {
+        return;
+    };
+    if batch.len() == 1 {
+        refused(&batch[0], &e);
+        return;
+    }
+    tracing::warn!(registrations = batch.len(), error = %e, "Queued registrations refused as a batch, writing them one by one");
+    for (queued, row) in batch.iter().zip(&rows) {
+        if let Err(e) = write(targets, breaker, std::slice::from_ref(row)).await {
+            refused(queued, &e);
+        }
+    }
+}
+
+// Retries until the database takes the rows or refuses them; registrations
+// are acknowledged already, so a database that is down holds the queue
+// rather than losing them
+async fn write(
+    targets: &dyn TargetStore,
+    breaker: &DbBreaker,
+    rows: &[NewTargets<'_>],
+) -> Result<(), sqlx::Error> {
+    loop {
+        match breaker.run(|| targets.insert_batch(rows)).await {
+            Ok(()) => return Ok(()),
+            Err(DbError::Failed(e)) => return Err(e),
+            // Writing the rows again could store them twice
+            Err(DbError::CommitUnknown(e)) => {
+                tracing::error!(registrations = rows.len(), error = %e, "Connection lost while committing queued registrations, they may not be stored");
+                return Ok(());
+            }
+            Err(e) => {
+                tracing::error!(registrations = rows.len(), error = %e, "Failed to write queued registrations, retrying");
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+fn refused(queued: &QueuedTargets, e: &sqlx::Error) {
+    tracing::error!(
+        tenant = %queued.tenant,
+        collection = %queued.collection,
+        uuids = ?queued.uuids(),
+        error = %e,
+        "Queued registration refused by the database, it only lives in memory"
+    );
+}
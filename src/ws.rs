@@ -0,0 +1,212 @@
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        ConnectInfo, Extension, Query, State,
+    },
+    http::{HeaderMap, StatusCode},
+    response::Response,
+};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+use crate::auth::ApiKey;
+use crate::collections::DEFAULT_COLLECTION;
+use crate::enhance::EnhanceOptions;
+use crate::handlers::{self, Fusion, ImageInput, SearchPayload, SearchResponse};
+use crate::jwt::Claims;
+use crate::tenant::Tenant;
+use crate::AppState;
+
+// Largest accepted frame, same bound as a JSON search body
+const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+// Search settings of a /ws/search session, fixed for all its frames
+#[derive(Deserialize)]
+pub struct StreamQuery {
+    collection: Option<String>,
+    threshold: Option<f32>,
+    limit: Option<usize>,
+    #[serde(default)]
+    group_by_uuid: bool,
+    #[serde(default)]
+    hit_counts: bool,
+    // Comma-separated origins to search
+    origins: Option<String>,
+    // Per-frame time budget
+    time_budget_ms: Option<u64>,
+    // IVF lists scanned per frame
+    nprobe: Option<usize>,
+    // Estimate age and gender of every frame
+    #[serde(default)]
+    attributes: bool,
+    // Estimate the expression of every frame
+    #[serde(default)]
+    emotion: bool,
+}
+
+impl StreamQuery {
+    fn collection(&self) -> &str {
+        self.collection.as_deref().unwrap_or(DEFAULT_COLLECTION)
+    }
+
+    fn payload(&self) -> SearchPayload {
+        SearchPayload {
+            image_base64: String::new(),
+            embedding: None,
+            threshold: self.threshold,
+            limit: self.limit,
+            enhance: EnhanceOptions::default(),
+            group_by_uuid: self.group_by_uuid,
+            hit_counts: self.hit_counts,
+            origins: self.origins.as_ref().map(|origins| {
+                origins
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|origin| !origin.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            }),
+            metadata: None,
+            exclude_uuids: None,
+            exclude_origins: None,
+            time_budget_ms: self.time_budget_ms,
+            nprobe: self.nprobe,
+            attributes: self.attributes,
+            emotion: self.emotion,
+            return_crops: false,
+            return_embedding: false,
+            source: None,
+            images_base64: Vec::new(),
+            fusion: Fusion::default(),
+            include_archived: false,
+        }
+    }
+}
+
+// Sent back for every processed frame
+#[derive(Serialize)]
+struct FrameResult {
+    // Position of the frame in the stream, counting from 0; frames that
+    // arrived while a previous one was processed are skipped
+    frame: u64,
+    #[serde(flatten)]
+    response: Option<SearchResponse>,
+    // HTTP status code the search failed with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<u16>,
+}
+
+// Handler for GET /ws/search - upgrades to a WebSocket where every binary
+// message is an encoded frame (JPEG, PNG...) and every text reply the search
+// result of one frame. Every frame searched counts against the rate limit.
+pub async fn ws_search(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Query(query): Query<StreamQuery>,
+    headers: HeaderMap,
+    claims: Option<Extension<Claims>>,
+    key: Option<Extension<ApiKey>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+) -> Result<Response, StatusCode> {
+    if state
+        .collections
+        .get(tenant.id(), query.collection())
+        .is_none()
+    {
+        tracing::warn!(collection = %query.collection(), "Received stream for unknown collection");
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let client = state.rate_limiter.client_id(
+        &headers,
+        claims.as_ref().map(|Extension(claims)| claims),
+        key.as_ref().map(|Extension(key)| key),
+        peer.map(|ConnectInfo(address)| address),
+    );
+    Ok(ws
+        .max_message_size(MAX_FRAME_SIZE)
+        .on_upgrade(move |socket| stream_search(socket, state, tenant, query, client)))
+}
+
+async fn stream_search(
+    socket: WebSocket,
+    state: AppState,
+    tenant: Tenant,
+    query: StreamQuery,
+    client: String,
+) {
+    let (mut sender, mut receiver) = socket.split();
+    // Only the latest frame is kept: a live feed wants the current picture,
+    // not a backlog that grows whenever inference is slower than the camera
+    let (latest_tx, mut latest_rx) = watch::channel::<Option<(u64, Arc<Vec<u8>>)>>(None);
+
+    let reader = tokio::spawn(async move {
+        let mut frame = 0u64;
+        while let Some(Ok(message)) = receiver.next().await {
+            match message {
+                Message::Binary(bytes) => {
+                    latest_tx.send_replace(Some((frame, Arc::new(bytes))));
+                    frame += 1;
+                }
+                Message::Close(_) => break,
+                // Pings are answered by axum; text messages are not frames
+                _ => {}
+            }
+        }
+    });
+
+    tracing::info!(tenant = %tenant.id(), collection = %query.collection(), "Search stream opened");
+    let payload = query.payload();
+    let mut processed = 0u64;
+    // Ends once the reader is done and the sender dropped
+    while latest_rx.changed().await.is_ok() {
+        let Some((frame, image)) = latest_rx.borrow_and_update().clone() else {
+            continue;
+        };
+        let searched = match state.rate_limiter.acquire(client.clone()) {
+            Ok(()) => {
+                handlers::run_search_image(
+                    &state,
+                    &tenant,
+                    query.collection(),
+                    &payload,
+                    ImageInput::Bytes(&image),
+                )
+                .await
+            }
+            Err(retry_after) => {
+                tracing::warn!(%client, frame, retry_after, "Rate limit exceeded in search stream");
+                Err(StatusCode::TOO_MANY_REQUESTS)
+            }
+        };
+        let result = match searched {
+            Ok(found) => FrameResult {
+                frame,
+                response: Some(handlers::search_response(
+                    found,
+                    &payload,
+                    state.calibration.as_deref(),
+                )),
+                error: None,
+            },
+            Err(status) => FrameResult {
+                frame,
+                response: None,
+                error: Some(status.as_u16()),
+            },
+        };
+        let Ok(text) = serde_json::to_string(&result) else {
+            break;
+        };
+        if sender.send(Message::Text(text)).await.is_err() {
+            break;
+        }
+        processed += 1;
+    }
+
+    reader.abort();
+    tracing::info!(tenant = %tenant.id(), processed, "Search stream closed");
+}
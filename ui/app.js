@@ -0,0 +1,224 @@
+// Admin dashboard: every call goes to the regular tenant API with the
+// credential entered in the header, kept for the browser session only
+"use strict";
+
+const $ = (id) => document.getElementById(id);
+let endCursor = null;
+
+function credentialHeaders() {
+  const key = sessionStorage.getItem("key") || "";
+  if (key.startsWith("Bearer ")) {
+    return { Authorization: key };
+  }
+  return key ? { "X-API-Key": key } : {};
+}
+
+function collection() {
+  return $("collection").value || "default";
+}
+
+function showError(message) {
+  $("error").textContent = message;
+  $("error").hidden = !message;
+}
+
+async function api(path, options = {}) {
+  const response = await fetch(path, {
+    ...options,
+    headers: { ...credentialHeaders(), ...(options.headers || {}) },
+  });
+  if (!response.ok) {
+    throw new Error(`${options.method || "GET"} ${path}: ${response.status} ${response.statusText}`);
+  }
+  return response.status === 204 ? null : response.json();
+}
+
+async function graphql(query, variables) {
+  const body = await api("/v1/graphql", {
+    method: "POST",
+    headers: { "Content-Type": "application/json" },
+    body: JSON.stringify({ query, variables }),
+  });
+  if (body.errors && body.errors.length) {
+    throw new Error(body.errors.map((error) => error.message).join(", "));
+  }
+  return body.data;
+}
+
+function cell(row, text, code) {
+  const td = row.insertCell();
+  if (code) {
+    const element = document.createElement("code");
+    element.textContent = text;
+    td.appendChild(element);
+  } else {
+    td.textContent = text;
+  }
+  return td;
+}
+
+function metadataText(metadata) {
+  return metadata && Object.keys(metadata).length ? JSON.stringify(metadata) : "";
+}
+
+async function loadCollections() {
+  const current = collection();
+  const collections = await api("/v1/collections/");
+  const select = $("collection");
+  select.replaceChildren(
+    ...collections.map((info) => new Option(`${info.name} (${info.entries})`, info.name))
+  );
+  select.value = collections.some((info) => info.name === current) ? current : "default";
+}
+
+const TARGETS = `query ($collection: String, $origin: String, $after: String) {
+  targets(collection: $collection, origin: $origin, first: 50, after: $after) {
+    edges { node { uuid origins registrations lastRegistered metadata } }
+    pageInfo { hasNextPage endCursor }
+  }
+}`;
+
+async function loadTargets(reset) {
+  if (reset) {
+    endCursor = null;
+    $("targets").replaceChildren();
+  }
+  const data = await graphql(TARGETS, {
+    collection: collection(),
+    origin: $("origin").value || null,
+    after: endCursor,
+  });
+  for (const { node } of data.targets.edges) {
+    const row = $("targets").insertRow();
+    cell(row, node.uuid, true);
+    cell(row, node.origins.join(", "));
+    cell(row, node.registrations);
+    cell(row, node.lastRegistered);
+    cell(row, metadataText(node.metadata), true);
+    const button = document.createElement("button");
+    button.className = "delete";
+    button.textContent = "Delete";
+    button.onclick = () => deleteTarget(node.uuid, row);
+    row.insertCell().appendChild(button);
+  }
+  endCursor = data.targets.pageInfo.endCursor;
+  $("more").hidden = !data.targets.pageInfo.hasNextPage;
+}
+
+async function deleteTarget(uuid, row) {
+  if (!confirm(`Delete every registration of ${uuid} from ${collection()}?`)) {
+    return;
+  }
+  await api(`/v1/targets/${uuid}?collection=${encodeURIComponent(collection())}`, {
+    method: "DELETE",
+  });
+  row.remove();
+}
+
+function readBase64(file) {
+  return new Promise((resolve, reject) => {
+    const reader = new FileReader();
+    reader.onload = () => resolve(reader.result.split(",")[1]);
+    reader.onerror = () => reject(reader.error);
+    reader.readAsDataURL(file);
+  });
+}
+
+async function search() {
+  const file = $("photo").files[0];
+  $("preview").src = URL.createObjectURL(file);
+  $("preview").hidden = false;
+  const payload = { image_base64: await readBase64(file), limit: 20 };
+  if ($("threshold").value) {
+    payload.threshold = Number($("threshold").value);
+  }
+  const response = await api(`/v1/search/?collection=${encodeURIComponent(collection())}`, {
+    method: "POST",
+    headers: { "Content-Type": "application/json" },
+    body: JSON.stringify(payload),
+  });
+  $("matches").replaceChildren();
+  for (const result of response.results) {
+    const row = $("matches").insertRow();
+    cell(row, result.target_uuid, true);
+    cell(row, result.similarity.toFixed(4));
+    cell(row, result.origin);
+    cell(row, metadataText(result.metadata), true);
+  }
+  if (!response.results.length) {
+    cell($("matches").insertRow(), "No match above the threshold");
+  }
+}
+
+const STATS = `{
+  stats { collections { name entries evicted memoryBytes } }
+  origins { origin identities embeddings oldestRegistration newestRegistration }
+}`;
+
+async function loadStats() {
+  const data = await graphql(STATS);
+  $("collections").replaceChildren();
+  for (const info of data.stats.collections) {
+    const row = $("collections").insertRow();
+    cell(row, info.name);
+    cell(row, info.entries);
+    cell(row, info.evicted);
+    cell(row, `${(info.memoryBytes / 1048576).toFixed(1)} MB`);
+  }
+  $("origins").replaceChildren();
+  for (const info of data.origins) {
+    const row = $("origins").insertRow();
+    cell(row, info.origin);
+    cell(row, info.identities);
+    cell(row, info.embeddings);
+    cell(row, info.oldestRegistration);
+    cell(row, info.newestRegistration);
+  }
+}
+
+// Runs an action, reporting its failure in the banner
+function guarded(action) {
+  return async (event) => {
+    if (event) {
+      event.preventDefault();
+    }
+    showError("");
+    try {
+      await action();
+    } catch (error) {
+      showError(error.message);
+    }
+  };
+}
+
+function showTab(name) {
+  for (const button of document.querySelectorAll("nav button")) {
+    button.classList.toggle("active", button.dataset.tab === name);
+  }
+  for (const section of document.querySelectorAll("section")) {
+    section.hidden = section.id !== name;
+  }
+  if (name === "stats") {
+    guarded(loadStats)();
+  }
+}
+
+async function connect() {
+  await loadCollections();
+  await loadTargets(true);
+}
+
+$("credentials").onsubmit = guarded(async () => {
+  sessionStorage.setItem("key", $("key").value.trim());
+  await connect();
+});
+$("collection").onchange = guarded(() => loadTargets(true));
+$("filter").onsubmit = guarded(() => loadTargets(true));
+$("more").onclick = guarded(() => loadTargets(false));
+$("query").onsubmit = guarded(search);
+for (const button of document.querySelectorAll("nav button")) {
+  button.onclick = () => showTab(button.dataset.tab);
+}
+
+$("key").value = sessionStorage.getItem("key") || "";
+guarded(connect)();